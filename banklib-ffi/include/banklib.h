@@ -0,0 +1,73 @@
+/*
+ * C interface of banklib, the client of the bank server.
+ *
+ * Build the library with `cargo build -p banklib-ffi --release` and link
+ * against target/release/libbanklib_ffi.so (or the static .a).
+ *
+ * Every call returns a status code, BANK_OK on success; after a failure
+ * bank_last_error() describes it. Strings returned by the library are freed
+ * with bank_string_free(), clients with bank_client_free(). A client is
+ * used by one thread at a time; the last error is kept per thread.
+ */
+#ifndef BANKLIB_H
+#define BANKLIB_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The call succeeded. */
+#define BANK_OK 0
+/* A pointer was null, a string was not UTF-8 or a command was not valid JSON. */
+#define BANK_INVALID_ARGUMENT 1
+/* The server could not be reached or did not answer in time. */
+#define BANK_UNAVAILABLE 2
+#define BANK_ACCOUNT_DOES_NOT_EXIST 3
+#define BANK_ACCOUNT_ALREADY_EXISTS 4
+#define BANK_INSUFFICIENT_FUNDS 5
+/* The identity or token may not run the command. */
+#define BANK_UNAUTHORIZED 6
+/* The server refused the command for another reason; the last error names it. */
+#define BANK_REJECTED 7
+/* The library itself failed; this is a bug. */
+#define BANK_INTERNAL 8
+
+typedef struct BankClient BankClient;
+
+/* Creates a client of the server at `address` ("host:port"). */
+int bank_client_new(const char *address, BankClient **out);
+/* Sends the following commands on behalf of the identity with `token`. */
+int bank_client_set_identity(BankClient *client, const char *token);
+void bank_client_free(BankClient *client);
+void bank_string_free(char *s);
+
+/*
+ * Message of the last failed call on this thread, NULL if none failed. It
+ * starts with the name of the bank error, e.g. "InsufficientFunds: ...",
+ * and stays valid until the next failed call on the thread.
+ */
+const char *bank_last_error(void);
+
+/*
+ * Sends any command of the protocol as JSON, e.g.
+ * {"type": "GetAccountBalance", "v": 1, "data": "Alice"}, and returns the
+ * response payload as JSON in `*response`.
+ */
+int bank_execute(const BankClient *client, const char *command, char **response);
+
+/* `id` may be NULL. */
+int bank_create_account(const BankClient *client, const char *name, uint64_t *id);
+int bank_deposit(const BankClient *client, const char *account, uint32_t amount);
+int bank_withdraw(const BankClient *client, const char *account, uint32_t amount);
+int bank_transfer(const BankClient *client, const char *from, const char *to, uint32_t amount);
+int bank_balance(const BankClient *client, const char *account, uint32_t *balance);
+/* Operations of the account, or of the bank with a NULL account, as a JSON array. */
+int bank_history(const BankClient *client, const char *account, char **history);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* BANKLIB_H */
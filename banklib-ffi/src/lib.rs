@@ -0,0 +1,335 @@
+//! C ABI of [`banklib`], for services in other languages that embed the
+//! client instead of speaking the wire protocol themselves. The functions
+//! are declared in `include/banklib.h`.
+//!
+//! Every call returns a status code, `BANK_OK` on success. On failure the
+//! thread's last error, read with [`bank_last_error`], says what happened.
+//! Strings handed out by the library are freed with [`bank_string_free`]
+//! and clients with [`bank_client_free`]. Any command of the protocol can
+//! be sent as JSON with [`bank_execute`]; the common ones have their own
+//! functions.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use banklib::BankClient;
+use protocol_crate::{BankError, Command};
+
+/// The call succeeded.
+pub const BANK_OK: c_int = 0;
+/// A pointer was null, a string was not UTF-8 or a command was not valid JSON.
+pub const BANK_INVALID_ARGUMENT: c_int = 1;
+/// The server could not be reached or did not answer in time.
+pub const BANK_UNAVAILABLE: c_int = 2;
+pub const BANK_ACCOUNT_DOES_NOT_EXIST: c_int = 3;
+pub const BANK_ACCOUNT_ALREADY_EXISTS: c_int = 4;
+pub const BANK_INSUFFICIENT_FUNDS: c_int = 5;
+/// The identity or token may not run the command.
+pub const BANK_UNAUTHORIZED: c_int = 6;
+/// The server refused the command for another reason; the last error names it.
+pub const BANK_REJECTED: c_int = 7;
+/// The library itself failed; this is a bug.
+pub const BANK_INTERNAL: c_int = 8;
+
+thread_local! {
+    // Ошибка последнего неудачного вызова в этом потоке
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Failure of a call: its status code and what to report as the last error.
+struct Failure {
+    status: c_int,
+    message: String,
+}
+
+impl From<BankError> for Failure {
+    fn from(e: BankError) -> Self {
+        let e = e.cause();
+        let status = match e {
+            BankError::RemoteUnavailable(_) | BankError::DeadlineExceeded => BANK_UNAVAILABLE,
+            BankError::AccountDoesNotExist(_) => BANK_ACCOUNT_DOES_NOT_EXIST,
+            BankError::AccountAlreadyExists(_) => BANK_ACCOUNT_ALREADY_EXISTS,
+            BankError::InsufficientFunds(_) => BANK_INSUFFICIENT_FUNDS,
+            BankError::Unauthorized | BankError::Forbidden(_) => BANK_UNAUTHORIZED,
+            _ => BANK_REJECTED,
+        };
+        Failure {
+            status,
+            message: format!("{}: {:?}", e.name(), e),
+        }
+    }
+}
+
+fn invalid(message: impl Into<String>) -> Failure {
+    Failure {
+        status: BANK_INVALID_ARGUMENT,
+        message: message.into(),
+    }
+}
+
+/// Runs the body of an exported function: records a failure as the last
+/// error and keeps a panic from unwinding into C.
+fn call(f: impl FnOnce() -> Result<(), Failure>) -> c_int {
+    let failure = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => return BANK_OK,
+        Ok(Err(failure)) => failure,
+        Err(_) => Failure {
+            status: BANK_INTERNAL,
+            message: "banklib panicked".to_string(),
+        },
+    };
+    // Нулевой байт внутри сообщения обрезал бы его в C, он заменяется
+    let message = CString::new(failure.message.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    failure.status
+}
+
+/// # Safety
+///
+/// `s` is null or a NUL-terminated string that outlives the call.
+unsafe fn string<'a>(s: *const c_char, name: &str) -> Result<&'a str, Failure> {
+    if s.is_null() {
+        return Err(invalid(format!("{} is null", name)));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| invalid(format!("{} is not UTF-8", name)))
+}
+
+/// # Safety
+///
+/// `client` is null or a client from [`bank_client_new`] not yet freed.
+unsafe fn client<'a>(client: *const BankClient) -> Result<&'a BankClient, Failure> {
+    client.as_ref().ok_or_else(|| invalid("client is null"))
+}
+
+/// # Safety
+///
+/// `out` is null or valid for a write.
+unsafe fn put<T>(out: *mut T, value: T) -> Result<(), Failure> {
+    if out.is_null() {
+        return Err(invalid("output pointer is null"));
+    }
+    out.write(value);
+    Ok(())
+}
+
+fn json(value: &impl serde::Serialize) -> Result<*mut c_char, Failure> {
+    let text = serde_json::to_string(value).map_err(|e| Failure {
+        status: BANK_INTERNAL,
+        message: e.to_string(),
+    })?;
+    Ok(CString::new(text).unwrap().into_raw())
+}
+
+/// Creates a client of the server at `address` (`host:port`) and stores it
+/// in `*out`. No connection is made until the first command.
+///
+/// # Safety
+///
+/// `address` is a NUL-terminated string and `out` is valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn bank_client_new(
+    address: *const c_char,
+    out: *mut *mut BankClient,
+) -> c_int {
+    call(|| {
+        let client = BankClient::new(string(address, "address")?);
+        put(out, Box::into_raw(Box::new(client)))
+    })
+}
+
+/// Makes `client` send its commands on behalf of the identity with `token`.
+///
+/// # Safety
+///
+/// `client` is a live client and `token` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn bank_client_set_identity(
+    client: *mut BankClient,
+    token: *const c_char,
+) -> c_int {
+    call(|| {
+        let token = string(token, "token")?;
+        let client = client.as_mut().ok_or_else(|| invalid("client is null"))?;
+        // with_identity берет клиента по значению, на его место ставится временный
+        let owned = std::mem::replace(client, BankClient::new(""));
+        *client = owned.with_identity(token);
+        Ok(())
+    })
+}
+
+/// Frees a client; null is ignored.
+///
+/// # Safety
+///
+/// `client` is null or a client from [`bank_client_new`] not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn bank_client_free(client: *mut BankClient) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+/// Frees a string the library returned; null is ignored.
+///
+/// # Safety
+///
+/// `s` is null or a string from this library not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn bank_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Message of the last failed call on this thread, null if none failed.
+/// It starts with the name of the bank error, e.g. `InsufficientFunds: ...`,
+/// and stays valid until the next failed call on the thread.
+#[no_mangle]
+pub extern "C" fn bank_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+/// Sends any command of the protocol given as JSON, e.g.
+/// `{"type": "GetAccountBalance", "v": 1, "data": "Alice"}`, and stores the
+/// response payload as JSON in `*response`, to be freed with
+/// [`bank_string_free`].
+///
+/// # Safety
+///
+/// `client` is a live client, `command` a NUL-terminated string and
+/// `response` valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn bank_execute(
+    client: *const BankClient,
+    command: *const c_char,
+    response: *mut *mut c_char,
+) -> c_int {
+    call(|| {
+        let command: Command = serde_json::from_str(string(command, "command")?)
+            .map_err(|e| invalid(format!("command: {}", e)))?;
+        let payload = self::client(client)?.execute(command)?;
+        put(response, json(&payload)?)
+    })
+}
+
+/// Creates the account `name` and stores its numeric id in `*id`; `id` may
+/// be null.
+///
+/// # Safety
+///
+/// `client` is a live client, `name` a NUL-terminated string and `id` null
+/// or valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn bank_create_account(
+    client: *const BankClient,
+    name: *const c_char,
+    id: *mut u64,
+) -> c_int {
+    call(|| {
+        let name = string(name, "name")?.to_string();
+        let created = self::client(client)?.create_account(name)?;
+        if !id.is_null() {
+            id.write(created as u64);
+        }
+        Ok(())
+    })
+}
+
+/// Adds `amount` to the account.
+///
+/// # Safety
+///
+/// `client` is a live client and `account` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn bank_deposit(
+    client: *const BankClient,
+    account: *const c_char,
+    amount: u32,
+) -> c_int {
+    call(|| {
+        let account = string(account, "account")?;
+        Ok(self::client(client)?.increase_account(account, amount)?)
+    })
+}
+
+/// Takes `amount` from the account.
+///
+/// # Safety
+///
+/// `client` is a live client and `account` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn bank_withdraw(
+    client: *const BankClient,
+    account: *const c_char,
+    amount: u32,
+) -> c_int {
+    call(|| {
+        let account = string(account, "account")?;
+        Ok(self::client(client)?.decrease_account(account, amount)?)
+    })
+}
+
+/// Moves `amount` from `from` to `to`.
+///
+/// # Safety
+///
+/// `client` is a live client, `from` and `to` NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn bank_transfer(
+    client: *const BankClient,
+    from: *const c_char,
+    to: *const c_char,
+    amount: u32,
+) -> c_int {
+    call(|| {
+        let (from, to) = (string(from, "from")?, string(to, "to")?);
+        Ok(self::client(client)?.transfer(from, to, amount)?)
+    })
+}
+
+/// Stores the balance of the account in `*balance`.
+///
+/// # Safety
+///
+/// `client` is a live client, `account` a NUL-terminated string and
+/// `balance` valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn bank_balance(
+    client: *const BankClient,
+    account: *const c_char,
+    balance: *mut u32,
+) -> c_int {
+    call(|| {
+        let account = string(account, "account")?;
+        let found = self::client(client)?.get_account_balance(account)?;
+        put(balance, found)
+    })
+}
+
+/// Stores the operations of the account, or of the whole bank if `account`
+/// is null, as a JSON array in `*history`, to be freed with
+/// [`bank_string_free`].
+///
+/// # Safety
+///
+/// `client` is a live client, `account` null or a NUL-terminated string and
+/// `history` valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn bank_history(
+    client: *const BankClient,
+    account: *const c_char,
+    history: *mut *mut c_char,
+) -> c_int {
+    call(|| {
+        let client = self::client(client)?;
+        let operations = match account.is_null() {
+            true => client.get_history()?,
+            false => client.account_history(string(account, "account")?)?,
+        };
+        put(history, json(&operations)?)
+    })
+}
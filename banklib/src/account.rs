@@ -0,0 +1,55 @@
+use protocol_crate::{AccountRef, BankError, Operation, VersionedBalance};
+
+use crate::BankClient;
+
+/// One account of the bank, so call sites name it once instead of passing
+/// the name to every method of [`BankClient`].
+#[derive(Clone)]
+pub struct AccountHandle<'a> {
+    client: &'a BankClient,
+    account: AccountRef,
+}
+
+impl<'a> AccountHandle<'a> {
+    pub(crate) fn new(client: &'a BankClient, account: AccountRef) -> Self {
+        AccountHandle { client, account }
+    }
+
+    /// The name or ID the handle refers to the account by.
+    pub fn account(&self) -> &AccountRef {
+        &self.account
+    }
+
+    /// Increases the balance of the account by `amount`.
+    pub fn deposit(&self, amount: u32) -> Result<(), BankError> {
+        self.client.increase_account(self.account.clone(), amount)
+    }
+
+    /// Decreases the balance of the account by `amount`.
+    pub fn withdraw(&self, amount: u32) -> Result<(), BankError> {
+        self.client.decrease_account(self.account.clone(), amount)
+    }
+
+    /// Transfers `amount` to the account `to`, a name, an ID or another handle.
+    pub fn transfer_to(&self, to: impl Into<AccountRef>, amount: u32) -> Result<(), BankError> {
+        self.client.transfer(self.account.clone(), to, amount)
+    }
+
+    pub fn balance(&self) -> Result<u32, BankError> {
+        self.client.get_account_balance(self.account.clone())
+    }
+
+    pub fn versioned_balance(&self) -> Result<VersionedBalance, BankError> {
+        self.client.get_versioned_balance(self.account.clone())
+    }
+
+    pub fn history(&self) -> Result<Vec<Operation>, BankError> {
+        self.client.account_history(self.account.clone())
+    }
+}
+
+impl From<&AccountHandle<'_>> for AccountRef {
+    fn from(handle: &AccountHandle<'_>) -> Self {
+        handle.account.clone()
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+
+use protocol_crate::{AccountFilter, BankError};
+
+use crate::BankClient;
+
+/// Differences between two banks found by [`diff`]; empty if the banks hold
+/// the same accounts, balances and history.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DiffReport {
+    /// Accounts only the left bank has, with their balances.
+    pub only_left: Vec<(String, u32)>,
+    /// Accounts only the right bank has, with their balances.
+    pub only_right: Vec<(String, u32)>,
+    /// Accounts of both banks with different balances: the name, the left
+    /// balance and the right one.
+    pub balances: Vec<(String, u32, u32)>,
+    pub left_operations: usize,
+    pub right_operations: usize,
+    /// How many first operations both histories share; they differ from
+    /// there on.
+    pub common_operations: usize,
+}
+
+impl DiffReport {
+    pub fn is_empty(&self) -> bool {
+        self.only_left.is_empty()
+            && self.only_right.is_empty()
+            && self.balances.is_empty()
+            && self.left_operations == self.common_operations
+            && self.right_operations == self.common_operations
+    }
+}
+
+/// Compares the accounts, the balances and the histories of two banks,
+/// e.g. a primary and its replica or a bank before and after a restore.
+/// Histories are compared by their hash-chain digests: the first operation
+/// they differ at is found by bisection, without downloading them. Banks
+/// that keep taking requests can show differences that are only the
+/// requests in flight; maintenance mode stops them.
+pub fn diff(left: &BankClient, right: &BankClient) -> Result<DiffReport, BankError> {
+    let mut report = DiffReport::default();
+    let mut right_accounts = accounts(right)?;
+    for (name, balance) in accounts(left)? {
+        match right_accounts.remove(&name) {
+            None => report.only_left.push((name, balance)),
+            Some(other) if other != balance => report.balances.push((name, balance, other)),
+            Some(_) => {}
+        }
+    }
+    report.only_right = right_accounts.into_iter().collect();
+
+    report.left_operations = left.history_digest(None)?.operations;
+    report.right_operations = right.history_digest(None)?.operations;
+    let same = |operations| -> Result<bool, BankError> {
+        Ok(left.history_digest(Some(operations))?.digest
+            == right.history_digest(Some(operations))?.digest)
+    };
+    // Начало истории общее всегда, конец общей длины - если совпал хеш
+    let (mut common, mut differs) = (0, report.left_operations.min(report.right_operations));
+    if same(differs)? {
+        common = differs;
+    }
+    while differs - common > 1 {
+        let middle = common + (differs - common) / 2;
+        match same(middle)? {
+            true => common = middle,
+            false => differs = middle,
+        }
+    }
+    report.common_operations = common;
+    Ok(report)
+}
+
+// Все счета банка с балансами по имени
+fn accounts(client: &BankClient) -> Result<BTreeMap<String, u32>, BankError> {
+    Ok(client
+        .list_accounts(AccountFilter::All)?
+        .into_iter()
+        .collect())
+}
@@ -0,0 +1,96 @@
+//! Faults injected into the connections of a [`BankClient`], so that retry
+//! and timeout handling of an application can be tested without a flaky
+//! network. Enabled by the `faults` feature; meant for tests only.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use protocol_crate::BankError;
+
+use crate::{BankClient, Endpoint};
+
+/// What happens to one command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The command goes through untouched.
+    Pass,
+    /// The connection is reset. With `delivered` the server has executed the
+    /// command and only its response is lost; otherwise the server never saw it.
+    Reset { delivered: bool },
+    /// The command is sent after the given delay.
+    Delay(Duration),
+    /// Only the first bytes of the response arrive.
+    Truncate(usize),
+    /// The command is sent twice, as by a retry after a lost response; the
+    /// response to the second one is returned.
+    Duplicate,
+}
+
+/// Script of faults, one per command in the order the commands are sent.
+/// Commands after the end of the script go through untouched. Clones share
+/// the script, so a test keeps one to extend it while the client runs.
+///
+/// Only single commands are affected, not [`crate::Pipeline`] connections.
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    script: Arc<Mutex<VecDeque<Fault>>>,
+}
+
+impl Faults {
+    pub fn new() -> Self {
+        Faults::default()
+    }
+
+    /// Appends `fault` for the next command not covered by the script yet.
+    pub fn inject(&self, fault: Fault) -> &Self {
+        self.script.lock().unwrap().push_back(fault);
+        self
+    }
+
+    /// Number of commands the script still covers.
+    pub fn pending(&self) -> usize {
+        self.script.lock().unwrap().len()
+    }
+
+    pub(crate) fn next(&self) -> Option<Fault> {
+        self.script.lock().unwrap().pop_front()
+    }
+}
+
+/// Sends `data` through `client` to `endpoint`, breaking the exchange as
+/// `fault` says.
+pub(crate) fn round_trip(
+    client: &BankClient,
+    endpoint: &Endpoint,
+    fault: Fault,
+    data: &[u8],
+) -> Result<Vec<u8>, BankError> {
+    match fault {
+        Fault::Pass => client.round_trip(endpoint, data),
+        Fault::Reset { delivered } => {
+            if delivered {
+                // Ответ дочитывается, чтобы команда точно была выполнена
+                let _ = client.round_trip(endpoint, data);
+            }
+            Err(BankError::RemoteUnavailable(format!(
+                "{}: connection reset (injected)",
+                endpoint.address()
+            )))
+        }
+        Fault::Delay(delay) => {
+            thread::sleep(delay);
+            client.round_trip(endpoint, data)
+        }
+        Fault::Truncate(len) => {
+            let mut received = client.round_trip(endpoint, data)?;
+            received.truncate(len);
+            Ok(received)
+        }
+        Fault::Duplicate => {
+            let _ = client.round_trip(endpoint, data);
+            client.round_trip(endpoint, data)
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use protocol_crate::{AccountRef, BankError, Command, Response, ResponsePayload};
+
+use crate::{unexpected, Pipeline};
+
+/// Interactive transaction open on a [`Pipeline`]: deposits, withdrawals
+/// and transfers are collected by the server and applied all at once by
+/// [`InteractiveTransaction::commit`], so later steps can depend on what
+/// earlier reads returned. Reads see the committed state, not the collected
+/// operations. The transaction belongs to the connection, so commands other
+/// threads send through the same pipeline join it too. Dropping it
+/// uncommitted rolls it back.
+#[must_use = "operations are only applied by `commit`"]
+pub struct InteractiveTransaction<'a> {
+    pipeline: &'a Pipeline,
+    finished: bool,
+}
+
+impl<'a> InteractiveTransaction<'a> {
+    pub(crate) fn begin(pipeline: &'a Pipeline) -> Result<Self, BankError> {
+        match pipeline.send(Command::BeginTransaction)?.wait()? {
+            ResponsePayload::Done => Ok(InteractiveTransaction {
+                pipeline,
+                finished: false,
+            }),
+            payload => Err(unexpected("begin", payload)),
+        }
+    }
+
+    /// Collects a deposit of `amount` to `account`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(usize)` - How many operations the transaction holds now.
+    /// * `Err(BankError)` - If the transaction is no longer open.
+    pub fn deposit(&self, account: impl Into<AccountRef>, amount: u32) -> Result<usize, BankError> {
+        self.collect(Command::IncreaseAccount(account.into(), amount))
+    }
+
+    /// Collects a withdrawal of `amount` from `account`; the balance is
+    /// only checked at commit.
+    pub fn withdraw(
+        &self,
+        account: impl Into<AccountRef>,
+        amount: u32,
+    ) -> Result<usize, BankError> {
+        self.collect(Command::DecreaseAccount(account.into(), amount))
+    }
+
+    /// Collects a transfer of `amount` from `from` to `to`.
+    pub fn transfer(
+        &self,
+        from: impl Into<AccountRef>,
+        to: impl Into<AccountRef>,
+        amount: u32,
+    ) -> Result<usize, BankError> {
+        self.collect(Command::Transfer {
+            from: from.into(),
+            to: to.into(),
+            amount,
+        })
+    }
+
+    /// Committed balance of `account`; a commit of someone else changing
+    /// it before this transaction commits makes the commit fail.
+    pub fn balance(&self, account: impl Into<AccountRef>) -> Result<u32, BankError> {
+        match self.send(Command::GetAccountBalance(account.into()))? {
+            ResponsePayload::AccountBalance(balance) => Ok(balance),
+            payload => Err(unexpected("balance", payload)),
+        }
+    }
+
+    /// Applies the collected operations.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<usize>)` - The history operation ID of each operation, in the order they were collected.
+    /// * `Err(BankError)` - `BankError::TransactionConflict` if another commit touched an account the
+    ///   transaction used, `BankError::BatchFailed` if an operation failed; nothing was applied.
+    pub fn commit(mut self) -> Result<Vec<usize>, BankError> {
+        self.finished = true;
+        match self.send(Command::CommitTransaction)? {
+            ResponsePayload::Batch(operation_ids) => Ok(operation_ids),
+            payload => Err(unexpected("commit", payload)),
+        }
+    }
+
+    /// Drops the collected operations.
+    pub fn rollback(mut self) -> Result<(), BankError> {
+        self.finished = true;
+        match self.send(Command::RollbackTransaction)? {
+            ResponsePayload::Done => Ok(()),
+            payload => Err(unexpected("rollback", payload)),
+        }
+    }
+
+    fn collect(&self, command: Command) -> Result<usize, BankError> {
+        match self.send(command)? {
+            ResponsePayload::Buffered(operations) => Ok(operations),
+            payload => Err(unexpected("collect", payload)),
+        }
+    }
+
+    fn send(&self, command: Command) -> Response {
+        self.pipeline.send(command)?.wait()
+    }
+}
+
+impl Drop for InteractiveTransaction<'_> {
+    fn drop(&mut self) {
+        // Ответ не нужен: откат не может не удаться, а соединение могло закрыться
+        if !self.finished {
+            let _ = self.pipeline.send(Command::RollbackTransaction);
+        }
+    }
+}
@@ -1,21 +1,252 @@
-use std::io::{Read, Write};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{ErrorKind, Read, Write};
 use std::net::TcpStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use protocol_crate::{Command, Response, BankError, Operation};
+use protocol_crate::codec::{Serializer, WireFormat};
+use protocol_crate::digest;
+#[cfg(feature = "otlp")]
+use protocol_crate::otlp::{Span, SpanKind, Tracer};
+use protocol_crate::pipeline::MAX_MESSAGE_SIZE;
+use protocol_crate::{
+    AccountFilter, AccountId, AccountLimits, AccountRef, Alert, BankError, BatchOperation, Command,
+    CommandMetrics, ConsistencyReport, Dispute, DisputeId, HistoryDigest, HistoryProjection,
+    JobInfo, LockId, Operation, Page, PageRequest, PendingTransfer, PendingTransferId, ProposalId,
+    ProposalStatus, QueryRow, RateLimit, RateLimits, RemoteAccount, Response, ResponsePayload,
+    RestoreProgress, ServerInfo, ServerStats, Statement, TokenInfo, TransactionId, TransactionLeg,
+    VersionedBalance, MAX_COMMAND_SIZE,
+};
 
-const SERVER_ADDRESS: &str = "127.0.0.1:7878";
+mod account;
+mod diff;
+#[cfg(feature = "faults")]
+mod faults;
+mod interactive;
+mod pipeline;
+mod session;
+mod shards;
+mod socks;
+mod transaction;
+
+pub use account::AccountHandle;
+pub use diff::{diff, DiffReport};
+#[cfg(feature = "faults")]
+pub use faults::{Fault, Faults};
+pub use interactive::InteractiveTransaction;
+pub use pipeline::{CancelToken, PendingResponse, Pipeline};
+pub use protocol_crate::socket::{Endpoint, SocketOptions};
+pub use session::Session;
+pub use shards::ShardMap;
+pub use socks::Socks5Proxy;
+pub use transaction::TransactionBuilder;
+
+// Сколько операций отправлять одним Restore; следующая часть уходит после ответа
+const RESTORE_CHUNK_SIZE: usize = 10_000;
+// Сколько элементов запрашивать за раз, собирая весь список по страницам
+const PAGE_SIZE: usize = 1000;
 
 pub struct BankClient {
-    server_address: String,
+    // Имя хоста разрешается при каждом соединении
+    endpoint: Endpoint,
+    proxy: Option<Socks5Proxy>,
+    identity_token: Option<String>,
+    request_id: Option<String>,
+    deadline: Option<Duration>,
+    lock: Option<LockId>,
+    session: Option<Session>,
+    shards: Option<ShardMap>,
+    // Соединения с шардами по адресу, каждое помнит свой рабочий адрес
+    shard_endpoints: BTreeMap<String, Endpoint>,
+    format: WireFormat,
+    max_message_size: usize,
+    socket: SocketOptions,
+    #[cfg(feature = "otlp")]
+    tracer: Option<Tracer>,
+    #[cfg(feature = "faults")]
+    faults: Option<Faults>,
 }
 
 impl BankClient {
+    /// Client of the server at `x`, an `IP:port` or a `host:port`. A host
+    /// name is resolved for every connection and all its addresses, IPv4 and
+    /// IPv6, are tried in turn, starting with the one that worked last.
     pub fn new(x: &str) -> Self {
         BankClient {
-            server_address: x.to_string(),
+            endpoint: Endpoint::new(x),
+            proxy: None,
+            identity_token: None,
+            request_id: None,
+            deadline: None,
+            lock: None,
+            session: None,
+            shards: None,
+            shard_endpoints: BTreeMap::new(),
+            format: WireFormat::default(),
+            max_message_size: MAX_MESSAGE_SIZE,
+            socket: SocketOptions::default(),
+            #[cfg(feature = "otlp")]
+            tracer: None,
+            #[cfg(feature = "faults")]
+            faults: None,
+        }
+    }
+
+    /// Talks to the server in `format` instead of the default one.
+    pub fn with_format(mut self, format: WireFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Asks the server for its wire formats and switches to the fastest
+    /// one both sides support.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Self)` - The client talking in the negotiated format.
+    /// * `Err(BankError)` - If the handshake failed.
+    pub fn negotiate_format(mut self) -> Result<Self, BankError> {
+        let info = self.server_info()?;
+        // Ответ пришел в текущем формате, так что он общий в любом случае
+        if let Some(format) = WireFormat::negotiate(&info.encodings) {
+            self.format = format;
+        }
+        Ok(self)
+    }
+
+    /// Sends commands on one account, and transfers within one shard,
+    /// straight to the shard in `shards` that owns the account instead of
+    /// through the router the client was created for. Other commands still
+    /// go to the router.
+    pub fn with_shards(mut self, shards: ShardMap) -> Self {
+        self.shard_endpoints = shards
+            .shards()
+            .iter()
+            .map(|address| (address.clone(), Endpoint::new(address)))
+            .collect();
+        self.shards = Some(shards);
+        self
+    }
+
+    /// Asks the router for its shards and sends commands on one account to
+    /// them directly from then on; see [`BankClient::with_shards`].
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Self)` - The client holding the shard map.
+    /// * `Err(BankError)` - If the router could not be asked.
+    pub fn discover_shards(self) -> Result<Self, BankError> {
+        let shards = self.get_shard_map()?;
+        Ok(self.with_shards(ShardMap::new(shards)))
+    }
+
+    /// # Returns
+    ///
+    /// * `Ok(Vec<String>)` - The addresses of the shards behind the router,
+    ///   or the server's own address if it is not a router.
+    /// * `Err(BankError)` - If the server could not be asked.
+    pub fn get_shard_map(&self) -> Result<Vec<String>, BankError> {
+        match self.send_command(Command::GetShardMap)? {
+            ResponsePayload::ShardMap(shards) => Ok(shards),
+            payload => Err(unexpected("get_shard_map", payload)),
         }
     }
 
+    /// The format commands are sent in.
+    pub fn format(&self) -> WireFormat {
+        self.format
+    }
+
+    /// Refuses to send pipelined commands or accept responses larger than
+    /// `limit` bytes, failing with `BankError::MessageTooLarge` instead.
+    /// Single commands are always limited to `MAX_COMMAND_SIZE`.
+    pub fn with_max_message_size(mut self, limit: usize) -> Self {
+        self.max_message_size = limit;
+        self
+    }
+
+    /// Opens every connection with `options`, e.g. with Nagle's algorithm
+    /// off for latency or keepalive probes for long-lived pipelines.
+    pub fn with_socket_options(mut self, options: SocketOptions) -> Self {
+        self.socket = options;
+        self
+    }
+
+    /// Reaches the server through a SOCKS5 proxy, e.g. a bastion host;
+    /// pipelines opened by the client go through it too.
+    pub fn with_socks5_proxy(mut self, proxy: Socks5Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Sends every command on behalf of the identity that owns `token`, so
+    /// accounts owned by that identity can be operated.
+    pub fn with_identity(mut self, token: &str) -> Self {
+        self.identity_token = Some(token.to_string());
+        self
+    }
+
+    /// Tags every command with `request_id` instead of a new ID per command,
+    /// e.g. to carry the ID of the caller's own request.
+    pub fn with_request_id(mut self, request_id: &str) -> Self {
+        self.request_id = Some(request_id.to_string());
+        self
+    }
+
+    /// Gives the server `timeout` to answer each command. The server skips a
+    /// command it could not start in time and the client stops waiting, both
+    /// failing with `BankError::DeadlineExceeded`.
+    pub fn with_deadline(mut self, timeout: Duration) -> Self {
+        self.deadline = Some(timeout);
+        self
+    }
+
+    /// Sends every command as the holder of `lock`, so the locked account
+    /// can be changed.
+    pub fn with_lock(mut self, lock: LockId) -> Self {
+        self.lock = Some(lock);
+        self
+    }
+
+    /// Reads through `session`: every command waits until the server has
+    /// caught up with the last command of the session. A replica that
+    /// cannot catch up fails with `BankError::NotPrimary`, naming the
+    /// primary to read from instead. Pipelines do not use the session.
+    pub fn with_session(mut self, session: Session) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    /// Records a client span for every command with `tracer`. The request ID
+    /// becomes the span's `traceparent`, so the server span joins the trace;
+    /// a `traceparent` given to `with_request_id` is used as the parent.
+    #[cfg(feature = "otlp")]
+    pub fn with_tracer(mut self, tracer: Tracer) -> Self {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    /// Breaks the exchanges of the following commands as scripted by
+    /// `faults`; see [`Faults`].
+    #[cfg(feature = "faults")]
+    pub fn with_faults(mut self, faults: Faults) -> Self {
+        self.faults = Some(faults);
+        self
+    }
+
+    /// Handle to operate the given `account` without naming it every time.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The name or ID of the account.
+    ///
+    /// # Returns
+    ///
+    /// * `AccountHandle` - The handle; the account is not checked to exist.
+    pub fn account(&self, account: impl Into<AccountRef>) -> AccountHandle<'_> {
+        AccountHandle::new(self, account.into())
+    }
+
     /// Creates a new account with the given `account` name.
     ///
     /// # Arguments
@@ -24,14 +255,13 @@ impl BankClient {
     ///
     /// # Returns
     ///
-    /// * `Ok(usize)` - The ID of the newly created account.
+    /// * `Ok(AccountId)` - The ID of the newly created account.
     /// * `Err(BankError)` - If the account already exists or there was an error during the process.
     ///
-    pub fn create_account(&self, account: String) -> Result<usize, BankError> {
-        let response = self.send_command(Command::CreateAccount(account));
-        match response {
-            Response::Account(result) => Ok(result?),
-            _ => panic!("Unexpected create_account response: {:?}", response),
+    pub fn create_account(&self, account: String) -> Result<AccountId, BankError> {
+        match self.send_command(Command::CreateAccount(account))? {
+            ResponsePayload::Account(id) => Ok(id),
+            payload => Err(unexpected("create_account", payload)),
         }
     }
 
@@ -39,18 +269,21 @@ impl BankClient {
     ///
     /// # Arguments
     ///
-    /// * `account` - The name of the account to be increased.
+    /// * `account` - The name or ID of the account to be increased.
     /// * `amount` - The amount to be increased.
     ///
     /// # Returns
     ///
     /// * `Ok(usize)` - The ID of the newly created account.
     /// * `Err(BankError)` - If the account already exists or there was an error during the process.
-    pub fn increase_account(&self, account: String, amount: u32) -> Result<(), BankError> {
-        let response = self.send_command(Command::IncreaseAccount(account, amount));
-        match response {
-            Response::OperationResult(Ok(_)) => Ok(()),
-            _ => panic!("Unexpected increase_account response: {:?}", response),
+    pub fn increase_account(
+        &self,
+        account: impl Into<AccountRef>,
+        amount: u32,
+    ) -> Result<(), BankError> {
+        match self.send_command(Command::IncreaseAccount(account.into(), amount))? {
+            ResponsePayload::OperationId(_) => Ok(()),
+            payload => Err(unexpected("increase_account", payload)),
         }
     }
 
@@ -58,18 +291,50 @@ impl BankClient {
     ///
     /// # Arguments
     ///
-    /// * `account` - The name of the account to be decreased.
+    /// * `account` - The name or ID of the account to be decreased.
     /// * `amount` - The amount to be decreased.
     ///
     /// # Returns
     ///
     /// * `Ok(usize)` - The ID of the newly created account.
     /// * `Err(BankError)` - If the account already exists or there was an error during the process.
-    pub fn decrease_account(&self, account: String, amount: u32) -> Result<(), BankError> {
-        let response = self.send_command(Command::DecreaseAccount(account, amount));
-        match response {
-            Response::OperationResult(Ok(_)) => Ok(()),
-            _ => panic!("Unexpected decrease_account response: {:?}", response),
+    pub fn decrease_account(
+        &self,
+        account: impl Into<AccountRef>,
+        amount: u32,
+    ) -> Result<(), BankError> {
+        match self.send_command(Command::DecreaseAccount(account.into(), amount))? {
+            ResponsePayload::OperationId(_) => Ok(()),
+            payload => Err(unexpected("decrease_account", payload)),
+        }
+    }
+
+    /// Decreases the balance of the given `account` only if at least
+    /// `min_after` stays on it; the check and the decrease happen at once.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The name or ID of the account to be decreased.
+    /// * `amount` - The amount to be decreased.
+    /// * `min_after` - The smallest balance allowed after the decrease.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the balance was decreased.
+    /// * `Err(BankError)` - `BankError::BelowMinimumBalance` if less than `min_after` would stay.
+    pub fn decrease_if_balance_at_least(
+        &self,
+        account: impl Into<AccountRef>,
+        amount: u32,
+        min_after: u32,
+    ) -> Result<(), BankError> {
+        match self.send_command(Command::DecreaseIfBalanceAtLeast {
+            account: account.into(),
+            amount,
+            min_after,
+        })? {
+            ResponsePayload::OperationId(_) => Ok(()),
+            payload => Err(unexpected("decrease_if_balance_at_least", payload)),
         }
     }
 
@@ -77,74 +342,529 @@ impl BankClient {
     ///
     /// # Arguments
     ///
-    /// * `from` - The name of the account to transfer from.
-    /// * `to` - The name of the account to transfer to.
+    /// * `from` - The name or ID of the account to transfer from.
+    /// * `to` - The name or ID of the account to transfer to.
     /// * `amount` - The amount to be transferred.
     ///
     /// # Returns
     ///
     /// * `Ok(usize)` - The ID of the newly created account.
     /// * `Err(BankError)` - If the account already exists or there was an error during the process.
-    pub fn transfer(&self, from: String, to: String, amount: u32) -> Result<(), BankError> {
-        let response = self.send_command(Command::Transfer { from, to, amount });
-        match response {
-            Response::TransferResult(Ok(_)) => Ok(()),
-            Response::TransferResult(Err(error)) => Err(error),
-            _ => panic!("Unexpected transfer response: {:?}", response),
+    ///   `BankError::TransferPending` if the amount is above the approval threshold of the server:
+    ///   the amount is held and the transfer waits for an admin.
+    pub fn transfer(
+        &self,
+        from: impl Into<AccountRef>,
+        to: impl Into<AccountRef>,
+        amount: u32,
+    ) -> Result<(), BankError> {
+        match self.send_command(Command::Transfer {
+            from: from.into(),
+            to: to.into(),
+            amount,
+        })? {
+            ResponsePayload::Done => Ok(()),
+            payload => Err(unexpected("transfer", payload)),
+        }
+    }
+
+    /// Transfers money unless the `from` account changed since it was read.
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - The name or ID of the account to transfer from.
+    /// * `to` - The name or ID of the account to transfer to.
+    /// * `amount` - The amount to be transferred.
+    /// * `expected_version` - The version `get_versioned_balance` returned for `from`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the transfer was successful.
+    /// * `Err(BankError)` - `BankError::Conflict` if `from` is at another version by now.
+    pub fn transfer_if(
+        &self,
+        from: impl Into<AccountRef>,
+        to: impl Into<AccountRef>,
+        amount: u32,
+        expected_version: u64,
+    ) -> Result<(), BankError> {
+        match self.send_command(Command::TransferIf {
+            from: from.into(),
+            to: to.into(),
+            amount,
+            expected_version,
+        })? {
+            ResponsePayload::Done => Ok(()),
+            payload => Err(unexpected("transfer_if", payload)),
+        }
+    }
+
+    /// Transfers money from a local account to an account on another bank server.
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - The name or ID of the local account to transfer from.
+    /// * `to` - The address of the remote server and the account on it.
+    /// * `amount` - The amount to be transferred.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The money was debited locally and credited on the remote server.
+    /// * `Err(BankError)` - If either side rejected the transfer or the remote server is unavailable.
+    ///   If the remote server may already have credited the money, the error says the transfer is
+    ///   in doubt and the amount stays held on `from` until an operator resolves it.
+    pub fn remote_transfer(
+        &self,
+        from: impl Into<AccountRef>,
+        to: RemoteAccount,
+        amount: u32,
+    ) -> Result<(), BankError> {
+        match self.send_command(Command::RemoteTransfer {
+            from: from.into(),
+            to,
+            amount,
+        })? {
+            ResponsePayload::Done => Ok(()),
+            payload => Err(unexpected("remote_transfer", payload)),
+        }
+    }
+
+    /// Locks `account` so other clients can not change it until the lock is
+    /// released or `lease` runs out. The client changes it with
+    /// [`BankClient::with_lock`].
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The name or ID of the account.
+    /// * `lease` - How long the lock holds unless released.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(LockId)` - The ID of the lock.
+    /// * `Err(BankError)` - `BankError::AccountLocked` if another lock on the account is in force.
+    pub fn lock_account(
+        &self,
+        account: impl Into<AccountRef>,
+        lease: Duration,
+    ) -> Result<LockId, BankError> {
+        match self.send_command(Command::LockAccount {
+            account: account.into(),
+            lease_ms: lease.as_millis() as u64,
+        })? {
+            ResponsePayload::Lock(lock) => Ok(lock),
+            payload => Err(unexpected("lock_account", payload)),
+        }
+    }
+
+    /// Locks all of `accounts` with one lock, e.g. before a batch or a
+    /// transaction touching them; either all of them are locked or none.
+    ///
+    /// # Arguments
+    ///
+    /// * `accounts` - The names or IDs of the accounts.
+    /// * `lease` - How long the lock holds unless released.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(LockId)` - The ID of the lock.
+    /// * `Err(BankError)` - `BankError::AccountLocked` with the first account another lock is in force on.
+    pub fn lock_accounts(
+        &self,
+        accounts: Vec<AccountRef>,
+        lease: Duration,
+    ) -> Result<LockId, BankError> {
+        match self.send_command(Command::LockAccounts {
+            accounts,
+            lease_ms: lease.as_millis() as u64,
+        })? {
+            ResponsePayload::Lock(lock) => Ok(lock),
+            payload => Err(unexpected("lock_accounts", payload)),
+        }
+    }
+
+    /// Releases a lock taken by [`BankClient::lock_account`] or
+    /// [`BankClient::lock_accounts`].
+    ///
+    /// # Arguments
+    ///
+    /// * `lock` - The ID of the lock.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The account is unlocked.
+    /// * `Err(BankError)` - `BankError::LockDoesNotExist` if the lease has already run out.
+    pub fn unlock_account(&self, lock: LockId) -> Result<(), BankError> {
+        match self.send_command(Command::UnlockAccount(lock))? {
+            ResponsePayload::Done => Ok(()),
+            payload => Err(unexpected("unlock_account", payload)),
+        }
+    }
+
+    /// Runs a multi-leg transaction coordinated by the connected server.
+    ///
+    /// # Arguments
+    ///
+    /// * `legs` - Debits and credits on accounts of any servers; they must add up to zero.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(TransactionId)` - Every leg was applied.
+    /// * `Err(BankError)` - No leg was applied.
+    pub fn transaction(&self, legs: Vec<TransactionLeg>) -> Result<TransactionId, BankError> {
+        match self.send_command(Command::Transaction(legs))? {
+            ResponsePayload::Transaction(transaction) => Ok(transaction),
+            payload => Err(unexpected("transaction", payload)),
+        }
+    }
+
+    /// Applies the given `operations` in order, all of them or none.
+    ///
+    /// # Arguments
+    ///
+    /// * `operations` - The deposits, withdrawals and transfers to be applied.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<usize>)` - The history operation ID of each operation.
+    /// * `Err(BankError)` - `BankError::BatchFailed` with the index of the operation that failed; nothing was applied.
+    ///   A transfer above the approval threshold of the server fails it with `BankError::LimitExceeded`.
+    pub fn batch(&self, operations: Vec<BatchOperation>) -> Result<Vec<usize>, BankError> {
+        match self.send_command(Command::Batch(operations))? {
+            ResponsePayload::Batch(operation_ids) => Ok(operation_ids),
+            payload => Err(unexpected("batch", payload)),
+        }
+    }
+
+    /// Pays each of `payees` from `from`, e.g. a payroll, with a transfer
+    /// per payee; the transfers are applied together or not at all.
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - The name or ID of the account that pays.
+    /// * `payees` - The accounts to be paid and how much each gets.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<Result<usize, BankError>>)` - For each payee the history operation ID of its transfer,
+    ///   or why it was not paid; payees that were not paid are not taken from `from`. A payee above the
+    ///   approval threshold of the server gets `BankError::TransferPending` and waits for an admin.
+    /// * `Err(BankError)` - `BankError::InsufficientFunds` if `from` can not pay the rest; nobody was paid.
+    pub fn bulk_transfer(
+        &self,
+        from: impl Into<AccountRef>,
+        payees: Vec<(AccountRef, u32)>,
+    ) -> Result<Vec<Result<usize, BankError>>, BankError> {
+        match self.send_command(Command::BulkTransfer {
+            from: from.into(),
+            payees,
+        })? {
+            ResponsePayload::BulkTransfer(results) => Ok(results),
+            payload => Err(unexpected("bulk_transfer", payload)),
         }
     }
 
+    /// Starts a batch of operations applied all or nothing; see [`TransactionBuilder`].
+    pub fn transaction_builder(&self) -> TransactionBuilder<'_> {
+        TransactionBuilder::new(self)
+    }
+
     /// Returns the account history of the given `account`.
     ///
     /// # Arguments
     ///
-    /// * `account` - The name of the account to be returned.
+    /// * `account` - The name or ID of the account to be returned.
     ///
     /// # Returns
     ///
     /// * `Ok(Vec<Operation>)` - The account history of the given `account`.
     /// * `Err(BankError)` - If the account does not exist or there was an error during the process.
-    pub fn get_account_balance(&self, account: String) -> Result<u32, BankError> {
-        let response = self.send_command(Command::GetAccountBalance(account));
-        match response {
-            Response::AccountBalance(Ok(result)) => Ok(result),
-            _ => panic!("Unexpected get_account_balance response: {:?}", response),
+    pub fn get_account_balance(&self, account: impl Into<AccountRef>) -> Result<u32, BankError> {
+        match self.send_command(Command::GetAccountBalance(account.into()))? {
+            ResponsePayload::AccountBalance(balance) => Ok(balance),
+            payload => Err(unexpected("get_account_balance", payload)),
         }
     }
 
-    /// Returns the account history of the given `account`.
+    /// Returns the balance the given `account` had before an operation.
     ///
     /// # Arguments
     ///
-    /// * `account` - The name of the account to be returned.
+    /// * `account` - The name or ID of the account.
+    /// * `operation_id` - The ID of the operation in the bank history.
     ///
     /// # Returns
     ///
-    /// * `Ok(Vec<Operation>)` - The account history of the given `account`.
+    /// * `Ok(u32)` - The balance after all operations before `operation_id`.
     /// * `Err(BankError)` - If the account does not exist or there was an error during the process.
-    pub fn get_history(&self) -> Vec<Operation> {
-        let response = self.send_command(Command::GetHistory);
-        match response {
-            Response::History(result) => result,
-            _ => panic!("Unexpected get_history response: {:?}", response),
+    pub fn get_balance_at(
+        &self,
+        account: impl Into<AccountRef>,
+        operation_id: usize,
+    ) -> Result<u32, BankError> {
+        match self.send_command(Command::GetBalanceAt {
+            account: account.into(),
+            operation_id,
+        })? {
+            ResponsePayload::AccountBalance(balance) => Ok(balance),
+            payload => Err(unexpected("get_balance_at", payload)),
         }
     }
 
-    /// Returns the account history of the given `account`.
+    /// Returns the balance of the given `account` with its version, to make
+    /// a conditional transfer later.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The name or ID of the account.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(VersionedBalance)` - The balance and the version of the account.
+    /// * `Err(BankError)` - If the account does not exist or there was an error during the process.
+    pub fn get_versioned_balance(
+        &self,
+        account: impl Into<AccountRef>,
+    ) -> Result<VersionedBalance, BankError> {
+        match self.send_command(Command::GetVersionedBalance(account.into()))? {
+            ResponsePayload::VersionedBalance(balance) => Ok(balance),
+            payload => Err(unexpected("get_versioned_balance", payload)),
+        }
+    }
+
+    /// Returns the total balance of the given `account` and all its sub-accounts.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The name or ID of the account, e.g. `Alice` for `Alice/savings` and `Alice/card`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(u64)` - The sum of the balances of the subtree.
+    /// * `Err(BankError)` - If the account does not exist.
+    pub fn get_subtree_balance(&self, account: impl Into<AccountRef>) -> Result<u64, BankError> {
+        match self.send_command(Command::GetSubtreeBalance(account.into()))? {
+            ResponsePayload::SubtreeBalance(balance) => Ok(balance),
+            payload => Err(unexpected("get_subtree_balance", payload)),
+        }
+    }
+
+    /// Returns the whole bank history, fetched page by page.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<Operation>)` - The operations of the history, oldest first.
+    /// * `Err(BankError)` - If there was an error during the process.
+    pub fn get_history(&self) -> Result<Vec<Operation>, BankError> {
+        all_pages(|page| self.get_history_paged(page))
+    }
+
+    /// Searches the bank history without downloading it.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - Words that the names or metadata of the accounts an operation touches must contain.
+    /// * `limit` - The maximum number of operations to return.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<(usize, Operation)>)` - The matching operations with their IDs, oldest first.
+    /// * `Err(BankError)` - If there was an error during the process.
+    pub fn search_history(
+        &self,
+        query: &str,
+        limit: usize,
+    ) -> Result<Vec<(usize, Operation)>, BankError> {
+        match self.send_command(Command::SearchHistory {
+            query: query.to_string(),
+            limit,
+        })? {
+            ResponsePayload::Operations(operations) => Ok(operations),
+            payload => Err(unexpected("search_history", payload)),
+        }
+    }
+
+    /// Filters, groups and aggregates the bank history on the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query, e.g. `sum where kind = transfer and from = Alice group by day`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<QueryRow>)` - One row per group, ordered by group; a single row without `group by`.
+    /// * `Err(BankError)` - `InvalidQuery` if the query can not be parsed.
+    pub fn query_history(&self, query: &str) -> Result<Vec<QueryRow>, BankError> {
+        match self.send_command(Command::QueryHistory {
+            query: query.to_string(),
+        })? {
+            ResponsePayload::QueryRows(rows) => Ok(rows),
+            payload => Err(unexpected("query_history", payload)),
+        }
+    }
+
+    /// Returns a page of the bank history.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The index of the first operation of the page.
+    /// * `limit` - The maximum number of operations in the page.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<Operation>)` - The operations of the page; empty past the end of the history.
+    /// * `Err(BankError)` - If the server sent an unexpected response.
+    #[deprecated(note = "use `get_history_paged` with `PageRequest::at`")]
+    #[allow(deprecated)]
+    pub fn get_history_page(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<Operation>, BankError> {
+        match self.send_command(Command::GetHistoryPage { offset, limit })? {
+            ResponsePayload::History(page) => Ok(page),
+            payload => Err(unexpected("get_history_page", payload)),
+        }
+    }
+
+    /// Returns a page of the bank history.
+    ///
+    /// # Arguments
+    ///
+    /// * `page` - The cursor from the previous page, if any, and the page size.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Page<Operation>)` - The operations of the page, oldest first, and the cursor of the next page.
+    /// * `Err(BankError)` - `BankError::InvalidCursor` if the cursor did not come from this listing.
+    pub fn get_history_paged(&self, page: PageRequest) -> Result<Page<Operation>, BankError> {
+        match self.send_command(Command::GetHistoryPaged { page })? {
+            ResponsePayload::HistoryPage(page) => Ok(page),
+            payload => Err(unexpected("get_history_paged", payload)),
+        }
+    }
+
+    /// Returns the account history of the given `account`, fetched page by page.
     ///
     /// # Arguments
     ///
-    /// * `account` - The name of the account to be returned.
+    /// * `account` - The name or ID of the account to be returned.
     ///
     /// # Returns
     ///
     /// * `Ok(Vec<Operation>)` - The account history of the given `account`.
     /// * `Err(BankError)` - If the account does not exist or there was an error during the process.
-    pub fn account_history(&self, account: String) -> Vec<Operation> {
-        let response = self.send_command(Command::GetAccountHistory(account));
-        match response {
-            Response::AccountHistory(result) => result.unwrap(),
-            _ => panic!("Unexpected account_history response: {:?}", response),
+    pub fn account_history(
+        &self,
+        account: impl Into<AccountRef>,
+    ) -> Result<Vec<Operation>, BankError> {
+        let account = account.into();
+        all_pages(|page| self.account_history_paged(account.clone(), page))
+    }
+
+    /// Returns a page of the account history of the given `account`.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The name or ID of the account.
+    /// * `page` - The cursor from the previous page, if any, and the page size.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Page<Operation>)` - The operations of the page, oldest first, and the cursor of the next page.
+    /// * `Err(BankError)` - If the account does not exist or the cursor did not come from this listing.
+    pub fn account_history_paged(
+        &self,
+        account: impl Into<AccountRef>,
+        page: PageRequest,
+    ) -> Result<Page<Operation>, BankError> {
+        match self.send_command(Command::GetAccountHistoryPaged {
+            account: account.into(),
+            page,
+        })? {
+            ResponsePayload::HistoryPage(page) => Ok(page),
+            payload => Err(unexpected("account_history_paged", payload)),
+        }
+    }
+
+    /// Returns a page of the amounts the operations of the history moved,
+    /// without the rest of the operations.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The name or ID of the account whose history to take; the whole history if `None`.
+    /// * `page` - The cursor from the previous page, if any, and the page size.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Page<u32>)` - The amount of every operation of the page, 0 for those without money.
+    /// * `Err(BankError)` - If the account does not exist or the cursor did not come from this listing.
+    pub fn history_amounts(
+        &self,
+        account: Option<AccountRef>,
+        page: PageRequest,
+    ) -> Result<Page<u32>, BankError> {
+        match self.send_command(Command::GetProjectedHistory {
+            account,
+            projection: HistoryProjection::Amounts,
+            page,
+        })? {
+            ResponsePayload::AmountsPage(page) => Ok(page),
+            payload => Err(unexpected("history_amounts", payload)),
+        }
+    }
+
+    /// Returns a page of the accounts on both sides of the operations of the
+    /// history, without the rest of the operations.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The name or ID of the account whose history to take; the whole history if `None`.
+    /// * `page` - The cursor from the previous page, if any, and the page size.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Page<Vec<String>>)` - The accounts of every operation of the page, the money source first.
+    /// * `Err(BankError)` - If the account does not exist or the cursor did not come from this listing.
+    pub fn history_counterparties(
+        &self,
+        account: Option<AccountRef>,
+        page: PageRequest,
+    ) -> Result<Page<Vec<String>>, BankError> {
+        match self.send_command(Command::GetProjectedHistory {
+            account,
+            projection: HistoryProjection::Counterparties,
+            page,
+        })? {
+            ResponsePayload::CounterpartiesPage(page) => Ok(page),
+            payload => Err(unexpected("history_counterparties", payload)),
+        }
+    }
+
+    /// Returns the statement of the given `account` for the period `[from_ts, to_ts)`.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The name or ID of the account.
+    /// * `from_ts` - Start of the period, unix seconds.
+    /// * `to_ts` - End of the period (exclusive), unix seconds.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Statement)` - Opening balance, operations with running balance and closing balance.
+    /// * `Err(BankError)` - If the account does not exist.
+    pub fn statement(
+        &self,
+        account: impl Into<AccountRef>,
+        from_ts: u64,
+        to_ts: u64,
+    ) -> Result<Statement, BankError> {
+        match self.send_command(Command::GetStatement {
+            account: account.into(),
+            from_ts,
+            to_ts,
+        })? {
+            ResponsePayload::Statement(statement) => Ok(statement),
+            payload => Err(unexpected("statement", payload)),
         }
     }
 
@@ -153,37 +873,1167 @@ impl BankClient {
     /// # Arguments
     ///
     /// * `operations` - The operations to be restored.
-    pub fn restore(&self, operations: Vec<Operation>) {
-        let response = self.send_command(Command::Restore(operations));
-        match response {
-            Response::Restore => (),
-            _ => panic!("Unexpected account_history response: {:?}", response),
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - All operations were applied and the digest the server computed over them matches.
+    /// * `Err(BankError)` - If the operations are inconsistent with each other or the bank state, and
+    ///   nothing was applied; `BankError::InvalidHistory` if the server applied operations that differ
+    ///   from the sent ones.
+    pub fn restore(&self, operations: Vec<Operation>) -> Result<(), BankError> {
+        let expected = digest::history_digest(&operations);
+        match self.send_command(Command::Restore(operations))? {
+            ResponsePayload::Restored(applied) => check_restored(0, &expected, &applied),
+            payload => Err(unexpected("restore", payload)),
         }
     }
 
-    /// Sends a command to the server and waits for the response.
+    /// Restores the bank state from the given `operations`, sending them in
+    /// chunks over one connection and reporting the progress as the server
+    /// applies them. Each chunk is applied all or nothing; chunks before a
+    /// failed one stay applied.
     ///
     /// # Arguments
     ///
-    /// * `command` - The command to be sent.
+    /// * `operations` - The operations to be restored.
+    /// * `on_progress` - Called with the operations of `operations` applied so far
+    ///   and the history ID of the last one.
     ///
     /// # Returns
     ///
-    /// * `Response` - The response from the server.
-    fn send_command(&self, command: Command) -> Response {
-        let mut stream = TcpStream::connect(&self.server_address).unwrap();
-        let serialized = serde_json::to_string(&command).unwrap();
-        stream.write_all(serialized.as_bytes()).unwrap();
-
-        let mut buffer = [0; 512];
-        let n = stream.read(&mut buffer).unwrap();
-        let received_data = &buffer[..n];
-
-        let serde_result: Result<Response, serde_json::Error> =
-            serde_json::from_slice(received_data);
-        let Ok(response) = serde_result else {
-            panic!("Fail create_account response: {:?}", serde_result);
-        };
-        response
+    /// * `Ok(())` - If every operation was restored and the digests of all chunks match.
+    /// * `Err(BankError)` - If a chunk could not be restored; `BankError::InvalidHistory` if
+    ///   the server applied operations that differ from the sent ones.
+    pub fn restore_with_progress(
+        &self,
+        operations: Vec<Operation>,
+        mut on_progress: impl FnMut(RestoreProgress),
+    ) -> Result<(), BankError> {
+        let pipeline = self.pipeline()?;
+        let mut restored = 0;
+        for chunk in operations.chunks(RESTORE_CHUNK_SIZE) {
+            let pending = pipeline.send(Command::Restore(chunk.to_vec()))?;
+            let response = pending.wait_with_progress(|progress| {
+                on_progress(RestoreProgress {
+                    applied: restored + progress.applied,
+                    operation_id: progress.operation_id,
+                })
+            });
+            match response? {
+                ResponsePayload::Restored(applied) => {
+                    check_restored(restored, &digest::history_digest(chunk), &applied)?;
+                    restored += chunk.len();
+                }
+                payload => return Err(unexpected("restore_with_progress", payload)),
+            }
+        }
+        Ok(())
     }
+
+    /// Streams the operations added to the bank history as the server
+    /// makes them, over a connection of its own.
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - The history ID of the first operation to stream; `None` streams only new ones.
+    /// * `account` - The account whose operations to stream; `None` streams all of them.
+    /// * `on_event` - Called with the history ID and the operation, oldest first; the
+    ///   subscription is cancelled once it returns `false`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If `on_event` ended the subscription.
+    /// * `Err(BankError)` - If the account does not exist, the server ended the subscription
+    ///   or the connection closed.
+    pub fn subscribe(
+        &self,
+        from: Option<usize>,
+        account: Option<AccountRef>,
+        mut on_event: impl FnMut(usize, Operation) -> bool,
+    ) -> Result<(), BankError> {
+        let pipeline = self.pipeline()?;
+        let pending = pipeline.send(Command::Subscribe { from, account })?;
+        let cancel = pending.cancel_token();
+        let mut stopped = false;
+        let response = pending.wait_with_events(|events| {
+            for (id, operation) in events {
+                // Операции, пришедшие до отмены, уже не нужны
+                if !stopped && !on_event(id, operation) {
+                    stopped = true;
+                    let _ = cancel.cancel();
+                }
+            }
+        });
+        match response {
+            Err(e) if stopped && matches!(e.cause(), BankError::Cancelled) => Ok(()),
+            Err(e) => Err(e),
+            Ok(payload) => Err(unexpected("subscribe", payload)),
+        }
+    }
+
+    /// Asks the server to re-read its config file.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - An admin token from the current server config.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The new config is in effect.
+    /// * `Err(BankError)` - If the token is not accepted or the config file is invalid.
+    pub fn reload(&self, token: &str) -> Result<(), BankError> {
+        match self.send_command(Command::Reload {
+            token: token.to_string(),
+        })? {
+            ResponsePayload::Done => Ok(()),
+            payload => Err(unexpected("reload", payload)),
+        }
+    }
+
+    /// Turns the read-only maintenance mode of the server on or off.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - An admin token from the current server config.
+    /// * `enabled` - Whether mutating commands should be rejected.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The mode is switched.
+    /// * `Err(BankError)` - If the token is not accepted.
+    pub fn set_maintenance(&self, token: &str, enabled: bool) -> Result<(), BankError> {
+        match self.send_command(Command::SetMaintenance {
+            token: token.to_string(),
+            enabled,
+        })? {
+            ResponsePayload::Done => Ok(()),
+            payload => Err(unexpected("set_maintenance", payload)),
+        }
+    }
+
+    /// Sets the outflow limits of the given `account`.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - An admin token from the current server config.
+    /// * `account` - The name or ID of the account.
+    /// * `limits` - The new limits; they replace the previous ones.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The limits are in effect.
+    /// * `Err(BankError)` - If the token is not accepted or the account does not exist.
+    pub fn set_account_limits(
+        &self,
+        token: &str,
+        account: impl Into<AccountRef>,
+        limits: AccountLimits,
+    ) -> Result<(), BankError> {
+        match self.send_command(Command::SetAccountLimits {
+            token: token.to_string(),
+            account: account.into(),
+            limits,
+        })? {
+            ResponsePayload::OperationId(_) => Ok(()),
+            payload => Err(unexpected("set_account_limits", payload)),
+        }
+    }
+
+    /// Returns the outflow limits of the given `account`.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The name or ID of the account.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(AccountLimits)` - The limits; unset ones are `None`.
+    /// * `Err(BankError)` - If the account does not exist.
+    pub fn get_account_limits(
+        &self,
+        account: impl Into<AccountRef>,
+    ) -> Result<AccountLimits, BankError> {
+        match self.send_command(Command::GetAccountLimits(account.into()))? {
+            ResponsePayload::AccountLimits(limits) => Ok(limits),
+            payload => Err(unexpected("get_account_limits", payload)),
+        }
+    }
+
+    /// Sets a metadata field of the given `account`.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The name or ID of the account.
+    /// * `key` - The name of the field, e.g. `email`.
+    /// * `value` - The new value; `None` removes the field.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The field was updated.
+    /// * `Err(BankError)` - If the account does not exist.
+    pub fn set_account_metadata(
+        &self,
+        account: impl Into<AccountRef>,
+        key: &str,
+        value: Option<&str>,
+    ) -> Result<(), BankError> {
+        match self.send_command(Command::SetAccountMetadata {
+            account: account.into(),
+            key: key.to_string(),
+            value: value.map(str::to_string),
+        })? {
+            ResponsePayload::Done => Ok(()),
+            payload => Err(unexpected("set_account_metadata", payload)),
+        }
+    }
+
+    /// Returns all metadata fields of the given `account`.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The name or ID of the account.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(BTreeMap<String, String>)` - The fields sorted by key.
+    /// * `Err(BankError)` - If the account does not exist.
+    pub fn get_account_metadata(
+        &self,
+        account: impl Into<AccountRef>,
+    ) -> Result<BTreeMap<String, String>, BankError> {
+        match self.send_command(Command::GetAccountMetadata(account.into()))? {
+            ResponsePayload::AccountMetadata(metadata) => Ok(metadata),
+            payload => Err(unexpected("get_account_metadata", payload)),
+        }
+    }
+
+    /// Replaces the owners of the given `account`.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The name or ID of the account.
+    /// * `owners` - The identities allowed to operate the account; empty makes it public.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The owners were replaced.
+    /// * `Err(BankError)` - If the client identity does not own the account or it does not exist.
+    pub fn set_account_owners(
+        &self,
+        account: impl Into<AccountRef>,
+        owners: BTreeSet<String>,
+    ) -> Result<(), BankError> {
+        match self.send_command(Command::SetAccountOwners {
+            account: account.into(),
+            owners,
+        })? {
+            ResponsePayload::Done => Ok(()),
+            payload => Err(unexpected("set_account_owners", payload)),
+        }
+    }
+
+    /// Raises an alert whenever the balance of `account` falls below `below`.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The name or ID of the account.
+    /// * `below` - The balance alerted below; `None` removes the rule.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The rule is in effect.
+    /// * `Err(BankError)` - If the client identity does not own the account or it does not exist.
+    pub fn set_alert_rule(
+        &self,
+        account: impl Into<AccountRef>,
+        below: Option<u32>,
+    ) -> Result<(), BankError> {
+        match self.send_command(Command::SetAlertRule {
+            account: account.into(),
+            below,
+        })? {
+            ResponsePayload::Done => Ok(()),
+            payload => Err(unexpected("set_alert_rule", payload)),
+        }
+    }
+
+    /// Returns the alerts of the accounts whose balance is below their rule.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<Alert>)` - The active alerts by account name.
+    /// * `Err(BankError)` - If there was an error during the process.
+    pub fn alerts(&self) -> Result<Vec<Alert>, BankError> {
+        match self.send_command(Command::ListAlerts)? {
+            ResponsePayload::Alerts(alerts) => Ok(alerts),
+            payload => Err(unexpected("alerts", payload)),
+        }
+    }
+
+    /// Returns the owners of the given `account`.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The name or ID of the account.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(BTreeSet<String>)` - The owner identities; empty for a public account.
+    /// * `Err(BankError)` - If the account does not exist.
+    pub fn get_account_owners(
+        &self,
+        account: impl Into<AccountRef>,
+    ) -> Result<BTreeSet<String>, BankError> {
+        match self.send_command(Command::GetAccountOwners(account.into()))? {
+            ResponsePayload::AccountOwners(owners) => Ok(owners),
+            payload => Err(unexpected("get_account_owners", payload)),
+        }
+    }
+
+    /// Replaces the tags of the given `account`.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - An admin token from the current server config.
+    /// * `account` - The name or ID of the account.
+    /// * `tags` - The new tags, e.g. `test`, `internal` or `customer`; empty removes them all.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The tags were replaced.
+    /// * `Err(BankError)` - If the token is not accepted or the account does not exist.
+    pub fn set_account_tags(
+        &self,
+        token: &str,
+        account: impl Into<AccountRef>,
+        tags: BTreeSet<String>,
+    ) -> Result<(), BankError> {
+        match self.send_command(Command::SetAccountTags {
+            token: token.to_string(),
+            account: account.into(),
+            tags,
+        })? {
+            ResponsePayload::Done => Ok(()),
+            payload => Err(unexpected("set_account_tags", payload)),
+        }
+    }
+
+    /// Returns the tags of the given `account`.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The name or ID of the account.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(BTreeSet<String>)` - The tags of the account.
+    /// * `Err(BankError)` - If the account does not exist.
+    pub fn get_account_tags(
+        &self,
+        account: impl Into<AccountRef>,
+    ) -> Result<BTreeSet<String>, BankError> {
+        match self.send_command(Command::GetAccountTags(account.into()))? {
+            ResponsePayload::AccountTags(tags) => Ok(tags),
+            payload => Err(unexpected("get_account_tags", payload)),
+        }
+    }
+
+    /// Returns the accounts carrying the given `tag`.
+    ///
+    /// # Arguments
+    ///
+    /// * `tag` - The tag to look for.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<(String, u32)>)` - The names and balances of the matching accounts.
+    /// * `Err(BankError)` - If the server sent an unexpected response.
+    pub fn find_accounts_by_tag(&self, tag: &str) -> Result<Vec<(String, u32)>, BankError> {
+        match self.send_command(Command::FindAccountsByTag(tag.to_string()))? {
+            ResponsePayload::AccountsByTag(accounts) => Ok(accounts),
+            payload => Err(unexpected("find_accounts_by_tag", payload)),
+        }
+    }
+
+    /// Returns the accounts with a balance within the given bounds.
+    ///
+    /// # Arguments
+    ///
+    /// * `min_balance` - The smallest balance to match, `None` for no lower bound.
+    /// * `max_balance` - The largest balance to match, `None` for no upper bound.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<(String, u32)>)` - The names and balances of the matching accounts.
+    /// * `Err(BankError)` - If there was an error during the process.
+    pub fn find_accounts(
+        &self,
+        min_balance: Option<u32>,
+        max_balance: Option<u32>,
+    ) -> Result<Vec<(String, u32)>, BankError> {
+        match self.send_command(Command::FindAccounts {
+            min_balance,
+            max_balance,
+        })? {
+            ResponsePayload::AccountsByBalance(accounts) => Ok(accounts),
+            payload => Err(unexpected("find_accounts", payload)),
+        }
+    }
+
+    /// Closes an account without money or unfinished transfers. Its history
+    /// stays, but no more operations are accepted on it.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The name or ID of the account.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(usize)` - The ID of the closing operation in the history.
+    /// * `Err(BankError)` - `BankError::AccountNotEmpty` if the account still has money.
+    pub fn close_account(&self, account: impl Into<AccountRef>) -> Result<usize, BankError> {
+        match self.send_command(Command::CloseAccount(account.into()))? {
+            ResponsePayload::OperationId(id) => Ok(id),
+            payload => Err(unexpected("close_account", payload)),
+        }
+    }
+
+    /// Returns the accounts with their balances in the order they were created,
+    /// fetched page by page.
+    ///
+    /// # Arguments
+    ///
+    /// * `filter` - Whether to return all accounts, only the active or only the closed ones.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<(String, u32)>)` - The names and balances of the accounts.
+    /// * `Err(BankError)` - If there was an error during the process.
+    pub fn list_accounts(&self, filter: AccountFilter) -> Result<Vec<(String, u32)>, BankError> {
+        all_pages(|page| self.list_accounts_paged(filter, page))
+    }
+
+    /// Returns a page of the accounts with their balances in the order they were created.
+    ///
+    /// # Arguments
+    ///
+    /// * `filter` - Whether to return all accounts, only the active or only the closed ones.
+    /// * `page` - The cursor from the previous page, if any, and the page size.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Page<(String, u32)>)` - The names and balances of the accounts and the cursor of the next page.
+    /// * `Err(BankError)` - `BankError::InvalidCursor` if the cursor did not come from this listing.
+    pub fn list_accounts_paged(
+        &self,
+        filter: AccountFilter,
+        page: PageRequest,
+    ) -> Result<Page<(String, u32)>, BankError> {
+        match self.send_command(Command::ListAccountsPaged { filter, page })? {
+            ResponsePayload::AccountsPage(page) => Ok(page),
+            payload => Err(unexpected("list_accounts_paged", payload)),
+        }
+    }
+
+    /// Returns the accounts with the largest balances.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - How many accounts to return.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<(String, u32)>)` - The names and balances of at most `n` accounts, largest first.
+    /// * `Err(BankError)` - If there was an error during the process.
+    pub fn top_accounts(&self, n: usize) -> Result<Vec<(String, u32)>, BankError> {
+        match self.send_command(Command::GetTopAccounts(n))? {
+            ResponsePayload::TopAccounts(accounts) => Ok(accounts),
+            payload => Err(unexpected("top_accounts", payload)),
+        }
+    }
+
+    /// Returns the hash chain digest of the bank history.
+    ///
+    /// # Arguments
+    ///
+    /// * `operations` - How many first operations to cover; `None` for the whole history.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(HistoryDigest)` - The digest and the number of operations it covers.
+    /// * `Err(BankError)` - If the server sent an unexpected response.
+    pub fn history_digest(&self, operations: Option<usize>) -> Result<HistoryDigest, BankError> {
+        match self.send_command(Command::GetHistoryDigest { operations })? {
+            ResponsePayload::HistoryDigest(digest) => Ok(digest),
+            payload => Err(unexpected("history_digest", payload)),
+        }
+    }
+
+    /// Asks the server to replay its history and compare the result with its live state.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - An admin token from the current server config.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(ConsistencyReport)` - The differences found; empty when the state is consistent.
+    /// * `Err(BankError)` - If the token is not accepted.
+    pub fn check_consistency(&self, token: &str) -> Result<ConsistencyReport, BankError> {
+        let command = Command::CheckConsistency {
+            token: token.to_string(),
+        };
+        match self.send_command(command)? {
+            ResponsePayload::ConsistencyReport(report) => Ok(report),
+            payload => Err(unexpected("check_consistency", payload)),
+        }
+    }
+
+    /// Returns the protocol version and wire formats of the server.
+    pub fn server_info(&self) -> Result<ServerInfo, BankError> {
+        match self.send_command(Command::Handshake)? {
+            ResponsePayload::ServerInfo(info) => Ok(info),
+            payload => Err(unexpected("server_info", payload)),
+        }
+    }
+
+    /// Returns per-command request statistics of the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - An admin token from the current server config.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<CommandMetrics>)` - Counters and latency percentiles of every command seen so far.
+    /// * `Err(BankError)` - If the token is not accepted.
+    pub fn metrics(&self, token: &str) -> Result<Vec<CommandMetrics>, BankError> {
+        match self.send_command(Command::GetMetrics {
+            token: token.to_string(),
+        })? {
+            ResponsePayload::Metrics(metrics) => Ok(metrics),
+            payload => Err(unexpected("metrics", payload)),
+        }
+    }
+
+    /// Returns how much memory the bank takes by the estimate of the server
+    /// and how far it may grow.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - An admin token from the current server config.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(ServerStats)` - Accounts and operations with their estimated bytes, and the capacity limits.
+    /// * `Err(BankError)` - If the token is not accepted.
+    pub fn stats(&self, token: &str) -> Result<ServerStats, BankError> {
+        match self.send_command(Command::GetStats {
+            token: token.to_string(),
+        })? {
+            ResponsePayload::Stats(stats) => Ok(stats),
+            payload => Err(unexpected("stats", payload)),
+        }
+    }
+
+    /// Asks the server to write a snapshot to its snapshot directory now.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - An admin token from the current server config.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(usize)` - The number of operations in the snapshot; it is written in the background.
+    /// * `Err(BankError)` - If the token is not accepted or the server has no snapshot directory.
+    pub fn write_snapshot(&self, token: &str) -> Result<usize, BankError> {
+        match self.send_command(Command::WriteSnapshot {
+            token: token.to_string(),
+        })? {
+            ResponsePayload::SnapshotQueued(operations) => Ok(operations),
+            payload => Err(unexpected("write_snapshot", payload)),
+        }
+    }
+
+    /// Adds a token to the server config file.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - An admin token from the current server config.
+    /// * `new_token` - The token to add.
+    /// * `identity` - The user the new token belongs to, if any.
+    /// * `admin` - Whether the new token may send admin commands.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The token is accepted from now on.
+    /// * `Err(BankError)` - If the token is not accepted, `new_token` is already in use or the
+    ///   server has no config file.
+    pub fn add_token(
+        &self,
+        token: &str,
+        new_token: &str,
+        identity: Option<&str>,
+        admin: bool,
+    ) -> Result<(), BankError> {
+        match self.send_command(Command::AddToken {
+            token: token.to_string(),
+            new_token: new_token.to_string(),
+            identity: identity.map(str::to_string),
+            admin,
+        })? {
+            ResponsePayload::Done => Ok(()),
+            payload => Err(unexpected("add_token", payload)),
+        }
+    }
+
+    /// Removes a token from the server config file.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - An admin token from the current server config.
+    /// * `revoked` - The token to remove; it can not be `token` itself.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The token is no longer accepted.
+    /// * `Err(BankError)` - If the token is not accepted or `revoked` is not in the config.
+    pub fn revoke_token(&self, token: &str, revoked: &str) -> Result<(), BankError> {
+        match self.send_command(Command::RevokeToken {
+            token: token.to_string(),
+            revoked: revoked.to_string(),
+        })? {
+            ResponsePayload::Done => Ok(()),
+            payload => Err(unexpected("revoke_token", payload)),
+        }
+    }
+
+    /// Returns the tokens of the server config, with only their beginnings shown.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - An admin token from the current server config.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<TokenInfo>)` - Admin and identity tokens, sorted.
+    /// * `Err(BankError)` - If the token is not accepted.
+    pub fn list_tokens(&self, token: &str) -> Result<Vec<TokenInfo>, BankError> {
+        match self.send_command(Command::ListTokens {
+            token: token.to_string(),
+        })? {
+            ResponsePayload::Tokens(tokens) => Ok(tokens),
+            payload => Err(unexpected("list_tokens", payload)),
+        }
+    }
+
+    /// Limits the rate of requests to an account, or to every account
+    /// without a limit of its own, in the server config file.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - An admin token from the current server config.
+    /// * `account` - The account to limit; `None` sets the default limit.
+    /// * `limit` - The new limit; `None` removes the limit.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The limit applies to the next request.
+    /// * `Err(BankError)` - If the token is not accepted or the limit is invalid.
+    pub fn set_rate_limit(
+        &self,
+        token: &str,
+        account: Option<&str>,
+        limit: Option<RateLimit>,
+    ) -> Result<(), BankError> {
+        match self.send_command(Command::SetRateLimit {
+            token: token.to_string(),
+            account: account.map(str::to_string),
+            limit,
+        })? {
+            ResponsePayload::Done => Ok(()),
+            payload => Err(unexpected("set_rate_limit", payload)),
+        }
+    }
+
+    /// Returns the request rate limits of the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - An admin token from the current server config.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(RateLimits)` - The default limit and the limits of accounts.
+    /// * `Err(BankError)` - If the token is not accepted.
+    pub fn rate_limits(&self, token: &str) -> Result<RateLimits, BankError> {
+        match self.send_command(Command::GetRateLimits {
+            token: token.to_string(),
+        })? {
+            ResponsePayload::RateLimits(limits) => Ok(limits),
+            payload => Err(unexpected("rate_limits", payload)),
+        }
+    }
+
+    /// Returns the scheduled jobs of the server with their next and last
+    /// runs.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - An admin token from the current server config.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<JobInfo>)` - The jobs in the order of the config.
+    /// * `Err(BankError)` - If the token is not accepted.
+    pub fn list_jobs(&self, token: &str) -> Result<Vec<JobInfo>, BankError> {
+        match self.send_command(Command::ListJobs {
+            token: token.to_string(),
+        })? {
+            ResponsePayload::Jobs(jobs) => Ok(jobs),
+            payload => Err(unexpected("list_jobs", payload)),
+        }
+    }
+
+    /// Makes a transfer that waits for approval.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - An admin token from the current server config.
+    /// * `id` - The ID from the `BankError::TransferPending` of the transfer.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the money moved.
+    /// * `Err(BankError)` - If there is no such pending transfer or the
+    ///   transfer failed; a failed transfer stays pending.
+    pub fn approve_transfer(&self, token: &str, id: PendingTransferId) -> Result<(), BankError> {
+        match self.send_command(Command::ApproveTransfer {
+            token: token.to_string(),
+            id,
+        })? {
+            ResponsePayload::Done => Ok(()),
+            payload => Err(unexpected("approve_transfer", payload)),
+        }
+    }
+
+    /// Drops a transfer that waits for approval and releases its funds.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - An admin token from the current server config.
+    /// * `id` - The ID from the `BankError::TransferPending` of the transfer.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the transfer was dropped.
+    /// * `Err(BankError)` - If there is no such pending transfer.
+    pub fn reject_transfer(&self, token: &str, id: PendingTransferId) -> Result<(), BankError> {
+        match self.send_command(Command::RejectTransfer {
+            token: token.to_string(),
+            id,
+        })? {
+            ResponsePayload::Done => Ok(()),
+            payload => Err(unexpected("reject_transfer", payload)),
+        }
+    }
+
+    /// Returns the transfers that wait for approval.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - An admin token from the current server config.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<PendingTransfer>)` - The pending transfers, oldest first.
+    /// * `Err(BankError)` - If the token is not accepted.
+    pub fn pending_transfers(&self, token: &str) -> Result<Vec<PendingTransfer>, BankError> {
+        match self.send_command(Command::ListPendingTransfers {
+            token: token.to_string(),
+        })? {
+            ResponsePayload::PendingTransfers(transfers) => Ok(transfers),
+            payload => Err(unexpected("pending_transfers", payload)),
+        }
+    }
+
+    /// Makes transfers out of `account` need several approvals.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - An admin token from the current server config.
+    /// * `account` - The name or ID of the account.
+    /// * `approvals` - How many distinct identities, the owners of the account if it has any, have
+    ///   to approve a transfer; up to one makes the account unprotected again.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - Transfers out of the account have to be proposed from now on.
+    /// * `Err(BankError)` - If the token is not accepted or the account does not exist.
+    pub fn set_required_approvals(
+        &self,
+        token: &str,
+        account: impl Into<AccountRef>,
+        approvals: u32,
+    ) -> Result<(), BankError> {
+        match self.send_command(Command::SetRequiredApprovals {
+            token: token.to_string(),
+            account: account.into(),
+            approvals,
+        })? {
+            ResponsePayload::Done => Ok(()),
+            payload => Err(unexpected("set_required_approvals", payload)),
+        }
+    }
+
+    /// Proposes a transfer out of an account that needs several approvals
+    /// and approves it on behalf of the client identity.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(ProposalStatus)` - The ID of the proposal and its approvals; the transfer is made
+    ///   already if one approval is enough.
+    /// * `Err(BankError)` - If an account does not exist or the identity may not approve.
+    pub fn propose_transfer(
+        &self,
+        from: impl Into<AccountRef>,
+        to: impl Into<AccountRef>,
+        amount: u32,
+    ) -> Result<ProposalStatus, BankError> {
+        match self.send_command(Command::ProposeTransfer {
+            from: from.into(),
+            to: to.into(),
+            amount,
+        })? {
+            ResponsePayload::Proposal(status) => Ok(status),
+            payload => Err(unexpected("propose_transfer", payload)),
+        }
+    }
+
+    /// Approves a proposed transfer on behalf of the client identity.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(ProposalStatus)` - The approvals so far; `operation` is set once the transfer is made.
+    /// * `Err(BankError)` - If there is no such proposal, the identity may not approve or has
+    ///   approved already, or the transfer failed; a failed approval can be given again.
+    pub fn approve_proposal(&self, id: ProposalId) -> Result<ProposalStatus, BankError> {
+        match self.send_command(Command::ApproveProposal(id))? {
+            ResponsePayload::Proposal(status) => Ok(status),
+            payload => Err(unexpected("approve_proposal", payload)),
+        }
+    }
+
+    /// Disputes an operation of the history, e.g. a payment the account
+    /// owner does not recognize.
+    ///
+    /// # Arguments
+    ///
+    /// * `operation` - The ID of the operation in the history.
+    /// * `reason` - Why the operation is disputed.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(DisputeId)` - The ID of the new dispute.
+    /// * `Err(BankError)` - If there is no such operation or the caller owns none of its accounts.
+    pub fn open_dispute(&self, operation: usize, reason: &str) -> Result<DisputeId, BankError> {
+        match self.send_command(Command::OpenDispute {
+            operation,
+            reason: reason.to_string(),
+        })? {
+            ResponsePayload::Dispute(id) => Ok(id),
+            payload => Err(unexpected("open_dispute", payload)),
+        }
+    }
+
+    /// Returns the open disputes, and the resolved ones too with `include_resolved`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<Dispute>)` - The disputes, oldest first.
+    /// * `Err(BankError)` - If there was an error during the process.
+    pub fn disputes(&self, include_resolved: bool) -> Result<Vec<Dispute>, BankError> {
+        match self.send_command(Command::ListDisputes { include_resolved })? {
+            ResponsePayload::Disputes(disputes) => Ok(disputes),
+            payload => Err(unexpected("disputes", payload)),
+        }
+    }
+
+    /// Closes a dispute, with `reverse` undoing the disputed operation.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - An admin token from the current server config.
+    /// * `id` - The ID of an open dispute.
+    /// * `reverse` - Whether to undo the disputed operation by a reversing one.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the dispute was closed.
+    /// * `Err(BankError)` - If there is no such open dispute or the operation
+    ///   could not be reversed; the dispute then stays open.
+    pub fn resolve_dispute(
+        &self,
+        token: &str,
+        id: DisputeId,
+        reverse: bool,
+    ) -> Result<(), BankError> {
+        match self.send_command(Command::ResolveDispute {
+            token: token.to_string(),
+            id,
+            reverse,
+        })? {
+            ResponsePayload::Done => Ok(()),
+            payload => Err(unexpected("resolve_dispute", payload)),
+        }
+    }
+
+    /// Replaces the name of an account and its sub-accounts with a pseudonym
+    /// in the account list and the whole history. Amounts and the history
+    /// structure stay; the renaming is appended to the history as an
+    /// `Operation::AnonymizeAccount`, and locks on the accounts move to the
+    /// new names.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - An admin token from the current server config.
+    /// * `account` - The name or ID of the account.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(String)` - The new name of the account.
+    /// * `Err(BankError)` - If there is no such account or the pseudonym is
+    ///   already taken.
+    pub fn anonymize_account(
+        &self,
+        token: &str,
+        account: impl Into<AccountRef>,
+    ) -> Result<String, BankError> {
+        match self.send_command(Command::AnonymizeAccount {
+            token: token.to_string(),
+            account: account.into(),
+        })? {
+            ResponsePayload::Pseudonym(name) => Ok(name),
+            payload => Err(unexpected("anonymize_account", payload)),
+        }
+    }
+
+    /// Sends any command as it is, e.g. one a binding for another language
+    /// decoded from JSON, and returns the response without interpreting it.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command to send.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(ResponsePayload)` - What the server answered the command with.
+    /// * `Err(BankError)` - If the server refused the command or could not be reached.
+    pub fn execute(&self, command: Command) -> Response {
+        self.send_command(command)
+    }
+
+    /// Opens a connection that many threads can send commands through
+    /// without waiting for each other; see [`Pipeline`].
+    pub fn pipeline(&self) -> Result<Pipeline, BankError> {
+        Pipeline::connect(
+            &self.endpoint,
+            self.proxy.as_ref(),
+            self.identity_token.clone(),
+            self.deadline,
+            self.format,
+            self.max_message_size,
+            self.socket,
+        )
+    }
+
+    /// Sends a command to the server and waits for the response.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command to be sent.
+    ///
+    /// # Returns
+    ///
+    /// * `Response` - The response from the server; an error carries the
+    ///   request ID the command was sent with.
+    fn send_command(&self, command: Command) -> Response {
+        #[cfg(feature = "otlp")]
+        if let Some(tracer) = &self.tracer {
+            let mut span =
+                Span::start(command.name(), SpanKind::Client, self.request_id.as_deref());
+            span.set_attribute("server.address", self.endpoint.address());
+            let request_id = span.traceparent();
+            let response = self
+                .exchange(command, &request_id)
+                .map_err(|e| e.with_request_id(&request_id));
+            span.end(response.as_ref().err().map(|e| format!("{:?}", e.cause())));
+            tracer.record(span);
+            return response;
+        }
+
+        let request_id = self.request_id.clone().unwrap_or_else(new_request_id);
+        self.exchange(command, &request_id)
+            .map_err(|e| e.with_request_id(&request_id))
+    }
+
+    fn exchange(&self, command: Command, request_id: &str) -> Response {
+        let endpoint = self
+            .shards
+            .as_ref()
+            .and_then(|shards| shards.route(&command))
+            .and_then(|shard| self.shard_endpoints.get(shard))
+            .unwrap_or(&self.endpoint);
+        let command = wrap(command, self.identity_token.as_deref(), self.deadline);
+        let command = match self.lock {
+            Some(lock) => Command::WithLock {
+                lock,
+                command: Box::new(command),
+            },
+            None => command,
+        };
+        let command = match &self.session {
+            Some(session) => Command::WithPosition {
+                min_position: Some(session.position()),
+                command: Box::new(command),
+            },
+            None => command,
+        };
+        let command = Command::WithRequestId {
+            request_id: request_id.to_string(),
+            command: Box::new(command),
+        };
+        let data = self.format.encode_command(&command);
+        // Сервер читает одиночную команду одним буфером, большая не дошла бы целиком
+        if data.len() > MAX_COMMAND_SIZE {
+            return Err(BankError::MessageTooLarge {
+                size: data.len(),
+                limit: MAX_COMMAND_SIZE,
+            });
+        }
+        #[cfg(feature = "faults")]
+        let received_data = match self.faults.as_ref().and_then(Faults::next) {
+            Some(fault) => faults::round_trip(self, endpoint, fault, &data)?,
+            None => self.round_trip(endpoint, &data)?,
+        };
+        #[cfg(not(feature = "faults"))]
+        let received_data = self.round_trip(endpoint, &data)?;
+
+        let response = self
+            .format
+            .decode(&received_data)
+            .unwrap_or_else(|e| Err(BankError::UnexpectedResponse(format!("{:?}", e))));
+        match (response, &self.session) {
+            (Ok(ResponsePayload::Positioned { position, payload }), Some(session)) => {
+                session.advance(position);
+                Ok(*payload)
+            }
+            (response, _) => response,
+        }
+    }
+
+    /// Sends an encoded command to `endpoint` over a new connection and
+    /// reads the response.
+    fn round_trip(&self, endpoint: &Endpoint, data: &[u8]) -> Result<Vec<u8>, BankError> {
+        let unavailable = |e: std::io::Error| {
+            BankError::RemoteUnavailable(format!("{}: {}", endpoint.address(), e))
+        };
+        let mut stream: TcpStream =
+            socks::connect(endpoint, self.proxy.as_ref(), &self.socket).map_err(unavailable)?;
+        // Нулевой таймаут чтения запрещен; такой срок сервер и так отклонит
+        let timeout = self.deadline.filter(|timeout| !timeout.is_zero());
+        stream.set_read_timeout(timeout).map_err(unavailable)?;
+        stream.write_all(data).map_err(unavailable)?;
+
+        // Сервер закрывает соединение после ответа, поэтому читаем до конца
+        let mut received_data = Vec::new();
+        (&stream)
+            .take(self.max_message_size as u64 + 1)
+            .read_to_end(&mut received_data)
+            .map_err(|e| match e.kind() {
+                ErrorKind::WouldBlock | ErrorKind::TimedOut => BankError::DeadlineExceeded,
+                _ => unavailable(e),
+            })?;
+        if received_data.len() > self.max_message_size {
+            return Err(BankError::MessageTooLarge {
+                size: received_data.len(),
+                limit: self.max_message_size,
+            });
+        }
+        Ok(received_data)
+    }
+}
+
+/// Wraps `command` to run on behalf of the identity with `identity_token`
+/// and within `deadline`.
+fn wrap(command: Command, identity_token: Option<&str>, deadline: Option<Duration>) -> Command {
+    let command = match identity_token {
+        Some(token) => Command::AsIdentity {
+            token: token.to_string(),
+            command: Box::new(command),
+        },
+        None => command,
+    };
+    match deadline {
+        Some(timeout) => Command::WithDeadline {
+            timeout_ms: timeout.as_millis() as u64,
+            command: Box::new(command),
+        },
+        None => command,
+    }
+}
+
+/// Request ID unique enough to find one request in the logs: current time,
+/// process and a counter.
+fn new_request_id() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    format!(
+        "{:x}-{:x}-{}",
+        now,
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Compares the digest the server computed over the operations a Restore
+/// appended with the one of the operations sent from `offset` on.
+fn check_restored(
+    offset: usize,
+    expected: &HistoryDigest,
+    applied: &HistoryDigest,
+) -> Result<(), BankError> {
+    if applied == expected {
+        return Ok(());
+    }
+    Err(BankError::InvalidHistory {
+        index: offset,
+        reason: format!(
+            "the server applied {} operations with digest {} instead of {} with digest {}",
+            applied.operations, applied.digest, expected.operations, expected.digest
+        ),
+    })
+}
+
+/// All items of a paginated listing, `get` fetching one page of it.
+fn all_pages<T>(
+    get: impl Fn(PageRequest) -> Result<Page<T>, BankError>,
+) -> Result<Vec<T>, BankError> {
+    let mut items = Vec::new();
+    let mut page = PageRequest::first(PAGE_SIZE);
+    loop {
+        let got = get(page.clone())?;
+        items.extend(got.items);
+        match got.next_cursor {
+            Some(cursor) => page.cursor = Some(cursor),
+            None => return Ok(items),
+        }
+    }
+}
+
+fn unexpected(method: &str, payload: ResponsePayload) -> BankError {
+    BankError::UnexpectedResponse(format!("{}: {:?}", method, payload))
 }
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use protocol_crate::codec::{Serializer, WireFormat};
+use protocol_crate::pipeline::{
+    read_frame_limited, write_cancel, write_frame, TooLarge, PIPELINE_MARKER,
+};
+use protocol_crate::socket::{Endpoint, SocketOptions};
+use protocol_crate::{BankError, Command, Operation, Response, ResponsePayload, RestoreProgress};
+
+use crate::socks::{self, Socks5Proxy};
+use crate::{new_request_id, wrap, InteractiveTransaction};
+
+// Ожидающие ответа запросы; None, когда соединение закрыто
+type Pending = Arc<Mutex<Option<HashMap<String, Sender<Response>>>>>;
+
+/// One connection shared by many commands in flight. [`Pipeline::send`]
+/// returns as soon as the command is written, so threads sharing the
+/// pipeline do not wait for each other's responses; the server may answer
+/// in any order.
+pub struct Pipeline {
+    server_address: String,
+    identity_token: Option<String>,
+    deadline: Option<Duration>,
+    format: WireFormat,
+    max_message_size: usize,
+    stream: Arc<Mutex<TcpStream>>,
+    pending: Pending,
+}
+
+/// Handle to the response of one command sent through a [`Pipeline`].
+pub struct PendingResponse {
+    server_address: String,
+    request_id: String,
+    response: Receiver<Response>,
+    cancel: CancelToken,
+    // Момент, после которого ответ уже не ждем
+    deadline: Option<Instant>,
+}
+
+/// Cancels one command sent through a [`Pipeline`]; clones can be handed to
+/// other threads, e.g. to give up on the command after a timeout.
+#[derive(Clone)]
+pub struct CancelToken {
+    server_address: String,
+    request_id: String,
+    stream: Arc<Mutex<TcpStream>>,
+}
+
+impl Pipeline {
+    pub(crate) fn connect(
+        endpoint: &Endpoint,
+        proxy: Option<&Socks5Proxy>,
+        identity_token: Option<String>,
+        deadline: Option<Duration>,
+        format: WireFormat,
+        max_message_size: usize,
+        socket: SocketOptions,
+    ) -> Result<Self, BankError> {
+        let server_address = endpoint.address();
+        let unavailable =
+            |e: std::io::Error| BankError::RemoteUnavailable(format!("{}: {}", server_address, e));
+        let mut stream = socks::connect(endpoint, proxy, &socket).map_err(unavailable)?;
+        stream.write_all(&[PIPELINE_MARKER]).map_err(unavailable)?;
+        let reader = stream.try_clone().map_err(unavailable)?;
+
+        let pending: Pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        let responses = Arc::clone(&pending);
+        thread::spawn(move || read_responses(reader, format, max_message_size, responses));
+        Ok(Pipeline {
+            server_address: server_address.to_string(),
+            identity_token,
+            deadline,
+            format,
+            max_message_size,
+            stream: Arc::new(Mutex::new(stream)),
+            pending,
+        })
+    }
+
+    /// Sends a command without waiting for the response.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command to be sent.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(PendingResponse)` - Handle to wait for the response with.
+    /// * `Err(BankError)` - If the command is too large or the connection is closed.
+    pub fn send(&self, command: Command) -> Result<PendingResponse, BankError> {
+        let command = wrap(command, self.identity_token.as_deref(), self.deadline);
+        let request_id = new_request_id();
+        let data = self.format.encode_command(&command);
+        if data.len() > self.max_message_size {
+            return Err(BankError::MessageTooLarge {
+                size: data.len(),
+                limit: self.max_message_size,
+            }
+            .with_request_id(&request_id));
+        }
+        let closed = || {
+            BankError::RemoteUnavailable(format!("{}: connection closed", self.server_address))
+                .with_request_id(&request_id)
+        };
+
+        let (sender, response) = mpsc::channel();
+        match self.pending.lock().unwrap().as_mut() {
+            Some(pending) => pending.insert(request_id.clone(), sender),
+            None => return Err(closed()),
+        };
+        let written = write_frame(&mut *self.stream.lock().unwrap(), &request_id, &data);
+        if let Err(e) = written {
+            if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+                pending.remove(&request_id);
+            }
+            return Err(
+                BankError::RemoteUnavailable(format!("{}: {}", self.server_address, e))
+                    .with_request_id(&request_id),
+            );
+        }
+        Ok(PendingResponse {
+            server_address: self.server_address.clone(),
+            cancel: CancelToken {
+                server_address: self.server_address.clone(),
+                request_id: request_id.clone(),
+                stream: Arc::clone(&self.stream),
+            },
+            request_id,
+            response,
+            deadline: self.deadline.map(|timeout| Instant::now() + timeout),
+        })
+    }
+
+    /// Opens an interactive transaction on the connection; see
+    /// [`InteractiveTransaction`].
+    pub fn begin(&self) -> Result<InteractiveTransaction<'_>, BankError> {
+        InteractiveTransaction::begin(self)
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        // Сервер допишет ответы на уже отправленные команды и закроет соединение
+        let _ = self.stream.lock().unwrap().shutdown(Shutdown::Write);
+    }
+}
+
+impl PendingResponse {
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// Waits for the response; an error carries the request ID. A cancelled
+    /// command fails with `BankError::Cancelled` unless the server finished
+    /// it first. When the deadline passes the command is cancelled and the
+    /// wait fails with `BankError::DeadlineExceeded`.
+    pub fn wait(self) -> Response {
+        self.wait_with_progress(|_| {})
+    }
+
+    /// Same as [`PendingResponse::wait`], but passes the progress reports of
+    /// a `Restore` to `on_progress` while waiting.
+    pub fn wait_with_progress(self, mut on_progress: impl FnMut(RestoreProgress)) -> Response {
+        let deadline = self.deadline;
+        self.wait_until(deadline, |payload| {
+            if let ResponsePayload::RestoreProgress(progress) = payload {
+                on_progress(progress);
+            }
+        })
+    }
+
+    /// Same as [`PendingResponse::wait`], but passes the operations a
+    /// `Subscribe` streams to `on_events`, with their history IDs. A
+    /// subscription lasts until it is cancelled, so the wait has no deadline.
+    pub fn wait_with_events(self, mut on_events: impl FnMut(Vec<(usize, Operation)>)) -> Response {
+        self.wait_until(None, |payload| {
+            if let ResponsePayload::Events(events) = payload {
+                on_events(events);
+            }
+        })
+    }
+
+    fn wait_until(
+        self,
+        deadline: Option<Instant>,
+        mut on_progress: impl FnMut(ResponsePayload),
+    ) -> Response {
+        let response = loop {
+            let received = match deadline {
+                Some(deadline) => self
+                    .response
+                    .recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                None => self.response.recv().map_err(RecvTimeoutError::from),
+            };
+            match received {
+                Ok(Ok(payload)) if is_progress(&payload) => on_progress(payload),
+                Ok(response) => break response,
+                Err(RecvTimeoutError::Timeout) => {
+                    let _ = self.cancel.cancel();
+                    break Err(BankError::DeadlineExceeded);
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    break Err(BankError::RemoteUnavailable(format!(
+                        "{}: connection closed",
+                        self.server_address
+                    )))
+                }
+            }
+        };
+        response.map_err(|e| e.with_request_id(&self.request_id))
+    }
+}
+
+impl CancelToken {
+    /// Asks the server to cancel the command. The server still answers it,
+    /// with `BankError::Cancelled` if the command was not done yet.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the request to cancel was sent.
+    /// * `Err(BankError)` - If the connection is closed.
+    pub fn cancel(&self) -> Result<(), BankError> {
+        write_cancel(&mut *self.stream.lock().unwrap(), &self.request_id).map_err(|e| {
+            BankError::RemoteUnavailable(format!("{}: {}", self.server_address, e))
+                .with_request_id(&self.request_id)
+        })
+    }
+}
+
+fn read_responses(mut reader: TcpStream, format: WireFormat, limit: usize, pending: Pending) {
+    // Все ответы читаются в один буфер: после декодирования он не нужен
+    let mut body = Vec::new();
+    loop {
+        let request_id = match read_frame_limited(&mut reader, &mut body, limit) {
+            Ok(Some(request_id)) => request_id,
+            Err(e) => {
+                // Непрочитанное тело остается в потоке, дальше читать нельзя
+                if let Some(too_large) = TooLarge::find(&e) {
+                    let error = BankError::MessageTooLarge {
+                        size: too_large.size,
+                        limit: too_large.limit,
+                    };
+                    let waiting = pending
+                        .lock()
+                        .unwrap()
+                        .as_mut()
+                        .and_then(|waiting| waiting.remove(&too_large.request_id));
+                    if let Some(sender) = waiting {
+                        let _ = sender.send(Err(error));
+                    }
+                }
+                break;
+            }
+            Ok(None) => break,
+        };
+        let response = format
+            .decode(&body)
+            .unwrap_or_else(|e| Err(BankError::UnexpectedResponse(format!("{:?}", e))));
+        let mut guard = pending.lock().unwrap();
+        let Some(waiting) = guard.as_mut() else {
+            break;
+        };
+        // За промежуточным ответом придет еще один, ожидание остается
+        if response.as_ref().is_ok_and(is_progress) {
+            if let Some(sender) = waiting.get(&request_id) {
+                let _ = sender.send(response);
+            }
+        } else if let Some(sender) = waiting.remove(&request_id) {
+            let _ = sender.send(response);
+        }
+    }
+    // Оставшиеся ожидания завершатся ошибкой, новые команды не отправятся
+    pending.lock().unwrap().take();
+}
+
+/// Whether `payload` is an intermediate answer, with the final one still to come.
+fn is_progress(payload: &ResponsePayload) -> bool {
+    matches!(
+        payload,
+        ResponsePayload::RestoreProgress(_) | ResponsePayload::Events(_)
+    )
+}
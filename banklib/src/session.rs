@@ -0,0 +1,36 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Read-your-writes token: the history length a bank had after the last
+/// command sent through the session. Clients given the session only read
+/// state at least that recent, so a client of a replica sharing the session
+/// with a client of the primary sees the writes made through the latter.
+/// Clones share the position.
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    position: Arc<AtomicUsize>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session::default()
+    }
+
+    /// Session that has already seen `position` operations, e.g. a position
+    /// handed over by another process.
+    pub fn at(position: usize) -> Self {
+        Session {
+            position: Arc::new(AtomicUsize::new(position)),
+        }
+    }
+
+    /// How many operations the history has to hold for the next command.
+    pub fn position(&self) -> usize {
+        self.position.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn advance(&self, position: usize) {
+        // Ответы параллельных команд приходят в любом порядке, позиция только растет
+        self.position.fetch_max(position, Ordering::Relaxed);
+    }
+}
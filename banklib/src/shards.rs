@@ -0,0 +1,85 @@
+use protocol_crate::{AccountRef, Command, ACCOUNT_SEPARATOR};
+
+// Точек на кольце у каждого шарда: чем больше, тем ровнее делятся счета
+const VIRTUAL_NODES: usize = 64;
+
+/// Which shard behind a router owns which account. An account lives on the
+/// shard its name hashes to on a consistent hash ring, so adding a shard
+/// moves only the accounts that land on it. Sub-accounts hash by their
+/// top-level account and live next to it. The router places accounts with
+/// the same map, so a client holding it can skip the router.
+#[derive(Debug, Clone)]
+pub struct ShardMap {
+    shards: Vec<String>,
+    // Точки кольца по возрастанию хеша и номер шарда каждой
+    ring: Vec<(u64, usize)>,
+}
+
+impl ShardMap {
+    /// Map over the shards at `shards`, in any order; the ring only depends
+    /// on the addresses.
+    pub fn new(shards: Vec<String>) -> Self {
+        let mut ring: Vec<(u64, usize)> = shards
+            .iter()
+            .enumerate()
+            .flat_map(|(shard, address)| {
+                (0..VIRTUAL_NODES).map(move |node| (hash(&format!("{}#{}", address, node)), shard))
+            })
+            .collect();
+        ring.sort_unstable();
+        ShardMap { shards, ring }
+    }
+
+    pub fn shards(&self) -> &[String] {
+        &self.shards
+    }
+
+    /// Address of the shard that owns `account`.
+    pub fn shard(&self, account: &str) -> &str {
+        let root = account.split(ACCOUNT_SEPARATOR).next().unwrap_or(account);
+        let point = hash(root);
+        // Первая точка кольца после хеша, за последней - снова первая
+        let index = self.ring.partition_point(|(hash, _)| *hash < point);
+        &self.shards[self.ring[index % self.ring.len()].1]
+    }
+
+    /// Address of the shard a client can send `command` to directly: the
+    /// shard of the one account it names, or of both sides of a transfer
+    /// within a shard. Other commands, and accounts given by ID, which is
+    /// only unique within a shard, are left to the router.
+    pub(crate) fn route(&self, command: &Command) -> Option<&str> {
+        let name = |account: &AccountRef| match account {
+            AccountRef::Name(name) => Some(self.shard(name)),
+            AccountRef::Id(_) => None,
+        };
+        match command {
+            Command::CreateAccount(name) => Some(self.shard(name)),
+            Command::IncreaseAccount(account, _)
+            | Command::DecreaseAccount(account, _)
+            | Command::DecreaseIfBalanceAtLeast { account, .. }
+            | Command::GetAccountBalance(account)
+            | Command::GetVersionedBalance(account)
+            | Command::GetBalanceAt { account, .. }
+            | Command::GetAccountHistoryPaged { account, .. } => name(account),
+            Command::Transfer { from, to, .. } | Command::TransferIf { from, to, .. } => {
+                let shard = name(from)?;
+                (Some(shard) == name(to)).then_some(shard)
+            }
+            _ => None,
+        }
+    }
+}
+
+// FNV-1a: хеш не должен меняться между версиями Rust, иначе счета переедут.
+// Ключи различаются последними символами, поэтому биты перемешиваются еще
+// раз, как в конце MurmurHash3
+fn hash(key: &str) -> u64 {
+    let mut hash = key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
@@ -0,0 +1,195 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, TcpStream};
+
+use protocol_crate::socket::{Endpoint, SocketOptions};
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN_NAME: u8 = 3;
+const IPV6: u8 = 4;
+
+/// A SOCKS5 proxy (RFC 1928) the client reaches the server through, e.g. a
+/// bastion host. The server address is passed to the proxy as given, so a
+/// host name is resolved by the proxy, not by the client.
+#[derive(Debug)]
+pub struct Socks5Proxy {
+    endpoint: Endpoint,
+    // Имя и пароль по RFC 1929; без них прокси должен пускать без проверки
+    credentials: Option<(String, String)>,
+}
+
+impl Socks5Proxy {
+    /// Proxy at `address`, an `IP:port` or a `host:port`.
+    pub fn new(address: &str) -> Self {
+        Socks5Proxy {
+            endpoint: Endpoint::new(address),
+            credentials: None,
+        }
+    }
+
+    /// Authenticates to the proxy with a user name and a password.
+    pub fn with_credentials(mut self, username: &str, password: &str) -> Self {
+        self.credentials = Some((username.to_string(), password.to_string()));
+        self
+    }
+
+    /// Connects to the proxy with `socket` and asks it to connect to
+    /// `target`; the stream then carries the traffic of the server. The
+    /// handshake is limited by `socket.connect_timeout`.
+    pub(crate) fn connect(&self, target: &str, socket: &SocketOptions) -> io::Result<TcpStream> {
+        let proxied = |e: io::Error| {
+            io::Error::new(
+                e.kind(),
+                format!("proxy {}: {}", self.endpoint.address(), e),
+            )
+        };
+        let request = connect_request(target)?;
+        let mut stream = self.endpoint.connect(socket).map_err(proxied)?;
+        stream.set_read_timeout(socket.connect_timeout)?;
+        stream.set_write_timeout(socket.connect_timeout)?;
+        self.handshake(&mut stream, &request).map_err(proxied)?;
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+        Ok(stream)
+    }
+
+    fn handshake(&self, stream: &mut TcpStream, request: &[u8]) -> io::Result<()> {
+        let greeting: &[u8] = match self.credentials {
+            Some(_) => &[VERSION, 2, NO_AUTHENTICATION, USERNAME_PASSWORD],
+            None => &[VERSION, 1, NO_AUTHENTICATION],
+        };
+        stream.write_all(greeting)?;
+        let mut choice = [0; 2];
+        stream.read_exact(&mut choice)?;
+        if choice[0] != VERSION {
+            return Err(invalid("not a SOCKS5 proxy"));
+        }
+        match (choice[1], &self.credentials) {
+            (NO_AUTHENTICATION, _) => {}
+            (USERNAME_PASSWORD, Some((username, password))) => {
+                authenticate(stream, username, password)?
+            }
+            (NO_ACCEPTABLE_METHOD, _) => {
+                return Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    "no acceptable authentication method",
+                ))
+            }
+            (method, _) => return Err(invalid(&format!("unexpected method {}", method))),
+        }
+
+        stream.write_all(request)?;
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != VERSION {
+            return Err(invalid("not a SOCKS5 proxy"));
+        }
+        if reply[1] != 0 {
+            return Err(refused(reply[1]));
+        }
+        // Адрес, с которого прокси подключился к серверу, клиенту не нужен
+        let address_len = match reply[3] {
+            IPV4 => 4,
+            IPV6 => 16,
+            DOMAIN_NAME => {
+                let mut len = [0; 1];
+                stream.read_exact(&mut len)?;
+                len[0] as usize
+            }
+            kind => return Err(invalid(&format!("unexpected address type {}", kind))),
+        };
+        stream.read_exact(&mut vec![0; address_len + 2])
+    }
+}
+
+/// Connects to the server at `endpoint` directly or through `proxy`.
+pub(crate) fn connect(
+    endpoint: &Endpoint,
+    proxy: Option<&Socks5Proxy>,
+    socket: &SocketOptions,
+) -> io::Result<TcpStream> {
+    match proxy {
+        Some(proxy) => proxy.connect(endpoint.address(), socket),
+        None => endpoint.connect(socket),
+    }
+}
+
+fn authenticate(stream: &mut TcpStream, username: &str, password: &str) -> io::Result<()> {
+    if username.len() > 255 || password.len() > 255 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "user name and password are limited to 255 bytes",
+        ));
+    }
+    let mut request = vec![1, username.len() as u8];
+    request.extend_from_slice(username.as_bytes());
+    request.push(password.len() as u8);
+    request.extend_from_slice(password.as_bytes());
+    stream.write_all(&request)?;
+    let mut status = [0; 2];
+    stream.read_exact(&mut status)?;
+    match status[1] {
+        0 => Ok(()),
+        _ => Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "user name or password rejected",
+        )),
+    }
+}
+
+/// CONNECT request for `target`, an `IP:port` or a `host:port`.
+fn connect_request(target: &str) -> io::Result<Vec<u8>> {
+    let bad_target = || {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{}: expected host:port", target),
+        )
+    };
+    let (host, port) = target.rsplit_once(':').ok_or_else(bad_target)?;
+    let port: u16 = port.parse().map_err(|_| bad_target())?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    let mut request = vec![VERSION, CONNECT, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) if host.is_empty() || host.len() > 255 => return Err(bad_target()),
+        Err(_) => {
+            request.extend_from_slice(&[DOMAIN_NAME, host.len() as u8]);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    Ok(request)
+}
+
+fn refused(code: u8) -> io::Error {
+    let (kind, reason) = match code {
+        2 => (
+            ErrorKind::PermissionDenied,
+            "connection not allowed by ruleset",
+        ),
+        3 => (ErrorKind::Other, "network unreachable"),
+        4 => (ErrorKind::Other, "host unreachable"),
+        5 => (ErrorKind::ConnectionRefused, "connection refused"),
+        6 => (ErrorKind::TimedOut, "TTL expired"),
+        7 => (ErrorKind::Unsupported, "command not supported"),
+        8 => (ErrorKind::Unsupported, "address type not supported"),
+        _ => (ErrorKind::Other, "general failure"),
+    };
+    io::Error::new(kind, format!("{} (SOCKS reply {})", reason, code))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
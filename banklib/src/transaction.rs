@@ -0,0 +1,60 @@
+use protocol_crate::{AccountRef, BankError, BatchOperation};
+
+use crate::BankClient;
+
+/// Collects deposits, withdrawals and transfers and submits them as one
+/// batch: the server applies either all of them or none.
+#[must_use = "operations are only sent by `submit`"]
+pub struct TransactionBuilder<'a> {
+    client: &'a BankClient,
+    operations: Vec<BatchOperation>,
+}
+
+impl<'a> TransactionBuilder<'a> {
+    pub(crate) fn new(client: &'a BankClient) -> Self {
+        TransactionBuilder {
+            client,
+            operations: Vec::new(),
+        }
+    }
+
+    pub fn deposit(mut self, account: impl Into<AccountRef>, amount: u32) -> Self {
+        self.operations.push(BatchOperation::Deposit {
+            account: account.into(),
+            amount,
+        });
+        self
+    }
+
+    pub fn withdraw(mut self, account: impl Into<AccountRef>, amount: u32) -> Self {
+        self.operations.push(BatchOperation::Withdraw {
+            account: account.into(),
+            amount,
+        });
+        self
+    }
+
+    pub fn transfer(
+        mut self,
+        from: impl Into<AccountRef>,
+        to: impl Into<AccountRef>,
+        amount: u32,
+    ) -> Self {
+        self.operations.push(BatchOperation::Transfer {
+            from: from.into(),
+            to: to.into(),
+            amount,
+        });
+        self
+    }
+
+    /// Sends the collected operations.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<usize>)` - The history operation ID of each operation, in the order they were added.
+    /// * `Err(BankError)` - `BankError::BatchFailed` with the index of the operation that failed; nothing was applied.
+    pub fn submit(self) -> Result<Vec<usize>, BankError> {
+        self.client.batch(self.operations)
+    }
+}
@@ -0,0 +1,989 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, IsTerminal, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use clap::{ColorChoice, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+
+use banklib::BankClient;
+use protocol_crate::digest::{chain, to_hex, GENESIS};
+use protocol_crate::{
+    validate_history, AccountFilter, AccountLimits, AccountRef, Operation, PageRequest, QueryRow,
+    ReservationKind, Statement, VelocityRule,
+};
+
+use crate::progress::{Checkpoint, Progress};
+use crate::table::{Align, Cell, Table};
+
+mod progress;
+mod table;
+
+// Сколько операций запрашивать у сервера за раз
+const PAGE_SIZE: usize = 100;
+// Столько операций восстанавливается между сохранениями хода восстановления
+const RESTORE_CHUNK_SIZE: usize = 10_000;
+
+#[derive(Parser, Debug)]
+#[command(name = "bank-cli")]
+#[command(version = "1.0")]
+#[command(about = "Командная строка для банковского сервера")]
+struct Cli {
+    /// Адрес сервера
+    #[arg(long, default_value = "127.0.0.1:7878")]
+    server: String,
+    /// Токен пользователя, от имени которого выполняются команды
+    #[arg(long)]
+    identity: Option<String>,
+    /// Раскрашивать поступления и списания в таблицах; auto - только в терминале
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+    #[command(subcommand)]
+    command: CliCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum CliCommand {
+    /// Выписка по счету за период
+    Statement {
+        /// Имя или числовой id счета
+        account: String,
+        /// Начало периода, unix-время в секундах
+        #[arg(long, default_value_t = 0)]
+        from: u64,
+        /// Конец периода (не включая), unix-время в секундах
+        #[arg(long, default_value_t = u64::MAX)]
+        to: u64,
+        #[arg(long, value_enum, default_value_t = Format::Table)]
+        format: Format,
+    },
+    /// История банка или, с --account, одного счета с его балансом после
+    /// каждой операции
+    History {
+        /// Имя или числовой id счета
+        #[arg(long)]
+        account: Option<String>,
+        #[arg(long, value_enum, default_value_t = Format::Table)]
+        format: Format,
+    },
+    /// Отчет по истории, посчитанный на сервере, например
+    /// "sum where kind = transfer and from = Alice group by day"
+    Query {
+        /// Запрос: count, sum, avg, min или max, затем where с условиями
+        /// через and и group by kind, from, to, account, day или month
+        query: String,
+        #[arg(long, value_enum, default_value_t = Format::Table)]
+        format: Format,
+    },
+    /// Счета с балансами: все, в границах --min и --max, --top наибольших
+    /// или отобранные по состоянию с --status
+    Accounts {
+        #[arg(long)]
+        min: Option<u32>,
+        #[arg(long)]
+        max: Option<u32>,
+        #[arg(long, conflicts_with_all = ["min", "max"])]
+        top: Option<usize>,
+        #[arg(long, value_enum, conflicts_with_all = ["min", "max", "top"])]
+        status: Option<Status>,
+        #[arg(long, value_enum, default_value_t = Format::Table)]
+        format: Format,
+    },
+    /// Закрыть пустой счет; его история сохраняется
+    Close {
+        /// Имя или числовой id счета
+        account: String,
+    },
+    /// Выгрузка всей истории банка в файл
+    Export {
+        #[arg(long)]
+        file: PathBuf,
+        #[arg(long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+        /// Продолжить прерванную выгрузку с места, записанного в ФАЙЛ.progress
+        #[arg(long)]
+        resume: bool,
+    },
+    /// Проверка истории сервера по цепочке хешей
+    Verify {
+        /// Адрес второго сервера (реплики), историю которого сравнить с этой
+        #[arg(long)]
+        peer: Option<String>,
+    },
+    /// Сверка балансов и индексов сервера с его историей
+    CheckConsistency {
+        /// Административный токен
+        #[arg(long)]
+        token: String,
+    },
+    /// Восстановление истории из файла, сделанного export
+    Restore {
+        #[arg(long)]
+        file: PathBuf,
+        /// Продолжить прерванное восстановление с места, записанного в ФАЙЛ.progress
+        #[arg(long)]
+        resume: bool,
+    },
+    /// Лимиты на списания со счета
+    Limits {
+        /// Имя или числовой id счета
+        account: String,
+    },
+    /// Установить лимиты на списания со счета
+    SetLimits {
+        /// Имя или числовой id счета
+        account: String,
+        /// Административный токен
+        #[arg(long)]
+        token: String,
+        /// Максимальная сумма одного списания
+        #[arg(long)]
+        max_withdrawal: Option<u32>,
+        /// Максимальная сумма списаний за сутки (UTC)
+        #[arg(long)]
+        max_daily_outflow: Option<u32>,
+        /// Ограничение скорости списаний в виде СЕКУНДЫ:СУММА, можно указать несколько раз
+        #[arg(long, value_parser = parse_velocity_rule)]
+        velocity: Vec<VelocityRule>,
+    },
+    /// Метаданные счета
+    Metadata {
+        /// Имя или числовой id счета
+        account: String,
+    },
+    /// Установить или удалить (без значения) поле метаданных счета
+    SetMetadata {
+        /// Имя или числовой id счета
+        account: String,
+        key: String,
+        value: Option<String>,
+    },
+    /// Владельцы счета
+    Owners {
+        /// Имя или числовой id счета
+        account: String,
+    },
+    /// Заменить владельцев счета (без владельцев счет доступен всем)
+    SetOwners {
+        /// Имя или числовой id счета
+        account: String,
+        owners: Vec<String>,
+    },
+    /// Теги счета
+    Tags {
+        /// Имя или числовой id счета
+        account: String,
+    },
+    /// Заменить теги счета
+    SetTags {
+        /// Административный токен
+        #[arg(long)]
+        token: String,
+        /// Имя или числовой id счета
+        account: String,
+        tags: Vec<String>,
+    },
+    /// Счета с тегом и их балансы
+    FindByTag {
+        tag: String,
+        #[arg(long, value_enum, default_value_t = Format::Table)]
+        format: Format,
+    },
+    /// Суммарный баланс счета вместе с подсчетами (Alice/savings, ...)
+    SubtreeBalance {
+        /// Имя или числовой id счета
+        account: String,
+    },
+    /// Статистика запросов сервера
+    Metrics {
+        /// Административный токен
+        #[arg(long)]
+        token: String,
+    },
+    /// Перечитать файл настроек сервера
+    Reload {
+        /// Административный токен
+        #[arg(long)]
+        token: String,
+    },
+    /// Включить режим обслуживания (только чтение) или выключить его с --off
+    Maintenance {
+        /// Административный токен
+        #[arg(long)]
+        token: String,
+        /// Выключить режим обслуживания
+        #[arg(long)]
+        off: bool,
+    },
+    /// Оспорить операцию истории, например незнакомое списание
+    Dispute {
+        /// Номер операции в истории
+        operation: usize,
+        /// Причина спора
+        reason: String,
+    },
+    /// Скрипт автодополнения команд для оболочки
+    Completions { shell: Shell },
+    /// Страница man; с --dir в каталог пишутся страницы и всех подкоманд
+    Man {
+        #[arg(long)]
+        dir: Option<PathBuf>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    Table,
+    Csv,
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Status {
+    All,
+    Active,
+    Closed,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ExportFormat {
+    Json,
+    Csv,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let color = match cli.color {
+        ColorChoice::Auto => io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+    };
+    let mut client = BankClient::new(&cli.server);
+    if let Some(token) = &cli.identity {
+        client = client.with_identity(token);
+    }
+
+    match cli.command {
+        CliCommand::Statement {
+            account,
+            from,
+            to,
+            format,
+        } => match client.statement(account_ref(&account), from, to) {
+            Ok(statement) => match format {
+                Format::Table => print_table(&statement, color),
+                Format::Csv => print_csv(&statement),
+                Format::Json => println!("{}", serde_json::to_string_pretty(&statement).unwrap()),
+            },
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        },
+        CliCommand::History { account, format } => {
+            let history = match &account {
+                Some(account) => client.account_history(account_ref(account)),
+                None => client.get_history(),
+            };
+            match history {
+                Ok(history) => print_history(&history, account.as_deref(), format, color),
+                Err(e) => {
+                    eprintln!("Error: {:?}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        CliCommand::Accounts {
+            min,
+            max,
+            top,
+            status,
+            format,
+        } => {
+            let accounts = match (top, status) {
+                (Some(n), _) => client.top_accounts(n),
+                (None, Some(status)) => client.list_accounts(match status {
+                    Status::All => AccountFilter::All,
+                    Status::Active => AccountFilter::Active,
+                    Status::Closed => AccountFilter::Closed,
+                }),
+                (None, None) => client.find_accounts(min, max),
+            };
+            match accounts {
+                Ok(accounts) => print_accounts(&accounts, format),
+                Err(e) => {
+                    eprintln!("Error: {:?}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        CliCommand::Close { account } => {
+            if let Err(e) = client.close_account(account_ref(&account)) {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+        CliCommand::Export {
+            file,
+            format,
+            resume,
+        } => match export(&client, &file, format, resume) {
+            Ok(count) => println!("Exported {} operations to {}", count, file.display()),
+            Err(e) => {
+                eprintln!("Error: {}: {}", file.display(), e);
+                std::process::exit(1);
+            }
+        },
+        CliCommand::Verify { peer } => match verify(&client, peer.as_deref()) {
+            Ok(count) => println!("History of {} operations is consistent", count),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        },
+        CliCommand::CheckConsistency { token } => match client.check_consistency(&token) {
+            Ok(report) if report.differences.is_empty() => {
+                println!("State is consistent with {} operations", report.operations)
+            }
+            Ok(report) => {
+                for difference in report.differences {
+                    println!("{}", difference);
+                }
+                std::process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        },
+        CliCommand::Restore { file, resume } => {
+            if let Err(e) = restore(&client, &file, resume) {
+                eprintln!("Error: {}: {}", file.display(), e);
+                std::process::exit(1);
+            }
+        }
+        CliCommand::Limits { account } => match client.get_account_limits(account_ref(&account)) {
+            Ok(limits) => println!("{:?}", limits),
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        },
+        CliCommand::SetLimits {
+            account,
+            token,
+            max_withdrawal,
+            max_daily_outflow,
+            velocity,
+        } => {
+            let limits = AccountLimits {
+                max_withdrawal,
+                max_daily_outflow,
+                velocity,
+            };
+            if let Err(e) = client.set_account_limits(&token, account_ref(&account), limits) {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+        CliCommand::Metadata { account } => {
+            match client.get_account_metadata(account_ref(&account)) {
+                Ok(metadata) => {
+                    for (key, value) in metadata {
+                        println!("{} = {}", key, value);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Error: {:?}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        CliCommand::SetMetadata {
+            account,
+            key,
+            value,
+        } => {
+            let result = client.set_account_metadata(account_ref(&account), &key, value.as_deref());
+            if let Err(e) = result {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+        CliCommand::Owners { account } => match client.get_account_owners(account_ref(&account)) {
+            Ok(owners) => {
+                for owner in owners {
+                    println!("{}", owner);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        },
+        CliCommand::SetOwners { account, owners } => {
+            let owners = owners.into_iter().collect();
+            if let Err(e) = client.set_account_owners(account_ref(&account), owners) {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+        CliCommand::Tags { account } => match client.get_account_tags(account_ref(&account)) {
+            Ok(tags) => {
+                for tag in tags {
+                    println!("{}", tag);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        },
+        CliCommand::SetTags {
+            token,
+            account,
+            tags,
+        } => {
+            let tags = tags.into_iter().collect();
+            if let Err(e) = client.set_account_tags(&token, account_ref(&account), tags) {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+        CliCommand::Query { query, format } => match client.query_history(&query) {
+            Ok(rows) => print_query(&rows, format),
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        },
+        CliCommand::FindByTag { tag, format } => match client.find_accounts_by_tag(&tag) {
+            Ok(accounts) => print_accounts(&accounts, format),
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        },
+        CliCommand::SubtreeBalance { account } => {
+            match client.get_subtree_balance(account_ref(&account)) {
+                Ok(balance) => println!("{}", balance),
+                Err(e) => {
+                    eprintln!("Error: {:?}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        CliCommand::Metrics { token } => match client.metrics(&token) {
+            Ok(metrics) => {
+                println!(
+                    "{:<20} | {:>8} | {:>8} | {:>8} | {:>8} | {:>8} | {:>8}",
+                    "command", "count", "errors", "p50 us", "p90 us", "p99 us", "max us"
+                );
+                for m in metrics {
+                    println!(
+                        "{:<20} | {:>8} | {:>8} | {:>8} | {:>8} | {:>8} | {:>8}",
+                        m.command, m.count, m.errors, m.p50_us, m.p90_us, m.p99_us, m.max_us
+                    );
+                }
+            }
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        },
+        CliCommand::Reload { token } => {
+            if let Err(e) = client.reload(&token) {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+        CliCommand::Maintenance { token, off } => {
+            if let Err(e) = client.set_maintenance(&token, !off) {
+                eprintln!("Error: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+        CliCommand::Dispute { operation, reason } => {
+            match client.open_dispute(operation, &reason) {
+                Ok(id) => println!("Opened dispute {}", id),
+                Err(e) => {
+                    eprintln!("Error: {:?}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        CliCommand::Completions { shell } => {
+            clap_complete::generate(shell, &mut Cli::command(), "bank-cli", &mut io::stdout())
+        }
+        CliCommand::Man { dir } => {
+            let written = match dir {
+                Some(dir) => clap_mangen::generate_to(Cli::command(), dir),
+                None => clap_mangen::Man::new(Cli::command()).render(&mut io::stdout()),
+            };
+            if let Err(e) = written {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// Validates the exported history and streams it to the server, showing
+/// how much of it the server has applied. The server applies each chunk
+/// all or nothing and a checkpoint is saved after it, so with `resume` an
+/// interrupted restore goes on after the last chunk the server confirmed.
+fn restore(client: &BankClient, file: &Path, resume: bool) -> Result<(), String> {
+    let reader = BufReader::new(File::open(file).map_err(|e| e.to_string())?);
+    let history: Vec<Operation> = serde_json::from_reader(reader).map_err(|e| e.to_string())?;
+    validate_history(&history).map_err(|e| format!("{:?}", e))?;
+
+    let checkpoint = Checkpoint::load(file).map_err(|e| e.to_string())?;
+    let mut done = match checkpoint {
+        None => 0,
+        Some(checkpoint) if resume => resumed(client, &history, checkpoint)?,
+        // Без --resume уже восстановленные операции применились бы второй раз
+        Some(_) => {
+            return Err(format!(
+                "{} is left by an interrupted restore; pass --resume to go on with it",
+                Checkpoint::path(file).display()
+            ))
+        }
+    };
+
+    let mut progress = Progress::new("Restored", history.len(), done);
+    for chunk in history[done..].chunks(RESTORE_CHUNK_SIZE) {
+        let mut last = None;
+        let restored = client.restore_with_progress(chunk.to_vec(), |step| {
+            last = Some(step.operation_id);
+            progress.update(done + step.applied);
+        });
+        if let Err(e) = restored {
+            progress.finish(done);
+            return Err(format!("{:?}", e));
+        }
+        done += chunk.len();
+        if let Some(operation_id) = last {
+            let checkpoint = Checkpoint {
+                operations: done,
+                operation_id,
+                bytes: 0,
+            };
+            checkpoint.save(file).map_err(|e| e.to_string())?;
+        }
+    }
+    progress.finish(done);
+    Checkpoint::remove(file).map_err(|e| e.to_string())
+}
+
+/// Operations of `history` an interrupted restore has applied, after
+/// checking that the server holds all of them, ending where `checkpoint`
+/// says; a single operation would not do, as histories repeat themselves.
+/// The chunk sent last may have been applied after the restore was gone,
+/// so whole chunks the server holds after the checkpoint count too.
+fn resumed(
+    client: &BankClient,
+    history: &[Operation],
+    checkpoint: Checkpoint,
+) -> Result<usize, String> {
+    let start = (checkpoint.operation_id + 1)
+        .checked_sub(checkpoint.operations)
+        .filter(|_| checkpoint.operations <= history.len())
+        .ok_or("the checkpoint does not match the file")?;
+    if !holds(client, start, &history[..checkpoint.operations])? {
+        return Err(format!(
+            "the server does not hold the operations the checkpoint confirms from #{}",
+            start
+        ));
+    }
+    let mut done = checkpoint.operations;
+    for chunk in history[done..].chunks(RESTORE_CHUNK_SIZE) {
+        if !holds(client, start + done, chunk)? {
+            break;
+        }
+        done += chunk.len();
+    }
+    Ok(done)
+}
+
+/// Whether the server history from the operation `start` on is `operations`.
+fn holds(client: &BankClient, start: usize, operations: &[Operation]) -> Result<bool, String> {
+    let mut offset = 0;
+    while offset < operations.len() {
+        let limit = PAGE_SIZE.min(operations.len() - offset);
+        let page = client
+            .get_history_paged(PageRequest::at(start + offset, limit))
+            .map_err(|e| format!("{:?}", e))?
+            .items;
+        if page.is_empty() || page[..] != operations[offset..offset + page.len()] {
+            return Ok(false);
+        }
+        offset += page.len();
+    }
+    Ok(true)
+}
+
+/// Recomputes the hash chain of the server history and compares it with the
+/// digest the server reports; with `peer`, also compares the two servers on
+/// the operations both of them have.
+fn verify(client: &BankClient, peer: Option<&str>) -> Result<usize, String> {
+    let expected = client
+        .history_digest(None)
+        .map_err(|e| format!("{:?}", e))?;
+    let mut hash = GENESIS;
+    let mut offset = 0;
+    while offset < expected.operations {
+        let limit = PAGE_SIZE.min(expected.operations - offset);
+        let page = client
+            .get_history_paged(PageRequest::at(offset, limit))
+            .map_err(|e| format!("{:?}", e))?
+            .items;
+        if page.is_empty() {
+            return Err(format!(
+                "history ends at {} of {} operations",
+                offset, expected.operations
+            ));
+        }
+        for operation in &page {
+            hash = chain(&hash, operation);
+        }
+        offset += page.len();
+    }
+    if to_hex(&hash) != expected.digest {
+        return Err("history does not match its digest".to_string());
+    }
+
+    if let Some(peer) = peer {
+        let theirs = BankClient::new(peer)
+            .history_digest(Some(expected.operations))
+            .map_err(|e| format!("{}: {:?}", peer, e))?;
+        let ours = client
+            .history_digest(Some(theirs.operations))
+            .map_err(|e| format!("{:?}", e))?;
+        if ours != theirs {
+            return Err(format!(
+                "{} diverges within the first {} operations",
+                peer, theirs.operations
+            ));
+        }
+    }
+    Ok(expected.operations)
+}
+
+/// Writes the whole history page by page, so it never has to fit in memory.
+/// The JSON file is an array of operations that `Restore` accepts as is.
+/// A checkpoint is saved after each page, so with `resume` an interrupted
+/// export goes on after the last page written instead of starting over.
+fn export(
+    client: &BankClient,
+    file: &Path,
+    format: ExportFormat,
+    resume: bool,
+) -> io::Result<usize> {
+    let total = client
+        .history_digest(None)
+        .map_err(|e| io::Error::other(format!("{:?}", e)))?
+        .operations;
+    let checkpoint = match resume {
+        true => Checkpoint::load(file)?,
+        false => None,
+    };
+    let (mut out, mut offset) = match checkpoint {
+        // Недописанный хвост после последней сохраненной страницы отрезается
+        Some(checkpoint) => {
+            let mut out = OpenOptions::new().write(true).open(file)?;
+            out.set_len(checkpoint.bytes)?;
+            out.seek(SeekFrom::End(0))?;
+            (BufWriter::new(out), checkpoint.operations)
+        }
+        None => {
+            let mut out = BufWriter::new(File::create(file)?);
+            match format {
+                ExportFormat::Json => write!(out, "[")?,
+                ExportFormat::Csv => writeln!(out, "id,type,from,to,amount")?,
+            }
+            (out, 0)
+        }
+    };
+
+    let mut progress = Progress::new("Exported", total, offset);
+    loop {
+        let page = client
+            .get_history_paged(PageRequest::at(offset, PAGE_SIZE))
+            .map_err(|e| io::Error::other(format!("{:?}", e)))?
+            .items;
+        if page.is_empty() {
+            break;
+        }
+        for (i, operation) in page.iter().enumerate() {
+            let id = offset + i;
+            match format {
+                ExportFormat::Json => {
+                    if id > 0 {
+                        write!(out, ",")?;
+                    }
+                    writeln!(out)?;
+                    serde_json::to_writer(&mut out, operation)?;
+                }
+                ExportFormat::Csv => {
+                    let (kind, from, to, amount) = csv_fields(operation);
+                    writeln!(out, "{},{},{},{},{}", id, kind, from, to, amount)?;
+                }
+            }
+        }
+        offset += page.len();
+        out.flush()?;
+        let checkpoint = Checkpoint {
+            operations: offset,
+            operation_id: offset - 1,
+            bytes: out.get_mut().stream_position()?,
+        };
+        checkpoint.save(file)?;
+        progress.update(offset);
+    }
+
+    if let ExportFormat::Json = format {
+        writeln!(out, "\n]")?;
+    }
+    out.flush()?;
+    progress.finish(offset);
+    Checkpoint::remove(file)?;
+    Ok(offset)
+}
+
+fn csv_fields(operation: &Operation) -> (&'static str, String, String, u32) {
+    match operation {
+        Operation::CreateAccount(account) => ("create", String::new(), account.clone(), 0),
+        Operation::IncreaseAccount(account, amount) => {
+            ("increase", String::new(), account.clone(), *amount)
+        }
+        Operation::DecreaseAccount(account, amount) => {
+            ("decrease", account.clone(), String::new(), *amount)
+        }
+        Operation::Transfer(from, to, amount) => ("transfer", from.clone(), to.clone(), *amount),
+        Operation::RemoteTransferOut { from, to, amount } => {
+            ("remote_out", from.clone(), to.to_string(), *amount)
+        }
+        Operation::RemoteTransferIn { from, to, amount } => {
+            ("remote_in", from.to_string(), to.clone(), *amount)
+        }
+        Operation::TransactionLeg {
+            transaction,
+            account,
+            kind: ReservationKind::Debit,
+            amount,
+        } => (
+            "transaction",
+            account.clone(),
+            transaction.to_string(),
+            *amount,
+        ),
+        Operation::TransactionLeg {
+            transaction,
+            account,
+            kind: ReservationKind::Credit,
+            amount,
+        } => (
+            "transaction",
+            transaction.to_string(),
+            account.clone(),
+            *amount,
+        ),
+        Operation::SetLimits { account, .. } => ("set_limits", String::new(), account.clone(), 0),
+        Operation::CloseAccount(account) => ("close", String::new(), account.clone(), 0),
+        Operation::AnonymizeAccount { pseudonym, .. } => {
+            ("anonymize", String::new(), pseudonym.clone(), 0)
+        }
+    }
+}
+
+fn parse_velocity_rule(rule: &str) -> Result<VelocityRule, String> {
+    let (window, max) = rule
+        .split_once(':')
+        .ok_or_else(|| "expected SECONDS:AMOUNT".to_string())?;
+    Ok(VelocityRule {
+        window_secs: window.parse().map_err(|e| format!("{}: {}", window, e))?,
+        max_amount: max.parse().map_err(|e| format!("{}: {}", max, e))?,
+    })
+}
+
+fn account_ref(account: &str) -> AccountRef {
+    match account.parse() {
+        Ok(id) => AccountRef::Id(id),
+        Err(_) => AccountRef::Name(account.to_string()),
+    }
+}
+
+fn print_table(statement: &Statement, color: bool) {
+    println!(
+        "Statement for {} [{}, {})",
+        statement.account, statement.from_ts, statement.to_ts
+    );
+    let mut table = Table::new(
+        &[
+            ("time", Align::Right),
+            ("type", Align::Left),
+            ("from", Align::Left),
+            ("to", Align::Left),
+            ("change", Align::Right),
+            ("balance", Align::Right),
+        ],
+        color,
+    );
+    let total = |label: &str, balance| {
+        let empty = Cell::empty;
+        vec![
+            empty(),
+            Cell::new(label),
+            empty(),
+            empty(),
+            empty(),
+            Cell::new(balance),
+        ]
+    };
+    table.row(total("opening", statement.opening_balance));
+    for line in &statement.lines {
+        let (kind, from, to, _) = csv_fields(&line.operation);
+        table.row(vec![
+            Cell::new(line.timestamp),
+            Cell::new(kind),
+            Cell::new(from),
+            Cell::new(to),
+            Cell::change(line.operation.balance_change(&statement.account)),
+            Cell::new(line.balance),
+        ]);
+    }
+    table.row(total("closing", statement.closing_balance));
+    print!("{}", table);
+}
+
+/// Prints the bank history or, with `account`, the history of the account
+/// with its balance after every operation.
+fn print_history(history: &[Operation], account: Option<&str>, format: Format, color: bool) {
+    match format {
+        Format::Table => {
+            // У истории счета номера свои, не номера операций банка
+            let id = if account.is_some() { "#" } else { "id" };
+            let mut columns = vec![
+                (id, Align::Right),
+                ("type", Align::Left),
+                ("from", Align::Left),
+                ("to", Align::Left),
+                ("amount", Align::Right),
+            ];
+            if account.is_some() {
+                columns.extend([("change", Align::Right), ("balance", Align::Right)]);
+            }
+            let mut table = Table::new(&columns, color);
+            // История счета начинается с его создания, баланс - с нуля
+            let mut balance = 0;
+            for (id, operation) in history.iter().enumerate() {
+                let (kind, from, to, amount) = csv_fields(operation);
+                let mut cells = vec![
+                    Cell::new(id),
+                    Cell::new(kind),
+                    Cell::new(from),
+                    Cell::new(to),
+                    Cell::new(amount),
+                ];
+                if let Some(account) = account {
+                    let change = operation.balance_change(account);
+                    balance += change;
+                    cells.extend([Cell::change(change), Cell::new(balance)]);
+                }
+                table.row(cells);
+            }
+            print!("{}", table);
+        }
+        Format::Csv => {
+            println!("id,type,from,to,amount");
+            for (id, operation) in history.iter().enumerate() {
+                let (kind, from, to, amount) = csv_fields(operation);
+                println!("{},{},{},{},{}", id, kind, from, to, amount);
+            }
+        }
+        Format::Json => println!("{}", serde_json::to_string_pretty(history).unwrap()),
+    }
+}
+
+fn print_accounts(accounts: &[(String, u32)], format: Format) {
+    match format {
+        Format::Table => {
+            let mut table = Table::new(
+                &[("account", Align::Left), ("balance", Align::Right)],
+                false,
+            );
+            for (account, balance) in accounts {
+                table.row(vec![Cell::new(account), Cell::new(balance)]);
+            }
+            print!("{}", table);
+        }
+        Format::Csv => {
+            println!("account,balance");
+            for (account, balance) in accounts {
+                println!("{},{}", account, balance);
+            }
+        }
+        Format::Json => {
+            let accounts: Vec<_> = accounts
+                .iter()
+                .map(|(account, balance)| serde_json::json!({ "account": account, "balance": balance }))
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&accounts).unwrap());
+        }
+    }
+}
+
+fn print_query(rows: &[QueryRow], format: Format) {
+    match format {
+        Format::Table => {
+            let mut table = Table::new(
+                &[
+                    ("group", Align::Left),
+                    ("count", Align::Right),
+                    ("value", Align::Right),
+                ],
+                false,
+            );
+            for row in rows {
+                let group = row.group.as_deref().unwrap_or("all");
+                table.row(vec![
+                    Cell::new(group),
+                    Cell::new(row.count),
+                    Cell::new(row.value),
+                ]);
+            }
+            print!("{}", table);
+        }
+        Format::Csv => {
+            println!("group,count,value");
+            for row in rows {
+                println!(
+                    "{},{},{}",
+                    row.group.as_deref().unwrap_or_default(),
+                    row.count,
+                    row.value
+                );
+            }
+        }
+        Format::Json => {
+            let rows: Vec<_> = rows
+                .iter()
+                .map(|row| serde_json::json!({ "group": row.group, "count": row.count, "value": row.value }))
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&rows).unwrap());
+        }
+    }
+}
+
+fn print_csv(statement: &Statement) {
+    println!("timestamp,operation,change,balance");
+    for line in &statement.lines {
+        let operation = format!("{:?}", line.operation).replace('"', "\"\"");
+        println!(
+            "{},\"{}\",{},{}",
+            line.timestamp,
+            operation,
+            line.operation.balance_change(&statement.account),
+            line.balance
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+// Ширина полосы в символах
+const BAR_WIDTH: usize = 30;
+// Чаще строка не перерисовывается
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Progress bar of a long transfer of operations on stderr, with the rate
+/// and the time left. Operations done before a resume count toward the bar
+/// but not toward the rate.
+pub struct Progress {
+    label: &'static str,
+    total: usize,
+    // Сколько операций было сделано до запуска
+    initial: usize,
+    started: Instant,
+    drawn: Option<Instant>,
+}
+
+impl Progress {
+    pub fn new(label: &'static str, total: usize, initial: usize) -> Self {
+        Progress {
+            label,
+            total,
+            initial,
+            started: Instant::now(),
+            drawn: None,
+        }
+    }
+
+    pub fn update(&mut self, done: usize) {
+        if self
+            .drawn
+            .is_some_and(|drawn| drawn.elapsed() < REDRAW_INTERVAL)
+        {
+            return;
+        }
+        self.draw(done);
+    }
+
+    /// Draws the final state and ends the line.
+    pub fn finish(&mut self, done: usize) {
+        if self.drawn.is_some() || done > self.initial {
+            self.draw(done);
+            if io::stderr().is_terminal() {
+                eprintln!();
+            }
+        }
+    }
+
+    fn draw(&mut self, done: usize) {
+        self.drawn = Some(Instant::now());
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = match elapsed > 0.0 {
+            true => done.saturating_sub(self.initial) as f64 / elapsed,
+            false => 0.0,
+        };
+        // История может вырасти, пока выгрузка идет
+        let total = self.total.max(done);
+        let filled = match total {
+            0 => BAR_WIDTH,
+            total => BAR_WIDTH * done / total,
+        };
+        let eta = match rate > 0.0 {
+            true => format_duration((total - done) as f64 / rate),
+            false => "--:--".to_string(),
+        };
+        let line = format!(
+            "{} [{}{}] {}/{} operations, {:.0} ops/s, ETA {}",
+            self.label,
+            "#".repeat(filled),
+            ".".repeat(BAR_WIDTH - filled),
+            done,
+            total,
+            rate,
+            eta
+        );
+        // В файл или канал пишутся целые строки, а не перерисовки
+        match io::stderr().is_terminal() {
+            true => eprint!("\r{}\x1b[K", line),
+            false => eprintln!("{}", line),
+        }
+        let _ = io::stderr().flush();
+    }
+}
+
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    match seconds / 3600 {
+        0 => format!("{}:{:02}", seconds / 60, seconds % 60),
+        hours => format!("{}:{:02}:{:02}", hours, seconds / 60 % 60, seconds % 60),
+    }
+}
+
+/// What an interrupted export or restore has confirmed, kept next to its
+/// file as FILE.progress until the transfer is complete.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Operations of the file that are done.
+    pub operations: usize,
+    /// History ID of the last of them on the server.
+    pub operation_id: usize,
+    /// Length of the exported file at that point; 0 for a restore.
+    pub bytes: u64,
+}
+
+impl Checkpoint {
+    pub fn path(file: &Path) -> PathBuf {
+        let mut path = file.as_os_str().to_owned();
+        path.push(".progress");
+        path.into()
+    }
+
+    /// Reads the checkpoint of `file`, `None` if there is none.
+    pub fn load(file: &Path) -> io::Result<Option<Checkpoint>> {
+        let data = match fs::read(Checkpoint::path(file)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let value: serde_json::Value = serde_json::from_slice(&data)?;
+        let field = |name: &str| {
+            value[name].as_u64().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: no {}", Checkpoint::path(file).display(), name),
+                )
+            })
+        };
+        Ok(Some(Checkpoint {
+            operations: field("operations")? as usize,
+            operation_id: field("operation_id")? as usize,
+            bytes: field("bytes")?,
+        }))
+    }
+
+    /// Replaces the checkpoint of `file` as a whole, so an interruption
+    /// leaves either the old one or the new one.
+    pub fn save(&self, file: &Path) -> io::Result<()> {
+        let path = Checkpoint::path(file);
+        let temporary = path.with_extension("progress.tmp");
+        let value = json!({
+            "operations": self.operations,
+            "operation_id": self.operation_id,
+            "bytes": self.bytes,
+        });
+        fs::write(&temporary, value.to_string())?;
+        fs::rename(&temporary, &path)
+    }
+
+    pub fn remove(file: &Path) -> io::Result<()> {
+        match fs::remove_file(Checkpoint::path(file)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::fmt;
+
+/// How the text of a column is aligned.
+#[derive(Debug, Clone, Copy)]
+pub enum Align {
+    Left,
+    Right,
+}
+
+/// One cell of a table; the color is applied after the padding, so the
+/// escape codes do not shift the columns.
+#[derive(Debug, Clone)]
+pub struct Cell {
+    text: String,
+    // Код цвета ANSI
+    color: Option<&'static str>,
+}
+
+const GREEN: &str = "32";
+const RED: &str = "31";
+
+impl Cell {
+    pub fn new(text: impl fmt::Display) -> Self {
+        Cell {
+            text: text.to_string(),
+            color: None,
+        }
+    }
+
+    /// Signed change of a balance: credits in green, debits in red.
+    pub fn change(change: i64) -> Self {
+        Cell {
+            text: format!("{:+}", change),
+            color: match change {
+                0 => None,
+                1.. => Some(GREEN),
+                _ => Some(RED),
+            },
+        }
+    }
+
+    pub fn empty() -> Self {
+        Cell::new("")
+    }
+}
+
+/// Text table with columns as wide as their widest cell.
+#[derive(Debug)]
+pub struct Table {
+    columns: Vec<(&'static str, Align)>,
+    rows: Vec<Vec<Cell>>,
+    color: bool,
+}
+
+impl Table {
+    /// Table with the given column headers; with `color` the cells are
+    /// colored with ANSI escape codes.
+    pub fn new(columns: &[(&'static str, Align)], color: bool) -> Self {
+        Table {
+            columns: columns.to_vec(),
+            rows: Vec::new(),
+            color,
+        }
+    }
+
+    /// Adds a row; missing cells at the end are left empty.
+    pub fn row(&mut self, cells: Vec<Cell>) {
+        self.rows.push(cells);
+    }
+
+    fn widths(&self) -> Vec<usize> {
+        self.columns
+            .iter()
+            .enumerate()
+            .map(|(i, (header, _))| {
+                let cells = self.rows.iter().filter_map(|row| row.get(i));
+                cells
+                    .map(|cell| cell.text.chars().count())
+                    .fold(header.chars().count(), usize::max)
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let widths = self.widths();
+        let line = |f: &mut fmt::Formatter<'_>, cells: &mut dyn Iterator<Item = Cell>| {
+            let mut separator = "";
+            for (i, (cell, (_, align))) in cells.zip(&self.columns).enumerate() {
+                // В конце строки пробелы не нужны
+                let padding = match (align, i + 1 == widths.len()) {
+                    (Align::Left, true) => String::new(),
+                    _ => " ".repeat(widths[i] - cell.text.chars().count()),
+                };
+                let text = match (cell.color, self.color) {
+                    (Some(color), true) => format!("\x1b[{}m{}\x1b[0m", color, cell.text),
+                    _ => cell.text,
+                };
+                match align {
+                    Align::Left => write!(f, "{}{}{}", separator, text, padding)?,
+                    Align::Right => write!(f, "{}{}{}", separator, padding, text)?,
+                }
+                separator = " | ";
+            }
+            writeln!(f)
+        };
+
+        line(
+            f,
+            &mut self.columns.iter().map(|(header, _)| Cell::new(header)),
+        )?;
+        let rule: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+        writeln!(f, "{}", rule.join("-+-"))?;
+        for row in &self.rows {
+            let cells = row
+                .iter()
+                .cloned()
+                .chain(std::iter::repeat_with(Cell::empty));
+            line(f, &mut cells.take(widths.len()))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+
+use banklib::BankClient;
+use protocol_crate::BankError;
+
+#[derive(Parser, Debug)]
+#[command(name = "loadgen")]
+#[command(version = "1.0")]
+#[command(about = "Нагрузочный тест банковского сервера")]
+struct Args {
+    /// Адрес сервера
+    #[arg(long, default_value = "127.0.0.1:7878")]
+    server: String,
+    /// Количество одновременных клиентов
+    #[arg(long, default_value_t = 8)]
+    clients: usize,
+    /// Длительность теста в секундах
+    #[arg(long, default_value_t = 10)]
+    duration: u64,
+    /// Количество счетов, между которыми ходят деньги
+    #[arg(long, default_value_t = 100)]
+    accounts: usize,
+    /// Начальный баланс каждого счета
+    #[arg(long, default_value_t = 1_000_000)]
+    initial_balance: u32,
+    /// Доли операций, например deposit=1,withdraw=1,transfer=4,balance=4
+    #[arg(long, default_value = "deposit=1,withdraw=1,transfer=4,balance=4", value_parser = parse_mix)]
+    mix: Mix,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Op {
+    Deposit,
+    Withdraw,
+    Transfer,
+    Balance,
+    History,
+}
+
+impl Op {
+    fn name(self) -> &'static str {
+        match self {
+            Op::Deposit => "deposit",
+            Op::Withdraw => "withdraw",
+            Op::Transfer => "transfer",
+            Op::Balance => "balance",
+            Op::History => "history",
+        }
+    }
+}
+
+/// Operations with their relative weights.
+#[derive(Debug, Clone)]
+struct Mix(Vec<(Op, u32)>);
+
+impl Mix {
+    fn pick(&self, random: u32) -> Op {
+        let total: u32 = self.0.iter().map(|(_, weight)| weight).sum();
+        let mut point = random % total;
+        for (op, weight) in &self.0 {
+            if point < *weight {
+                return *op;
+            }
+            point -= weight;
+        }
+        unreachable!("point is below the total weight")
+    }
+}
+
+fn parse_mix(value: &str) -> Result<Mix, String> {
+    let mut mix = Vec::new();
+    for part in value.split(',') {
+        let (name, weight) = part
+            .split_once('=')
+            .ok_or_else(|| format!("expected OPERATION=WEIGHT, got {}", part))?;
+        let op = match name.trim() {
+            "deposit" => Op::Deposit,
+            "withdraw" => Op::Withdraw,
+            "transfer" => Op::Transfer,
+            "balance" => Op::Balance,
+            "history" => Op::History,
+            other => return Err(format!("unknown operation {}", other)),
+        };
+        let weight: u32 = weight
+            .trim()
+            .parse()
+            .map_err(|e| format!("{}: {}", part, e))?;
+        mix.push((op, weight));
+    }
+    if mix.iter().all(|(_, weight)| *weight == 0) {
+        return Err("at least one operation needs a non-zero weight".to_string());
+    }
+    Ok(Mix(mix))
+}
+
+/// Xorshift: тесту не нужна криптостойкость, нужна только скорость.
+struct Random(u32);
+
+impl Random {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+}
+
+/// Latencies and error counts of one operation.
+#[derive(Debug, Default)]
+struct Stats {
+    latencies: Vec<Duration>,
+    errors: BTreeMap<String, u64>,
+}
+
+impl Stats {
+    fn merge(&mut self, other: Stats) {
+        self.latencies.extend(other.latencies);
+        for (error, count) in other.errors {
+            *self.errors.entry(error).or_default() += count;
+        }
+    }
+}
+
+fn account_name(index: usize) -> String {
+    format!("loadgen-{}", index)
+}
+
+/// Creates the accounts the clients work with; existing ones are reused.
+fn setup(client: &BankClient, args: &Args) -> Result<(), BankError> {
+    for index in 0..args.accounts {
+        match client.create_account(account_name(index)) {
+            Err(e) if !matches!(e.cause(), BankError::AccountAlreadyExists(_)) => return Err(e),
+            _ => {}
+        }
+        client.increase_account(account_name(index), args.initial_balance)?;
+    }
+    Ok(())
+}
+
+fn run_client(args: &Args, seed: u32, deadline: Instant) -> BTreeMap<Op, Stats> {
+    let client = BankClient::new(&args.server);
+    // Ноль - неподвижная точка xorshift
+    let mut random = Random(seed.wrapping_mul(2654435761) | 1);
+    let mut stats: BTreeMap<Op, Stats> = BTreeMap::new();
+
+    while Instant::now() < deadline {
+        let op = args.mix.pick(random.next());
+        let index = random.next() as usize % args.accounts;
+        let account = account_name(index);
+        let amount = 1 + random.next() % 100;
+
+        let started = Instant::now();
+        let result = match op {
+            Op::Deposit => client.increase_account(account, amount),
+            Op::Withdraw => client.decrease_account(account, amount),
+            Op::Transfer => {
+                // Получатель всегда отличается от отправителя, если счетов больше одного
+                let shift = 1 + random.next() as usize % args.accounts.max(2).saturating_sub(1);
+                let to = account_name((index + shift) % args.accounts);
+                client.transfer(account, to, amount)
+            }
+            Op::Balance => client.get_account_balance(account).map(|_| ()),
+            Op::History => client.account_history(account).map(|_| ()),
+        };
+        let stats = stats.entry(op).or_default();
+        stats.latencies.push(started.elapsed());
+        if let Err(e) = result {
+            // Считаем ошибки по виду, без подробностей
+            let kind = e.cause().name().to_string();
+            *stats.errors.entry(kind).or_default() += 1;
+        }
+    }
+    stats
+}
+
+/// Nearest-rank percentile of sorted latencies.
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    let rank = (sorted.len() * percent).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+fn report(stats: BTreeMap<Op, Stats>, elapsed: Duration) {
+    println!(
+        "{:<10} {:>8} {:>10} {:>8} {:>10} {:>10} {:>10} {:>10}",
+        "operation", "count", "ops/s", "errors", "p50_us", "p90_us", "p99_us", "max_us"
+    );
+    let mut total = Stats::default();
+    for (op, stats) in stats {
+        print_row(op.name(), &stats, elapsed);
+        total.merge(stats);
+    }
+    print_row("total", &total, elapsed);
+
+    for (error, count) in &total.errors {
+        println!("{}: {}", error, count);
+    }
+}
+
+fn print_row(name: &str, stats: &Stats, elapsed: Duration) {
+    let mut latencies = stats.latencies.clone();
+    if latencies.is_empty() {
+        return;
+    }
+    latencies.sort();
+    let errors: u64 = stats.errors.values().sum();
+    println!(
+        "{:<10} {:>8} {:>10.1} {:>7.2}% {:>10} {:>10} {:>10} {:>10}",
+        name,
+        latencies.len(),
+        latencies.len() as f64 / elapsed.as_secs_f64(),
+        errors as f64 * 100.0 / latencies.len() as f64,
+        percentile(&latencies, 50).as_micros(),
+        percentile(&latencies, 90).as_micros(),
+        percentile(&latencies, 99).as_micros(),
+        latencies[latencies.len() - 1].as_micros(),
+    );
+}
+
+fn main() {
+    let args = Args::parse();
+    if args.clients == 0 || args.accounts == 0 {
+        eprintln!("--clients and --accounts must be positive");
+        std::process::exit(1);
+    }
+
+    if let Err(e) = setup(&BankClient::new(&args.server), &args) {
+        eprintln!("Failed to create accounts: {:?}", e);
+        std::process::exit(1);
+    }
+
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(args.duration);
+    let stats = thread::scope(|scope| {
+        let workers: Vec<_> = (0..args.clients)
+            .map(|index| {
+                let args = &args;
+                scope.spawn(move || run_client(args, index as u32, deadline))
+            })
+            .collect();
+
+        let mut stats: BTreeMap<Op, Stats> = BTreeMap::new();
+        for worker in workers {
+            for (op, worker_stats) in worker.join().unwrap() {
+                stats.entry(op).or_default().merge(worker_stats);
+            }
+        }
+        stats
+    });
+    report(stats, started.elapsed());
+}
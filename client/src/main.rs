@@ -1,4 +1,5 @@
 use banklib::BankClient;
+use protocol_crate::{RemoteAccount, ReservationKind, TransactionLeg};
 
 const SERVER_ADDRESS: &str = "127.0.0.1:7878";
 const SERVER_ADDRESS2: &str = "127.0.0.1:7879";
@@ -23,17 +24,44 @@ fn main() {
     let vec = bank_client.account_history("Alice".to_string());
     println!("Alice account operations history= {:?}", vec);
 
-    let history = bank_client.get_history();
+    let history = bank_client.get_history().unwrap_or_default();
     println!("Bank operations history= {:?}", history);
     let history_len = history.len();
 
     let lib2 = BankClient::new(SERVER_ADDRESS2);
-    lib2.restore(history);
+    let restored = lib2.restore(history);
+    println!("Restore = {:?}", restored);
 
-    let history2_len = lib2.get_history();
+    let history2_len = lib2.get_history().map(|history| history.len());
     println!(
         "history_size = {:?} and new history_size = {:?}",
-        history_len,
-        history2_len.len()
+        history_len, history2_len
     );
+
+    let bob_on_second = RemoteAccount {
+        address: SERVER_ADDRESS2.to_string(),
+        account: "Bob".into(),
+    };
+    let remote = bank_client.remote_transfer("Alice", bob_on_second, 1);
+    println!(
+        "Remote transfer Alice -> Bob@{} = {:?}",
+        SERVER_ADDRESS2, remote
+    );
+    let b = lib2.get_account_balance("Bob"); //4
+    println!("Bob balance on second server = {:?}", b);
+
+    let leg = |address: &str, account: &str, kind, amount| TransactionLeg {
+        account: RemoteAccount {
+            address: address.to_string(),
+            account: account.into(),
+        },
+        kind,
+        amount,
+    };
+    let transaction = bank_client.transaction(vec![
+        leg(SERVER_ADDRESS, "Alice", ReservationKind::Debit, 2),
+        leg(SERVER_ADDRESS, "Bob", ReservationKind::Credit, 1),
+        leg(SERVER_ADDRESS2, "Alice", ReservationKind::Credit, 1),
+    ]);
+    println!("Transaction = {:?}", transaction);
 }
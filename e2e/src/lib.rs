@@ -0,0 +1,291 @@
+//! Harness for end-to-end tests: servers embedded in the test process on
+//! ephemeral ports, so tests run in parallel and talk to them over TCP.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use banklib::BankClient;
+use clap::Parser;
+use server::{Clock, SystemClock, TestClock};
+
+/// Token accepted for administrative commands.
+pub const ADMIN_TOKEN: &str = "admin-token";
+/// Token of the identity `alice`.
+pub const ALICE_TOKEN: &str = "alice-token";
+
+/// A server running in a background thread of the test process. It is not
+/// stopped when dropped, only its files are removed.
+pub struct TestServer {
+    address: String,
+    dir: PathBuf,
+}
+
+impl TestServer {
+    pub fn start() -> Self {
+        TestServer::start_with(&[])
+    }
+
+    /// Starts a server with extra command line arguments.
+    pub fn start_with(args: &[&str]) -> Self {
+        TestServer::launch(args, "", Arc::new(SystemClock))
+    }
+
+    /// Starts a server whose time moves only with `clock`.
+    pub fn start_with_clock(clock: &TestClock) -> Self {
+        TestServer::launch(&[], "", Arc::new(clock.clone()))
+    }
+
+    /// Starts a server with extra config file keys and sections, e.g.
+    /// `[snapshots]`.
+    pub fn start_with_config(config: &str) -> Self {
+        TestServer::launch(&[], config, Arc::new(SystemClock))
+    }
+
+    fn launch(args: &[&str], config_sections: &str, clock: Arc<dyn Clock>) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "e2e-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("config.toml");
+        fs::write(
+            &config,
+            format!(
+                "admin_tokens = [\"{}\"]\nidentities = {{ {:?} = \"alice\" }}\n\n{}\n",
+                ADMIN_TOKEN, ALICE_TOKEN, config_sections
+            ),
+        )
+        .unwrap();
+        let log = dir.join("coordinator.log");
+
+        let mut command_line = vec![
+            "server",
+            "0",
+            "--config",
+            config.to_str().unwrap(),
+            "--coordinator-log",
+            log.to_str().unwrap(),
+        ];
+        command_line.extend_from_slice(args);
+        let address =
+            server::spawn_with_clock(server::Args::parse_from(command_line), clock).unwrap();
+        TestServer {
+            address: address.to_string(),
+            dir,
+        }
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub fn config_path(&self) -> PathBuf {
+        self.dir.join("config.toml")
+    }
+
+    pub fn client(&self) -> BankClient {
+        BankClient::new(&self.address)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Starts a SOCKS5 proxy in a background thread and returns its address.
+/// With `credentials` it lets in only that user name and password.
+pub fn start_socks5_proxy(credentials: Option<(&'static str, &'static str)>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            thread::spawn(move || socks5_session(stream, credentials));
+        }
+    });
+    address
+}
+
+fn socks5_session(mut client: TcpStream, credentials: Option<(&str, &str)>) -> io::Result<()> {
+    let mut header = [0; 2];
+    client.read_exact(&mut header)?;
+    let mut methods = vec![0; header[1] as usize];
+    client.read_exact(&mut methods)?;
+    let method = if credentials.is_some() { 2 } else { 0 };
+    if !methods.contains(&method) {
+        return client.write_all(&[5, 0xff]);
+    }
+    client.write_all(&[5, method])?;
+    if let Some((username, password)) = credentials {
+        // Версия согласования по RFC 1929, затем имя и пароль
+        client.read_exact(&mut [0; 1])?;
+        let given = (read_string(&mut client)?, read_string(&mut client)?);
+        let accepted = given == (username.to_string(), password.to_string());
+        client.write_all(&[1, if accepted { 0 } else { 1 }])?;
+        if !accepted {
+            return Ok(());
+        }
+    }
+
+    let mut request = [0; 4];
+    client.read_exact(&mut request)?;
+    let host = match request[3] {
+        1 => {
+            let mut ip = [0; 4];
+            client.read_exact(&mut ip)?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        4 => {
+            let mut ip = [0; 16];
+            client.read_exact(&mut ip)?;
+            format!("[{}]", Ipv6Addr::from(ip))
+        }
+        _ => {
+            let mut len = [0; 1];
+            client.read_exact(&mut len)?;
+            let mut name = vec![0; len[0] as usize];
+            client.read_exact(&mut name)?;
+            String::from_utf8_lossy(&name).into_owned()
+        }
+    };
+    let mut port = [0; 2];
+    client.read_exact(&mut port)?;
+    let server = match TcpStream::connect(format!("{}:{}", host, u16::from_be_bytes(port))) {
+        Ok(server) => server,
+        Err(_) => return client.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]),
+    };
+    client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])?;
+
+    // Данные идут в обе стороны, пока одна из сторон не закроет соединение
+    let (mut upstream, mut downstream) = (server.try_clone()?, client.try_clone()?);
+    thread::spawn(move || {
+        let _ = io::copy(&mut downstream, &mut upstream);
+        let _ = upstream.shutdown(Shutdown::Write);
+    });
+    io::copy(&mut &server, &mut client)?;
+    client.shutdown(Shutdown::Write)
+}
+
+/// Objects of an in-memory S3 storage by bucket and key.
+pub type S3Objects = Arc<Mutex<BTreeMap<String, Vec<u8>>>>;
+
+/// Starts an S3-compatible storage in a background thread that keeps its
+/// objects in memory and returns its address and objects, keyed by
+/// `BUCKET/KEY`. It takes path-style PUT, GET and DELETE of objects and
+/// ListObjectsV2 and only checks that requests are signed.
+pub fn start_s3_server() -> (String, S3Objects) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let objects = S3Objects::default();
+    let served = Arc::clone(&objects);
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let objects = Arc::clone(&served);
+            thread::spawn(move || s3_request(stream, &objects));
+        }
+    });
+    (address, objects)
+}
+
+fn s3_request(mut stream: TcpStream, objects: &S3Objects) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut byte = [0; 1];
+    while !request.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte)?;
+        request.push(byte[0]);
+    }
+    let head = String::from_utf8_lossy(&request).into_owned();
+    let header = |name: &str| {
+        head.lines()
+            .filter_map(|line| line.split_once(": "))
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.to_string())
+    };
+    let length = header("Content-Length").map_or(0, |length| length.parse().unwrap());
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body)?;
+
+    let mut words = head.split(' ');
+    let (method, target) = (
+        words.next().unwrap_or_default(),
+        words.next().unwrap_or_default(),
+    );
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let path = percent_decode(path.trim_start_matches('/'));
+    let signed =
+        header("Authorization").is_some_and(|value| value.starts_with("AWS4-HMAC-SHA256 "));
+    let mut objects = objects.lock().unwrap();
+    let (status, body) = match method {
+        _ if !signed => ("403 Forbidden", Vec::new()),
+        "PUT" => {
+            objects.insert(path, body);
+            ("200 OK", Vec::new())
+        }
+        "GET" if query.contains("list-type=2") => {
+            let prefix = query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("prefix="))
+                .map(percent_decode)
+                .unwrap_or_default();
+            let keys: String = objects
+                .keys()
+                .filter_map(|key| key.strip_prefix(&format!("{}/", path)))
+                .filter(|key| key.starts_with(&prefix))
+                .map(|key| format!("<Contents><Key>{}</Key></Contents>", key))
+                .collect();
+            let list = format!(
+                "<ListBucketResult><IsTruncated>false</IsTruncated>{}</ListBucketResult>",
+                keys
+            );
+            ("200 OK", list.into_bytes())
+        }
+        "GET" => match objects.get(&path) {
+            Some(object) => ("200 OK", object.clone()),
+            None => ("404 Not Found", Vec::new()),
+        },
+        "DELETE" => {
+            objects.remove(&path);
+            ("204 No Content", Vec::new())
+        }
+        _ => ("405 Method Not Allowed", Vec::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n",
+        status,
+        body.len()
+    )?;
+    stream.write_all(&body)
+}
+
+fn percent_decode(text: &str) -> String {
+    let mut decoded = Vec::new();
+    let mut bytes = text.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let hex: String = bytes.by_ref().take(2).map(char::from).collect();
+                decoded.push(u8::from_str_radix(&hex, 16).unwrap_or(b'?'));
+            }
+            _ => decoded.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn read_string(stream: &mut TcpStream) -> io::Result<String> {
+    let mut len = [0; 1];
+    stream.read_exact(&mut len)?;
+    let mut value = vec![0; len[0] as usize];
+    stream.read_exact(&mut value)?;
+    Ok(String::from_utf8_lossy(&value).into_owned())
+}
@@ -0,0 +1,1592 @@
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use banklib::{BankClient, Session, ShardMap, Socks5Proxy};
+use e2e::{start_socks5_proxy, TestServer, ADMIN_TOKEN, ALICE_TOKEN};
+use protocol_crate::codec::{Serializer, WireFormat};
+use protocol_crate::{
+    AccountFilter, AccountLimits, AccountRef, BankError, BatchOperation, Command, Operation,
+    PageRequest, RateLimit, RemoteAccount, ReservationKind, Response, ResponsePayload, ServerInfo,
+    TransactionLeg, MAX_COMMAND_SIZE,
+};
+use server::{Router, TestClock};
+
+/// The error of `result` without the request ID banklib tags it with.
+fn error<T: std::fmt::Debug>(result: Result<T, BankError>) -> BankError {
+    match result.unwrap_err() {
+        BankError::RequestFailed { error, .. } => *error,
+        error => error,
+    }
+}
+
+#[test]
+fn accounts() {
+    let server = TestServer::start();
+    let client = server.client();
+
+    assert_eq!(0, client.create_account("X".to_string()).unwrap());
+    assert_eq!(1, client.create_account("Y".to_string()).unwrap());
+    assert!(matches!(
+        error(client.create_account("X".to_string())),
+        BankError::AccountAlreadyExists(_)
+    ));
+    assert!(matches!(
+        error(client.create_account("X//savings".to_string())),
+        BankError::InvalidAccountName(_)
+    ));
+
+    client.increase_account("X", 10).unwrap();
+    client.decrease_account(AccountRef::Id(0), 3).unwrap();
+    assert!(matches!(
+        error(client.decrease_account("X", 100)),
+        BankError::InsufficientFunds(_)
+    ));
+    assert!(matches!(
+        error(client.increase_account("X", 0)),
+        BankError::IncorrectAmount(0)
+    ));
+    assert!(matches!(
+        error(client.increase_account("Z", 1)),
+        BankError::AccountDoesNotExist(_)
+    ));
+    assert_eq!(7, client.get_account_balance("X").unwrap());
+
+    client.transfer("X", "Y", 2).unwrap();
+    assert!(matches!(
+        error(client.transfer("X", "X", 1)),
+        BankError::TransferToMyself
+    ));
+    assert_eq!(2, client.get_account_balance(AccountRef::Id(1)).unwrap());
+
+    // Условные операции
+    let version = client.get_versioned_balance("X").unwrap();
+    assert_eq!(5, version.balance);
+    assert!(matches!(
+        error(client.transfer_if("X", "Y", 1, version.version + 1)),
+        BankError::Conflict { .. }
+    ));
+    client.transfer_if("X", "Y", 1, version.version).unwrap();
+    assert!(matches!(
+        error(client.decrease_if_balance_at_least("X", 3, 2)),
+        BankError::BelowMinimumBalance { min_after: 2 }
+    ));
+    client.decrease_if_balance_at_least("X", 2, 2).unwrap();
+
+    // Подсчет и ручка счета
+    client.create_account("X/savings".to_string()).unwrap();
+    let savings = client.account("X/savings");
+    savings.deposit(4).unwrap();
+    savings.withdraw(1).unwrap();
+    savings.transfer_to("Y", 1).unwrap();
+    assert_eq!(2, savings.balance().unwrap());
+    assert_eq!(4, savings.history().unwrap().len());
+    assert_eq!(4, client.get_subtree_balance("X").unwrap());
+}
+
+#[test]
+fn history() {
+    let server = TestServer::start();
+    let client = server.client();
+    client.create_account("Alpha".to_string()).unwrap();
+    client.create_account("Beta".to_string()).unwrap();
+    client.increase_account("Alpha", 5).unwrap();
+    client.transfer("Alpha", "Beta", 3).unwrap();
+
+    let history = client.get_history().unwrap();
+    let expected = vec![
+        Operation::CreateAccount("Alpha".to_string()),
+        Operation::CreateAccount("Beta".to_string()),
+        Operation::IncreaseAccount("Alpha".to_string(), 5),
+        Operation::Transfer("Alpha".to_string(), "Beta".to_string(), 3),
+    ];
+    assert_eq!(expected, history);
+    let page = client.get_history_paged(PageRequest::at(1, 2)).unwrap();
+    assert_eq!(expected[1..3], page.items);
+    assert_eq!(2, client.account_history("Beta").unwrap().len());
+    // Баланс до операции с этим номером
+    assert_eq!(0, client.get_balance_at("Alpha", 2).unwrap());
+    assert_eq!(5, client.get_balance_at("Alpha", 3).unwrap());
+    let found = client.search_history("beta", 10).unwrap();
+    assert_eq!(
+        vec![1, 3],
+        found.iter().map(|(id, _)| *id).collect::<Vec<_>>()
+    );
+    // Отчет считается на сервере
+    let rows = client
+        .query_history("sum where amount > 0 group by to")
+        .unwrap();
+    assert_eq!(
+        vec![(Some("Alpha"), 1, 5), (Some("Beta"), 1, 3)],
+        rows.iter()
+            .map(|row| (row.group.as_deref(), row.count, row.value))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        1,
+        client.query_history("count where from = Alpha").unwrap()[0].count
+    );
+    assert!(matches!(
+        error(client.query_history("sum by day")),
+        BankError::InvalidQuery(_)
+    ));
+
+    let statement = client.statement("Alpha", 0, u64::MAX).unwrap();
+    assert_eq!(0, statement.opening_balance);
+    assert_eq!(2, statement.closing_balance);
+    assert_eq!(3, statement.lines.len());
+
+    // Другой сервер с той же историей приходит к тому же дайджесту
+    let digest = client.history_digest(None).unwrap();
+    assert_eq!(4, digest.operations);
+    let other = TestServer::start();
+    let copy = other.client();
+    copy.restore(history).unwrap();
+    assert_eq!(digest, copy.history_digest(None).unwrap());
+    assert_eq!(2, copy.get_account_balance("Alpha").unwrap());
+
+    // Несогласованная история не применяется совсем
+    let invalid = vec![
+        Operation::IncreaseAccount("Gamma".to_string(), 1),
+        Operation::CreateAccount("Gamma".to_string()),
+    ];
+    assert!(matches!(
+        error(copy.restore(invalid)),
+        BankError::InvalidHistory { index: 0, .. }
+    ));
+    assert_eq!(4, copy.get_history().unwrap().len());
+}
+
+#[test]
+fn restore_large_history() {
+    let server = TestServer::start();
+    let client = server.client();
+    let mut history = vec![Operation::CreateAccount("X".to_string())];
+    history.extend((0..25_000).map(|_| Operation::IncreaseAccount("X".to_string(), 1)));
+
+    // Одиночная команда не больше MAX_COMMAND_SIZE, большая история идет частями
+    assert!(matches!(
+        error(client.restore(history.clone())),
+        BankError::MessageTooLarge {
+            limit: MAX_COMMAND_SIZE,
+            ..
+        }
+    ));
+    let mut applied = Vec::new();
+    client
+        .restore_with_progress(history, |progress| applied.push(progress.applied))
+        .unwrap();
+    assert_eq!(Some(&25_001), applied.last());
+    assert_eq!(25_000, client.get_account_balance("X").unwrap());
+}
+
+#[test]
+fn batches_and_transactions() {
+    let server = TestServer::start();
+    let client = server.client();
+    client.create_account("X".to_string()).unwrap();
+    client.create_account("Y".to_string()).unwrap();
+
+    let ids = client
+        .batch(vec![
+            BatchOperation::Deposit {
+                account: "X".into(),
+                amount: 10,
+            },
+            BatchOperation::Transfer {
+                from: "X".into(),
+                to: "Y".into(),
+                amount: 4,
+            },
+        ])
+        .unwrap();
+    assert_eq!(vec![2, 3], ids);
+    let failed = client
+        .transaction_builder()
+        .deposit("Y", 1)
+        .withdraw("X", 100)
+        .submit();
+    assert!(matches!(
+        error(failed),
+        BankError::BatchFailed { index: 1, .. }
+    ));
+    assert_eq!(6, client.get_account_balance("X").unwrap());
+    assert_eq!(4, client.get_account_balance("Y").unwrap());
+
+    // Переводы и транзакции между двумя серверами
+    let other = TestServer::start();
+    other.client().create_account("Z".to_string()).unwrap();
+    let z = RemoteAccount {
+        address: other.address().to_string(),
+        account: "Z".into(),
+    };
+    client.remote_transfer("X", z.clone(), 2).unwrap();
+    assert_eq!(2, other.client().get_account_balance("Z").unwrap());
+
+    let leg = |account: RemoteAccount, kind, amount| TransactionLeg {
+        account,
+        kind,
+        amount,
+    };
+    let local = |name: &str| RemoteAccount {
+        address: server.address().to_string(),
+        account: name.into(),
+    };
+    client
+        .transaction(vec![
+            leg(local("Y"), ReservationKind::Debit, 3),
+            leg(z.clone(), ReservationKind::Credit, 3),
+        ])
+        .unwrap();
+    assert_eq!(1, client.get_account_balance("Y").unwrap());
+    assert_eq!(5, other.client().get_account_balance("Z").unwrap());
+
+    // Участник проверяет владельца списываемого счета: координатор передает
+    // ему токен клиента
+    let owners = BTreeSet::from(["alice".to_string()]);
+    other.client().set_account_owners("Z", owners).unwrap();
+    let back = vec![
+        leg(z.clone(), ReservationKind::Debit, 1),
+        leg(local("Y"), ReservationKind::Credit, 1),
+    ];
+    assert!(matches!(
+        error(client.transaction(back.clone())),
+        BankError::Forbidden(_)
+    ));
+    server
+        .client()
+        .with_identity(ALICE_TOKEN)
+        .transaction(back)
+        .unwrap();
+    assert_eq!(2, client.get_account_balance("Y").unwrap());
+    assert_eq!(4, other.client().get_account_balance("Z").unwrap());
+    assert!(matches!(
+        error(client.transaction(vec![
+            leg(local("Y"), ReservationKind::Debit, 1),
+            leg(z, ReservationKind::Credit, 2),
+        ])),
+        BankError::UnbalancedTransaction
+    ));
+
+    let nowhere = RemoteAccount {
+        address: "127.0.0.1:1".to_string(),
+        account: "Z".into(),
+    };
+    assert!(matches!(
+        error(client.remote_transfer("X", nowhere, 1)),
+        BankError::RemoteUnavailable(_)
+    ));
+    assert_eq!(4, client.get_account_balance("X").unwrap());
+}
+
+#[test]
+fn administration() {
+    let server = TestServer::start();
+    let client = server.client();
+    client.create_account("X".to_string()).unwrap();
+    client.create_account("Y".to_string()).unwrap();
+    client.increase_account("X", 10).unwrap();
+
+    // Лимиты
+    let limits = AccountLimits {
+        max_withdrawal: Some(3),
+        ..AccountLimits::default()
+    };
+    assert!(matches!(
+        error(client.set_account_limits("wrong", "X", limits.clone())),
+        BankError::Unauthorized
+    ));
+    client
+        .set_account_limits(ADMIN_TOKEN, "X", limits.clone())
+        .unwrap();
+    assert_eq!(limits, client.get_account_limits("X").unwrap());
+    assert!(matches!(
+        error(client.decrease_account("X", 4)),
+        BankError::LimitExceeded(_)
+    ));
+
+    // Метки, метаданные и поиск
+    let tags = BTreeSet::from(["vip".to_string()]);
+    client
+        .set_account_tags(ADMIN_TOKEN, "X", tags.clone())
+        .unwrap();
+    assert_eq!(tags, client.get_account_tags("X").unwrap());
+    assert_eq!(
+        vec![("X".to_string(), 10)],
+        client.find_accounts_by_tag("vip").unwrap()
+    );
+    client
+        .set_account_metadata("Y", "city", Some("Oslo"))
+        .unwrap();
+    assert_eq!(
+        Some(&"Oslo".to_string()),
+        client.get_account_metadata("Y").unwrap().get("city")
+    );
+    assert_eq!(
+        vec![("X".to_string(), 10)],
+        client.find_accounts(Some(5), None).unwrap()
+    );
+    assert_eq!(
+        vec![("X".to_string(), 10), ("Y".to_string(), 0)],
+        client.top_accounts(2).unwrap()
+    );
+
+    // Владельцы: чужой клиент не может списывать
+    let owners = BTreeSet::from(["alice".to_string()]);
+    client.set_account_owners("Y", owners.clone()).unwrap();
+    assert_eq!(owners, client.get_account_owners("Y").unwrap());
+    let alice = server.client().with_identity(ALICE_TOKEN);
+    client.increase_account("Y", 2).unwrap();
+    alice.decrease_account("Y", 1).unwrap();
+    assert!(matches!(
+        error(client.decrease_account("Y", 1)),
+        BankError::Forbidden(_)
+    ));
+
+    // Обслуживание: изменения отклоняются, чтение работает
+    client.set_maintenance(ADMIN_TOKEN, true).unwrap();
+    assert!(matches!(
+        error(client.increase_account("X", 1)),
+        BankError::Maintenance
+    ));
+    assert_eq!(10, client.get_account_balance("X").unwrap());
+    client.set_maintenance(ADMIN_TOKEN, false).unwrap();
+    client.increase_account("X", 1).unwrap();
+
+    let report = client.check_consistency(ADMIN_TOKEN).unwrap();
+    assert!(report.differences.is_empty());
+    client.reload(ADMIN_TOKEN).unwrap();
+    let metrics = client.metrics(ADMIN_TOKEN).unwrap();
+    let increases = metrics
+        .iter()
+        .find(|metrics| metrics.command == "IncreaseAccount")
+        .unwrap();
+    assert!(increases.count >= 3);
+    assert!(increases.errors >= 1);
+    assert!(matches!(
+        error(client.metrics("wrong")),
+        BankError::Unauthorized
+    ));
+}
+
+#[test]
+fn operator_commands() {
+    let dir = std::env::temp_dir().join(format!("e2e-operator-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let server = TestServer::start_with_config(&format!("[snapshots]\ndir = {:?}\n", dir));
+    let client = server.client();
+    client.create_account("X".to_string()).unwrap();
+
+    // Снимок по команде, без правила в конфиге
+    assert_eq!(1, client.write_snapshot(ADMIN_TOKEN).unwrap());
+    let snapshot = dir.join(format!("snapshot-{:020}.json", 1));
+    let started = std::time::Instant::now();
+    while !snapshot.exists() {
+        assert!(started.elapsed() < Duration::from_secs(5), "no snapshot");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(matches!(
+        error(TestServer::start().client().write_snapshot(ADMIN_TOKEN)),
+        BankError::InvalidConfig(_)
+    ));
+
+    // Токены: новый действует сразу, отозванный - больше нет
+    let tokens = client.list_tokens(ADMIN_TOKEN).unwrap();
+    assert_eq!(2, tokens.len());
+    assert!(tokens.iter().all(|info| !info.token.contains("token")));
+    client
+        .add_token(ADMIN_TOKEN, "bob-token", Some("bob"), false)
+        .unwrap();
+    let bob = server.client().with_identity("bob-token");
+    let id = bob.create_account("Bob".to_string()).unwrap();
+    assert_eq!(
+        BTreeSet::from(["bob".to_string()]),
+        client.get_account_owners(id).unwrap()
+    );
+    client.revoke_token(ADMIN_TOKEN, "bob-token").unwrap();
+    let id = bob.create_account("Bob/savings".to_string()).unwrap();
+    assert!(client.get_account_owners(id).unwrap().is_empty());
+    assert!(matches!(
+        error(client.revoke_token(ADMIN_TOKEN, ADMIN_TOKEN)),
+        BankError::Forbidden(_)
+    ));
+    assert!(matches!(
+        error(client.add_token("wrong", "x", None, true)),
+        BankError::Unauthorized
+    ));
+    assert_eq!(2, client.list_tokens(ADMIN_TOKEN).unwrap().len());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn rate_limits() {
+    let server = TestServer::start();
+    let client = server.client();
+    let id = client.create_account("X".to_string()).unwrap();
+    client.create_account("Y".to_string()).unwrap();
+    let slow = RateLimit {
+        per_second: 1,
+        burst: 3,
+    };
+    client
+        .set_rate_limit(ADMIN_TOKEN, None, Some(slow))
+        .unwrap();
+
+    // Обращения к счету по имени и по номеру берутся из одной корзины
+    client.increase_account("X", 1).unwrap();
+    client.get_account_balance("X").unwrap();
+    client.get_account_balance(id).unwrap();
+    assert!(matches!(
+        error(client.get_account_balance("X")),
+        BankError::RateLimited { account, retry_after_ms } if account == "X" && retry_after_ms <= 1000
+    ));
+    assert!(matches!(
+        error(client.transfer("Y", "X", 1)),
+        BankError::RateLimited { account, .. } if account == "X"
+    ));
+    // Отклоненный перевод не потратил запрос счета Y
+    for _ in 0..3 {
+        client.get_account_balance("Y").unwrap();
+    }
+
+    let fast = RateLimit {
+        per_second: 1000,
+        burst: 100,
+    };
+    client
+        .set_rate_limit(ADMIN_TOKEN, Some("X"), Some(fast))
+        .unwrap();
+    client.get_account_balance("X").unwrap();
+    let limits = client.rate_limits(ADMIN_TOKEN).unwrap();
+    assert_eq!(Some(slow), limits.default);
+    assert_eq!(Some(&fast), limits.accounts.get("X"));
+
+    client.set_rate_limit(ADMIN_TOKEN, None, None).unwrap();
+    client.get_account_balance("Y").unwrap();
+    assert!(matches!(
+        error(client.set_rate_limit("wrong", None, Some(slow))),
+        BankError::Unauthorized
+    ));
+}
+
+#[test]
+fn scheduled_jobs() {
+    let server = TestServer::start_with_config(
+        r#"
+[[jobs]]
+name = "rent"
+schedule = "every 1s"
+task = { transfer = { from = "F", to = "X", amount = 5 } }
+
+[[jobs]]
+name = "interest"
+schedule = "every 1s"
+task = { interest = { from = "F", prefix = "S", rate_bps = 1000 } }
+
+[[jobs]]
+name = "snapshot"
+schedule = "0 3 * * *"
+task = "snapshot"
+"#,
+    );
+    let client = server.client();
+    for account in ["F", "X", "S1"] {
+        client.create_account(account.to_string()).unwrap();
+    }
+    client.increase_account("F", 1000).unwrap();
+    client.increase_account("S1", 100).unwrap();
+
+    // Задания выполняет сам сервер, без запросов клиента
+    let mut waited = 0;
+    while client.get_account_balance("X").unwrap() < 10 {
+        assert!(waited < 100, "standing order did not run");
+        std::thread::sleep(Duration::from_millis(50));
+        waited += 1;
+    }
+    assert!(client.get_account_balance("S1").unwrap() >= 110);
+
+    let jobs = client.list_jobs(ADMIN_TOKEN).unwrap();
+    let names: Vec<&str> = jobs.iter().map(|job| job.name.as_str()).collect();
+    assert_eq!(vec!["rent", "interest", "snapshot"], names);
+    assert_eq!("every 1s", jobs[0].schedule);
+    assert!(jobs[0].last_run.is_some());
+    assert!(jobs[0].next_run > jobs[0].last_run);
+    assert_eq!(None, jobs[2].last_run);
+    assert_eq!(0, jobs[2].next_run.unwrap() % 86_400 / 60 - 3 * 60);
+    assert!(matches!(
+        error(client.list_jobs("wrong")),
+        BankError::Unauthorized
+    ));
+}
+
+#[test]
+fn interest_all_or_nothing() {
+    let server = TestServer::start_with_config(
+        r#"
+[[jobs]]
+name = "interest"
+schedule = "every 1s"
+task = { interest = { from = "F", prefix = "S", rate_bps = 1000 } }
+"#,
+    );
+    let client = server.client();
+    for account in ["F", "S1", "S2"] {
+        client.create_account(account.to_string()).unwrap();
+    }
+    client.increase_account("S1", 100).unwrap();
+    client.increase_account("S2", 100).unwrap();
+    client.increase_account("F", 15).unwrap();
+
+    // Денег хватает только на одну выплату: не получает никто
+    let mut waited = 0;
+    loop {
+        let jobs = client.list_jobs(ADMIN_TOKEN).unwrap();
+        let error = jobs[0].last_error.as_deref().unwrap_or_default();
+        if error.starts_with("InsufficientFunds(20)") {
+            break;
+        }
+        assert!(waited < 100, "interest did not run: {}", error);
+        std::thread::sleep(Duration::from_millis(50));
+        waited += 1;
+    }
+    assert_eq!(15, client.get_account_balance("F").unwrap());
+    assert_eq!(100, client.get_account_balance("S1").unwrap());
+    assert_eq!(100, client.get_account_balance("S2").unwrap());
+}
+
+#[test]
+fn transfer_approval() {
+    let server = TestServer::start_with_config("approval_threshold = 100");
+    let client = server.client();
+    client.create_account("X".to_string()).unwrap();
+    client.create_account("Y".to_string()).unwrap();
+    client.increase_account("X", 501).unwrap();
+
+    client.transfer("X", "Y", 100).unwrap();
+    let BankError::TransferPending(large) = error(client.transfer("X", "Y", 300)) else {
+        panic!("transfer above the threshold was not queued");
+    };
+    let version = client.get_versioned_balance("X").unwrap().version;
+    let BankError::TransferPending(other) = error(client.transfer_if("X", "Y", 101, version))
+    else {
+        panic!("conditional transfer above the threshold was not queued");
+    };
+    // Ждущие переводы держат средства, но баланс не меняют
+    assert_eq!(401, client.get_account_balance("X").unwrap());
+    assert!(matches!(
+        error(client.decrease_account("X", 1)),
+        BankError::InsufficientFunds(1)
+    ));
+    let pending = client.pending_transfers(ADMIN_TOKEN).unwrap();
+    assert_eq!(
+        vec![large, other],
+        pending.iter().map(|t| t.id).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        ("X", "Y", 300),
+        (
+            pending[0].from.as_str(),
+            pending[0].to.as_str(),
+            pending[0].amount
+        )
+    );
+
+    client.approve_transfer(ADMIN_TOKEN, large).unwrap();
+    client.reject_transfer(ADMIN_TOKEN, other).unwrap();
+    assert_eq!(101, client.get_account_balance("X").unwrap());
+    assert_eq!(400, client.get_account_balance("Y").unwrap());
+    client.decrease_account("X", 101).unwrap();
+    assert!(client.pending_transfers(ADMIN_TOKEN).unwrap().is_empty());
+    assert!(matches!(
+        error(client.approve_transfer(ADMIN_TOKEN, other)),
+        BankError::PendingTransferDoesNotExist(id) if id == other
+    ));
+    assert!(matches!(
+        error(client.reject_transfer("wrong", other)),
+        BankError::Unauthorized
+    ));
+
+    // Пакет с крупным переводом не применяется целиком
+    client.increase_account("X", 500).unwrap();
+    let batch = vec![
+        BatchOperation::Transfer {
+            from: "X".into(),
+            to: "Y".into(),
+            amount: 10,
+        },
+        BatchOperation::Transfer {
+            from: "X".into(),
+            to: "Y".into(),
+            amount: 200,
+        },
+    ];
+    assert!(matches!(
+        error(client.batch(batch)),
+        BankError::BatchFailed { index: 1, error } if matches!(*error, BankError::LimitExceeded(_))
+    ));
+    assert_eq!(500, client.get_account_balance("X").unwrap());
+
+    // Крупная выплата из пакета ждет администратора, остальные проходят
+    let results = client
+        .bulk_transfer("X", vec![("Y".into(), 200), ("Y".into(), 50)])
+        .unwrap();
+    let Err(BankError::TransferPending(bulk)) = results[0] else {
+        panic!("bulk payment above the threshold was not queued");
+    };
+    assert!(results[1].is_ok());
+    assert_eq!(450, client.get_account_balance("X").unwrap());
+    assert_eq!(450, client.get_account_balance("Y").unwrap());
+    let pending = client.pending_transfers(ADMIN_TOKEN).unwrap();
+    assert_eq!(
+        vec![(bulk, 200)],
+        pending.iter().map(|t| (t.id, t.amount)).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn scheduled_transfer_approval() {
+    let server = TestServer::start_with_config(
+        r#"
+approval_threshold = 100
+
+[[jobs]]
+name = "rent"
+schedule = "every 1s"
+task = { transfer = { from = "F", to = "X", amount = 300 } }
+
+[[jobs]]
+name = "interest"
+schedule = "every 1s"
+task = { interest = { from = "F", prefix = "S", rate_bps = 1000 } }
+"#,
+    );
+    let client = server.client();
+    for account in ["F", "X", "S1", "S2"] {
+        client.create_account(account.to_string()).unwrap();
+    }
+    client.increase_account("F", 10_000).unwrap();
+    client.increase_account("S1", 2_000).unwrap();
+    client.increase_account("S2", 500).unwrap();
+
+    // Задания ставят крупные переводы в очередь, мелкие проходят сразу
+    let mut waited = 0;
+    while client.get_account_balance("S2").unwrap() == 500 {
+        assert!(waited < 100, "interest was not paid");
+        std::thread::sleep(Duration::from_millis(50));
+        waited += 1;
+    }
+    let pending = client.pending_transfers(ADMIN_TOKEN).unwrap();
+    let queued = |to: &str, amount| {
+        pending
+            .iter()
+            .any(|t| t.from == "F" && t.to == to && t.amount == amount)
+    };
+    assert!(queued("X", 300));
+    assert!(queued("S1", 200));
+    assert_eq!(0, client.get_account_balance("X").unwrap());
+    assert_eq!(2_000, client.get_account_balance("S1").unwrap());
+    let jobs = client.list_jobs(ADMIN_TOKEN).unwrap();
+    assert!(jobs[0]
+        .last_error
+        .as_deref()
+        .is_some_and(|e| e.starts_with("TransferPending")));
+}
+
+#[test]
+fn scheduled_interest_all_or_nothing() {
+    let server = TestServer::start_with_config(
+        r#"
+approval_threshold = 100
+
+[[jobs]]
+name = "interest"
+schedule = "every 1s"
+task = { interest = { from = "F", prefix = "S", rate_bps = 1000 } }
+"#,
+    );
+    let client = server.client();
+    for account in ["F", "S1", "S2"] {
+        client.create_account(account.to_string()).unwrap();
+    }
+    client.increase_account("F", 10_000).unwrap();
+    client.increase_account("S1", 2_000).unwrap();
+    client.increase_account("S2", 500).unwrap();
+    let limits = AccountLimits {
+        max_withdrawal: None,
+        max_daily_outflow: Some(220),
+        velocity: Vec::new(),
+    };
+    client.set_account_limits(ADMIN_TOKEN, "F", limits).unwrap();
+
+    // Выплата в очередь и мелкая выплата вместе превышают лимит: не
+    // делается ни одна, и повтор задания не платит S2 второй раз
+    let mut waited = 0;
+    loop {
+        let jobs = client.list_jobs(ADMIN_TOKEN).unwrap();
+        let error = jobs[0].last_error.as_deref().unwrap_or_default();
+        if error.contains("LimitExceeded") {
+            break;
+        }
+        assert!(waited < 100, "interest did not run: {}", error);
+        std::thread::sleep(Duration::from_millis(50));
+        waited += 1;
+    }
+    assert_eq!(500, client.get_account_balance("S2").unwrap());
+    assert!(client.pending_transfers(ADMIN_TOKEN).unwrap().is_empty());
+    // Ничего не списано и не удерживается: лимит дня еще целиком свободен
+    client.decrease_account("F", 220).unwrap();
+}
+
+#[test]
+fn disputes() {
+    let server = TestServer::start();
+    let client = server.client();
+    client.create_account("X".to_string()).unwrap();
+    client.create_account("Y".to_string()).unwrap();
+    client.increase_account("X", 10).unwrap();
+    client.transfer("X", "Y", 4).unwrap();
+
+    let id = client.open_dispute(3, "charged twice").unwrap();
+    let open = client.disputes(false).unwrap();
+    assert_eq!(1, open.len());
+    assert_eq!(
+        (3, "charged twice"),
+        (open[0].operation, open[0].reason.as_str())
+    );
+    assert!(matches!(
+        error(client.resolve_dispute("wrong", id, true)),
+        BankError::Unauthorized
+    ));
+
+    client.resolve_dispute(ADMIN_TOKEN, id, true).unwrap();
+    assert_eq!(10, client.get_account_balance("X").unwrap());
+    assert!(client.disputes(false).unwrap().is_empty());
+    let resolved = client.disputes(true).unwrap();
+    assert_eq!(Some(4), resolved[0].resolution.unwrap().reversal);
+}
+
+#[test]
+fn anonymized_account() {
+    let server = TestServer::start();
+    let client = server.client();
+    client.create_account("alice".to_string()).unwrap();
+    client.create_account("alice/savings".to_string()).unwrap();
+    client.increase_account("alice", 10).unwrap();
+    client.transfer("alice", "alice/savings", 3).unwrap();
+    assert!(matches!(
+        error(client.anonymize_account("wrong", "alice")),
+        BankError::Unauthorized
+    ));
+
+    let lock = client
+        .lock_account("alice", Duration::from_secs(60))
+        .unwrap();
+
+    let pseudonym = server
+        .client()
+        .with_lock(lock)
+        .anonymize_account(ADMIN_TOKEN, "alice")
+        .unwrap();
+    assert_eq!("anonymous-0", pseudonym);
+    // Блокировка переехала на псевдоним
+    assert!(matches!(
+        error(client.increase_account("anonymous-0", 1)),
+        BankError::AccountLocked(account) if account == "anonymous-0"
+    ));
+    client.unlock_account(lock).unwrap();
+    assert_eq!(7, client.get_account_balance("anonymous-0").unwrap());
+    assert_eq!(
+        3,
+        client.get_account_balance("anonymous-0/savings").unwrap()
+    );
+    assert!(matches!(
+        error(client.get_account_balance("alice")),
+        BankError::AccountDoesNotExist(_)
+    ));
+    let history = client.get_history().unwrap();
+    assert_eq!(5, history.len());
+    assert!(history
+        .iter()
+        .flat_map(|operation| operation.accounts())
+        .all(|name| !name.starts_with("alice")));
+}
+
+#[test]
+fn pagination() {
+    let server = TestServer::start();
+    let client = server.client();
+    for i in 0..5 {
+        client.create_account(format!("account-{}", i)).unwrap();
+        client.increase_account("account-0", i + 1).unwrap();
+    }
+
+    // Обход по курсорам дает то же, что и весь список сразу
+    let mut page = PageRequest::first(2);
+    let mut history = Vec::new();
+    loop {
+        let got = client
+            .account_history_paged("account-0", page.clone())
+            .unwrap();
+        assert!(got.items.len() <= 2);
+        history.extend(got.items);
+        match got.next_cursor {
+            Some(cursor) => page.cursor = Some(cursor),
+            None => break,
+        }
+    }
+    assert_eq!(client.account_history("account-0").unwrap(), history);
+
+    let first = client.get_history_paged(PageRequest::first(7)).unwrap();
+    assert_eq!(
+        client.get_history_paged(PageRequest::at(0, 7)).unwrap(),
+        first
+    );
+    let rest = client
+        .get_history_paged(PageRequest {
+            cursor: first.next_cursor,
+            limit: 100,
+        })
+        .unwrap();
+    assert_eq!(3, rest.items.len());
+    assert_eq!(None, rest.next_cursor);
+
+    let accounts = client
+        .list_accounts_paged(AccountFilter::All, PageRequest::first(5))
+        .unwrap();
+    assert_eq!(
+        client.list_accounts(AccountFilter::All).unwrap(),
+        accounts.items
+    );
+    assert_eq!(None, accounts.next_cursor);
+}
+
+#[test]
+fn projected_history() {
+    let server = TestServer::start();
+    let client = server.client();
+    client.create_account("X".to_string()).unwrap();
+    client.create_account("Y".to_string()).unwrap();
+    client.increase_account("X", 10).unwrap();
+    client.transfer("X", "Y", 4).unwrap();
+
+    let amounts = client
+        .history_amounts(None, PageRequest::first(10))
+        .unwrap();
+    assert_eq!(vec![0, 0, 10, 4], amounts.items);
+    assert_eq!(None, amounts.next_cursor);
+    let counterparties = client
+        .history_counterparties(Some("Y".into()), PageRequest::first(1))
+        .unwrap();
+    assert_eq!(vec![vec!["Y".to_string()]], counterparties.items);
+    let rest = client
+        .history_counterparties(
+            Some("Y".into()),
+            PageRequest {
+                cursor: counterparties.next_cursor,
+                limit: 1,
+            },
+        )
+        .unwrap();
+    assert_eq!(vec![vec!["X".to_string(), "Y".to_string()]], rest.items);
+    assert!(matches!(
+        error(client.history_amounts(Some("Z".into()), PageRequest::first(1))),
+        BankError::AccountDoesNotExist(_)
+    ));
+}
+
+#[test]
+fn diff() {
+    let left = TestServer::start();
+    let right = TestServer::start();
+    for client in [left.client(), right.client()] {
+        for name in ["X", "Y"] {
+            client.create_account(name.to_string()).unwrap();
+        }
+        client.increase_account("X", 10).unwrap();
+    }
+    assert!(banklib::diff(&left.client(), &right.client())
+        .unwrap()
+        .is_empty());
+
+    left.client().transfer("X", "Y", 3).unwrap();
+    right.client().increase_account("Y", 3).unwrap();
+    right.client().create_account("Z".to_string()).unwrap();
+    let report = banklib::diff(&left.client(), &right.client()).unwrap();
+    assert!(report.only_left.is_empty());
+    assert_eq!(vec![("Z".to_string(), 0)], report.only_right);
+    assert_eq!(vec![("X".to_string(), 7, 10)], report.balances);
+    assert_eq!((4, 5), (report.left_operations, report.right_operations));
+    assert_eq!(3, report.common_operations);
+}
+
+#[test]
+fn wire() {
+    let server = TestServer::start();
+    let info = server.client().server_info().unwrap();
+    assert_eq!(server.address(), info.address);
+
+    // Каждый формат проходит весь путь туда и обратно
+    for format in [WireFormat::Json, WireFormat::Bincode, WireFormat::MsgPack] {
+        let client = server.client().with_format(format);
+        let name = format!("account-{}", format.name());
+        let id = client.create_account(name.clone()).unwrap();
+        client.increase_account(id, 3).unwrap();
+        assert_eq!(3, client.get_account_balance(name.as_str()).unwrap());
+    }
+    let client = server.client().negotiate_format().unwrap();
+    assert_eq!(WireFormat::Bincode, client.format());
+
+    // Конвейер: много команд на одном соединении, ответы по ID
+    let pipeline = client.pipeline().unwrap();
+    let pending: Vec<_> = (0..50)
+        .map(|_| {
+            pipeline
+                .send(Command::IncreaseAccount("account-json".into(), 1))
+                .unwrap()
+        })
+        .collect();
+    for pending in pending {
+        assert!(matches!(
+            pending.wait(),
+            Ok(ResponsePayload::OperationId(_))
+        ));
+    }
+    assert_eq!(53, client.get_account_balance("account-json").unwrap());
+
+    // Срок ответа и ошибка недоступного сервера
+    let late = server.client().with_deadline(Duration::ZERO);
+    assert!(matches!(
+        error(late.get_account_balance("account-json")),
+        BankError::DeadlineExceeded
+    ));
+    let nowhere = BankClient::new("127.0.0.1:1");
+    assert!(matches!(
+        error(nowhere.get_history()),
+        BankError::RemoteUnavailable(_)
+    ));
+}
+
+#[test]
+fn host_names_and_proxies() {
+    let server = TestServer::start();
+    let port = server.address().rsplit_once(':').unwrap().1;
+    let by_name = format!("localhost:{}", port);
+    let client = BankClient::new(&by_name);
+    client.create_account("X".to_string()).unwrap();
+
+    // Имя сервера разрешает прокси, а не клиент
+    let proxy = start_socks5_proxy(None);
+    let proxied = BankClient::new(&by_name).with_socks5_proxy(Socks5Proxy::new(&proxy));
+    proxied.increase_account("X", 5).unwrap();
+    let pipeline = proxied.pipeline().unwrap();
+    let pending = pipeline
+        .send(Command::IncreaseAccount("X".into(), 1))
+        .unwrap();
+    assert!(pending.wait().is_ok());
+    assert_eq!(6, client.get_account_balance("X").unwrap());
+
+    let proxy = start_socks5_proxy(Some(("bastion", "secret")));
+    let through = |proxy: Socks5Proxy| BankClient::new(server.address()).with_socks5_proxy(proxy);
+    let authenticated = through(Socks5Proxy::new(&proxy).with_credentials("bastion", "secret"));
+    assert_eq!(6, authenticated.get_account_balance("X").unwrap());
+    for rejected in [
+        Socks5Proxy::new(&proxy),
+        Socks5Proxy::new(&proxy).with_credentials("bastion", "wrong"),
+    ] {
+        assert!(matches!(
+            error(through(rejected).get_account_balance("X")),
+            BankError::RemoteUnavailable(_)
+        ));
+    }
+    let unreachable = BankClient::new("127.0.0.1:1")
+        .with_socks5_proxy(Socks5Proxy::new(&start_socks5_proxy(None)));
+    assert!(matches!(
+        error(unreachable.get_account_balance("X")),
+        BankError::RemoteUnavailable(_)
+    ));
+}
+
+#[test]
+fn proxy_protocol() {
+    let server = TestServer::start_with_config("[proxy_protocol]\nenabled = true\n");
+    // Балансировщик шлет заголовок и сразу за ним данные клиента
+    let handshake = |header: &[u8]| -> Option<ServerInfo> {
+        let mut stream = TcpStream::connect(server.address()).unwrap();
+        let command = WireFormat::Json.encode_command(&Command::Handshake);
+        stream.write_all(&[header, &command].concat()).unwrap();
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).ok()?;
+        match WireFormat::Json.decode::<Response>(&data) {
+            Ok(Ok(ResponsePayload::ServerInfo(info))) => Some(info),
+            _ => None,
+        }
+    };
+    let info = handshake(b"PROXY TCP4 203.0.113.7 10.0.0.1 4321 7878\r\n").unwrap();
+    assert_eq!(Some("203.0.113.7:4321".to_string()), info.client_address);
+    let mut v2 = b"\r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x24".to_vec();
+    v2.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+    v2.extend_from_slice(&[0; 11]);
+    v2.extend_from_slice(&[1]);
+    v2.extend_from_slice(&[0; 16]);
+    v2.extend_from_slice(&[0x10, 0xe1, 0x1e, 0xc6]);
+    let info = handshake(&v2).unwrap();
+    assert_eq!(Some("[2001:db8::1]:4321".to_string()), info.client_address);
+    // Без заголовка соединение закрывается, не выполнив команду
+    assert_eq!(None, handshake(b""));
+    assert!(server.client().server_info().is_err());
+
+    // Заголовок ждется только от доверенных балансировщиков
+    let server = TestServer::start_with_config(
+        "[proxy_protocol]\nenabled = true\ntrusted = [\"10.0.0.1\"]\n",
+    );
+    let info = server.client().server_info().unwrap();
+    assert!(info.client_address.unwrap().starts_with("127.0.0.1:"));
+}
+
+#[test]
+fn daily_limits() {
+    let clock = TestClock::at(1_000 * 24 * 60 * 60);
+    let server = TestServer::start_with_clock(&clock);
+    let client = server.client();
+    client.create_account("X".to_string()).unwrap();
+    client.increase_account("X", 100).unwrap();
+    let limits = AccountLimits {
+        max_daily_outflow: Some(10),
+        ..AccountLimits::default()
+    };
+    client.set_account_limits(ADMIN_TOKEN, "X", limits).unwrap();
+
+    client.decrease_account("X", 10).unwrap();
+    clock.advance(12 * 60 * 60);
+    assert!(matches!(
+        error(client.decrease_account("X", 1)),
+        BankError::LimitExceeded(_)
+    ));
+    // Наступил следующий день
+    clock.advance(12 * 60 * 60);
+    client.decrease_account("X", 10).unwrap();
+    assert_eq!(80, client.get_account_balance("X").unwrap());
+}
+
+#[test]
+fn read_your_writes() {
+    let primary = TestServer::start();
+    let client = primary.client();
+    client.create_account("X".to_string()).unwrap();
+    // Реплика сама не синхронизируется, пока идет тест
+    let replica = TestServer::start_with(&[
+        "--replica-of",
+        primary.address(),
+        "--replica-sync-ms",
+        "3600000",
+    ]);
+    client.increase_account("X", 5).unwrap();
+    assert_eq!(0, replica.client().get_account_balance("X").unwrap());
+
+    let session = Session::new();
+    let writer = primary.client().with_session(session.clone());
+    let reader = replica.client().with_session(session.clone());
+    writer.increase_account("X", 2).unwrap();
+    assert_eq!(3, session.position());
+    assert_eq!(7, reader.get_account_balance("X").unwrap());
+
+    // Позицию, которой нет у основного сервера, не догнать и реплике
+    let ahead = Session::at(10);
+    assert!(matches!(
+        error(replica.client().with_session(ahead.clone()).get_account_balance("X")),
+        BankError::NotPrimary(address) if address == primary.address()
+    ));
+    assert!(matches!(
+        error(
+            primary
+                .client()
+                .with_session(ahead)
+                .get_account_balance("X")
+        ),
+        BankError::PositionNotReached {
+            position: 10,
+            operations: 3
+        }
+    ));
+}
+
+#[test]
+fn interactive_transaction() {
+    let server = TestServer::start();
+    let client = server.client();
+    client.create_account("X".to_string()).unwrap();
+    client.create_account("Y".to_string()).unwrap();
+    client.increase_account("X", 10).unwrap();
+    let pipeline = client.pipeline().unwrap();
+
+    let transaction = pipeline.begin().unwrap();
+    let balance = transaction.balance("X").unwrap();
+    assert_eq!(1, transaction.withdraw("X", balance / 2).unwrap());
+    assert_eq!(2, transaction.transfer("X", "Y", 3).unwrap());
+    // До фиксации операции никому не видны
+    assert_eq!(10, client.get_account_balance("X").unwrap());
+    assert_eq!(vec![3, 4], transaction.commit().unwrap());
+    assert_eq!(2, client.get_account_balance("X").unwrap());
+    assert_eq!(3, client.get_account_balance("Y").unwrap());
+
+    // Счет, который транзакция прочитала, изменили до ее фиксации
+    let transaction = pipeline.begin().unwrap();
+    transaction.balance("X").unwrap();
+    client.increase_account("X", 1).unwrap();
+    transaction.deposit("Y", 1).unwrap();
+    assert!(matches!(
+        error(transaction.commit()),
+        BankError::TransactionConflict(account) if account == "X"
+    ));
+    assert_eq!(3, client.get_account_balance("Y").unwrap());
+
+    let transaction = pipeline.begin().unwrap();
+    transaction.deposit("Y", 1).unwrap();
+    assert!(matches!(
+        error(pipeline.send(Command::CreateAccount("Z".to_string())).unwrap().wait()),
+        BankError::UnsupportedInTransaction(command) if command == "CreateAccount"
+    ));
+    assert!(matches!(
+        error(pipeline.begin().map(drop)),
+        BankError::TransactionAlreadyOpen
+    ));
+    // Брошенная транзакция откатывается
+    drop(transaction);
+    pipeline.begin().unwrap().rollback().unwrap();
+    assert_eq!(3, client.get_account_balance("Y").unwrap());
+}
+
+#[test]
+fn subscription() {
+    let server = TestServer::start();
+    let client = server.client();
+    client.create_account("X".to_string()).unwrap();
+    client.create_account("Y".to_string()).unwrap();
+    client.increase_account("X", 5).unwrap();
+
+    let subscriber = server.client();
+    let events = std::thread::spawn(move || {
+        let mut events = Vec::new();
+        subscriber
+            .subscribe(Some(1), Some("X".into()), |id, operation| {
+                events.push((id, operation));
+                events.len() < 3
+            })
+            .map(|()| events)
+    });
+    client.transfer("X", "Y", 2).unwrap();
+    client.increase_account("Y", 1).unwrap();
+    client.decrease_account("X", 1).unwrap();
+    // Старые операции приходят сразу, новые - по мере появления
+    assert_eq!(
+        vec![
+            (2, Operation::IncreaseAccount("X".to_string(), 5)),
+            (3, Operation::Transfer("X".to_string(), "Y".to_string(), 2)),
+            (5, Operation::DecreaseAccount("X".to_string(), 1)),
+        ],
+        events.join().unwrap().unwrap()
+    );
+
+    assert!(matches!(
+        error(client.subscribe(None, Some("Z".into()), |_, _| true)),
+        BankError::AccountDoesNotExist(_)
+    ));
+    // Без конвейерного соединения операции присылать некуда
+    assert!(matches!(
+        error(client.execute(Command::Subscribe {
+            from: None,
+            account: None,
+        })),
+        BankError::ProtocolError(_)
+    ));
+}
+
+#[test]
+fn interactive_transaction_checks() {
+    let server = TestServer::start_with_config("approval_threshold = 100");
+    let client = server.client();
+    client.create_account("X".to_string()).unwrap();
+    client.create_account("Y".to_string()).unwrap();
+    client.increase_account("X", 500).unwrap();
+    let pipeline = client.pipeline().unwrap();
+
+    // Крупный перевод не копится в транзакции мимо очереди администратора
+    let transaction = pipeline.begin().unwrap();
+    transaction.transfer("X", "Y", 50).unwrap();
+    assert!(matches!(
+        error(transaction.transfer("X", "Y", 200)),
+        BankError::LimitExceeded(_)
+    ));
+    assert_eq!(vec![3], transaction.commit().unwrap());
+    assert_eq!(450, client.get_account_balance("X").unwrap());
+    assert!(client.pending_transfers(ADMIN_TOKEN).unwrap().is_empty());
+
+    // Счет заблокировали после того, как изменение попало в транзакцию
+    let transaction = pipeline.begin().unwrap();
+    transaction.deposit("Y", 1).unwrap();
+    let lock = client.lock_account("Y", Duration::from_secs(60)).unwrap();
+    assert!(matches!(
+        error(transaction.commit()),
+        BankError::AccountLocked(account) if account == "Y"
+    ));
+    assert_eq!(50, client.get_account_balance("Y").unwrap());
+
+    // Изменение под своей блокировкой фиксируется
+    let transaction = pipeline.begin().unwrap();
+    let deposit = Command::WithLock {
+        lock,
+        command: Box::new(Command::IncreaseAccount("Y".into(), 1)),
+    };
+    pipeline.send(deposit).unwrap().wait().unwrap();
+    transaction.commit().unwrap();
+    assert_eq!(51, client.get_account_balance("Y").unwrap());
+}
+
+#[test]
+fn account_locks() {
+    let clock = TestClock::at(1_000);
+    let server = TestServer::start_with_clock(&clock);
+    let client = server.client();
+    client.create_account("X".to_string()).unwrap();
+    client.create_account("Y".to_string()).unwrap();
+    client.increase_account("X", 10).unwrap();
+
+    let lock = client.lock_account("X", Duration::from_secs(60)).unwrap();
+    assert!(matches!(
+        error(client.increase_account("X", 1)),
+        BankError::AccountLocked(account) if account == "X"
+    ));
+    assert!(matches!(
+        error(client.lock_account("X", Duration::from_secs(60))),
+        BankError::AccountLocked(_)
+    ));
+    // Чтение и другие счета блокировка не затрагивает
+    assert_eq!(10, client.get_account_balance("X").unwrap());
+    client.increase_account("Y", 1).unwrap();
+    let holder = server.client().with_lock(lock);
+    holder.transfer("X", "Y", 4).unwrap();
+    client.unlock_account(lock).unwrap();
+    client.increase_account("X", 1).unwrap();
+    assert!(matches!(
+        error(client.unlock_account(lock)),
+        BankError::LockDoesNotExist(_)
+    ));
+
+    // Аренда кончилась, счет снова свободен
+    client.lock_account("X", Duration::from_secs(60)).unwrap();
+    clock.advance(59);
+    assert!(matches!(
+        error(client.increase_account("X", 1)),
+        BankError::AccountLocked(_)
+    ));
+    clock.advance(1);
+    client.increase_account("X", 1).unwrap();
+    assert_eq!(8, client.get_account_balance("X").unwrap());
+}
+
+#[test]
+fn multi_account_locks() {
+    let server = TestServer::start();
+    let client = server.client();
+    for name in ["X", "Y", "Z"] {
+        client.create_account(name.to_string()).unwrap();
+    }
+    client.increase_account("X", 10).unwrap();
+    // Порядок и повторы счетов не важны
+    let accounts = vec!["Y".into(), AccountRef::Id(0), "Y".into()];
+    let lock = client
+        .lock_accounts(accounts, Duration::from_secs(60))
+        .unwrap();
+    assert!(matches!(
+        error(client.lock_accounts(vec!["Z".into(), "Y".into()], Duration::from_secs(60))),
+        BankError::AccountLocked(account) if account == "Y"
+    ));
+    client.increase_account("Z", 1).unwrap();
+
+    let batch = vec![
+        BatchOperation::Transfer {
+            from: "X".into(),
+            to: "Y".into(),
+            amount: 4,
+        },
+        BatchOperation::Deposit {
+            account: "Z".into(),
+            amount: 1,
+        },
+    ];
+    assert!(matches!(
+        error(client.batch(batch.clone())),
+        BankError::AccountLocked(account) if account == "X"
+    ));
+    server.client().with_lock(lock).batch(batch).unwrap();
+    assert_eq!(4, client.get_account_balance("Y").unwrap());
+    client.unlock_account(lock).unwrap();
+    client.decrease_account("Y", 4).unwrap();
+}
+
+#[test]
+fn sharding_router() {
+    let shards = [TestServer::start(), TestServer::start()];
+    let addresses: Vec<String> = shards.iter().map(|s| s.address().to_string()).collect();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let router = BankClient::new(&listener.local_addr().unwrap().to_string());
+    let ring = Router::new(addresses.clone());
+    std::thread::spawn(move || Router::new(addresses).serve(listener));
+
+    let names: Vec<String> = (0..20).map(|i| format!("client-{}", i)).collect();
+    for (i, name) in names.iter().enumerate() {
+        router.create_account(name.clone()).unwrap();
+        router
+            .increase_account(name.as_str(), i as u32 + 1)
+            .unwrap();
+    }
+    // Каждый счет лежит на своем шарде и только на нем
+    for name in &names {
+        let owner = BankClient::new(ring.shard(name));
+        assert!(owner.get_account_balance(name.as_str()).is_ok());
+    }
+    let (left, right) = (
+        names
+            .iter()
+            .find(|name| ring.shard(name) == shards[0].address())
+            .unwrap(),
+        names
+            .iter()
+            .find(|name| ring.shard(name) == shards[1].address())
+            .unwrap(),
+    );
+
+    let before = router.get_account_balance(left.as_str()).unwrap();
+    router.transfer(left.as_str(), right.as_str(), 1).unwrap();
+    assert_eq!(
+        before - 1,
+        router.get_account_balance(left.as_str()).unwrap()
+    );
+    assert_eq!(20, router.list_accounts(AccountFilter::All).unwrap().len());
+    // Страницы идут по шардам друг за другом, не теряя и не повторяя счетов
+    let mut paged = Vec::new();
+    let mut page = PageRequest::first(3);
+    loop {
+        let got = router
+            .list_accounts_paged(AccountFilter::All, page.clone())
+            .unwrap();
+        paged.extend(got.items.into_iter().map(|(name, _)| name));
+        match got.next_cursor {
+            Some(cursor) => page.cursor = Some(cursor),
+            None => break,
+        }
+    }
+    paged.sort();
+    let mut expected = names.clone();
+    expected.sort();
+    assert_eq!(expected, paged);
+    assert_eq!(
+        vec![("client-19".to_string(), 20), ("client-18".to_string(), 19)],
+        router.top_accounts(2).unwrap()
+    );
+    assert!(router.get_history().unwrap().len() >= 40);
+    assert!(matches!(
+        error(router.get_account_balance(AccountRef::Id(0))),
+        BankError::NotRoutable(_)
+    ));
+}
+
+#[test]
+fn shard_aware_client() {
+    let shards = [TestServer::start(), TestServer::start()];
+    let addresses: Vec<String> = shards.iter().map(|s| s.address().to_string()).collect();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let router_address = listener.local_addr().unwrap().to_string();
+    let routed = addresses.clone();
+    std::thread::spawn(move || Router::new(routed).serve(listener));
+
+    let direct = BankClient::new(&router_address).discover_shards().unwrap();
+    assert_eq!(addresses, direct.get_shard_map().unwrap());
+    assert_eq!(
+        vec![shards[0].address().to_string()],
+        shards[0].client().get_shard_map().unwrap()
+    );
+    let names: Vec<String> = (0..10).map(|i| format!("client-{}", i)).collect();
+    for name in &names {
+        direct.create_account(name.clone()).unwrap();
+        direct.increase_account(name.as_str(), 10).unwrap();
+    }
+    let router = BankClient::new(&router_address);
+    assert_eq!(10, router.list_accounts(AccountFilter::All).unwrap().len());
+
+    // Без маршрутизатора команды на один счет идут прямо на шарды, а остальные не проходят
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let nowhere = listener.local_addr().unwrap().to_string();
+    drop(listener);
+    let direct = BankClient::new(&nowhere).with_shards(ShardMap::new(addresses));
+    for name in &names {
+        assert_eq!(10, direct.get_account_balance(name.as_str()).unwrap());
+    }
+    assert!(matches!(
+        error(direct.list_accounts(AccountFilter::All)),
+        BankError::RemoteUnavailable(_)
+    ));
+}
+
+#[test]
+fn bulk_transfer() {
+    let server = TestServer::start();
+    let client = server.client();
+    for name in ["payroll", "alice", "bob"] {
+        client.create_account(name.to_string()).unwrap();
+    }
+    client.increase_account("payroll", 100).unwrap();
+
+    let results = client
+        .bulk_transfer(
+            "payroll",
+            vec![
+                ("alice".into(), 30),
+                ("carol".into(), 50),
+                ("bob".into(), 20),
+            ],
+        )
+        .unwrap();
+    assert!(results[0].is_ok());
+    assert!(matches!(results[1], Err(BankError::AccountDoesNotExist(_))));
+    assert!(results[2].is_ok());
+    assert_eq!(50, client.get_account_balance("payroll").unwrap());
+    assert_eq!(30, client.get_account_balance("alice").unwrap());
+
+    // На всех не хватает: не платится никому
+    assert!(matches!(
+        error(client.bulk_transfer("payroll", vec![("alice".into(), 30), ("bob".into(), 30)])),
+        BankError::InsufficientFunds(60)
+    ));
+    assert_eq!(50, client.get_account_balance("payroll").unwrap());
+    assert_eq!(20, client.get_account_balance("bob").unwrap());
+}
+
+#[test]
+fn multi_signature() {
+    let server = TestServer::start();
+    let client = server.client();
+    client
+        .add_token(ADMIN_TOKEN, "bob-token", Some("bob"), false)
+        .unwrap();
+    let alice = server.client().with_identity(ALICE_TOKEN);
+    let bob = server.client().with_identity("bob-token");
+    client.create_account("treasury".to_string()).unwrap();
+    client.create_account("vendor".to_string()).unwrap();
+    client.increase_account("treasury", 100).unwrap();
+    let owners = BTreeSet::from(["alice".to_string(), "bob".to_string()]);
+    alice.set_account_owners("treasury", owners).unwrap();
+    assert!(matches!(
+        error(alice.set_required_approvals("wrong", "treasury", 2)),
+        BankError::Unauthorized
+    ));
+    alice
+        .set_required_approvals(ADMIN_TOKEN, "treasury", 2)
+        .unwrap();
+
+    assert!(matches!(
+        error(alice.transfer("treasury", "vendor", 30)),
+        BankError::ApprovalRequired(_)
+    ));
+    let proposal = alice.propose_transfer("treasury", "vendor", 30).unwrap();
+    assert_eq!(None, proposal.operation);
+    assert!(matches!(
+        error(alice.approve_proposal(proposal.id)),
+        BankError::AlreadyApproved(_)
+    ));
+    assert!(matches!(
+        error(client.approve_proposal(proposal.id)),
+        BankError::Forbidden(_)
+    ));
+    let approved = bob.approve_proposal(proposal.id).unwrap();
+    assert_eq!((2, 2), (approved.approvals, approved.required));
+    assert!(approved.operation.is_some());
+    assert_eq!(30, bob.get_account_balance("vendor").unwrap());
+}
+
+#[test]
+fn low_balance_alerts() {
+    let webhook = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/alerts", webhook.local_addr().unwrap());
+    let (sender, received) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for mut stream in webhook.incoming().flatten() {
+            // Запрос приходит одной записью: заголовки и тело в формате JSON
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            while !String::from_utf8_lossy(&request).ends_with('}') {
+                let n = stream.read(&mut buffer).unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buffer[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            let _ = sender.send(String::from_utf8(request).unwrap());
+        }
+    });
+    let server = TestServer::start_with_config(&format!("alert_webhook = {:?}\n", url));
+    let client = server.client();
+    client.create_account("X".to_string()).unwrap();
+    client.increase_account("X", 100).unwrap();
+    client.set_alert_rule("X", Some(50)).unwrap();
+    assert!(client.alerts().unwrap().is_empty());
+
+    client.decrease_account("X", 60).unwrap();
+    let alerts = client.alerts().unwrap();
+    assert_eq!(
+        vec![("X".to_string(), 50, 40)],
+        alerts
+            .iter()
+            .map(|alert| (alert.account.clone(), alert.below, alert.balance))
+            .collect::<Vec<_>>()
+    );
+    let request = received.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(request.starts_with("POST /alerts HTTP/1.1\r\n"));
+    assert!(request.contains(r#""account":"X""#));
+
+    // Баланс вернулся: оповещение снято, повторно не отправляется, пока не сработает снова
+    client.increase_account("X", 10).unwrap();
+    assert!(client.alerts().unwrap().is_empty());
+    client.decrease_account("X", 1).unwrap();
+    assert_eq!(1, client.alerts().unwrap().len());
+    assert!(received.recv_timeout(Duration::from_secs(5)).is_ok());
+}
+
+#[test]
+fn capacity_limits() {
+    let server = TestServer::start_with_config("[capacity]\nmax_accounts = 1\nmax_history = 3\n");
+    let client = server.client();
+    client.create_account("X".to_string()).unwrap();
+    assert!(matches!(
+        error(client.create_account("Y".to_string())),
+        BankError::CapacityExceeded { resource, limit: 1 } if resource == "accounts"
+    ));
+    client.increase_account("X", 10).unwrap();
+    client.decrease_account("X", 1).unwrap();
+    assert!(matches!(
+        error(client.increase_account("X", 1)),
+        BankError::CapacityExceeded { resource, limit: 3 } if resource == "history"
+    ));
+
+    let stats = client.stats(ADMIN_TOKEN).unwrap();
+    assert_eq!(server.address(), stats.address);
+    assert_eq!(None, stats.durability);
+    assert_eq!(
+        (1, 3, Some(1), Some(3)),
+        (
+            stats.accounts,
+            stats.operations,
+            stats.max_accounts,
+            stats.max_history
+        )
+    );
+    assert!(stats.account_bytes > 0 && stats.history_bytes > 0);
+    assert!(matches!(
+        error(client.stats(ALICE_TOKEN)),
+        BankError::Unauthorized
+    ));
+}
@@ -0,0 +1,181 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use banklib::{Fault, Faults};
+use e2e::TestServer;
+use protocol_crate::codec::{Serializer, WireFormat};
+use protocol_crate::{BankError, Command, RemoteAccount};
+
+/// The error of `result` without the request ID banklib tags it with.
+fn error<T: std::fmt::Debug>(result: Result<T, BankError>) -> BankError {
+    match result.unwrap_err() {
+        BankError::RequestFailed { error, .. } => *error,
+        error => error,
+    }
+}
+
+#[test]
+fn injected_faults() {
+    let server = TestServer::start();
+    let faults = Faults::new();
+    let client = server.client().with_faults(faults.clone());
+    client.create_account("X".to_string()).unwrap();
+
+    // Потерянный запрос не выполняется, потерянный ответ - выполняется
+    faults.inject(Fault::Reset { delivered: false });
+    assert!(matches!(
+        error(client.increase_account("X", 1)),
+        BankError::RemoteUnavailable(_)
+    ));
+    assert_eq!(0, client.get_account_balance("X").unwrap());
+    faults.inject(Fault::Reset { delivered: true });
+    assert!(matches!(
+        error(client.increase_account("X", 1)),
+        BankError::RemoteUnavailable(_)
+    ));
+    assert_eq!(1, client.get_account_balance("X").unwrap());
+
+    // Повтор команды выполняет ее дважды
+    faults.inject(Fault::Duplicate);
+    client.increase_account("X", 10).unwrap();
+    assert_eq!(21, client.get_account_balance("X").unwrap());
+
+    faults.inject(Fault::Truncate(3));
+    assert!(matches!(
+        error(client.get_account_balance("X")),
+        BankError::UnexpectedResponse(_)
+    ));
+
+    // Сценарий расходуется по одной записи на команду
+    faults
+        .inject(Fault::Pass)
+        .inject(Fault::Delay(Duration::from_millis(200)));
+    assert_eq!(2, faults.pending());
+    let started = Instant::now();
+    client.get_account_balance("X").unwrap();
+    client.get_account_balance("X").unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    assert_eq!(0, faults.pending());
+    assert_eq!(21, client.get_account_balance("X").unwrap());
+}
+
+/// Proxy to the server at `target` for the commands one server sends
+/// another. The responses to the first `lost` commits of reservations are
+/// lost: the server commits, but the connection closes without an answer.
+fn start_lossy_proxy(target: &str, lost: Arc<AtomicUsize>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let target = target.to_string();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = vec![0; 64 * 1024];
+            let n = stream.read(&mut request).unwrap();
+            request.truncate(n);
+            let mut server = TcpStream::connect(&target).unwrap();
+            server.write_all(&request).unwrap();
+            let mut response = Vec::new();
+            server.read_to_end(&mut response).unwrap();
+            let (format, body) = WireFormat::detect(&request).unwrap();
+            let command: Command = format.decode(body).unwrap();
+            let lose = matches!(command, Command::CommitReservation(_))
+                && lost
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                    .is_ok();
+            if !lose {
+                let _ = stream.write_all(&response);
+            }
+        }
+    });
+    address
+}
+
+#[test]
+fn remote_commit_in_doubt() {
+    let server = TestServer::start();
+    let client = server.client();
+    client.create_account("X".to_string()).unwrap();
+    client.increase_account("X", 10).unwrap();
+    let other = TestServer::start();
+    other.client().create_account("Z".to_string()).unwrap();
+    let lost = Arc::new(AtomicUsize::new(0));
+    let z = RemoteAccount {
+        address: start_lossy_proxy(other.address(), lost.clone()),
+        account: "Z".into(),
+    };
+
+    // Ответ на фиксацию потерялся: зачисление находится в истории получателя
+    lost.store(1, Ordering::SeqCst);
+    client.remote_transfer("X", z.clone(), 3).unwrap();
+    assert_eq!(7, client.get_account_balance("X").unwrap());
+    assert_eq!(3, other.client().get_account_balance("Z").unwrap());
+
+    // Ответа нет и на повторы: исход неясен, средства остаются удержанными
+    lost.store(usize::MAX, Ordering::SeqCst);
+    assert!(matches!(
+        error(client.remote_transfer("X", z, 4)),
+        BankError::RemoteUnavailable(message) if message.contains("in doubt")
+    ));
+    assert_eq!(7, other.client().get_account_balance("Z").unwrap());
+    assert_eq!(7, client.get_account_balance("X").unwrap());
+    assert!(matches!(
+        error(client.decrease_account("X", 7)),
+        BankError::InsufficientFunds(7)
+    ));
+    client.decrease_account("X", 3).unwrap();
+}
+
+#[test]
+fn remote_transfer_off_bank_thread() {
+    let server = TestServer::start();
+    let client = server.client();
+    client.create_account("X".to_string()).unwrap();
+    client.increase_account("X", 10).unwrap();
+
+    // Удаленный сервер принимает соединение и молчит до конца теста
+    let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let nowhere = RemoteAccount {
+        address: silent.local_addr().unwrap().to_string(),
+        account: "Z".into(),
+    };
+    let transfer = {
+        let client = server.client();
+        thread::spawn(move || client.remote_transfer("X", nowhere, 4))
+    };
+    let (_stream, _) = silent.accept().unwrap();
+    // Пока перевод ждет ответа, банк обслуживает другие запросы
+    let started = Instant::now();
+    assert_eq!(10, client.get_account_balance("X").unwrap());
+    assert!(started.elapsed() < Duration::from_secs(1));
+    assert!(matches!(
+        error(transfer.join().unwrap()),
+        BankError::RemoteUnavailable(_)
+    ));
+    client.decrease_account("X", 10).unwrap();
+    client.increase_account("X", 10).unwrap();
+
+    // Два сервера переводят друг другу одновременно и не ждут друг друга
+    let other = TestServer::start();
+    other.client().create_account("Z".to_string()).unwrap();
+    other.client().increase_account("Z", 10).unwrap();
+    let account = |server: &TestServer, name: &str| RemoteAccount {
+        address: server.address().to_string(),
+        account: name.into(),
+    };
+    let (x, z) = (account(&server, "X"), account(&other, "Z"));
+    let started = Instant::now();
+    let there = {
+        let client = server.client();
+        thread::spawn(move || client.remote_transfer("X", z, 3))
+    };
+    let back = other.client().remote_transfer("Z", x, 5);
+    there.join().unwrap().unwrap();
+    back.unwrap();
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(12, client.get_account_balance("X").unwrap());
+    assert_eq!(8, other.client().get_account_balance("Z").unwrap());
+}
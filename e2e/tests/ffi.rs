@@ -0,0 +1,120 @@
+use std::ffi::{CStr, CString};
+use std::ptr;
+
+use banklib_ffi::*;
+use serde_json::{json, Value};
+
+use e2e::TestServer;
+
+fn text(s: &str) -> CString {
+    CString::new(s).unwrap()
+}
+
+fn last_error() -> String {
+    unsafe { CStr::from_ptr(bank_last_error()) }
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+/// Takes a string the library returned as JSON and frees it.
+unsafe fn take_json(s: *mut std::ffi::c_char) -> Value {
+    let value = serde_json::from_str(CStr::from_ptr(s).to_str().unwrap()).unwrap();
+    bank_string_free(s);
+    value
+}
+
+#[test]
+fn c_abi() {
+    let server = TestServer::start();
+    unsafe {
+        let mut client = ptr::null_mut();
+        assert_eq!(
+            BANK_OK,
+            bank_client_new(text(server.address()).as_ptr(), &mut client)
+        );
+
+        let mut id = u64::MAX;
+        assert_eq!(
+            BANK_OK,
+            bank_create_account(client, text("Alice").as_ptr(), &mut id)
+        );
+        assert_eq!(0, id);
+        assert_eq!(
+            BANK_OK,
+            bank_create_account(client, text("Bob").as_ptr(), ptr::null_mut())
+        );
+        assert_eq!(
+            BANK_ACCOUNT_ALREADY_EXISTS,
+            bank_create_account(client, text("Bob").as_ptr(), ptr::null_mut())
+        );
+        assert!(last_error().starts_with("AccountAlreadyExists"));
+
+        assert_eq!(BANK_OK, bank_deposit(client, text("Alice").as_ptr(), 10));
+        assert_eq!(BANK_OK, bank_withdraw(client, text("Alice").as_ptr(), 2));
+        assert_eq!(
+            BANK_OK,
+            bank_transfer(client, text("Alice").as_ptr(), text("Bob").as_ptr(), 3)
+        );
+        assert_eq!(
+            BANK_INSUFFICIENT_FUNDS,
+            bank_withdraw(client, text("Bob").as_ptr(), 4)
+        );
+        let mut balance = 0;
+        assert_eq!(
+            BANK_OK,
+            bank_balance(client, text("Alice").as_ptr(), &mut balance)
+        );
+        assert_eq!(5, balance);
+        assert_eq!(
+            BANK_ACCOUNT_DOES_NOT_EXIST,
+            bank_balance(client, text("Carol").as_ptr(), &mut balance)
+        );
+
+        let mut history = ptr::null_mut();
+        assert_eq!(
+            BANK_OK,
+            bank_history(client, text("Bob").as_ptr(), &mut history)
+        );
+        assert_eq!(2, take_json(history).as_array().unwrap().len());
+        assert_eq!(BANK_OK, bank_history(client, ptr::null(), &mut history));
+        assert_eq!(5, take_json(history).as_array().unwrap().len());
+
+        // Любая команда протокола в виде JSON
+        let mut response = ptr::null_mut();
+        let command = json!({ "type": "GetAccountBalance", "v": 1, "data": "Bob" });
+        assert_eq!(
+            BANK_OK,
+            bank_execute(client, text(&command.to_string()).as_ptr(), &mut response)
+        );
+        assert_eq!(3, take_json(response)["data"]);
+        assert_eq!(
+            BANK_INVALID_ARGUMENT,
+            bank_execute(client, text("{\"type\": \"Nope\"}").as_ptr(), &mut response)
+        );
+        assert_eq!(
+            BANK_INVALID_ARGUMENT,
+            bank_balance(client, ptr::null(), &mut balance)
+        );
+
+        // Административная команда без прав отклоняется
+        assert_eq!(
+            BANK_OK,
+            bank_client_set_identity(client, text("not-a-token").as_ptr())
+        );
+        let command = json!({ "type": "GetStats", "v": 1, "data": { "token": "nope" } });
+        assert_eq!(
+            BANK_UNAUTHORIZED,
+            bank_execute(client, text(&command.to_string()).as_ptr(), &mut response)
+        );
+        bank_client_free(client);
+
+        let mut unreachable = ptr::null_mut();
+        bank_client_new(text("127.0.0.1:1").as_ptr(), &mut unreachable);
+        assert_eq!(
+            BANK_UNAVAILABLE,
+            bank_balance(unreachable, text("Alice").as_ptr(), &mut balance)
+        );
+        bank_client_free(unreachable);
+    }
+}
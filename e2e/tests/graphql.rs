@@ -0,0 +1,189 @@
+#![cfg(feature = "graphql")]
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use e2e::{TestServer, ALICE_TOKEN};
+use server::GraphqlGateway;
+
+/// Starts a gateway to `server` and returns its address.
+fn start_gateway(server: &TestServer) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let gateway = GraphqlGateway::new(server.address(), Duration::from_millis(20));
+    thread::spawn(move || gateway.serve(listener));
+    address
+}
+
+fn post(gateway: &str, query: &str) -> Value {
+    let body = json!({ "query": query }).to_string();
+    let mut stream = TcpStream::connect(gateway).unwrap();
+    write!(
+        stream,
+        "POST /graphql HTTP/1.1\r\nHost: {}\r\nAuthorization: Bearer {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        gateway,
+        ALICE_TOKEN,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    serde_json::from_str(body).unwrap()
+}
+
+// Клиентские кадры WebSocket маскируются; нулевая маска оставляет данные как есть
+fn send_frame(stream: &mut TcpStream, message: &Value) {
+    let payload = message.to_string().into_bytes();
+    let mut frame = vec![0x81];
+    match payload.len() {
+        len @ 0..=125 => frame.push(0x80 | len as u8),
+        len => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(&[0; 4]);
+    frame.extend_from_slice(&payload);
+    stream.write_all(&frame).unwrap();
+}
+
+fn read_frame(stream: &mut TcpStream) -> Value {
+    let mut header = [0; 2];
+    stream.read_exact(&mut header).unwrap();
+    let len = match header[1] & 0x7f {
+        126 => {
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).unwrap();
+            u16::from_be_bytes(len) as usize
+        }
+        127 => {
+            let mut len = [0; 8];
+            stream.read_exact(&mut len).unwrap();
+            u64::from_be_bytes(len) as usize
+        }
+        len => len as usize,
+    };
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).unwrap();
+    serde_json::from_slice(&payload).unwrap()
+}
+
+#[test]
+fn graphql_queries() {
+    let server = TestServer::start();
+    let client = server.client();
+    client.create_account("Alice".to_string()).unwrap();
+    client.create_account("Bob".to_string()).unwrap();
+    client.increase_account("Alice", 10).unwrap();
+    client.transfer("Alice", "Bob", 3).unwrap();
+    let gateway = start_gateway(&server);
+
+    let response = post(&gateway, "{ account(name: \"Alice\") { balance } }");
+    assert_eq!(json!({ "account": { "balance": 7 } }), response["data"]);
+
+    let response = post(
+        &gateway,
+        "{ accounts(first: 1) { accounts { name balance } nextCursor } }",
+    );
+    let page = &response["data"]["accounts"];
+    assert_eq!(json!([{ "name": "Alice", "balance": 7 }]), page["accounts"]);
+    let query = format!(
+        "{{ accounts(after: {}) {{ accounts {{ name }} nextCursor }} }}",
+        page["nextCursor"]
+    );
+    let response = post(&gateway, &query);
+    assert_eq!(
+        json!({ "accounts": [{ "name": "Bob" }], "nextCursor": null }),
+        response["data"]["accounts"]
+    );
+
+    let response = post(
+        &gateway,
+        "{ history(first: 10) { operations { kind from to amount } } \
+           account(name: \"Bob\") { history { operations { kind amount } } } }",
+    );
+    assert_eq!(
+        json!([
+            { "kind": "create", "from": null, "to": "Alice", "amount": 0 },
+            { "kind": "create", "from": null, "to": "Bob", "amount": 0 },
+            { "kind": "increase", "from": null, "to": "Alice", "amount": 10 },
+            { "kind": "transfer", "from": "Alice", "to": "Bob", "amount": 3 },
+        ]),
+        response["data"]["history"]["operations"]
+    );
+    assert_eq!(
+        json!([{ "kind": "create", "amount": 0 }, { "kind": "transfer", "amount": 3 }]),
+        response["data"]["account"]["history"]["operations"]
+    );
+
+    // Ошибки банка приходят с именем в extensions.code
+    let response = post(&gateway, "{ account(name: \"Carol\") { balance } }");
+    assert_eq!(
+        "AccountDoesNotExist",
+        response["errors"][0]["extensions"]["code"]
+    );
+}
+
+#[test]
+fn graphql_subscription() {
+    let server = TestServer::start();
+    let client = server.client();
+    client.create_account("Alice".to_string()).unwrap();
+    client.create_account("Bob".to_string()).unwrap();
+    client.increase_account("Alice", 10).unwrap();
+    let gateway = start_gateway(&server);
+
+    let mut stream = TcpStream::connect(&gateway).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    write!(
+        stream,
+        "GET /graphql/ws HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: graphql-transport-ws\r\n\r\n",
+        gateway
+    )
+    .unwrap();
+    // Ответ на рукопожатие читается до пустой строки
+    let mut handshake = Vec::new();
+    while !handshake.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        handshake.push(byte[0]);
+    }
+    assert!(handshake.starts_with(b"HTTP/1.1 101"));
+
+    let init = json!({
+        "type": "connection_init",
+        "payload": { "Authorization": format!("Bearer {}", ALICE_TOKEN) },
+    });
+    send_frame(&mut stream, &init);
+    assert_eq!("connection_ack", read_frame(&mut stream)["type"]);
+    let subscribe = json!({
+        "id": "1",
+        "type": "subscribe",
+        "payload": {
+            "query": "subscription { operations(from: 1, account: \"Bob\") { id operation { kind to amount } } }",
+        },
+    });
+    send_frame(&mut stream, &subscribe);
+
+    // Уже записанная операция и новая, пришедшая после подписки
+    let message = read_frame(&mut stream);
+    assert_eq!("next", message["type"]);
+    assert_eq!(
+        json!({ "id": 1, "operation": { "kind": "create", "to": "Bob", "amount": 0 } }),
+        message["payload"]["data"]["operations"]
+    );
+    client.transfer("Alice", "Bob", 4).unwrap();
+    let message = read_frame(&mut stream);
+    assert_eq!(
+        json!({ "id": 3, "operation": { "kind": "transfer", "to": "Bob", "amount": 4 } }),
+        message["payload"]["data"]["operations"]
+    );
+}
@@ -0,0 +1,163 @@
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use e2e::{start_s3_server, TestServer, ADMIN_TOKEN};
+use server::Location;
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("e2e-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn sled_migrates_from_snapshot() {
+    let dir = temp_dir("sled-migration");
+    let snapshots = dir.join("snapshots");
+    let memory = TestServer::start_with_config(&format!(
+        "[snapshots]\ndir = {:?}\nevery_operations = 1\n",
+        snapshots
+    ));
+    let client = memory.client();
+    client.create_account("X".to_string()).unwrap();
+    client.create_account("Y".to_string()).unwrap();
+    client.increase_account("X", 10).unwrap();
+
+    // Снимок пишется в фоне после следующего запроса
+    let snapshot = snapshots.join(format!("snapshot-{:020}.json", 3));
+    let started = Instant::now();
+    while !snapshot.exists() {
+        assert!(started.elapsed() < Duration::from_secs(5), "no snapshot");
+        client.get_account_balance("X").unwrap();
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let sled = TestServer::start_with_config(&format!(
+        "[storage]\nbackend = \"sled\"\npath = {:?}\nmigrate_from = {:?}\ndurability = \"every 50ms\"\n",
+        dir.join("bank.sled"),
+        snapshot
+    ));
+    let client = sled.client();
+    assert_eq!(10, client.get_account_balance("X").unwrap());
+    client.transfer("X", "Y", 4).unwrap();
+    assert_eq!(4, client.get_account_balance("Y").unwrap());
+    assert_eq!(4, client.get_history().unwrap().len());
+    assert_eq!(
+        Some("every 50ms"),
+        client.stats(ADMIN_TOKEN).unwrap().durability.as_deref()
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn snapshots_are_copied_to_s3() {
+    let dir = temp_dir("s3");
+    let (address, objects) = start_s3_server();
+    let server = TestServer::start_with_config(&format!(
+        "[snapshots]\ndir = {:?}\nkeep = 1\n\n[snapshots.s3]\nendpoint = \"http://{}\"\nbucket = \"backups\"\naccess_key = \"bank\"\nsecret_key = \"secret\"\nprefix = \"bank/\"\nkeep = 2\nallow_http = true\n",
+        dir.join("snapshots"),
+        address
+    ));
+    let client = server.client();
+    client.create_account("X".to_string()).unwrap();
+    for amount in [10, 5, 1] {
+        client.increase_account("X", amount).unwrap();
+        client.write_snapshot(ADMIN_TOKEN).unwrap();
+    }
+
+    // В хранилище остаются два последних снимка, на машине - один
+    let name = |operations: usize| format!("snapshot-{:020}.json", operations);
+    let expected = vec![
+        format!("backups/bank/{}", name(3)),
+        format!("backups/bank/{}", name(4)),
+    ];
+    let started = Instant::now();
+    while objects.lock().unwrap().keys().cloned().collect::<Vec<_>>() != expected {
+        assert!(started.elapsed() < Duration::from_secs(5), "no snapshots");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let restored = server::restore_from_s3(
+        &server.config_path(),
+        None,
+        &Location::Sled(dir.join("latest.sled")),
+    )
+    .unwrap();
+    assert_eq!(
+        (1, 16, 4),
+        (
+            restored.accounts,
+            restored.total_balance,
+            restored.operations
+        )
+    );
+    let older = server::restore_from_s3(
+        &server.config_path(),
+        Some(&name(3)),
+        &Location::Sled(dir.join("older.sled")),
+    )
+    .unwrap();
+    assert_eq!(15, older.total_balance);
+    assert!(server::restore_from_s3(
+        &server.config_path(),
+        Some(&name(2)),
+        &Location::Sled(dir.join("pruned.sled")),
+    )
+    .is_err());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn encrypted_at_rest() {
+    let dir = temp_dir("encryption");
+    std::fs::create_dir_all(&dir).unwrap();
+    let key_file = dir.join("bank.key");
+    std::fs::write(&key_file, "5e".repeat(32)).unwrap();
+    let server = TestServer::start_with_config(&format!(
+        "[snapshots]\ndir = {:?}\n\n[storage]\nbackend = \"sled\"\npath = {:?}\n\n[encryption]\nkey_file = {:?}\n",
+        dir.join("snapshots"),
+        dir.join("bank.sled"),
+        key_file
+    ));
+    let client = server.client();
+    client.create_account("Confidential".to_string()).unwrap();
+    client.increase_account("Confidential", 77).unwrap();
+    client.write_snapshot(ADMIN_TOKEN).unwrap();
+    let snapshot = dir
+        .join("snapshots")
+        .join("snapshot-00000000000000000002.json");
+    let started = Instant::now();
+    while !snapshot.exists() {
+        assert!(started.elapsed() < Duration::from_secs(5), "no snapshot");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // Имени счета нет ни в снимке, ни в файлах базы
+    let mut files = vec![snapshot.clone()];
+    let mut dirs = vec![dir.join("bank.sled")];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            match path.is_dir() {
+                true => dirs.push(path),
+                false => files.push(path),
+            }
+        }
+    }
+    for file in &files {
+        let data = std::fs::read(file).unwrap();
+        assert!(
+            !data.windows(12).any(|window| window == b"Confidential"),
+            "{} is readable",
+            file.display()
+        );
+    }
+
+    let key = server::Key::load(&key_file).unwrap();
+    let copy = Location::Sled(dir.join("copy.sled"));
+    assert!(server::migrate(&Location::Snapshot(snapshot.clone()), &copy, None).is_err());
+    let migrated = server::migrate(&Location::Snapshot(snapshot), &copy, Some(&key)).unwrap();
+    assert_eq!((1, 77), (migrated.accounts, migrated.total_balance));
+    let _ = std::fs::remove_dir_all(&dir);
+}
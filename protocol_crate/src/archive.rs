@@ -0,0 +1,161 @@
+//! Archive format of histories and snapshots, built on rkyv.
+//!
+//! An archive is read in place: [`with_history`] validates the bytes once
+//! and hands out a view of the operations whose strings point into the
+//! buffer, so nothing is allocated until the operations are deserialized.
+//! The serde codecs build every `String` while parsing, which dominates the
+//! cost of loading large histories.
+
+use std::borrow::Cow;
+
+use rkyv::rancor::Error;
+use rkyv::util::AlignedVec;
+use rkyv::vec::ArchivedVec;
+use rkyv::{Archive, Archived, Serialize};
+
+use crate::{BankError, Operation, Snapshot};
+
+pub type ArchivedOperation = Archived<Operation>;
+
+// Страница истории, сериализуемая без копирования среза
+#[derive(Archive, Serialize)]
+struct Page<'a>(#[rkyv(with = rkyv::with::AsOwned)] Cow<'a, [Operation]>);
+
+pub fn encode_history(history: &[Operation]) -> Vec<u8> {
+    rkyv::to_bytes::<Error>(&Page(Cow::Borrowed(history)))
+        .unwrap()
+        .into_vec()
+}
+
+/// Validates an archive made by [`encode_history`] and passes its operations
+/// to `f` without deserializing them.
+///
+/// # Returns
+///
+/// * `Ok(R)` - What `f` returned.
+/// * `Err(BankError)` - If the archive is malformed.
+pub fn with_history<R>(
+    data: &[u8],
+    f: impl FnOnce(&ArchivedVec<ArchivedOperation>) -> R,
+) -> Result<R, BankError> {
+    aligned(data, |data| {
+        let page = rkyv::access::<ArchivedPage, Error>(data).map_err(malformed)?;
+        Ok(f(&page.0))
+    })
+}
+
+pub fn decode_history(data: &[u8]) -> Result<Vec<Operation>, BankError> {
+    with_history(data, |history| {
+        rkyv::deserialize::<Vec<Operation>, Error>(history).map_err(malformed)
+    })?
+}
+
+pub fn encode_snapshot(snapshot: &Snapshot) -> Vec<u8> {
+    rkyv::to_bytes::<Error>(snapshot).unwrap().into_vec()
+}
+
+pub fn decode_snapshot(data: &[u8]) -> Result<Snapshot, BankError> {
+    aligned(data, |data| {
+        rkyv::from_bytes::<Snapshot, Error>(data).map_err(malformed)
+    })
+}
+
+// Архив читается на месте, поэтому буфер должен быть выровнен; иначе копия
+fn aligned<R>(data: &[u8], f: impl FnOnce(&[u8]) -> R) -> R {
+    if (data.as_ptr() as usize).is_multiple_of(AlignedVec::<16>::ALIGNMENT) {
+        return f(data);
+    }
+    let mut copy = AlignedVec::<16>::with_capacity(data.len());
+    copy.extend_from_slice(data);
+    f(&copy)
+}
+
+fn malformed(e: impl std::fmt::Display) -> BankError {
+    BankError::ProtocolError(format!("malformed archive: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        AccountLimits, OpenProposal, OpenReservation, PendingTransfer, RemoteAccount,
+        ReservationKind, ReservationSource, TransactionId,
+    };
+
+    #[test]
+    fn round_trip() {
+        let history = vec![
+            Operation::CreateAccount("X".to_string()),
+            Operation::IncreaseAccount("X".to_string(), 7),
+            Operation::RemoteTransferOut {
+                from: "X".to_string(),
+                to: RemoteAccount {
+                    address: "127.0.0.1:7879".to_string(),
+                    account: 3.into(),
+                },
+                amount: 2,
+            },
+            Operation::SetLimits {
+                account: "X".to_string(),
+                limits: AccountLimits {
+                    max_withdrawal: Some(5),
+                    ..AccountLimits::default()
+                },
+            },
+        ];
+        let data = encode_history(&history);
+        assert_eq!(history, decode_history(&data).unwrap());
+        let name = with_history(&data, |history| match &history[1] {
+            ArchivedOperation::IncreaseAccount(name, _) => name.as_str().to_string(),
+            _ => String::new(),
+        });
+        assert_eq!("X", name.unwrap());
+
+        // Смещенный буфер копируется, испорченный отвергается
+        let mut shifted = vec![0];
+        shifted.extend_from_slice(&data);
+        assert_eq!(history, decode_history(&shifted[1..]).unwrap());
+        assert!(decode_history(&data[..data.len() - 1]).is_err());
+
+        let snapshot = Snapshot {
+            accounts: vec![("X".to_string(), 5)],
+            history,
+            timestamps: vec![1, 2, 3, 4],
+            metadata: Default::default(),
+            owners: Default::default(),
+            tags: Default::default(),
+            disputes: Default::default(),
+            approvals: Default::default(),
+            alert_rules: Default::default(),
+            reservations: vec![OpenReservation {
+                id: 3,
+                account: "X".to_string(),
+                amount: 2,
+                kind: ReservationKind::Debit,
+                source: ReservationSource::Transaction(TransactionId {
+                    coordinator: "127.0.0.1:7878".to_string(),
+                    number: 1,
+                }),
+            }],
+            next_reservation_id: 4,
+            pending_transfers: vec![PendingTransfer {
+                id: 1,
+                from: "X".to_string(),
+                to: "Y".to_string(),
+                amount: 1,
+                requested_at: 5,
+            }],
+            next_pending_id: 2,
+            proposals: vec![OpenProposal {
+                id: 0,
+                from: "X".to_string(),
+                to: "Y".to_string(),
+                amount: 3,
+                approvals: ["alice".to_string()].into(),
+            }],
+            next_proposal_id: 1,
+        };
+        let data = encode_snapshot(&snapshot);
+        assert_eq!(snapshot, decode_snapshot(&data).unwrap());
+    }
+}
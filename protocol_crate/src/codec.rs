@@ -0,0 +1,326 @@
+//! Wire formats of commands and responses.
+//!
+//! A JSON message is sent as is. Binary formats start with a marker byte,
+//! so a server built with several codecs can tell them apart and answer in
+//! the format of the request.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::BankError;
+
+#[cfg(not(any(feature = "json", feature = "bincode", feature = "msgpack")))]
+compile_error!("protocol_crate needs at least one of the json, bincode, msgpack features");
+
+/// Encoding of protocol messages.
+pub trait Serializer {
+    /// Byte that precedes an encoded command, `None` for no prefix.
+    fn marker(&self) -> Option<u8>;
+
+    /// Appends the encoding of `value` to `buffer`, so a caller encoding
+    /// many messages can reuse one buffer.
+    fn encode_into<T: Serialize>(&self, value: &T, buffer: &mut Vec<u8>);
+
+    fn encode<T: Serialize>(&self, value: &T) -> Vec<u8> {
+        let mut data = Vec::new();
+        self.encode_into(value, &mut data);
+        data
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, BankError>;
+
+    /// Encodes a command together with its marker byte.
+    fn encode_command<T: Serialize>(&self, value: &T) -> Vec<u8> {
+        let mut data: Vec<u8> = self.marker().into_iter().collect();
+        self.encode_into(value, &mut data);
+        data
+    }
+}
+
+fn malformed(e: impl std::fmt::Display) -> BankError {
+    BankError::ProtocolError(format!("malformed message: {}", e))
+}
+
+#[cfg(feature = "json")]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Serializer for Json {
+    fn marker(&self) -> Option<u8> {
+        None
+    }
+
+    fn encode_into<T: Serialize>(&self, value: &T, buffer: &mut Vec<u8>) {
+        serde_json::to_writer(buffer, value).unwrap()
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, BankError> {
+        serde_json::from_slice(data).map_err(malformed)
+    }
+}
+
+#[cfg(feature = "bincode")]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Serializer for Bincode {
+    fn marker(&self) -> Option<u8> {
+        Some(BINCODE_MARKER)
+    }
+
+    fn encode_into<T: Serialize>(&self, value: &T, buffer: &mut Vec<u8>) {
+        bincode::serialize_into(buffer, value).unwrap()
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, BankError> {
+        use bincode::Options;
+        // Как bincode::deserialize, но длина строки или списка не может
+        // превысить само сообщение
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(data.len() as u64)
+            .deserialize(data)
+            .map_err(malformed)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+pub struct MsgPack;
+
+#[cfg(feature = "msgpack")]
+impl Serializer for MsgPack {
+    fn marker(&self) -> Option<u8> {
+        Some(MSGPACK_MARKER)
+    }
+
+    fn encode_into<T: Serialize>(&self, value: &T, buffer: &mut Vec<u8>) {
+        rmp_serde::encode::write(buffer, value).unwrap()
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, BankError> {
+        rmp_serde::from_slice(data).map_err(malformed)
+    }
+}
+
+// Управляющие байты, с которых JSON начинаться не может
+#[cfg(feature = "bincode")]
+const BINCODE_MARKER: u8 = 0x01;
+#[cfg(feature = "msgpack")]
+const MSGPACK_MARKER: u8 = 0x02;
+
+/// One of the compiled-in formats, chosen at run time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    #[cfg(feature = "json")]
+    Json,
+    #[cfg(feature = "bincode")]
+    Bincode,
+    #[cfg(feature = "msgpack")]
+    MsgPack,
+}
+
+impl Default for WireFormat {
+    /// JSON when it is compiled in, otherwise the first enabled binary format.
+    fn default() -> Self {
+        #[cfg(feature = "json")]
+        return WireFormat::Json;
+        #[cfg(all(not(feature = "json"), feature = "msgpack"))]
+        return WireFormat::MsgPack;
+        #[cfg(all(not(feature = "json"), not(feature = "msgpack")))]
+        return WireFormat::Bincode;
+    }
+}
+
+impl WireFormat {
+    /// Compiled-in formats, fastest first; see `server/benches/encoding.rs`.
+    pub fn supported() -> Vec<WireFormat> {
+        vec![
+            #[cfg(feature = "bincode")]
+            WireFormat::Bincode,
+            #[cfg(feature = "msgpack")]
+            WireFormat::MsgPack,
+            #[cfg(feature = "json")]
+            WireFormat::Json,
+        ]
+    }
+
+    /// Name the format is advertised by in the handshake.
+    pub fn name(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "json")]
+            WireFormat::Json => "json",
+            #[cfg(feature = "bincode")]
+            WireFormat::Bincode => "bincode",
+            #[cfg(feature = "msgpack")]
+            WireFormat::MsgPack => "msgpack",
+        }
+    }
+
+    /// Fastest of the compiled-in formats among the `names` a server
+    /// advertised, `None` if there is no common format.
+    pub fn negotiate(names: &[String]) -> Option<WireFormat> {
+        WireFormat::supported()
+            .into_iter()
+            .find(|format| names.iter().any(|name| name == format.name()))
+    }
+
+    /// Splits a received command into its format and payload.
+    pub fn detect(data: &[u8]) -> Result<(WireFormat, &[u8]), BankError> {
+        match data.first() {
+            #[cfg(feature = "bincode")]
+            Some(&BINCODE_MARKER) => Ok((WireFormat::Bincode, &data[1..])),
+            #[cfg(feature = "msgpack")]
+            Some(&MSGPACK_MARKER) => Ok((WireFormat::MsgPack, &data[1..])),
+            #[cfg(feature = "json")]
+            _ => Ok((WireFormat::Json, data)),
+            #[cfg(not(feature = "json"))]
+            _ => Err(BankError::ProtocolError(
+                "unsupported wire format".to_string(),
+            )),
+        }
+    }
+}
+
+impl Serializer for WireFormat {
+    fn marker(&self) -> Option<u8> {
+        match self {
+            #[cfg(feature = "json")]
+            WireFormat::Json => Json.marker(),
+            #[cfg(feature = "bincode")]
+            WireFormat::Bincode => Bincode.marker(),
+            #[cfg(feature = "msgpack")]
+            WireFormat::MsgPack => MsgPack.marker(),
+        }
+    }
+
+    fn encode_into<T: Serialize>(&self, value: &T, buffer: &mut Vec<u8>) {
+        match self {
+            #[cfg(feature = "json")]
+            WireFormat::Json => Json.encode_into(value, buffer),
+            #[cfg(feature = "bincode")]
+            WireFormat::Bincode => Bincode.encode_into(value, buffer),
+            #[cfg(feature = "msgpack")]
+            WireFormat::MsgPack => MsgPack.encode_into(value, buffer),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, BankError> {
+        match self {
+            #[cfg(feature = "json")]
+            WireFormat::Json => Json.decode(data),
+            #[cfg(feature = "bincode")]
+            WireFormat::Bincode => Bincode.decode(data),
+            #[cfg(feature = "msgpack")]
+            WireFormat::MsgPack => MsgPack.decode(data),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AccountRef, Command, Response, ResponsePayload, MAX_NESTING};
+
+    fn formats() -> Vec<WireFormat> {
+        vec![
+            #[cfg(feature = "json")]
+            WireFormat::Json,
+            #[cfg(feature = "bincode")]
+            WireFormat::Bincode,
+            #[cfg(feature = "msgpack")]
+            WireFormat::MsgPack,
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        for format in formats() {
+            let command = Command::Transfer {
+                from: AccountRef::Id(3),
+                to: "Alice/savings".into(),
+                amount: 5,
+            };
+            let data = format.encode_command(&command);
+            let (detected, payload) = WireFormat::detect(&data).unwrap();
+            assert_eq!(format, detected);
+            let x: Command = detected.decode(payload).unwrap();
+            assert!(matches!(
+                x,
+                Command::Transfer { from: AccountRef::Id(3), to: AccountRef::Name(to), amount: 5 }
+                    if to == "Alice/savings"
+            ));
+
+            let response: Response = Err(BankError::InvalidHistory {
+                index: 1,
+                reason: "x".to_string(),
+            });
+            let x: Response = format.decode(&format.encode(&response)).unwrap();
+            assert!(matches!(x, Err(BankError::InvalidHistory { index: 1, .. })));
+            let response: Response = Ok(ResponsePayload::AccountBalance(7));
+            let x: Response = format.decode(&format.encode(&response)).unwrap();
+            assert!(matches!(x, Ok(ResponsePayload::AccountBalance(7))));
+        }
+    }
+
+    #[test]
+    fn deep_nesting() {
+        let leaf = Command::GetSnapshot;
+        let wrapped = Command::WithDeadline {
+            timeout_ms: 1,
+            command: Box::new(leaf.clone()),
+        };
+        for format in formats() {
+            // Обертка вокруг команды: ее байты до и после вложенной команды
+            let leaf_data = format.encode(&leaf);
+            let data = format.encode(&wrapped);
+            let start = data
+                .windows(leaf_data.len())
+                .position(|window| window == leaf_data)
+                .unwrap();
+            let (before, after) = (&data[..start], &data[start + leaf_data.len()..]);
+            let nested = |levels: usize| {
+                let mut data = before.repeat(levels);
+                data.extend_from_slice(&leaf_data);
+                data.extend(after.repeat(levels));
+                data
+            };
+
+            let x: Command = format.decode(&nested(MAX_NESTING - 1)).unwrap();
+            assert!(matches!(x, Command::WithDeadline { .. }));
+            let x = format.decode::<Command>(&nested(MAX_NESTING));
+            assert!(matches!(x, Err(BankError::ProtocolError(_))), "{:?}", x);
+            // Без ограничения такое сообщение переполняло стек
+            let x = format.decode::<Command>(&nested(200_000));
+            assert!(matches!(x, Err(BankError::ProtocolError(_))), "{:?}", x);
+        }
+    }
+
+    #[test]
+    fn negotiate() {
+        let names =
+            |names: &[&str]| -> Vec<String> { names.iter().map(|name| name.to_string()).collect() };
+        let fastest = WireFormat::supported()[0];
+        assert_eq!(
+            Some(fastest),
+            WireFormat::negotiate(&names(&["json", "bincode", "msgpack", "rkyv"]))
+        );
+        assert_eq!(None, WireFormat::negotiate(&names(&["rkyv"])));
+        for format in formats() {
+            assert_eq!(
+                Some(format),
+                WireFormat::negotiate(&names(&[format.name()]))
+            );
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_account_ref_is_untagged() {
+        let data = Json.encode(&Command::GetAccountBalance("X".into()));
+        assert_eq!(
+            br#"{"data":"X","type":"GetAccountBalance","v":1}"#,
+            data.as_slice()
+        );
+    }
+}
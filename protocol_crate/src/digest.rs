@@ -0,0 +1,140 @@
+//! Hash chain over the bank history.
+//!
+//! The hash of an operation covers the hash of the previous one, so the last
+//! hash commits to the whole history: two servers with equal digests have
+//! the same history, and a changed operation changes every later hash.
+//!
+//! The exception is [`Operation::AnonymizeAccount`], which renames accounts
+//! in the history before it: its hash covers the checkpoint it carries
+//! instead of the previous hash, so the renamed operations before it are
+//! only as trustworthy as the checkpoint was when it was written.
+
+use sha2::{Digest, Sha256};
+
+use crate::{BankError, HistoryDigest, Operation};
+
+pub type Hash = [u8; 32];
+
+/// Hash "before" the first operation.
+pub const GENESIS: Hash = [0; 32];
+
+/// SHA-256 of the previous hash followed by the bincode encoding of
+/// `operation`; for an [`Operation::AnonymizeAccount`] with a well-formed
+/// checkpoint, of the checkpoint instead of the previous hash.
+pub fn chain(previous: &Hash, operation: &Operation) -> Hash {
+    let checkpoint = match operation {
+        Operation::AnonymizeAccount { checkpoint, .. } => from_hex(checkpoint),
+        _ => None,
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(checkpoint.as_ref().unwrap_or(previous));
+    hasher.update(bincode::serialize(operation).unwrap());
+    hasher.finalize().into()
+}
+
+pub fn history_hash(history: &[Operation]) -> Hash {
+    history
+        .iter()
+        .fold(GENESIS, |hash, operation| chain(&hash, operation))
+}
+
+pub fn to_hex(hash: &Hash) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Hash written by [`to_hex`]; `None` for anything else.
+pub fn from_hex(hex: &str) -> Option<Hash> {
+    if hex.len() != 64 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut hash = GENESIS;
+    for (byte, pair) in hash.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+/// Digest of `history` alone, as if it started the bank history.
+pub fn history_digest(history: &[Operation]) -> HistoryDigest {
+    HistoryDigest {
+        operations: history.len(),
+        digest: to_hex(&history_hash(history)),
+    }
+}
+
+/// Checks `history` against the digest a server reported for it.
+pub fn verify_history(history: &[Operation], expected: &HistoryDigest) -> Result<(), BankError> {
+    if history.len() != expected.operations {
+        return Err(BankError::InvalidHistory {
+            index: history.len().min(expected.operations),
+            reason: format!(
+                "digest covers {} operations, history has {}",
+                expected.operations,
+                history.len()
+            ),
+        });
+    }
+    if to_hex(&history_hash(history)) != expected.digest {
+        return Err(BankError::InvalidHistory {
+            index: history.len(),
+            reason: "history does not match the digest".to_string(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history() -> Vec<Operation> {
+        vec![
+            Operation::CreateAccount("X".to_string()),
+            Operation::IncreaseAccount("X".to_string(), 10),
+            Operation::DecreaseAccount("X".to_string(), 3),
+        ]
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let history = history();
+        let digest = HistoryDigest {
+            operations: 3,
+            digest: to_hex(&history_hash(&history)),
+        };
+        assert!(verify_history(&history, &digest).is_ok());
+
+        let mut tampered = history.clone();
+        tampered[1] = Operation::IncreaseAccount("X".to_string(), 100);
+        assert!(verify_history(&tampered, &digest).is_err());
+        assert!(verify_history(&history[..2], &digest).is_err());
+    }
+
+    #[test]
+    fn checkpoint() {
+        let history = history();
+        let hash = history_hash(&history);
+        assert_eq!(Some(hash), from_hex(&to_hex(&hash)));
+        assert_eq!(None, from_hex("00"));
+
+        // После переименования цепочка продолжается от контрольной точки,
+        // а не от хеша переименованной истории
+        let anonymize = Operation::AnonymizeAccount {
+            id: 0,
+            pseudonym: "anonymous-0".to_string(),
+            checkpoint: to_hex(&hash),
+        };
+        let renamed = vec![
+            Operation::CreateAccount("anonymous-0".to_string()),
+            Operation::IncreaseAccount("anonymous-0".to_string(), 10),
+            Operation::DecreaseAccount("anonymous-0".to_string(), 3),
+            anonymize.clone(),
+        ];
+        assert_eq!(chain(&hash, &anonymize), history_hash(&renamed));
+        let mut tampered = renamed.clone();
+        tampered.push(Operation::IncreaseAccount("anonymous-0".to_string(), 1));
+        let digest = history_digest(&tampered);
+        tampered[4] = Operation::IncreaseAccount("anonymous-0".to_string(), 2);
+        assert!(verify_history(&tampered, &digest).is_err());
+    }
+}
@@ -1,46 +1,1695 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+#[cfg(feature = "archive")]
+pub mod archive;
+pub mod codec;
+#[cfg(feature = "digest")]
+pub mod digest;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+pub mod pipeline;
+#[cfg(feature = "socket")]
+pub mod socket;
+mod versioned;
+
+use versioned::versioned_serde;
+pub use versioned::{MAX_NESTING, PROTOCOL_VERSION};
+
+/// The server reads a command with a single read of this many bytes.
+pub const MAX_COMMAND_SIZE: usize = 512;
+
+pub type AccountId = usize;
+
+/// Separates a sub-account name from its parent: `Alice/savings`.
+pub const ACCOUNT_SEPARATOR: char = '/';
+
+/// Name of the parent of a sub-account, `None` for a top-level account.
+pub fn parent_account(name: &str) -> Option<&str> {
+    name.rsplit_once(ACCOUNT_SEPARATOR)
+        .map(|(parent, _)| parent)
+}
+
+/// Whether `name` is usable as an account name: no empty path segments.
+pub fn is_valid_account_name(name: &str) -> bool {
+    name.split(ACCOUNT_SEPARATOR)
+        .all(|segment| !segment.is_empty())
+}
+pub type ReservationId = usize;
+pub type PendingTransferId = u64;
+pub type DisputeId = u64;
+pub type LockId = u64;
+pub type ProposalId = u64;
+
+/// Reference to an account either by its numeric id or by its name.
+///
+/// Text formats write it as a bare number or string. Binary formats can not
+/// guess the type of the next value, so there it is an ordinary enum.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(
+    feature = "archive",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+pub enum AccountRef {
+    Id(AccountId),
+    Name(String),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UntaggedAccountRef {
+    Id(AccountId),
+    Name(String),
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "AccountRef")]
+enum TaggedAccountRef {
+    Id(AccountId),
+    Name(String),
+}
+
+impl Serialize for AccountRef {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match (self, serializer.is_human_readable()) {
+            (AccountRef::Id(id), true) => id.serialize(serializer),
+            (AccountRef::Name(name), true) => name.serialize(serializer),
+            (AccountRef::Id(id), false) => {
+                serializer.serialize_newtype_variant("AccountRef", 0, "Id", id)
+            }
+            (AccountRef::Name(name), false) => {
+                serializer.serialize_newtype_variant("AccountRef", 1, "Name", name)
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for AccountRef {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            Ok(match UntaggedAccountRef::deserialize(deserializer)? {
+                UntaggedAccountRef::Id(id) => AccountRef::Id(id),
+                UntaggedAccountRef::Name(name) => AccountRef::Name(name),
+            })
+        } else {
+            Ok(match TaggedAccountRef::deserialize(deserializer)? {
+                TaggedAccountRef::Id(id) => AccountRef::Id(id),
+                TaggedAccountRef::Name(name) => AccountRef::Name(name),
+            })
+        }
+    }
+}
+
+impl From<AccountId> for AccountRef {
+    fn from(id: AccountId) -> Self {
+        AccountRef::Id(id)
+    }
+}
+
+impl From<String> for AccountRef {
+    fn from(name: String) -> Self {
+        AccountRef::Name(name)
+    }
+}
+
+impl From<&str> for AccountRef {
+    fn from(name: &str) -> Self {
+        AccountRef::Name(name.to_string())
+    }
+}
+
+impl fmt::Display for AccountRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountRef::Id(id) => write!(f, "#{}", id),
+            AccountRef::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Account that lives on another bank server.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+#[cfg_attr(
+    feature = "archive",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+pub struct RemoteAccount {
+    pub address: String,
+    pub account: AccountRef,
+}
+
+impl fmt::Display for RemoteAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.account, self.address)
+    }
+}
+
+/// Cluster-wide identifier of a multi-leg transaction: the coordinating
+/// server address plus a sequence number local to that coordinator.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+#[cfg_attr(
+    feature = "archive",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+pub struct TransactionId {
+    pub coordinator: String,
+    pub number: usize,
+}
+
+impl fmt::Display for TransactionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.coordinator, self.number)
+    }
+}
+
+/// One debit or credit of a multi-leg transaction.
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct TransactionLeg {
+    pub account: RemoteAccount,
+    pub kind: ReservationKind,
+    pub amount: u32,
+}
+
+/// One operation of a [`Command::Batch`].
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum BatchOperation {
+    Deposit {
+        account: AccountRef,
+        amount: u32,
+    },
+    Withdraw {
+        account: AccountRef,
+        amount: u32,
+    },
+    Transfer {
+        from: AccountRef,
+        to: AccountRef,
+        amount: u32,
+    },
+}
+
+/// No more than `max_amount` may leave an account within any `window_secs`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(
+    feature = "archive",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+pub struct VelocityRule {
+    pub window_secs: u64,
+    pub max_amount: u32,
+}
+
+/// Outflow limits of an account; `None` means unlimited.
+#[derive(Debug, PartialEq, Eq, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(
+    feature = "archive",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+pub struct AccountLimits {
+    pub max_withdrawal: Option<u32>,
+    pub max_daily_outflow: Option<u32>,
+    #[serde(default)]
+    pub velocity: Vec<VelocityRule>,
+}
+
+/// Which accounts `ListAccounts` returns.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum AccountFilter {
+    All,
+    Active,
+    Closed,
+}
+
+/// Where a paginated listing continues. A client does not look inside: it
+/// passes back the `next_cursor` of the page it got to get the next one.
+/// Listings of the history are the exception, see [`PageRequest::at`].
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Cursor(String);
+
+impl Cursor {
+    /// Cursor of the listing item at `position`, for the server side.
+    pub fn at(position: usize) -> Self {
+        Cursor(position.to_string())
+    }
+
+    /// Position the cursor was made for by [`Cursor::at`].
+    pub fn position(&self) -> Result<usize, BankError> {
+        self.0
+            .parse()
+            .map_err(|_| BankError::InvalidCursor(self.0.clone()))
+    }
+}
+
+// Шлюзы передают курсор своим клиентам как строку, не заглядывая в нее
+impl From<String> for Cursor {
+    fn from(text: String) -> Self {
+        Cursor(text)
+    }
+}
+
+impl From<Cursor> for String {
+    fn from(cursor: Cursor) -> Self {
+        cursor.0
+    }
+}
+
+/// Which page of a listing to return: at most `limit` items from `cursor`,
+/// or from the start without one.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct PageRequest {
+    pub cursor: Option<Cursor>,
+    pub limit: usize,
+}
+
+impl PageRequest {
+    /// The first page of at most `limit` items.
+    pub fn first(limit: usize) -> Self {
+        PageRequest {
+            cursor: None,
+            limit,
+        }
+    }
+
+    /// The page of at most `limit` items from the one at `position`. Items
+    /// of the history and of an account history are positioned by their
+    /// operation IDs, so a client can start reading them at any operation.
+    pub fn at(position: usize, limit: usize) -> Self {
+        PageRequest {
+            cursor: Some(Cursor::at(position)),
+            limit,
+        }
+    }
+}
+
+/// Page of a paginated listing. Items added to the listing after the first
+/// page show up on later pages rather than shifting them; `next_cursor` is
+/// `None` on the last page.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<Cursor>,
+}
+
+impl<T> Page<T> {
+    /// The same page with every item replaced by `f(item)`.
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Page<U> {
+        Page {
+            items: self.items.into_iter().map(f).collect(),
+            next_cursor: self.next_cursor,
+        }
+    }
+}
+
+/// Which field of the operations a projected history query returns, so a
+/// client that only aggregates does not download whole operations.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum HistoryProjection {
+    /// [`Operation::amount`] of every operation.
+    Amounts,
+    /// [`Operation::counterparties`] of every operation.
+    Counterparties,
+}
+
+/// Direction of the money movement held by a reservation.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(
+    feature = "archive",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+pub enum ReservationKind {
+    Debit,
+    Credit,
+}
+
+/// What a reservation was made for; decides how it shows up in the history.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[cfg_attr(
+    feature = "archive",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+pub enum ReservationSource {
+    Transfer(RemoteAccount),
+    Transaction(TransactionId),
+}
+
+/// Reservation not committed or released yet, by account name.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[cfg_attr(
+    feature = "archive",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+pub struct OpenReservation {
+    pub id: ReservationId,
+    pub account: String,
+    pub amount: u32,
+    pub kind: ReservationKind,
+    pub source: ReservationSource,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(remote = "Self")]
 pub enum Command {
     CreateAccount(String),
-    IncreaseAccount(String, u32),
-    DecreaseAccount(String, u32),
+    IncreaseAccount(AccountRef, u32),
+    DecreaseAccount(AccountRef, u32),
+    /// Decreases the account only if at least `min_after` stays available.
+    DecreaseIfBalanceAtLeast {
+        account: AccountRef,
+        amount: u32,
+        min_after: u32,
+    },
     Transfer {
-        from: String,
-        to: String,
+        from: AccountRef,
+        to: AccountRef,
+        amount: u32,
+    },
+    /// Transfers only if `from` is still at `expected_version`, i.e. nothing
+    /// changed its balance since the client read it.
+    TransferIf {
+        from: AccountRef,
+        to: AccountRef,
         amount: u32,
+        expected_version: u64,
     },
+    #[deprecated(note = "the history can be long; use `GetHistoryPaged`")]
     GetHistory,
-    GetAccountBalance(String),
+    /// At most `limit` operations touching an account whose name or metadata
+    /// contains every word of `query`.
+    SearchHistory {
+        query: String,
+        limit: usize,
+    },
+    /// Filters, groups and aggregates the history on the server, e.g.
+    /// `sum where kind = transfer and from = Alice group by day`.
+    QueryHistory {
+        query: String,
+    },
+    #[deprecated(note = "use `GetHistoryPaged`")]
+    GetHistoryPage {
+        offset: usize,
+        limit: usize,
+    },
+    /// The history, one page at a time.
+    GetHistoryPaged {
+        page: PageRequest,
+    },
+    /// Same page as `GetHistoryPaged`, with the operations encoded by
+    /// `archive::encode_history`.
+    GetHistoryArchive {
+        page: PageRequest,
+    },
+    GetAccountBalance(AccountRef),
+    /// Balance the account had before operation `operation_id`.
+    GetBalanceAt {
+        account: AccountRef,
+        operation_id: usize,
+    },
+    /// Balance together with the account version.
+    GetVersionedBalance(AccountRef),
     Restore(Vec<Operation>),
-    GetAccountHistory(String),
+    #[deprecated(note = "the history can be long; use `GetAccountHistoryPaged`")]
+    GetAccountHistory(AccountRef),
+    /// A page of the history, of `account` only if given, with only the
+    /// field of every operation that `projection` picks.
+    GetProjectedHistory {
+        account: Option<AccountRef>,
+        projection: HistoryProjection,
+        page: PageRequest,
+    },
+    /// Operations of `account`, one page at a time.
+    GetAccountHistoryPaged {
+        account: AccountRef,
+        page: PageRequest,
+    },
+    RemoteTransfer {
+        from: AccountRef,
+        to: RemoteAccount,
+        amount: u32,
+    },
+    Reserve {
+        account: AccountRef,
+        amount: u32,
+        kind: ReservationKind,
+        counterparty: RemoteAccount,
+    },
+    CommitReservation(ReservationId),
+    ReleaseReservation(ReservationId),
+    /// Keeps other clients from changing `account` for `lease_ms`
+    /// milliseconds; the holder changes it with `WithLock`.
+    LockAccount {
+        account: AccountRef,
+        lease_ms: u64,
+    },
+    /// Same as `LockAccount` for all of `accounts` at once, e.g. the
+    /// accounts of a batch: they are locked together or not at all.
+    LockAccounts {
+        accounts: Vec<AccountRef>,
+        lease_ms: u64,
+    },
+    UnlockAccount(LockId),
+    Transaction(Vec<TransactionLeg>),
+    /// Applies the operations in order, all of them or none.
+    Batch(Vec<BatchOperation>),
+    /// Pays every payee from `from` with a transfer of its own, all of them
+    /// applied as one batch. Payees that can not be paid are reported and
+    /// left out; if `from` can not cover the rest, nobody is paid.
+    BulkTransfer {
+        from: AccountRef,
+        payees: Vec<(AccountRef, u32)>,
+    },
+    Prepare {
+        transaction: TransactionId,
+        legs: Vec<TransactionLeg>,
+    },
+    Commit(TransactionId),
+    Abort(TransactionId),
+    /// Opens an interactive transaction on the pipelined connection: its
+    /// deposits, withdrawals, transfers and batches are only collected
+    /// until `CommitTransaction`. Closing the connection rolls it back.
+    BeginTransaction,
+    /// Applies the collected operations all at once, unless a commit made
+    /// after `BeginTransaction` touched an account the transaction used.
+    CommitTransaction,
+    RollbackTransaction,
+    GetStatement {
+        account: AccountRef,
+        from_ts: u64,
+        to_ts: u64,
+    },
+    Reload {
+        token: String,
+    },
+    GetMetrics {
+        token: String,
+    },
+    /// Estimated memory of the bank and its capacity limits.
+    GetStats {
+        token: String,
+    },
+    GetSnapshot,
+    /// At most `limit` bytes from `offset` of the snapshot the server keeps
+    /// for shipping to replicas, the one at `operations` operations. Without
+    /// `operations` the server takes a new snapshot, unless the one it keeps
+    /// is still current.
+    GetSnapshotChunk {
+        operations: Option<usize>,
+        offset: u64,
+        limit: usize,
+    },
+    /// Protocol version and wire formats of the server. It is answered in
+    /// the format it was sent in, so a client can start with any of them.
+    Handshake,
+    /// Addresses of the shards behind a router, for placing accounts with a
+    /// shard map; a server on its own is the only shard.
+    GetShardMap,
+    SetAccountLimits {
+        token: String,
+        account: AccountRef,
+        limits: AccountLimits,
+    },
+    GetAccountLimits(AccountRef),
+    SetAccountMetadata {
+        account: AccountRef,
+        key: String,
+        value: Option<String>,
+    },
+    GetAccountMetadata(AccountRef),
+    SetAccountOwners {
+        account: AccountRef,
+        owners: BTreeSet<String>,
+    },
+    GetAccountOwners(AccountRef),
+    GetSubtreeBalance(AccountRef),
+    SetAccountTags {
+        token: String,
+        account: AccountRef,
+        tags: BTreeSet<String>,
+    },
+    GetAccountTags(AccountRef),
+    FindAccountsByTag(String),
+    /// The `n` accounts with the largest balances.
+    GetTopAccounts(usize),
+    /// Marks an empty account closed. It keeps its name and history, but
+    /// no more operations are accepted on it.
+    CloseAccount(AccountRef),
+    #[deprecated(note = "there can be many accounts; use `ListAccountsPaged`")]
+    ListAccounts(AccountFilter),
+    /// Accounts `filter` picks with their balances, in the order they were
+    /// created, one page at a time.
+    ListAccountsPaged {
+        filter: AccountFilter,
+        page: PageRequest,
+    },
+    /// Accounts with a balance within the bounds; a missing bound is open.
+    FindAccounts {
+        min_balance: Option<u32>,
+        max_balance: Option<u32>,
+    },
+    /// Digest of the first `operations` operations, of the whole history by default.
+    GetHistoryDigest {
+        operations: Option<usize>,
+    },
+    CheckConsistency {
+        token: String,
+    },
+    /// Turns the read-only maintenance mode on or off.
+    SetMaintenance {
+        token: String,
+        enabled: bool,
+    },
+    /// Writes a snapshot to the snapshot directory now, whatever the
+    /// snapshot policy says.
+    WriteSnapshot {
+        token: String,
+    },
+    /// Adds `new_token` to the config file as an admin token, a token of
+    /// `identity` or both.
+    AddToken {
+        token: String,
+        new_token: String,
+        identity: Option<String>,
+        admin: bool,
+    },
+    /// Removes `revoked` from the config file; the token the command is sent
+    /// with can not be revoked.
+    RevokeToken {
+        token: String,
+        revoked: String,
+    },
+    ListTokens {
+        token: String,
+    },
+    /// Limits the requests to `account`, or to every account without a
+    /// limit of its own when `account` is `None`. `limit: None` removes the
+    /// limit.
+    SetRateLimit {
+        token: String,
+        account: Option<String>,
+        limit: Option<RateLimit>,
+    },
+    GetRateLimits {
+        token: String,
+    },
+    /// Jobs of the server scheduler with their next runs.
+    ListJobs {
+        token: String,
+    },
+    /// Makes a transfer that waits for approval; its held funds move.
+    ApproveTransfer {
+        token: String,
+        id: PendingTransferId,
+    },
+    /// Drops a transfer that waits for approval and releases its funds.
+    RejectTransfer {
+        token: String,
+        id: PendingTransferId,
+    },
+    ListPendingTransfers {
+        token: String,
+    },
+    /// Disputes operation `operation` of the history, e.g. a payment the
+    /// client does not recognize.
+    OpenDispute {
+        operation: usize,
+        reason: String,
+    },
+    /// Open disputes, and resolved ones too with `include_resolved`.
+    ListDisputes {
+        include_resolved: bool,
+    },
+    /// Closes a dispute; with `reverse` the disputed operation is undone
+    /// by a reversing operation.
+    ResolveDispute {
+        token: String,
+        id: DisputeId,
+        reverse: bool,
+    },
+    /// Makes transfers out of `account` need `approvals` distinct
+    /// identities, its owners if it has any, to agree: they go through
+    /// `ProposeTransfer` instead. Up to one approval the account is
+    /// unprotected again.
+    SetRequiredApprovals {
+        token: String,
+        account: AccountRef,
+        approvals: u32,
+    },
+    /// Proposes a transfer out of an account that needs several approvals;
+    /// the proposer approves it right away.
+    ProposeTransfer {
+        from: AccountRef,
+        to: AccountRef,
+        amount: u32,
+    },
+    /// Approves a proposed transfer on behalf of the caller. The transfer is
+    /// made by the approval that completes the required number.
+    ApproveProposal(ProposalId),
+    /// Raises an alert whenever the balance of `account` falls below
+    /// `below`; `None` removes the rule.
+    SetAlertRule {
+        account: AccountRef,
+        below: Option<u32>,
+    },
+    /// Alerts of the accounts whose balance is below their rule now.
+    ListAlerts,
+    /// Replaces the name of `account` and its sub-accounts with a
+    /// pseudonym everywhere, the history included, e.g. to honour a request
+    /// to remove personal data.
+    AnonymizeAccount {
+        token: String,
+        account: AccountRef,
+    },
+    /// Streams the operations added to the history from operation `from`
+    /// on, or from now on, of `account` only if given, as
+    /// `ResponsePayload::Events` progress frames until the request is
+    /// cancelled. Needs a pipelined connection.
+    Subscribe {
+        from: Option<usize>,
+        account: Option<AccountRef>,
+    },
+    /// Runs `command` on behalf of the identity the `token` belongs to.
+    AsIdentity {
+        token: String,
+        command: Box<Command>,
+    },
+    /// Runs `command`; the server tags its log lines and an error response
+    /// with `request_id`.
+    WithRequestId {
+        request_id: String,
+        command: Box<Command>,
+    },
+    /// Runs `command` unless `timeout_ms` milliseconds have passed since the
+    /// server received it: by then the client no longer waits for the answer.
+    WithDeadline {
+        timeout_ms: u64,
+        command: Box<Command>,
+    },
+    /// Runs `command` once the server history holds at least `min_position`
+    /// operations and answers with `ResponsePayload::Positioned`. A replica
+    /// behind that position syncs with the primary first, so a client that
+    /// passes the position of its last write reads that write.
+    WithPosition {
+        min_position: Option<usize>,
+        command: Box<Command>,
+    },
+    /// Runs `command` as the holder of `lock`, so it may change the account
+    /// the lock is on.
+    WithLock {
+        lock: LockId,
+        command: Box<Command>,
+    },
+}
+
+impl Command {
+    /// Name of the command variant, used as a metrics key.
+    #[allow(deprecated)]
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::CreateAccount(_) => "CreateAccount",
+            Command::IncreaseAccount(..) => "IncreaseAccount",
+            Command::DecreaseAccount(..) => "DecreaseAccount",
+            Command::DecreaseIfBalanceAtLeast { .. } => "DecreaseIfBalanceAtLeast",
+            Command::Transfer { .. } => "Transfer",
+            Command::TransferIf { .. } => "TransferIf",
+            Command::GetHistory => "GetHistory",
+            Command::SearchHistory { .. } => "SearchHistory",
+            Command::QueryHistory { .. } => "QueryHistory",
+            Command::GetHistoryPage { .. } => "GetHistoryPage",
+            Command::GetHistoryPaged { .. } => "GetHistoryPaged",
+            Command::GetHistoryArchive { .. } => "GetHistoryArchive",
+            Command::GetAccountBalance(_) => "GetAccountBalance",
+            Command::GetVersionedBalance(_) => "GetVersionedBalance",
+            Command::GetBalanceAt { .. } => "GetBalanceAt",
+            Command::Restore(_) => "Restore",
+            Command::GetAccountHistory(_) => "GetAccountHistory",
+            Command::GetAccountHistoryPaged { .. } => "GetAccountHistoryPaged",
+            Command::GetProjectedHistory { .. } => "GetProjectedHistory",
+            Command::RemoteTransfer { .. } => "RemoteTransfer",
+            Command::Reserve { .. } => "Reserve",
+            Command::CommitReservation(_) => "CommitReservation",
+            Command::ReleaseReservation(_) => "ReleaseReservation",
+            Command::LockAccount { .. } => "LockAccount",
+            Command::LockAccounts { .. } => "LockAccounts",
+            Command::UnlockAccount(_) => "UnlockAccount",
+            Command::Transaction(_) => "Transaction",
+            Command::Batch(_) => "Batch",
+            Command::BulkTransfer { .. } => "BulkTransfer",
+            Command::Prepare { .. } => "Prepare",
+            Command::Commit(_) => "Commit",
+            Command::Abort(_) => "Abort",
+            Command::BeginTransaction => "BeginTransaction",
+            Command::CommitTransaction => "CommitTransaction",
+            Command::RollbackTransaction => "RollbackTransaction",
+            Command::GetStatement { .. } => "GetStatement",
+            Command::Reload { .. } => "Reload",
+            Command::GetMetrics { .. } => "GetMetrics",
+            Command::GetStats { .. } => "GetStats",
+            Command::GetSnapshot => "GetSnapshot",
+            Command::GetSnapshotChunk { .. } => "GetSnapshotChunk",
+            Command::Handshake => "Handshake",
+            Command::GetShardMap => "GetShardMap",
+            Command::SetAccountLimits { .. } => "SetAccountLimits",
+            Command::GetAccountLimits(_) => "GetAccountLimits",
+            Command::SetAccountMetadata { .. } => "SetAccountMetadata",
+            Command::GetAccountMetadata(_) => "GetAccountMetadata",
+            Command::SetAccountOwners { .. } => "SetAccountOwners",
+            Command::GetAccountOwners(_) => "GetAccountOwners",
+            Command::GetSubtreeBalance(_) => "GetSubtreeBalance",
+            Command::SetAccountTags { .. } => "SetAccountTags",
+            Command::GetAccountTags(_) => "GetAccountTags",
+            Command::FindAccountsByTag(_) => "FindAccountsByTag",
+            Command::FindAccounts { .. } => "FindAccounts",
+            Command::GetTopAccounts(_) => "GetTopAccounts",
+            Command::CloseAccount(_) => "CloseAccount",
+            Command::ListAccounts(_) => "ListAccounts",
+            Command::ListAccountsPaged { .. } => "ListAccountsPaged",
+            Command::GetHistoryDigest { .. } => "GetHistoryDigest",
+            Command::CheckConsistency { .. } => "CheckConsistency",
+            Command::SetMaintenance { .. } => "SetMaintenance",
+            Command::WriteSnapshot { .. } => "WriteSnapshot",
+            Command::AddToken { .. } => "AddToken",
+            Command::RevokeToken { .. } => "RevokeToken",
+            Command::ListTokens { .. } => "ListTokens",
+            Command::SetRateLimit { .. } => "SetRateLimit",
+            Command::GetRateLimits { .. } => "GetRateLimits",
+            Command::ListJobs { .. } => "ListJobs",
+            Command::ApproveTransfer { .. } => "ApproveTransfer",
+            Command::RejectTransfer { .. } => "RejectTransfer",
+            Command::ListPendingTransfers { .. } => "ListPendingTransfers",
+            Command::OpenDispute { .. } => "OpenDispute",
+            Command::ListDisputes { .. } => "ListDisputes",
+            Command::ResolveDispute { .. } => "ResolveDispute",
+            Command::SetRequiredApprovals { .. } => "SetRequiredApprovals",
+            Command::ProposeTransfer { .. } => "ProposeTransfer",
+            Command::ApproveProposal(_) => "ApproveProposal",
+            Command::SetAlertRule { .. } => "SetAlertRule",
+            Command::ListAlerts => "ListAlerts",
+            Command::AnonymizeAccount { .. } => "AnonymizeAccount",
+            Command::Subscribe { .. } => "Subscribe",
+            Command::AsIdentity { command, .. } => command.name(),
+            Command::WithRequestId { command, .. } => command.name(),
+            Command::WithDeadline { command, .. } => command.name(),
+            Command::WithPosition { command, .. } => command.name(),
+            Command::WithLock { command, .. } => command.name(),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[cfg_attr(
+    feature = "archive",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+#[serde(remote = "Self")]
 pub enum Operation {
     CreateAccount(String),
     IncreaseAccount(String, u32),
     DecreaseAccount(String, u32),
     Transfer(String, String, u32),
+    RemoteTransferOut {
+        from: String,
+        to: RemoteAccount,
+        amount: u32,
+    },
+    RemoteTransferIn {
+        from: RemoteAccount,
+        to: String,
+        amount: u32,
+    },
+    TransactionLeg {
+        transaction: TransactionId,
+        account: String,
+        kind: ReservationKind,
+        amount: u32,
+    },
+    SetLimits {
+        account: String,
+        limits: AccountLimits,
+    },
+    CloseAccount(String),
+    /// Account `id` and its sub-accounts were renamed under `pseudonym`
+    /// everywhere, the history before this operation included. Replaying it
+    /// renames them too.
+    ///
+    /// `checkpoint` is the hex hash of the chain just before the operation,
+    /// over the history as it was: the chain goes on from it (see
+    /// [`digest::chain`]), so digests reported before the renaming stay
+    /// valid, but the renamed operations before it are no longer covered by
+    /// the later digests.
+    AnonymizeAccount {
+        id: AccountId,
+        pseudonym: String,
+        checkpoint: String,
+    },
+}
+
+impl Operation {
+    /// Names of the local accounts the operation touches.
+    pub fn accounts(&self) -> Vec<&str> {
+        match self {
+            Operation::Transfer(from, to, _) => vec![from, to],
+            Operation::CreateAccount(account)
+            | Operation::IncreaseAccount(account, _)
+            | Operation::DecreaseAccount(account, _)
+            | Operation::RemoteTransferOut { from: account, .. }
+            | Operation::RemoteTransferIn { to: account, .. }
+            | Operation::TransactionLeg { account, .. }
+            | Operation::SetLimits { account, .. }
+            | Operation::CloseAccount(account)
+            | Operation::AnonymizeAccount {
+                pseudonym: account, ..
+            } => vec![account],
+        }
+    }
+
+    /// Same as [`Operation::accounts`], but the names can be changed.
+    pub fn accounts_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            Operation::Transfer(from, to, _) => vec![from, to],
+            Operation::CreateAccount(account)
+            | Operation::IncreaseAccount(account, _)
+            | Operation::DecreaseAccount(account, _)
+            | Operation::RemoteTransferOut { from: account, .. }
+            | Operation::RemoteTransferIn { to: account, .. }
+            | Operation::TransactionLeg { account, .. }
+            | Operation::SetLimits { account, .. }
+            | Operation::CloseAccount(account)
+            | Operation::AnonymizeAccount {
+                pseudonym: account, ..
+            } => vec![account],
+        }
+    }
+
+    /// Money the operation moved; 0 for operations that move none.
+    pub fn amount(&self) -> u32 {
+        match self {
+            Operation::IncreaseAccount(_, amount)
+            | Operation::DecreaseAccount(_, amount)
+            | Operation::Transfer(_, _, amount)
+            | Operation::RemoteTransferOut { amount, .. }
+            | Operation::RemoteTransferIn { amount, .. }
+            | Operation::TransactionLeg { amount, .. } => *amount,
+            Operation::CreateAccount(_)
+            | Operation::SetLimits { .. }
+            | Operation::CloseAccount(_)
+            | Operation::AnonymizeAccount { .. } => 0,
+        }
+    }
+
+    /// Accounts on both sides of the operation, the money source first;
+    /// a remote account is written as `account@address`.
+    pub fn counterparties(&self) -> Vec<String> {
+        match self {
+            Operation::RemoteTransferOut { from, to, .. } => vec![from.clone(), to.to_string()],
+            Operation::RemoteTransferIn { from, to, .. } => vec![from.to_string(), to.clone()],
+            operation => operation.accounts().into_iter().map(String::from).collect(),
+        }
+    }
+
+    /// How much the operation changed the balance of `account`.
+    pub fn balance_change(&self, account: &str) -> i64 {
+        match self {
+            Operation::CreateAccount(_) => 0,
+            Operation::IncreaseAccount(name, amount) if name == account => *amount as i64,
+            Operation::DecreaseAccount(name, amount) if name == account => -(*amount as i64),
+            Operation::Transfer(from, _, amount) if from == account => -(*amount as i64),
+            Operation::Transfer(_, to, amount) if to == account => *amount as i64,
+            Operation::RemoteTransferOut { from, amount, .. } if from == account => {
+                -(*amount as i64)
+            }
+            Operation::RemoteTransferIn { to, amount, .. } if to == account => *amount as i64,
+            Operation::TransactionLeg {
+                account: name,
+                kind,
+                amount,
+                ..
+            } if name == account => match kind {
+                ReservationKind::Debit => -(*amount as i64),
+                ReservationKind::Credit => *amount as i64,
+            },
+            _ => 0,
+        }
+    }
+}
+
+/// One operation of a statement with the account balance right after it.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct StatementLine {
+    pub timestamp: u64,
+    pub operation: Operation,
+    pub balance: u32,
+}
+
+/// Account activity for the period `[from_ts, to_ts)`, timestamps are unix seconds.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Statement {
+    pub account: String,
+    pub from_ts: u64,
+    pub to_ts: u64,
+    pub opening_balance: u32,
+    pub lines: Vec<StatementLine>,
+    pub closing_balance: u32,
 }
 
+/// Reply to a command: the payload on success, the reason otherwise.
+pub type Response = Result<ResponsePayload, BankError>;
+
+/// What a successful command returns.
 #[derive(Debug, Serialize, Deserialize)]
-pub enum Response {
-    Account(Result<usize, BankError>),
-    OperationResult(Result<usize, BankError>),
-    TransferResult(Result<(), BankError>),
+#[serde(remote = "Self")]
+pub enum ResponsePayload {
+    // Команда выполнена, возвращать нечего
+    Done,
+    Account(AccountId),
+    // Номер операции в истории банка
+    OperationId(usize),
     History(Vec<Operation>),
-    AccountBalance(Result<u32, BankError>),
-    AccountHistory(Option<Vec<Operation>>),
-    Restore,
+    // Страница GetHistoryArchive: операции в архивном формате rkyv
+    HistoryArchive {
+        data: Vec<u8>,
+        next_cursor: Option<Cursor>,
+    },
+    // Найденные операции с их номерами в истории
+    Operations(Vec<(usize, Operation)>),
+    QueryRows(Vec<QueryRow>),
+    AccountBalance(u32),
+    VersionedBalance(VersionedBalance),
+    Reservation(ReservationId),
+    Lock(LockId),
+    Transaction(TransactionId),
+    Statement(Statement),
+    Metrics(Vec<CommandMetrics>),
+    Snapshot(Box<Snapshot>),
+    SnapshotChunk(SnapshotChunk),
+    AccountLimits(AccountLimits),
+    AccountMetadata(BTreeMap<String, String>),
+    AccountOwners(BTreeSet<String>),
+    SubtreeBalance(u64),
+    AccountTags(BTreeSet<String>),
+    // Счета с тегом и их балансы
+    AccountsByTag(Vec<(String, u32)>),
+    // Счета с балансом в заданных границах
+    AccountsByBalance(Vec<(String, u32)>),
+    // Счета с наибольшими балансами, по убыванию
+    TopAccounts(Vec<(String, u32)>),
+    // Счета, отобранные ListAccounts, в порядке создания
+    Accounts(Vec<(String, u32)>),
+    AccountsPage(Page<(String, u32)>),
+    // Страница GetHistoryPaged или GetAccountHistoryPaged
+    HistoryPage(Page<Operation>),
+    // Страницы GetProjectedHistory: одно поле на операцию
+    AmountsPage(Page<u32>),
+    CounterpartiesPage(Page<Vec<String>>),
+    HistoryDigest(HistoryDigest),
+    ConsistencyReport(ConsistencyReport),
+    // Номера операций пакета в истории банка
+    Batch(Vec<usize>),
+    // Номер операции зачисления или ошибка для каждого получателя пакетного перевода
+    BulkTransfer(Vec<Result<usize, BankError>>),
+    // Дайджест операций, добавленных Restore, как если бы история с них начиналась
+    Restored(HistoryDigest),
+    // Промежуточный ответ на Restore по конвейерному соединению
+    RestoreProgress(RestoreProgress),
+    ServerInfo(ServerInfo),
+    Stats(ServerStats),
+    // Адреса шардов за маршрутизатором
+    ShardMap(Vec<String>),
+    // Снимок на стольких операциях передан на запись
+    SnapshotQueued(usize),
+    Tokens(Vec<TokenInfo>),
+    RateLimits(RateLimits),
+    Jobs(Vec<JobInfo>),
+    PendingTransfers(Vec<PendingTransfer>),
+    Dispute(DisputeId),
+    Disputes(Vec<Dispute>),
+    Proposal(ProposalStatus),
+    Alerts(Vec<Alert>),
+    // Новое имя счета после AnonymizeAccount
+    Pseudonym(String),
+    // Сколько операций накопила интерактивная транзакция
+    Buffered(usize),
+    // Промежуточный ответ на Subscribe: новые операции с их номерами в истории
+    Events(Vec<(usize, Operation)>),
+    // Ответ на WithPosition: длина истории после команды и ответ самой команды
+    Positioned {
+        position: usize,
+        payload: Box<ResponsePayload>,
+    },
+}
+
+/// How far a restore got: `applied` of its operations are in the history,
+/// the last of them at `operation_id`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct RestoreProgress {
+    pub applied: usize,
+    pub operation_id: usize,
+}
+
+/// What a server tells about itself in the handshake.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub protocol_version: u32,
+    // Адрес, на котором сервер принимает соединения; с портом 0 - выбранный системой
+    pub address: String,
+    // Имена форматов (`WireFormat::name`), самый быстрый первым
+    pub encodings: Vec<String>,
+    // Адрес, с которого сервер видит клиента; за балансировщиком с PROXY - настоящий
+    #[serde(default)]
+    pub client_address: Option<String>,
+}
+
+/// A token from the server config. Only the beginning of the token is
+/// shown, enough to tell the tokens apart.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct TokenInfo {
+    pub token: String,
+    pub identity: Option<String>,
+    pub admin: bool,
+}
+
+/// Requests to an account are let through at `per_second` on average, with
+/// bursts of up to `burst` at once.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub per_second: u32,
+    pub burst: u32,
+}
+
+/// Request rate limits of a server: `default` applies to every account
+/// without a limit in `accounts`.
+#[derive(Debug, PartialEq, Eq, Clone, Default, Serialize, Deserialize)]
+pub struct RateLimits {
+    pub default: Option<RateLimit>,
+    pub accounts: BTreeMap<String, RateLimit>,
+}
+
+/// A job of the server scheduler; times are unix seconds.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct JobInfo {
+    pub name: String,
+    pub schedule: String,
+    pub task: String,
+    // Нет, если расписание больше не сработает
+    pub next_run: Option<u64>,
+    pub last_run: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+/// A transfer above the approval threshold of the server. Its amount is
+/// held on `from` until an admin approves or rejects it.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[cfg_attr(
+    feature = "archive",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+pub struct PendingTransfer {
+    pub id: PendingTransferId,
+    pub from: String,
+    pub to: String,
+    pub amount: u32,
+    // Когда перевод был запрошен, unix-секунды
+    pub requested_at: u64,
+}
+
+/// Transfer proposed out of an account that needs several approvals, with
+/// the identities that have approved it so far.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[cfg_attr(
+    feature = "archive",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+pub struct OpenProposal {
+    pub id: ProposalId,
+    pub from: String,
+    pub to: String,
+    pub amount: u32,
+    pub approvals: BTreeSet<String>,
+}
+
+/// Where a transfer proposed out of an account that needs several
+/// approvals stands.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct ProposalStatus {
+    pub id: ProposalId,
+    // Сколько разных личностей одобрило перевод и сколько нужно
+    pub approvals: u32,
+    pub required: u32,
+    // Номер операции перевода, если одобрений хватило и он сделан
+    pub operation: Option<usize>,
+}
+
+/// The balance of `account` fell below the `below` of its alert rule at
+/// `since`, unix seconds, and has not recovered; `balance` is the balance
+/// it fell to.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Alert {
+    pub account: String,
+    pub below: u32,
+    pub balance: u32,
+    pub since: u64,
+}
+
+/// One group of a history query result: the group, `None` without
+/// `group by`, the number of operations in it and the aggregate over them.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct QueryRow {
+    pub group: Option<String>,
+    pub count: u64,
+    pub value: u64,
+}
+
+/// How much memory the bank takes by the estimate of the server, and how
+/// far it may grow. The estimates count the data and its indexes, not the
+/// allocator overhead, and the history as if it were kept in memory.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct ServerStats {
+    // Адрес, на котором сервер принимает соединения, с портом, выбранным системой
+    #[serde(default)]
+    pub address: String,
+    // Когда операции попадают на диск, как в storage.durability; нет, если
+    // банк хранится только в памяти
+    #[serde(default)]
+    pub durability: Option<String>,
+    pub accounts: usize,
+    pub operations: usize,
+    pub account_bytes: u64,
+    pub history_bytes: u64,
+    pub max_accounts: Option<usize>,
+    pub max_history: Option<usize>,
+}
+
+/// A dispute against operation `operation` of the history; times are unix
+/// seconds.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[cfg_attr(
+    feature = "archive",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+pub struct Dispute {
+    pub id: DisputeId,
+    pub operation: usize,
+    pub reason: String,
+    // Кто открыл спор; нет для анонимного клиента
+    pub opened_by: Option<String>,
+    pub opened_at: u64,
+    // Нет, пока спор открыт
+    pub resolution: Option<DisputeResolution>,
+}
+
+/// How a dispute was closed.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(
+    feature = "archive",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+pub struct DisputeResolution {
+    pub resolved_at: u64,
+    // Операция, отменившая оспоренную, если ее отменили
+    pub reversal: Option<usize>,
+}
+
+/// Balance of an account and its version: the number of operations that
+/// changed the balance so far. Conditional commands fail when the version
+/// moved on.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct VersionedBalance {
+    pub balance: u32,
+    pub version: u64,
+}
+
+/// Differences between the live bank state and the state its history gives;
+/// empty when they agree.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct ConsistencyReport {
+    pub operations: usize,
+    pub differences: Vec<String>,
+}
+
+/// Last hash of the hash chain over the first `operations` operations of the
+/// history, hex encoded.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct HistoryDigest {
+    pub operations: usize,
+    pub digest: String,
+}
+
+/// Part of a snapshot shipped to a replica: bytes from `offset` of the
+/// snapshot at `operations` operations, encoded by
+/// `archive::encode_snapshot` into `size` bytes. A download interrupted
+/// anywhere continues from the bytes it already has.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct SnapshotChunk {
+    pub operations: usize,
+    pub size: u64,
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+/// Consistent copy of the bank state: account balances in id order and the
+/// history they result from. Operations after `history.len()` can be fetched
+/// with `GetHistoryPaged` to catch up.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[cfg_attr(
+    feature = "archive",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+pub struct Snapshot {
+    pub accounts: Vec<(String, u32)>,
+    pub history: Vec<Operation>,
+    pub timestamps: Vec<u64>,
+    // Метаданные счетов по имени
+    #[serde(default)]
+    pub metadata: HashMap<String, BTreeMap<String, String>>,
+    // Владельцы счетов по имени
+    #[serde(default)]
+    pub owners: HashMap<String, BTreeSet<String>>,
+    // Теги счетов по имени
+    #[serde(default)]
+    pub tags: HashMap<String, BTreeSet<String>>,
+    #[serde(default)]
+    pub disputes: Vec<Dispute>,
+    // Сколько одобрений нужно для переводов со счета, по имени
+    #[serde(default)]
+    pub approvals: HashMap<String, u32>,
+    // Порог оповещения о низком балансе, по имени счета
+    #[serde(default)]
+    pub alert_rules: HashMap<String, u32>,
+    // Незавершенные резервирования: голоса распределенных транзакций и
+    // переводы между банками; номер следующего не выдается повторно
+    #[serde(default)]
+    pub reservations: Vec<OpenReservation>,
+    #[serde(default)]
+    pub next_reservation_id: ReservationId,
+    // Переводы, ждущие администратора; номер следующего тоже не выдается повторно
+    #[serde(default)]
+    pub pending_transfers: Vec<PendingTransfer>,
+    #[serde(default)]
+    pub next_pending_id: PendingTransferId,
+    // Переводы, ждущие одобрений владельцев счета, и номер следующего
+    #[serde(default)]
+    pub proposals: Vec<OpenProposal>,
+    #[serde(default)]
+    pub next_proposal_id: ProposalId,
+}
+
+/// Request statistics of one command; latencies are in microseconds.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct CommandMetrics {
+    pub command: String,
+    pub count: u64,
+    pub errors: u64,
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(remote = "Self")]
 pub enum BankError {
     AccountAlreadyExists(String),
     IncorrectAmount(u32),
     InsufficientFunds(u32),
     TransferToMyself,
-    AccountDoesNotExist(String)
+    AccountDoesNotExist(String),
+    ReservationDoesNotExist(ReservationId),
+    RemoteUnavailable(String),
+    UnbalancedTransaction,
+    TransactionDoesNotExist(TransactionId),
+    CoordinatorLog(String),
+    InvalidHistory {
+        index: usize,
+        reason: String,
+    },
+    Unauthorized,
+    InvalidConfig(String),
+    LimitExceeded(String),
+    Forbidden(String),
+    InvalidAccountName(String),
+    UnexpectedResponse(String),
+    ProtocolError(String),
+    /// The server is in maintenance mode and only answers queries.
+    Maintenance,
+    /// The server is a read-only replica; mutations go to the primary at this address.
+    NotPrimary(String),
+    /// The client cancelled the request before it was done.
+    Cancelled,
+    /// The deadline of the request passed before the server got to it.
+    DeadlineExceeded,
+    /// Less than `min_after` would stay on the account.
+    BelowMinimumBalance {
+        min_after: u32,
+    },
+    /// The account changed since the client read it at version `expected`.
+    Conflict {
+        expected: u64,
+        actual: u64,
+    },
+    /// Operation `index` of a batch failed, so none of them was applied.
+    BatchFailed {
+        index: usize,
+        error: Box<BankError>,
+    },
+    /// `error` of the request tagged with `request_id`.
+    RequestFailed {
+        request_id: String,
+        error: Box<BankError>,
+    },
+    /// A request or response of `size` bytes is larger than `limit`; for a
+    /// request it is the size up to where the limit was exceeded.
+    MessageTooLarge {
+        size: usize,
+        limit: usize,
+    },
+    /// `account` got more requests than its rate limit allows; the next
+    /// one is let through in `retry_after_ms`.
+    RateLimited {
+        account: String,
+        retry_after_ms: u64,
+    },
+    /// The transfer is above the approval threshold: its amount is held
+    /// and it waits for an admin as pending transfer `id`.
+    TransferPending(PendingTransferId),
+    PendingTransferDoesNotExist(PendingTransferId),
+    OperationDoesNotExist(usize),
+    /// The account is closed and accepts no more operations.
+    AccountClosed(String),
+    /// Only an account without money or reservations can be closed.
+    AccountNotEmpty(String),
+    /// The operation with this ID can not be undone by a reversing operation.
+    NotReversible(usize),
+    /// There is no open dispute with this ID.
+    DisputeDoesNotExist(DisputeId),
+    /// The cursor was not given out by this listing.
+    InvalidCursor(String),
+    /// The server no longer keeps the snapshot at this many operations for
+    /// shipping; the download has to start over.
+    SnapshotUnavailable(usize),
+    /// The history of this server is shorter than the position the request
+    /// has to see, e.g. the position was given out by another bank.
+    PositionNotReached {
+        position: usize,
+        operations: usize,
+    },
+    /// The connection already has an interactive transaction open.
+    TransactionAlreadyOpen,
+    /// The connection has no interactive transaction open.
+    NoOpenTransaction,
+    /// Another commit changed this account after the interactive
+    /// transaction used it; nothing was applied.
+    TransactionConflict(String),
+    /// The command can not run inside an interactive transaction.
+    UnsupportedInTransaction(String),
+    /// Another client holds a lock on the account.
+    AccountLocked(String),
+    /// There is no lock with this ID, or its lease has run out.
+    LockDoesNotExist(LockId),
+    /// The router can not tell which shard the command goes to.
+    NotRoutable(String),
+    /// Transfers out of the account need several approvals; they have to be
+    /// proposed with `ProposeTransfer`.
+    ApprovalRequired(String),
+    ProposalDoesNotExist(ProposalId),
+    /// The caller has already approved this proposal.
+    AlreadyApproved(ProposalId),
+    /// The bank already holds `limit` of `resource`, accounts or history
+    /// operations, the most the server is configured for.
+    CapacityExceeded {
+        resource: String,
+        limit: usize,
+    },
+    /// The history query can not be parsed; the reason says where.
+    InvalidQuery(String),
+    /// The balance of the account would exceed the largest one an account
+    /// can hold.
+    BalanceOverflow(String),
+}
+
+impl BankError {
+    /// Tags the error with `request_id` unless it already carries one.
+    pub fn with_request_id(self, request_id: &str) -> BankError {
+        match self {
+            BankError::RequestFailed { .. } => self,
+            error => BankError::RequestFailed {
+                request_id: request_id.to_string(),
+                error: Box::new(error),
+            },
+        }
+    }
+
+    /// The error itself, without the request it failed in.
+    pub fn cause(&self) -> &BankError {
+        match self {
+            BankError::RequestFailed { error, .. } => error.cause(),
+            error => error,
+        }
+    }
+
+    /// Name of the error variant, used as a metrics label.
+    pub fn name(&self) -> &'static str {
+        match self {
+            BankError::AccountAlreadyExists(_) => "AccountAlreadyExists",
+            BankError::IncorrectAmount(_) => "IncorrectAmount",
+            BankError::InsufficientFunds(_) => "InsufficientFunds",
+            BankError::TransferToMyself => "TransferToMyself",
+            BankError::AccountDoesNotExist(_) => "AccountDoesNotExist",
+            BankError::ReservationDoesNotExist(_) => "ReservationDoesNotExist",
+            BankError::RemoteUnavailable(_) => "RemoteUnavailable",
+            BankError::UnbalancedTransaction => "UnbalancedTransaction",
+            BankError::TransactionDoesNotExist(_) => "TransactionDoesNotExist",
+            BankError::CoordinatorLog(_) => "CoordinatorLog",
+            BankError::InvalidHistory { .. } => "InvalidHistory",
+            BankError::Unauthorized => "Unauthorized",
+            BankError::InvalidConfig(_) => "InvalidConfig",
+            BankError::LimitExceeded(_) => "LimitExceeded",
+            BankError::Forbidden(_) => "Forbidden",
+            BankError::InvalidAccountName(_) => "InvalidAccountName",
+            BankError::UnexpectedResponse(_) => "UnexpectedResponse",
+            BankError::ProtocolError(_) => "ProtocolError",
+            BankError::Maintenance => "Maintenance",
+            BankError::NotPrimary(_) => "NotPrimary",
+            BankError::Cancelled => "Cancelled",
+            BankError::DeadlineExceeded => "DeadlineExceeded",
+            BankError::BatchFailed { .. } => "BatchFailed",
+            BankError::Conflict { .. } => "Conflict",
+            BankError::BelowMinimumBalance { .. } => "BelowMinimumBalance",
+            BankError::RequestFailed { .. } => "RequestFailed",
+            BankError::MessageTooLarge { .. } => "MessageTooLarge",
+            BankError::RateLimited { .. } => "RateLimited",
+            BankError::TransferPending(_) => "TransferPending",
+            BankError::PendingTransferDoesNotExist(_) => "PendingTransferDoesNotExist",
+            BankError::OperationDoesNotExist(_) => "OperationDoesNotExist",
+            BankError::AccountClosed(_) => "AccountClosed",
+            BankError::AccountNotEmpty(_) => "AccountNotEmpty",
+            BankError::NotReversible(_) => "NotReversible",
+            BankError::DisputeDoesNotExist(_) => "DisputeDoesNotExist",
+            BankError::InvalidCursor(_) => "InvalidCursor",
+            BankError::SnapshotUnavailable(_) => "SnapshotUnavailable",
+            BankError::PositionNotReached { .. } => "PositionNotReached",
+            BankError::TransactionAlreadyOpen => "TransactionAlreadyOpen",
+            BankError::NoOpenTransaction => "NoOpenTransaction",
+            BankError::TransactionConflict(_) => "TransactionConflict",
+            BankError::UnsupportedInTransaction(_) => "UnsupportedInTransaction",
+            BankError::AccountLocked(_) => "AccountLocked",
+            BankError::LockDoesNotExist(_) => "LockDoesNotExist",
+            BankError::NotRoutable(_) => "NotRoutable",
+            BankError::ApprovalRequired(_) => "ApprovalRequired",
+            BankError::ProposalDoesNotExist(_) => "ProposalDoesNotExist",
+            BankError::AlreadyApproved(_) => "AlreadyApproved",
+            BankError::CapacityExceeded { .. } => "CapacityExceeded",
+            BankError::InvalidQuery(_) => "InvalidQuery",
+            BankError::BalanceOverflow(_) => "BalanceOverflow",
+        }
+    }
+}
+
+versioned_serde!(Command, Operation, ResponsePayload, BankError);
+
+/// Replays `history` on empty balances and reports the first operation that
+/// the bank would reject.
+pub fn validate_history(history: &[Operation]) -> Result<(), BankError> {
+    validate_history_from(HashMap::new(), history)
+}
+
+/// Same as [`validate_history`], but on top of existing account balances.
+pub fn validate_history_from(
+    mut balances: HashMap<String, i64>,
+    history: &[Operation],
+) -> Result<(), BankError> {
+    let mut closed = HashSet::new();
+    for (index, operation) in history.iter().enumerate() {
+        let invalid = |reason: String| BankError::InvalidHistory { index, reason };
+        if let Some(account) = operation
+            .accounts()
+            .into_iter()
+            .find(|a| closed.contains(a))
+        {
+            return Err(invalid(format!("account {} is closed", account)));
+        }
+        match operation {
+            Operation::CreateAccount(account) => {
+                if !is_valid_account_name(account) {
+                    return Err(invalid(format!("invalid account name {}", account)));
+                }
+                if let Some(parent) = parent_account(account) {
+                    if !balances.contains_key(parent) {
+                        return Err(invalid(format!("parent account {} does not exist", parent)));
+                    }
+                }
+                if balances.insert(account.clone(), 0).is_some() {
+                    return Err(invalid(format!("account {} already exists", account)));
+                }
+                continue;
+            }
+            Operation::Transfer(from, to, _) if from == to => {
+                return Err(invalid(format!("transfer from {} to itself", from)));
+            }
+            Operation::SetLimits { account, .. }
+            | Operation::AnonymizeAccount {
+                pseudonym: account, ..
+            } => {
+                if !balances.contains_key(account) {
+                    return Err(invalid(format!("account {} does not exist", account)));
+                }
+                continue;
+            }
+            Operation::CloseAccount(account) => {
+                match balances.get(account) {
+                    None => return Err(invalid(format!("account {} does not exist", account))),
+                    Some(0) => {}
+                    Some(_) => return Err(invalid(format!("account {} is not empty", account))),
+                }
+                closed.insert(account.as_str());
+                continue;
+            }
+            _ => {}
+        }
+
+        for account in operation.accounts() {
+            let Some(balance) = balances.get_mut(account) else {
+                return Err(invalid(format!("account {} does not exist", account)));
+            };
+            let change = operation.balance_change(account);
+            if change == 0 {
+                return Err(invalid("zero amount".to_string()));
+            }
+            *balance += change;
+            if *balance < 0 {
+                return Err(invalid(format!("balance of {} goes negative", account)));
+            }
+            if *balance > u32::MAX as i64 {
+                return Err(invalid(format!("balance of {} overflows", account)));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_valid_history() {
+        let history = vec![
+            Operation::CreateAccount("X".to_string()),
+            Operation::CreateAccount("Y".to_string()),
+            Operation::IncreaseAccount("X".to_string(), 10),
+            Operation::Transfer("X".to_string(), "Y".to_string(), 10),
+        ];
+        assert!(validate_history(&history).is_ok());
+    }
+
+    #[test]
+    fn validate_negative_balance() {
+        let history = vec![
+            Operation::CreateAccount("X".to_string()),
+            Operation::IncreaseAccount("X".to_string(), 10),
+            Operation::DecreaseAccount("X".to_string(), 11),
+        ];
+        let x = validate_history(&history);
+        assert!(matches!(x, Err(BankError::InvalidHistory { index: 2, .. })));
+    }
+
+    #[test]
+    fn validate_balance_overflow() {
+        let history = vec![
+            Operation::CreateAccount("X".to_string()),
+            Operation::IncreaseAccount("X".to_string(), u32::MAX),
+            Operation::IncreaseAccount("X".to_string(), 1),
+        ];
+        let x = validate_history(&history);
+        assert!(matches!(x, Err(BankError::InvalidHistory { index: 2, .. })));
+        assert!(validate_history(&history[..2]).is_ok());
+    }
+
+    #[test]
+    fn validate_closed_account() {
+        let history = vec![
+            Operation::CreateAccount("X".to_string()),
+            Operation::IncreaseAccount("X".to_string(), 10),
+            Operation::CloseAccount("X".to_string()),
+        ];
+        let x = validate_history(&history);
+        assert!(matches!(x, Err(BankError::InvalidHistory { index: 2, .. })));
+
+        let history = vec![
+            Operation::CreateAccount("X".to_string()),
+            Operation::CloseAccount("X".to_string()),
+            Operation::IncreaseAccount("X".to_string(), 10),
+        ];
+        let x = validate_history(&history);
+        assert!(matches!(x, Err(BankError::InvalidHistory { index: 2, .. })));
+        assert!(validate_history(&history[..2]).is_ok());
+    }
+
+    #[test]
+    fn validate_sub_account_without_parent() {
+        let history = vec![
+            Operation::CreateAccount("X/savings".to_string()),
+            Operation::CreateAccount("X".to_string()),
+        ];
+        let x = validate_history(&history);
+        assert!(matches!(x, Err(BankError::InvalidHistory { index: 0, .. })));
+        assert!(validate_history(&[Operation::CreateAccount("X/".to_string())]).is_err());
+    }
+
+    #[test]
+    fn validate_unknown_account() {
+        let history = vec![Operation::IncreaseAccount("X".to_string(), 10)];
+        let x = validate_history(&history);
+        assert!(matches!(x, Err(BankError::InvalidHistory { index: 0, .. })));
+    }
+
+    #[test]
+    fn projections() {
+        let remote = RemoteAccount {
+            address: "10.0.0.2:8080".to_string(),
+            account: "Y".into(),
+        };
+        let operations = [
+            Operation::CreateAccount("X".to_string()),
+            Operation::Transfer("X".to_string(), "Y".to_string(), 5),
+            Operation::RemoteTransferIn {
+                from: remote,
+                to: "X".to_string(),
+                amount: 7,
+            },
+        ];
+        let amounts: Vec<u32> = operations.iter().map(Operation::amount).collect();
+        assert_eq!(vec![0, 5, 7], amounts);
+        assert_eq!(vec!["X", "Y"], operations[1].counterparties());
+        assert_eq!(vec!["Y@10.0.0.2:8080", "X"], operations[2].counterparties());
+    }
+
+    #[test]
+    fn cursor() {
+        assert_eq!(42, Cursor::at(42).position().unwrap());
+        assert!(matches!(
+            Cursor("page-2".to_string()).position(),
+            Err(BankError::InvalidCursor(_))
+        ));
+    }
 }
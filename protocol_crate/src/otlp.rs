@@ -0,0 +1,368 @@
+//! Minimal OpenTelemetry trace export: spans are sent in batches to an
+//! OTLP/HTTP collector (`http://host:4318/v1/traces`) as JSON.
+//!
+//! The trace context travels in the request ID as a W3C `traceparent`
+//! (`00-<trace id>-<span id>-01`), so a server span becomes a child of the
+//! client span that sent the command.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+// Сколько спанов отправлять одним запросом
+const BATCH_SIZE: usize = 512;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpanKind {
+    Server,
+    Client,
+}
+
+/// One traced operation.
+#[derive(Debug, Clone)]
+pub struct Span {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    end: Option<SystemTime>,
+    attributes: Vec<(String, String)>,
+    error: Option<String>,
+}
+
+impl Span {
+    /// Starts a span; it continues the trace of `parent` when that is a
+    /// `traceparent`, otherwise a new trace is started.
+    pub fn start(name: &str, kind: SpanKind, parent: Option<&str>) -> Self {
+        let parent = parent.and_then(parse_traceparent);
+        let mut span_id = [0; 8];
+        fill_random(&mut span_id);
+        let trace_id = parent.map_or_else(
+            || {
+                let mut trace_id = [0; 16];
+                fill_random(&mut trace_id);
+                trace_id
+            },
+            |(trace_id, _)| trace_id,
+        );
+        Span {
+            trace_id,
+            span_id,
+            parent_span_id: parent.map(|(_, span_id)| span_id),
+            name: name.to_string(),
+            kind,
+            start: SystemTime::now(),
+            end: None,
+            attributes: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// `traceparent` that makes spans of the receiving side children of this one.
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-01", hex(&self.trace_id), hex(&self.span_id))
+    }
+
+    pub fn set_attribute(&mut self, key: &str, value: impl Into<String>) {
+        self.attributes.push((key.to_string(), value.into()));
+    }
+
+    /// Ends the span, as failed with `error` if there is one.
+    pub fn end(&mut self, error: Option<String>) {
+        self.end = Some(SystemTime::now());
+        self.error = error;
+    }
+
+    fn to_json(&self) -> Value {
+        let nanos = |time: SystemTime| {
+            let nanos = time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos();
+            nanos.to_string()
+        };
+        let mut span = json!({
+            "traceId": hex(&self.trace_id),
+            "spanId": hex(&self.span_id),
+            "name": self.name,
+            // SPAN_KIND_SERVER = 2, SPAN_KIND_CLIENT = 3
+            "kind": match self.kind {
+                SpanKind::Server => 2,
+                SpanKind::Client => 3,
+            },
+            "startTimeUnixNano": nanos(self.start),
+            "endTimeUnixNano": nanos(self.end.unwrap_or_else(SystemTime::now)),
+            "attributes": attributes(&self.attributes),
+            // STATUS_CODE_OK = 1, STATUS_CODE_ERROR = 2
+            "status": match &self.error {
+                None => json!({ "code": 1 }),
+                Some(message) => json!({ "code": 2, "message": message }),
+            },
+        });
+        if let Some(parent) = &self.parent_span_id {
+            span["parentSpanId"] = json!(hex(parent));
+        }
+        span
+    }
+}
+
+enum Message {
+    Span(Box<Span>),
+    Flush(Sender<()>),
+}
+
+/// Handle of the background thread exporting spans; clones share the thread.
+#[derive(Debug, Clone)]
+pub struct Tracer {
+    sender: Sender<Message>,
+}
+
+impl Tracer {
+    /// Starts exporting to the OTLP/HTTP `endpoint`, e.g.
+    /// `http://localhost:4318/v1/traces`, on behalf of `service`.
+    pub fn start(endpoint: &str, service: &str) -> Result<Self, String> {
+        let collector = Collector::parse(endpoint, service)?;
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || collector.run(receiver));
+        Ok(Tracer { sender })
+    }
+
+    /// Queues `span` for export, ending it first if it is still open.
+    pub fn record(&self, mut span: Span) {
+        if span.end.is_none() {
+            span.end(None);
+        }
+        let _ = self.sender.send(Message::Span(Box::new(span)));
+    }
+
+    /// Waits until every span recorded so far has been sent.
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        if self.sender.send(Message::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+}
+
+struct Collector {
+    address: String,
+    host: String,
+    path: String,
+    service: String,
+}
+
+impl Collector {
+    fn parse(endpoint: &str, service: &str) -> Result<Self, String> {
+        // TLS не поддерживается: коллектор обычно слушает локально
+        let rest = endpoint
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("{}: only http:// endpoints are supported", endpoint))?;
+        let (host, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/v1/traces"),
+        };
+        let address = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:80", host)
+        };
+        Ok(Collector {
+            address,
+            host: host.to_string(),
+            path: path.to_string(),
+            service: service.to_string(),
+        })
+    }
+
+    fn run(self, receiver: Receiver<Message>) {
+        let mut batch = Vec::new();
+        while let Ok(message) = receiver.recv() {
+            let mut flushed = Vec::new();
+            // Забираем все, что накопилось, и отправляем одним запросом
+            for message in std::iter::once(message).chain(receiver.try_iter()) {
+                match message {
+                    Message::Span(span) => batch.push(*span),
+                    Message::Flush(done) => flushed.push(done),
+                }
+                if batch.len() >= BATCH_SIZE {
+                    self.export(&mut batch);
+                }
+            }
+            self.export(&mut batch);
+            for done in flushed {
+                let _ = done.send(());
+            }
+        }
+    }
+
+    fn export(&self, batch: &mut Vec<Span>) {
+        if batch.is_empty() {
+            return;
+        }
+        let body = self.request_body(batch);
+        if let Err(e) = self.post(&body) {
+            eprintln!(
+                "Failed to export {} spans to {}: {}",
+                batch.len(),
+                self.address,
+                e
+            );
+        }
+        batch.clear();
+    }
+
+    fn request_body(&self, spans: &[Span]) -> String {
+        json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": attributes(&[("service.name".to_string(), self.service.clone())]),
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "bank" },
+                    "spans": spans.iter().map(Span::to_json).collect::<Vec<_>>(),
+                }],
+            }],
+        })
+        .to_string()
+    }
+
+    fn post(&self, body: &str) -> io::Result<()> {
+        let mut stream = TcpStream::connect(&self.address)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            body.len(),
+            body
+        )?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        let response = String::from_utf8_lossy(&response);
+        let status = response.lines().next().unwrap_or_default();
+        match status.split(' ').nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(io::Error::other(format!("collector answered {:?}", status))),
+        }
+    }
+}
+
+fn attributes(attributes: &[(String, String)]) -> Value {
+    attributes
+        .iter()
+        .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+        .collect()
+}
+
+/// Trace and parent span IDs from a `traceparent` header value.
+fn parse_traceparent(value: &str) -> Option<([u8; 16], [u8; 8])> {
+    let mut parts = value.split('-');
+    let (version, trace_id, span_id) = (parts.next()?, parts.next()?, parts.next()?);
+    parts.next()?;
+    if version != "00" {
+        return None;
+    }
+    let trace_id: [u8; 16] = unhex(trace_id)?.try_into().ok()?;
+    let span_id: [u8; 8] = unhex(span_id)?.try_into().ok()?;
+    // Нулевые идентификаторы по спецификации недействительны
+    if trace_id == [0; 16] || span_id == [0; 8] {
+        return None;
+    }
+    Some((trace_id, span_id))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn fill_random(bytes: &mut [u8]) {
+    // RandomState получает случайные ключи от ОС, отдельный генератор не нужен
+    for chunk in bytes.chunks_mut(8) {
+        let random = RandomState::new().build_hasher().finish().to_le_bytes();
+        chunk.copy_from_slice(&random[..chunk.len()]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn traceparent() {
+        let client = Span::start("Transfer", SpanKind::Client, Some("not a traceparent"));
+        assert_eq!(None, client.parent_span_id);
+        let server = Span::start("Transfer", SpanKind::Server, Some(&client.traceparent()));
+        assert_eq!(client.trace_id, server.trace_id);
+        assert_eq!(Some(client.span_id), server.parent_span_id);
+        assert_ne!(client.span_id, server.span_id);
+        assert_eq!(
+            None,
+            parse_traceparent(&format!("00-{}-{}-01", "0".repeat(32), "1".repeat(16)))
+        );
+    }
+
+    #[test]
+    fn export() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let collector = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            // Читаем, пока не придет тело целиком
+            while !String::from_utf8_lossy(&request).ends_with("}]}]}]}") {
+                let n = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let tracer = Tracer::start(&endpoint, "bank-test").unwrap();
+        let mut span = Span::start("Transfer", SpanKind::Server, None);
+        span.set_attribute("bank.request_id", "r-1");
+        span.end(Some("InsufficientFunds".to_string()));
+        tracer.record(span);
+        tracer.flush();
+
+        let request = collector.join().unwrap();
+        assert!(request.starts_with("POST /v1/traces HTTP/1.1\r\n"));
+        let body: Value = serde_json::from_str(request.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        let resource = &body["resourceSpans"][0];
+        assert_eq!(
+            "bank-test",
+            resource["resource"]["attributes"][0]["value"]["stringValue"]
+        );
+        let span = &resource["scopeSpans"][0]["spans"][0];
+        assert_eq!("Transfer", span["name"]);
+        assert_eq!(2, span["kind"]);
+        assert_eq!(2, span["status"]["code"]);
+        assert_eq!("r-1", span["attributes"][0]["value"]["stringValue"]);
+        assert!(Tracer::start("https://collector", "bank").is_err());
+    }
+}
@@ -0,0 +1,307 @@
+//! Framing of pipelined connections. A client that starts the connection
+//! with [`PIPELINE_MARKER`] may send many commands without waiting for the
+//! answers; the server answers each of them, not necessarily in order.
+//!
+//! Every command and every response is a frame: a big-endian `u32` length of
+//! the rest, the length of the request ID (one byte), the request ID and the
+//! body - a command or a response encoded by a [`crate::codec::WireFormat`].
+//!
+//! A body that does not fit into [`MAX_FRAME_SIZE`] is split over several
+//! frames with the same request ID, sent one after another. Every frame but
+//! the last has [`CONTINUATION_FLAG`] set in its length; [`read_frame`]
+//! joins them back, so callers only ever see whole messages.
+//!
+//! A frame from the client with an empty body cancels the request with its
+//! request ID: if the server has not finished it yet, it answers
+//! [`crate::BankError::Cancelled`] instead.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// First byte of a pipelined connection.
+pub const PIPELINE_MARKER: u8 = 0x10;
+
+/// Largest single frame; larger messages are split into several.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// Largest message accepted after joining its frames.
+pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024 * 1024;
+
+/// Set in the length of a frame that is followed by more of the same message.
+pub const CONTINUATION_FLAG: u32 = 1 << 31;
+
+/// Error of [`read_frame_limited`] for a message larger than the limit. It
+/// is found from the frame headers, before the body is read.
+#[derive(Debug)]
+pub struct TooLarge {
+    pub request_id: String,
+    /// Size of the message so far; the rest of it was not looked at.
+    pub size: usize,
+    pub limit: usize,
+}
+
+impl TooLarge {
+    /// The [`TooLarge`] inside `error`, if that is what it is.
+    pub fn find(error: &io::Error) -> Option<&TooLarge> {
+        error.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "message {} of at least {} bytes is larger than {} bytes",
+            self.request_id, self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+/// Writes a message, split into as many frames as its size requires.
+pub fn write_frame(writer: &mut impl Write, request_id: &str, body: &[u8]) -> io::Result<()> {
+    write_frame_with(writer, request_id, body, &mut Vec::new())
+}
+
+/// Same as [`write_frame`], but assembles the frames in `buffer`, so a
+/// connection writing many messages reuses one allocation.
+pub fn write_frame_with(
+    writer: &mut impl Write,
+    request_id: &str,
+    body: &[u8],
+    buffer: &mut Vec<u8>,
+) -> io::Result<()> {
+    write_frames(writer, request_id, body, MAX_FRAME_SIZE, buffer)
+}
+
+fn write_frames(
+    writer: &mut impl Write,
+    request_id: &str,
+    body: &[u8],
+    max_frame_size: usize,
+    frame: &mut Vec<u8>,
+) -> io::Result<()> {
+    let id = request_id.as_bytes();
+    let id_len = u8::try_from(id.len()).map_err(|_| {
+        invalid(format!(
+            "request ID is longer than 255 bytes: {}",
+            request_id
+        ))
+    })?;
+    if body.len() > MAX_MESSAGE_SIZE {
+        return Err(invalid(format!(
+            "message of {} bytes is too large",
+            body.len()
+        )));
+    }
+    let chunk_size = max_frame_size - 1 - id.len();
+    let mut chunks = body.chunks(chunk_size);
+    // Пустое тело (отмена) - тоже кадр
+    let mut chunk: &[u8] = chunks.next().unwrap_or_default();
+    loop {
+        let next = chunks.next();
+        let len = 1 + id.len() + chunk.len();
+        let header = match next {
+            Some(_) => len as u32 | CONTINUATION_FLAG,
+            None => len as u32,
+        };
+        frame.clear();
+        frame.extend_from_slice(&header.to_be_bytes());
+        frame.push(id_len);
+        frame.extend_from_slice(id);
+        frame.extend_from_slice(chunk);
+        writer.write_all(frame)?;
+        match next {
+            Some(next) => chunk = next,
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Asks the server to cancel the request tagged with `request_id`.
+pub fn write_cancel(writer: &mut impl Write, request_id: &str) -> io::Result<()> {
+    write_frame(writer, request_id, &[])
+}
+
+/// Reads the next message, joining its frames; `None` when the connection
+/// was closed between messages.
+pub fn read_frame(reader: &mut impl Read) -> io::Result<Option<(String, Vec<u8>)>> {
+    let mut body = Vec::new();
+    Ok(read_frame_into(reader, &mut body)?.map(|request_id| (request_id, body)))
+}
+
+/// Same as [`read_frame`], but reads the body into `body`, replacing its
+/// contents, and returns only the request ID. Passing the same buffer again
+/// saves allocating one per message.
+pub fn read_frame_into(reader: &mut impl Read, body: &mut Vec<u8>) -> io::Result<Option<String>> {
+    read_frame_limited(reader, body, MAX_MESSAGE_SIZE)
+}
+
+/// Same as [`read_frame_into`], but a message larger than `limit` fails
+/// with a [`TooLarge`] error before its body is read.
+pub fn read_frame_limited(
+    reader: &mut impl Read,
+    body: &mut Vec<u8>,
+    limit: usize,
+) -> io::Result<Option<String>> {
+    body.clear();
+    let limit = limit.min(MAX_MESSAGE_SIZE);
+    let Some((request_id, mut more)) = read_one_frame(reader, body, limit)? else {
+        return Ok(None);
+    };
+    while more {
+        let (next_id, next_more) = read_one_frame(reader, body, limit)?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "message is not finished")
+        })?;
+        if next_id != request_id {
+            return Err(invalid(format!(
+                "continuation of {} has request ID {}",
+                request_id, next_id
+            )));
+        }
+        more = next_more;
+    }
+    Ok(Some(request_id))
+}
+
+// Один кадр: тело дописывается в body, возвращаются ID запроса и признак продолжения
+fn read_one_frame(
+    reader: &mut impl Read,
+    body: &mut Vec<u8>,
+    limit: usize,
+) -> io::Result<Option<(String, bool)>> {
+    let mut header = [0; 4];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let header = u32::from_be_bytes(header);
+    let more = header & CONTINUATION_FLAG != 0;
+    let len = (header & !CONTINUATION_FLAG) as usize;
+    if len == 0 || len > MAX_FRAME_SIZE {
+        return Err(invalid(format!("invalid frame length {}", len)));
+    }
+    let mut id_len = [0; 1];
+    reader.read_exact(&mut id_len)?;
+    let id_len = id_len[0] as usize;
+    if 1 + id_len > len {
+        return Err(invalid("request ID is longer than the frame".to_string()));
+    }
+    let mut id = vec![0; id_len];
+    reader.read_exact(&mut id)?;
+    let request_id = String::from_utf8(id).map_err(|e| invalid(e.to_string()))?;
+
+    let start = body.len();
+    let size = start + len - 1 - id_len;
+    if size > limit {
+        let error = TooLarge {
+            request_id,
+            size,
+            limit,
+        };
+        return Err(io::Error::new(io::ErrorKind::InvalidData, error));
+    }
+    body.resize(size, 0);
+    reader.read_exact(&mut body[start..])?;
+    Ok(Some((request_id, more)))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames() {
+        let mut data = Vec::new();
+        write_frame(&mut data, "r-1", b"{\"GetHistory\":null}").unwrap();
+        write_cancel(&mut data, "").unwrap();
+
+        let mut reader = data.as_slice();
+        let (id, body) = read_frame(&mut reader).unwrap().unwrap();
+        assert_eq!("r-1", id);
+        assert_eq!(b"{\"GetHistory\":null}", body.as_slice());
+        assert_eq!(
+            Some((String::new(), Vec::new())),
+            read_frame(&mut reader).unwrap()
+        );
+        assert_eq!(None, read_frame(&mut reader).unwrap());
+
+        // Тот же буфер для каждого сообщения, прежнее содержимое заменяется
+        let mut reader = data.as_slice();
+        let mut body = Vec::new();
+        assert_eq!(
+            Some("r-1".to_string()),
+            read_frame_into(&mut reader, &mut body).unwrap()
+        );
+        assert_eq!(b"{\"GetHistory\":null}", body.as_slice());
+        assert_eq!(
+            Some(String::new()),
+            read_frame_into(&mut reader, &mut body).unwrap()
+        );
+        assert!(body.is_empty());
+
+        // Обрыв посреди кадра - ошибка, а не конец соединения
+        assert!(read_frame(&mut &data[..6]).is_err());
+        assert!(read_frame(&mut &[0u8, 0, 0, 2, 5, b'x'][..]).is_err());
+        assert!(write_frame(&mut Vec::new(), &"x".repeat(256), b"").is_err());
+    }
+
+    #[test]
+    fn continuation() {
+        let body: Vec<u8> = (0..100).collect();
+        let mut data = Vec::new();
+        // Кадр из 16 байт: длина ID, "r-1" и 12 байт тела
+        write_frames(&mut data, "r-1", &body, 16, &mut Vec::new()).unwrap();
+        write_frames(&mut data, "r-2", b"", 16, &mut Vec::new()).unwrap();
+        assert_eq!(9 * (4 + 4) + 100 + 4 + 4, data.len());
+        assert_eq!(
+            CONTINUATION_FLAG | 16,
+            u32::from_be_bytes([data[0], data[1], data[2], data[3]])
+        );
+
+        let mut reader = data.as_slice();
+        assert_eq!(
+            Some(("r-1".to_string(), body)),
+            read_frame(&mut reader).unwrap()
+        );
+        assert_eq!(
+            Some(("r-2".to_string(), Vec::new())),
+            read_frame(&mut reader).unwrap()
+        );
+        assert_eq!(None, read_frame(&mut reader).unwrap());
+
+        // Продолжение с чужим ID или оборванное сообщение - ошибка
+        let mut mixed = Vec::new();
+        write_frames(&mut mixed, "r-1", &[0; 20], 16, &mut Vec::new()).unwrap();
+        let mut other = Vec::new();
+        write_frame(&mut other, "r-2", b"x").unwrap();
+        mixed.truncate(4 + 16);
+        assert!(read_frame(&mut mixed.as_slice()).is_err());
+        mixed.extend_from_slice(&other);
+        assert!(read_frame(&mut mixed.as_slice()).is_err());
+    }
+
+    #[test]
+    fn limited() {
+        let mut data = Vec::new();
+        write_frames(&mut data, "r-1", &[0; 20], 16, &mut Vec::new()).unwrap();
+        let mut body = Vec::new();
+        assert_eq!(
+            Some("r-1".to_string()),
+            read_frame_limited(&mut data.as_slice(), &mut body, 20).unwrap()
+        );
+
+        // Превышение видно уже по заголовку второго кадра
+        let e = read_frame_limited(&mut data.as_slice(), &mut body, 19).unwrap_err();
+        let too_large = TooLarge::find(&e).unwrap();
+        assert_eq!("r-1", too_large.request_id);
+        assert_eq!((20, 19), (too_large.size, too_large.limit));
+        assert!(TooLarge::find(&invalid("other".to_string())).is_none());
+    }
+}
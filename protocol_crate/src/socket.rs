@@ -0,0 +1,200 @@
+//! TCP options shared by the server listener and client connections.
+
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+
+// Очередь еще не принятых соединений, как у TcpListener::bind
+const BACKLOG: i32 = 128;
+
+/// Options of a TCP socket. The default matches what the standard library
+/// does on Unix: Nagle's algorithm and no keepalive probes, the listener
+/// address reusable right after a restart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Send small writes at once instead of coalescing them (`TCP_NODELAY`).
+    pub nodelay: bool,
+    /// Probe an idle connection this often; `None` sends no probes.
+    pub keepalive: Option<Duration>,
+    /// Bind a listener to an address with connections still in `TIME_WAIT`
+    /// (`SO_REUSEADDR`). Applies only to listeners.
+    pub reuse_address: bool,
+    /// Give up on each address of a connection after this long and try the
+    /// next one; `None` waits as long as the system does.
+    pub connect_timeout: Option<Duration>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            nodelay: false,
+            keepalive: None,
+            reuse_address: true,
+            connect_timeout: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Sets the connection options on `stream`, e.g. one accepted by a listener.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        let socket = SockRef::from(stream);
+        match self.keepalive {
+            Some(interval) => {
+                let keepalive = TcpKeepalive::new()
+                    .with_time(interval)
+                    .with_interval(interval);
+                socket.set_tcp_keepalive(&keepalive)
+            }
+            None => socket.set_keepalive(false),
+        }
+    }
+
+    /// Connects to the first of the addresses `address` resolves to that
+    /// accepts the connection and sets the connection options.
+    pub fn connect(&self, address: impl ToSocketAddrs) -> io::Result<TcpStream> {
+        let addresses: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
+        self.connect_any(&addresses).map(|(stream, _)| stream)
+    }
+
+    /// Tries `addresses` in order; returns the connection and the address
+    /// that accepted it, or the error of the last attempt.
+    fn connect_any(&self, addresses: &[SocketAddr]) -> io::Result<(TcpStream, SocketAddr)> {
+        let mut last_error = None;
+        for address in addresses {
+            let stream = match self.connect_timeout {
+                Some(timeout) => TcpStream::connect_timeout(address, timeout),
+                None => TcpStream::connect(address),
+            };
+            match stream.and_then(|stream| self.apply(&stream).map(|()| stream)) {
+                Ok(stream) => return Ok((stream, *address)),
+                Err(e) => {
+                    last_error = Some(io::Error::new(e.kind(), format!("{}: {}", address, e)))
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to")
+        }))
+    }
+
+    /// Listens on `address`. Connections it accepts do not get the options
+    /// on every platform, so they should be passed to [`SocketOptions::apply`].
+    pub fn bind(&self, address: impl ToSocketAddrs) -> io::Result<TcpListener> {
+        let mut last_error = None;
+        for address in address.to_socket_addrs()? {
+            let socket = Socket::new(
+                Domain::for_address(address),
+                Type::STREAM,
+                Some(Protocol::TCP),
+            )?;
+            socket.set_reuse_address(self.reuse_address)?;
+            match socket
+                .bind(&address.into())
+                .and_then(|()| socket.listen(BACKLOG))
+            {
+                Ok(()) => return Ok(socket.into()),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no address to listen on")
+        }))
+    }
+}
+
+/// Address of a server as the user gave it, e.g. a host name with a port.
+/// It is resolved again for every connection, so DNS changes are picked up,
+/// and the address that accepted the last connection is tried first.
+#[derive(Debug)]
+pub struct Endpoint {
+    address: String,
+    // Адрес, принявший последнее соединение
+    last: Mutex<Option<SocketAddr>>,
+}
+
+impl Endpoint {
+    pub fn new(address: &str) -> Self {
+        Endpoint {
+            address: address.to_string(),
+            last: Mutex::new(None),
+        }
+    }
+
+    /// The address as given, for messages.
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Connects to one of the addresses the endpoint resolves to, IPv4 and
+    /// IPv6 alike, each attempt limited by `options.connect_timeout`.
+    pub fn connect(&self, options: &SocketOptions) -> io::Result<TcpStream> {
+        let mut addresses: Vec<SocketAddr> = self.address.to_socket_addrs()?.collect();
+        let last = *self.last.lock().unwrap();
+        if let Some(position) = addresses.iter().position(|address| Some(*address) == last) {
+            addresses[..=position].rotate_right(1);
+        }
+        let (stream, address) = options.connect_any(&addresses)?;
+        *self.last.lock().unwrap() = Some(address);
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options() {
+        let options = SocketOptions {
+            nodelay: true,
+            keepalive: Some(Duration::from_secs(30)),
+            reuse_address: true,
+            connect_timeout: None,
+        };
+        let listener = options.bind("127.0.0.1:0").unwrap();
+        assert!(SockRef::from(&listener).reuse_address().unwrap());
+        let stream = options.connect(listener.local_addr().unwrap()).unwrap();
+        assert!(stream.nodelay().unwrap());
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(Duration::from_secs(30), socket.keepalive_time().unwrap());
+
+        let stream = SocketOptions::default()
+            .connect(listener.local_addr().unwrap())
+            .unwrap();
+        assert!(!stream.nodelay().unwrap());
+        assert!(!SockRef::from(&stream).keepalive().unwrap());
+    }
+
+    #[test]
+    fn endpoint_tries_every_address() {
+        let listener = SocketOptions::default().bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Закрытый порт пропускается, соединение доходит до следующего адреса
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let good: SocketAddr = ([127, 0, 0, 1], port).into();
+        let options = SocketOptions {
+            connect_timeout: Some(Duration::from_secs(1)),
+            ..SocketOptions::default()
+        };
+        let (_, address) = options.connect_any(&[closed, good]).unwrap();
+        assert_eq!(good, address);
+        assert!(options.connect_any(&[closed]).is_err());
+
+        // localhost может разрешаться и в ::1, где никто не слушает
+        let endpoint = Endpoint::new(&format!("localhost:{}", port));
+        endpoint.connect(&options).unwrap();
+        assert_eq!(Some(good), *endpoint.last.lock().unwrap());
+        assert!(Endpoint::new("no-such-host.invalid:1")
+            .connect(&options)
+            .is_err());
+    }
+}
@@ -0,0 +1,192 @@
+//! Versioned representation of the protocol enums in text formats.
+//!
+//! A variant is written as `{"type": "Transfer", "v": 1, "data": {...}}`;
+//! unit variants have no `data`. Unknown fields inside `data` are ignored, so
+//! fields can be added without a new version. Messages in the older
+//! `{"Transfer": {...}}` form are still accepted.
+//!
+//! Binary formats keep the positional representation derived by serde.
+
+use std::cell::Cell;
+
+/// Version written into every message; a peer rejects newer versions.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// How deep the protocol enums may nest in one message, e.g. a command in
+/// `WithDeadline` in `AsIdentity`. Decoding is recursive, so a message
+/// nesting deeper would overflow the stack of the thread decoding it.
+pub const MAX_NESTING: usize = 16;
+
+thread_local! {
+    // Сколько перечислений протокола сейчас декодируется на этом потоке
+    static NESTING: Cell<usize> = const { Cell::new(0) };
+}
+
+/// One level of a protocol enum being decoded; leaving it drops the level.
+pub(crate) struct Nesting;
+
+impl Nesting {
+    pub(crate) fn enter<E: serde::de::Error>() -> Result<Nesting, E> {
+        NESTING.with(|nesting| {
+            if nesting.get() >= MAX_NESTING {
+                return Err(E::custom(format!(
+                    "message nests deeper than {} levels",
+                    MAX_NESTING
+                )));
+            }
+            nesting.set(nesting.get() + 1);
+            Ok(Nesting)
+        })
+    }
+}
+
+impl Drop for Nesting {
+    fn drop(&mut self) {
+        NESTING.with(|nesting| nesting.set(nesting.get() - 1));
+    }
+}
+
+/// Implements `Serialize`/`Deserialize` for enums that derive them with
+/// `#[serde(remote = "Self")]`, which leaves the derived code callable as
+/// inherent `serialize`/`deserialize` functions.
+macro_rules! versioned_serde {
+    ($($name:ident),* $(,)?) => {$(
+        impl serde::Serialize for $name {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                #[cfg(feature = "json")]
+                if serializer.is_human_readable() {
+                    use serde::ser::Error;
+                    let external = $name::serialize(self, serde_json::value::Serializer)
+                        .map_err(S::Error::custom)?;
+                    return $crate::versioned::tag(external).serialize(serializer);
+                }
+                $name::serialize(self, serializer)
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $name {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let _nesting = $crate::versioned::Nesting::enter()?;
+                #[cfg(feature = "json")]
+                if deserializer.is_human_readable() {
+                    use serde::de::Error;
+                    let value = serde_json::Value::deserialize(deserializer)?;
+                    let external = $crate::versioned::untag(value).map_err(D::Error::custom)?;
+                    return $name::deserialize(external).map_err(D::Error::custom);
+                }
+                $name::deserialize(deserializer)
+            }
+        }
+    )*};
+}
+
+pub(crate) use versioned_serde;
+
+/// Turns the derived `{"Variant": data}` / `"Variant"` form into the tagged one.
+#[cfg(feature = "json")]
+pub(crate) fn tag(external: serde_json::Value) -> serde_json::Value {
+    use serde_json::{json, Value};
+
+    let (name, data) = match external {
+        Value::String(name) => (name, None),
+        Value::Object(map) if map.len() == 1 => {
+            let (name, data) = map.into_iter().next().unwrap();
+            (name, Some(data))
+        }
+        other => unreachable!("not an externally tagged enum: {}", other),
+    };
+    let mut tagged = json!({ "type": name, "v": PROTOCOL_VERSION });
+    if let Some(data) = data {
+        tagged["data"] = data;
+    }
+    tagged
+}
+
+/// Turns a tagged value back into the derived form; the older untagged form
+/// passes through unchanged.
+#[cfg(feature = "json")]
+pub(crate) fn untag(value: serde_json::Value) -> Result<serde_json::Value, String> {
+    use serde_json::{Map, Value};
+
+    let Value::Object(mut map) = value else {
+        return Ok(value);
+    };
+    let Some(Value::String(name)) = map.remove("type") else {
+        return Ok(Value::Object(map));
+    };
+    let version = match map.get("v") {
+        Some(v) => v.as_u64().ok_or("protocol version is not a number")?,
+        None => return Err("protocol version is missing".to_string()),
+    };
+    if version > PROTOCOL_VERSION as u64 {
+        return Err(format!(
+            "protocol version {} is newer than supported {}",
+            version, PROTOCOL_VERSION
+        ));
+    }
+    Ok(match map.remove("data") {
+        Some(data) => Value::Object(Map::from_iter([(name, data)])),
+        None => Value::String(name),
+    })
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use serde_json::json;
+
+    use crate::{AccountRef, BankError, Command, Operation, ResponsePayload};
+
+    #[test]
+    fn tagged_representation() {
+        let command = Command::Transfer {
+            from: "X".into(),
+            to: AccountRef::Id(1),
+            amount: 5,
+        };
+        assert_eq!(
+            json!({"type": "Transfer", "v": 1, "data": {"from": "X", "to": 1, "amount": 5}}),
+            serde_json::to_value(&command).unwrap()
+        );
+        assert_eq!(
+            json!({"type": "GetSnapshot", "v": 1}),
+            serde_json::to_value(Command::GetSnapshot).unwrap()
+        );
+        let command = Command::AsIdentity {
+            token: "t".to_string(),
+            command: Box::new(Command::Restore(vec![Operation::CreateAccount(
+                "X".to_string(),
+            )])),
+        };
+        let data = serde_json::to_string(&command).unwrap();
+        assert_eq!(command, serde_json::from_str(&data).unwrap());
+    }
+
+    #[test]
+    fn legacy_representation() {
+        let x: Command =
+            serde_json::from_str(r#"{"Transfer":{"from":"X","to":1,"amount":5}}"#).unwrap();
+        assert!(matches!(x, Command::Transfer { amount: 5, .. }));
+        let x: Command = serde_json::from_str(r#""GetSnapshot""#).unwrap();
+        assert_eq!(Command::GetSnapshot, x);
+        let x: Result<ResponsePayload, BankError> =
+            serde_json::from_str(r#"{"Err":{"InsufficientFunds":5}}"#).unwrap();
+        assert!(matches!(x, Err(BankError::InsufficientFunds(5))));
+    }
+
+    #[test]
+    fn unknown_fields_and_versions() {
+        let x: Command = serde_json::from_value(json!({
+            "type": "IncreaseAccount", "v": 1, "data": ["X", 3]
+        }))
+        .unwrap();
+        assert_eq!(Command::IncreaseAccount("X".into(), 3), x);
+        let x: Command = serde_json::from_value(json!({
+            "type": "GetStatement", "v": 1,
+            "data": {"account": "X", "from_ts": 0, "to_ts": 1, "currency": "EUR"}
+        }))
+        .unwrap();
+        assert!(matches!(x, Command::GetStatement { to_ts: 1, .. }));
+        let x = serde_json::from_value::<Command>(json!({"type": "GetHistory", "v": 2}));
+        assert!(x.is_err());
+    }
+}
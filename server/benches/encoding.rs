@@ -0,0 +1,94 @@
+//! Encoding and decoding of typical messages in every wire format, plus the
+//! rkyv archive for history pages. Run with `cargo bench -p server`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use protocol_crate::archive;
+use protocol_crate::codec::{Serializer, WireFormat};
+use protocol_crate::{Command, Operation, Response, ResponsePayload};
+
+const FORMATS: [WireFormat; 3] = [WireFormat::Json, WireFormat::Bincode, WireFormat::MsgPack];
+
+fn history(operations: usize) -> Vec<Operation> {
+    (0..operations)
+        .map(|i| match i % 3 {
+            0 => Operation::IncreaseAccount(format!("account-{}", i % 100), 10),
+            1 => Operation::DecreaseAccount(format!("account-{}", i % 100), 3),
+            _ => Operation::Transfer(
+                format!("account-{}", i % 100),
+                format!("account-{}", (i + 1) % 100),
+                5,
+            ),
+        })
+        .collect()
+}
+
+fn command(c: &mut Criterion) {
+    let command = Command::WithRequestId {
+        request_id: "18f2c3a41b2-1f3a-42".to_string(),
+        command: Box::new(Command::Transfer {
+            from: "Alice".into(),
+            to: "Bob/savings".into(),
+            amount: 100,
+        }),
+    };
+    let response: Response = Ok(ResponsePayload::OperationId(42));
+    let mut group = c.benchmark_group("command");
+    for format in FORMATS {
+        // Ответ в новый буфер и в буфер, переиспользуемый соединением
+        group.bench_function(BenchmarkId::new("encode_response", format.name()), |b| {
+            b.iter(|| format.encode(&response))
+        });
+        let mut buffer = Vec::new();
+        group.bench_function(
+            BenchmarkId::new("encode_response_into", format.name()),
+            |b| {
+                b.iter(|| {
+                    buffer.clear();
+                    format.encode_into(&response, &mut buffer);
+                })
+            },
+        );
+
+        let data = format.encode(&command);
+        group.bench_function(BenchmarkId::new("encode", format.name()), |b| {
+            b.iter(|| format.encode(&command))
+        });
+        group.bench_function(BenchmarkId::new("decode", format.name()), |b| {
+            b.iter(|| format.decode::<Command>(&data).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn history_page(c: &mut Criterion) {
+    let operations = history(10_000);
+    let response: Response = Ok(ResponsePayload::History(operations.clone()));
+    let mut group = c.benchmark_group("history");
+    group.sample_size(20);
+    for format in FORMATS {
+        let data = format.encode(&response);
+        group.bench_function(BenchmarkId::new("encode", format.name()), |b| {
+            b.iter(|| format.encode(&response))
+        });
+        group.bench_function(BenchmarkId::new("decode", format.name()), |b| {
+            b.iter(|| format.decode::<Response>(&data).unwrap())
+        });
+    }
+
+    let data = archive::encode_history(&operations);
+    group.bench_function(BenchmarkId::new("encode", "rkyv"), |b| {
+        b.iter(|| archive::encode_history(&operations))
+    });
+    group.bench_function(BenchmarkId::new("decode", "rkyv"), |b| {
+        b.iter(|| archive::decode_history(&data).unwrap())
+    });
+    // Только проверка и чтение на месте, без сборки операций
+    group.bench_function(BenchmarkId::new("access", "rkyv"), |b| {
+        b.iter(|| archive::with_history(&data, |history| history.len()).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, command, history_page);
+criterion_main!(benches);
@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+
+use protocol_crate::AccountId;
+
+/// Operations in a block of the history. The account index and the hash
+/// checkpoints of a bank both go block by block.
+pub const BLOCK_SIZE: usize = 1024;
+
+/// Blocks of the history with operations of each account. It takes memory
+/// per block an account was active in rather than per operation; the
+/// operations themselves are read back from the storage a block at a time.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct AccountIndex {
+    accounts: HashMap<AccountId, Blocks>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Blocks {
+    // Номера блоков по возрастанию, каждый один раз
+    blocks: Vec<usize>,
+    // Сколько всего операций счета в истории
+    count: u64,
+}
+
+impl AccountIndex {
+    /// Notes that operation `operation`, the newest in the history, is one
+    /// of `account`.
+    pub fn push(&mut self, account: AccountId, operation: usize) {
+        let entry = self.accounts.entry(account).or_default();
+        let block = operation / BLOCK_SIZE;
+        if entry.blocks.last() != Some(&block) {
+            entry.blocks.push(block);
+        }
+        entry.count += 1;
+    }
+
+    /// Number of operations of `account`.
+    pub fn count(&self, account: AccountId) -> u64 {
+        self.accounts.get(&account).map_or(0, |entry| entry.count)
+    }
+
+    /// Blocks with operations of `account`, oldest first.
+    pub fn blocks(&self, account: AccountId) -> &[usize] {
+        self.accounts
+            .get(&account)
+            .map_or(&[][..], |entry| entry.blocks.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks() {
+        let mut index = AccountIndex::default();
+        for operation in [0, 5, BLOCK_SIZE - 1, 3 * BLOCK_SIZE] {
+            index.push(7, operation);
+        }
+        index.push(8, 3 * BLOCK_SIZE + 1);
+
+        assert_eq!(&[0, 3], index.blocks(7));
+        assert_eq!(4, index.count(7));
+        assert_eq!(&[3], index.blocks(8));
+        assert!(index.blocks(9).is_empty());
+        assert_eq!(0, index.count(9));
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+use protocol_crate::Alert;
+
+use crate::bank::Bank;
+
+// Сколько ждать получателя оповещения
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Low-balance alerts. An alert goes off when the balance of an account
+/// falls below the threshold of its rule and stays active until the balance
+/// is back at the threshold or the rule is removed. Each alert that goes
+/// off is posted to the webhook of the config, if there is one.
+#[derive(Debug, Default)]
+pub struct Alerts {
+    // Сработавшие оповещения по имени счета
+    active: BTreeMap<String, Alert>,
+}
+
+impl Alerts {
+    /// Compares the balances with the alert rules after a change of the
+    /// bank and posts the alerts that went off to `webhook`.
+    pub fn check(&mut self, bank: &Bank, webhook: Option<&str>) {
+        let rules = bank.alert_rules();
+        self.active
+            .retain(|account, _| rules.iter().any(|(name, ..)| name == account));
+        for (account, below, balance) in rules {
+            if balance >= below {
+                self.active.remove(account);
+                continue;
+            }
+            // Оповещение уже сработало; порог могли сменить
+            if let Some(alert) = self.active.get_mut(account) {
+                alert.below = below;
+                alert.balance = balance;
+                continue;
+            }
+            let alert = Alert {
+                account: account.to_string(),
+                below,
+                balance,
+                since: bank.now(),
+            };
+            if let Some(url) = webhook {
+                notify(url, &alert);
+            }
+            self.active.insert(alert.account.clone(), alert);
+        }
+    }
+
+    /// Moves the alerts of renamed accounts to their new names, so that
+    /// they do not go off again.
+    pub fn rename(&mut self, renames: &HashMap<String, String>) {
+        for (old, new) in renames {
+            if let Some(mut alert) = self.active.remove(old) {
+                alert.account = new.clone();
+                self.active.insert(new.clone(), alert);
+            }
+        }
+    }
+
+    pub fn active(&self) -> Vec<Alert> {
+        self.active.values().cloned().collect()
+    }
+}
+
+/// Posts `alert` as JSON to the http:// `url` from a thread of its own, so
+/// a slow receiver does not hold up the requests.
+fn notify(url: &str, alert: &Alert) {
+    let (url, body) = (url.to_string(), serde_json::to_string(alert).unwrap());
+    thread::spawn(move || {
+        if let Err(e) = post(&url, &body) {
+            eprintln!("Failed to post alert to {}: {}", url, e);
+        }
+    });
+}
+
+fn post(url: &str, body: &str) -> io::Result<()> {
+    // TLS не поддерживается, как и у экспорта трассировок
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| io::Error::other("only http:// webhooks are supported"))?;
+    let (host, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    let address = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    let address = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::other("the host has no addresses"))?;
+    let mut stream = TcpStream::connect_timeout(&address, WEBHOOK_TIMEOUT)?;
+    stream.set_read_timeout(Some(WEBHOOK_TIMEOUT))?;
+    stream.set_write_timeout(Some(WEBHOOK_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        body.len(),
+        body
+    )?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or_default();
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!("webhook answered {:?}", status))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn low_balance() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.increase_account("X", 10);
+        bank.set_alert_rule("X", Some(5)).unwrap();
+        let mut alerts = Alerts::default();
+        alerts.check(&bank, None);
+        assert!(alerts.active().is_empty());
+
+        let _ = bank.decrease_account("X", 7);
+        alerts.check(&bank, None);
+        let since = alerts.active()[0].since;
+        // Пока баланс не вернулся, оповещение то же самое
+        let _ = bank.decrease_account("X", 1);
+        alerts.check(&bank, None);
+        assert_eq!(
+            vec![Alert {
+                account: "X".to_string(),
+                below: 5,
+                balance: 2,
+                since,
+            }],
+            alerts.active()
+        );
+
+        let _ = bank.increase_account("X", 3);
+        alerts.check(&bank, None);
+        assert!(alerts.active().is_empty());
+        let _ = bank.decrease_account("X", 1);
+        alerts.check(&bank, None);
+        assert_eq!(1, alerts.active().len());
+        bank.set_alert_rule("X", None).unwrap();
+        alerts.check(&bank, None);
+        assert!(alerts.active().is_empty());
+    }
+}
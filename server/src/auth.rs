@@ -0,0 +1,470 @@
+use protocol_crate::{AccountRef, BankError, BatchOperation, Command, ReservationKind};
+
+use crate::bank::Bank;
+use crate::config::Role;
+use crate::locks::Locks;
+
+/// Lowest role that may run `command`. Commands carrying an admin token are
+/// authorized by the token and need no role.
+#[allow(deprecated)]
+fn required_role(command: &Command) -> Option<Role> {
+    match command {
+        Command::GetHistory
+        | Command::SearchHistory { .. }
+        | Command::QueryHistory { .. }
+        | Command::GetHistoryPage { .. }
+        | Command::GetHistoryPaged { .. }
+        | Command::GetHistoryArchive { .. }
+        | Command::GetAccountBalance(_)
+        | Command::GetVersionedBalance(_)
+        | Command::GetBalanceAt { .. }
+        | Command::GetAccountHistory(_)
+        | Command::GetAccountHistoryPaged { .. }
+        | Command::GetProjectedHistory { .. }
+        | Command::GetStatement { .. }
+        | Command::GetSnapshot
+        | Command::GetSnapshotChunk { .. }
+        | Command::GetAccountLimits(_)
+        | Command::GetAccountMetadata(_)
+        | Command::GetAccountOwners(_)
+        | Command::GetSubtreeBalance(_)
+        | Command::GetAccountTags(_)
+        | Command::FindAccountsByTag(_)
+        | Command::FindAccounts { .. }
+        | Command::GetTopAccounts(_)
+        | Command::ListAccounts(_)
+        | Command::ListAccountsPaged { .. }
+        | Command::GetHistoryDigest { .. }
+        | Command::GetShardMap
+        | Command::ListAlerts
+        | Command::ListDisputes { .. }
+        | Command::Subscribe { .. } => Some(Role::ReadOnly),
+        // Команды участника перевода приходят от других серверов
+        Command::CreateAccount(_)
+        | Command::IncreaseAccount(..)
+        | Command::DecreaseAccount(..)
+        | Command::DecreaseIfBalanceAtLeast { .. }
+        | Command::Transfer { .. }
+        | Command::TransferIf { .. }
+        | Command::RemoteTransfer { .. }
+        | Command::Reserve { .. }
+        | Command::CommitReservation(_)
+        | Command::ReleaseReservation(_)
+        | Command::LockAccount { .. }
+        | Command::LockAccounts { .. }
+        | Command::UnlockAccount(_)
+        | Command::Transaction(_)
+        | Command::Batch(_)
+        | Command::BulkTransfer { .. }
+        | Command::Prepare { .. }
+        | Command::Commit(_)
+        | Command::Abort(_)
+        | Command::BeginTransaction
+        | Command::CommitTransaction
+        | Command::RollbackTransaction
+        | Command::SetAccountMetadata { .. }
+        | Command::SetAlertRule { .. }
+        | Command::SetAccountOwners { .. }
+        | Command::CloseAccount(_)
+        | Command::ProposeTransfer { .. }
+        | Command::ApproveProposal(_)
+        | Command::OpenDispute { .. } => Some(Role::Teller),
+        Command::Restore(_) => Some(Role::Admin),
+        // Без рукопожатия клиент не знает, как говорить с сервером
+        Command::Handshake => None,
+        Command::Reload { .. }
+        | Command::GetMetrics { .. }
+        | Command::GetStats { .. }
+        | Command::SetAccountLimits { .. }
+        | Command::SetAccountTags { .. }
+        | Command::CheckConsistency { .. }
+        | Command::SetMaintenance { .. }
+        | Command::WriteSnapshot { .. }
+        | Command::AddToken { .. }
+        | Command::RevokeToken { .. }
+        | Command::ListTokens { .. }
+        | Command::SetRateLimit { .. }
+        | Command::GetRateLimits { .. }
+        | Command::ListJobs { .. }
+        | Command::ApproveTransfer { .. }
+        | Command::RejectTransfer { .. }
+        | Command::ListPendingTransfers { .. }
+        | Command::ResolveDispute { .. }
+        | Command::SetRequiredApprovals { .. }
+        | Command::AnonymizeAccount { .. } => None,
+        Command::AsIdentity { command, .. }
+        | Command::WithRequestId { command, .. }
+        | Command::WithDeadline { command, .. }
+        | Command::WithPosition { command, .. }
+        | Command::WithLock { command, .. } => required_role(command),
+    }
+}
+
+/// Whether `command` changes the bank.
+#[allow(deprecated)]
+pub fn mutates(command: &Command) -> bool {
+    match command {
+        Command::CreateAccount(_)
+        | Command::IncreaseAccount(..)
+        | Command::DecreaseAccount(..)
+        | Command::DecreaseIfBalanceAtLeast { .. }
+        | Command::Transfer { .. }
+        | Command::TransferIf { .. }
+        | Command::Restore(_)
+        | Command::RemoteTransfer { .. }
+        | Command::Reserve { .. }
+        | Command::CommitReservation(_)
+        | Command::ReleaseReservation(_)
+        | Command::LockAccount { .. }
+        | Command::LockAccounts { .. }
+        | Command::UnlockAccount(_)
+        | Command::Transaction(_)
+        | Command::Batch(_)
+        | Command::BulkTransfer { .. }
+        | Command::Prepare { .. }
+        | Command::Commit(_)
+        | Command::Abort(_)
+        | Command::BeginTransaction
+        | Command::CommitTransaction
+        | Command::SetAccountLimits { .. }
+        | Command::SetAccountMetadata { .. }
+        | Command::SetAlertRule { .. }
+        | Command::SetAccountOwners { .. }
+        | Command::SetAccountTags { .. }
+        | Command::ApproveTransfer { .. }
+        | Command::RejectTransfer { .. }
+        | Command::CloseAccount(_)
+        | Command::OpenDispute { .. }
+        | Command::ResolveDispute { .. }
+        | Command::SetRequiredApprovals { .. }
+        | Command::ProposeTransfer { .. }
+        | Command::ApproveProposal(_)
+        | Command::AnonymizeAccount { .. } => true,
+        Command::GetHistory
+        | Command::SearchHistory { .. }
+        | Command::QueryHistory { .. }
+        | Command::GetHistoryPage { .. }
+        | Command::GetHistoryPaged { .. }
+        | Command::GetHistoryArchive { .. }
+        | Command::GetAccountBalance(_)
+        | Command::GetVersionedBalance(_)
+        | Command::GetBalanceAt { .. }
+        | Command::GetAccountHistory(_)
+        | Command::GetAccountHistoryPaged { .. }
+        | Command::GetProjectedHistory { .. }
+        | Command::GetStatement { .. }
+        | Command::RollbackTransaction
+        | Command::Reload { .. }
+        | Command::GetMetrics { .. }
+        | Command::GetStats { .. }
+        | Command::GetSnapshot
+        | Command::GetSnapshotChunk { .. }
+        | Command::Handshake
+        | Command::GetShardMap
+        | Command::ListAlerts
+        | Command::GetAccountLimits(_)
+        | Command::GetAccountMetadata(_)
+        | Command::GetAccountOwners(_)
+        | Command::GetSubtreeBalance(_)
+        | Command::GetAccountTags(_)
+        | Command::FindAccountsByTag(_)
+        | Command::FindAccounts { .. }
+        | Command::GetTopAccounts(_)
+        | Command::GetHistoryDigest { .. }
+        | Command::CheckConsistency { .. }
+        | Command::SetMaintenance { .. }
+        | Command::WriteSnapshot { .. }
+        | Command::AddToken { .. }
+        | Command::RevokeToken { .. }
+        | Command::ListTokens { .. }
+        | Command::SetRateLimit { .. }
+        | Command::GetRateLimits { .. }
+        | Command::ListJobs { .. }
+        | Command::ListPendingTransfers { .. }
+        | Command::ListAccounts(_)
+        | Command::ListAccountsPaged { .. }
+        | Command::ListDisputes { .. }
+        | Command::Subscribe { .. } => false,
+        Command::AsIdentity { command, .. }
+        | Command::WithRequestId { command, .. }
+        | Command::WithDeadline { command, .. }
+        | Command::WithPosition { command, .. }
+        | Command::WithLock { command, .. } => mutates(command),
+    }
+}
+
+/// Whether `command` finishes a transfer or transaction that was already prepared.
+fn finishes_prepared(command: &Command) -> bool {
+    match command {
+        Command::AsIdentity { command, .. }
+        | Command::WithRequestId { command, .. }
+        | Command::WithDeadline { command, .. }
+        | Command::WithPosition { command, .. }
+        | Command::WithLock { command, .. } => finishes_prepared(command),
+        command => matches!(
+            command,
+            Command::CommitReservation(_)
+                | Command::ReleaseReservation(_)
+                | Command::UnlockAccount(_)
+                | Command::Commit(_)
+                | Command::Abort(_)
+                | Command::ApproveTransfer { .. }
+                | Command::RejectTransfer { .. }
+        ),
+    }
+}
+
+/// Rejects mutations in maintenance mode. Queries keep working, and so does
+/// finishing what was already prepared, so no funds stay held.
+pub fn check_maintenance(maintenance: bool, command: &Command) -> Result<(), BankError> {
+    if maintenance && mutates(command) && !finishes_prepared(command) {
+        Err(BankError::Maintenance)
+    } else {
+        Ok(())
+    }
+}
+
+/// Rejects mutations on a replica of `primary`; they have to go to the primary.
+pub fn check_primary(primary: Option<&str>, command: &Command) -> Result<(), BankError> {
+    match primary {
+        Some(primary) if mutates(command) => Err(BankError::NotPrimary(primary.to_string())),
+        _ => Ok(()),
+    }
+}
+
+/// Checks that `role` may run `command`; no role means no restrictions.
+pub fn check_role(role: Option<Role>, command: &Command) -> Result<(), BankError> {
+    match (role, required_role(command)) {
+        (Some(role), Some(required)) if role < required => Err(BankError::Forbidden(format!(
+            "{:?} role can not run {}",
+            role,
+            command.name()
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Checks that `caller` owns every local account `command` takes money from
+/// or modifies. Funds held or accounts locked by an owner are committed,
+/// released or unlocked by an owner too.
+pub fn check(
+    bank: &Bank,
+    locks: &Locks,
+    address: &str,
+    caller: Option<&str>,
+    command: &Command,
+) -> Result<(), BankError> {
+    let held: Vec<AccountRef> = match command {
+        Command::CommitReservation(id) | Command::ReleaseReservation(id) => bank
+            .debited_account(*id)
+            .map(AccountRef::Id)
+            .into_iter()
+            .collect(),
+        Command::UnlockAccount(lock) => locks
+            .accounts(*lock)
+            .into_iter()
+            .map(AccountRef::from)
+            .collect(),
+        _ => Vec::new(),
+    };
+    held.iter()
+        .try_for_each(|account| bank.check_owner(account, caller))?;
+
+    let accounts = match command {
+        Command::DecreaseAccount(account, _)
+        | Command::DecreaseIfBalanceAtLeast { account, .. }
+        | Command::Transfer { from: account, .. }
+        | Command::TransferIf { from: account, .. }
+        | Command::RemoteTransfer { from: account, .. }
+        | Command::SetAccountMetadata { account, .. }
+        | Command::SetAlertRule { account, .. }
+        | Command::SetAccountOwners { account, .. }
+        | Command::LockAccount { account, .. }
+        | Command::BulkTransfer { from: account, .. }
+        | Command::ProposeTransfer { from: account, .. }
+        | Command::Reserve {
+            account,
+            kind: ReservationKind::Debit,
+            ..
+        }
+        | Command::CloseAccount(account) => vec![account],
+        Command::Transaction(legs) => legs
+            .iter()
+            .filter(|leg| leg.kind == ReservationKind::Debit && leg.account.address == address)
+            .map(|leg| &leg.account.account)
+            .collect(),
+        // Участник держит у себя все ноги, которые ему прислали
+        Command::Prepare { legs, .. } => legs
+            .iter()
+            .filter(|leg| leg.kind == ReservationKind::Debit)
+            .map(|leg| &leg.account.account)
+            .collect(),
+        Command::Batch(operations) => operations
+            .iter()
+            .filter_map(|operation| match operation {
+                BatchOperation::Deposit { .. } => None,
+                BatchOperation::Withdraw { account, .. }
+                | BatchOperation::Transfer { from: account, .. } => Some(account),
+            })
+            .collect(),
+        Command::LockAccounts { accounts, .. } => accounts.iter().collect(),
+        _ => return Ok(()),
+    };
+
+    accounts
+        .into_iter()
+        .try_for_each(|account| bank.check_owner(account, caller))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use protocol_crate::{PageRequest, RemoteAccount, TransactionId, TransactionLeg};
+
+    #[test]
+    fn permission_matrix() {
+        let read = Command::GetAccountBalance("X".into());
+        let write = Command::IncreaseAccount("X".into(), 1);
+        let restore = Command::Restore(Vec::new());
+        let reload = Command::Reload {
+            token: String::new(),
+        };
+
+        for command in [&read, &write, &restore, &reload] {
+            assert!(check_role(None, command).is_ok());
+            assert!(check_role(Some(Role::Admin), command).is_ok());
+        }
+        assert!(check_role(Some(Role::ReadOnly), &read).is_ok());
+        assert!(matches!(
+            check_role(Some(Role::ReadOnly), &write),
+            Err(BankError::Forbidden(_))
+        ));
+        assert!(check_role(Some(Role::Teller), &write).is_ok());
+        assert!(check_role(Some(Role::Teller), &restore).is_err());
+        assert!(check_role(Some(Role::ReadOnly), &reload).is_ok());
+    }
+
+    #[test]
+    fn owned_debits() {
+        let mut bank = Bank::default();
+        for account in ["X", "Y"] {
+            bank.create_account(account.to_string()).unwrap();
+        }
+        bank.set_account_owners("X", ["alice".to_string()].into())
+            .unwrap();
+        let mut locks = Locks::default();
+        let remote = |account: &str| RemoteAccount {
+            address: "remote:7878".to_string(),
+            account: account.into(),
+        };
+        let reserve = |kind| Command::Reserve {
+            account: "X".into(),
+            amount: 1,
+            kind,
+            counterparty: remote("Z"),
+        };
+        let prepare = |kind| Command::Prepare {
+            transaction: TransactionId {
+                coordinator: "remote:7878".to_string(),
+                number: 0,
+            },
+            legs: vec![TransactionLeg {
+                account: remote("X"),
+                kind,
+                amount: 1,
+            }],
+        };
+
+        for command in [
+            reserve(ReservationKind::Debit),
+            prepare(ReservationKind::Debit),
+        ] {
+            assert!(check(&bank, &locks, "local:7878", Some("alice"), &command).is_ok());
+            for caller in [None, Some("bob")] {
+                assert!(matches!(
+                    check(&bank, &locks, "local:7878", caller, &command),
+                    Err(BankError::Forbidden(_))
+                ));
+            }
+        }
+        // Зачислять на чужой счет можно
+        for command in [
+            reserve(ReservationKind::Credit),
+            prepare(ReservationKind::Credit),
+        ] {
+            assert!(check(&bank, &locks, "local:7878", None, &command).is_ok());
+        }
+
+        // Удержание и блокировку владельца снимает тоже владелец
+        let _ = bank.increase_account("X", 2);
+        let debit = bank
+            .reserve("X", 1, ReservationKind::Debit, remote("Z"))
+            .unwrap();
+        let credit = bank
+            .reserve("X", 1, ReservationKind::Credit, remote("Z"))
+            .unwrap();
+        let lock = locks.lock(&["X", "Y"], Duration::from_secs(10)).unwrap();
+        for command in [
+            Command::CommitReservation(debit),
+            Command::ReleaseReservation(debit),
+            Command::UnlockAccount(lock),
+        ] {
+            assert!(check(&bank, &locks, "local:7878", Some("alice"), &command).is_ok());
+            for caller in [None, Some("bob")] {
+                assert!(matches!(
+                    check(&bank, &locks, "local:7878", caller, &command),
+                    Err(BankError::Forbidden(_))
+                ));
+            }
+        }
+        for command in [
+            Command::CommitReservation(credit),
+            Command::ReleaseReservation(credit),
+        ] {
+            assert!(check(&bank, &locks, "local:7878", None, &command).is_ok());
+        }
+    }
+
+    #[test]
+    fn maintenance() {
+        let read = Command::GetAccountBalance("X".into());
+        let write = Command::AsIdentity {
+            token: String::new(),
+            command: Box::new(Command::IncreaseAccount("X".into(), 1)),
+        };
+        let commit = Command::Commit(TransactionId {
+            coordinator: String::new(),
+            number: 0,
+        });
+
+        for command in [&read, &write, &commit] {
+            assert!(check_maintenance(false, command).is_ok());
+        }
+        assert!(check_maintenance(true, &read).is_ok());
+        assert!(check_maintenance(true, &commit).is_ok());
+        assert!(matches!(
+            check_maintenance(true, &write),
+            Err(BankError::Maintenance)
+        ));
+    }
+
+    #[test]
+    fn replica() {
+        let read = Command::GetAccountHistoryPaged {
+            account: "X".into(),
+            page: PageRequest::first(10),
+        };
+        let commit = Command::Commit(TransactionId {
+            coordinator: String::new(),
+            number: 0,
+        });
+        assert!(check_primary(None, &commit).is_ok());
+        assert!(check_primary(Some("primary:7878"), &read).is_ok());
+        assert!(matches!(
+            check_primary(Some("primary:7878"), &commit),
+            Err(BankError::NotPrimary(primary)) if primary == "primary:7878"
+        ));
+    }
+}
@@ -1,22 +1,129 @@
+use protocol_crate::digest::{chain, from_hex, to_hex, Hash, GENESIS};
+use protocol_crate::{
+    is_valid_account_name, parent_account, validate_history_from, AccountFilter, AccountId,
+    AccountLimits, AccountRef, BankError, BatchOperation, ConsistencyReport, Cursor, Dispute,
+    DisputeId, DisputeResolution, HistoryDigest, OpenProposal, OpenReservation, Operation, Page,
+    PageRequest, PendingTransfer, PendingTransferId, ProposalId, ProposalStatus, QueryRow,
+    RemoteAccount, ReservationId, ReservationKind, ReservationSource, RestoreProgress, ServerStats,
+    Snapshot, Statement, StatementLine, TransactionId, TransactionLeg, VelocityRule,
+    VersionedBalance,
+};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
-use protocol_crate::{Operation, BankError};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-type OperationId = usize;
+use crate::account_index::{AccountIndex, BLOCK_SIZE};
+use crate::clock::{Clock, SystemClock};
+use crate::history::FrozenHistory;
+use crate::query::Query;
+use crate::storage::{self, AccountDetails, BankStorage, MemoryStorage};
+use crate::velocity::VelocityTracker;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+// Как часто длинные операции проверяют, не отменен ли запрос
+const CANCEL_CHECK_INTERVAL: usize = 1000;
+/// How many operations a restore applies between progress reports.
+pub const RESTORE_PROGRESS_INTERVAL: usize = 1000;
+// Счет без имени: строки в списке и в индексе по имени, баланс, блоки и
+// число операций в индексе истории счета
+const ACCOUNT_BYTES: usize = 2 * mem::size_of::<String>()
+    + mem::size_of::<AccountId>()
+    + mem::size_of::<u32>()
+    + mem::size_of::<Vec<usize>>()
+    + mem::size_of::<u64>();
+
+/// Transfer out of an account that needs several approvals, with the
+/// identities that have approved it so far.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Proposal {
+    from: AccountId,
+    to: AccountId,
+    amount: u32,
+    approvals: BTreeSet<String>,
+}
+
+/// Local transfer waiting for an admin; its amount is held on `from`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pending {
+    from: AccountId,
+    to: AccountId,
+    amount: u32,
+    requested_at: u64,
+}
+
+/// Money movement agreed with a remote bank but not applied yet.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reservation {
+    account: AccountId,
+    amount: u32,
+    kind: ReservationKind,
+    source: ReservationSource,
+}
 
 #[derive(Debug)]
 pub struct Bank {
-    // Счета
-    accounts: HashSet<String>,
-    // Балансы
-    balances: HashMap<String, u32>,
-    // История счета
-    account_operations_index: HashMap<String, Vec<OperationId>>,
-    // История
-    history: Vec<Operation>,
+    // Счета, балансы и история
+    storage: Box<dyn BankStorage>,
+    // Дочерние счета (Alice -> Alice/savings)
+    children: HashMap<AccountId, Vec<AccountId>>,
+    // Закрытые счета: история остается, новые операции не принимаются
+    closed: HashSet<AccountId>,
+    // Блоки истории с операциями каждого счета
+    account_index: AccountIndex,
+    // Хеш цепочки после каждого полного блока истории и после всей истории
+    checkpoints: Vec<Hash>,
+    head: Hash,
+    // Незавершенные межбанковские переводы
+    reservations: HashMap<ReservationId, Reservation>,
+    next_reservation_id: ReservationId,
+    // Средства, заблокированные под списание
+    held: HashMap<AccountId, u32>,
+    // Средства, ждущие зачисления по резервированиям
+    incoming: HashMap<AccountId, u64>,
+    // Подготовленные (prepared) части распределенных транзакций
+    prepared: HashMap<TransactionId, Vec<ReservationId>>,
+    // Транзакции, части которых уже в истории
+    committed: HashSet<TransactionId>,
+    // Переводы, ждущие одобрения
+    pending: BTreeMap<PendingTransferId, Pending>,
+    next_pending_id: PendingTransferId,
+    // Сколько одобрений нужно для переводов со счета
+    required_approvals: HashMap<AccountId, u32>,
+    // Предложенные переводы, ждущие одобрений
+    proposals: BTreeMap<ProposalId, Proposal>,
+    next_proposal_id: ProposalId,
+    // Идет перевод по предложению, собравшему одобрения
+    approved: bool,
+    // Порог оповещения о низком балансе
+    alert_rules: HashMap<AccountId, u32>,
+    // Споры по операциям истории, открытые и закрытые
+    disputes: BTreeMap<DisputeId, Dispute>,
+    // Лимиты на списания
+    limits: HashMap<AccountId, AccountLimits>,
+    // Метаданные счетов (ключ -> значение)
+    metadata: HashMap<AccountId, BTreeMap<String, String>>,
+    // Владельцы счетов; счет без владельцев доступен всем
+    owners: HashMap<AccountId, BTreeSet<String>>,
+    // Теги счетов (test, internal, customer, ...)
+    tags: HashMap<AccountId, BTreeSet<String>>,
+    // Правила скорости списаний для всех счетов (из конфига сервера)
+    velocity_rules: Vec<VelocityRule>,
+    // Списания в скользящем окне по счетам
+    outflows: HashMap<AccountId, VelocityTracker>,
+    // При восстановлении истории лимиты уже были проверены в момент операций
+    enforce_limits: bool,
+    // Больше счетов и операций истории банк не принимает
+    max_accounts: Option<usize>,
+    max_history: Option<usize>,
+    // Оценка памяти истории, считается по мере добавления операций
+    history_bytes: u64,
+    // Источник времени для меток операций и лимитов
+    clock: Arc<dyn Clock>,
 }
 
-
 impl Default for Bank {
     fn default() -> Self {
         Self::new()
@@ -26,462 +133,3979 @@ impl Default for Bank {
 impl Bank {
     pub fn new() -> Self {
         Bank {
-            accounts: HashSet::new(),
-            balances: HashMap::new(),
-            account_operations_index: HashMap::new(),
-            history: Vec::new(),
+            storage: Box::new(MemoryStorage::default()),
+            children: HashMap::new(),
+            closed: HashSet::new(),
+            account_index: AccountIndex::default(),
+            checkpoints: Vec::new(),
+            head: GENESIS,
+            reservations: HashMap::new(),
+            next_reservation_id: 0,
+            held: HashMap::new(),
+            incoming: HashMap::new(),
+            prepared: HashMap::new(),
+            committed: HashSet::new(),
+            pending: BTreeMap::new(),
+            next_pending_id: 0,
+            required_approvals: HashMap::new(),
+            proposals: BTreeMap::new(),
+            next_proposal_id: 0,
+            approved: false,
+            alert_rules: HashMap::new(),
+            disputes: BTreeMap::new(),
+            limits: HashMap::new(),
+            metadata: HashMap::new(),
+            owners: HashMap::new(),
+            tags: HashMap::new(),
+            velocity_rules: Vec::new(),
+            outflows: HashMap::new(),
+            enforce_limits: true,
+            max_accounts: None,
+            max_history: None,
+            history_bytes: 0,
+            clock: Arc::new(SystemClock),
         }
     }
 
-    pub fn get_account_balance(&self, account: String) -> Result<u32, BankError> {
-        if !self.accounts.contains(&account) {
-            return Err(BankError::AccountAlreadyExists(format!(
-                "Account {} does not exist",
-                account
-            )));
-        }
-        let balance = *self.balances.get(&account).unwrap();
-        Ok(balance)
+    pub fn get_account_balance(&self, account: impl Into<AccountRef>) -> Result<u32, BankError> {
+        let id = self.resolve_account(&account.into())?;
+        Ok(self.storage.balance(id))
     }
 
-    pub fn create_account(&mut self, account: String) -> Result<usize, BankError> {
-        if !self.accounts.contains(&account) {
-            self.accounts.insert(account.clone());
-            self.balances.insert(account.clone(), 0);
-            let id = self.append_history(Operation::CreateAccount(account.clone()));
-            self.append_account_index(account, id);
-            Ok(id)
+    /// Balance of `account` less the funds held by reservations and
+    /// pending transfers, i.e. what can be debited now.
+    pub fn get_available_balance(&self, account: impl Into<AccountRef>) -> Result<u32, BankError> {
+        let id = self.resolve_account(&account.into())?;
+        Ok(self.available_balance(id))
+    }
+
+    /// Balance `account` had before operation `operation_id`, after all the
+    /// earlier ones; an ID past the end of the history gives the current one.
+    pub fn get_balance_at(
+        &self,
+        account: impl Into<AccountRef>,
+        operation_id: usize,
+    ) -> Result<u32, BankError> {
+        let id = self.resolve_account(&account.into())?;
+        let name = self.storage.account_name(id);
+        let blocks = self.account_index.blocks(id);
+        // Пересчитываем с той стороны, где блоков счета меньше: от открытия
+        // счета вперед или от текущего баланса назад
+        let split = blocks.partition_point(|block| block * BLOCK_SIZE < operation_id);
+        let balance = if split <= blocks.len() - split {
+            self.account_operations(id, 0)
+                .take_while(|(index, _, _)| *index < operation_id)
+                .map(|(_, _, operation)| operation.balance_change(name))
+                .sum()
         } else {
-            Err(BankError::AccountAlreadyExists(format!(
+            let later: i64 = self
+                .account_operations_rev(id)
+                .take_while(|(index, _, _)| *index >= operation_id)
+                .map(|(_, _, operation)| operation.balance_change(name))
+                .sum();
+            self.storage.balance(id) as i64 - later
+        };
+        Ok(balance as u32)
+    }
+
+    pub fn get_versioned_balance(
+        &self,
+        account: impl Into<AccountRef>,
+    ) -> Result<VersionedBalance, BankError> {
+        let id = self.resolve_account(&account.into())?;
+        Ok(VersionedBalance {
+            balance: self.storage.balance(id),
+            version: self.account_version(id),
+        })
+    }
+
+    /// Sum of the balances of `account` and all of its sub-accounts.
+    pub fn get_subtree_balance(&self, account: impl Into<AccountRef>) -> Result<u64, BankError> {
+        let mut pending = vec![self.resolve_account(&account.into())?];
+        let mut total = 0;
+        while let Some(id) = pending.pop() {
+            total += self.storage.balance(id) as u64;
+            pending.extend(self.children.get(&id).into_iter().flatten());
+        }
+        Ok(total)
+    }
+
+    /// Creates an account. A name like `Alice/savings` creates a sub-account
+    /// of `Alice`, which must already exist.
+    pub fn create_account(&mut self, account: String) -> Result<AccountId, BankError> {
+        if self.storage.account_id(&account).is_some() {
+            return Err(BankError::AccountAlreadyExists(format!(
                 "Account {} already exists",
                 account
-            )))
+            )));
+        }
+        if !is_valid_account_name(&account) {
+            return Err(BankError::InvalidAccountName(format!(
+                "Account name {} has an empty part",
+                account
+            )));
+        }
+        let parent = match parent_account(&account) {
+            Some(parent) => Some(self.resolve_open_account(&parent.into())?),
+            None => None,
+        };
+
+        self.check_capacity(1, 1)?;
+
+        let id = self.storage.add_account(&account);
+        if let Some(parent) = parent {
+            self.children.entry(parent).or_default().push(id);
         }
+        let operation_id = self.append_history(Operation::CreateAccount(account));
+        self.append_account_index(id, operation_id);
+        Ok(id)
     }
 
-    pub fn increase_account(&mut self, account: String, amount: u32) -> Result<usize, BankError> {
-        self.check_exists_account(account.clone())?;
+    pub fn increase_account(
+        &mut self,
+        account: impl Into<AccountRef>,
+        amount: u32,
+    ) -> Result<usize, BankError> {
+        let id = self.resolve_open_account(&account.into())?;
         self.check_zero_amount(amount)?;
+        self.check_capacity(0, 1)?;
 
-        let current_balance = *self.balances.get(&account).unwrap();
-        let new_balance = current_balance + amount;
-        self.balances.insert(account.clone(), new_balance);
+        let current_balance = self.storage.balance(id);
+        let new_balance = self.credited_balance(id, current_balance, amount, 0)?;
+        self.storage.set_balance(id, new_balance);
 
-        let id = self.append_history(Operation::IncreaseAccount(account.clone(), amount));
-        self.append_account_index(account, id);
-        Ok(id)
+        let name = self.storage.account_name(id).to_string();
+        let operation_id = self.append_history(Operation::IncreaseAccount(name, amount));
+        self.append_account_index(id, operation_id);
+        Ok(operation_id)
     }
 
-    pub fn decrease_account(&mut self, account: String, amount: u32) -> Result<usize, BankError> {
-        self.check_exists_account(account.clone())?;
+    pub fn decrease_account(
+        &mut self,
+        account: impl Into<AccountRef>,
+        amount: u32,
+    ) -> Result<usize, BankError> {
+        let id = self.resolve_open_account(&account.into())?;
         self.check_zero_amount(amount)?;
 
-        let current_balance = *self.balances.get(&account).unwrap();
-        if current_balance < amount {
+        let current_balance = self.storage.balance(id);
+        if self.available_balance(id) < amount {
             return Err(BankError::InsufficientFunds(amount));
         }
+        self.check_outflow_limits(id, amount)?;
+        self.check_capacity(0, 1)?;
 
         let new_balance = current_balance - amount;
-        self.balances.insert(account.clone(), new_balance);
-        let id = self.append_history(Operation::DecreaseAccount(account.clone(), amount));
-        self.append_account_index(account, id);
-        Ok(id)
+        self.storage.set_balance(id, new_balance);
+        let name = self.storage.account_name(id).to_string();
+        let operation_id = self.append_history(Operation::DecreaseAccount(name, amount));
+        self.append_account_index(id, operation_id);
+        Ok(operation_id)
     }
 
-    pub fn transfer(&mut self, from: String, to: String, amount: u32) -> Result<(), BankError> {
+    /// Same as [`Bank::decrease_account`], but fails with
+    /// `BankError::BelowMinimumBalance` unless at least `min_after` stays
+    /// available afterwards.
+    pub fn decrease_if_balance_at_least(
+        &mut self,
+        account: impl Into<AccountRef>,
+        amount: u32,
+        min_after: u32,
+    ) -> Result<usize, BankError> {
+        let id = self.resolve_account(&account.into())?;
+        if (self.available_balance(id) as u64) < amount as u64 + min_after as u64 {
+            return Err(BankError::BelowMinimumBalance { min_after });
+        }
+        self.decrease_account(id, amount)
+    }
+
+    pub fn transfer(
+        &mut self,
+        from: impl Into<AccountRef>,
+        to: impl Into<AccountRef>,
+        amount: u32,
+    ) -> Result<(), BankError> {
+        let from = self.resolve_open_account(&from.into())?;
+        let to = self.resolve_open_account(&to.into())?;
         if from == to {
             return Err(BankError::TransferToMyself);
         }
-
-        self.check_exists_account(from.clone())?;
-        self.check_exists_account(to.clone())?;
         self.check_zero_amount(amount)?;
 
-        let current_balance_from = *self.balances.get(&from.clone()).unwrap();
-        if current_balance_from < amount {
+        let current_balance_from = self.storage.balance(from);
+        if self.available_balance(from) < amount {
             return Err(BankError::InsufficientFunds(amount));
         }
-        let new_balance_from = current_balance_from - amount;
-        self.balances.insert(from.clone(), new_balance_from);
+        self.check_outflow_limits(from, amount)?;
+        self.check_capacity(0, 1)?;
+        let current_balance_to = self.storage.balance(to);
+        let new_balance_to = self.credited_balance(to, current_balance_to, amount, 0)?;
 
-        let current_balance_to = *self.balances.get(&to.clone()).unwrap();
-        let new_balance_to = current_balance_to + amount;
-        self.balances.insert(to.clone(), new_balance_to);
+        let new_balance_from = current_balance_from - amount;
+        self.storage.set_balance(from, new_balance_from);
+        self.storage.set_balance(to, new_balance_to);
 
-        let id = self.append_history(Operation::Transfer(from.clone(), to.clone(), amount));
+        let operation_id = self.append_history(Operation::Transfer(
+            self.storage.account_name(from).to_string(),
+            self.storage.account_name(to).to_string(),
+            amount,
+        ));
 
-        self.append_account_index(from, id);
-        self.append_account_index(to, id);
+        self.append_account_index(from, operation_id);
+        self.append_account_index(to, operation_id);
         Ok(())
     }
 
-    pub fn get_history(&self) -> &Vec<Operation> {
-        &self.history
+    /// Applies `operations` in order. Every operation is checked before the
+    /// first one is applied, so either all of them are applied or none is.
+    ///
+    /// Returns the history operation ID of each operation.
+    pub fn batch(&mut self, operations: &[BatchOperation]) -> Result<Vec<usize>, BankError> {
+        // Списания пакета временно блокируются, чтобы лимиты учли их все
+        let held = self.held.clone();
+        let checked = self.check_batch(operations);
+        self.held = held;
+        checked?;
+        self.check_capacity(0, operations.len())?;
+
+        let mut operation_ids = Vec::with_capacity(operations.len());
+        for (index, operation) in operations.iter().enumerate() {
+            let operation_id = match operation {
+                BatchOperation::Deposit { account, amount } => {
+                    self.increase_account(account.clone(), *amount)
+                }
+                BatchOperation::Withdraw { account, amount } => {
+                    self.decrease_account(account.clone(), *amount)
+                }
+                BatchOperation::Transfer { from, to, amount } => self
+                    .transfer(from.clone(), to.clone(), *amount)
+                    .map(|()| self.storage.history_len() - 1),
+            };
+            let operation_id = operation_id.map_err(|error| BankError::BatchFailed {
+                index,
+                error: Box::new(error),
+            })?;
+            operation_ids.push(operation_id);
+        }
+        Ok(operation_ids)
     }
 
-    pub fn get_account_history(&self, account: String) -> Option<Vec<Operation>> {
-        self.account_operations_index
-            .get(&account)
-            .map(|vec| vec.iter().map(|id| self.history[*id].clone()).collect())
+    /// Pays each of `payees` from `from` with a transfer of its own, all of
+    /// them applied as one [`Bank::batch`]. A payee that does not exist, is
+    /// closed, is `from` itself or is paid nothing gets its error and is
+    /// left out of the total. If `from` can not cover the total or one of
+    /// the transfers fails, nothing is applied.
+    ///
+    /// Returns for each payee the history operation ID of its transfer, or
+    /// why it was not paid.
+    pub fn bulk_transfer(
+        &mut self,
+        from: &AccountRef,
+        payees: &[(AccountRef, u32)],
+    ) -> Result<Vec<Result<usize, BankError>>, BankError> {
+        let from = self.resolve_open_account(from)?;
+        let checked: Vec<Result<(AccountId, u32), BankError>> = payees
+            .iter()
+            .map(|(payee, amount)| {
+                let payee = self.resolve_open_account(payee)?;
+                if payee == from {
+                    return Err(BankError::TransferToMyself);
+                }
+                self.check_zero_amount(*amount)?;
+                Ok((payee, *amount))
+            })
+            .collect();
+        let total: u64 = checked
+            .iter()
+            .flatten()
+            .map(|(_, amount)| *amount as u64)
+            .sum();
+        if total > self.available_balance(from) as u64 {
+            // Больше u32 на счете не бывает
+            return Err(BankError::InsufficientFunds(
+                u32::try_from(total).unwrap_or(u32::MAX),
+            ));
+        }
+        let transfers: Vec<BatchOperation> = checked
+            .iter()
+            .flatten()
+            .map(|(payee, amount)| BatchOperation::Transfer {
+                from: from.into(),
+                to: (*payee).into(),
+                amount: *amount,
+            })
+            .collect();
+        // Выплата не удалась целиком: ее ошибка - ошибка того перевода
+        let mut operation_ids = self
+            .batch(&transfers)
+            .map_err(|e| match e {
+                BankError::BatchFailed { error, .. } => *error,
+                e => e,
+            })?
+            .into_iter();
+        Ok(checked
+            .into_iter()
+            .map(|payee| payee.map(|_| operation_ids.next().unwrap()))
+            .collect())
     }
 
-    pub fn restore(&mut self, history: &Vec<Operation>) {
-        for operation in history {
-            match operation {
-                Operation::CreateAccount(account) => {
-                    let _ = self.create_account(account.clone());
+    fn check_batch(&mut self, operations: &[BatchOperation]) -> Result<(), BankError> {
+        // Поступления пакета, которые могут покрыть следующие списания
+        let mut credits: HashMap<AccountId, i64> = HashMap::new();
+        for (index, operation) in operations.iter().enumerate() {
+            let mut check = || {
+                let (debit, credit, amount) = match operation {
+                    BatchOperation::Deposit { account, amount } => {
+                        (None, Some(self.resolve_open_account(account)?), *amount)
+                    }
+                    BatchOperation::Withdraw { account, amount } => {
+                        (Some(self.resolve_open_account(account)?), None, *amount)
+                    }
+                    BatchOperation::Transfer { from, to, amount } => {
+                        let from = self.resolve_open_account(from)?;
+                        let to = self.resolve_open_account(to)?;
+                        if from == to {
+                            return Err(BankError::TransferToMyself);
+                        }
+                        (Some(from), Some(to), *amount)
+                    }
+                };
+                self.check_zero_amount(amount)?;
+                if let Some(account) = debit {
+                    // Блокировка может превысить баланс за счет поступлений, поэтому
+                    // available_balance здесь не подходит
+                    let held = self.held.get(&account).copied().unwrap_or(0) as i64;
+                    let credited = credits.get(&account).copied().unwrap_or(0);
+                    if self.storage.balance(account) as i64 - held + credited < amount as i64 {
+                        return Err(BankError::InsufficientFunds(amount));
+                    }
+                    self.check_outflow_limits(account, amount)?;
+                    *self.held.entry(account).or_default() += amount;
                 }
-                Operation::IncreaseAccount(account, amount) => {
-                    let _ = self.increase_account(account.clone(), *amount);
+                if let Some(account) = credit {
+                    let credited = credits.entry(account).or_default();
+                    *credited += amount as i64;
+                    let incoming = self.incoming.get(&account).copied().unwrap_or(0) as i64;
+                    if self.storage.balance(account) as i64 + incoming + *credited > u32::MAX as i64
+                    {
+                        return Err(BankError::BalanceOverflow(
+                            self.storage.account_name(account).to_string(),
+                        ));
+                    }
                 }
-                Operation::DecreaseAccount(account, amount) => {
-                    let _ = self.decrease_account(account.clone(), *amount).unwrap();
+                Ok(())
+            };
+            check().map_err(|error| BankError::BatchFailed {
+                index,
+                error: Box::new(error),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Same as [`Bank::transfer`], but fails with `BankError::Conflict` unless
+    /// `from` is at `expected_version`.
+    pub fn transfer_if(
+        &mut self,
+        from: impl Into<AccountRef>,
+        to: impl Into<AccountRef>,
+        amount: u32,
+        expected_version: u64,
+    ) -> Result<(), BankError> {
+        let from = self.resolve_account(&from.into())?;
+        self.check_version(from, expected_version)?;
+        self.transfer(from, to, amount)
+    }
+
+    /// Fails with `BankError::Conflict` unless `account` is at
+    /// `expected_version`.
+    pub fn check_version(
+        &self,
+        account: impl Into<AccountRef>,
+        expected_version: u64,
+    ) -> Result<(), BankError> {
+        let account = self.resolve_account(&account.into())?;
+        let actual = self.account_version(account);
+        if actual != expected_version {
+            return Err(BankError::Conflict {
+                expected: expected_version,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    /// Holds `amount` on `from` for a transfer to `to` that is made only
+    /// once an admin approves it.
+    pub fn queue_transfer(
+        &mut self,
+        from: impl Into<AccountRef>,
+        to: impl Into<AccountRef>,
+        amount: u32,
+    ) -> Result<PendingTransferId, BankError> {
+        let from = self.resolve_open_account(&from.into())?;
+        let to = self.resolve_open_account(&to.into())?;
+        if from == to {
+            return Err(BankError::TransferToMyself);
+        }
+        self.check_zero_amount(amount)?;
+        if self.available_balance(from) < amount {
+            return Err(BankError::InsufficientFunds(amount));
+        }
+        self.check_outflow_limits(from, amount)?;
+        *self.held.entry(from).or_default() += amount;
+
+        let id = self.next_pending_id;
+        self.next_pending_id += 1;
+        let requested_at = self.clock.now();
+        self.pending.insert(
+            id,
+            Pending {
+                from,
+                to,
+                amount,
+                requested_at,
+            },
+        );
+        self.save_details();
+        Ok(id)
+    }
+
+    /// Makes the pending transfer `id`. If the transfer fails, e.g. on an
+    /// outflow limit, it stays pending.
+    pub fn approve_transfer(&mut self, id: PendingTransferId) -> Result<(), BankError> {
+        let pending = self.take_pending(id)?;
+        let result = self.transfer(pending.from, pending.to, pending.amount);
+        if result.is_err() {
+            *self.held.entry(pending.from).or_default() += pending.amount;
+            self.pending.insert(id, pending);
+        } else {
+            self.save_details();
+        }
+        result
+    }
+
+    /// Drops the pending transfer `id` and releases its funds.
+    pub fn reject_transfer(&mut self, id: PendingTransferId) -> Result<(), BankError> {
+        self.take_pending(id)?;
+        self.save_details();
+        Ok(())
+    }
+
+    /// Transfers waiting for approval, oldest first.
+    pub fn pending_transfers(&self) -> Vec<PendingTransfer> {
+        self.pending
+            .iter()
+            .map(|(id, pending)| PendingTransfer {
+                id: *id,
+                from: self.storage.account_name(pending.from).to_string(),
+                to: self.storage.account_name(pending.to).to_string(),
+                amount: pending.amount,
+                requested_at: pending.requested_at,
+            })
+            .collect()
+    }
+
+    /// Holds `amount` on `account` (for a debit) or checks that `account` can
+    /// receive it (for a credit) until the reservation is committed or released.
+    pub fn reserve(
+        &mut self,
+        account: impl Into<AccountRef>,
+        amount: u32,
+        kind: ReservationKind,
+        counterparty: RemoteAccount,
+    ) -> Result<ReservationId, BankError> {
+        let account = self.resolve_account(&account.into())?;
+        let id = self.hold(
+            account,
+            amount,
+            kind,
+            ReservationSource::Transfer(counterparty),
+        )?;
+        self.save_details();
+        Ok(id)
+    }
+
+    /// Reserves every leg of `transaction` that belongs to this bank. Either
+    /// all legs are reserved or none are.
+    pub fn prepare(
+        &mut self,
+        transaction: TransactionId,
+        legs: &[TransactionLeg],
+    ) -> Result<(), BankError> {
+        let mut reservations = Vec::new();
+        for leg in legs {
+            let reservation = self
+                .resolve_account(&leg.account.account)
+                .and_then(|account| {
+                    self.hold(
+                        account,
+                        leg.amount,
+                        leg.kind,
+                        ReservationSource::Transaction(transaction.clone()),
+                    )
+                });
+            match reservation {
+                Ok(id) => reservations.push(id),
+                Err(e) => {
+                    for id in reservations {
+                        let _ = self.take_reservation(id);
+                    }
+                    return Err(e);
                 }
-                Operation::Transfer(from, to, amount) => {
-                    let _ = self.transfer(from.clone(), to.clone(), *amount);
+            }
+        }
+        // Голос "да" переживает перезапуск: резервирования уже сохранены
+        self.prepared.insert(transaction, reservations);
+        self.save_details();
+        Ok(())
+    }
+
+    /// Applies all legs prepared for `transaction`. Committing it again is
+    /// not an error once its legs are in the history. A transaction this
+    /// bank does not know, e.g. one whose prepared legs were lost in a
+    /// restart, fails with `TransactionDoesNotExist`.
+    pub fn commit(&mut self, transaction: &TransactionId) -> Result<(), BankError> {
+        let Some(reservations) = self.prepared.remove(transaction) else {
+            if self.committed.contains(transaction) {
+                return Ok(());
+            }
+            return Err(BankError::TransactionDoesNotExist(transaction.clone()));
+        };
+        let result = reservations
+            .into_iter()
+            .try_for_each(|id| self.apply_reservation(id).map(|_| ()));
+        self.save_details();
+        result
+    }
+
+    /// Releases all legs prepared for `transaction`. Aborting a transaction
+    /// this bank never prepared is not an error.
+    pub fn abort(&mut self, transaction: &TransactionId) -> Result<(), BankError> {
+        let result = self
+            .prepared
+            .remove(transaction)
+            .unwrap_or_default()
+            .into_iter()
+            .try_for_each(|id| self.take_reservation(id).map(|_| ()));
+        self.save_details();
+        result
+    }
+
+    fn hold(
+        &mut self,
+        account: AccountId,
+        amount: u32,
+        kind: ReservationKind,
+        source: ReservationSource,
+    ) -> Result<ReservationId, BankError> {
+        self.check_open(account)?;
+        self.check_zero_amount(amount)?;
+
+        if kind == ReservationKind::Debit {
+            if self.available_balance(account) < amount {
+                return Err(BankError::InsufficientFunds(amount));
+            }
+            self.check_outflow_limits(account, amount)?;
+            *self.held.entry(account).or_default() += amount;
+        } else {
+            self.credited_balance(account, self.storage.balance(account), amount, 0)?;
+            *self.incoming.entry(account).or_default() += amount as u64;
+        }
+
+        let id = self.next_reservation_id;
+        self.next_reservation_id += 1;
+        self.reservations.insert(
+            id,
+            Reservation {
+                account,
+                amount,
+                kind,
+                source,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Applies a reservation to the balance and records it in the history.
+    pub fn commit_reservation(&mut self, id: ReservationId) -> Result<usize, BankError> {
+        let operation_id = self.apply_reservation(id)?;
+        self.save_details();
+        Ok(operation_id)
+    }
+
+    fn apply_reservation(&mut self, id: ReservationId) -> Result<usize, BankError> {
+        let reservation = self
+            .reservations
+            .get(&id)
+            .ok_or(BankError::ReservationDoesNotExist(id))?;
+        let account = reservation.account;
+        let current_balance = self.storage.balance(account);
+        let new_balance = match reservation.kind {
+            ReservationKind::Debit => current_balance - reservation.amount,
+            ReservationKind::Credit => {
+                let amount = reservation.amount;
+                self.credited_balance(account, current_balance, amount, amount)?
+            }
+        };
+        let reservation = self.take_reservation(id)?;
+        let name = self.storage.account_name(account).to_string();
+        self.storage.set_balance(account, new_balance);
+
+        let operation = match (reservation.source, reservation.kind) {
+            (ReservationSource::Transfer(to), ReservationKind::Debit) => {
+                Operation::RemoteTransferOut {
+                    from: name,
+                    to,
+                    amount: reservation.amount,
+                }
+            }
+            (ReservationSource::Transfer(from), ReservationKind::Credit) => {
+                Operation::RemoteTransferIn {
+                    from,
+                    to: name,
+                    amount: reservation.amount,
                 }
             }
+            (ReservationSource::Transaction(transaction), kind) => Operation::TransactionLeg {
+                transaction,
+                account: name,
+                kind,
+                amount: reservation.amount,
+            },
+        };
+
+        let operation_id = self.append_history(operation);
+        self.append_account_index(account, operation_id);
+        Ok(operation_id)
+    }
+
+    /// Drops a reservation without touching the balance.
+    pub fn release_reservation(&mut self, id: ReservationId) -> Result<(), BankError> {
+        self.take_reservation(id)?;
+        self.save_details();
+        Ok(())
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.snapshot_over(self.storage.snapshot())
+    }
+
+    /// Same as [`Bank::snapshot`], but the history and its timestamps are
+    /// left for the returned [`FrozenHistory`] to read, e.g. on another
+    /// thread: history segments are not read back here.
+    pub fn snapshot_frozen(&self) -> (Snapshot, FrozenHistory) {
+        let snapshot = self.snapshot_over(self.storage.snapshot_accounts());
+        (snapshot, self.storage.freeze_history())
+    }
+
+    // Снимок банка поверх снимка хранилища: счета и история из него
+    fn snapshot_over(&self, storage: Snapshot) -> Snapshot {
+        let mut reservations: Vec<OpenReservation> = self
+            .reservations
+            .iter()
+            .map(|(id, reservation)| OpenReservation {
+                id: *id,
+                account: self.storage.account_name(reservation.account).to_string(),
+                amount: reservation.amount,
+                kind: reservation.kind,
+                source: reservation.source.clone(),
+            })
+            .collect();
+        reservations.sort_by_key(|reservation| reservation.id);
+        Snapshot {
+            metadata: self
+                .metadata
+                .iter()
+                .map(|(id, metadata)| {
+                    (self.storage.account_name(*id).to_string(), metadata.clone())
+                })
+                .collect(),
+            owners: self
+                .owners
+                .iter()
+                .map(|(id, owners)| (self.storage.account_name(*id).to_string(), owners.clone()))
+                .collect(),
+            tags: self
+                .tags
+                .iter()
+                .map(|(id, tags)| (self.storage.account_name(*id).to_string(), tags.clone()))
+                .collect(),
+            disputes: self.disputes.values().cloned().collect(),
+            approvals: self
+                .required_approvals
+                .iter()
+                .map(|(id, approvals)| (self.storage.account_name(*id).to_string(), *approvals))
+                .collect(),
+            alert_rules: self
+                .alert_rules
+                .iter()
+                .map(|(id, below)| (self.storage.account_name(*id).to_string(), *below))
+                .collect(),
+            reservations,
+            next_reservation_id: self.next_reservation_id,
+            pending_transfers: self.pending_transfers(),
+            next_pending_id: self.next_pending_id,
+            proposals: self
+                .proposals
+                .iter()
+                .map(|(id, proposal)| OpenProposal {
+                    id: *id,
+                    from: self.storage.account_name(proposal.from).to_string(),
+                    to: self.storage.account_name(proposal.to).to_string(),
+                    amount: proposal.amount,
+                    approvals: proposal.approvals.clone(),
+                })
+                .collect(),
+            next_proposal_id: self.next_proposal_id,
+            ..storage
         }
     }
 
-    fn check_zero_amount(&self, amount: u32) -> Result<(), BankError> {
-        if amount == 0 {
-            return Err(BankError::IncorrectAmount(amount));
+    /// Builds a bank from a snapshot without replaying its history; only the
+    /// per-account index is rebuilt.
+    pub fn from_snapshot(mut snapshot: Snapshot) -> Self {
+        let metadata = mem::take(&mut snapshot.metadata);
+        let owners = mem::take(&mut snapshot.owners);
+        let tags = mem::take(&mut snapshot.tags);
+        let disputes = mem::take(&mut snapshot.disputes);
+        let approvals = mem::take(&mut snapshot.approvals);
+        let alert_rules = mem::take(&mut snapshot.alert_rules);
+        let reservations = mem::take(&mut snapshot.reservations);
+        let next_reservation_id = snapshot.next_reservation_id;
+        let pending_transfers = mem::take(&mut snapshot.pending_transfers);
+        let next_pending_id = snapshot.next_pending_id;
+        let proposals = mem::take(&mut snapshot.proposals);
+        let next_proposal_id = snapshot.next_proposal_id;
+        let mut bank = Bank::with_storage(Box::new(MemoryStorage::from_snapshot(snapshot)));
+        let reservations: Vec<_> = reservations
+            .into_iter()
+            .filter_map(|reservation| {
+                let account = bank.storage.account_id(&reservation.account)?;
+                let OpenReservation {
+                    id,
+                    amount,
+                    kind,
+                    source,
+                    ..
+                } = reservation;
+                Some((
+                    id,
+                    Reservation {
+                        account,
+                        amount,
+                        kind,
+                        source,
+                    },
+                ))
+            })
+            .collect();
+        bank.set_reservations(reservations, next_reservation_id);
+        let pending: Vec<_> = pending_transfers
+            .into_iter()
+            .filter_map(|transfer| {
+                let pending = Pending {
+                    from: bank.storage.account_id(&transfer.from)?,
+                    to: bank.storage.account_id(&transfer.to)?,
+                    amount: transfer.amount,
+                    requested_at: transfer.requested_at,
+                };
+                Some((transfer.id, pending))
+            })
+            .collect();
+        bank.set_pending(pending, next_pending_id);
+        bank.proposals = proposals
+            .into_iter()
+            .filter_map(|proposal| {
+                let open = Proposal {
+                    from: bank.storage.account_id(&proposal.from)?,
+                    to: bank.storage.account_id(&proposal.to)?,
+                    amount: proposal.amount,
+                    approvals: proposal.approvals,
+                };
+                Some((proposal.id, open))
+            })
+            .collect();
+        bank.next_proposal_id = next_proposal_id;
+        bank.set_account_details(metadata, owners, tags, approvals, alert_rules);
+        bank.set_disputes(disputes);
+        bank
+    }
+
+    /// Sets the metadata, owners, tags, required approvals and alert rules
+    /// of the accounts by name, e.g. from a snapshot, and saves them to the
+    /// storage. Unknown accounts are skipped.
+    pub fn set_account_details(
+        &mut self,
+        metadata: HashMap<String, BTreeMap<String, String>>,
+        owners: HashMap<String, BTreeSet<String>>,
+        tags: HashMap<String, BTreeSet<String>>,
+        approvals: HashMap<String, u32>,
+        alert_rules: HashMap<String, u32>,
+    ) {
+        for (name, metadata) in metadata {
+            if let Some(id) = self.storage.account_id(&name) {
+                self.metadata.insert(id, metadata);
+            }
+        }
+        for (name, owners) in owners {
+            if let Some(id) = self.storage.account_id(&name) {
+                self.owners.insert(id, owners);
+            }
+        }
+        for (name, tags) in tags {
+            if let Some(id) = self.storage.account_id(&name) {
+                self.tags.insert(id, tags);
+            }
+        }
+        for (name, approvals) in approvals {
+            if let Some(id) = self.storage.account_id(&name) {
+                self.required_approvals.insert(id, approvals);
+            }
+        }
+        for (name, below) in alert_rules {
+            if let Some(id) = self.storage.account_id(&name) {
+                self.alert_rules.insert(id, below);
+            }
+        }
+        self.save_details();
+    }
+
+    /// Details of the accounts as the storage keeps them.
+    fn details(&self) -> AccountDetails {
+        AccountDetails {
+            metadata: self.metadata.clone(),
+            owners: self.owners.clone(),
+            tags: self.tags.clone(),
+            approvals: self.required_approvals.clone(),
+            alert_rules: self.alert_rules.clone(),
+            disputes: self.disputes.values().cloned().collect(),
+            reservations: self.reservations.clone().into_iter().collect(),
+            next_reservation_id: self.next_reservation_id,
+            pending: self.pending.clone(),
+            next_pending_id: self.next_pending_id,
+            proposals: self.proposals.clone(),
+            next_proposal_id: self.next_proposal_id,
+        }
+    }
+
+    // Данные счетов пишутся целиком: меняются они редко, а открытых
+    // резервирований и ждущих переводов немного
+    fn save_details(&mut self) {
+        let details = self.details();
+        self.storage.set_details(&details);
+    }
+
+    /// Makes transfers out of `account` need `approvals` distinct
+    /// identities, its owners if it has any, to agree through
+    /// [`Bank::propose_transfer`]. Up to one approval the account is
+    /// unprotected again.
+    pub fn set_required_approvals(
+        &mut self,
+        account: impl Into<AccountRef>,
+        approvals: u32,
+    ) -> Result<(), BankError> {
+        let id = self.resolve_account(&account.into())?;
+        if approvals > 1 {
+            self.required_approvals.insert(id, approvals);
+        } else {
+            self.required_approvals.remove(&id);
         }
+        self.save_details();
         Ok(())
     }
 
-    fn check_exists_account(&mut self, account: String) -> Result<(), BankError> {
-        if !self.accounts.contains(&account) {
-            return Err(BankError::AccountDoesNotExist(format!(
-                "Account {} does not exist",
-                account
+    /// Proposes a transfer out of `from` on behalf of `caller`, who approves
+    /// it right away. Funds are not held: the transfer is checked when the
+    /// last approval comes. Proposals are saved with the details of the
+    /// accounts.
+    pub fn propose_transfer(
+        &mut self,
+        from: impl Into<AccountRef>,
+        to: impl Into<AccountRef>,
+        amount: u32,
+        caller: Option<&str>,
+    ) -> Result<ProposalStatus, BankError> {
+        let from = self.resolve_open_account(&from.into())?;
+        let to = self.resolve_open_account(&to.into())?;
+        if from == to {
+            return Err(BankError::TransferToMyself);
+        }
+        self.check_zero_amount(amount)?;
+        self.check_approver(from, caller)?;
+
+        let id = self.next_proposal_id;
+        self.next_proposal_id += 1;
+        let proposal = Proposal {
+            from,
+            to,
+            amount,
+            approvals: BTreeSet::new(),
+        };
+        self.proposals.insert(id, proposal);
+        self.save_details();
+        self.approve_proposal(id, caller)
+    }
+
+    /// Approves proposal `id` on behalf of `caller`, an owner of the debited
+    /// account if it has any. The approval that completes the required
+    /// number makes the transfer; if the transfer fails, e.g. for lack of
+    /// funds, the approval is not counted and can be given again later.
+    pub fn approve_proposal(
+        &mut self,
+        id: ProposalId,
+        caller: Option<&str>,
+    ) -> Result<ProposalStatus, BankError> {
+        let proposal = self
+            .proposals
+            .get(&id)
+            .ok_or(BankError::ProposalDoesNotExist(id))?;
+        let approver = self.check_approver(proposal.from, caller)?;
+        if proposal.approvals.contains(approver) {
+            return Err(BankError::AlreadyApproved(id));
+        }
+        let (from, to, amount) = (proposal.from, proposal.to, proposal.amount);
+        let approvals = proposal.approvals.len() as u32 + 1;
+        let required = self.required_approvals.get(&from).copied().unwrap_or(1);
+        let mut status = ProposalStatus {
+            id,
+            approvals,
+            required,
+            operation: None,
+        };
+        if approvals < required {
+            let approver = approver.to_string();
+            self.proposals
+                .get_mut(&id)
+                .unwrap()
+                .approvals
+                .insert(approver);
+            self.save_details();
+            return Ok(status);
+        }
+
+        self.approved = true;
+        let transferred = self.transfer(from, to, amount);
+        self.approved = false;
+        transferred?;
+        self.proposals.remove(&id);
+        self.save_details();
+        status.operation = Some(self.storage.history_len() - 1);
+        Ok(status)
+    }
+
+    /// Fails unless `caller` is an identity that may approve transfers out
+    /// of `account`.
+    fn check_approver<'a>(
+        &self,
+        account: AccountId,
+        caller: Option<&'a str>,
+    ) -> Result<&'a str, BankError> {
+        let caller = caller.ok_or_else(|| {
+            BankError::Forbidden("an anonymous caller can not approve transfers".to_string())
+        })?;
+        self.check_owner(&account.into(), Some(caller))?;
+        Ok(caller)
+    }
+
+    /// Sets the balance below which `account` raises an alert; `None`
+    /// removes the rule.
+    pub fn set_alert_rule(
+        &mut self,
+        account: impl Into<AccountRef>,
+        below: Option<u32>,
+    ) -> Result<(), BankError> {
+        let id = self.resolve_account(&account.into())?;
+        match below {
+            Some(below) => self.alert_rules.insert(id, below),
+            None => self.alert_rules.remove(&id),
+        };
+        self.save_details();
+        Ok(())
+    }
+
+    /// Accounts with an alert rule: the name, the threshold of the rule
+    /// and the balance.
+    pub fn alert_rules(&self) -> Vec<(&str, u32, u32)> {
+        self.alert_rules
+            .iter()
+            .map(|(id, below)| {
+                (
+                    self.storage.account_name(*id),
+                    *below,
+                    self.storage.balance(*id),
+                )
+            })
+            .collect()
+    }
+
+    /// Sets the disputes, e.g. from a snapshot, and saves them to the
+    /// storage.
+    pub fn set_disputes(&mut self, disputes: Vec<Dispute>) {
+        self.disputes = disputes
+            .into_iter()
+            .map(|dispute| (dispute.id, dispute))
+            .collect();
+        self.save_details();
+    }
+
+    /// Opens a dispute against operation `operation` of the history. Only
+    /// an owner of one of its accounts may dispute it.
+    pub fn open_dispute(
+        &mut self,
+        operation: usize,
+        reason: String,
+        caller: Option<&str>,
+    ) -> Result<DisputeId, BankError> {
+        let disputed = self
+            .get_history_page(operation, 1)
+            .pop()
+            .ok_or(BankError::OperationDoesNotExist(operation))?;
+        let mut owned = Err(BankError::OperationDoesNotExist(operation));
+        for account in disputed.accounts() {
+            owned = self.check_owner(&account.into(), caller);
+            if owned.is_ok() {
+                break;
+            }
+        }
+        owned?;
+
+        let id = self.disputes.keys().next_back().map_or(0, |id| id + 1);
+        let dispute = Dispute {
+            id,
+            operation,
+            reason,
+            opened_by: caller.map(str::to_string),
+            opened_at: self.clock.now(),
+            resolution: None,
+        };
+        self.disputes.insert(id, dispute);
+        self.save_details();
+        Ok(id)
+    }
+
+    /// Closes the open dispute `id`. With `reverse` the disputed operation
+    /// is undone first: a deposit by a withdrawal, a withdrawal by a
+    /// deposit, a transfer by a transfer back.
+    pub fn resolve_dispute(&mut self, id: DisputeId, reverse: bool) -> Result<(), BankError> {
+        let operation = match self.disputes.get(&id) {
+            Some(dispute) if dispute.resolution.is_none() => dispute.operation,
+            _ => return Err(BankError::DisputeDoesNotExist(id)),
+        };
+        let reversal = if reverse {
+            let reversal = match self.get_history_page(operation, 1).pop() {
+                Some(Operation::IncreaseAccount(account, amount)) => {
+                    self.decrease_account(account, amount)?
+                }
+                Some(Operation::DecreaseAccount(account, amount)) => {
+                    self.increase_account(account, amount)?
+                }
+                Some(Operation::Transfer(from, to, amount)) => {
+                    self.transfer(to, from, amount)?;
+                    self.storage.history_len() - 1
+                }
+                _ => return Err(BankError::NotReversible(operation)),
+            };
+            Some(reversal)
+        } else {
+            None
+        };
+        let resolved_at = self.clock.now();
+        self.disputes.get_mut(&id).unwrap().resolution = Some(DisputeResolution {
+            resolved_at,
+            reversal,
+        });
+        self.save_details();
+        Ok(())
+    }
+
+    /// Open disputes, oldest first, and the resolved ones too with
+    /// `include_resolved`.
+    pub fn disputes(&self, include_resolved: bool) -> Vec<Dispute> {
+        self.disputes
+            .values()
+            .filter(|dispute| include_resolved || dispute.resolution.is_none())
+            .cloned()
+            .collect()
+    }
+
+    /// Bank over the accounts and the history already in `storage`, e.g. a
+    /// persistent backend opened after a restart. The indices and limits are
+    /// rebuilt from the history without replaying it. The hash checkpoints
+    /// the storage keeps are taken as they are, the missing ones are
+    /// computed and saved; the details of the accounts are read from the
+    /// storage if it keeps them.
+    pub fn with_storage(storage: Box<dyn BankStorage>) -> Self {
+        let mut bank = Bank {
+            storage,
+            ..Bank::new()
+        };
+        let storage = &bank.storage;
+        for id in 0..storage.account_count() {
+            let parent = parent_account(storage.account_name(id));
+            if let Some(parent) = parent.and_then(|parent| storage.account_id(parent)) {
+                bank.children.entry(parent).or_default().push(id);
+            }
+        }
+        let saved = storage.checkpoints();
+        let mut previous = GENESIS;
+        for (operation_id, operation) in storage.operations().enumerate() {
+            bank.history_bytes += operation_bytes(&operation);
+            for name in operation.accounts() {
+                if let Some(id) = storage.account_id(name) {
+                    bank.account_index.push(id, operation_id);
+                }
+            }
+            match &operation {
+                Operation::SetLimits { account, limits } => {
+                    if let Some(id) = storage.account_id(account) {
+                        bank.limits.insert(id, limits.clone());
+                    }
+                }
+                Operation::CloseAccount(account) => {
+                    if let Some(id) = storage.account_id(account) {
+                        bank.closed.insert(id);
+                    }
+                }
+                Operation::TransactionLeg { transaction, .. } => {
+                    bank.committed.insert(transaction.clone());
+                }
+                _ => {}
+            }
+            // Сохраненные хеши посчитаны по именам до переименований: по
+            // ним дайджесты, выданные раньше, остаются верными
+            let block = operation_id / BLOCK_SIZE;
+            if block >= saved.len() {
+                previous = chain(&previous, &operation);
+            }
+            if (operation_id + 1).is_multiple_of(BLOCK_SIZE) {
+                previous = saved.get(block).copied().unwrap_or(previous);
+                bank.checkpoints.push(previous);
+            }
+        }
+        bank.head = previous;
+        for (block, hash) in bank.checkpoints.iter().enumerate().skip(saved.len()) {
+            bank.storage.set_checkpoint(block, hash);
+        }
+        if let Some(details) = bank.storage.details() {
+            bank.metadata = details.metadata;
+            bank.owners = details.owners;
+            bank.tags = details.tags;
+            bank.required_approvals = details.approvals;
+            bank.alert_rules = details.alert_rules;
+            bank.disputes = details
+                .disputes
+                .into_iter()
+                .map(|dispute| (dispute.id, dispute))
+                .collect();
+            bank.set_reservations(details.reservations, details.next_reservation_id);
+            bank.set_pending(details.pending, details.next_pending_id);
+            bank.proposals = details.proposals;
+            bank.next_proposal_id = details.next_proposal_id;
+        }
+        bank
+    }
+
+    /// Takes over the reservations left when the bank was saved, with the
+    /// funds they hold and the transactions they prepared.
+    fn set_reservations(
+        &mut self,
+        reservations: impl IntoIterator<Item = (ReservationId, Reservation)>,
+        next_id: ReservationId,
+    ) {
+        for (id, reservation) in reservations {
+            // Части транзакции попали в историю, а снятие резерва сохранить
+            // не успели: транзакция уже зафиксирована
+            if let ReservationSource::Transaction(transaction) = &reservation.source {
+                if self.committed.contains(transaction) {
+                    continue;
+                }
+                self.prepared
+                    .entry(transaction.clone())
+                    .or_default()
+                    .push(id);
+            }
+            match reservation.kind {
+                ReservationKind::Debit => {
+                    *self.held.entry(reservation.account).or_default() += reservation.amount;
+                }
+                ReservationKind::Credit => {
+                    *self.incoming.entry(reservation.account).or_default() +=
+                        reservation.amount as u64;
+                }
+            }
+            self.next_reservation_id = self.next_reservation_id.max(id + 1);
+            self.reservations.insert(id, reservation);
+        }
+        self.next_reservation_id = self.next_reservation_id.max(next_id);
+    }
+
+    /// Takes over the transfers left waiting for an admin when the bank was
+    /// saved, with the funds they hold.
+    fn set_pending(
+        &mut self,
+        pending: impl IntoIterator<Item = (PendingTransferId, Pending)>,
+        next_id: PendingTransferId,
+    ) {
+        for (id, pending) in pending {
+            *self.held.entry(pending.from).or_default() += pending.amount;
+            self.next_pending_id = self.next_pending_id.max(id + 1);
+            self.pending.insert(id, pending);
+        }
+        self.next_pending_id = self.next_pending_id.max(next_id);
+    }
+
+    pub fn set_account_limits(
+        &mut self,
+        account: impl Into<AccountRef>,
+        limits: AccountLimits,
+    ) -> Result<usize, BankError> {
+        let id = self.resolve_account(&account.into())?;
+        self.check_capacity(0, 1)?;
+        self.limits.insert(id, limits.clone());
+
+        let name = self.storage.account_name(id).to_string();
+        let operation_id = self.append_history(Operation::SetLimits {
+            account: name,
+            limits,
+        });
+        self.append_account_index(id, operation_id);
+        Ok(operation_id)
+    }
+
+    /// Closes an account without money, holds or reservations. Its name
+    /// stays taken and its history stays queryable.
+    pub fn close_account(&mut self, account: impl Into<AccountRef>) -> Result<usize, BankError> {
+        let id = self.resolve_open_account(&account.into())?;
+        let name = self.storage.account_name(id).to_string();
+        let balance = self.storage.balance(id);
+        if balance > 0 {
+            return Err(BankError::AccountNotEmpty(format!(
+                "Account {} has a balance of {}",
+                name, balance
+            )));
+        }
+        let reserved = self.reservations.values().any(|r| r.account == id)
+            || self.pending.values().any(|p| p.to == id);
+        if reserved {
+            return Err(BankError::AccountNotEmpty(format!(
+                "Account {} has unfinished transfers",
+                name
+            )));
+        }
+        self.check_capacity(0, 1)?;
+        self.closed.insert(id);
+
+        let operation_id = self.append_history(Operation::CloseAccount(name));
+        self.append_account_index(id, operation_id);
+        Ok(operation_id)
+    }
+
+    /// Replaces the name of `account` with the pseudonym `anonymous-<id>`
+    /// (under the same parent for a sub-account) in the account list and in
+    /// the whole history; its sub-accounts move under the pseudonym. Amounts,
+    /// IDs and the history structure stay as they were and an
+    /// [`Operation::AnonymizeAccount`] is appended, so that replicas
+    /// replaying the history rename the accounts too.
+    ///
+    /// The hash chain is not recomputed: the operation carries the last hash
+    /// as a checkpoint and the chain goes on from it, so digests handed out
+    /// before stay valid. The hashes of whole blocks before it are kept by a
+    /// storage that saves them. A digest of a prefix ending inside such a
+    /// block, except right before the renaming, hashes the renamed
+    /// operations of that block.
+    ///
+    /// Returns the pseudonym and the old and new name of every account that
+    /// was renamed.
+    pub fn anonymize_account(
+        &mut self,
+        account: impl Into<AccountRef>,
+    ) -> Result<(String, HashMap<String, String>), BankError> {
+        let id = self.resolve_account(&account.into())?;
+        let checkpoint = to_hex(&self.head);
+        self.anonymize(id, checkpoint)
+    }
+
+    fn anonymize(
+        &mut self,
+        id: AccountId,
+        checkpoint: String,
+    ) -> Result<(String, HashMap<String, String>), BankError> {
+        let name = self.storage.account_name(id).to_string();
+        let pseudonym = match parent_account(&name) {
+            Some(parent) => format!("{}/anonymous-{}", parent, id),
+            None => format!("anonymous-{}", id),
+        };
+        let prefix = format!("{}/", name);
+        let renames: HashMap<String, String> = self
+            .accounts()
+            .filter_map(|(_, old)| {
+                let new = match old.strip_prefix(&prefix) {
+                    Some(rest) => format!("{}/{}", pseudonym, rest),
+                    None if old == name => pseudonym.clone(),
+                    None => return None,
+                };
+                // При повторе истории счет уже может носить псевдоним
+                (old != new).then(|| (old.to_string(), new))
+            })
+            .collect();
+        if let Some(taken) = renames
+            .values()
+            .find(|new| self.storage.account_id(new).is_some())
+        {
+            return Err(BankError::AccountAlreadyExists(format!(
+                "Account {} already exists",
+                taken
             )));
         }
+
+        self.check_capacity(0, 1)?;
+
+        if !renames.is_empty() {
+            self.storage.rename_accounts(&renames);
+        }
+        let operation_id = self.append_history(Operation::AnonymizeAccount {
+            id,
+            pseudonym: pseudonym.clone(),
+            checkpoint,
+        });
+        self.append_account_index(id, operation_id);
+        Ok((pseudonym, renames))
+    }
+
+    /// Accounts with their balances in the order they were created.
+    pub fn list_accounts(&self, filter: AccountFilter) -> Vec<(String, u32)> {
+        self.accounts()
+            .filter(|(id, _)| self.matches(*id, filter))
+            .map(|(id, name)| (name.to_string(), self.storage.balance(id)))
+            .collect()
+    }
+
+    /// Page of [`Bank::list_accounts`]; the cursor is an account ID.
+    pub fn list_accounts_paged(
+        &self,
+        filter: AccountFilter,
+        page: &PageRequest,
+    ) -> Result<Page<(String, u32)>, BankError> {
+        let start = start_of(page)?;
+        let accounts = (start..self.storage.account_count())
+            .filter(|id| self.matches(*id, filter))
+            .map(|id| {
+                let name = self.storage.account_name(id).to_string();
+                (id, (name, self.storage.balance(id)))
+            });
+        Ok(paginate(accounts, page.limit))
+    }
+
+    pub fn get_account_limits(
+        &self,
+        account: impl Into<AccountRef>,
+    ) -> Result<AccountLimits, BankError> {
+        let id = self.resolve_account(&account.into())?;
+        Ok(self.limits.get(&id).cloned().unwrap_or_default())
+    }
+
+    /// Sets `key` of the account metadata to `value`, or removes it when `value` is `None`.
+    pub fn set_account_metadata(
+        &mut self,
+        account: impl Into<AccountRef>,
+        key: String,
+        value: Option<String>,
+    ) -> Result<(), BankError> {
+        let id = self.resolve_account(&account.into())?;
+        let metadata = self.metadata.entry(id).or_default();
+        match value {
+            Some(value) => {
+                metadata.insert(key, value);
+            }
+            None => {
+                metadata.remove(&key);
+            }
+        }
+        self.save_details();
         Ok(())
     }
 
-    fn append_history(&mut self, operation: Operation) -> usize {
-        self.history.push(operation);
-        self.history.len() - 1
+    pub fn get_account_metadata(
+        &self,
+        account: impl Into<AccountRef>,
+    ) -> Result<BTreeMap<String, String>, BankError> {
+        let id = self.resolve_account(&account.into())?;
+        Ok(self.metadata.get(&id).cloned().unwrap_or_default())
     }
 
-    fn append_account_index(&mut self, account: String, id: usize) {
-        if self.account_operations_index.contains_key(&account) {
-            //для такого аккаунта есть индекс
-            self.account_operations_index
-                .get_mut(&account)
-                .unwrap()
-                .push(id);
+    /// Replaces the owners of the account; an empty set makes it public again.
+    pub fn set_account_owners(
+        &mut self,
+        account: impl Into<AccountRef>,
+        owners: BTreeSet<String>,
+    ) -> Result<(), BankError> {
+        let id = self.resolve_account(&account.into())?;
+        if owners.is_empty() {
+            self.owners.remove(&id);
+        } else {
+            self.owners.insert(id, owners);
+        }
+        self.save_details();
+        Ok(())
+    }
+
+    pub fn get_account_owners(
+        &self,
+        account: impl Into<AccountRef>,
+    ) -> Result<BTreeSet<String>, BankError> {
+        let id = self.resolve_account(&account.into())?;
+        Ok(self.owners.get(&id).cloned().unwrap_or_default())
+    }
+
+    /// Replaces the tags of the account.
+    pub fn set_account_tags(
+        &mut self,
+        account: impl Into<AccountRef>,
+        tags: BTreeSet<String>,
+    ) -> Result<(), BankError> {
+        let id = self.resolve_account(&account.into())?;
+        if tags.is_empty() {
+            self.tags.remove(&id);
         } else {
-            //для такого аккаунта еще нет индекса
-            self.account_operations_index
-                .insert(account.clone(), vec![id]);
+            self.tags.insert(id, tags);
         }
+        self.save_details();
+        Ok(())
+    }
+
+    pub fn get_account_tags(
+        &self,
+        account: impl Into<AccountRef>,
+    ) -> Result<BTreeSet<String>, BankError> {
+        let id = self.resolve_account(&account.into())?;
+        Ok(self.tags.get(&id).cloned().unwrap_or_default())
+    }
+
+    /// Accounts carrying `tag` with their balances, in id order.
+    pub fn find_accounts_by_tag(&self, tag: &str) -> Vec<(String, u32)> {
+        self.accounts()
+            .filter(|(id, _)| self.tags.get(id).is_some_and(|tags| tags.contains(tag)))
+            .map(|(id, name)| (name.to_string(), self.storage.balance(id)))
+            .collect()
+    }
+
+    /// Accounts with a balance within `[min_balance, max_balance]` with their
+    /// balances, in id order; a missing bound is open.
+    pub fn find_accounts(
+        &self,
+        min_balance: Option<u32>,
+        max_balance: Option<u32>,
+    ) -> Vec<(String, u32)> {
+        let range = min_balance.unwrap_or(0)..=max_balance.unwrap_or(u32::MAX);
+        self.accounts()
+            .filter(|(id, _)| range.contains(&self.storage.balance(*id)))
+            .map(|(id, name)| (name.to_string(), self.storage.balance(id)))
+            .collect()
+    }
+
+    /// The `n` accounts with the largest balances, largest first; of equal
+    /// balances the older account comes first.
+    pub fn top_accounts(&self, n: usize) -> Vec<(String, u32)> {
+        // Куча из n наименьших среди лучших: O(N log n) без копии всех балансов
+        let mut top = BinaryHeap::with_capacity(n.min(self.storage.account_count()) + 1);
+        for id in 0..self.storage.account_count() {
+            let balance = self.storage.balance(id);
+            top.push(Reverse((balance, Reverse(id))));
+            if top.len() > n {
+                top.pop();
+            }
+        }
+        top.into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((balance, Reverse(id)))| {
+                (self.storage.account_name(id).to_string(), balance)
+            })
+            .collect()
+    }
+
+    /// Name of `account`, `None` if there is no such account.
+    pub fn account_name(&self, account: &AccountRef) -> Option<&str> {
+        let id = self.resolve_account(account).ok()?;
+        Some(self.storage.account_name(id))
+    }
+
+    /// Checks that `caller` may operate `account`: either the account has no
+    /// owners or the caller is one of them.
+    pub fn check_owner(&self, account: &AccountRef, caller: Option<&str>) -> Result<(), BankError> {
+        let id = self.resolve_account(account)?;
+        let Some(owners) = self.owners.get(&id) else {
+            return Ok(());
+        };
+        if caller.is_some_and(|caller| owners.contains(caller)) {
+            return Ok(());
+        }
+        Err(BankError::Forbidden(format!(
+            "{} is not an owner of account {}",
+            caller.unwrap_or("anonymous caller"),
+            self.storage.account_name(id)
+        )))
+    }
+
+    /// Sets the most accounts and history operations the bank takes; what
+    /// would go past them fails with `CapacityExceeded`. A bank that is
+    /// already past a lowered limit keeps what it has.
+    pub fn set_capacity(&mut self, max_accounts: Option<usize>, max_history: Option<usize>) {
+        self.max_accounts = max_accounts;
+        self.max_history = max_history;
+    }
+
+    /// Estimated memory of the accounts and the history, and the capacity.
+    pub fn stats(&self) -> ServerStats {
+        // Имя хранится дважды: в списке счетов и в индексе по имени
+        let account_bytes = self
+            .accounts()
+            .map(|(_, name)| (ACCOUNT_BYTES + 2 * name.len()) as u64)
+            .sum();
+        ServerStats {
+            // Адрес и режим хранилища знает только сервер
+            address: String::new(),
+            durability: None,
+            accounts: self.storage.account_count(),
+            operations: self.storage.history_len(),
+            account_bytes,
+            history_bytes: self.history_bytes,
+            max_accounts: self.max_accounts,
+            max_history: self.max_history,
+        }
+    }
+
+    /// Fails with `CapacityExceeded` if `accounts` new accounts or
+    /// `operations` new history operations would not fit.
+    fn check_capacity(&self, accounts: usize, operations: usize) -> Result<(), BankError> {
+        let check = |resource: &str, held: usize, new: usize, limit: Option<usize>| match limit {
+            Some(limit) if new > 0 && held + new > limit => Err(BankError::CapacityExceeded {
+                resource: resource.to_string(),
+                limit,
+            }),
+            _ => Ok(()),
+        };
+        check(
+            "accounts",
+            self.storage.account_count(),
+            accounts,
+            self.max_accounts,
+        )?;
+        check(
+            "history",
+            self.storage.history_len(),
+            operations,
+            self.max_history,
+        )
+    }
+
+    /// Sets the velocity rules that apply to every account in addition to its own.
+    pub fn set_velocity_rules(&mut self, rules: Vec<VelocityRule>) {
+        self.velocity_rules = rules;
+    }
+
+    /// Current time of the bank clock, unix seconds.
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// Takes the time for operation timestamps and outflow limits from `clock`.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Moves the accounts, the history with its hash checkpoints and the
+    /// details of the accounts to `storage`, which must be empty, e.g. a
+    /// [`MemoryStorage`] keeping its history in segment files.
+    pub fn set_storage(&mut self, mut storage: Box<dyn BankStorage>) {
+        storage.set_details(&self.details());
+        for (block, hash) in self.checkpoints.iter().enumerate() {
+            storage.set_checkpoint(block, hash);
+        }
+        storage::copy(&*self.storage, &mut *storage);
+        self.storage = storage;
+    }
+
+    /// Same as [`Bank::get_history`], but gives up with
+    /// `BankError::Cancelled` once `cancelled` is set.
+    pub fn get_history_cancellable(
+        &self,
+        cancelled: &AtomicBool,
+    ) -> Result<Vec<Operation>, BankError> {
+        let mut history = Vec::with_capacity(self.storage.history_len());
+        for (index, operation) in self.storage.operations().enumerate() {
+            if index.is_multiple_of(CANCEL_CHECK_INTERVAL) && cancelled.load(Ordering::Relaxed) {
+                return Err(BankError::Cancelled);
+            }
+            history.push(operation);
+        }
+        Ok(history)
+    }
+
+    pub fn history_len(&self) -> usize {
+        self.storage.history_len()
+    }
+
+    pub fn account_count(&self) -> usize {
+        self.storage.account_count()
+    }
+
+    /// Sum of all balances, including funds held by reservations.
+    pub fn total_balance(&self) -> u64 {
+        (0..self.storage.account_count())
+            .map(|id| self.storage.balance(id) as u64)
+            .sum()
+    }
+
+    /// Returns at most `limit` operations of the history starting at `offset`.
+    pub fn get_history_page(&self, offset: usize, limit: usize) -> Vec<Operation> {
+        self.storage.range(offset, offset.saturating_add(limit))
+    }
+
+    /// Page of the history; the cursor is an operation ID.
+    pub fn get_history_paged(&self, page: &PageRequest) -> Result<Page<Operation>, BankError> {
+        let start = start_of(page)?;
+        // Одна лишняя операция показывает, есть ли следующая страница
+        let end = start.saturating_add(page.limit.max(1)).saturating_add(1);
+        let operations = self.storage.range(start, end);
+        Ok(paginate((start..).zip(operations), page.limit))
+    }
+
+    /// Operations with their IDs, oldest first, that touch an account whose
+    /// name or metadata values contain every word of `query`, ignoring case.
+    /// Returns at most `limit` of them.
+    pub fn search_history(&self, query: &str, limit: usize) -> Vec<(usize, Operation)> {
+        let words: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+        // Текст для поиска собирается по каждому счету один раз
+        let texts: Vec<String> = self
+            .accounts()
+            .map(|(id, name)| {
+                let values = self.metadata.get(&id).into_iter().flat_map(|m| m.values());
+                let text: Vec<&str> = std::iter::once(name)
+                    .chain(values.map(String::as_str))
+                    .collect();
+                text.join(" ").to_lowercase()
+            })
+            .collect();
+
+        self.storage
+            .operations()
+            .enumerate()
+            .filter(|(_, operation)| {
+                let accounts: Vec<&str> = operation
+                    .accounts()
+                    .into_iter()
+                    .filter_map(|name| self.storage.account_id(name))
+                    .map(|id| texts[id].as_str())
+                    .collect();
+                words
+                    .iter()
+                    .all(|word| accounts.iter().any(|text| text.contains(word.as_str())))
+            })
+            .take(limit)
+            .collect()
+    }
+
+    /// Evaluates a history [`Query`]. A query about one account only looks
+    /// at the operations of that account.
+    pub fn query_history(&self, query: &str) -> Result<Vec<QueryRow>, BankError> {
+        let query: Query = query.parse().map_err(BankError::InvalidQuery)?;
+        let rows = match query.account() {
+            Some(name) => match self.storage.account_id(name) {
+                Some(id) => query.run(
+                    self.account_operations(id, 0)
+                        .map(|(_, timestamp, operation)| (timestamp, operation)),
+                ),
+                None => query.run(std::iter::empty()),
+            },
+            None => query.run(
+                (0..self.storage.history_len())
+                    .step_by(storage::PAGE_SIZE)
+                    .flat_map(|start| self.storage.entries(start, start + storage::PAGE_SIZE)),
+            ),
+        };
+        Ok(rows)
+    }
+
+    pub fn get_account_history(
+        &self,
+        account: impl Into<AccountRef>,
+    ) -> Result<Vec<Operation>, BankError> {
+        let id = self.resolve_account(&account.into())?;
+        Ok(self
+            .account_operations(id, 0)
+            .map(|(_, _, operation)| operation)
+            .collect())
+    }
+
+    /// Page of [`Bank::get_account_history`]; the cursor is an operation ID,
+    /// so the pages stay in place while the account gets new operations.
+    pub fn get_account_history_paged(
+        &self,
+        account: impl Into<AccountRef>,
+        page: &PageRequest,
+    ) -> Result<Page<Operation>, BankError> {
+        let id = self.resolve_account(&account.into())?;
+        let start = start_of(page)?;
+        let operations = self
+            .account_operations(id, start)
+            .map(|(operation_id, _, operation)| (operation_id, operation));
+        Ok(paginate(operations, page.limit))
+    }
+
+    /// Digest of the first `operations` operations (all by default); a longer
+    /// prefix than the history is cut to the history length.
+    pub fn get_history_digest(&self, operations: Option<usize>) -> HistoryDigest {
+        let operations = operations.map_or(self.storage.history_len(), |n| {
+            n.min(self.storage.history_len())
+        });
+        // От ближайшего хеша блока хешируются только операции после него
+        let hash = match operations / BLOCK_SIZE {
+            _ if operations == self.storage.history_len() => self.head,
+            block => {
+                let mut range = self.storage.range(block * BLOCK_SIZE, operations + 1);
+                // Переименование несет хеш истории перед ним, посчитанный
+                // еще по прежним именам
+                let saved = match range.pop() {
+                    Some(Operation::AnonymizeAccount { checkpoint, .. }) => from_hex(&checkpoint),
+                    _ => None,
+                };
+                saved.unwrap_or_else(|| {
+                    let previous = match block {
+                        0 => GENESIS,
+                        n => self.checkpoints[n - 1],
+                    };
+                    range
+                        .iter()
+                        .fold(previous, |hash, operation| chain(&hash, operation))
+                })
+            }
+        };
+        HistoryDigest {
+            operations,
+            digest: to_hex(&hash),
+        }
+    }
+
+    /// Builds the statement of `account` for the period `[from_ts, to_ts)`.
+    pub fn get_statement(
+        &self,
+        account: impl Into<AccountRef>,
+        from_ts: u64,
+        to_ts: u64,
+    ) -> Result<Statement, BankError> {
+        let id = self.resolve_account(&account.into())?;
+        let name = self.storage.account_name(id);
+
+        let mut opening_balance = 0i64;
+        let mut balance = 0i64;
+        let mut lines = Vec::new();
+        for (_, timestamp, operation) in self.account_operations(id, 0) {
+            if timestamp >= to_ts {
+                break;
+            }
+            balance += operation.balance_change(name);
+            if timestamp < from_ts {
+                opening_balance = balance;
+            } else {
+                lines.push(StatementLine {
+                    timestamp,
+                    operation,
+                    balance: balance as u32,
+                });
+            }
+        }
+
+        Ok(Statement {
+            account: name.to_string(),
+            from_ts,
+            to_ts,
+            opening_balance: opening_balance as u32,
+            lines,
+            closing_balance: balance as u32,
+        })
+    }
+
+    /// Replays the history into a scratch bank and lists every place where the
+    /// live balances, accounts or indices differ from what the history gives.
+    pub fn check_consistency(&self) -> ConsistencyReport {
+        let mut scratch = Bank::new();
+        scratch.enforce_limits = false;
+        let mut differences = Vec::new();
+        // Хеши до последнего переименования посчитаны по прежним именам
+        let mut checkpoint = 0;
+        for (index, operation) in self.storage.operations().enumerate() {
+            if matches!(operation, Operation::AnonymizeAccount { .. }) {
+                checkpoint = index;
+            }
+            if let Err(e) = scratch.apply_history(std::slice::from_ref(&operation)) {
+                differences.push(format!("operation {} can not be replayed: {:?}", index, e));
+                break;
+            }
+        }
+
+        for (id, name) in self.accounts() {
+            let replayed =
+                (id < scratch.storage.account_count()).then(|| scratch.storage.account_name(id));
+            match replayed {
+                Some(replayed) if replayed == name => {}
+                Some(replayed) => {
+                    differences.push(format!(
+                        "account #{} is {}, history gives {}",
+                        id, name, replayed
+                    ));
+                    continue;
+                }
+                None => {
+                    differences.push(format!("account {} is missing from the history", name));
+                    continue;
+                }
+            }
+            let (live, replayed) = (self.storage.balance(id), scratch.storage.balance(id));
+            if live != replayed {
+                differences.push(format!(
+                    "account {}: balance {}, history gives {}",
+                    name, live, replayed
+                ));
+            }
+            let live = (self.account_index.count(id), self.account_index.blocks(id));
+            let replayed = (
+                scratch.account_index.count(id),
+                scratch.account_index.blocks(id),
+            );
+            if live != replayed {
+                differences.push(format!(
+                    "account {}: index lists {} operations in blocks {:?}, history gives {} in {:?}",
+                    name, live.0, live.1, replayed.0, replayed.1
+                ));
+            }
+        }
+        for (_, name) in scratch.accounts().skip(self.storage.account_count()) {
+            differences.push(format!("account {} from the history does not exist", name));
+        }
+        // Блок, в котором было переименование, кончается уже после него
+        if let Some(block) = (checkpoint / BLOCK_SIZE..self.checkpoints.len())
+            .find(|&block| self.checkpoints.get(block) != scratch.checkpoints.get(block))
+        {
+            differences.push(format!(
+                "hash of block {} does not match the history",
+                block
+            ));
+        }
+        if self.head != scratch.head {
+            differences.push("hash of the history does not match it".to_string());
+        }
+
+        ConsistencyReport {
+            operations: self.storage.history_len(),
+            differences,
+        }
+    }
+
+    /// Appends `history` to the bank. The whole history is checked first, so
+    /// either every operation is applied or none is.
+    pub fn restore(&mut self, history: &[Operation]) -> Result<(), BankError> {
+        self.restore_with(history, &AtomicBool::new(false), |_| {})
+            .map(|_| ())
+    }
+
+    /// Same as [`Bank::restore`], but gives up with `BankError::Cancelled` if
+    /// `cancelled` is set while the history is checked. Once operations are
+    /// being applied the restore is no longer cancelled; `on_progress` hears
+    /// about every [`RESTORE_PROGRESS_INTERVAL`] operations applied and the end.
+    /// Returns the digest of the operations appended to the history, read
+    /// back from the storage, for the client to compare with `history`.
+    pub fn restore_with(
+        &mut self,
+        history: &[Operation],
+        cancelled: &AtomicBool,
+        on_progress: impl FnMut(RestoreProgress),
+    ) -> Result<HistoryDigest, BankError> {
+        // Переименование меняет имена счетов и в записанной истории, поэтому
+        // часть истории после него проверяется, когда оно уже применено
+        let parts: Vec<&[Operation]> = history
+            .split_inclusive(|operation| matches!(operation, Operation::AnonymizeAccount { .. }))
+            .collect();
+        self.validate_restore(parts.first().copied().unwrap_or_default(), 0)?;
+        let accounts = history
+            .iter()
+            .filter(|operation| matches!(operation, Operation::CreateAccount(_)))
+            .count();
+        self.check_capacity(accounts, history.len())?;
+
+        // Вся история сначала применяется к черновику со счетами банка: так
+        // ошибка в ее середине не оставляет банк восстановленным наполовину
+        self.scratch().apply_restore(&parts, cancelled, |_| {})?;
+
+        self.enforce_limits = false;
+        let start = self.storage.history_len();
+        let result = self.apply_restore(&parts, &AtomicBool::new(false), on_progress);
+        self.enforce_limits = true;
+        result?;
+
+        // Хешируется то, что действительно попало в хранилище
+        let end = self.storage.history_len();
+        let mut hash = GENESIS;
+        for page in (start..end).step_by(storage::PAGE_SIZE) {
+            for operation in self
+                .storage
+                .range(page, (page + storage::PAGE_SIZE).min(end))
+            {
+                hash = chain(&hash, &operation);
+            }
+        }
+        Ok(HistoryDigest {
+            operations: end - start,
+            digest: to_hex(&hash),
+        })
+    }
+
+    /// Applies the `parts` of a restored history, each checked once the
+    /// ones before it are applied.
+    fn apply_restore(
+        &mut self,
+        parts: &[&[Operation]],
+        cancelled: &AtomicBool,
+        mut on_progress: impl FnMut(RestoreProgress),
+    ) -> Result<(), BankError> {
+        let total: usize = parts.iter().map(|part| part.len()).sum();
+        let mut applied = 0;
+        let mut reported = 0;
+        for (index, part) in parts.iter().enumerate() {
+            if index > 0 {
+                self.validate_restore(part, applied)?;
+            }
+            for chunk in part.chunks(RESTORE_PROGRESS_INTERVAL) {
+                if cancelled.load(Ordering::Relaxed) {
+                    return Err(BankError::Cancelled);
+                }
+                self.apply_history(chunk)?;
+                applied += chunk.len();
+                if applied - reported >= RESTORE_PROGRESS_INTERVAL || applied == total {
+                    reported = applied;
+                    on_progress(RestoreProgress {
+                        applied,
+                        operation_id: self.storage.history_len() - 1,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Bank with the accounts, balances and holds of this one but none of
+    /// its history, to try a restore on without touching this bank.
+    fn scratch(&self) -> Bank {
+        let mut storage = MemoryStorage::default();
+        for id in 0..self.storage.account_count() {
+            storage.add_account(self.storage.account_name(id));
+            storage.set_balance(id, self.storage.balance(id));
+        }
+        Bank {
+            closed: self.closed.clone(),
+            reservations: self.reservations.clone(),
+            next_reservation_id: self.next_reservation_id,
+            held: self.held.clone(),
+            incoming: self.incoming.clone(),
+            pending: self.pending.clone(),
+            enforce_limits: false,
+            clock: self.clock.clone(),
+            ..Bank::with_storage(Box::new(storage))
+        }
+    }
+
+    /// Checks `history`, which starts at index `offset` of a restored
+    /// history, against the accounts of the bank. A closing
+    /// [`Operation::AnonymizeAccount`] is left out: it names the account
+    /// only as it is called once renamed.
+    fn validate_restore(&self, history: &[Operation], offset: usize) -> Result<(), BankError> {
+        let history = match history.split_last() {
+            Some((Operation::AnonymizeAccount { .. }, rest)) => rest,
+            _ => history,
+        };
+        // Для создаваемых подсчетов нужен и уже существующий родитель
+        let parents = history.iter().filter_map(|operation| match operation {
+            Operation::CreateAccount(account) => parent_account(account),
+            _ => None,
+        });
+        let balances = history
+            .iter()
+            .flat_map(Operation::accounts)
+            .chain(parents)
+            .filter_map(|name| {
+                let id = self.storage.account_id(name)?;
+                Some((name.to_string(), self.available_balance(id) as i64))
+            })
+            .collect();
+        validate_history_from(balances, history).map_err(|e| match e {
+            BankError::InvalidHistory { index, reason } => BankError::InvalidHistory {
+                index: offset + index,
+                reason,
+            },
+            e => e,
+        })
+    }
+
+    fn apply_history(&mut self, history: &[Operation]) -> Result<(), BankError> {
+        for operation in history {
+            match operation {
+                Operation::CreateAccount(account) => {
+                    self.create_account(account.clone())?;
+                }
+                Operation::IncreaseAccount(account, amount) => {
+                    self.increase_account(account.as_str(), *amount)?;
+                }
+                Operation::DecreaseAccount(account, amount) => {
+                    self.decrease_account(account.as_str(), *amount)?;
+                }
+                Operation::Transfer(from, to, amount) => {
+                    self.transfer(from.as_str(), to.as_str(), *amount)?;
+                }
+                // Резервирование живет только внутри операции: сохранять нечего
+                Operation::RemoteTransferOut { from, to, amount } => {
+                    let source = ReservationSource::Transfer(to.clone());
+                    let account = self.resolve_account(&from.as_str().into())?;
+                    let id = self.hold(account, *amount, ReservationKind::Debit, source)?;
+                    self.apply_reservation(id)?;
+                }
+                Operation::RemoteTransferIn { from, to, amount } => {
+                    let source = ReservationSource::Transfer(from.clone());
+                    let account = self.resolve_account(&to.as_str().into())?;
+                    let id = self.hold(account, *amount, ReservationKind::Credit, source)?;
+                    self.apply_reservation(id)?;
+                }
+                Operation::TransactionLeg {
+                    transaction,
+                    account,
+                    kind,
+                    amount,
+                } => {
+                    let source = ReservationSource::Transaction(transaction.clone());
+                    let account = self.resolve_account(&account.as_str().into())?;
+                    let id = self.hold(account, *amount, *kind, source)?;
+                    self.apply_reservation(id)?;
+                }
+                Operation::SetLimits { account, limits } => {
+                    self.set_account_limits(account.as_str(), limits.clone())?;
+                }
+                Operation::CloseAccount(account) => {
+                    self.close_account(account.as_str())?;
+                }
+                Operation::AnonymizeAccount {
+                    id,
+                    pseudonym,
+                    checkpoint,
+                } => {
+                    let id = self.resolve_account(&(*id).into())?;
+                    let (renamed, _) = self.anonymize(id, checkpoint.clone())?;
+                    if renamed != *pseudonym {
+                        return Err(BankError::InvalidHistory {
+                            index: self.storage.history_len() - 1,
+                            reason: format!("account {} was anonymized as {}", id, renamed),
+                        });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_zero_amount(&self, amount: u32) -> Result<(), BankError> {
+        if amount == 0 {
+            return Err(BankError::IncorrectAmount(amount));
+        }
+        Ok(())
+    }
+
+    fn check_outflow_limits(&self, account: AccountId, amount: u32) -> Result<(), BankError> {
+        if !self.enforce_limits {
+            return Ok(());
+        }
+        let name = self.storage.account_name(account);
+        if self.required_approvals.contains_key(&account) && !self.approved {
+            return Err(BankError::ApprovalRequired(format!(
+                "Transfers out of {} need several approvals",
+                name
+            )));
+        }
+        let held = self.held.get(&account).copied().unwrap_or(0) as u64;
+
+        for rule in self.velocity_rules(account) {
+            let since = self.clock.now().saturating_sub(rule.window_secs);
+            let spent = self
+                .outflows
+                .get(&account)
+                .map_or(0, |tracker| tracker.total_since(since));
+            if spent + held + amount as u64 > rule.max_amount as u64 {
+                return Err(BankError::LimitExceeded(format!(
+                    "Outflow of {} would exceed {} per {} seconds",
+                    name, rule.max_amount, rule.window_secs
+                )));
+            }
+        }
+
+        let Some(limits) = self.limits.get(&account) else {
+            return Ok(());
+        };
+
+        if let Some(max) = limits.max_withdrawal {
+            if amount > max {
+                return Err(BankError::LimitExceeded(format!(
+                    "Withdrawal of {} from {} exceeds the limit of {}",
+                    amount, name, max
+                )));
+            }
+        }
+
+        if let Some(max) = limits.max_daily_outflow {
+            let now = self.clock.now();
+            let day_start = now - now % SECONDS_PER_DAY;
+            let spent: u64 = self
+                .account_operations_rev(account)
+                .take_while(|(_, timestamp, _)| *timestamp >= day_start)
+                .map(|(_, _, operation)| (-operation.balance_change(name)).max(0) as u64)
+                .sum();
+            if spent + held + amount as u64 > max as u64 {
+                return Err(BankError::LimitExceeded(format!(
+                    "Daily outflow of {} would exceed the limit of {}",
+                    name, max
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn velocity_rules(&self, account: AccountId) -> impl Iterator<Item = &VelocityRule> {
+        let own = self.limits.get(&account).map(|limits| &limits.velocity);
+        self.velocity_rules.iter().chain(own.into_iter().flatten())
+    }
+
+    fn record_outflows(&mut self, operation: &Operation, timestamp: u64) {
+        for name in operation.accounts() {
+            let change = operation.balance_change(name);
+            let Some(id) = self.storage.account_id(name) else {
+                continue;
+            };
+            if change >= 0 {
+                continue;
+            }
+            let retention = self
+                .velocity_rules(id)
+                .map(|rule| rule.window_secs)
+                .max()
+                .unwrap_or(0);
+            self.outflows
+                .entry(id)
+                .or_default()
+                .record(timestamp, (-change) as u32, retention);
+        }
+    }
+
+    /// Balance `account` has after `amount` is added to `balance`, unless
+    /// it, with the credits reserved for the account beyond `own` of them,
+    /// does not fit in a balance. Checking the reserved credits keeps their
+    /// commit from overflowing later.
+    fn credited_balance(
+        &self,
+        account: AccountId,
+        balance: u32,
+        amount: u32,
+        own: u32,
+    ) -> Result<u32, BankError> {
+        let incoming = self.incoming.get(&account).copied().unwrap_or(0) - own as u64;
+        let credited = balance as u64 + amount as u64;
+        if credited + incoming > u32::MAX as u64 {
+            return Err(BankError::BalanceOverflow(
+                self.storage.account_name(account).to_string(),
+            ));
+        }
+        Ok(credited as u32)
+    }
+
+    fn available_balance(&self, account: AccountId) -> u32 {
+        self.storage.balance(account) - self.held.get(&account).copied().unwrap_or(0)
+    }
+
+    /// Account that reservation `id` holds funds on, if it is a debit.
+    pub fn debited_account(&self, id: ReservationId) -> Option<AccountId> {
+        self.reservations
+            .get(&id)
+            .filter(|reservation| reservation.kind == ReservationKind::Debit)
+            .map(|reservation| reservation.account)
+    }
+
+    fn take_reservation(&mut self, id: ReservationId) -> Result<Reservation, BankError> {
+        let reservation = self
+            .reservations
+            .remove(&id)
+            .ok_or(BankError::ReservationDoesNotExist(id))?;
+        match reservation.kind {
+            ReservationKind::Debit => {
+                *self.held.get_mut(&reservation.account).unwrap() -= reservation.amount;
+            }
+            ReservationKind::Credit => {
+                *self.incoming.get_mut(&reservation.account).unwrap() -= reservation.amount as u64;
+            }
+        }
+        Ok(reservation)
+    }
+
+    fn take_pending(&mut self, id: PendingTransferId) -> Result<Pending, BankError> {
+        let pending = self
+            .pending
+            .remove(&id)
+            .ok_or(BankError::PendingTransferDoesNotExist(id))?;
+        *self.held.get_mut(&pending.from).unwrap() -= pending.amount;
+        Ok(pending)
+    }
+
+    /// Same as [`Bank::resolve_account`], but fails for a closed account.
+    fn resolve_open_account(&self, account: &AccountRef) -> Result<AccountId, BankError> {
+        let id = self.resolve_account(account)?;
+        self.check_open(id)?;
+        Ok(id)
+    }
+
+    fn check_open(&self, account: AccountId) -> Result<(), BankError> {
+        if self.closed.contains(&account) {
+            return Err(BankError::AccountClosed(format!(
+                "Account {} is closed",
+                self.storage.account_name(account)
+            )));
+        }
+        Ok(())
+    }
+
+    /// ID of the account `account` refers to.
+    pub fn resolve_account(&self, account: &AccountRef) -> Result<AccountId, BankError> {
+        let id = match account {
+            AccountRef::Id(id) if *id < self.storage.account_count() => Some(*id),
+            AccountRef::Id(_) => None,
+            AccountRef::Name(name) => self.storage.account_id(name),
+        };
+        id.ok_or_else(|| {
+            BankError::AccountDoesNotExist(format!("Account {} does not exist", account))
+        })
+    }
+
+    fn append_history(&mut self, operation: Operation) -> usize {
+        let timestamp = self.clock.now();
+        self.record_outflows(&operation, timestamp);
+        if let Operation::TransactionLeg { transaction, .. } = &operation {
+            self.committed.insert(transaction.clone());
+        }
+        self.head = chain(&self.head, &operation);
+        let index = self.storage.history_len();
+        if (index + 1).is_multiple_of(BLOCK_SIZE) {
+            // Хеш блока пишется вместе с его последней операцией
+            self.checkpoints.push(self.head);
+            self.storage.set_checkpoint(index / BLOCK_SIZE, &self.head);
+        }
+        self.history_bytes += operation_bytes(&operation);
+        self.storage.append(operation, timestamp);
+        self.storage.history_len() - 1
+    }
+
+    // Счета с именами в порядке id
+    fn accounts(&self) -> impl Iterator<Item = (AccountId, &str)> {
+        (0..self.storage.account_count()).map(|id| (id, self.storage.account_name(id)))
+    }
+
+    fn matches(&self, id: AccountId, filter: AccountFilter) -> bool {
+        match filter {
+            AccountFilter::All => true,
+            AccountFilter::Active => !self.closed.contains(&id),
+            AccountFilter::Closed => self.closed.contains(&id),
+        }
+    }
+
+    // Версия счета - число операций в его истории: каждая из них меняет баланс
+    fn account_version(&self, account: AccountId) -> u64 {
+        self.account_index.count(account)
+    }
+
+    fn append_account_index(&mut self, account: AccountId, id: usize) {
+        self.account_index.push(account, id);
+    }
+
+    // Операции счета с номерами и временем от старых к новым, начиная с
+    // номера start; из хранилища читаются только блоки, где они есть
+    fn account_operations(
+        &self,
+        account: AccountId,
+        start: usize,
+    ) -> impl Iterator<Item = (usize, u64, Operation)> + '_ {
+        let name = self.storage.account_name(account);
+        let blocks = self.account_index.blocks(account);
+        let first = blocks.partition_point(|block| (block + 1) * BLOCK_SIZE <= start);
+        blocks[first..].iter().flat_map(move |block| {
+            let from = (block * BLOCK_SIZE).max(start);
+            self.block_operations(name, from, (block + 1) * BLOCK_SIZE)
+        })
+    }
+
+    // То же от новых к старым
+    fn account_operations_rev(
+        &self,
+        account: AccountId,
+    ) -> impl Iterator<Item = (usize, u64, Operation)> + '_ {
+        let name = self.storage.account_name(account);
+        let blocks = self.account_index.blocks(account);
+        blocks.iter().rev().flat_map(move |block| {
+            let from = block * BLOCK_SIZE;
+            self.block_operations(name, from, from + BLOCK_SIZE)
+                .into_iter()
+                .rev()
+        })
+    }
+
+    // Операции счета name среди операций start..end
+    fn block_operations(
+        &self,
+        name: &str,
+        start: usize,
+        end: usize,
+    ) -> Vec<(usize, u64, Operation)> {
+        self.storage
+            .entries(start, end)
+            .into_iter()
+            .zip(start..)
+            .filter(|((_, operation), _)| operation.accounts().contains(&name))
+            .map(|((timestamp, operation), id)| (id, timestamp, operation))
+            .collect()
+    }
+}
+
+// Оценка памяти операции истории: сама операция с именами счетов и метка
+// времени; индекс счетов и хеши блоков занимают память на блок, а не на
+// операцию
+fn operation_bytes(operation: &Operation) -> u64 {
+    let accounts: usize = operation.accounts().iter().map(|name| name.len()).sum();
+    (mem::size_of::<Operation>() + mem::size_of::<u64>() + accounts) as u64
+}
+
+// Позиция, с которой начинается страница
+fn start_of(page: &PageRequest) -> Result<usize, BankError> {
+    page.cursor.as_ref().map_or(Ok(0), Cursor::position)
+}
+
+// Первые limit элементов (хотя бы один, чтобы обход продвигался); курсор
+// указывает на позицию следующего
+fn paginate<T>(mut items: impl Iterator<Item = (usize, T)>, limit: usize) -> Page<T> {
+    let page = items
+        .by_ref()
+        .take(limit.max(1))
+        .map(|(_, item)| item)
+        .collect();
+    Page {
+        items: page,
+        next_cursor: items.next().map(|(position, _)| Cursor::at(position)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::TestClock;
+    use crate::history::History;
+
+    impl Bank {
+        fn get_history(&self) -> Vec<Operation> {
+            self.storage.operations().collect()
+        }
+
+        fn account_operation_ids(&self, account: &str) -> Vec<usize> {
+            let id = self.storage.account_id(account).unwrap();
+            self.account_operations(id, 0)
+                .map(|(id, _, _)| id)
+                .collect()
+        }
+    }
+
+    #[test]
+    fn create_bank() {
+        let b = Bank::new();
+        assert_eq!(0, b.storage.history_len());
+        assert_eq!(0, b.account_index.count(0));
+    }
+
+    #[test]
+    fn create_account() {
+        let mut b = Bank::new();
+        let _ = b.create_account("X".to_string());
+        assert_eq!(1, b.storage.history_len());
+        assert_eq!(1, b.account_index.count(0));
+    }
+
+    #[test]
+    fn create_account_twice() {
+        let mut b = Bank::new();
+        let _ = b.create_account("X".to_string());
+        let x = b.create_account("X".to_string());
+        assert!(x.is_err());
+    }
+
+    #[test]
+    fn create_account_returns_sequential_ids() {
+        let mut bank = Bank::new();
+        assert_eq!(0, bank.create_account("X".to_string()).unwrap());
+        assert_eq!(1, bank.create_account("Y".to_string()).unwrap());
+    }
+
+    #[test]
+    fn operations_by_account_id() {
+        let mut bank = Bank::new();
+        let x = bank.create_account("X".to_string()).unwrap();
+        let y = bank.create_account("Y".to_string()).unwrap();
+        assert!(bank.increase_account(x, 10).is_ok());
+        assert!(bank.transfer(x, "Y", 4).is_ok());
+        assert_eq!(6, bank.get_account_balance("X").unwrap());
+        assert_eq!(4, bank.get_account_balance(y).unwrap());
+        assert_eq!(
+            Operation::Transfer("X".to_string(), "Y".to_string(), 4),
+            *bank.get_history().last().unwrap()
+        );
+    }
+
+    #[test]
+    fn unknown_account_id() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let x = bank.increase_account(1, 10);
+        assert!(matches!(x, Err(BankError::AccountDoesNotExist(_))));
+    }
+
+    #[test]
+    fn increase_account() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let x = bank.increase_account("X".to_string(), 10);
+        assert!(x.is_ok());
+        let balance = bank.storage.balance(bank.storage.account_id("X").unwrap());
+        assert_eq!(10, balance);
+    }
+
+    #[test]
+    fn increase_account_zero() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let x = bank.increase_account("X".to_string(), 0);
+        assert!(x.is_err());
+    }
+
+    #[test]
+    fn increase_no_account() {
+        let mut bank = Bank::new();
+        let x = bank.increase_account("X".to_string(), 10);
+        assert!(x.is_err());
+    }
+
+    #[test]
+    fn get_account_balance() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let x = bank.get_account_balance("X".to_string());
+        assert!(x.is_ok());
+        assert_eq!(0, x.unwrap());
+    }
+
+    #[test]
+    fn get_no_account_balance() {
+        let bank = Bank::new();
+        let x = bank.get_account_balance("X".to_string());
+        assert!(x.is_err());
+    }
+
+    #[test]
+    fn decrease_from_no_account() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let x = bank.decrease_account("Y".to_string(), 5);
+        assert!(x.is_err());
+    }
+
+    #[test]
+    fn decrease_account() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.increase_account("X".to_string(), 10);
+        let x = bank.decrease_account("X".to_string(), 5);
+        assert!(x.is_ok());
+        let balance = bank.storage.balance(bank.storage.account_id("X").unwrap());
+        assert_eq!(5, balance);
+    }
+
+    #[test]
+    fn decrease_no_account() {
+        let mut bank = Bank::new();
+        let x = bank.decrease_account("X".to_string(), 5);
+        assert!(x.is_err());
+    }
+
+    #[test]
+    fn decrease_account_zero() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let x = bank.decrease_account("X".to_string(), 0);
+        assert!(x.is_err());
+    }
+
+    #[test]
+    fn decrease_account_too_much() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.increase_account("X".to_string(), 10);
+        let x = bank.decrease_account("X".to_string(), 20);
+        assert!(x.is_err());
+    }
+
+    #[test]
+    fn transfer() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.create_account("Y".to_string());
+        let _ = bank.increase_account("X".to_string(), 10);
+        let x = bank.transfer("X".to_string(), "Y".to_string(), 5);
+        assert!(x.is_ok());
+        let balance = bank.storage.balance(bank.storage.account_id("X").unwrap());
+        assert_eq!(5, balance);
+        let balance = bank.storage.balance(bank.storage.account_id("Y").unwrap());
+        assert_eq!(5, balance);
+    }
+
+    #[test]
+    fn transfer_zero() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.create_account("Y".to_string());
+        let _ = bank.increase_account("X".to_string(), 10);
+        let x = bank.transfer("X".to_string(), "Y".to_string(), 0);
+        assert!(x.is_err());
+    }
+
+    #[test]
+    fn transfer_to_self() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.increase_account("X".to_string(), 10);
+        let x = bank.transfer("X".to_string(), "X".to_string(), 5);
+        assert!(x.is_err());
+    }
+
+    #[test]
+    fn transfer_to_no_account() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.increase_account("X".to_string(), 10);
+        let x = bank.transfer("X".to_string(), "Y".to_string(), 5);
+        assert!(x.is_err());
+    }
+
+    #[test]
+    fn history_create_account() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+
+        let id = 0;
+        assert_eq!(
+            Operation::CreateAccount("X".to_string()),
+            *bank.get_history().get(id).unwrap()
+        );
+        assert_eq!(id, bank.account_operation_ids("X")[0]);
+    }
+
+    #[test]
+    fn history_increase_account() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.increase_account("X".to_string(), 10);
+
+        let id = 1;
+        assert_eq!(
+            Operation::IncreaseAccount("X".to_string(), 10),
+            *bank.get_history().get(id).unwrap()
+        );
+        assert_eq!(id, bank.account_operation_ids("X")[1]);
+    }
+
+    #[test]
+    fn history_decrease_account() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.increase_account("X".to_string(), 10);
+        let _ = bank.decrease_account("X".to_string(), 5);
+
+        let id = 2;
+        assert_eq!(
+            Operation::DecreaseAccount("X".to_string(), 5),
+            *bank.get_history().get(id).unwrap()
+        );
+        assert_eq!(id, bank.account_operation_ids("X")[2]);
+    }
+
+    #[test]
+    fn history_transfer() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.create_account("Y".to_string());
+        let _ = bank.increase_account("X".to_string(), 10);
+        let _ = bank.transfer("X".to_string(), "Y".to_string(), 5);
+
+        let id = 3;
+        assert_eq!(
+            Operation::Transfer("X".to_string(), "Y".to_string(), 5),
+            *bank.get_history().get(id).unwrap()
+        );
+        assert_eq!(id, bank.account_operation_ids("X")[2]);
+        assert_eq!(id, bank.account_operation_ids("Y")[1]);
+    }
+
+    #[test]
+    fn get_account_history() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.create_account("Y".to_string());
+        let _ = bank.increase_account("X".to_string(), 10);
+        let _ = bank.decrease_account("X".to_string(), 5);
+        let _ = bank.transfer("X".to_string(), "Y".to_string(), 5);
+        let history = bank.get_account_history("X".to_string()).unwrap();
+        assert_eq!(4, history.len());
+        assert_eq!(
+            Operation::CreateAccount("X".to_string()),
+            *history.first().unwrap()
+        );
+        assert_eq!(
+            Operation::IncreaseAccount("X".to_string(), 10),
+            *history.get(1).unwrap()
+        );
+        assert_eq!(
+            Operation::DecreaseAccount("X".to_string(), 5),
+            *history.get(2).unwrap()
+        );
+        assert_eq!(
+            Operation::Transfer("X".to_string(), "Y".to_string(), 5),
+            *history.get(3).unwrap()
+        );
+    }
+
+    #[test]
+    fn get_no_account_history() {
+        let bank = Bank::new();
+        let history = bank.get_account_history("X".to_string());
+        assert!(matches!(history, Err(BankError::AccountDoesNotExist(_))));
+    }
+
+    #[test]
+    fn restore() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.create_account("Y".to_string());
+        let _ = bank.increase_account("X".to_string(), 10);
+        let _ = bank.transfer("X".to_string(), "Y".to_string(), 5);
+
+        let mut new_bank = Bank::new();
+        assert!(new_bank.restore(&bank.get_history()).is_ok());
+        assert_eq!(4, new_bank.get_history().len());
+        assert_eq!(5, new_bank.get_account_balance("X".to_string()).unwrap());
+        assert_eq!(5, new_bank.get_account_balance("X".to_string()).unwrap());
+
+        assert_eq!(bank.get_history().len(), new_bank.get_history().len());
+    }
+
+    fn remote(account: &str) -> RemoteAccount {
+        RemoteAccount {
+            address: "127.0.0.1:7879".to_string(),
+            account: account.into(),
+        }
+    }
+
+    #[test]
+    fn prepared_transaction_survives_snapshot() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.create_account("Y".to_string());
+        let _ = bank.increase_account("X", 10);
+        let transaction = TransactionId {
+            coordinator: "coordinator".to_string(),
+            number: 0,
+        };
+        let legs = [
+            TransactionLeg {
+                account: remote("X"),
+                kind: ReservationKind::Debit,
+                amount: 4,
+            },
+            TransactionLeg {
+                account: remote("Y"),
+                kind: ReservationKind::Credit,
+                amount: 4,
+            },
+        ];
+        bank.prepare(transaction.clone(), &legs).unwrap();
+        let out = bank
+            .reserve("X", 1, ReservationKind::Debit, remote("Z"))
+            .unwrap();
+
+        let mut restored = Bank::from_snapshot(bank.snapshot());
+        assert_eq!(bank.snapshot(), restored.snapshot());
+        assert_eq!(5, restored.get_available_balance("X").unwrap());
+        restored.commit(&transaction).unwrap();
+        assert_eq!(4, restored.get_account_balance("Y").unwrap());
+        // Повторная фиксация находит транзакцию в индексе
+        restored.commit(&transaction).unwrap();
+        // Номера резервирований не выдаются заново
+        let next = restored
+            .reserve("X", 1, ReservationKind::Debit, remote("Z"))
+            .unwrap();
+        assert!(next > out);
+        restored.commit_reservation(out).unwrap();
+        assert_eq!(5, restored.get_account_balance("X").unwrap());
+
+        // Части уже в истории, а снятие резервов не сохранено
+        let _ = bank.commit(&transaction);
+        let mut snapshot = bank.snapshot();
+        snapshot.reservations = restored.snapshot().reservations;
+        snapshot.reservations.push(OpenReservation {
+            id: 0,
+            account: "X".to_string(),
+            amount: 4,
+            kind: ReservationKind::Debit,
+            source: ReservationSource::Transaction(transaction.clone()),
+        });
+        let reopened = Bank::from_snapshot(snapshot);
+        assert_eq!(5, reopened.get_available_balance("X").unwrap());
+    }
+
+    #[test]
+    fn reserve_debit_holds_funds() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.increase_account("X".to_string(), 10);
+        let id = bank
+            .reserve("X", 8, ReservationKind::Debit, remote("Y"))
+            .unwrap();
+        assert!(bank.decrease_account("X", 5).is_err());
+        assert_eq!(10, bank.get_account_balance("X").unwrap());
+
+        assert!(bank.commit_reservation(id).is_ok());
+        assert_eq!(2, bank.get_account_balance("X").unwrap());
+        assert_eq!(
+            Operation::RemoteTransferOut {
+                from: "X".to_string(),
+                to: remote("Y"),
+                amount: 8
+            },
+            *bank.get_history().last().unwrap()
+        );
+    }
+
+    #[test]
+    fn pending_transfer_holds_funds() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.create_account("Y".to_string());
+        let _ = bank.increase_account("X".to_string(), 10);
+        let first = bank.queue_transfer("X", "Y", 6).unwrap();
+        let second = bank.queue_transfer("X", "Y", 4).unwrap();
+        assert!(matches!(
+            bank.queue_transfer("X", "Y", 1),
+            Err(BankError::InsufficientFunds(1))
+        ));
+        assert_eq!(10, bank.get_account_balance("X").unwrap());
+        assert_eq!(2, bank.pending_transfers().len());
+
+        // Ждущие переводы переживают снимок вместе с удержанными средствами
+        let mut restored = Bank::from_snapshot(bank.snapshot());
+        assert_eq!(bank.snapshot(), restored.snapshot());
+        assert!(matches!(
+            restored.queue_transfer("X", "Y", 1),
+            Err(BankError::InsufficientFunds(1))
+        ));
+        restored.reject_transfer(second).unwrap();
+        assert!(restored.queue_transfer("X", "Y", 1).unwrap() > second);
+
+        bank.approve_transfer(first).unwrap();
+        assert_eq!(4, bank.get_account_balance("X").unwrap());
+        assert_eq!(6, bank.get_account_balance("Y").unwrap());
+        bank.reject_transfer(second).unwrap();
+        assert!(bank.decrease_account("X", 4).is_ok());
+        assert!(matches!(
+            bank.approve_transfer(second),
+            Err(BankError::PendingTransferDoesNotExist(_))
+        ));
+        assert!(bank.pending_transfers().is_empty());
+    }
+
+    #[test]
+    fn disputes() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.create_account("Y".to_string());
+        let _ = bank.increase_account("X", 10);
+        let _ = bank.transfer("X", "Y", 4);
+        let _ = bank.set_account_owners("Y", ["bob".to_string()].into());
+        assert!(matches!(
+            bank.open_dispute(4, "unknown".to_string(), Some("alice")),
+            Err(BankError::OperationDoesNotExist(4))
+        ));
+        assert!(matches!(
+            bank.open_dispute(1, "unknown".to_string(), Some("alice")),
+            Err(BankError::Forbidden(_))
+        ));
+        // Перевод может оспорить владелец любой из его сторон
+        let transfer = bank
+            .open_dispute(3, "twice".to_string(), Some("alice"))
+            .unwrap();
+        let created = bank.open_dispute(0, "no".to_string(), None).unwrap();
+        assert_eq!(2, bank.disputes(false).len());
+
+        assert!(matches!(
+            bank.resolve_dispute(created, true),
+            Err(BankError::NotReversible(0))
+        ));
+        bank.resolve_dispute(created, false).unwrap();
+        bank.resolve_dispute(transfer, true).unwrap();
+        assert_eq!(10, bank.get_account_balance("X").unwrap());
+        assert_eq!(
+            Operation::Transfer("Y".to_string(), "X".to_string(), 4),
+            *bank.get_history().last().unwrap()
+        );
+        assert!(bank.disputes(false).is_empty());
+        assert!(matches!(
+            bank.resolve_dispute(transfer, false),
+            Err(BankError::DisputeDoesNotExist(_))
+        ));
+
+        let restored = Bank::from_snapshot(bank.snapshot());
+        let resolved = restored.disputes(true);
+        assert_eq!(bank.disputes(true), resolved);
+        assert_eq!(Some(4), resolved[0].resolution.unwrap().reversal);
+        assert_eq!(None, resolved[1].resolution.unwrap().reversal);
+    }
+
+    #[test]
+    fn closed_account() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.create_account("Y".to_string());
+        let _ = bank.increase_account("X", 10);
+        assert!(matches!(
+            bank.close_account("X"),
+            Err(BankError::AccountNotEmpty(_))
+        ));
+        let _ = bank.transfer("X", "Y", 10);
+        bank.close_account("X").unwrap();
+
+        for result in [
+            bank.increase_account("X", 1).map(|_| ()),
+            bank.transfer("Y", "X", 1),
+            bank.close_account("X").map(|_| ()),
+            bank.create_account("X/savings".to_string()).map(|_| ()),
+        ] {
+            assert!(matches!(result, Err(BankError::AccountClosed(_))));
+        }
+        assert!(matches!(
+            bank.create_account("X".to_string()),
+            Err(BankError::AccountAlreadyExists(_))
+        ));
+        // История и баланс закрытого счета по-прежнему доступны
+        assert_eq!(4, bank.get_account_history("X").unwrap().len());
+        assert_eq!(0, bank.get_account_balance("X").unwrap());
+        assert_eq!(
+            vec![("Y".to_string(), 10)],
+            bank.list_accounts(AccountFilter::Active)
+        );
+        assert_eq!(
+            vec![("X".to_string(), 0)],
+            bank.list_accounts(AccountFilter::Closed)
+        );
+
+        let mut restored = Bank::new();
+        restored.restore(&bank.get_history()).unwrap();
+        let reopened = Bank::from_snapshot(bank.snapshot());
+        for mut bank in [restored, reopened] {
+            assert_eq!(2, bank.list_accounts(AccountFilter::All).len());
+            assert!(matches!(
+                bank.increase_account("X", 1),
+                Err(BankError::AccountClosed(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn anonymized_account() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.create_account("X/savings".to_string());
+        let _ = bank.create_account("Y".to_string());
+        let _ = bank.create_account("Xavier".to_string());
+        let _ = bank.increase_account("X", 10);
+        let _ = bank.transfer("X", "X/savings", 4);
+        let _ = bank.transfer("X", "Y", 1);
+        let digest = bank.get_history_digest(None);
+
+        let old_history = bank.get_history();
+        let (pseudonym, renames) = bank.anonymize_account("X").unwrap();
+        assert_eq!("anonymous-0", pseudonym);
+        assert_eq!(
+            Some("anonymous-0/savings"),
+            renames.get("X/savings").map(String::as_str)
+        );
+        assert!(matches!(
+            bank.get_account_balance("X"),
+            Err(BankError::AccountDoesNotExist(_))
+        ));
+        assert_eq!(5, bank.get_account_balance("anonymous-0").unwrap());
+        assert_eq!(4, bank.get_account_balance("anonymous-0/savings").unwrap());
+        assert_eq!(9, bank.get_subtree_balance("anonymous-0").unwrap());
+        assert_eq!(5, bank.get_account_history("anonymous-0").unwrap().len());
+        // Имя осталось только у счета, который лишь начинается так же
+        let history = bank.get_history();
+        assert_eq!(8, history.len());
+        assert!(history
+            .iter()
+            .flat_map(|operation| operation.accounts())
+            .all(|name| name != "X" && !name.starts_with("X/")));
+        assert_eq!(0, bank.get_account_balance("Xavier").unwrap());
+
+        // Цепочка не пересчитывается: выданные дайджесты остаются в силе
+        assert_eq!(digest, bank.get_history_digest(Some(7)));
+        assert!(bank.check_consistency().differences.is_empty());
+
+        // Реплика, получившая историю до переименования, повторяет его и
+        // приходит к той же цепочке; после него счет известен под псевдонимом
+        let _ = bank.transfer("anonymous-0", "Y", 1);
+        let mut replica = Bank::new();
+        replica.restore(&old_history).unwrap();
+        replica.restore(&bank.get_history()[7..]).unwrap();
+        assert_eq!(
+            bank.get_history_digest(None),
+            replica.get_history_digest(None)
+        );
+        assert_eq!(bank.snapshot(), replica.snapshot());
+        let mut restored = Bank::new();
+        restored
+            .restore(&[old_history.as_slice(), &bank.get_history()[7..]].concat())
+            .unwrap();
+        assert_eq!(
+            bank.get_history_digest(None),
+            restored.get_history_digest(None)
+        );
+        // Ошибка после переименования не оставляет ни его, ни операций до него
+        let mut partial = Bank::new();
+        let invalid = [
+            old_history.as_slice(),
+            &bank.get_history()[7..],
+            &[Operation::DecreaseAccount("anonymous-0".to_string(), 100)],
+        ]
+        .concat();
+        assert!(matches!(
+            partial.restore(&invalid),
+            Err(BankError::InvalidHistory { index: 9, .. })
+        ));
+        assert!(partial.get_history().is_empty());
+        assert_eq!(0, partial.account_count());
+
+        assert_eq!(
+            "anonymous-0/anonymous-1",
+            bank.anonymize_account("anonymous-0/savings").unwrap().0
+        );
+        let _ = bank.create_account("anonymous-2".to_string());
+        assert!(matches!(
+            bank.anonymize_account("Y"),
+            Err(BankError::AccountAlreadyExists(_))
+        ));
+        assert_eq!(2, bank.get_account_balance("Y").unwrap());
+    }
+
+    #[test]
+    fn paged_listings() {
+        let mut bank = Bank::new();
+        for name in ["X", "Y", "Z"] {
+            let _ = bank.create_account(name.to_string());
+        }
+        let _ = bank.increase_account("X", 1);
+        let _ = bank.transfer("X", "Y", 1);
+        let _ = bank.increase_account("X", 2);
+        bank.close_account("Z").unwrap();
+
+        let first = bank.get_history_paged(&PageRequest::first(4)).unwrap();
+        assert_eq!(bank.get_history_page(0, 4), first.items);
+        let next = PageRequest {
+            cursor: first.next_cursor,
+            limit: 4,
+        };
+        let last = bank.get_history_paged(&next).unwrap();
+        assert_eq!(bank.get_history_page(4, 4), last.items);
+        assert_eq!(None, last.next_cursor);
+
+        let first = bank
+            .get_account_history_paged("X", &PageRequest::first(2))
+            .unwrap();
+        assert_eq!(2, first.items.len());
+        // Новые операции счета не сдвигают уже выданные страницы
+        let _ = bank.increase_account("X", 3);
+        let next = PageRequest {
+            cursor: first.next_cursor,
+            limit: 2,
+        };
+        let history = bank.get_account_history("X").unwrap();
+        assert_eq!(
+            history[2..4],
+            bank.get_account_history_paged("X", &next).unwrap().items
+        );
+
+        let first = bank
+            .list_accounts_paged(AccountFilter::Active, &PageRequest::first(1))
+            .unwrap();
+        assert_eq!(vec![("X".to_string(), 5)], first.items);
+        let next = PageRequest {
+            cursor: first.next_cursor,
+            limit: 10,
+        };
+        let last = bank
+            .list_accounts_paged(AccountFilter::Active, &next)
+            .unwrap();
+        assert_eq!(vec![("Y".to_string(), 1)], last.items);
+        assert_eq!(None, last.next_cursor);
+
+        let invalid = PageRequest {
+            cursor: Some(serde_json::from_str("\"x\"").unwrap()),
+            limit: 1,
+        };
+        assert!(matches!(
+            bank.get_history_paged(&invalid),
+            Err(BankError::InvalidCursor(_))
+        ));
+    }
+
+    #[test]
+    fn reserve_debit_too_much() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.increase_account("X".to_string(), 10);
+        let x = bank.reserve("X", 20, ReservationKind::Debit, remote("Y"));
+        assert!(matches!(x, Err(BankError::InsufficientFunds(20))));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn release_reservation() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.increase_account("X".to_string(), 10);
+        let id = bank
+            .reserve("X", 10, ReservationKind::Debit, remote("Y"))
+            .unwrap();
+        assert!(bank.release_reservation(id).is_ok());
+        assert!(bank.decrease_account("X", 10).is_ok());
+        assert!(matches!(
+            bank.commit_reservation(id),
+            Err(BankError::ReservationDoesNotExist(_))
+        ));
+    }
 
     #[test]
-    fn create_bank() {
-        let b = Bank::new();
-        assert_eq!(0, b.history.len());
-        assert_eq!(0, b.account_operations_index.len());
+    fn restore_remote_transfers() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.increase_account("X".to_string(), 10);
+        let out = bank
+            .reserve("X", 4, ReservationKind::Debit, remote("Y"))
+            .unwrap();
+        let _ = bank.commit_reservation(out);
+        let incoming = bank
+            .reserve("X", 7, ReservationKind::Credit, remote("Z"))
+            .unwrap();
+        let _ = bank.commit_reservation(incoming);
+
+        let mut new_bank = Bank::new();
+        assert!(new_bank.restore(&bank.get_history()).is_ok());
+        assert_eq!(13, new_bank.get_account_balance("X").unwrap());
+        assert_eq!(bank.get_history(), new_bank.get_history());
     }
 
     #[test]
-    fn create_account() {
-        let mut b = Bank::new();
-        let _ = b.create_account("X".to_string());
-        assert_eq!(1, b.history.len());
-        assert_eq!(1, b.account_operations_index.len());
+    fn statement() {
+        let clock = TestClock::at(100);
+        let mut bank = Bank::new();
+        bank.set_clock(Arc::new(clock.clone()));
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.create_account("Y".to_string());
+        let _ = bank.increase_account("X", 10);
+        clock.set(200);
+        let _ = bank.transfer("X", "Y", 4);
+        clock.set(300);
+        let _ = bank.decrease_account("X", 1);
+
+        let statement = bank.get_statement("X", 150, 300).unwrap();
+        assert_eq!(10, statement.opening_balance);
+        assert_eq!(6, statement.closing_balance);
+        assert_eq!(
+            vec![StatementLine {
+                timestamp: 200,
+                operation: Operation::Transfer("X".to_string(), "Y".to_string(), 4),
+                balance: 6,
+            }],
+            statement.lines
+        );
+
+        let statement = bank.get_statement("Y", 0, 1000).unwrap();
+        assert_eq!(0, statement.opening_balance);
+        assert_eq!(2, statement.lines.len());
+        assert_eq!(4, statement.closing_balance);
     }
 
     #[test]
-    fn create_account_twice() {
-        let mut b = Bank::new();
-        let _ = b.create_account("X".to_string());
-        let x = b.create_account("X".to_string());
-        assert!(x.is_err());
+    fn statement_no_account() {
+        let bank = Bank::new();
+        assert!(bank.get_statement("X", 0, 1000).is_err());
     }
 
     #[test]
-    fn increase_account() {
+    fn history_page() {
         let mut bank = Bank::new();
         let _ = bank.create_account("X".to_string());
-        let x = bank.increase_account("X".to_string(), 10);
-        assert!(x.is_ok());
-        let balance = bank.balances.get(&"X".to_string()).unwrap();
-        assert_eq!(10, *balance);
+        let _ = bank.increase_account("X", 10);
+        let _ = bank.decrease_account("X", 5);
+        assert_eq!(2, bank.get_history_page(0, 2).len());
+        assert_eq!(
+            vec![Operation::DecreaseAccount("X".to_string(), 5)],
+            bank.get_history_page(2, 2)
+        );
+        assert!(bank.get_history_page(3, 2).is_empty());
+        assert!(bank.get_history_page(usize::MAX, usize::MAX).is_empty());
     }
 
     #[test]
-    fn increase_account_zero() {
+    fn from_snapshot() {
         let mut bank = Bank::new();
         let _ = bank.create_account("X".to_string());
-        let x = bank.increase_account("X".to_string(), 0);
-        assert!(x.is_err());
+        let _ = bank.create_account("Y".to_string());
+        let _ = bank.increase_account("X", 10);
+        let _ = bank.transfer("X", "Y", 4);
+
+        let mut replica = Bank::from_snapshot(bank.snapshot());
+        assert_eq!(6, replica.get_account_balance("X").unwrap());
+        assert_eq!(4, replica.get_account_balance(1).unwrap());
+        assert_eq!(
+            bank.get_account_history("Y").unwrap(),
+            replica.get_account_history("Y").unwrap()
+        );
+
+        let _ = bank.decrease_account("Y", 1);
+        assert!(replica.restore(&bank.get_history_page(4, 10)).is_ok());
+        assert_eq!(bank.get_history(), replica.get_history());
+        assert_eq!(3, replica.get_account_balance("Y").unwrap());
     }
 
     #[test]
-    fn increase_no_account() {
+    fn restore_inconsistent_history() {
         let mut bank = Bank::new();
-        let x = bank.increase_account("X".to_string(), 10);
-        assert!(x.is_err());
+        let history = vec![
+            Operation::CreateAccount("X".to_string()),
+            Operation::DecreaseAccount("X".to_string(), 5),
+            Operation::IncreaseAccount("X".to_string(), 10),
+        ];
+        let x = bank.restore(&history);
+        assert!(matches!(x, Err(BankError::InvalidHistory { index: 1, .. })));
+        assert!(bank.get_history().is_empty());
     }
 
     #[test]
-    fn get_account_balance() {
+    fn restore_on_top_of_existing_state() {
         let mut bank = Bank::new();
         let _ = bank.create_account("X".to_string());
-        let x = bank.get_account_balance("X".to_string());
-        assert!(x.is_ok());
-        assert_eq!(0, x.unwrap());
+        let _ = bank.increase_account("X", 10);
+        let x = bank.restore(&[Operation::DecreaseAccount("X".to_string(), 11)]);
+        assert!(matches!(x, Err(BankError::InvalidHistory { index: 0, .. })));
+        // Дайджест покрывает только добавленные операции
+        let restored = [Operation::DecreaseAccount("X".to_string(), 10)];
+        let digest = bank
+            .restore_with(&restored, &AtomicBool::new(false), |_| {})
+            .unwrap();
+        assert_eq!(protocol_crate::digest::history_digest(&restored), digest);
+        assert_eq!(0, bank.get_account_balance("X").unwrap());
+        let x = bank.restore(&[Operation::CreateAccount("X".to_string())]);
+        assert!(matches!(x, Err(BankError::InvalidHistory { index: 0, .. })));
     }
 
     #[test]
-    fn get_no_account_balance() {
-        let bank = Bank::new();
-        let x = bank.get_account_balance("X".to_string());
-        assert!(x.is_err());
+    fn max_withdrawal_limit() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.create_account("Y".to_string());
+        let _ = bank.increase_account("X", 100);
+        let limits = AccountLimits {
+            max_withdrawal: Some(10),
+            max_daily_outflow: None,
+            velocity: Vec::new(),
+        };
+        assert!(bank.set_account_limits("X", limits.clone()).is_ok());
+        assert_eq!(limits, bank.get_account_limits("X").unwrap());
+
+        let x = bank.decrease_account("X", 11);
+        assert!(matches!(x, Err(BankError::LimitExceeded(_))));
+        let x = bank.transfer("X", "Y", 11);
+        assert!(matches!(x, Err(BankError::LimitExceeded(_))));
+        assert!(bank.transfer("X", "Y", 10).is_ok());
+        assert!(bank.increase_account("X", 11).is_ok());
     }
 
     #[test]
-    fn decrease_from_no_account() {
+    fn max_daily_outflow_limit() {
+        let clock = TestClock::at(10 * SECONDS_PER_DAY + 100);
         let mut bank = Bank::new();
+        bank.set_clock(Arc::new(clock.clone()));
         let _ = bank.create_account("X".to_string());
-        let x = bank.decrease_account("Y".to_string(), 5);
-        assert!(x.is_err());
+        let _ = bank.create_account("Y".to_string());
+        let _ = bank.increase_account("X", 100);
+        let limits = AccountLimits {
+            max_withdrawal: None,
+            max_daily_outflow: Some(20),
+            velocity: Vec::new(),
+        };
+        let _ = bank.set_account_limits("X", limits);
+        assert!(bank.decrease_account("X", 15).is_ok());
+        let x = bank.transfer("X", "Y", 6);
+        assert!(matches!(x, Err(BankError::LimitExceeded(_))));
+        assert!(bank.transfer("X", "Y", 5).is_ok());
+
+        // Вчерашние списания не учитываются
+        clock.advance(SECONDS_PER_DAY);
+        assert!(bank.decrease_account("X", 20).is_ok());
+        assert_eq!(
+            11 * SECONDS_PER_DAY + 100,
+            bank.storage.timestamp(bank.storage.history_len() - 1)
+        );
     }
 
     #[test]
-    fn decrease_account() {
+    fn limits_survive_restore_and_snapshot() {
         let mut bank = Bank::new();
         let _ = bank.create_account("X".to_string());
-        let _ = bank.increase_account("X".to_string(), 10);
-        let x = bank.decrease_account("X".to_string(), 5);
-        assert!(x.is_ok());
-        let balance = bank.balances.get(&"X".to_string()).unwrap();
-        assert_eq!(5, *balance);
+        let _ = bank.increase_account("X", 100);
+        let limits = AccountLimits {
+            max_withdrawal: Some(10),
+            max_daily_outflow: Some(10),
+            velocity: vec![VelocityRule {
+                window_secs: 60,
+                max_amount: 10,
+            }],
+        };
+        let _ = bank.set_account_limits("X", limits.clone());
+        let _ = bank.decrease_account("X", 10);
+
+        let mut restored = Bank::new();
+        assert!(restored.restore(&bank.get_history()).is_ok());
+        assert_eq!(limits, restored.get_account_limits("X").unwrap());
+        let replica = Bank::from_snapshot(bank.snapshot());
+        assert_eq!(limits, replica.get_account_limits("X").unwrap());
     }
 
     #[test]
-    fn decrease_no_account() {
+    fn velocity_rules() {
+        let clock = TestClock::at(1_000_000);
         let mut bank = Bank::new();
-        let x = bank.decrease_account("X".to_string(), 5);
-        assert!(x.is_err());
+        bank.set_clock(Arc::new(clock.clone()));
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.create_account("Y".to_string());
+        let _ = bank.increase_account("X", 100);
+        let _ = bank.increase_account("Y", 100);
+        bank.set_velocity_rules(vec![VelocityRule {
+            window_secs: 3600,
+            max_amount: 30,
+        }]);
+        let limits = AccountLimits {
+            velocity: vec![VelocityRule {
+                window_secs: 3600,
+                max_amount: 10,
+            }],
+            ..AccountLimits::default()
+        };
+        let _ = bank.set_account_limits("X", limits);
+
+        assert!(bank.transfer("X", "Y", 10).is_ok());
+        let x = bank.decrease_account("X", 1);
+        assert!(matches!(x, Err(BankError::LimitExceeded(_))));
+
+        assert!(bank.decrease_account("Y", 25).is_ok());
+        let x = bank.reserve("Y", 6, ReservationKind::Debit, remote("Z"));
+        assert!(matches!(x, Err(BankError::LimitExceeded(_))));
+        assert!(bank
+            .reserve("Y", 5, ReservationKind::Debit, remote("Z"))
+            .is_ok());
+        let x = bank.decrease_account("Y", 1);
+        assert!(matches!(x, Err(BankError::LimitExceeded(_))));
+
+        // Окно скользит вместе со временем
+        clock.advance(3599);
+        let x = bank.decrease_account("X", 1);
+        assert!(matches!(x, Err(BankError::LimitExceeded(_))));
+        clock.advance(2);
+        assert!(bank.decrease_account("X", 10).is_ok());
     }
 
     #[test]
-    fn decrease_account_zero() {
+    fn account_metadata() {
         let mut bank = Bank::new();
         let _ = bank.create_account("X".to_string());
-        let x = bank.decrease_account("X".to_string(), 0);
-        assert!(x.is_err());
+        let set = |bank: &mut Bank, key: &str, value: Option<&str>| {
+            bank.set_account_metadata("X", key.to_string(), value.map(str::to_string))
+        };
+        assert!(set(&mut bank, "email", Some("x@example.com")).is_ok());
+        assert!(set(&mut bank, "type", Some("savings")).is_ok());
+        assert!(set(&mut bank, "type", None).is_ok());
+        assert!(bank
+            .set_account_metadata("Y", "type".to_string(), None)
+            .is_err());
+
+        let metadata = bank.get_account_metadata(0).unwrap();
+        assert_eq!(1, metadata.len());
+        assert_eq!("x@example.com", metadata["email"]);
+
+        let replica = Bank::from_snapshot(bank.snapshot());
+        assert_eq!(metadata, replica.get_account_metadata("X").unwrap());
     }
 
     #[test]
-    fn decrease_account_too_much() {
+    fn joint_account_owners() {
         let mut bank = Bank::new();
         let _ = bank.create_account("X".to_string());
-        let _ = bank.increase_account("X".to_string(), 10);
-        let x = bank.decrease_account("X".to_string(), 20);
-        assert!(x.is_err());
+        let x = AccountRef::from("X");
+        assert!(bank.check_owner(&x, None).is_ok());
+
+        let owners: BTreeSet<String> = ["alice".to_string(), "bob".to_string()].into();
+        assert!(bank.set_account_owners("X", owners.clone()).is_ok());
+        assert!(bank.check_owner(&x, Some("alice")).is_ok());
+        assert!(bank.check_owner(&x, Some("bob")).is_ok());
+        assert!(matches!(
+            bank.check_owner(&x, Some("eve")),
+            Err(BankError::Forbidden(_))
+        ));
+        assert!(matches!(
+            bank.check_owner(&x, None),
+            Err(BankError::Forbidden(_))
+        ));
+
+        let replica = Bank::from_snapshot(bank.snapshot());
+        assert_eq!(owners, replica.get_account_owners("X").unwrap());
+
+        assert!(bank.set_account_owners("X", BTreeSet::new()).is_ok());
+        assert!(bank.check_owner(&x, None).is_ok());
     }
 
     #[test]
-    fn transfer() {
+    fn sub_accounts() {
         let mut bank = Bank::new();
+        assert!(matches!(
+            bank.create_account("X/savings".to_string()),
+            Err(BankError::AccountDoesNotExist(_))
+        ));
         let _ = bank.create_account("X".to_string());
-        let _ = bank.create_account("Y".to_string());
-        let _ = bank.increase_account("X".to_string(), 10);
-        let x = bank.transfer("X".to_string(), "Y".to_string(), 5);
-        assert!(x.is_ok());
-        let balance = bank.balances.get(&"X".to_string()).unwrap();
-        assert_eq!(5, *balance);
-        let balance = bank.balances.get(&"Y".to_string()).unwrap();
-        assert_eq!(5, *balance);
+        assert!(bank.create_account("X/savings".to_string()).is_ok());
+        assert!(bank.create_account("X/savings/2024".to_string()).is_ok());
+        assert!(bank.create_account("X/card".to_string()).is_ok());
+        assert!(bank.create_account("XY".to_string()).is_ok());
+        assert!(matches!(
+            bank.create_account("X//card".to_string()),
+            Err(BankError::InvalidAccountName(_))
+        ));
+
+        let _ = bank.increase_account("X", 1);
+        let _ = bank.increase_account("X/savings", 10);
+        let _ = bank.increase_account("X/savings/2024", 100);
+        let _ = bank.increase_account("X/card", 1000);
+        let _ = bank.increase_account("XY", 10000);
+        assert_eq!(10, bank.get_account_balance("X/savings").unwrap());
+        assert_eq!(1111, bank.get_subtree_balance("X").unwrap());
+        assert_eq!(110, bank.get_subtree_balance("X/savings").unwrap());
+
+        let replica = Bank::from_snapshot(bank.snapshot());
+        assert_eq!(1111, replica.get_subtree_balance("X").unwrap());
+        let mut restored = Bank::new();
+        assert!(restored.restore(&bank.get_history()).is_ok());
+        assert_eq!(1111, restored.get_subtree_balance("X").unwrap());
     }
 
     #[test]
-    fn transfer_zero() {
+    fn account_tags() {
         let mut bank = Bank::new();
         let _ = bank.create_account("X".to_string());
         let _ = bank.create_account("Y".to_string());
-        let _ = bank.increase_account("X".to_string(), 10);
-        let x = bank.transfer("X".to_string(), "Y".to_string(), 0);
-        assert!(x.is_err());
+        let _ = bank.create_account("Z".to_string());
+        let _ = bank.increase_account("Z", 5);
+        let test: BTreeSet<String> = ["test".to_string()].into();
+        assert!(bank.set_account_tags("X", test.clone()).is_ok());
+        assert!(bank
+            .set_account_tags("Z", ["test".to_string(), "internal".to_string()].into())
+            .is_ok());
+        assert_eq!(test, bank.get_account_tags("X").unwrap());
+        assert!(bank.get_account_tags("Y").unwrap().is_empty());
+        assert_eq!(
+            vec![("X".to_string(), 0), ("Z".to_string(), 5)],
+            bank.find_accounts_by_tag("test")
+        );
+        assert!(bank.find_accounts_by_tag("customer").is_empty());
+
+        let replica = Bank::from_snapshot(bank.snapshot());
+        assert_eq!(
+            vec![("Z".to_string(), 5)],
+            replica.find_accounts_by_tag("internal")
+        );
+        assert!(bank.set_account_tags("X", BTreeSet::new()).is_ok());
+        assert_eq!(1, bank.find_accounts_by_tag("test").len());
     }
 
     #[test]
-    fn transfer_to_self() {
+    fn history_digest() {
+        use protocol_crate::digest::verify_history;
+
         let mut bank = Bank::new();
+        let empty = bank.get_history_digest(None);
         let _ = bank.create_account("X".to_string());
-        let _ = bank.increase_account("X".to_string(), 10);
-        let x = bank.transfer("X".to_string(), "X".to_string(), 5);
-        assert!(x.is_err());
+        let _ = bank.increase_account("X", 10);
+        let digest = bank.get_history_digest(None);
+        assert_eq!(2, digest.operations);
+        assert_ne!(empty.digest, digest.digest);
+        assert_eq!(empty, bank.get_history_digest(Some(0)));
+        assert_eq!(digest, bank.get_history_digest(Some(5)));
+        assert!(verify_history(&bank.get_history(), &digest).is_ok());
+
+        let replica = Bank::from_snapshot(bank.snapshot());
+        assert_eq!(digest, replica.get_history_digest(None));
+        let mut restored = Bank::new();
+        assert!(restored.restore(&bank.get_history()).is_ok());
+        assert_eq!(digest, restored.get_history_digest(None));
+
+        let _ = bank.decrease_account("X", 1);
+        assert_eq!(digest, bank.get_history_digest(Some(2)));
+        let mut tampered = bank.get_history()[..2].to_vec();
+        tampered[1] = Operation::IncreaseAccount("X".to_string(), 100);
+        assert!(verify_history(&tampered, &digest).is_err());
     }
 
     #[test]
-    fn transfer_to_no_account() {
+    fn check_consistency() {
         let mut bank = Bank::new();
         let _ = bank.create_account("X".to_string());
-        let _ = bank.increase_account("X".to_string(), 10);
-        let x = bank.transfer("X".to_string(), "Y".to_string(), 5);
-        assert!(x.is_err());
+        let _ = bank.create_account("Y".to_string());
+        let _ = bank.increase_account("X", 10);
+        let _ = bank.transfer("X", "Y", 4);
+        let report = bank.check_consistency();
+        assert_eq!(4, report.operations);
+        assert!(report.differences.is_empty(), "{:?}", report.differences);
+        let replica = Bank::from_snapshot(bank.snapshot());
+        assert!(replica.check_consistency().differences.is_empty());
+
+        bank.storage.set_balance(1, 5);
+        bank.account_index.push(0, 2);
+        let report = bank.check_consistency();
+        assert_eq!(2, report.differences.len(), "{:?}", report.differences);
+        assert!(report.differences[0].starts_with("account X: index"));
+        assert!(report.differences[1].contains("balance 5, history gives 4"));
     }
 
     #[test]
-    fn history_create_account() {
+    fn segmented_history() {
+        let dir = std::env::temp_dir().join(format!("bank-history-{}", std::process::id()));
         let mut bank = Bank::new();
         let _ = bank.create_account("X".to_string());
+        let _ = bank.create_account("Y".to_string());
+        let history = History::segmented(&dir, 2, 0, None, None).unwrap();
+        bank.set_storage(Box::new(MemoryStorage::with_history(history)));
+        for amount in 1..=5 {
+            let _ = bank.increase_account("X", amount);
+        }
+        let _ = bank.transfer("X", "Y", 4);
 
-        let id = 0;
+        assert_eq!(8, bank.history_len());
+        assert_eq!(7, bank.get_account_history("X").unwrap().len());
         assert_eq!(
-            Operation::CreateAccount("X".to_string()),
-            *bank.get_history().get(id).unwrap()
+            vec![Operation::IncreaseAccount("X".to_string(), 2)],
+            bank.get_history_page(3, 1)
         );
+        assert!(bank.check_consistency().differences.is_empty());
+        let restored = Bank::from_snapshot(bank.snapshot());
+        assert_eq!(bank.get_history(), restored.get_history());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn cancellation() {
+        let mut bank = Bank::new();
+        let history = vec![
+            Operation::CreateAccount("X".to_string()),
+            Operation::IncreaseAccount("X".to_string(), 10),
+        ];
+        let cancelled = AtomicBool::new(true);
+        assert!(matches!(
+            bank.restore_with(&history, &cancelled, |_| {}),
+            Err(BankError::Cancelled)
+        ));
+        assert_eq!(0, bank.history_len());
+
+        bank.restore(&history).unwrap();
+        assert!(matches!(
+            bank.get_history_cancellable(&cancelled),
+            Err(BankError::Cancelled)
+        ));
         assert_eq!(
-            id,
-            *bank
-                .account_operations_index
-                .get("X")
-                .unwrap()
-                .get(0)
+            history,
+            bank.get_history_cancellable(&AtomicBool::new(false))
                 .unwrap()
         );
     }
 
     #[test]
-    fn history_increase_account() {
+    fn batch() {
         let mut bank = Bank::new();
         let _ = bank.create_account("X".to_string());
-        let _ = bank.increase_account("X".to_string(), 10);
+        let _ = bank.create_account("Y".to_string());
+        let deposit = |amount| BatchOperation::Deposit {
+            account: "X".into(),
+            amount,
+        };
+        let transfer = |amount| BatchOperation::Transfer {
+            from: "X".into(),
+            to: "Y".into(),
+            amount,
+        };
 
-        let id = 1;
-        assert_eq!(
-            Operation::IncreaseAccount("X".to_string(), 10),
-            *bank.get_history().get(id).unwrap()
-        );
+        // Списание покрыто поступлением из того же пакета
         assert_eq!(
-            id,
-            *bank
-                .account_operations_index
-                .get("X")
-                .unwrap()
-                .get(1)
-                .unwrap()
+            vec![2, 3],
+            bank.batch(&[deposit(10), transfer(10)]).unwrap()
         );
+        assert_eq!(10, bank.get_account_balance("Y").unwrap());
+
+        let failed = bank.batch(&[deposit(5), transfer(3), transfer(3)]);
+        assert!(matches!(
+            failed,
+            Err(BankError::BatchFailed { index: 2, error }) if matches!(*error, BankError::InsufficientFunds(3))
+        ));
+        assert_eq!(0, bank.get_account_balance("X").unwrap());
+        assert_eq!(4, bank.history_len());
+
+        // Лимит на списание проверяется с учетом всего пакета
+        let limits = AccountLimits {
+            max_daily_outflow: Some(8),
+            ..Default::default()
+        };
+        bank.set_account_limits("Y", limits).unwrap();
+        let withdraw = |amount| BatchOperation::Withdraw {
+            account: "Y".into(),
+            amount,
+        };
+        assert!(matches!(
+            bank.batch(&[withdraw(5), withdraw(5)]),
+            Err(BankError::BatchFailed { index: 1, .. })
+        ));
+        assert_eq!(10, bank.get_account_balance("Y").unwrap());
+        assert!(bank.check_consistency().differences.is_empty());
     }
 
     #[test]
-    fn history_decrease_account() {
+    fn capacity() {
         let mut bank = Bank::new();
-        let _ = bank.create_account("X".to_string());
-        let _ = bank.increase_account("X".to_string(), 10);
-        let _ = bank.decrease_account("X".to_string(), 5);
+        bank.set_capacity(Some(2), Some(4));
+        bank.create_account("X".to_string()).unwrap();
+        bank.create_account("Y".to_string()).unwrap();
+        let exceeded = |expected: &'static str, max: usize| {
+            move |error: BankError| {
+                matches!(error, BankError::CapacityExceeded { resource, limit }
+                    if resource == expected && limit == max)
+            }
+        };
+        assert!(bank
+            .create_account("Z".to_string())
+            .is_err_and(exceeded("accounts", 2)));
+        bank.increase_account("X", 10).unwrap();
 
-        let id = 2;
+        // Пакет, который не помещается, не применяется целиком
+        let deposits = vec![
+            BatchOperation::Deposit {
+                account: "Y".into(),
+                amount: 1,
+            };
+            3
+        ];
+        assert!(bank.batch(&deposits).is_err_and(exceeded("history", 4)));
+        bank.transfer("X", "Y", 4).unwrap();
+        assert!(bank
+            .decrease_account("X", 1)
+            .is_err_and(exceeded("history", 4)));
+        assert!(bank
+            .restore(&[Operation::IncreaseAccount("X".to_string(), 1)])
+            .is_err_and(exceeded("history", 4)));
         assert_eq!(
-            Operation::DecreaseAccount("X".to_string(), 5),
-            *bank.get_history().get(id).unwrap()
+            (10 - 4, 4),
+            (bank.get_account_balance("X").unwrap(), bank.history_len())
         );
+
+        let stats = bank.stats();
         assert_eq!(
-            id,
-            *bank
-                .account_operations_index
-                .get("X")
-                .unwrap()
-                .get(2)
-                .unwrap()
+            (2, 4, Some(2), Some(4)),
+            (
+                stats.accounts,
+                stats.operations,
+                stats.max_accounts,
+                stats.max_history
+            )
         );
+        assert!(stats.account_bytes > 0);
+        // Оценка не зависит от того, откуда банк поднят
+        let reopened = Bank::from_snapshot(bank.snapshot());
+        assert_eq!(stats.history_bytes, reopened.stats().history_bytes);
+
+        // Снятые ограничения снова пускают банк расти
+        bank.set_capacity(None, None);
+        bank.create_account("Z".to_string()).unwrap();
+        assert!(bank.stats().history_bytes > stats.history_bytes);
     }
 
     #[test]
-    fn history_transfer() {
+    fn bulk_transfer() {
         let mut bank = Bank::new();
-        let _ = bank.create_account("X".to_string());
-        let _ = bank.create_account("Y".to_string());
-        let _ = bank.increase_account("X".to_string(), 10);
-        let _ = bank.transfer("X".to_string(), "Y".to_string(), 5);
+        for name in ["payer", "X", "Y", "closed"] {
+            let _ = bank.create_account(name.to_string());
+        }
+        let _ = bank.increase_account("payer", 10);
+        let _ = bank.close_account("closed");
+        let payees = |x| {
+            vec![
+                ("X".into(), x),
+                ("nobody".into(), 100),
+                ("closed".into(), 100),
+                ("payer".into(), 100),
+                ("Y".into(), 0),
+                ("Y".into(), 3),
+            ]
+        };
 
-        let id = 3;
-        assert_eq!(
-            Operation::Transfer("X".to_string(), "Y".to_string(), 5),
-            *bank.get_history().get(id).unwrap()
-        );
+        // Счета, которым платить нельзя, не входят в сумму
+        let results = bank.bulk_transfer(&"payer".into(), &payees(5)).unwrap();
+        assert!(matches!(results[0], Ok(6)));
+        assert!(matches!(results[1], Err(BankError::AccountDoesNotExist(_))));
+        assert!(matches!(results[2], Err(BankError::AccountClosed(_))));
+        assert!(matches!(results[3], Err(BankError::TransferToMyself)));
+        assert!(matches!(results[4], Err(BankError::IncorrectAmount(0))));
+        assert!(matches!(results[5], Ok(7)));
+        // Каждому получателю - свой перевод
         assert_eq!(
-            id,
-            *bank
-                .account_operations_index
-                .get("X")
-                .unwrap()
-                .get(2)
-                .unwrap()
+            vec![
+                Operation::Transfer("payer".to_string(), "X".to_string(), 5),
+                Operation::Transfer("payer".to_string(), "Y".to_string(), 3),
+            ],
+            bank.get_history_page(6, 2)
         );
+        assert_eq!(2, bank.get_account_balance("payer").unwrap());
+        assert_eq!(5, bank.get_account_balance("X").unwrap());
+        assert_eq!(3, bank.get_account_balance("Y").unwrap());
+
+        // Не хватает на всех - не платится никому
+        assert!(matches!(
+            bank.bulk_transfer(&"payer".into(), &payees(1)),
+            Err(BankError::InsufficientFunds(4))
+        ));
+        assert_eq!(8, bank.history_len());
+        assert!(bank.check_consistency().differences.is_empty());
+    }
+
+    #[test]
+    fn balance_overflow() {
+        let mut bank = Bank::new();
+        for name in ["X", "Y", "full"] {
+            let _ = bank.create_account(name.to_string());
+        }
+        let _ = bank.increase_account("full", u32::MAX - 1);
+        let _ = bank.increase_account("X", 10);
+        assert!(matches!(
+            bank.increase_account("full", 2),
+            Err(BankError::BalanceOverflow(account)) if account == "full"
+        ));
+        assert!(matches!(
+            bank.transfer("X", "full", 2),
+            Err(BankError::BalanceOverflow(_))
+        ));
+
+        // Переполнение одного получателя отменяет всю выплату
+        let payees = [("Y".into(), 1), ("full".into(), 1), ("full".into(), 1)];
+        assert!(matches!(
+            bank.bulk_transfer(&"X".into(), &payees),
+            Err(BankError::BalanceOverflow(_))
+        ));
+        assert_eq!(10, bank.get_account_balance("X").unwrap());
+        assert_eq!(0, bank.get_account_balance("Y").unwrap());
+        assert_eq!(5, bank.history_len());
+        bank.transfer("X", "full", 1).unwrap();
+        assert_eq!(u32::MAX, bank.get_account_balance("full").unwrap());
+
+        // Зарезервированные зачисления учитываются до фиксации
+        let credit = bank
+            .reserve("Y", u32::MAX - 1, ReservationKind::Credit, remote("Z"))
+            .unwrap();
+        assert!(matches!(
+            bank.reserve("Y", 2, ReservationKind::Credit, remote("Z")),
+            Err(BankError::BalanceOverflow(_))
+        ));
+        assert!(matches!(
+            bank.transfer("X", "Y", 2),
+            Err(BankError::BalanceOverflow(_))
+        ));
+        bank.transfer("X", "Y", 1).unwrap();
+        bank.commit_reservation(credit).unwrap();
+        assert_eq!(u32::MAX, bank.get_account_balance("Y").unwrap());
+        assert!(bank.check_consistency().differences.is_empty());
+    }
+
+    #[test]
+    fn multi_signature() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("treasury".to_string());
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.increase_account("treasury", 10);
+        let owners = BTreeSet::from(["alice".to_string(), "bob".to_string()]);
+        bank.set_account_owners("treasury", owners).unwrap();
+        bank.set_required_approvals("treasury", 2).unwrap();
+        assert!(matches!(
+            bank.transfer("treasury", "X", 5),
+            Err(BankError::ApprovalRequired(_))
+        ));
+        assert!(matches!(
+            bank.propose_transfer("treasury", "X", 5, Some("mallory")),
+            Err(BankError::Forbidden(_))
+        ));
+
+        let proposal = bank
+            .propose_transfer("treasury", "X", 20, Some("alice"))
+            .unwrap();
         assert_eq!(
-            id,
-            *bank
-                .account_operations_index
-                .get("Y")
-                .unwrap()
-                .get(1)
-                .unwrap()
+            (1, 2, None),
+            (proposal.approvals, proposal.required, proposal.operation)
         );
+        assert!(matches!(
+            bank.approve_proposal(proposal.id, Some("alice")),
+            Err(BankError::AlreadyApproved(_))
+        ));
+        // Перевод не прошел: одобрение не засчитано, его можно дать снова
+        assert!(matches!(
+            bank.approve_proposal(proposal.id, Some("bob")),
+            Err(BankError::InsufficientFunds(20))
+        ));
+        let _ = bank.increase_account("treasury", 10);
+        let approved = bank.approve_proposal(proposal.id, Some("bob")).unwrap();
+        assert_eq!(Some(bank.history_len() - 1), approved.operation);
+        assert_eq!(20, bank.get_account_balance("X").unwrap());
+        assert!(matches!(
+            bank.approve_proposal(proposal.id, Some("bob")),
+            Err(BankError::ProposalDoesNotExist(_))
+        ));
+
+        // Требование и начатые предложения переживают снимок
+        let _ = bank.increase_account("treasury", 5);
+        let open = bank
+            .propose_transfer("treasury", "X", 2, Some("alice"))
+            .unwrap();
+        let mut restored = Bank::from_snapshot(bank.snapshot());
+        assert_eq!(bank.snapshot(), restored.snapshot());
+        assert!(matches!(
+            restored.decrease_account("treasury", 1),
+            Err(BankError::ApprovalRequired(_))
+        ));
+        assert!(matches!(
+            restored.approve_proposal(open.id, Some("alice")),
+            Err(BankError::AlreadyApproved(_))
+        ));
+        let approved = restored.approve_proposal(open.id, Some("bob")).unwrap();
+        assert!(approved.operation.is_some());
+        let next = restored
+            .propose_transfer("treasury", "X", 1, Some("alice"))
+            .unwrap();
+        assert!(next.id > open.id);
+        restored.set_required_approvals("treasury", 1).unwrap();
+        restored.decrease_account("treasury", 1).unwrap();
     }
 
     #[test]
-    fn get_account_history() {
+    fn versions() {
         let mut bank = Bank::new();
         let _ = bank.create_account("X".to_string());
         let _ = bank.create_account("Y".to_string());
-        let _ = bank.increase_account("X".to_string(), 10);
-        let _ = bank.decrease_account("X".to_string(), 5);
-        let _ = bank.transfer("X".to_string(), "Y".to_string(), 5);
-        let history = bank.get_account_history("X".to_string()).unwrap();
-        assert_eq!(4, history.len());
+        let _ = bank.increase_account("X", 10);
+        let read = bank.get_versioned_balance("X").unwrap();
         assert_eq!(
-            Operation::CreateAccount("X".to_string()),
-            *history.get(0).unwrap()
+            VersionedBalance {
+                balance: 10,
+                version: 2
+            },
+            read
         );
+
+        bank.transfer_if("X", "Y", 4, read.version).unwrap();
+        // Второй перевод по той же прочитанной версии уже конфликтует
+        assert!(matches!(
+            bank.transfer_if("X", "Y", 4, read.version),
+            Err(BankError::Conflict {
+                expected: 2,
+                actual: 3
+            })
+        ));
+        assert_eq!(6, bank.get_account_balance("X").unwrap());
+        // Поступление тоже меняет версию получателя
+        assert_eq!(2, bank.get_versioned_balance("Y").unwrap().version);
+    }
+
+    #[test]
+    fn minimum_balance() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.increase_account("X", 10);
+        assert!(bank.decrease_if_balance_at_least("X", 4, 6).is_ok());
+        assert!(matches!(
+            bank.decrease_if_balance_at_least("X", 1, 6),
+            Err(BankError::BelowMinimumBalance { min_after: 6 })
+        ));
+        assert_eq!(6, bank.get_account_balance("X").unwrap());
+        assert!(matches!(
+            bank.decrease_if_balance_at_least("X", 0, 0),
+            Err(BankError::IncorrectAmount(0))
+        ));
+    }
+
+    #[test]
+    fn find_accounts() {
+        let mut bank = Bank::new();
+        for (name, amount) in [("X", 5), ("Y", 50), ("Z", 500)] {
+            let _ = bank.create_account(name.to_string());
+            let _ = bank.increase_account(name, amount);
+        }
         assert_eq!(
-            Operation::IncreaseAccount("X".to_string(), 10),
-            *history.get(1).unwrap()
+            vec![("Y".to_string(), 50)],
+            bank.find_accounts(Some(50), Some(499))
         );
+        assert_eq!(2, bank.find_accounts(Some(6), None).len());
+        assert_eq!(3, bank.find_accounts(None, None).len());
+        assert!(bank.find_accounts(Some(10), Some(5)).is_empty());
+    }
+
+    #[test]
+    fn top_accounts() {
+        let mut bank = Bank::new();
+        for (name, amount) in [("X", 5), ("Y", 50), ("Z", 50), ("W", 7)] {
+            let _ = bank.create_account(name.to_string());
+            let _ = bank.increase_account(name, amount);
+        }
         assert_eq!(
-            Operation::DecreaseAccount("X".to_string(), 5),
-            *history.get(2).unwrap()
+            vec![
+                ("Y".to_string(), 50),
+                ("Z".to_string(), 50),
+                ("W".to_string(), 7)
+            ],
+            bank.top_accounts(3)
         );
+        assert_eq!(4, bank.top_accounts(10).len());
+        assert!(bank.top_accounts(0).is_empty());
+    }
+
+    #[test]
+    fn search_history() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("Alice".to_string());
+        let _ = bank.create_account("Landlord".to_string());
+        let _ = bank.increase_account("Alice", 100);
+        bank.set_account_metadata(
+            "Landlord",
+            "purpose".to_string(),
+            Some("Monthly RENT".to_string()),
+        )
+        .unwrap();
+        let _ = bank.transfer("Alice", "Landlord", 30);
+        let _ = bank.transfer("Alice", "Landlord", 30);
+
+        let found = bank.search_history("rent", 10);
         assert_eq!(
-            Operation::Transfer("X".to_string(), "Y".to_string(), 5),
-            *history.get(3).unwrap()
+            vec![1, 3, 4],
+            found.iter().map(|(id, _)| *id).collect::<Vec<_>>()
         );
+        // Все слова запроса должны найтись, каждое в любом из счетов операции
+        assert_eq!(2, bank.search_history("alice monthly", 10).len());
+        assert_eq!(1, bank.search_history("alice monthly", 1).len());
+        assert!(bank.search_history("alice bob", 10).is_empty());
     }
 
     #[test]
-    fn get_no_account_history() {
-        let bank = Bank::new();
-        let history = bank.get_account_history("X".to_string());
-        assert!(history.is_none());
+    fn balance_at() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.create_account("Y".to_string());
+        // Баланс X до каждой операции: до двух созданий и первого пополнения 0
+        let mut expected = vec![0, 0, 0];
+        for amount in 1..=6 {
+            let _ = bank.increase_account("X", amount * 10);
+            let _ = bank.transfer("X", "Y", amount);
+            expected.push(expected.last().unwrap() + amount * 10);
+            expected.push(expected.last().unwrap() - amount);
+        }
+        // И пересчет вперед, и пересчет назад дают баланс до каждой операции
+        for (operation_id, balance) in expected.iter().enumerate() {
+            assert_eq!(*balance, bank.get_balance_at("X", operation_id).unwrap());
+        }
+        assert_eq!(189, bank.get_balance_at("X", usize::MAX).unwrap());
+        assert_eq!(21, bank.get_balance_at("Y", 100).unwrap());
+        assert!(bank.get_balance_at("Z", 0).is_err());
     }
 
     #[test]
-    fn restore() {
+    fn history_blocks() {
         let mut bank = Bank::new();
         let _ = bank.create_account("X".to_string());
         let _ = bank.create_account("Y".to_string());
-        let _ = bank.increase_account("X".to_string(), 10);
-        let _ = bank.transfer("X".to_string(), "Y".to_string(), 5);
-
-        let mut new_bank = Bank::new();
-        let _ = new_bank.restore(bank.get_history());
-        assert_eq!(4, new_bank.get_history().len());
-        assert_eq!(5, new_bank.get_account_balance("X".to_string()).unwrap());
-        assert_eq!(5, new_bank.get_account_balance("X".to_string()).unwrap());
+        // X есть в каждом блоке, Y только в первом и третьем
+        for _ in 0..2 * BLOCK_SIZE {
+            let _ = bank.increase_account("X", 1);
+        }
+        let _ = bank.transfer("X", "Y", 5);
+        let history = bank.get_history();
+        assert_eq!(2 * BLOCK_SIZE + 3, history.len());
+        assert_eq!(2, bank.checkpoints.len());
+        assert_eq!(&[0, 1, 2], bank.account_index.blocks(0));
+        assert_eq!(&[0, 2], bank.account_index.blocks(1));
 
-        assert_eq!(bank.get_history().len(), new_bank.get_history().len());
+        // Дайджест любого префикса совпадает с посчитанным по всей истории
+        for n in [
+            0,
+            1,
+            BLOCK_SIZE - 1,
+            BLOCK_SIZE,
+            BLOCK_SIZE + 7,
+            2 * BLOCK_SIZE,
+            history.len(),
+        ] {
+            assert_eq!(
+                protocol_crate::digest::history_digest(&history[..n]),
+                bank.get_history_digest(Some(n))
+            );
+        }
+        assert_eq!(2, bank.get_account_history("Y").unwrap().len());
+        assert_eq!(vec![1, 2 * BLOCK_SIZE + 2], bank.account_operation_ids("Y"));
+        assert_eq!(
+            BLOCK_SIZE as u32,
+            bank.get_balance_at("X", BLOCK_SIZE + 2).unwrap()
+        );
+        let page = bank
+            .get_account_history_paged(
+                "X",
+                &PageRequest {
+                    cursor: Some(Cursor::at(BLOCK_SIZE - 1)),
+                    limit: 3,
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            vec![Operation::IncreaseAccount("X".to_string(), 1); 3],
+            page.items
+        );
+        assert_eq!(Some(Cursor::at(BLOCK_SIZE + 2)), page.next_cursor);
+        assert!(bank.check_consistency().differences.is_empty());
     }
 }
@@ -0,0 +1,30 @@
+use std::io;
+use std::net::TcpListener;
+use std::time::Duration;
+
+use clap::Parser;
+
+use server::GraphqlGateway;
+
+#[derive(Parser, Debug)]
+#[command(name = "bank-graphql")]
+#[command(version = "1.0")]
+#[command(about = "GraphQL-шлюз к банковскому серверу для панелей мониторинга")]
+struct Args {
+    /// Адрес, на котором принимать HTTP-запросы
+    #[arg(long, default_value = "127.0.0.1:7879")]
+    listen: String,
+    /// Адрес банковского сервера
+    #[arg(long, default_value = "127.0.0.1:7878")]
+    server: String,
+    /// Как часто подписки проверяют новые операции, мс
+    #[arg(long, default_value_t = 500)]
+    poll_ms: u64,
+}
+
+fn main() -> io::Result<()> {
+    let args = Args::parse();
+    let listener = TcpListener::bind(&args.listen)?;
+    println!("graphql_address: {}", listener.local_addr()?);
+    GraphqlGateway::new(&args.server, Duration::from_millis(args.poll_ms)).serve(listener)
+}
@@ -0,0 +1,544 @@
+use std::io;
+use std::path::PathBuf;
+use std::process;
+
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+
+use banklib::{BankClient, DiffReport};
+use protocol_crate::{
+    AccountRef, DisputeId, DisputeResolution, Operation, PendingTransferId, RateLimit,
+    ReservationKind,
+};
+use server::{Key, Location};
+
+#[derive(Parser, Debug)]
+#[command(name = "bankctl")]
+#[command(version = "1.0")]
+#[command(about = "Администрирование банковского сервера")]
+struct Cli {
+    /// Адрес сервера
+    #[arg(long, default_value = "127.0.0.1:7878")]
+    server: String,
+    /// Административный токен; нужен всем командам, кроме migrate и tail
+    #[arg(long)]
+    token: Option<String>,
+    /// Токен пользователя, от имени которого читается история для tail
+    #[arg(long)]
+    identity: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Перенос банка из одного хранилища в другое со сверкой после переноса.
+    /// Хранилища указываются как snapshot:ФАЙЛ, sled:КАТАЛОГ или
+    /// postgres:СТРОКА_ПОДКЛЮЧЕНИЯ; сервер с ними в это время работать не должен
+    Migrate {
+        /// Откуда переносить
+        #[arg(long)]
+        from: Location,
+        /// Куда переносить; хранилище должно быть пустым
+        #[arg(long)]
+        to: Location,
+        /// Файл ключа шифрования из [encryption] конфигурации сервера: им
+        /// читаются зашифрованные снимки и базы sled и шифруется новая база sled
+        #[arg(long)]
+        key_file: Option<PathBuf>,
+    },
+    /// Восстановление банка из снимка в хранилище S3 после потери машины.
+    /// Хранилище берется из раздела [snapshots.s3] конфигурации сервера
+    RestoreFromS3 {
+        /// Файл конфигурации сервера
+        #[arg(long)]
+        config: PathBuf,
+        /// Имя снимка в хранилище; по умолчанию самый новый
+        #[arg(long)]
+        snapshot: Option<String>,
+        /// Куда восстанавливать; хранилище должно быть пустым
+        #[arg(long)]
+        to: Location,
+    },
+    /// Записать снимок в каталог снимков сервера сейчас
+    Snapshot,
+    /// Включить режим обслуживания (только чтение) или выключить его с --off
+    Maintenance {
+        /// Выключить режим обслуживания
+        #[arg(long)]
+        off: bool,
+    },
+    /// Сведения о сервере и статистика запросов
+    Stats,
+    /// Сверка балансов и индексов сервера с его историей
+    Check,
+    /// Сравнить счета, балансы и историю с другим сервером, например после
+    /// восстановления или сбоя репликации
+    Diff {
+        /// Адрес сервера, с которым сравнивать
+        other: String,
+    },
+    /// Печатать операции банка по мере их появления, как tail -f. Сервер
+    /// сам присылает новые операции по подписке
+    Tail {
+        /// Сколько последних операций показать перед ожиданием новых
+        #[arg(short = 'n', long, default_value_t = 10)]
+        lines: usize,
+        /// Только операции этого счета
+        #[arg(long)]
+        account: Option<String>,
+        /// Выводить операции в JSON, по одной на строку
+        #[arg(long)]
+        json: bool,
+    },
+    /// Токены из файла настроек сервера
+    Tokens {
+        #[command(subcommand)]
+        command: TokenCommand,
+    },
+    /// Ограничения частоты запросов к счетам
+    RateLimit {
+        #[command(subcommand)]
+        command: RateLimitCommand,
+    },
+    /// Задания расписания сервера: когда выполнятся и как выполнились
+    Jobs,
+    /// Переводы выше порога, ждущие одобрения администратора
+    Pending {
+        #[command(subcommand)]
+        command: PendingCommand,
+    },
+    /// Споры клиентов по операциям истории
+    Disputes {
+        #[command(subcommand)]
+        command: DisputeCommand,
+    },
+    /// Заменить имя счета и его дочерних счетов псевдонимом везде, включая
+    /// историю. Реплики и уже записанные снимки сохраняют старое имя
+    Anonymize { account: String },
+    /// Скрипт автодополнения команд для оболочки
+    Completions { shell: Shell },
+    /// Страница man; с --dir в каталог пишутся страницы и всех подкоманд
+    Man {
+        #[arg(long)]
+        dir: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum TokenCommand {
+    /// Токены сервера; показывается только их начало
+    List,
+    /// Добавить токен пользователя, административный токен или сразу оба
+    Add {
+        token: String,
+        /// Пользователь, которому принадлежит токен
+        #[arg(long)]
+        identity: Option<String>,
+        /// Токену разрешены административные команды
+        #[arg(long)]
+        admin: bool,
+    },
+    /// Отозвать токен; токен, с которым отправлена команда, отозвать нельзя
+    Revoke { token: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum PendingCommand {
+    /// Ждущие переводы, сначала самые старые
+    List,
+    /// Выполнить перевод
+    Approve { id: PendingTransferId },
+    /// Отклонить перевод и освободить его средства
+    Reject { id: PendingTransferId },
+}
+
+#[derive(Subcommand, Debug)]
+enum DisputeCommand {
+    /// Открытые споры, сначала самые старые
+    List {
+        /// Показать и закрытые споры
+        #[arg(long)]
+        all: bool,
+    },
+    /// Закрыть спор
+    Resolve {
+        id: DisputeId,
+        /// Отменить оспоренную операцию обратной
+        #[arg(long)]
+        reverse: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum RateLimitCommand {
+    /// Ограничение по умолчанию и ограничения отдельных счетов
+    List,
+    /// Ограничить запросы к счету или, без --account, ко всем счетам без своего ограничения
+    Set {
+        #[arg(long)]
+        account: Option<String>,
+        /// Сколько запросов в секунду пропускать в среднем
+        #[arg(long)]
+        per_second: u32,
+        /// Сколько запросов пропускать подряд
+        #[arg(long)]
+        burst: u32,
+    },
+    /// Снять ограничение счета или, без --account, ограничение по умолчанию
+    Remove {
+        #[arg(long)]
+        account: Option<String>,
+    },
+}
+
+fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(cli) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(cli: Cli) -> Result<(), String> {
+    let mut client = BankClient::new(&cli.server);
+    if let Some(token) = &cli.identity {
+        client = client.with_identity(token);
+    }
+    let token = || cli.token.as_deref().ok_or("--token is required");
+    let failed = |e| format!("{:?}", e);
+    match cli.command {
+        Command::Migrate { from, to, key_file } => {
+            let key = key_file
+                .as_deref()
+                .map(Key::load)
+                .transpose()
+                .map_err(|e| e.to_string())?;
+            let migrated = server::migrate(&from, &to, key.as_ref()).map_err(|e| e.to_string())?;
+            println!(
+                "Migrated {} accounts with total balance {} and {} operations",
+                migrated.accounts, migrated.total_balance, migrated.operations
+            );
+            println!("History digest: {}", migrated.history_digest);
+        }
+        Command::RestoreFromS3 {
+            config,
+            snapshot,
+            to,
+        } => {
+            let migrated = server::restore_from_s3(&config, snapshot.as_deref(), &to)
+                .map_err(|e| e.to_string())?;
+            println!(
+                "Restored {} accounts with total balance {} and {} operations",
+                migrated.accounts, migrated.total_balance, migrated.operations
+            );
+            println!("History digest: {}", migrated.history_digest);
+        }
+        Command::Snapshot => {
+            let operations = client.write_snapshot(token()?).map_err(failed)?;
+            println!("Snapshot at operation {} is being written", operations);
+        }
+        Command::Maintenance { off } => {
+            client.set_maintenance(token()?, !off).map_err(failed)?;
+        }
+        Command::Stats => {
+            let info = client.server_info().map_err(failed)?;
+            let digest = client.history_digest(None).map_err(failed)?;
+            let metrics = client.metrics(token()?).map_err(failed)?;
+            let stats = client.stats(token()?).map_err(failed)?;
+            let limit = |limit: Option<usize>| limit.map_or("none".to_string(), |l| l.to_string());
+            println!("Server:     {}", stats.address);
+            println!(
+                "Durability: {}",
+                stats.durability.as_deref().unwrap_or("memory only")
+            );
+            println!("Protocol:   {}", info.protocol_version);
+            println!("Encodings:  {}", info.encodings.join(", "));
+            println!("Operations: {}", digest.operations);
+            println!("Digest:     {}", digest.digest);
+            println!(
+                "Accounts:   {} of {}, ~{} bytes",
+                stats.accounts,
+                limit(stats.max_accounts),
+                stats.account_bytes
+            );
+            println!(
+                "History:    {} of {}, ~{} bytes",
+                stats.operations,
+                limit(stats.max_history),
+                stats.history_bytes
+            );
+            println!();
+            println!(
+                "{:<20} | {:>8} | {:>8} | {:>8} | {:>8} | {:>8} | {:>8}",
+                "command", "count", "errors", "p50 us", "p90 us", "p99 us", "max us"
+            );
+            for m in metrics {
+                println!(
+                    "{:<20} | {:>8} | {:>8} | {:>8} | {:>8} | {:>8} | {:>8}",
+                    m.command, m.count, m.errors, m.p50_us, m.p90_us, m.p99_us, m.max_us
+                );
+            }
+        }
+        Command::Check => {
+            let report = client.check_consistency(token()?).map_err(failed)?;
+            if !report.differences.is_empty() {
+                return Err(report.differences.join("\n"));
+            }
+            println!("State is consistent with {} operations", report.operations);
+        }
+        Command::Diff { other } => {
+            let mut other_client = BankClient::new(&other);
+            if let Some(token) = &cli.identity {
+                other_client = other_client.with_identity(token);
+            }
+            let report = banklib::diff(&client, &other_client).map_err(failed)?;
+            print_diff(&cli.server, &other, &report);
+            if !report.is_empty() {
+                return Err(format!("{} and {} differ", cli.server, other));
+            }
+        }
+        Command::Tail {
+            lines,
+            account,
+            json,
+        } => {
+            let operations = client.history_digest(None).map_err(failed)?.operations;
+            let start = operations.saturating_sub(lines);
+            tail(&client, start, account, json).map_err(failed)?;
+        }
+        Command::RateLimit { command } => match command {
+            RateLimitCommand::List => {
+                let limits = client.rate_limits(token()?).map_err(failed)?;
+                println!("{:<20} | {:>10} | {:>6}", "account", "per second", "burst");
+                let default = limits.default.map(|limit| ("(default)".to_string(), limit));
+                for (account, limit) in default.into_iter().chain(limits.accounts) {
+                    println!(
+                        "{:<20} | {:>10} | {:>6}",
+                        account, limit.per_second, limit.burst
+                    );
+                }
+            }
+            RateLimitCommand::Set {
+                account,
+                per_second,
+                burst,
+            } => {
+                let limit = RateLimit { per_second, burst };
+                client
+                    .set_rate_limit(token()?, account.as_deref(), Some(limit))
+                    .map_err(failed)?;
+            }
+            RateLimitCommand::Remove { account } => {
+                client
+                    .set_rate_limit(token()?, account.as_deref(), None)
+                    .map_err(failed)?;
+            }
+        },
+        Command::Jobs => {
+            let jobs = client.list_jobs(token()?).map_err(failed)?;
+            println!(
+                "{:<16} | {:<16} | {:<30} | {:>10} | {:>10} | last error",
+                "name", "schedule", "task", "next run", "last run"
+            );
+            let time = |time: Option<u64>| time.map_or("-".to_string(), |t| t.to_string());
+            for job in jobs {
+                println!(
+                    "{:<16} | {:<16} | {:<30} | {:>10} | {:>10} | {}",
+                    job.name,
+                    job.schedule,
+                    job.task,
+                    time(job.next_run),
+                    time(job.last_run),
+                    job.last_error.as_deref().unwrap_or("-")
+                );
+            }
+        }
+        Command::Pending { command } => match command {
+            PendingCommand::List => {
+                let transfers = client.pending_transfers(token()?).map_err(failed)?;
+                println!(
+                    "{:>6} | {:<20} | {:<20} | {:>10} | requested at",
+                    "id", "from", "to", "amount"
+                );
+                for transfer in transfers {
+                    println!(
+                        "{:>6} | {:<20} | {:<20} | {:>10} | {}",
+                        transfer.id,
+                        transfer.from,
+                        transfer.to,
+                        transfer.amount,
+                        transfer.requested_at
+                    );
+                }
+            }
+            PendingCommand::Approve { id } => {
+                client.approve_transfer(token()?, id).map_err(failed)?;
+            }
+            PendingCommand::Reject { id } => {
+                client.reject_transfer(token()?, id).map_err(failed)?;
+            }
+        },
+        Command::Disputes { command } => match command {
+            DisputeCommand::List { all } => {
+                let disputes = client.disputes(all).map_err(failed)?;
+                println!(
+                    "{:>6} | {:>9} | {:<12} | {:<10} | reason",
+                    "id", "operation", "opened by", "status"
+                );
+                for dispute in disputes {
+                    let status = match dispute.resolution {
+                        None => "open".to_string(),
+                        Some(DisputeResolution {
+                            reversal: Some(reversal),
+                            ..
+                        }) => format!("reversed by {}", reversal),
+                        Some(_) => "closed".to_string(),
+                    };
+                    println!(
+                        "{:>6} | {:>9} | {:<12} | {:<10} | {}",
+                        dispute.id,
+                        dispute.operation,
+                        dispute.opened_by.as_deref().unwrap_or("-"),
+                        status,
+                        dispute.reason
+                    );
+                }
+            }
+            DisputeCommand::Resolve { id, reverse } => {
+                client
+                    .resolve_dispute(token()?, id, reverse)
+                    .map_err(failed)?;
+            }
+        },
+        Command::Anonymize { account } => {
+            let pseudonym = client
+                .anonymize_account(token()?, account.as_str())
+                .map_err(failed)?;
+            println!("{} is now {}", account, pseudonym);
+        }
+        Command::Completions { shell } => {
+            clap_complete::generate(shell, &mut Cli::command(), "bankctl", &mut io::stdout())
+        }
+        Command::Man { dir } => {
+            match dir {
+                Some(dir) => clap_mangen::generate_to(Cli::command(), dir),
+                None => clap_mangen::Man::new(Cli::command()).render(&mut io::stdout()),
+            }
+            .map_err(|e| e.to_string())?;
+        }
+        Command::Tokens { command } => match command {
+            TokenCommand::List => {
+                println!("{:<10} | {:<20} | admin", "token", "identity");
+                for info in client.list_tokens(token()?).map_err(failed)? {
+                    println!(
+                        "{:<10} | {:<20} | {}",
+                        info.token,
+                        info.identity.as_deref().unwrap_or("-"),
+                        if info.admin { "yes" } else { "no" }
+                    );
+                }
+            }
+            TokenCommand::Add {
+                token: new_token,
+                identity,
+                admin,
+            } => {
+                client
+                    .add_token(token()?, &new_token, identity.as_deref(), admin)
+                    .map_err(failed)?;
+            }
+            TokenCommand::Revoke { token: revoked } => {
+                client.revoke_token(token()?, &revoked).map_err(failed)?;
+            }
+        },
+    }
+    Ok(())
+}
+
+// Отчет о расхождениях: счета, которые есть не везде или с разным балансом,
+// и место, где расходится история
+fn print_diff(left: &str, right: &str, report: &DiffReport) {
+    let missing = || "-".to_string();
+    let differences = report
+        .only_left
+        .iter()
+        .map(|(name, balance)| (name, balance.to_string(), missing()))
+        .chain(
+            report
+                .only_right
+                .iter()
+                .map(|(name, balance)| (name, missing(), balance.to_string())),
+        )
+        .chain(
+            report
+                .balances
+                .iter()
+                .map(|(name, left, right)| (name, left.to_string(), right.to_string())),
+        );
+    println!("{:<20} | {:>21} | {:>21}", "account", left, right);
+    for (name, left, right) in differences {
+        println!("{:<20} | {:>21} | {:>21}", name, left, right);
+    }
+    println!();
+    println!(
+        "History: {} operations on {}, {} on {}, the first {} are the same",
+        report.left_operations, left, report.right_operations, right, report.common_operations
+    );
+}
+
+/// Prints the operations from `offset` on and then the new ones as the
+/// server streams them; returns only on error.
+fn tail(
+    client: &BankClient,
+    offset: usize,
+    account: Option<String>,
+    json: bool,
+) -> Result<(), protocol_crate::BankError> {
+    client.subscribe(
+        Some(offset),
+        account.map(AccountRef::from),
+        |id, operation| {
+            match json {
+                true => println!(
+                    "{}",
+                    serde_json::json!({ "id": id, "operation": operation })
+                ),
+                false => println!("{:>8}  {}", id, describe(&operation)),
+            }
+            true
+        },
+    )
+}
+
+fn describe(operation: &Operation) -> String {
+    match operation {
+        Operation::CreateAccount(account) => format!("create {}", account),
+        Operation::IncreaseAccount(account, amount) => format!("increase {} {}", account, amount),
+        Operation::DecreaseAccount(account, amount) => format!("decrease {} {}", account, amount),
+        Operation::Transfer(from, to, amount) => format!("transfer {} -> {} {}", from, to, amount),
+        Operation::RemoteTransferOut { from, to, amount } => {
+            format!("remote transfer {} -> {} {}", from, to, amount)
+        }
+        Operation::RemoteTransferIn { from, to, amount } => {
+            format!("remote transfer {} -> {} {}", from, to, amount)
+        }
+        Operation::TransactionLeg {
+            transaction,
+            account,
+            kind,
+            amount,
+        } => {
+            let kind = match kind {
+                ReservationKind::Debit => "debit",
+                ReservationKind::Credit => "credit",
+            };
+            format!("{} {} {} in {}", kind, account, amount, transaction)
+        }
+        Operation::SetLimits { account, limits } => format!("limits {} {:?}", account, limits),
+        Operation::CloseAccount(account) => format!("close {}", account),
+        Operation::AnonymizeAccount { id, pseudonym, .. } => {
+            format!("anonymize account {} as {}", id, pseudonym)
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use std::io;
+use std::net::TcpListener;
+
+use clap::Parser;
+
+use server::Router;
+
+#[derive(Parser, Debug)]
+#[command(name = "bankrouter")]
+#[command(version = "1.0")]
+#[command(about = "Маршрутизатор запросов к шардам банка")]
+struct Args {
+    /// Адрес, на котором принимать соединения
+    #[arg(long, default_value = "127.0.0.1:7877")]
+    listen: String,
+    /// Адрес шарда, как его видит сам шард; указывается для каждого шарда,
+    /// порядок не важен
+    #[arg(long = "shard", required = true)]
+    shards: Vec<String>,
+}
+
+fn main() -> io::Result<()> {
+    let args = Args::parse();
+    let listener = TcpListener::bind(&args.listen)?;
+    println!("router_address: {}", listener.local_addr()?);
+    Router::new(args.shards).serve(listener);
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of the current time for everything in the bank that depends on it:
+/// operation timestamps, daily outflow limits, velocity rules, rate limits
+/// and account lock leases.
+pub trait Clock: Debug + Send + Sync {
+    /// Current time as unix seconds.
+    fn now(&self) -> u64 {
+        self.now_millis() / 1000
+    }
+
+    /// Current time as unix milliseconds, for what has to be finer than a
+    /// second.
+    fn now_millis(&self) -> u64;
+}
+
+/// Wall-clock time of the host.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64)
+    }
+}
+
+/// Clock that only moves when told to. Clones share the time, so a test keeps
+/// one to move the time of the bank it gave the other to.
+#[derive(Debug, Clone, Default)]
+// Время в миллисекундах
+pub struct TestClock(Arc<AtomicU64>);
+
+impl TestClock {
+    pub fn at(secs: u64) -> Self {
+        let clock = TestClock::default();
+        clock.set(secs);
+        clock
+    }
+
+    pub fn set(&self, secs: u64) {
+        self.0.store(secs * 1000, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, secs: u64) {
+        self.advance_millis(secs * 1000);
+    }
+
+    pub fn advance_millis(&self, millis: u64) {
+        self.0.fetch_add(millis, Ordering::SeqCst);
+    }
+}
+
+impl Clock for TestClock {
+    fn now_millis(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,755 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use protocol_crate::{BankError, RateLimit, RateLimits, VelocityRule};
+
+use crate::encryption::Key;
+use crate::scheduler::Schedule;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    #[default]
+    Info,
+    Debug,
+}
+
+/// What an identity may do; every role can do everything the lower ones can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    ReadOnly,
+    Teller,
+    Admin,
+}
+
+/// When and where to write snapshots of the bank state.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotConfig {
+    // Каталог снимков; без него снимки не пишутся
+    pub dir: Option<PathBuf>,
+    // Писать снимок после стольких новых операций
+    pub every_operations: Option<usize>,
+    // Писать снимок, если с прошлого прошло столько минут и были операции
+    pub every_minutes: Option<u64>,
+    // Сколько последних снимков хранить
+    pub keep: usize,
+    // Писать снимки в архивном формате rkyv вместо JSON
+    pub archive: bool,
+    // Сжимать снимки zstd с этим уровнем, от 1 до 22
+    pub compression: Option<i32>,
+    // Копировать каждый снимок в хранилище S3
+    pub s3: Option<S3Config>,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        SnapshotConfig {
+            dir: None,
+            every_operations: None,
+            every_minutes: None,
+            keep: 3,
+            archive: false,
+            compression: None,
+            s3: None,
+        }
+    }
+}
+
+/// S3-compatible bucket every snapshot is copied to once it is written, so
+/// the bank survives the loss of the machine.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct S3Config {
+    // Адрес хранилища, https://HOST[:PORT]; бакет указывается в пути
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    // Префикс ключей снимков в бакете
+    #[serde(default)]
+    pub prefix: String,
+    // Сколько последних снимков хранить в бакете
+    #[serde(default = "default_s3_keep")]
+    pub keep: usize,
+    // Разрешить адрес http:// без TLS: для хранилища на этой же машине или в тестах
+    #[serde(default)]
+    pub allow_http: bool,
+}
+
+impl S3Config {
+    /// Host and port of the endpoint. A plain http:// endpoint is refused
+    /// unless `allow_http` is set: snapshots hold the whole bank.
+    pub fn host(&self) -> Result<&str, String> {
+        let host = match self.endpoint.split_once("://") {
+            Some(("https", host)) => host,
+            Some(("http", host)) if self.allow_http => host,
+            Some(("http", _)) => {
+                return Err(format!(
+                    "{}: an http:// S3 endpoint needs allow_http = true",
+                    self.endpoint
+                ))
+            }
+            _ => {
+                return Err(format!(
+                    "{}: expected an https:// S3 endpoint",
+                    self.endpoint
+                ))
+            }
+        };
+        Ok(host.trim_end_matches('/'))
+    }
+}
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_s3_keep() -> usize {
+    10
+}
+
+/// How far the bank may grow; without a limit it grows until memory or
+/// disk runs out.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CapacityConfig {
+    // Больше счетов создать нельзя
+    pub max_accounts: Option<usize>,
+    // Больше операций в истории не добавляется
+    pub max_history: Option<usize>,
+}
+
+/// Distributed transactions this server coordinates; read once at startup.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CoordinatorConfig {
+    // Имя координатора в номерах транзакций и в имени журнала по умолчанию;
+    // без него - адрес сервера, который с портом 0 меняется при каждом запуске
+    pub id: Option<String>,
+    // Как часто повторять решения, которые участники еще не подтвердили, с
+    pub retry_secs: u64,
+}
+
+impl Default for CoordinatorConfig {
+    fn default() -> Self {
+        CoordinatorConfig {
+            id: None,
+            retry_secs: 10,
+        }
+    }
+}
+
+/// Where the accounts, balances and history live.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    #[default]
+    Memory,
+    Sled,
+    // Только в сборке с возможностью postgres
+    Postgres,
+}
+
+/// Storage backend of the bank; read once at startup.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    // Каталог базы sled
+    pub path: Option<PathBuf>,
+    // Когда операции попадают на диск; только для sled и postgres
+    pub durability: Option<Durability>,
+    // Строка подключения к PostgreSQL
+    pub url: Option<String>,
+    // Размер пула соединений с PostgreSQL
+    pub pool_size: Option<u32>,
+    // Снимок, из которого заполняется новая база, вместо последнего из каталога снимков
+    pub migrate_from: Option<PathBuf>,
+}
+
+/// When an operation the server has answered is on disk: before the answer
+/// (`every operation`), in the background every so many milliseconds
+/// (`every 200ms`), losing at most that interval in a crash, or whenever the
+/// storage gets to it (`never`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Durability {
+    #[default]
+    EveryOperation,
+    EveryMs(u64),
+    Never,
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "every operation" => return Ok(Durability::EveryOperation),
+            "never" => return Ok(Durability::Never),
+            _ => {}
+        }
+        let ms = s
+            .strip_prefix("every ")
+            .and_then(|every| every.trim().strip_suffix("ms"))
+            .ok_or_else(|| {
+                format!(
+                    "{}: expected \"every operation\", \"every <N>ms\" or \"never\"",
+                    s
+                )
+            })?;
+        match ms.parse::<u64>() {
+            Ok(ms) if ms > 0 => Ok(Durability::EveryMs(ms)),
+            _ => Err(format!("{}: expected a positive number", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Durability {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Durability::EveryOperation => f.write_str("every operation"),
+            Durability::EveryMs(ms) => write!(f, "every {}ms", ms),
+            Durability::Never => f.write_str("never"),
+        }
+    }
+}
+
+/// Encryption at rest of what the server writes to disk: the snapshots and
+/// their copies in S3, the history segments and the sled database. The
+/// postgres storage is not covered, its tables are there to be queried; read
+/// once at startup.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptionConfig {
+    // Файл ключа AES-256 из 64 шестнадцатеричных цифр; без него данные не шифруются
+    pub key_file: Option<PathBuf>,
+}
+
+impl EncryptionConfig {
+    /// The key from `key_file`, `None` if data is written as it is.
+    pub fn key(&self) -> io::Result<Option<Key>> {
+        self.key_file.as_deref().map(Key::load).transpose()
+    }
+}
+
+/// PROXY protocol headers that a TCP load balancer sends before the client's
+/// data; read once at startup.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyProtocolConfig {
+    // Ждать заголовок перед командой; соединение без него закрывается
+    pub enabled: bool,
+    // Адреса балансировщиков; с других адресов заголовок не ждется. Пустой - со всех
+    pub trusted: Vec<IpAddr>,
+}
+
+impl ProxyProtocolConfig {
+    /// Whether a connection from `peer` starts with a PROXY header.
+    pub fn expected_from(&self, peer: IpAddr) -> bool {
+        self.enabled && (self.trusted.is_empty() || self.trusted.contains(&peer))
+    }
+}
+
+/// Request rate limits per account; changed with `SetRateLimit`.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    // Для счетов без своего ограничения
+    pub default: Option<RateLimit>,
+    // Имя счета -> его ограничение
+    pub accounts: HashMap<String, RateLimit>,
+}
+
+impl RateLimitConfig {
+    /// Limit of `account`, `None` if its requests are not limited.
+    pub fn limit(&self, account: &str) -> Option<RateLimit> {
+        self.accounts.get(account).copied().or(self.default)
+    }
+
+    pub fn to_limits(&self) -> RateLimits {
+        RateLimits {
+            default: self.default,
+            accounts: self
+                .accounts
+                .iter()
+                .map(|(account, limit)| (account.clone(), *limit))
+                .collect(),
+        }
+    }
+}
+
+/// A job the server runs on a schedule, from a `[[jobs]]` section.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobConfig {
+    // Имя задачи, уникальное в конфиге
+    pub name: String,
+    pub schedule: Schedule,
+    pub task: Task,
+}
+
+/// What a scheduled job does.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum Task {
+    // Снимок в каталог снимков, как по команде WriteSnapshot
+    Snapshot,
+    // Постоянное поручение: перевод по расписанию
+    Transfer {
+        from: String,
+        to: String,
+        amount: u32,
+    },
+    // Проценты на остаток: каждому счету с префиксом переводится rate_bps
+    // сотых долей процента его баланса со счета from, всем одним пакетом
+    Interest {
+        from: String,
+        prefix: String,
+        rate_bps: u32,
+    },
+}
+
+impl Task {
+    /// Whether the task changes the bank.
+    pub fn mutates(&self) -> bool {
+        !matches!(self, Task::Snapshot)
+    }
+}
+
+impl fmt::Display for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Task::Snapshot => write!(f, "snapshot"),
+            Task::Transfer { from, to, amount } => {
+                write!(f, "transfer {} -> {} {}", from, to, amount)
+            }
+            Task::Interest {
+                from,
+                prefix,
+                rate_bps,
+            } => write!(f, "interest {} bps from {} to {}*", rate_bps, from, prefix),
+        }
+    }
+}
+
+/// Settings read from the config file; all of them can be changed at runtime
+/// except the storage, the encryption, the coordinator and the PROXY protocol.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub log_level: LogLevel,
+    // Токены, которым разрешены административные команды
+    pub admin_tokens: Vec<String>,
+    // Токен -> имя пользователя (владельца счетов)
+    pub identities: HashMap<String, String>,
+    // Имя пользователя -> роль
+    pub roles: HashMap<String, Role>,
+    // Роль анонимных клиентов и пользователей без роли; без нее команды не ограничены
+    pub default_role: Option<Role>,
+    // Ограничения скорости списаний для всех счетов
+    pub velocity_rules: Vec<VelocityRule>,
+    pub snapshots: SnapshotConfig,
+    pub storage: StorageConfig,
+    pub encryption: EncryptionConfig,
+    pub coordinator: CoordinatorConfig,
+    pub capacity: CapacityConfig,
+    pub proxy_protocol: ProxyProtocolConfig,
+    pub rate_limits: RateLimitConfig,
+    pub jobs: Vec<JobConfig>,
+    // Переводы больше этой суммы ждут одобрения администратора
+    pub approval_threshold: Option<u32>,
+    // Режим обслуживания: изменяющие команды отклоняются
+    pub maintenance: bool,
+    // Адрес http://, куда отправляются сработавшие оповещения о низком балансе
+    pub alert_webhook: Option<String>,
+}
+
+impl Config {
+    pub fn is_admin(&self, token: &str) -> bool {
+        self.admin_tokens.iter().any(|t| t == token)
+    }
+
+    pub fn identity(&self, token: &str) -> Option<&str> {
+        self.identities.get(token).map(String::as_str)
+    }
+
+    /// Role of `caller`, `None` when commands are not restricted for it.
+    pub fn role(&self, caller: Option<&str>) -> Option<Role> {
+        caller
+            .and_then(|caller| self.roles.get(caller))
+            .copied()
+            .or(self.default_role)
+    }
+
+    pub fn enabled(&self, level: LogLevel) -> bool {
+        level <= self.log_level
+    }
+}
+
+/// Current config together with the file it came from.
+pub struct Settings {
+    path: Option<PathBuf>,
+    pub config: Config,
+}
+
+impl Settings {
+    pub fn load(path: Option<PathBuf>) -> Result<Self, BankError> {
+        let mut settings = Settings {
+            path,
+            config: Config::default(),
+        };
+        settings.reload()?;
+        Ok(settings)
+    }
+
+    /// Re-reads the config file. On error the previous config stays in effect.
+    pub fn reload(&mut self) -> Result<(), BankError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let invalid = |e: String| BankError::InvalidConfig(format!("{}: {}", path.display(), e));
+        let text = fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        let config: Config = toml::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+        // Задачи планировщик различает по имени
+        let mut names = HashSet::new();
+        if let Some(job) = config.jobs.iter().find(|job| !names.insert(&job.name)) {
+            return Err(invalid(format!("job {} is defined twice", job.name)));
+        }
+        if let Some(s3) = &config.snapshots.s3 {
+            s3.host().map_err(invalid)?;
+        }
+        self.config = config;
+        Ok(())
+    }
+
+    /// Adds `token` to the config file as an admin token, a token of
+    /// `identity` or both, and applies the file. Comments and layout of the
+    /// file are kept.
+    pub fn add_token(
+        &mut self,
+        token: &str,
+        identity: Option<&str>,
+        admin: bool,
+    ) -> Result<(), BankError> {
+        if !admin && identity.is_none() {
+            return Err(BankError::InvalidConfig(
+                "a token needs an identity or admin rights".to_string(),
+            ));
+        }
+        if self.config.is_admin(token) || self.config.identity(token).is_some() {
+            return Err(BankError::InvalidConfig(
+                "the token is already in use".to_string(),
+            ));
+        }
+        self.edit(|document| {
+            if admin {
+                let tokens = document
+                    .entry("admin_tokens")
+                    .or_insert(toml_edit::value(toml_edit::Array::new()))
+                    .as_array_mut()
+                    .ok_or("admin_tokens is not an array")?;
+                tokens.push(token);
+            }
+            if let Some(identity) = identity {
+                let identities = document
+                    .entry("identities")
+                    .or_insert(toml_edit::table())
+                    .as_table_like_mut()
+                    .ok_or("identities is not a table")?;
+                identities.insert(token, toml_edit::value(identity));
+            }
+            Ok(())
+        })
+    }
+
+    /// Removes `token` from the admin tokens and the identities in the config
+    /// file and applies the file.
+    pub fn revoke_token(&mut self, token: &str) -> Result<(), BankError> {
+        if !self.config.is_admin(token) && self.config.identity(token).is_none() {
+            return Err(BankError::InvalidConfig("no such token".to_string()));
+        }
+        self.edit(|document| {
+            if let Some(tokens) = document
+                .get_mut("admin_tokens")
+                .and_then(|tokens| tokens.as_array_mut())
+            {
+                tokens.retain(|t| t.as_str() != Some(token));
+            }
+            if let Some(identities) = document
+                .get_mut("identities")
+                .and_then(|identities| identities.as_table_like_mut())
+            {
+                identities.remove(token);
+            }
+            Ok(())
+        })
+    }
+
+    /// Sets the rate limit of `account`, or the default one without an
+    /// account, in the config file and applies the file; `None` removes it.
+    pub fn set_rate_limit(
+        &mut self,
+        account: Option<&str>,
+        limit: Option<RateLimit>,
+    ) -> Result<(), BankError> {
+        if limit.is_some_and(|limit| limit.per_second == 0 || limit.burst == 0) {
+            return Err(BankError::InvalidConfig(
+                "a rate limit needs at least one request per second and in a burst".to_string(),
+            ));
+        }
+        self.edit(|document| {
+            let section = document
+                .entry("rate_limits")
+                .or_insert(toml_edit::table())
+                .as_table_like_mut()
+                .ok_or("rate_limits is not a table")?;
+            let (table, key) = match account {
+                Some(account) => {
+                    let accounts = section
+                        .entry("accounts")
+                        .or_insert(toml_edit::table())
+                        .as_table_like_mut()
+                        .ok_or("rate_limits.accounts is not a table")?;
+                    (accounts, account)
+                }
+                None => (section, "default"),
+            };
+            match limit {
+                Some(limit) => {
+                    let mut value = toml_edit::InlineTable::new();
+                    value.insert("per_second", i64::from(limit.per_second).into());
+                    value.insert("burst", i64::from(limit.burst).into());
+                    table.insert(key, toml_edit::value(value));
+                }
+                None => {
+                    table.remove(key);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Changes the config file with `change` and applies it. The file is
+    /// replaced only if the changed config is valid.
+    fn edit(
+        &mut self,
+        change: impl FnOnce(&mut toml_edit::DocumentMut) -> Result<(), &'static str>,
+    ) -> Result<(), BankError> {
+        let Some(path) = &self.path else {
+            return Err(BankError::InvalidConfig(
+                "the server runs without a config file".to_string(),
+            ));
+        };
+        let invalid = |e: String| BankError::InvalidConfig(format!("{}: {}", path.display(), e));
+        let text = fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        let mut document: toml_edit::DocumentMut = text
+            .parse()
+            .map_err(|e: toml_edit::TomlError| invalid(e.to_string()))?;
+        change(&mut document).map_err(|e| invalid(e.to_string()))?;
+        let text = document.to_string();
+        toml::from_str::<Config>(&text).map_err(|e| invalid(e.to_string()))?;
+
+        // Через временный файл, чтобы сервер не прочел файл наполовину записанным
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, text)
+            .and_then(|()| fs::rename(&temporary, path))
+            .map_err(|e| invalid(e.to_string()))?;
+        self.reload()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_path(name: &str, text: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("config-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn defaults_without_file() {
+        let settings = Settings::load(None).unwrap();
+        assert_eq!(LogLevel::Info, settings.config.log_level);
+        assert!(!settings.config.is_admin(""));
+    }
+
+    #[test]
+    fn reload_keeps_config_on_error() {
+        let path = config_path("reload", "log_level = \"debug\"\nadmin_tokens = [\"t\"]\n");
+        let mut settings = Settings::load(Some(path.clone())).unwrap();
+        assert_eq!(LogLevel::Debug, settings.config.log_level);
+        assert!(settings.config.is_admin("t"));
+
+        fs::write(&path, "log_level = \"loud\"\n").unwrap();
+        assert!(matches!(
+            settings.reload(),
+            Err(BankError::InvalidConfig(_))
+        ));
+        assert!(settings.config.is_admin("t"));
+
+        fs::write(&path, "log_level = \"error\"\n").unwrap();
+        assert!(settings.reload().is_ok());
+        assert_eq!(LogLevel::Error, settings.config.log_level);
+        assert!(!settings.config.is_admin("t"));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn token_management_keeps_the_file() {
+        let path = config_path(
+            "tokens",
+            "# Настройки сервера\nadmin_tokens = [\"root\"]\n\n[identities]\nt1 = \"alice\"\n",
+        );
+        let mut settings = Settings::load(Some(path.clone())).unwrap();
+        settings.add_token("t2", Some("bob"), false).unwrap();
+        settings.add_token("ops", None, true).unwrap();
+        assert_eq!(Some("bob"), settings.config.identity("t2"));
+        assert!(settings.config.is_admin("ops"));
+        assert!(settings.add_token("t1", None, true).is_err());
+        assert!(settings.add_token("t3", None, false).is_err());
+
+        settings.revoke_token("t1").unwrap();
+        assert!(settings.revoke_token("t1").is_err());
+        assert_eq!(None, settings.config.identity("t1"));
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with("# Настройки сервера\n"));
+
+        // Перечитанный файл дает то же самое
+        let settings = Settings::load(Some(path.clone())).unwrap();
+        assert_eq!(vec!["root", "ops"], settings.config.admin_tokens);
+        assert_eq!(Some("bob"), settings.config.identity("t2"));
+        assert_eq!(None, settings.config.identity("t1"));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn rate_limits() {
+        let path = config_path("rate-limits", "admin_tokens = [\"root\"]\n");
+        let mut settings = Settings::load(Some(path.clone())).unwrap();
+        let limit = |per_second, burst| RateLimit { per_second, burst };
+        settings.set_rate_limit(None, Some(limit(10, 20))).unwrap();
+        settings
+            .set_rate_limit(Some("alice/main"), Some(limit(1, 5)))
+            .unwrap();
+        assert_eq!(
+            Some(limit(1, 5)),
+            settings.config.rate_limits.limit("alice/main")
+        );
+        assert_eq!(
+            Some(limit(10, 20)),
+            settings.config.rate_limits.limit("bob")
+        );
+        assert!(settings.set_rate_limit(None, Some(limit(0, 1))).is_err());
+
+        settings.set_rate_limit(None, None).unwrap();
+        let settings = Settings::load(Some(path.clone())).unwrap();
+        assert_eq!(None, settings.config.rate_limits.limit("bob"));
+        assert_eq!(
+            vec![("alice/main".to_string(), limit(1, 5))],
+            settings
+                .config
+                .rate_limits
+                .to_limits()
+                .accounts
+                .into_iter()
+                .collect::<Vec<_>>()
+        );
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn roles() {
+        let path = config_path(
+            "roles",
+            "default_role = \"read-only\"\n[roles]\nalice = \"teller\"\nroot = \"admin\"\n",
+        );
+        let settings = Settings::load(Some(path.clone())).unwrap();
+        assert_eq!(Some(Role::Teller), settings.config.role(Some("alice")));
+        assert_eq!(Some(Role::Admin), settings.config.role(Some("root")));
+        assert_eq!(Some(Role::ReadOnly), settings.config.role(Some("bob")));
+        assert_eq!(Some(Role::ReadOnly), settings.config.role(None));
+        assert_eq!(None, Config::default().role(Some("alice")));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn s3_endpoints() {
+        let s3 = |endpoint: &str, allow_http: bool| S3Config {
+            endpoint: endpoint.to_string(),
+            bucket: "backups".to_string(),
+            region: default_region(),
+            access_key: "bank".to_string(),
+            secret_key: "secret".to_string(),
+            prefix: String::new(),
+            keep: 1,
+            allow_http,
+        };
+        assert_eq!(
+            Ok("s3.example.com"),
+            s3("https://s3.example.com/", false).host()
+        );
+        assert!(s3("http://127.0.0.1:9000", false).host().is_err());
+        assert_eq!(
+            Ok("127.0.0.1:9000"),
+            s3("http://127.0.0.1:9000", true).host()
+        );
+        assert!(s3("s3.example.com", true).host().is_err());
+
+        // Конфиг с открытым адресом не принимается целиком
+        let path = config_path(
+            "s3",
+            "[snapshots.s3]\nendpoint = \"http://s3.example.com\"\nbucket = \"b\"\naccess_key = \"a\"\nsecret_key = \"s\"\n",
+        );
+        assert!(matches!(
+            Settings::load(Some(path.clone())),
+            Err(BankError::InvalidConfig(_))
+        ));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn durability() {
+        let storage = |text: &str| toml::from_str::<StorageConfig>(text).map(|c| c.durability);
+        assert_eq!(None, storage("backend = \"sled\"\n").unwrap());
+        assert_eq!(
+            Some(Durability::EveryOperation),
+            storage("durability = \"every operation\"\n").unwrap()
+        );
+        assert_eq!(
+            Some(Durability::EveryMs(200)),
+            storage("durability = \"every 200ms\"\n").unwrap()
+        );
+        assert_eq!(
+            Some(Durability::Never),
+            storage("durability = \"never\"\n").unwrap()
+        );
+        for invalid in ["every 0ms", "every 2s", "sometimes"] {
+            assert!(invalid.parse::<Durability>().is_err(), "{}", invalid);
+        }
+        assert_eq!("every 200ms", Durability::EveryMs(200).to_string());
+    }
+}
@@ -0,0 +1,551 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use protocol_crate::{BankError, ReservationKind, TransactionId, TransactionLeg};
+
+use crate::bank::Bank;
+use crate::federation::RemoteBank;
+
+/// Record of the coordinator log. A transaction without an `End` record is
+/// unfinished and gets resolved on the next start.
+#[derive(Debug, Serialize, Deserialize)]
+enum LogRecord {
+    Begin {
+        transaction: TransactionId,
+        participants: Vec<String>,
+        // Адрес этого сервера в начале транзакции: участник с ним - он сам,
+        // даже если после перезапуска адрес другой
+        #[serde(default)]
+        address: String,
+    },
+    Commit(TransactionId),
+    Abort(TransactionId),
+    End(TransactionId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Decision {
+    Commit,
+    Abort,
+}
+
+/// Transaction with a decision that not every participant has
+/// acknowledged yet.
+#[derive(Debug)]
+struct Decided {
+    transaction: TransactionId,
+    participants: Vec<String>,
+    // Адрес этого сервера, когда транзакция началась
+    address: String,
+    decision: Decision,
+}
+
+/// Two-phase commit coordinator for transactions touching several servers.
+pub struct Coordinator {
+    // Имя координатора в номерах транзакций
+    id: String,
+    // Адрес, по которому ноги транзакции узнаются как свои
+    address: String,
+    log_path: PathBuf,
+    log: File,
+    next_number: usize,
+    // Решения, которые повторяются участникам, пока те их не подтвердят
+    unacknowledged: Vec<Decided>,
+    retry_every: Duration,
+    last_retry: Instant,
+}
+
+impl Coordinator {
+    /// Opens (or creates) the coordinator log at `log_path` and finishes the
+    /// transactions that were in flight when the server stopped. `id` names
+    /// the transactions of this coordinator; legs for `address` are local.
+    /// Decisions that participants do not acknowledge are sent again every
+    /// `retry_every` by [`Coordinator::retry`].
+    pub fn open(
+        id: &str,
+        address: &str,
+        log_path: &Path,
+        retry_every: Duration,
+        bank: &mut Bank,
+    ) -> io::Result<Self> {
+        let records = read_log(log_path)?;
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path)?;
+
+        let mut coordinator = Coordinator {
+            id: id.to_string(),
+            address: address.to_string(),
+            log_path: log_path.to_path_buf(),
+            log,
+            next_number: 0,
+            unacknowledged: Vec::new(),
+            retry_every,
+            last_retry: Instant::now(),
+        };
+        coordinator.recover(bank, records)?;
+        Ok(coordinator)
+    }
+
+    /// Sends the decisions that some participants have not acknowledged
+    /// again, at most once every `retry_every`. Transactions acknowledged by
+    /// all participants are closed in the log.
+    pub fn retry(&mut self, bank: &mut Bank, now: Instant) {
+        if self.unacknowledged.is_empty() || now < self.last_retry + self.retry_every {
+            return;
+        }
+        self.last_retry = now;
+        for decided in mem::take(&mut self.unacknowledged) {
+            if let Err(e) = self.finish(bank, decided) {
+                eprintln!("Failed to write the coordinator log: {}", e);
+            }
+        }
+    }
+
+    /// Runs `legs` as one transaction: every participant prepares its legs,
+    /// then all of them commit, or all of them abort if any prepare failed.
+    /// The client `token` goes to the other participants with the legs, so
+    /// that they check the owners of the accounts they debit; the servers
+    /// have to share the tokens for that.
+    pub fn execute(
+        &mut self,
+        bank: &mut Bank,
+        legs: Vec<TransactionLeg>,
+        token: Option<&str>,
+    ) -> Result<TransactionId, BankError> {
+        check_balanced(&legs)?;
+
+        let transaction = TransactionId {
+            coordinator: self.id.clone(),
+            number: self.next_number,
+        };
+        self.next_number += 1;
+
+        let participants = group_by_participant(legs);
+        self.append(&LogRecord::Begin {
+            transaction: transaction.clone(),
+            participants: participants.iter().map(|(p, _)| p.clone()).collect(),
+            address: self.address.clone(),
+        })
+        .map_err(log_error)?;
+
+        let mut vote = Ok(());
+        for (participant, legs) in &participants {
+            vote = if *participant == self.address {
+                bank.prepare(transaction.clone(), legs)
+            } else {
+                RemoteBank::new(participant).prepare(transaction.clone(), legs.clone(), token)
+            };
+            if vote.is_err() {
+                break;
+            }
+        }
+
+        let decision = match vote {
+            Ok(()) => Decision::Commit,
+            Err(_) => Decision::Abort,
+        };
+        self.decide(&transaction, decision).map_err(log_error)?;
+
+        let decided = Decided {
+            transaction: transaction.clone(),
+            participants: participants.into_iter().map(|(p, _)| p).collect(),
+            address: self.address.clone(),
+            decision,
+        };
+        self.finish(bank, decided).map_err(log_error)?;
+        vote.map(|_| transaction)
+    }
+
+    fn recover(&mut self, bank: &mut Bank, records: Vec<LogRecord>) -> io::Result<()> {
+        let mut unfinished: Vec<(TransactionId, Vec<String>, String)> = Vec::new();
+        let mut decisions: HashMap<TransactionId, Decision> = HashMap::new();
+
+        for record in records {
+            match record {
+                LogRecord::Begin {
+                    transaction,
+                    participants,
+                    address,
+                } => {
+                    self.next_number = self.next_number.max(transaction.number + 1);
+                    unfinished.push((transaction, participants, address));
+                }
+                LogRecord::Commit(transaction) => {
+                    decisions.insert(transaction, Decision::Commit);
+                }
+                LogRecord::Abort(transaction) => {
+                    decisions.insert(transaction, Decision::Abort);
+                }
+                LogRecord::End(transaction) => {
+                    unfinished.retain(|(t, _, _)| *t != transaction);
+                }
+            }
+        }
+
+        for (transaction, participants, address) in unfinished {
+            // Без записанного решения транзакцию можно только откатить
+            let decision = match decisions.get(&transaction) {
+                Some(decision) => *decision,
+                None => {
+                    self.decide(&transaction, Decision::Abort)?;
+                    Decision::Abort
+                }
+            };
+            println!("Recovering transaction {}: {:?}", transaction, decision);
+            let decided = Decided {
+                transaction,
+                participants,
+                address,
+                decision,
+            };
+            self.finish(bank, decided)?;
+        }
+        Ok(())
+    }
+
+    /// Sends the decision to every participant. The transaction is closed in
+    /// the log only once all of them have acknowledged it; until then it is
+    /// retried by [`Coordinator::retry`]. A participant that does not know a
+    /// committed transaction has lost its prepared legs, so the transaction
+    /// stays open for an operator to resolve.
+    fn finish(&mut self, bank: &mut Bank, decided: Decided) -> io::Result<()> {
+        let Decided {
+            transaction,
+            participants,
+            address,
+            decision,
+        } = &decided;
+        let mut acknowledged = true;
+        for participant in participants {
+            let local = participant == &self.address || participant == address;
+            let result = match (local, *decision) {
+                (true, Decision::Commit) => bank.commit(transaction),
+                (true, Decision::Abort) => bank.abort(transaction),
+                (false, Decision::Commit) => {
+                    RemoteBank::new(participant).commit(transaction.clone())
+                }
+                (false, Decision::Abort) => RemoteBank::new(participant).abort(transaction.clone()),
+            };
+            match result {
+                Ok(()) => {}
+                Err(e) => {
+                    eprintln!(
+                        "Participant {} did not finish {}: {:?}",
+                        participant, transaction, e
+                    );
+                    acknowledged = false;
+                }
+            }
+        }
+
+        if acknowledged {
+            self.append(&LogRecord::End(transaction.clone()))?;
+        } else {
+            self.unacknowledged.push(decided);
+        }
+        Ok(())
+    }
+
+    fn decide(&mut self, transaction: &TransactionId, decision: Decision) -> io::Result<()> {
+        let record = match decision {
+            Decision::Commit => LogRecord::Commit(transaction.clone()),
+            Decision::Abort => LogRecord::Abort(transaction.clone()),
+        };
+        self.append(&record)
+    }
+
+    fn append(&mut self, record: &LogRecord) -> io::Result<()> {
+        let line = serde_json::to_string(record).unwrap();
+        writeln!(self.log, "{}", line)?;
+        self.log
+            .sync_data()
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", self.log_path.display(), e)))
+    }
+}
+
+fn log_error(e: io::Error) -> BankError {
+    BankError::CoordinatorLog(e.to_string())
+}
+
+fn read_log(path: &Path) -> io::Result<Vec<LogRecord>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut records = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        // Последняя строка может быть недописана при падении
+        match serde_json::from_str(&line) {
+            Ok(record) => records.push(record),
+            Err(_) => break,
+        }
+    }
+    Ok(records)
+}
+
+fn check_balanced(legs: &[TransactionLeg]) -> Result<(), BankError> {
+    let total = legs.iter().fold(0i64, |total, leg| match leg.kind {
+        ReservationKind::Debit => total - leg.amount as i64,
+        ReservationKind::Credit => total + leg.amount as i64,
+    });
+    if legs.is_empty() || total != 0 {
+        return Err(BankError::UnbalancedTransaction);
+    }
+    Ok(())
+}
+
+fn group_by_participant(legs: Vec<TransactionLeg>) -> Vec<(String, Vec<TransactionLeg>)> {
+    let mut participants: Vec<(String, Vec<TransactionLeg>)> = Vec::new();
+    for leg in legs {
+        match participants
+            .iter_mut()
+            .find(|(address, _)| *address == leg.account.address)
+        {
+            Some((_, legs)) => legs.push(leg),
+            None => participants.push((leg.account.address.clone(), vec![leg])),
+        }
+    }
+    participants
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol_crate::RemoteAccount;
+
+    const ADDRESS: &str = "127.0.0.1:7878";
+
+    fn leg(account: &str, kind: ReservationKind, amount: u32) -> TransactionLeg {
+        TransactionLeg {
+            account: RemoteAccount {
+                address: ADDRESS.to_string(),
+                account: account.into(),
+            },
+            kind,
+            amount,
+        }
+    }
+
+    fn log_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("coordinator-{}-{}.log", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn open(path: &Path, bank: &mut Bank) -> io::Result<Coordinator> {
+        Coordinator::open(ADDRESS, ADDRESS, path, Duration::ZERO, bank)
+    }
+
+    fn bank() -> Bank {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.create_account("Y".to_string());
+        let _ = bank.create_account("Z".to_string());
+        let _ = bank.increase_account("X", 10);
+        bank
+    }
+
+    #[test]
+    fn commit_local_transaction() {
+        let path = log_path("commit");
+        let mut bank = bank();
+        let mut coordinator = open(&path, &mut bank).unwrap();
+        let legs = vec![
+            leg("X", ReservationKind::Debit, 10),
+            leg("Y", ReservationKind::Credit, 6),
+            leg("Z", ReservationKind::Credit, 4),
+        ];
+        assert!(coordinator.execute(&mut bank, legs, None).is_ok());
+        assert_eq!(0, bank.get_account_balance("X").unwrap());
+        assert_eq!(6, bank.get_account_balance("Y").unwrap());
+        assert_eq!(4, bank.get_account_balance("Z").unwrap());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn abort_on_failed_prepare() {
+        let path = log_path("abort");
+        let mut bank = bank();
+        let mut coordinator = open(&path, &mut bank).unwrap();
+        let legs = vec![
+            leg("X", ReservationKind::Debit, 20),
+            leg("Y", ReservationKind::Credit, 20),
+        ];
+        let x = coordinator.execute(&mut bank, legs, None);
+        assert!(matches!(x, Err(BankError::InsufficientFunds(20))));
+        assert_eq!(10, bank.get_account_balance("X").unwrap());
+        assert!(bank.decrease_account("X", 10).is_ok());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn unbalanced_transaction() {
+        let path = log_path("unbalanced");
+        let mut bank = bank();
+        let mut coordinator = open(&path, &mut bank).unwrap();
+        let legs = vec![
+            leg("X", ReservationKind::Debit, 5),
+            leg("Y", ReservationKind::Credit, 4),
+        ];
+        let x = coordinator.execute(&mut bank, legs, None);
+        assert!(matches!(x, Err(BankError::UnbalancedTransaction)));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn recover_unfinished_transaction() {
+        let path = log_path("recover");
+        let mut bank = bank();
+        let transaction = TransactionId {
+            coordinator: ADDRESS.to_string(),
+            number: 3,
+        };
+        let legs = vec![
+            leg("X", ReservationKind::Debit, 10),
+            leg("Y", ReservationKind::Credit, 10),
+        ];
+        assert!(bank.prepare(transaction.clone(), &legs).is_ok());
+        let records = [
+            LogRecord::Begin {
+                transaction: transaction.clone(),
+                participants: vec![ADDRESS.to_string()],
+                address: ADDRESS.to_string(),
+            },
+            LogRecord::Commit(transaction),
+        ];
+        let log: String = records
+            .iter()
+            .map(|r| serde_json::to_string(r).unwrap() + "\n")
+            .collect();
+        std::fs::write(&path, log).unwrap();
+
+        let coordinator = open(&path, &mut bank).unwrap();
+        assert_eq!(4, coordinator.next_number);
+        assert_eq!(10, bank.get_account_balance("Y").unwrap());
+        assert!(matches!(
+            read_log(&path).unwrap().last(),
+            Some(LogRecord::End(_))
+        ));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn recover_with_lost_legs() {
+        let path = log_path("lost");
+        let transaction = TransactionId {
+            coordinator: ADDRESS.to_string(),
+            number: 0,
+        };
+        let legs = vec![
+            leg("X", ReservationKind::Debit, 10),
+            leg("Y", ReservationKind::Credit, 10),
+        ];
+        let records = [
+            LogRecord::Begin {
+                transaction: transaction.clone(),
+                participants: vec![ADDRESS.to_string()],
+                address: ADDRESS.to_string(),
+            },
+            LogRecord::Commit(transaction.clone()),
+        ];
+        let log: String = records
+            .iter()
+            .map(|r| serde_json::to_string(r).unwrap() + "\n")
+            .collect();
+
+        // Участник проголосовал и перезапустился: его ноги потеряны
+        std::fs::write(&path, &log).unwrap();
+        let mut bank = bank();
+        open(&path, &mut bank).unwrap();
+        assert_eq!(0, bank.get_account_balance("Y").unwrap());
+        assert!(matches!(
+            read_log(&path).unwrap().last(),
+            Some(LogRecord::Commit(_))
+        ));
+
+        // Участник уже зафиксировал транзакцию до сбоя координатора
+        std::fs::write(&path, &log).unwrap();
+        assert!(bank.prepare(transaction.clone(), &legs).is_ok());
+        assert!(bank.commit(&transaction).is_ok());
+        open(&path, &mut bank).unwrap();
+        assert_eq!(10, bank.get_account_balance("Y").unwrap());
+        assert!(matches!(
+            read_log(&path).unwrap().last(),
+            Some(LogRecord::End(_))
+        ));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn retry_unacknowledged_decision() {
+        let path = log_path("retry");
+        let transaction = TransactionId {
+            coordinator: "bank-1".to_string(),
+            number: 0,
+        };
+        // Транзакция началась, когда сервер слушал другой порт
+        let old_address = "127.0.0.1:7000";
+        let legs = vec![
+            TransactionLeg {
+                account: RemoteAccount {
+                    address: old_address.to_string(),
+                    account: "X".into(),
+                },
+                kind: ReservationKind::Debit,
+                amount: 10,
+            },
+            TransactionLeg {
+                account: RemoteAccount {
+                    address: old_address.to_string(),
+                    account: "Y".into(),
+                },
+                kind: ReservationKind::Credit,
+                amount: 10,
+            },
+        ];
+        let records = [
+            LogRecord::Begin {
+                transaction: transaction.clone(),
+                participants: vec![old_address.to_string()],
+                address: old_address.to_string(),
+            },
+            LogRecord::Commit(transaction.clone()),
+        ];
+        let log: String = records
+            .iter()
+            .map(|r| serde_json::to_string(r).unwrap() + "\n")
+            .collect();
+        std::fs::write(&path, log).unwrap();
+
+        let mut bank = bank();
+        let mut coordinator =
+            Coordinator::open("bank-1", ADDRESS, &path, Duration::from_secs(60), &mut bank)
+                .unwrap();
+        assert_eq!(1, coordinator.unacknowledged.len());
+
+        // Участник снова знает транзакцию; решение повторяется не чаще retry_every
+        bank.prepare(transaction, &legs).unwrap();
+        coordinator.retry(&mut bank, Instant::now());
+        assert_eq!(0, bank.get_account_balance("Y").unwrap());
+        coordinator.retry(&mut bank, Instant::now() + Duration::from_secs(60));
+        assert_eq!(10, bank.get_account_balance("Y").unwrap());
+        assert!(coordinator.unacknowledged.is_empty());
+        assert!(matches!(
+            read_log(&path).unwrap().last(),
+            Some(LogRecord::End(_))
+        ));
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,133 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+
+// Зашифрованные данные узнаются по началу, как сжатые - по кадру zstd
+const MAGIC: &[u8] = b"bank-aes256gcm\0";
+const NONCE_SIZE: usize = 12;
+
+/// Key of the encryption at rest. Data is sealed with AES-256-GCM as the
+/// magic bytes, a random nonce and the ciphertext with its tag, so sealed
+/// data is told from plain data written before the key was set.
+#[derive(Clone)]
+pub struct Key(Aes256Gcm);
+
+impl Key {
+    /// Reads the key from `path`: 64 hex digits, e.g. made with
+    /// `openssl rand -hex 32`.
+    pub fn load(path: &Path) -> io::Result<Key> {
+        let text = fs::read_to_string(path)?;
+        let text = text.trim();
+        if text.len() != 64 || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: expected a key of 64 hex digits", path.display()),
+            ));
+        }
+        let mut key = [0u8; 32];
+        for (byte, digits) in key.iter_mut().zip(text.as_bytes().chunks(2)) {
+            // Цифры уже проверены
+            *byte = u8::from_str_radix(std::str::from_utf8(digits).unwrap(), 16).unwrap();
+        }
+        Ok(Key(Aes256Gcm::new(&key.into())))
+    }
+
+    pub fn seal(&self, data: &[u8]) -> Vec<u8> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        // Шифрование не удается только для данных больше 64 ГиБ
+        let sealed = self
+            .0
+            .encrypt(&nonce, data)
+            .expect("data too large to seal");
+        [MAGIC, nonce.as_slice(), &sealed].concat()
+    }
+
+    /// Data sealed by [`Key::seal`]; fails if it was sealed with another key
+    /// or changed since.
+    pub fn open(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let invalid = |e: &str| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+        let data = data
+            .strip_prefix(MAGIC)
+            .filter(|data| data.len() >= NONCE_SIZE)
+            .ok_or_else(|| invalid("the data is not encrypted"))?;
+        let (nonce, sealed) = data.split_at(NONCE_SIZE);
+        self.0
+            .decrypt(Nonce::from_slice(nonce), sealed)
+            .map_err(|_| invalid("the data is damaged or encrypted with another key"))
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+/// `data` sealed with `key`, as it is without one.
+pub fn seal(key: Option<&Key>, data: Vec<u8>) -> Vec<u8> {
+    match key {
+        Some(key) => key.seal(&data),
+        None => data,
+    }
+}
+
+/// `data` opened with `key` if it is sealed, as it is otherwise. Sealed
+/// data without a key is an error rather than garbage further on.
+pub fn open(key: Option<&Key>, data: Vec<u8>) -> io::Result<Vec<u8>> {
+    if !data.starts_with(MAGIC) {
+        return Ok(data);
+    }
+    match key {
+        Some(key) => key.open(&data),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the data is encrypted, but no encryption key is set",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str, text: &str) -> io::Result<Key> {
+        let path = std::env::temp_dir().join(format!("key-{}-{}", name, std::process::id()));
+        fs::write(&path, text).unwrap();
+        let key = Key::load(&path);
+        let _ = fs::remove_file(&path);
+        key
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let first = key("first", &format!("{}\n", "0f".repeat(32))).unwrap();
+        let second = key("second", &"a1".repeat(32)).unwrap();
+        let sealed = seal(Some(&first), b"balance".to_vec());
+        assert!(!sealed.windows(7).any(|window| window == b"balance"));
+        // Случайный nonce: одни и те же данные шифруются по-разному
+        assert_ne!(sealed, first.seal(b"balance"));
+        assert_eq!(
+            b"balance".to_vec(),
+            open(Some(&first), sealed.clone()).unwrap()
+        );
+
+        assert!(open(Some(&second), sealed.clone()).is_err());
+        assert!(open(None, sealed.clone()).is_err());
+        let mut damaged = sealed;
+        *damaged.last_mut().unwrap() ^= 1;
+        assert!(open(Some(&first), damaged).is_err());
+        // Незашифрованные данные читаются как есть
+        assert_eq!(
+            b"plain".to_vec(),
+            open(Some(&first), b"plain".to_vec()).unwrap()
+        );
+        assert_eq!(b"plain".to_vec(), seal(None, b"plain".to_vec()));
+
+        assert!(key("short", "0f0f").is_err());
+        assert!(key("digits", &"+f".repeat(32)).is_err());
+    }
+}
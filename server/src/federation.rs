@@ -0,0 +1,386 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use protocol_crate::archive;
+use protocol_crate::codec::{Bincode, Serializer};
+use protocol_crate::{
+    AccountRef, BankError, Command, HistoryDigest, Operation, Page, PageRequest, RemoteAccount,
+    ReservationId, ReservationKind, Response, ResponsePayload, Snapshot, SnapshotChunk,
+    TransactionId, TransactionLeg,
+};
+
+use crate::bank::Bank;
+
+// Не ждем удаленный сервер бесконечно: он может сам ждать нас
+const REMOTE_TIMEOUT: Duration = Duration::from_secs(5);
+// Снимок для реплики скачивается частями такого размера
+const SNAPSHOT_CHUNK_SIZE: usize = 1024 * 1024;
+// Сколько раз подряд повторять неудавшуюся часть снимка, прежде чем сдаться
+const SNAPSHOT_RETRIES: u32 = 5;
+const SNAPSHOT_RETRY_DELAY: Duration = Duration::from_millis(200);
+// Сколько раз повторять фиксацию, ответ на которую потерялся
+const COMMIT_RETRIES: u32 = 3;
+const COMMIT_RETRY_DELAY: Duration = Duration::from_millis(200);
+// Страница истории удаленного сервера при поиске зачисления
+const HISTORY_PAGE: usize = 1_000;
+
+/// Connection to another bank server taking part in a transfer.
+pub struct RemoteBank {
+    address: String,
+}
+
+impl RemoteBank {
+    pub fn new(address: &str) -> Self {
+        RemoteBank {
+            address: address.to_string(),
+        }
+    }
+
+    pub fn reserve(
+        &self,
+        account: AccountRef,
+        amount: u32,
+        kind: ReservationKind,
+        counterparty: RemoteAccount,
+    ) -> Result<ReservationId, BankError> {
+        let command = Command::Reserve {
+            account,
+            amount,
+            kind,
+            counterparty,
+        };
+        match self.send_command(command)? {
+            ResponsePayload::Reservation(id) => Ok(id),
+            payload => Err(self.unexpected(payload)),
+        }
+    }
+
+    pub fn commit_reservation(&self, id: ReservationId) -> Result<usize, BankError> {
+        match self.send_command(Command::CommitReservation(id))? {
+            ResponsePayload::OperationId(id) => Ok(id),
+            payload => Err(self.unexpected(payload)),
+        }
+    }
+
+    pub fn release_reservation(&self, id: ReservationId) -> Result<(), BankError> {
+        match self.send_command(Command::ReleaseReservation(id))? {
+            ResponsePayload::Done => Ok(()),
+            payload => Err(self.unexpected(payload)),
+        }
+    }
+
+    /// Prepares `legs` on behalf of the client whose `token` came with the
+    /// transaction: debits on the remote server need its owner too.
+    pub fn prepare(
+        &self,
+        transaction: TransactionId,
+        legs: Vec<TransactionLeg>,
+        token: Option<&str>,
+    ) -> Result<(), BankError> {
+        let command = Command::Prepare { transaction, legs };
+        let command = match token {
+            Some(token) => Command::AsIdentity {
+                token: token.to_string(),
+                command: Box::new(command),
+            },
+            None => command,
+        };
+        match self.send_command(command)? {
+            ResponsePayload::Done => Ok(()),
+            payload => Err(self.unexpected(payload)),
+        }
+    }
+
+    pub fn commit(&self, transaction: TransactionId) -> Result<(), BankError> {
+        match self.send_command(Command::Commit(transaction))? {
+            ResponsePayload::Done => Ok(()),
+            payload => Err(self.unexpected(payload)),
+        }
+    }
+
+    pub fn abort(&self, transaction: TransactionId) -> Result<(), BankError> {
+        match self.send_command(Command::Abort(transaction))? {
+            ResponsePayload::Done => Ok(()),
+            payload => Err(self.unexpected(payload)),
+        }
+    }
+
+    /// Snapshot of the remote bank streamed in chunks. A failed chunk is
+    /// asked for again from the bytes already received; if the server has
+    /// moved on to a newer snapshot meanwhile, the download starts over.
+    /// Gives up after [`SNAPSHOT_RETRIES`] failures in a row.
+    pub fn download_snapshot(&self) -> Result<Snapshot, BankError> {
+        let mut operations = None;
+        let mut data = Vec::new();
+        let mut failures = 0;
+        loop {
+            let command = Command::GetSnapshotChunk {
+                operations,
+                offset: data.len() as u64,
+                limit: SNAPSHOT_CHUNK_SIZE,
+            };
+            let chunk = match self.send_command(command) {
+                Ok(ResponsePayload::SnapshotChunk(chunk)) => chunk,
+                Ok(payload) => return Err(self.unexpected(payload)),
+                Err(e) => {
+                    failures += 1;
+                    if failures == SNAPSHOT_RETRIES {
+                        return Err(e);
+                    }
+                    eprintln!("Failed to download snapshot from {}: {:?}", self.address, e);
+                    if let BankError::SnapshotUnavailable(_) = e {
+                        operations = None;
+                        data.clear();
+                    }
+                    thread::sleep(SNAPSHOT_RETRY_DELAY);
+                    continue;
+                }
+            };
+            failures = 0;
+            let SnapshotChunk {
+                operations: chunk_operations,
+                size,
+                data: chunk_data,
+                ..
+            } = chunk;
+            operations = Some(chunk_operations);
+            data.extend(chunk_data);
+            if data.len() as u64 >= size {
+                return archive::decode_snapshot(&data);
+            }
+        }
+    }
+
+    /// Page of the history, transferred in the archive format.
+    pub fn history_archive(&self, page: PageRequest) -> Result<Page<Operation>, BankError> {
+        match self.send_command(Command::GetHistoryArchive { page })? {
+            ResponsePayload::HistoryArchive { data, next_cursor } => Ok(Page {
+                items: archive::decode_history(&data)?,
+                next_cursor,
+            }),
+            payload => Err(self.unexpected(payload)),
+        }
+    }
+
+    /// Number of operations in the history.
+    pub fn history_len(&self) -> Result<usize, BankError> {
+        match self.send_command(Command::GetHistoryDigest { operations: None })? {
+            ResponsePayload::HistoryDigest(digest) => Ok(digest.operations),
+            payload => Err(self.unexpected(payload)),
+        }
+    }
+
+    pub fn history_digest(&self, operations: usize) -> Result<HistoryDigest, BankError> {
+        let command = Command::GetHistoryDigest {
+            operations: Some(operations),
+        };
+        match self.send_command(command)? {
+            ResponsePayload::HistoryDigest(digest) => Ok(digest),
+            payload => Err(self.unexpected(payload)),
+        }
+    }
+
+    /// Sends `command`; an unreachable server is reported as `RemoteUnavailable`.
+    pub fn send_command(&self, command: Command) -> Response {
+        let unavailable =
+            |e: std::io::Error| BankError::RemoteUnavailable(format!("{}: {}", self.address, e));
+
+        let mut stream = TcpStream::connect(&self.address).map_err(unavailable)?;
+        stream
+            .set_read_timeout(Some(REMOTE_TIMEOUT))
+            .map_err(unavailable)?;
+        stream
+            .write_all(&Bincode.encode_command(&command))
+            .map_err(unavailable)?;
+
+        let mut received_data = Vec::new();
+        stream
+            .read_to_end(&mut received_data)
+            .map_err(unavailable)?;
+        Bincode.decode(&received_data).map_err(|e| {
+            BankError::RemoteUnavailable(format!("{}: bad response: {:?}", self.address, e))
+        })?
+    }
+
+    fn unexpected(&self, payload: ResponsePayload) -> BankError {
+        BankError::UnexpectedResponse(format!("{}: {:?}", self.address, payload))
+    }
+}
+
+/// Transfer of `amount` from a local account to an account on another
+/// server, with the funds already held locally.
+///
+/// The remote part runs on the federation worker, never on the thread that
+/// owns the bank: the remote server may at the same time be waiting for
+/// this one. The remote server reserves the credit and commits it; only
+/// after that the local hold is committed. Any failure before the remote
+/// commit releases both reservations, and so does a remote that refuses the
+/// commit. If the answer to the commit is lost, the remote may have credited
+/// the money already: the commit is retried and the remote history searched
+/// for the credit. While that stays unclear the local hold is kept, and the
+/// error names it, so that an operator can commit or release it once the
+/// remote is back. The hold is stored with the bank, so it outlives a
+/// restart of the server.
+pub struct Transfer {
+    local: ReservationId,
+    to: RemoteAccount,
+    counterparty: RemoteAccount,
+    amount: u32,
+}
+
+impl Transfer {
+    /// Holds `amount` on `from` for a transfer to `to`.
+    pub fn hold(
+        bank: &mut Bank,
+        local_address: &str,
+        from: AccountRef,
+        to: RemoteAccount,
+        amount: u32,
+    ) -> Result<Self, BankError> {
+        let local = bank.reserve(from.clone(), amount, ReservationKind::Debit, to.clone())?;
+        Ok(Transfer {
+            local,
+            to,
+            counterparty: RemoteAccount {
+                address: local_address.to_string(),
+                account: from,
+            },
+            amount,
+        })
+    }
+
+    /// Credits the remote account. Talks only to the remote server, so it
+    /// runs off the thread that owns the bank.
+    pub fn run(&self) -> Commit {
+        let remote = RemoteBank::new(&self.to.address);
+        let remote_id = match remote.reserve(
+            self.to.account.clone(),
+            self.amount,
+            ReservationKind::Credit,
+            self.counterparty.clone(),
+        ) {
+            Ok(id) => id,
+            Err(e) => return Commit::Refused(e),
+        };
+        // С этого места истории зачисление ищется, если ответ на фиксацию потеряется
+        let start = match remote.history_len() {
+            Ok(start) => start,
+            Err(e) => {
+                let _ = remote.release_reservation(remote_id);
+                return Commit::Refused(e);
+            }
+        };
+
+        match commit_remote(&remote, remote_id, &self.counterparty, self.amount, start) {
+            Commit::Refused(e) => {
+                let _ = remote.release_reservation(remote_id);
+                Commit::Refused(e)
+            }
+            Commit::InDoubt(e) => {
+                eprintln!(
+                    "Commit of reservation {} on {} is in doubt, keeping local reservation {}: {:?}",
+                    remote_id, remote.address, self.local, e
+                );
+                Commit::InDoubt(BankError::RemoteUnavailable(format!(
+                    "{}: commit of reservation {} is in doubt, local reservation {} is kept",
+                    remote.address, remote_id, self.local
+                )))
+            }
+            Commit::Done => Commit::Done,
+        }
+    }
+
+    /// Settles the local hold by the outcome of [`Transfer::run`].
+    pub fn finish(self, bank: &mut Bank, commit: Commit) -> Result<(), BankError> {
+        match commit {
+            Commit::Done => {
+                bank.commit_reservation(self.local)?;
+                Ok(())
+            }
+            Commit::Refused(e) => {
+                bank.release_reservation(self.local)?;
+                Err(e)
+            }
+            Commit::InDoubt(e) => Err(e),
+        }
+    }
+}
+
+/// Outcome of committing the credit on the remote server.
+pub enum Commit {
+    Done,
+    /// The remote did not credit the money.
+    Refused(BankError),
+    /// The remote could not be asked whether it credited the money.
+    InDoubt(BankError),
+}
+
+/// Commits reservation `id` of a credit from `counterparty`; the remote
+/// history had `start` operations before the commit.
+fn commit_remote(
+    remote: &RemoteBank,
+    id: ReservationId,
+    counterparty: &RemoteAccount,
+    amount: u32,
+    start: usize,
+) -> Commit {
+    let mut last = match remote.commit_reservation(id) {
+        Ok(_) => return Commit::Done,
+        Err(BankError::RemoteUnavailable(e)) => BankError::RemoteUnavailable(e),
+        Err(e) => return Commit::Refused(e),
+    };
+    for _ in 0..COMMIT_RETRIES {
+        thread::sleep(COMMIT_RETRY_DELAY);
+        match remote.commit_reservation(id) {
+            Ok(_) => return Commit::Done,
+            // Резерва уже нет: либо прошлая фиксация дошла, либо удаленный
+            // сервер его потерял; это видно по его истории
+            Err(BankError::ReservationDoesNotExist(_)) => {
+                return match credited(remote, counterparty, amount, start) {
+                    Ok(true) => Commit::Done,
+                    Ok(false) => Commit::Refused(BankError::ReservationDoesNotExist(id)),
+                    Err(e) => Commit::InDoubt(e),
+                };
+            }
+            Err(e @ BankError::RemoteUnavailable(_)) => last = e,
+            Err(e) => return Commit::Refused(e),
+        }
+    }
+    Commit::InDoubt(last)
+}
+
+/// Whether the remote history from `start` has the credit of `amount` from
+/// `counterparty`. The federation worker makes one remote transfer at a
+/// time, so there is no other such credit of this server there.
+fn credited(
+    remote: &RemoteBank,
+    counterparty: &RemoteAccount,
+    amount: u32,
+    start: usize,
+) -> Result<bool, BankError> {
+    let mut page = PageRequest::at(start, HISTORY_PAGE);
+    loop {
+        let got = remote.history_archive(page)?;
+        let found = got.items.iter().any(|operation| {
+            matches!(
+                operation,
+                Operation::RemoteTransferIn { from, amount: credited, .. }
+                    if from == counterparty && *credited == amount
+            )
+        });
+        if found {
+            return Ok(true);
+        }
+        match got.next_cursor {
+            Some(cursor) => {
+                page = PageRequest {
+                    cursor: Some(cursor),
+                    limit: HISTORY_PAGE,
+                }
+            }
+            None => return Ok(false),
+        }
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::VecDeque;
+use std::io;
+use std::net::TcpListener;
+use std::time::Duration;
+
+use async_graphql::http::{GraphiQLSource, WebSocket, WebSocketProtocols, WsMessage};
+use async_graphql::{
+    Context, Data, EmptyMutation, ErrorExtensions, Object, Schema, SimpleObject, Subscription,
+};
+use axum::extract::ws::{Message, WebSocketUpgrade};
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::get;
+use futures_util::{future, SinkExt, Stream, StreamExt};
+
+use banklib::BankClient;
+use protocol_crate::{AccountFilter, BankError, Cursor, Operation, Page, PageRequest};
+
+use crate::query;
+
+// Больше стольких записей за раз страница не отдает
+const MAX_PAGE: usize = 1_000;
+
+type BankSchema = Schema<QueryRoot, EmptyMutation, SubscriptionRoot>;
+
+/// GraphQL gateway to a bank server for dashboards: accounts, balances and
+/// paginated histories over HTTP at `/graphql`, and new operations as a
+/// subscription over a WebSocket at `/graphql/ws`. Every request is passed
+/// on as bank commands with the token of `Authorization: Bearer <token>` as
+/// the identity; a WebSocket client may send it in the `connection_init`
+/// payload instead.
+pub struct GraphqlGateway {
+    server: String,
+    poll: Duration,
+}
+
+/// Identity token of the caller.
+#[derive(Clone)]
+struct Identity(String);
+
+/// Account with its balance.
+#[derive(SimpleObject)]
+#[graphql(complex)]
+struct Account {
+    name: String,
+    balance: u32,
+}
+
+#[derive(SimpleObject)]
+struct AccountPage {
+    accounts: Vec<Account>,
+    /// Cursor of the next page; null on the last page.
+    next_cursor: Option<String>,
+}
+
+/// Operation of the history: its kind as in the CSV export and the accounts
+/// the money comes from and goes to.
+#[derive(SimpleObject)]
+#[graphql(name = "Operation")]
+struct OperationNode {
+    kind: String,
+    from: Option<String>,
+    to: Option<String>,
+    amount: u32,
+}
+
+#[derive(SimpleObject)]
+struct OperationPage {
+    operations: Vec<OperationNode>,
+    /// Cursor of the next page; null on the last page.
+    next_cursor: Option<String>,
+}
+
+/// Operation just appended to the history, with its ID there.
+#[derive(SimpleObject)]
+struct OperationEvent {
+    id: usize,
+    operation: OperationNode,
+}
+
+struct QueryRoot;
+
+struct SubscriptionRoot;
+
+impl GraphqlGateway {
+    /// Gateway to the bank server at `server`; subscriptions look for new
+    /// operations every `poll`.
+    pub fn new(server: &str, poll: Duration) -> Self {
+        GraphqlGateway {
+            server: server.to_string(),
+            poll,
+        }
+    }
+
+    /// Serves GraphQL requests on `listener` until the process ends.
+    pub fn serve(self, listener: TcpListener) -> io::Result<()> {
+        listener.set_nonblocking(true)?;
+        let schema = Schema::build(QueryRoot, EmptyMutation, SubscriptionRoot)
+            .data(self)
+            .finish();
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(async move {
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            let app = axum::Router::new()
+                .route("/graphql", get(graphiql).post(execute))
+                .route("/graphql/ws", get(subscribe))
+                .with_state(schema);
+            axum::serve(listener, app).await
+        })
+    }
+}
+
+#[Object]
+impl QueryRoot {
+    /// Account by name.
+    async fn account(&self, ctx: &Context<'_>, name: String) -> async_graphql::Result<Account> {
+        let balance = call(ctx, {
+            let name = name.clone();
+            move |client| client.get_account_balance(name.as_str())
+        })
+        .await?;
+        Ok(Account { name, balance })
+    }
+
+    /// Page of the accounts in the order they were created.
+    async fn accounts(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 100)] first: usize,
+        after: Option<String>,
+    ) -> async_graphql::Result<AccountPage> {
+        let page = page_request(first, after);
+        let page = call(ctx, move |client| {
+            client.list_accounts_paged(AccountFilter::All, page)
+        })
+        .await?;
+        Ok(AccountPage {
+            accounts: page
+                .items
+                .into_iter()
+                .map(|(name, balance)| Account { name, balance })
+                .collect(),
+            next_cursor: page.next_cursor.map(String::from),
+        })
+    }
+
+    /// Page of the bank history, oldest first.
+    async fn history(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 100)] first: usize,
+        after: Option<String>,
+    ) -> async_graphql::Result<OperationPage> {
+        let page = page_request(first, after);
+        let page = call(ctx, move |client| client.get_history_paged(page)).await?;
+        Ok(operation_page(page))
+    }
+}
+
+#[async_graphql::ComplexObject]
+impl Account {
+    /// Page of the operations of the account, oldest first.
+    async fn history(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 100)] first: usize,
+        after: Option<String>,
+    ) -> async_graphql::Result<OperationPage> {
+        let page = page_request(first, after);
+        let name = self.name.clone();
+        let page = call(ctx, move |client| {
+            client.account_history_paged(name.as_str(), page)
+        })
+        .await?;
+        Ok(operation_page(page))
+    }
+}
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// Operations of the history from ID `from`, by default the ones
+    /// appended after the subscription starts; with `account`, only the
+    /// ones touching that account.
+    async fn operations(
+        &self,
+        ctx: &Context<'_>,
+        from: Option<usize>,
+        account: Option<String>,
+    ) -> async_graphql::Result<impl Stream<Item = async_graphql::Result<OperationEvent>>> {
+        let start = match from {
+            Some(from) => from,
+            None => {
+                call(ctx, |client| client.history_digest(None))
+                    .await?
+                    .operations
+            }
+        };
+        let gateway = ctx.data_unchecked::<GraphqlGateway>();
+        let (server, poll) = (gateway.server.clone(), gateway.poll);
+        let identity = ctx.data_opt::<Identity>().cloned();
+        // Новые операции забираются страницами с сервера, как это делают реплики
+        let state = (start, VecDeque::new(), true);
+        Ok(futures_util::stream::unfold(
+            state,
+            move |(mut next, mut pending, mut first)| {
+                let (server, identity, account) =
+                    (server.clone(), identity.clone(), account.clone());
+                async move {
+                    loop {
+                        if let Some(event) = pending.pop_front() {
+                            return Some((Ok(event), (next, pending, first)));
+                        }
+                        if !first {
+                            tokio::time::sleep(poll).await;
+                        }
+                        first = false;
+                        let client = client(&server, identity.as_ref());
+                        let fetched = tokio::task::spawn_blocking(move || {
+                            client
+                                .get_history_paged(PageRequest::at(next, MAX_PAGE))
+                                .map(|page| page.items)
+                        })
+                        .await
+                        .map_err(|e| async_graphql::Error::new(e.to_string()))
+                        .and_then(|result| result.map_err(error));
+                        let operations = match fetched {
+                            Ok(operations) => operations,
+                            Err(e) => return Some((Err(e), (next, pending, first))),
+                        };
+                        for operation in operations {
+                            let touches = account
+                                .as_deref()
+                                .is_none_or(|account| operation.accounts().contains(&account));
+                            if touches {
+                                pending.push_back(OperationEvent {
+                                    id: next,
+                                    operation: node(&operation),
+                                });
+                            }
+                            next += 1;
+                        }
+                    }
+                }
+            },
+        ))
+    }
+}
+
+async fn graphiql() -> Html<String> {
+    Html(
+        GraphiQLSource::build()
+            .endpoint("/graphql")
+            .subscription_endpoint("/graphql/ws")
+            .finish(),
+    )
+}
+
+async fn execute(State(schema): State<BankSchema>, headers: HeaderMap, body: String) -> Response {
+    let request: async_graphql::Request = match serde_json::from_str(&body) {
+        Ok(request) => request,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    let request = match bearer(
+        headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok()),
+    ) {
+        Some(identity) => request.data(identity),
+        None => request,
+    };
+    axum::Json(schema.execute(request).await).into_response()
+}
+
+async fn subscribe(
+    State(schema): State<BankSchema>,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let protocol = headers
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').find_map(|p| p.trim().parse().ok()))
+        .unwrap_or(WebSocketProtocols::SubscriptionsTransportWS);
+    let mut data = Data::default();
+    if let Some(identity) = bearer(
+        headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok()),
+    ) {
+        data.insert(identity);
+    }
+    upgrade
+        .protocols([protocol.sec_websocket_protocol()])
+        .on_upgrade(move |socket| async move {
+            let (mut sink, stream) = socket.split();
+            let input = stream
+                .take_while(|message| future::ready(message.is_ok()))
+                .filter_map(|message| {
+                    future::ready(match message {
+                        Ok(Message::Text(text)) => Some(text.as_bytes().to_vec()),
+                        Ok(Message::Binary(data)) => Some(data.to_vec()),
+                        _ => None,
+                    })
+                });
+            let mut output = WebSocket::new(schema, input, protocol)
+                .connection_data(data)
+                .on_connection_init(|payload| async move {
+                    let mut data = Data::default();
+                    let token = payload
+                        .get("Authorization")
+                        .and_then(|value| value.as_str());
+                    if let Some(identity) = bearer(token) {
+                        data.insert(identity);
+                    }
+                    Ok(data)
+                });
+            while let Some(message) = output.next().await {
+                let message = match message {
+                    WsMessage::Text(text) => Message::Text(text.into()),
+                    WsMessage::Close(code, reason) => {
+                        Message::Close(Some(axum::extract::ws::CloseFrame {
+                            code,
+                            reason: reason.into(),
+                        }))
+                    }
+                };
+                if sink.send(message).await.is_err() {
+                    break;
+                }
+            }
+        })
+}
+
+/// Identity of an `Authorization: Bearer <token>` value.
+fn bearer(value: Option<&str>) -> Option<Identity> {
+    value
+        .and_then(|value| value.trim().strip_prefix("Bearer "))
+        .map(|token| Identity(token.to_string()))
+}
+
+fn client(server: &str, identity: Option<&Identity>) -> BankClient {
+    let client = BankClient::new(server);
+    match identity {
+        Some(Identity(token)) => client.with_identity(token),
+        None => client,
+    }
+}
+
+/// Runs blocking banklib calls off the async threads.
+async fn call<T, F>(ctx: &Context<'_>, f: F) -> async_graphql::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&BankClient) -> Result<T, BankError> + Send + 'static,
+{
+    let gateway = ctx.data_unchecked::<GraphqlGateway>();
+    let client = client(&gateway.server, ctx.data_opt::<Identity>());
+    tokio::task::spawn_blocking(move || f(&client))
+        .await
+        .map_err(|e| async_graphql::Error::new(e.to_string()))?
+        .map_err(error)
+}
+
+/// GraphQL error of a bank error, with its name as the `code` extension.
+fn error(e: BankError) -> async_graphql::Error {
+    // banklib помечает ошибку номером запроса, клиенту GraphQL он не нужен
+    let e = match e {
+        BankError::RequestFailed { error, .. } => *error,
+        e => e,
+    };
+    let code = e.name();
+    async_graphql::Error::new(format!("{:?}", e)).extend_with(|_, extensions| {
+        extensions.set("code", code);
+    })
+}
+
+fn page_request(first: usize, after: Option<String>) -> PageRequest {
+    PageRequest {
+        cursor: after.map(Cursor::from),
+        limit: first.clamp(1, MAX_PAGE),
+    }
+}
+
+fn operation_page(page: Page<Operation>) -> OperationPage {
+    OperationPage {
+        operations: page.items.iter().map(node).collect(),
+        next_cursor: page.next_cursor.map(String::from),
+    }
+}
+
+fn node(operation: &Operation) -> OperationNode {
+    let (kind, from, to) = query::sides(operation);
+    OperationNode {
+        kind: kind.to_string(),
+        from,
+        to,
+        amount: operation.amount(),
+    }
+}
@@ -0,0 +1,852 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use protocol_crate::Operation;
+
+use crate::encryption::{self, Key};
+use crate::names::Names;
+use crate::storage;
+
+// Сколько операций в одном файле сегмента
+pub const SEGMENT_SIZE: usize = 10_000;
+// Смещение в файле сегмента запоминается у каждой такой по счету операции,
+// до остальных строки дочитываются
+const OFFSET_STRIDE: usize = 64;
+
+const PREFIX: &str = "segment-";
+const SUFFIX: &str = ".jsonl";
+// Дописывается к имени сжатого (архивного) сегмента
+const ARCHIVE_SUFFIX: &str = ".zst";
+// Архив пишется один раз и читается редко: сжатие посильнее
+const ARCHIVE_LEVEL: i32 = 9;
+
+// Операции со временем, как они лежат в сегменте
+type Timed = Vec<(u64, Operation)>;
+
+/// Sealed segment file: operations `first..first + len`, one JSON line
+/// `[timestamp, operation]` per operation. An archived segment is
+/// compressed, an encrypted one is sealed as a whole; both are read back
+/// whole.
+#[derive(Debug)]
+struct Segment {
+    first: usize,
+    len: usize,
+    // Смещения строк каждой OFFSET_STRIDE-й операции в файле; у архивного и
+    // зашифрованного сегмента их нет
+    offsets: Vec<u64>,
+    archived: bool,
+}
+
+#[derive(Debug)]
+struct Segments {
+    dir: PathBuf,
+    segment_size: usize,
+    // Сколько последних вынесенных операций оставлять и в памяти
+    keep: usize,
+    // Сколько операций держать в несжатых сегментах; старые сжимаются
+    archive_after: Option<usize>,
+    sealed: Vec<Segment>,
+    // Ключ, которым шифруются файлы сегментов
+    key: Option<Key>,
+    // Последний сегмент, прочитанный целиком: запросы истории идут подряд
+    cache: Mutex<Option<(usize, Arc<Timed>)>>,
+    // Сколько раз сегменты переписывались: так читающий их в другом потоке
+    // узнает о переименовании
+    rewrites: Arc<AtomicU64>,
+}
+
+/// Operations of a history with their time as they were at
+/// [`History::freeze`], to be read on another thread while the history goes
+/// on. The sealed segments are read from their files; the operations not in
+/// a segment yet were copied.
+#[derive(Debug)]
+pub struct FrozenHistory {
+    dir: PathBuf,
+    // Номер первой операции и сжат ли сегмент на момент заморозки
+    sealed: Vec<(usize, bool)>,
+    key: Option<Key>,
+    // Счетчик переписываний сегментов и его значение на момент заморозки
+    rewrites: Option<(Arc<AtomicU64>, u64)>,
+    rest: Timed,
+}
+
+/// Operation kept in memory. Local account names are the shared copies from
+/// [`Names`] rather than a `String` per operation.
+#[derive(Debug)]
+enum Entry {
+    CreateAccount(Arc<str>),
+    IncreaseAccount(Arc<str>, u32),
+    DecreaseAccount(Arc<str>, u32),
+    Transfer(Arc<str>, Arc<str>, u32),
+    // Остальные операции редки, в коробке они не раздувают частые
+    Other(Box<Operation>),
+}
+
+impl Entry {
+    fn new(operation: Operation, names: &Names) -> Self {
+        match operation {
+            Operation::CreateAccount(account) => Entry::CreateAccount(names.intern(&account)),
+            Operation::IncreaseAccount(account, amount) => {
+                Entry::IncreaseAccount(names.intern(&account), amount)
+            }
+            Operation::DecreaseAccount(account, amount) => {
+                Entry::DecreaseAccount(names.intern(&account), amount)
+            }
+            Operation::Transfer(from, to, amount) => {
+                Entry::Transfer(names.intern(&from), names.intern(&to), amount)
+            }
+            operation => Entry::Other(Box::new(operation)),
+        }
+    }
+
+    fn to_operation(&self) -> Operation {
+        match self {
+            Entry::CreateAccount(account) => Operation::CreateAccount(account.to_string()),
+            Entry::IncreaseAccount(account, amount) => {
+                Operation::IncreaseAccount(account.to_string(), *amount)
+            }
+            Entry::DecreaseAccount(account, amount) => {
+                Operation::DecreaseAccount(account.to_string(), *amount)
+            }
+            Entry::Transfer(from, to, amount) => {
+                Operation::Transfer(from.to_string(), to.to_string(), *amount)
+            }
+            Entry::Other(operation) => (**operation).clone(),
+        }
+    }
+}
+
+/// Operation history of a bank with the time of every operation. By default
+/// it lives in memory; with a directory every `segment_size` operations are
+/// written to a segment file and only the `keep` most recent of them stay
+/// in memory as well. Older operations are read back from their segments on
+/// demand. With
+/// `archive_after`, the oldest segments beyond that many operations are
+/// archived: compressed with zstd and marked as such, then loaded whole
+/// when a query reaches back to them. With a key the segment files are
+/// encrypted and every segment is loaded whole.
+#[derive(Debug, Default)]
+pub struct History {
+    segments: Option<Segments>,
+    // Номер первой операции, которая есть в памяти
+    first: usize,
+    // Операции с номера first: еще не вынесенные в сегмент и последние
+    // вынесенные (без каталога - вся история)
+    tail: Vec<Entry>,
+    // Время операций tail (unix, секунды)
+    timestamps: Vec<u64>,
+}
+
+impl History {
+    /// History kept in segment files under `dir`, with at least the `keep`
+    /// most recent operations in memory and, with `archive_after`, at most
+    /// about that many in uncompressed segments, encrypted with `key` if
+    /// there is one. Segments left there by a previous run are removed: the
+    /// state is restored from snapshots.
+    pub fn segmented(
+        dir: &Path,
+        segment_size: usize,
+        keep: usize,
+        archive_after: Option<usize>,
+        key: Option<Key>,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if is_segment(&path) {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(History {
+            segments: Some(Segments {
+                dir: dir.to_path_buf(),
+                segment_size: segment_size.max(1),
+                keep,
+                archive_after,
+                sealed: Vec::new(),
+                key,
+                cache: Mutex::new(None),
+                rewrites: Arc::default(),
+            }),
+            first: 0,
+            tail: Vec::new(),
+            timestamps: Vec::new(),
+        })
+    }
+
+    /// History in memory made of `operations` made at `timestamps`.
+    pub fn from_operations(
+        operations: Vec<Operation>,
+        timestamps: Vec<u64>,
+        names: &Names,
+    ) -> Self {
+        History {
+            segments: None,
+            first: 0,
+            tail: operations
+                .into_iter()
+                .map(|operation| Entry::new(operation, names))
+                .collect(),
+            timestamps,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.first + self.tail.len()
+    }
+
+    /// Appends `operation` made at `timestamp`; its account names are
+    /// replaced by their shared copies from `names`.
+    pub fn push(&mut self, operation: Operation, timestamp: u64, names: &Names) {
+        self.tail.push(Entry::new(operation, names));
+        self.timestamps.push(timestamp);
+        let Some(segments) = &mut self.segments else {
+            return;
+        };
+        // После неудачной записи хвост пробуем вынести снова через сегмент
+        let unsealed = self.first + self.tail.len() - segments.len();
+        if unsealed.is_multiple_of(segments.segment_size) {
+            let from = self.tail.len() - unsealed;
+            let sealed = segments
+                .seal(&self.tail[from..], &self.timestamps[from..])
+                .and_then(|()| segments.archive());
+            match sealed {
+                Ok(()) => {
+                    let dropped = self.tail.len().saturating_sub(segments.keep);
+                    self.tail.drain(..dropped);
+                    self.timestamps.drain(..dropped);
+                    self.first += dropped;
+                }
+                Err(e) => eprintln!(
+                    "Failed to write history segment to {}: {}",
+                    segments.dir.display(),
+                    e
+                ),
+            }
+        }
+    }
+
+    /// Time of operation `index`; panics if it is out of range, like
+    /// indexing a `Vec`.
+    pub fn timestamp(&self, index: usize) -> u64 {
+        match index.checked_sub(self.first) {
+            Some(offset) => self.timestamps[offset],
+            None => self.entry(index).0,
+        }
+    }
+
+    // Операция index со временем; из сегмента читается только ее строка
+    fn entry(&self, index: usize) -> (u64, Operation) {
+        if index >= self.first {
+            let offset = index - self.first;
+            return (self.timestamps[offset], self.tail[offset].to_operation());
+        }
+        let segments = self.segments.as_ref().unwrap();
+        let segment = &segments.sealed[segments.find(index)];
+        if segments.whole(segment) {
+            return segments.load_whole(segment)[index - segment.first].clone();
+        }
+        segments
+            .read_at(segment, index - segment.first)
+            .unwrap_or_else(|e| segments.failed(segment, e))
+    }
+
+    /// Operations `start..end`, cut to the history length.
+    pub fn range(&self, start: usize, end: usize) -> Vec<Operation> {
+        self.entries(start, end)
+            .into_iter()
+            .map(|(_, operation)| operation)
+            .collect()
+    }
+
+    /// Operations `start..end` with their time, cut to the history length.
+    pub fn entries(&self, start: usize, end: usize) -> Vec<(u64, Operation)> {
+        let end = end.min(self.len());
+        let start = start.min(end);
+        let mut operations = Vec::with_capacity(end - start);
+        // С диска читается только то, чего уже нет в памяти
+        let on_disk = end.min(self.first);
+        if let Some(segments) = &self.segments {
+            if start < on_disk {
+                for segment in &segments.sealed[segments.find(start)..] {
+                    if segment.first >= on_disk {
+                        break;
+                    }
+                    let loaded = segments.load(segment);
+                    let from = start.saturating_sub(segment.first);
+                    let to = (on_disk - segment.first).min(loaded.len());
+                    operations.extend(loaded.into_iter().take(to).skip(from));
+                }
+            }
+        }
+        if end > self.first {
+            let from = start.max(self.first) - self.first;
+            let to = end - self.first;
+            let tail = self.tail[from..to].iter().map(Entry::to_operation);
+            operations.extend(self.timestamps[from..to].iter().copied().zip(tail));
+        }
+        operations
+    }
+
+    /// Renames the accounts `old -> new` of `renames` in every operation;
+    /// the renamed operations in memory share their names with `names`.
+    /// Sealed segments with a renamed account are written anew.
+    pub fn rename(&mut self, renames: &HashMap<String, String>, names: &Names) {
+        for entry in &mut self.tail {
+            let mut operation = entry.to_operation();
+            if storage::rename(&mut operation, renames) {
+                *entry = Entry::new(operation, names);
+            }
+        }
+        let Some(segments) = &mut self.segments else {
+            return;
+        };
+        for index in 0..segments.sealed.len() {
+            let mut operations = segments.load(&segments.sealed[index]);
+            let mut renamed = false;
+            for (_, operation) in &mut operations {
+                renamed |= storage::rename(operation, renames);
+            }
+            if renamed {
+                segments
+                    .rewrite(index, &operations)
+                    .unwrap_or_else(|e| segments.failed(&segments.sealed[index], e));
+            }
+        }
+    }
+
+    /// The operations as they are now, to be read on another thread; only
+    /// those not in a sealed segment are copied here.
+    pub fn freeze(&self) -> FrozenHistory {
+        let Some(segments) = &self.segments else {
+            return FrozenHistory::in_memory(self.entries(0, self.len()));
+        };
+        FrozenHistory {
+            dir: segments.dir.clone(),
+            sealed: segments
+                .sealed
+                .iter()
+                .map(|segment| (segment.first, segment.archived))
+                .collect(),
+            key: segments.key.clone(),
+            rewrites: Some((
+                Arc::clone(&segments.rewrites),
+                segments.rewrites.load(Ordering::SeqCst),
+            )),
+            rest: self.entries(segments.len(), self.len()),
+        }
+    }
+
+    /// All operations in order; sealed segments are read one at a time.
+    pub fn iter(&self) -> impl Iterator<Item = Operation> + '_ {
+        let sealed = self.segments.iter().flat_map(|segments| {
+            segments
+                .sealed
+                .iter()
+                .flat_map(|segment| segments.load(segment))
+        });
+        sealed
+            .take(self.first)
+            .map(|(_, operation)| operation)
+            .chain(self.tail.iter().map(Entry::to_operation))
+    }
+}
+
+impl FrozenHistory {
+    /// Operations already read, e.g. from a storage without segments.
+    pub fn in_memory(entries: Vec<(u64, Operation)>) -> Self {
+        FrozenHistory {
+            dir: PathBuf::new(),
+            sealed: Vec::new(),
+            key: None,
+            rewrites: None,
+            rest: entries,
+        }
+    }
+
+    /// Reads the operations. Fails if a segment was rewritten since the
+    /// freeze, i.e. accounts were renamed: the segments no longer match the
+    /// copied operations.
+    pub fn read(self) -> io::Result<Vec<(u64, Operation)>> {
+        let mut operations = Vec::new();
+        for (first, archived) in self.sealed {
+            // Сегмент могли сжать после заморозки: содержимое у него то же
+            let key = self.key.as_ref();
+            let loaded = match read_segment(&path(&self.dir, first, archived), archived, key) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound && !archived => {
+                    read_segment(&path(&self.dir, first, true), true, key)
+                }
+                loaded => loaded,
+            };
+            operations.extend(loaded?);
+        }
+        if let Some((rewrites, frozen)) = self.rewrites {
+            if rewrites.load(Ordering::SeqCst) != frozen {
+                return Err(io::Error::other(
+                    "history segments were rewritten while they were read",
+                ));
+            }
+        }
+        operations.extend(self.rest);
+        Ok(operations)
+    }
+}
+
+impl Segments {
+    fn path(&self, first: usize, archived: bool) -> PathBuf {
+        path(&self.dir, first, archived)
+    }
+
+    fn segment_path(&self, segment: &Segment) -> PathBuf {
+        self.path(segment.first, segment.archived)
+    }
+
+    /// Number of operations written to segments.
+    fn len(&self) -> usize {
+        self.sealed
+            .last()
+            .map_or(0, |segment| segment.first + segment.len)
+    }
+
+    /// Whether `segment` is read whole rather than line by line.
+    fn whole(&self, segment: &Segment) -> bool {
+        segment.archived || self.key.is_some()
+    }
+
+    // Зашифрованный сегмент читается целиком, смещения в нем не нужны
+    fn offsets(&self, offsets: Vec<u64>) -> Vec<u64> {
+        match self.key {
+            Some(_) => Vec::new(),
+            None => offsets,
+        }
+    }
+
+    /// Index of the segment holding operation `index`.
+    fn find(&self, index: usize) -> usize {
+        self.sealed
+            .partition_point(|segment| segment.first <= index)
+            - 1
+    }
+
+    fn seal(&mut self, operations: &[Entry], timestamps: &[u64]) -> io::Result<()> {
+        let first = self.len();
+        let entries = operations.iter().map(Entry::to_operation);
+        let (data, offsets) = encode(timestamps.iter().copied().zip(entries));
+        let mut file = File::create(self.path(first, false))?;
+        file.write_all(&encryption::seal(self.key.as_ref(), data))?;
+        self.sealed.push(Segment {
+            first,
+            len: operations.len(),
+            offsets: self.offsets(offsets),
+            archived: false,
+        });
+        Ok(())
+    }
+
+    /// Archives the oldest uncompressed segments while more than
+    /// `archive_after` operations are in them. The compressed file is
+    /// complete before the uncompressed one is removed.
+    fn archive(&mut self) -> io::Result<()> {
+        let Some(archive_after) = self.archive_after else {
+            return Ok(());
+        };
+        let mut plain: usize = self
+            .sealed
+            .iter()
+            .filter(|segment| !segment.archived)
+            .map(|segment| segment.len)
+            .sum();
+        for index in 0..self.sealed.len() {
+            if plain <= archive_after {
+                break;
+            }
+            if self.sealed[index].archived {
+                continue;
+            }
+            let path = self.segment_path(&self.sealed[index]);
+            let data = encryption::open(self.key.as_ref(), fs::read(&path)?)?;
+            self.write_archive(self.sealed[index].first, &data)?;
+            fs::remove_file(&path)?;
+            let segment = &mut self.sealed[index];
+            segment.archived = true;
+            segment.offsets = Vec::new();
+            plain -= segment.len;
+        }
+        Ok(())
+    }
+
+    // Сегмент data сжимается и шифруется
+    fn write_archive(&self, first: usize, data: &[u8]) -> io::Result<()> {
+        let path = self.path(first, true);
+        let temp = path.with_extension("tmp");
+        let data = zstd::encode_all(data, ARCHIVE_LEVEL)?;
+        fs::write(&temp, encryption::seal(self.key.as_ref(), data))?;
+        fs::rename(&temp, &path)
+    }
+
+    // Новое содержимое пишется рядом и подменяет сегмент целиком
+    fn rewrite(&mut self, index: usize, operations: &[(u64, Operation)]) -> io::Result<()> {
+        self.rewrites.fetch_add(1, Ordering::SeqCst);
+        let (data, offsets) = encode(operations.iter().cloned());
+        let segment = &self.sealed[index];
+        *self.cache.lock().unwrap() = None;
+        if segment.archived {
+            return self.write_archive(segment.first, &data);
+        }
+        let path = self.segment_path(segment);
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, encryption::seal(self.key.as_ref(), data))?;
+        fs::rename(&temp, &path)?;
+        self.sealed[index].offsets = self.offsets(offsets);
+        Ok(())
+    }
+
+    /// Operation `index` of the uncompressed `segment`: the read starts at
+    /// the nearest remembered offset before it.
+    fn read_at(&self, segment: &Segment, index: usize) -> io::Result<(u64, Operation)> {
+        let mut file = File::open(self.segment_path(segment))?;
+        file.seek(SeekFrom::Start(segment.offsets[index / OFFSET_STRIDE]))?;
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+        for _ in 0..=index % OFFSET_STRIDE {
+            line.clear();
+            reader.read_line(&mut line)?;
+        }
+        serde_json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn load(&self, segment: &Segment) -> Vec<(u64, Operation)> {
+        if self.whole(segment) {
+            return self.load_whole(segment).to_vec();
+        }
+        read_segment(&self.segment_path(segment), false, None)
+            .unwrap_or_else(|e| self.failed(segment, e))
+    }
+
+    /// Operations of the archived or encrypted `segment`, read once for a
+    /// run of queries into it.
+    fn load_whole(&self, segment: &Segment) -> Arc<Timed> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some((first, operations)) = &*cache {
+            if *first == segment.first {
+                return Arc::clone(operations);
+            }
+        }
+        let path = self.segment_path(segment);
+        let operations = read_segment(&path, segment.archived, self.key.as_ref())
+            .unwrap_or_else(|e| self.failed(segment, e));
+        let operations = Arc::new(operations);
+        *cache = Some((segment.first, Arc::clone(&operations)));
+        operations
+    }
+
+    // Сегмент - это вынесенная на диск часть памяти: без него состояние потеряно
+    fn failed(&self, segment: &Segment, e: io::Error) -> ! {
+        panic!(
+            "Failed to read history segment {}: {}",
+            self.segment_path(segment).display(),
+            e
+        )
+    }
+}
+
+// Операции со временем по строке JSON и смещения каждой OFFSET_STRIDE-й строки
+fn encode(operations: impl Iterator<Item = (u64, Operation)>) -> (Vec<u8>, Vec<u64>) {
+    let mut data = Vec::new();
+    let mut offsets = Vec::new();
+    for (index, entry) in operations.enumerate() {
+        if index.is_multiple_of(OFFSET_STRIDE) {
+            offsets.push(data.len() as u64);
+        }
+        serde_json::to_writer(&mut data, &entry).unwrap();
+        data.push(b'\n');
+    }
+    (data, offsets)
+}
+
+fn path(dir: &Path, first: usize, archived: bool) -> PathBuf {
+    // Ведущие нули, чтобы файлы сортировались по номеру операции
+    let archived = if archived { ARCHIVE_SUFFIX } else { "" };
+    dir.join(format!("{}{:020}{}{}", PREFIX, first, SUFFIX, archived))
+}
+
+fn read_segment(path: &Path, archived: bool, key: Option<&Key>) -> io::Result<Timed> {
+    let data = encryption::open(key, fs::read(path)?)?;
+    match archived {
+        true => decode(BufReader::new(zstd::Decoder::new(&data[..])?)),
+        false => decode(&data[..]),
+    }
+}
+
+fn decode(reader: impl BufRead) -> io::Result<Vec<(u64, Operation)>> {
+    reader
+        .lines()
+        .map(|line| {
+            serde_json::from_str(&line?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        })
+        .collect()
+}
+
+fn is_segment(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.strip_suffix(ARCHIVE_SUFFIX).unwrap_or(name))
+        .is_some_and(|name| name.starts_with(PREFIX) && name.ends_with(SUFFIX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segments() {
+        let dir = std::env::temp_dir().join(format!("history-{}", std::process::id()));
+        let mut history = History::segmented(&dir, 3, 0, None, None).unwrap();
+        let operations: Vec<Operation> = (1..=8)
+            .map(|amount| Operation::IncreaseAccount("X".to_string(), amount))
+            .collect();
+        let names = Names::default();
+        for (index, operation) in operations.iter().enumerate() {
+            history.push(operation.clone(), index as u64 * 10, &names);
+        }
+
+        assert_eq!(8, history.len());
+        assert_eq!(2, history.tail.len());
+        assert_eq!(2, fs::read_dir(&dir).unwrap().count());
+        assert_eq!(operations, history.iter().collect::<Vec<_>>());
+        for (index, operation) in operations.iter().enumerate() {
+            assert_eq!(*operation, history.entry(index).1);
+        }
+        assert_eq!(operations[2..7], history.range(2, 7));
+        assert_eq!(operations[7..], history.range(7, 100));
+        assert!(history.range(9, 12).is_empty());
+        // Время хранится вместе с операцией и в сегментах, и в памяти
+        assert_eq!(20, history.timestamp(2));
+        assert_eq!(70, history.timestamp(7));
+        assert_eq!(
+            vec![(50, operations[5].clone()), (60, operations[6].clone())],
+            history.entries(5, 7)
+        );
+
+        // Сегменты прошлого запуска не подхватываются
+        let history = History::segmented(&dir, 3, 0, None, None).unwrap();
+        assert_eq!(0, history.len());
+        assert_eq!(0, fs::read_dir(&dir).unwrap().count());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn sparse_offsets() {
+        let dir = std::env::temp_dir().join(format!("history-offsets-{}", std::process::id()));
+        let mut history = History::segmented(&dir, 150, 0, None, None).unwrap();
+        let operations: Vec<Operation> = (1..=300)
+            .map(|amount| Operation::IncreaseAccount("X".to_string(), amount))
+            .collect();
+        let names = Names::default();
+        for (index, operation) in operations.iter().enumerate() {
+            history.push(operation.clone(), index as u64 * 10, &names);
+        }
+
+        // В памяти смещение каждой OFFSET_STRIDE-й строки, остальные дочитываются
+        let sealed = &history.segments.as_ref().unwrap().sealed;
+        assert_eq!(
+            vec![3, 3],
+            sealed.iter().map(|s| s.offsets.len()).collect::<Vec<_>>()
+        );
+        for (index, operation) in operations.iter().enumerate() {
+            assert_eq!(*operation, history.entry(index).1);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn renamed() {
+        let dir = std::env::temp_dir().join(format!("history-renamed-{}", std::process::id()));
+        let mut history = History::segmented(&dir, 2, 1, None, None).unwrap();
+        let mut names = Names::default();
+        names.push("X");
+        names.push("Y");
+        let operations = [
+            Operation::IncreaseAccount("X".to_string(), 1),
+            Operation::IncreaseAccount("Y".to_string(), 2),
+            Operation::Transfer("X".to_string(), "Y".to_string(), 3),
+            Operation::DecreaseAccount("X".to_string(), 4),
+            Operation::IncreaseAccount("Y".to_string(), 5),
+        ];
+        for (index, operation) in operations.iter().enumerate() {
+            history.push(operation.clone(), index as u64 * 10, &names);
+        }
+
+        names.rename(0, "Z");
+        let renames = HashMap::from([("X".to_string(), "Z".to_string())]);
+        history.rename(&renames, &names);
+        let expected: Vec<Operation> = operations
+            .into_iter()
+            .map(|mut operation| {
+                storage::rename(&mut operation, &renames);
+                operation
+            })
+            .collect();
+        // И из переписанных сегментов, и из памяти
+        assert_eq!(expected, history.iter().collect::<Vec<_>>());
+        for (index, operation) in expected.iter().enumerate() {
+            assert_eq!(*operation, history.entry(index).1);
+        }
+        assert_eq!(Some(0), names.id("Z"));
+        assert_eq!(None, names.id("X"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn archived() {
+        let dir = std::env::temp_dir().join(format!("history-archived-{}", std::process::id()));
+        let mut history = History::segmented(&dir, 3, 0, Some(3), None).unwrap();
+        let mut names = Names::default();
+        names.push("X");
+        let operations: Vec<Operation> = (1..=10)
+            .map(|amount| Operation::IncreaseAccount("X".to_string(), amount))
+            .collect();
+        for (index, operation) in operations.iter().enumerate() {
+            history.push(operation.clone(), index as u64 * 10, &names);
+        }
+        // Из трех сегментов несжатым остается только последний
+        let mut files: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(
+            vec![
+                "segment-00000000000000000000.jsonl.zst",
+                "segment-00000000000000000003.jsonl.zst",
+                "segment-00000000000000000006.jsonl",
+            ],
+            files
+        );
+        assert_eq!(operations, history.iter().collect::<Vec<_>>());
+        for (index, operation) in operations.iter().enumerate().rev() {
+            assert_eq!(*operation, history.entry(index).1);
+        }
+        assert_eq!(operations[1..8], history.range(1, 8));
+
+        names.rename(0, "Z");
+        let renames = HashMap::from([("X".to_string(), "Z".to_string())]);
+        history.rename(&renames, &names);
+        assert_eq!(
+            Operation::IncreaseAccount("Z".to_string(), 2),
+            history.entry(1).1
+        );
+
+        // Архивы прошлого запуска удаляются вместе с сегментами
+        History::segmented(&dir, 3, 0, Some(3), None).unwrap();
+        assert_eq!(0, fs::read_dir(&dir).unwrap().count());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn encrypted() {
+        let dir = std::env::temp_dir().join(format!("history-encrypted-{}", std::process::id()));
+        let key_file = dir.with_extension("key");
+        fs::write(&key_file, "3c".repeat(32)).unwrap();
+        let key = Key::load(&key_file).unwrap();
+        let mut history = History::segmented(&dir, 3, 0, Some(3), Some(key)).unwrap();
+        let mut names = Names::default();
+        names.push("Hidden");
+        let operations: Vec<Operation> = (1..=10)
+            .map(|amount| Operation::IncreaseAccount("Hidden".to_string(), amount))
+            .collect();
+        for (index, operation) in operations.iter().enumerate() {
+            history.push(operation.clone(), index as u64 * 10, &names);
+        }
+        for entry in fs::read_dir(&dir).unwrap() {
+            let data = fs::read(entry.unwrap().path()).unwrap();
+            assert!(!data.windows(6).any(|window| window == b"Hidden"));
+        }
+        assert_eq!(operations, history.iter().collect::<Vec<_>>());
+        for (index, operation) in operations.iter().enumerate() {
+            assert_eq!(*operation, history.entry(index).1);
+        }
+        assert_eq!(
+            operations,
+            history
+                .freeze()
+                .read()
+                .unwrap()
+                .into_iter()
+                .map(|(_, operation)| operation)
+                .collect::<Vec<_>>()
+        );
+
+        names.rename(0, "Shown");
+        let renames = HashMap::from([("Hidden".to_string(), "Shown".to_string())]);
+        history.rename(&renames, &names);
+        assert_eq!(
+            Operation::IncreaseAccount("Shown".to_string(), 8),
+            history.entry(7).1
+        );
+        let _ = fs::remove_dir_all(&dir);
+        let _ = fs::remove_file(&key_file);
+    }
+
+    #[test]
+    fn frozen() {
+        let dir = std::env::temp_dir().join(format!("history-frozen-{}", std::process::id()));
+        let mut history = History::segmented(&dir, 3, 1, Some(3), None).unwrap();
+        let mut names = Names::default();
+        names.push("X");
+        let operations: Vec<(u64, Operation)> = (1..=10)
+            .map(|amount| {
+                (
+                    amount as u64,
+                    Operation::IncreaseAccount("X".to_string(), amount),
+                )
+            })
+            .collect();
+        for (timestamp, operation) in &operations[..7] {
+            history.push(operation.clone(), *timestamp, &names);
+        }
+        let frozen = history.freeze();
+        assert_eq!(1, frozen.rest.len());
+
+        // Новые операции и сжатие сегментов после заморозки ее не меняют
+        for (timestamp, operation) in &operations[7..] {
+            history.push(operation.clone(), *timestamp, &names);
+        }
+        assert_eq!(operations[..7], frozen.read().unwrap());
+        assert_eq!(operations, history.freeze().read().unwrap());
+
+        // Переписанные сегменты уже не сходятся со скопированными операциями
+        let frozen = history.freeze();
+        names.rename(0, "Z");
+        history.rename(&HashMap::from([("X".to_string(), "Z".to_string())]), &names);
+        assert!(frozen.read().is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn recent_in_memory() {
+        let dir = std::env::temp_dir().join(format!("history-recent-{}", std::process::id()));
+        let mut history = History::segmented(&dir, 3, 4, None, None).unwrap();
+        let operations: Vec<Operation> = (1..=8)
+            .map(|amount| Operation::IncreaseAccount("X".to_string(), amount))
+            .collect();
+        let names = Names::default();
+        for (index, operation) in operations.iter().enumerate() {
+            history.push(operation.clone(), index as u64 * 10, &names);
+        }
+        // Вынесены 0..6, в памяти последние 4 из них и еще не вынесенные 6..8
+        assert_eq!(2, history.first);
+        assert_eq!(6, history.tail.len());
+        assert_eq!(operations, history.iter().collect::<Vec<_>>());
+        assert_eq!(operations[1..5], history.range(1, 5));
+
+        // Последние операции читаются без сегментов
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(operations[2..], history.range(2, 8));
+        assert_eq!(operations[3], history.entry(3).1);
+    }
+}
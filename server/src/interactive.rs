@@ -0,0 +1,209 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+
+use protocol_crate::{
+    BankError, BatchOperation, Command, LockId, Operation, Response, ResponsePayload,
+};
+
+use crate::auth;
+use crate::bank::Bank;
+use crate::locks::Locks;
+use crate::rate_limit;
+use crate::storage::PAGE_SIZE;
+
+/// A pipelined connection. Interactive transactions are bound to it and are
+/// rolled back once it is closed and its requests are done.
+pub struct Connection {
+    id: u64,
+    // Клиент больше ничего не пришлет; подписки соединения на этом кончаются
+    closed: AtomicBool,
+}
+
+impl Connection {
+    pub fn open() -> Arc<Connection> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        Arc::new(Connection {
+            id: NEXT.fetch_add(1, Ordering::Relaxed),
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    /// Notes that the client closed the connection or stopped sending.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+}
+
+/// Interactive transactions open on the pipelined connections.
+#[derive(Default)]
+pub struct Transactions {
+    open: HashMap<u64, Transaction>,
+}
+
+struct Transaction {
+    connection: Weak<Connection>,
+    // Длина истории при открытии: операции после нее проверяются на конфликт
+    started: usize,
+    operations: Vec<BatchOperation>,
+    // Счета, которые транзакция читала или меняла, по имени
+    accounts: BTreeSet<String>,
+    // Измененные счета с блокировкой, под которой пришло изменение
+    changed: Vec<(String, Option<LockId>)>,
+}
+
+impl Transactions {
+    pub fn begin(
+        &mut self,
+        connection: Option<&Arc<Connection>>,
+        bank: &Bank,
+    ) -> Result<(), BankError> {
+        let connection = connection.ok_or_else(|| {
+            BankError::ProtocolError(
+                "interactive transactions need a pipelined connection".to_string(),
+            )
+        })?;
+        if self.open.contains_key(&connection.id) {
+            return Err(BankError::TransactionAlreadyOpen);
+        }
+        let transaction = Transaction {
+            connection: Arc::downgrade(connection),
+            started: bank.history_len(),
+            operations: Vec::new(),
+            accounts: BTreeSet::new(),
+            changed: Vec::new(),
+        };
+        self.open.insert(connection.id, transaction);
+        Ok(())
+    }
+
+    /// Takes `command` into the transaction open on `connection`, if there
+    /// is one. Deposits, withdrawals, transfers and batches are collected
+    /// and answered with the number of operations collected so far; other
+    /// mutations are refused, and so are transfers above
+    /// `approval_threshold`, which have to wait for an admin on their own.
+    /// Queries run as usual, so `None` is returned for them, but the
+    /// accounts they read are checked for conflicts too. The accounts a
+    /// mutation changes are checked again at commit against the locks other
+    /// than `lock`, the one it was sent with.
+    pub fn take(
+        &mut self,
+        connection: Option<&Arc<Connection>>,
+        bank: &Bank,
+        address: &str,
+        command: &Command,
+        lock: Option<LockId>,
+        approval_threshold: Option<u32>,
+    ) -> Option<Response> {
+        let transaction = self.open.get_mut(&connection?.id)?;
+        if matches!(
+            command,
+            Command::BeginTransaction | Command::CommitTransaction | Command::RollbackTransaction
+        ) {
+            return None;
+        }
+        let accounts: Vec<String> = rate_limit::accounts(command, address)
+            .into_iter()
+            .filter_map(|account| bank.account_name(account))
+            .map(str::to_string)
+            .collect();
+        transaction.accounts.extend(accounts.iter().cloned());
+
+        let operations = match command {
+            Command::IncreaseAccount(account, amount) => vec![BatchOperation::Deposit {
+                account: account.clone(),
+                amount: *amount,
+            }],
+            Command::DecreaseAccount(account, amount) => vec![BatchOperation::Withdraw {
+                account: account.clone(),
+                amount: *amount,
+            }],
+            Command::Transfer { from, to, amount } => vec![BatchOperation::Transfer {
+                from: from.clone(),
+                to: to.clone(),
+                amount: *amount,
+            }],
+            Command::Batch(operations) => operations.clone(),
+            command if auth::mutates(command) => {
+                return Some(Err(BankError::UnsupportedInTransaction(
+                    command.name().to_string(),
+                )));
+            }
+            _ => return None,
+        };
+        let large = operations.iter().find_map(|operation| match operation {
+            BatchOperation::Transfer { amount, .. }
+                if approval_threshold.is_some_and(|threshold| *amount > threshold) =>
+            {
+                Some(*amount)
+            }
+            _ => None,
+        });
+        if let Some(amount) = large {
+            return Some(Err(BankError::LimitExceeded(format!(
+                "transfer of {} needs approval and can not be part of a transaction",
+                amount
+            ))));
+        }
+        transaction
+            .changed
+            .extend(accounts.into_iter().map(|account| (account, lock)));
+        transaction.operations.extend(operations);
+        Some(Ok(ResponsePayload::Buffered(transaction.operations.len())))
+    }
+
+    /// Closes the transaction open on `connection` and applies its
+    /// operations as one batch. Fails with `AccountLocked` if another client
+    /// locked an account the transaction changes, and with
+    /// `TransactionConflict` if an operation committed since the transaction
+    /// began touched one of its accounts.
+    ///
+    /// Returns the history operation ID of each operation.
+    pub fn commit(
+        &mut self,
+        connection: Option<&Arc<Connection>>,
+        bank: &mut Bank,
+        locks: &Locks,
+    ) -> Result<Vec<usize>, BankError> {
+        let transaction = connection
+            .and_then(|connection| self.open.remove(&connection.id))
+            .ok_or(BankError::NoOpenTransaction)?;
+        // Блокировку могли взять уже после того, как изменение попало в транзакцию
+        for (account, lock) in &transaction.changed {
+            locks.check(&[account.as_str()], *lock)?;
+        }
+        let mut offset = transaction.started;
+        loop {
+            let page = bank.get_history_page(offset, PAGE_SIZE);
+            if page.is_empty() {
+                break;
+            }
+            offset += page.len();
+            let conflict = page
+                .iter()
+                .flat_map(Operation::accounts)
+                .find(|account| transaction.accounts.contains(*account));
+            if let Some(account) = conflict {
+                return Err(BankError::TransactionConflict(account.to_string()));
+            }
+        }
+        bank.batch(&transaction.operations)
+    }
+
+    pub fn rollback(&mut self, connection: Option<&Arc<Connection>>) -> Result<(), BankError> {
+        connection
+            .and_then(|connection| self.open.remove(&connection.id))
+            .map(|_| ())
+            .ok_or(BankError::NoOpenTransaction)
+    }
+
+    /// Rolls back the transactions of the connections that are closed.
+    pub fn expire(&mut self) {
+        self.open
+            .retain(|_, transaction| transaction.connection.strong_count() > 0);
+    }
+}
@@ -0,0 +1,2573 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+
+use crate::alerts::Alerts;
+use crate::bank::Bank;
+use crate::config::{
+    Config, Durability, LogLevel, ProxyProtocolConfig, Settings, StorageBackend, Task,
+};
+use crate::coordinator::Coordinator;
+use crate::history::History;
+use crate::interactive::{Connection, Transactions};
+use crate::locks::Locks;
+use crate::metrics::Metrics;
+#[cfg(feature = "postgres")]
+use crate::postgres_storage::PostgresStorage;
+use crate::rate_limit::RateLimiter;
+use crate::replica::Replica;
+use crate::scheduler::Scheduler;
+use crate::sled_storage::SledStorage;
+use crate::snapshots::{Shipping, Snapshots};
+use crate::storage::{BankStorage, MemoryStorage};
+use crate::subscriptions::Subscriptions;
+use protocol_crate::archive;
+use protocol_crate::codec::{Serializer, WireFormat};
+use protocol_crate::otlp::{Span, SpanKind, Tracer};
+use protocol_crate::pipeline::{
+    read_frame_limited, write_frame_with, TooLarge, MAX_FRAME_SIZE, PIPELINE_MARKER,
+};
+use protocol_crate::socket::SocketOptions;
+use protocol_crate::{
+    AccountRef, BankError, BatchOperation, Command, HistoryProjection, LockId, Response,
+    ResponsePayload, ServerInfo, ServerStats, TokenInfo, MAX_COMMAND_SIZE, PROTOCOL_VERSION,
+};
+
+mod account_index;
+mod alerts;
+mod auth;
+mod bank;
+mod clock;
+mod config;
+mod coordinator;
+mod encryption;
+mod federation;
+#[cfg(feature = "graphql")]
+mod graphql;
+mod history;
+mod interactive;
+mod locks;
+mod metrics;
+mod migration;
+mod names;
+#[cfg(feature = "postgres")]
+mod postgres_storage;
+mod proxy_protocol;
+mod query;
+mod rate_limit;
+mod replica;
+mod router;
+mod s3;
+mod scheduler;
+mod sled_storage;
+mod snapshots;
+mod storage;
+mod subscriptions;
+mod systemd;
+mod velocity;
+
+pub use clock::{Clock, SystemClock, TestClock};
+pub use encryption::Key;
+#[cfg(feature = "graphql")]
+pub use graphql::GraphqlGateway;
+pub use migration::{migrate, restore_from_s3, Location, Migrated};
+pub use router::Router;
+
+// Сколько буферов запросов держит про запас конвейерное соединение
+const SPARE_BUFFERS: usize = 16;
+// Буферы больше этого не хранятся: редкий большой запрос не должен держать память
+const MAX_KEPT_BUFFER: usize = 64 * 1024;
+// Сколько ждать первую команду нового соединения, мс
+const HANDSHAKE_TIMEOUT_MS: u64 = 5_000;
+// За сколько должен дойти начатый кадр конвейерного соединения, мс
+const FRAME_TIMEOUT_MS: u64 = 30_000;
+// Сколько прочитанных запросов может ждать потока банка; дальше потоки
+// соединений ждут очереди и перестают читать свои сокеты
+const MAX_QUEUED_JOBS: usize = 256;
+// Как часто проверять расписание, когда запросов нет
+const SCHEDULER_TICK: Duration = Duration::from_secs(1);
+// Соединений с PostgreSQL, если в конфиге не указано
+#[cfg(feature = "postgres")]
+const DEFAULT_POOL_SIZE: u32 = 4;
+
+#[derive(Parser, Debug)]
+#[command(name = "Пример")]
+#[command(version = "1.0")]
+#[command(about = "Пример использования clap")]
+pub struct Args {
+    /// Порт сервера; 0 - любой свободный, настоящий выводится при запуске
+    port: String,
+    /// Журнал координатора распределенных транзакций
+    #[arg(long)]
+    coordinator_log: Option<PathBuf>,
+    /// Файл настроек (TOML), перечитывается по SIGHUP или команде Reload
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Адрес основного сервера: работать его репликой только для чтения
+    #[arg(long)]
+    replica_of: Option<String>,
+    /// Как часто реплика забирает новые операции основного сервера, мс
+    #[arg(long, default_value_t = 1000)]
+    replica_sync_ms: u64,
+    /// Сверить балансы и индексы с историей перед запуском
+    #[arg(long)]
+    check_consistency: bool,
+    /// Каталог для сегментов истории; без него история целиком в памяти
+    #[arg(long)]
+    history_dir: Option<PathBuf>,
+    /// Сколько последних операций держать в памяти и после выноса в сегмент
+    #[arg(long, default_value_t = history::SEGMENT_SIZE)]
+    history_memory: usize,
+    /// Сколько операций держать в несжатых сегментах; более старые сегменты
+    /// сжимаются в архив и читаются из него по запросу
+    #[arg(long)]
+    history_archive_after: Option<usize>,
+    /// Куда отправлять спаны OpenTelemetry (http://host:4318/v1/traces)
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+    /// Файл, в который записать pid процесса
+    #[arg(long)]
+    pid_file: Option<PathBuf>,
+    /// Сколько ждать первую команду нового соединения, мс; 0 - без ограничения
+    #[arg(long, default_value_t = HANDSHAKE_TIMEOUT_MS)]
+    handshake_timeout_ms: u64,
+    /// За сколько должен дойти начатый кадр конвейерного соединения, мс;
+    /// 0 - без ограничения
+    #[arg(long, default_value_t = FRAME_TIMEOUT_MS)]
+    frame_timeout_ms: u64,
+    /// Наибольший размер запроса и ответа конвейерного соединения, байт;
+    /// не больше 1 ГиБ
+    #[arg(long, default_value_t = MAX_FRAME_SIZE)]
+    max_message_size: usize,
+    /// Отключить алгоритм Нейгла: маленькие ответы уходят сразу
+    #[arg(long)]
+    tcp_nodelay: bool,
+    /// Как часто проверять простаивающие соединения пробами keepalive, с
+    #[arg(long)]
+    tcp_keepalive_secs: Option<u64>,
+    /// SO_REUSEADDR: занимать порт сразу после перезапуска, пока старые
+    /// соединения в TIME_WAIT
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    reuse_address: bool,
+}
+
+/// What a connection may send and get back. A client that sends nothing or
+/// dribbles bytes is disconnected when the timeouts run out.
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    // Первое чтение соединения: одиночная команда или маркер конвейера
+    handshake: Option<Duration>,
+    // Чтение кадра конвейера от первого байта до последнего; между кадрами
+    // соединение может простаивать сколько угодно
+    frame: Option<Duration>,
+    // Одиночная команда всегда не больше MAX_COMMAND_SIZE
+    max_message_size: usize,
+}
+
+impl Limits {
+    fn from_millis(handshake_ms: u64, frame_ms: u64) -> Self {
+        let timeout = |ms| (ms > 0).then(|| Duration::from_millis(ms));
+        Limits {
+            handshake: timeout(handshake_ms),
+            frame: timeout(frame_ms),
+            // Запрос читается в память целиком, и так на каждом соединении
+            max_message_size: MAX_FRAME_SIZE,
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits::from_millis(HANDSHAKE_TIMEOUT_MS, FRAME_TIMEOUT_MS)
+    }
+}
+
+/// Everything a request can touch.
+struct Server {
+    address: String,
+    // С каким режимом открыто хранилище; нет, если банк только в памяти
+    durability: Option<Durability>,
+    bank: Bank,
+    coordinator: Coordinator,
+    settings: Settings,
+    metrics: Metrics,
+    snapshots: Snapshots,
+    tracer: Option<Tracer>,
+    // Режим обслуживания, включенный командой; флаг из конфига действует независимо
+    maintenance: bool,
+    replica: Option<Replica>,
+    rate_limiter: RateLimiter,
+    locks: Locks,
+    alerts: Alerts,
+    scheduler: Scheduler,
+    // Снимок, который сейчас скачивают реплики
+    shipping: Shipping,
+    transactions: Transactions,
+    subscriptions: Subscriptions,
+    // Флаг отмены выполняемого запроса
+    cancelled: Arc<AtomicBool>,
+    // Когда выполняемый запрос был прочитан; от этого момента отсчитывается срок
+    received: Instant,
+    // Адрес клиента выполняемого запроса, за балансировщиком - из заголовка PROXY
+    client: Option<SocketAddr>,
+    // Конвейерное соединение выполняемого запроса
+    connection: Option<Arc<Connection>>,
+    // Промежуточные ответы выполняемого запроса, если соединение их принимает
+    progress: Option<Progress>,
+    // Токен выполняемого запроса: координатор передает его участникам транзакции
+    token: Option<String>,
+    // Перевод в другой банк, начатый выполняемым запросом: ответ на него
+    // придет, когда рабочий поток федерации доделает удаленную часть
+    remote_transfer: Option<RemoteTransfer>,
+    // Подписка, начатая выполняемым запросом: с какой операции и на какой
+    // счет; вместо ответа на запрос пойдут новые операции
+    subscribe: Option<(usize, Option<String>)>,
+}
+
+/// Transfer to another bank whose remote part is left to the federation
+/// worker.
+struct RemoteTransfer {
+    transfer: federation::Transfer,
+    // Номер запроса для ошибки, как в ответе handle_command
+    request_id: Option<String>,
+}
+
+/// Answer to a request: the request ID of its frame and the response.
+struct Reply {
+    request_id: Option<String>,
+    answer: Answer,
+    // Буфер запроса возвращается соединению, чтобы прочитать в него следующий
+    buffer: Vec<u8>,
+    // Промежуточный ответ: окончательный придет позже
+    progress: bool,
+}
+
+/// Response to write back. It is encoded by the connection thread into a
+/// buffer of its own, not by the thread that owns the bank.
+enum Answer {
+    Response(WireFormat, Response),
+    // Готовые байты, например ответ на HTTP-запрос метрик
+    Raw(Vec<u8>),
+}
+
+impl Answer {
+    /// Encodes the answer into `buffer`; a response larger than `limit` is
+    /// replaced with a `MessageTooLarge` error.
+    fn encode_into(&self, buffer: &mut Vec<u8>, limit: usize) {
+        match self {
+            Answer::Response(format, response) => {
+                format.encode_into(response, buffer);
+                if buffer.len() > limit {
+                    let error = BankError::MessageTooLarge {
+                        size: buffer.len(),
+                        limit,
+                    };
+                    buffer.clear();
+                    format.encode_into(&Err::<ResponsePayload, _>(error), buffer);
+                }
+            }
+            Answer::Raw(data) => buffer.extend_from_slice(data),
+        }
+    }
+}
+
+/// Sends intermediate answers to the request being executed. Only pipelined
+/// connections take more than one answer per request.
+struct Progress {
+    request_id: String,
+    format: WireFormat,
+    reply: Sender<Reply>,
+}
+
+impl Progress {
+    fn send(&self, payload: ResponsePayload) {
+        let _ = self.reply.send(Reply {
+            request_id: Some(self.request_id.clone()),
+            answer: Answer::Response(self.format, Ok(payload)),
+            buffer: Vec::new(),
+            progress: true,
+        });
+    }
+}
+
+/// A request read by a connection thread, to be executed by the thread that
+/// owns the bank.
+struct Job {
+    // Номер запроса из кадра конвейерного соединения
+    request_id: Option<String>,
+    // Запрос, уже декодированный потоком соединения, и его байты
+    request: Request,
+    data: Vec<u8>,
+    reply: Sender<Reply>,
+    cancelled: Arc<AtomicBool>,
+    received: Instant,
+    client: SocketAddr,
+    // Конвейерное соединение запроса; у одиночной команды его нет
+    connection: Option<Arc<Connection>>,
+}
+
+/// A request whose transfer to another bank waits for the federation worker.
+/// The worker credits the remote account and sends the request back to the
+/// thread that owns the bank, to settle the local hold and answer.
+struct RemoteJob {
+    format: WireFormat,
+    transfer: RemoteTransfer,
+    // Остальное - как в задании, из которого перевод начался
+    request_id: Option<String>,
+    data: Vec<u8>,
+    reply: Sender<Reply>,
+    cancelled: Arc<AtomicBool>,
+    received: Instant,
+    client: SocketAddr,
+    connection: Option<Arc<Connection>>,
+}
+
+/// Runs the remote parts of transfers to other banks one at a time, in the
+/// order they were started.
+fn federation_worker(remote_jobs: Receiver<RemoteJob>, jobs: SyncSender<Job>) {
+    for remote_job in remote_jobs {
+        let RemoteJob {
+            format,
+            transfer,
+            request_id,
+            data,
+            reply,
+            cancelled,
+            received,
+            client,
+            connection,
+        } = remote_job;
+        let commit = transfer.transfer.run();
+        let job = Job {
+            request_id,
+            request: Request::Settle {
+                format,
+                transfer,
+                commit,
+            },
+            data,
+            reply,
+            cancelled,
+            received,
+            client,
+            connection,
+        };
+        if jobs.send(job).is_err() {
+            return;
+        }
+    }
+}
+
+/// What a connection thread made of the bytes of a request.
+enum Request {
+    // Prometheus забирает метрики обычным HTTP GET на тот же порт
+    Scrape,
+    // Ответ кодируется в том же формате, что и команда
+    Command(WireFormat, Result<Command, BankError>),
+    // Удаленная часть перевода в другой банк, сделанная рабочим потоком
+    // федерации: остается зафиксировать или снять локальное удержание
+    Settle {
+        format: WireFormat,
+        transfer: RemoteTransfer,
+        commit: federation::Commit,
+    },
+}
+
+impl Request {
+    /// Decodes the bytes of a request. Connection threads call it, so that
+    /// a malformed or too deeply nested command never reaches the thread
+    /// that owns the bank.
+    fn decode(data: &[u8], pipelined: bool) -> Request {
+        if !pipelined && data.starts_with(b"GET ") {
+            return Request::Scrape;
+        }
+        match WireFormat::detect(data) {
+            Ok((format, payload)) => Request::Command(format, format.decode(payload)),
+            Err(e) => Request::Command(WireFormat::default(), Err(e)),
+        }
+    }
+}
+
+/// Handles one request and returns what to answer it with.
+fn handle_request(
+    server: &mut Server,
+    request: Request,
+    data: &[u8],
+    request_id: Option<String>,
+) -> Answer {
+    let (format, response) = match request {
+        Request::Scrape => return Answer::Raw(handle_scrape(server, data)),
+        Request::Command(format, command) => (format, handle_command(server, command, request_id)),
+        Request::Settle {
+            format,
+            transfer:
+                RemoteTransfer {
+                    transfer,
+                    request_id,
+                },
+            commit,
+        } => {
+            let response = transfer
+                .finish(&mut server.bank, commit)
+                .map(|()| ResponsePayload::Done);
+            let response = match request_id {
+                Some(request_id) => response.map_err(|e| e.with_request_id(&request_id)),
+                None => response,
+            };
+            (format, response)
+        }
+    };
+    if server.settings.config.enabled(LogLevel::Info) {
+        println!("Sent response: {:?} \n", &response);
+    }
+    Answer::Response(format, response)
+}
+
+/// Answers `GET /metrics` with the Prometheus metrics. The scraper has to
+/// send an admin token as `Authorization: Bearer <token>`.
+fn handle_scrape(server: &Server, request: &[u8]) -> Vec<u8> {
+    let request = String::from_utf8_lossy(request);
+    let mut lines = request.lines();
+    let path = lines.next().and_then(|line| line.split(' ').nth(1));
+    let token = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("authorization"))
+        .and_then(|(_, value)| value.trim().strip_prefix("Bearer "));
+
+    let (status, body) = if path != Some("/metrics") {
+        ("404 Not Found", String::new())
+    } else if !token.is_some_and(|token| server.settings.config.is_admin(token)) {
+        ("401 Unauthorized", String::new())
+    } else {
+        ("200 OK", server.metrics.prometheus(&server.bank))
+    };
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+fn handle_command(
+    server: &mut Server,
+    command: Result<Command, BankError>,
+    frame_id: Option<String>,
+) -> Response {
+    let (request_id, command) = match command? {
+        Command::WithRequestId {
+            request_id,
+            command,
+        } => (Some(request_id), *command),
+        command => (None, command),
+    };
+    // Номер в самой команде может быть traceparent, он важнее номера кадра
+    let request_id = request_id.or(frame_id);
+    // Номер запроса в каждой строке журнала о нем
+    let tag = request_id
+        .as_deref()
+        .map(|id| format!("[{}] ", id))
+        .unwrap_or_default();
+
+    // Вывод десериализованных данных
+    if server.settings.config.enabled(LogLevel::Info) {
+        let client = server.client.map(|client| client.to_string());
+        println!(
+            "{}Received command from {}: {:?}",
+            tag,
+            client.as_deref().unwrap_or("unknown client"),
+            command
+        );
+    }
+
+    let name = command.name();
+    // Номер запроса в виде traceparent продолжает трассировку клиента
+    let span = server
+        .tracer
+        .as_ref()
+        .map(|_| Span::start(name, SpanKind::Server, request_id.as_deref()));
+    let started = Instant::now();
+    let response = dispatch(server, command, None, None);
+    let elapsed = started.elapsed();
+    let error = response.as_ref().err().map(BankError::name);
+    server.metrics.record(name, elapsed, error);
+    if let (Some(tracer), Some(mut span)) = (&server.tracer, span) {
+        span.set_attribute("server.address", server.address.as_str());
+        if let Some(client) = server.client {
+            span.set_attribute("client.address", client.to_string().as_str());
+        }
+        if let Some(request_id) = &request_id {
+            span.set_attribute("bank.request_id", request_id.as_str());
+        }
+        span.end(response.as_ref().err().map(|e| format!("{:?}", e)));
+        tracer.record(span);
+    }
+    if server.settings.config.enabled(LogLevel::Info) {
+        let outcome = error.unwrap_or("ok");
+        println!("{}Finished {} in {:?}: {}", tag, name, elapsed, outcome);
+    }
+    if let Some(remote_transfer) = &mut server.remote_transfer {
+        remote_transfer.request_id = request_id.clone();
+    }
+    match request_id {
+        Some(request_id) => response.map_err(|e| e.with_request_id(&request_id)),
+        None => response,
+    }
+}
+
+/// Resolves the caller identity and checks its role, account ownership and
+/// the account locks other than `lock` before executing.
+fn dispatch(
+    server: &mut Server,
+    command: Command,
+    caller: Option<String>,
+    lock: Option<LockId>,
+) -> Response {
+    if let Command::AsIdentity { token, command } = command {
+        // С неизвестным токеном команда выполняется анонимно
+        let caller = server.settings.config.identity(&token).map(str::to_string);
+        server.token = Some(token);
+        return dispatch(server, *command, caller, lock);
+    }
+    if let Command::WithLock { lock, command } = command {
+        return dispatch(server, *command, caller, Some(lock));
+    }
+    // Номер запроса имеет смысл только снаружи, здесь он уже не нужен
+    if let Command::WithRequestId { command, .. } = command {
+        return dispatch(server, *command, caller, lock);
+    }
+    if let Command::WithDeadline {
+        timeout_ms,
+        command,
+    } = command
+    {
+        // Клиент уже не ждет ответа, выполнять команду незачем
+        if server.received.elapsed() >= Duration::from_millis(timeout_ms) {
+            return Err(BankError::DeadlineExceeded);
+        }
+        return dispatch(server, *command, caller, lock);
+    }
+    if let Command::WithPosition {
+        min_position,
+        command,
+    } = command
+    {
+        if let Some(position) = min_position {
+            reach(server, position)?;
+        }
+        let payload = dispatch(server, *command, caller, lock)?;
+        return Ok(ResponsePayload::Positioned {
+            position: server.bank.history_len(),
+            payload: Box::new(payload),
+        });
+    }
+    // Запрос отменили, пока он ждал очереди
+    if server.cancelled.load(Ordering::Relaxed) {
+        return Err(BankError::Cancelled);
+    }
+    auth::check_role(server.settings.config.role(caller.as_deref()), &command)?;
+    let maintenance = server.maintenance || server.settings.config.maintenance;
+    auth::check_maintenance(maintenance, &command)?;
+    auth::check_primary(server.replica.as_ref().map(Replica::primary), &command)?;
+    auth::check(
+        &server.bank,
+        &server.locks,
+        &server.address,
+        caller.as_deref(),
+        &command,
+    )?;
+    // Несуществующие счета не ограничиваются: такая команда и так не пройдет
+    let accounts: Vec<&str> = rate_limit::accounts(&command, &server.address)
+        .into_iter()
+        .filter_map(|account| server.bank.account_name(account))
+        .collect();
+    let mutates = auth::mutates(&command);
+    if mutates {
+        server.locks.check(&accounts, lock)?;
+    }
+    server
+        .rate_limiter
+        .check(&server.settings.config.rate_limits, &accounts)?;
+    let response = execute(server, command, caller, lock);
+    if mutates {
+        let webhook = server.settings.config.alert_webhook.as_deref();
+        server.alerts.check(&server.bank, webhook);
+    }
+    response
+}
+
+/// Waits until the history holds `position` operations: a replica syncs
+/// with its primary or redirects to it, a primary never gets behind.
+fn reach(server: &mut Server, position: usize) -> Result<(), BankError> {
+    let operations = server.bank.history_len();
+    match &mut server.replica {
+        Some(replica) => replica.reach(&mut server.bank, position),
+        None if operations < position => Err(BankError::PositionNotReached {
+            position,
+            operations,
+        }),
+        None => Ok(()),
+    }
+}
+
+fn execute(
+    server: &mut Server,
+    command: Command,
+    caller: Option<String>,
+    lock: Option<LockId>,
+) -> Response {
+    let Server {
+        address,
+        durability,
+        bank,
+        coordinator,
+        settings,
+        metrics,
+        snapshots,
+        maintenance,
+        scheduler,
+        shipping,
+        locks,
+        alerts,
+        transactions,
+        cancelled,
+        progress,
+        connection,
+        rate_limiter,
+        subscriptions,
+        remote_transfer,
+        subscribe,
+        token,
+        ..
+    } = server;
+
+    // Изменения внутри интерактивной транзакции копятся до ее фиксации
+    if let Some(response) = transactions.take(
+        connection.as_ref(),
+        bank,
+        address,
+        &command,
+        lock,
+        settings.config.approval_threshold,
+    ) {
+        return response;
+    }
+
+    // Выполнение команды
+    match command {
+        Command::CreateAccount(account) => {
+            let id = bank.create_account(account)?;
+            // Счет, созданный от имени пользователя, принадлежит ему
+            if let Some(owner) = caller {
+                let _ = bank.set_account_owners(id, [owner].into());
+            }
+            Ok(ResponsePayload::Account(id))
+        }
+
+        Command::IncreaseAccount(account, amount) => bank
+            .increase_account(account, amount)
+            .map(ResponsePayload::OperationId),
+        Command::DecreaseAccount(account, amount) => bank
+            .decrease_account(account, amount)
+            .map(ResponsePayload::OperationId),
+        Command::DecreaseIfBalanceAtLeast {
+            account,
+            amount,
+            min_after,
+        } => bank
+            .decrease_if_balance_at_least(account, amount, min_after)
+            .map(ResponsePayload::OperationId),
+        // Крупный перевод ждет администратора, а клиент узнает номер ожидания
+        Command::Transfer { from, to, amount } if needs_approval(settings, amount) => Err(
+            BankError::TransferPending(bank.queue_transfer(from, to, amount)?),
+        ),
+        Command::Transfer { from, to, amount } => bank
+            .transfer(from, to, amount)
+            .map(|()| ResponsePayload::Done),
+        Command::TransferIf {
+            from,
+            to,
+            amount,
+            expected_version,
+        } if needs_approval(settings, amount) => {
+            bank.check_version(from.clone(), expected_version)?;
+            Err(BankError::TransferPending(
+                bank.queue_transfer(from, to, amount)?,
+            ))
+        }
+        Command::TransferIf {
+            from,
+            to,
+            amount,
+            expected_version,
+        } => bank
+            .transfer_if(from, to, amount, expected_version)
+            .map(|()| ResponsePayload::Done),
+        // Устаревшие списки без страниц остаются для прежних клиентов
+        #[allow(deprecated)]
+        Command::GetHistory => bank
+            .get_history_cancellable(cancelled)
+            .map(ResponsePayload::History),
+        Command::SearchHistory { query, limit } => Ok(ResponsePayload::Operations(
+            bank.search_history(&query, limit),
+        )),
+        Command::QueryHistory { query } => {
+            bank.query_history(&query).map(ResponsePayload::QueryRows)
+        }
+        #[allow(deprecated)]
+        Command::GetHistoryPage { offset, limit } => Ok(ResponsePayload::History(
+            bank.get_history_page(offset, limit),
+        )),
+        Command::GetHistoryPaged { page } => bank
+            .get_history_paged(&page)
+            .map(ResponsePayload::HistoryPage),
+        Command::GetHistoryArchive { page } => {
+            bank.get_history_paged(&page)
+                .map(|page| ResponsePayload::HistoryArchive {
+                    data: archive::encode_history(&page.items),
+                    next_cursor: page.next_cursor,
+                })
+        }
+        Command::GetAccountBalance(account) => bank
+            .get_account_balance(account)
+            .map(ResponsePayload::AccountBalance),
+        Command::GetBalanceAt {
+            account,
+            operation_id,
+        } => bank
+            .get_balance_at(account, operation_id)
+            .map(ResponsePayload::AccountBalance),
+        Command::GetVersionedBalance(account) => bank
+            .get_versioned_balance(account)
+            .map(ResponsePayload::VersionedBalance),
+        #[allow(deprecated)]
+        Command::GetAccountHistory(account) => bank
+            .get_account_history(account)
+            .map(ResponsePayload::History),
+        Command::GetAccountHistoryPaged { account, page } => bank
+            .get_account_history_paged(account, &page)
+            .map(ResponsePayload::HistoryPage),
+        Command::GetProjectedHistory {
+            account,
+            projection,
+            page,
+        } => {
+            let page = match account {
+                Some(account) => bank.get_account_history_paged(account, &page)?,
+                None => bank.get_history_paged(&page)?,
+            };
+            Ok(match projection {
+                HistoryProjection::Amounts => {
+                    ResponsePayload::AmountsPage(page.map(|operation| operation.amount()))
+                }
+                HistoryProjection::Counterparties => ResponsePayload::CounterpartiesPage(
+                    page.map(|operation| operation.counterparties()),
+                ),
+            })
+        }
+        Command::Restore(history) => bank
+            .restore_with(&history, cancelled, |step| {
+                if let Some(progress) = progress {
+                    progress.send(ResponsePayload::RestoreProgress(step));
+                }
+            })
+            .map(ResponsePayload::Restored),
+        // Ответ отправит рабочий поток федерации, когда доделает перевод
+        Command::RemoteTransfer { from, to, amount } => {
+            let transfer = federation::Transfer::hold(bank, address, from, to, amount)?;
+            *remote_transfer = Some(RemoteTransfer {
+                transfer,
+                request_id: None,
+            });
+            Ok(ResponsePayload::Done)
+        }
+        Command::Reserve {
+            account,
+            amount,
+            kind,
+            counterparty,
+        } => bank
+            .reserve(account, amount, kind, counterparty)
+            .map(ResponsePayload::Reservation),
+        Command::CommitReservation(id) => bank
+            .commit_reservation(id)
+            .map(ResponsePayload::OperationId),
+        Command::ReleaseReservation(id) => {
+            bank.release_reservation(id).map(|()| ResponsePayload::Done)
+        }
+        Command::Transaction(legs) => coordinator
+            .execute(bank, legs, token.as_deref())
+            .map(ResponsePayload::Transaction),
+        Command::Batch(operations) => {
+            check_batch_approval(settings, &operations)?;
+            bank.batch(&operations).map(ResponsePayload::Batch)
+        }
+        Command::BulkTransfer { from, payees } => {
+            bulk_transfer(bank, settings, from, payees).map(ResponsePayload::BulkTransfer)
+        }
+        Command::Prepare { transaction, legs } => bank
+            .prepare(transaction, &legs)
+            .map(|()| ResponsePayload::Done),
+        Command::Commit(transaction) => bank.commit(&transaction).map(|()| ResponsePayload::Done),
+        Command::Abort(transaction) => bank.abort(&transaction).map(|()| ResponsePayload::Done),
+        Command::LockAccount { account, lease_ms } => {
+            let name = bank.account_name(&account).ok_or_else(|| {
+                BankError::AccountDoesNotExist(format!("Account {} does not exist", account))
+            })?;
+            locks
+                .lock(&[name], Duration::from_millis(lease_ms))
+                .map(ResponsePayload::Lock)
+        }
+        Command::LockAccounts { accounts, lease_ms } => {
+            // Счета берутся по возрастанию номера, каждый один раз
+            let mut ids = accounts
+                .iter()
+                .map(|account| bank.resolve_account(account))
+                .collect::<Result<Vec<_>, _>>()?;
+            ids.sort_unstable();
+            ids.dedup();
+            let names: Vec<&str> = ids
+                .into_iter()
+                .filter_map(|id| bank.account_name(&AccountRef::Id(id)))
+                .collect();
+            locks
+                .lock(&names, Duration::from_millis(lease_ms))
+                .map(ResponsePayload::Lock)
+        }
+        Command::UnlockAccount(lock) => locks.unlock(lock).map(|()| ResponsePayload::Done),
+        Command::BeginTransaction => transactions
+            .begin(connection.as_ref(), bank)
+            .map(|()| ResponsePayload::Done),
+        Command::CommitTransaction => transactions
+            .commit(connection.as_ref(), bank, locks)
+            .map(ResponsePayload::Batch),
+        Command::RollbackTransaction => transactions
+            .rollback(connection.as_ref())
+            .map(|()| ResponsePayload::Done),
+        Command::GetStatement {
+            account,
+            from_ts,
+            to_ts,
+        } => bank
+            .get_statement(account, from_ts, to_ts)
+            .map(ResponsePayload::Statement),
+        Command::Reload { token } => {
+            check_admin(settings, &token)?;
+            reload_config(settings, bank).map(|()| ResponsePayload::Done)
+        }
+        Command::GetSnapshot => Ok(ResponsePayload::Snapshot(Box::new(bank.snapshot()))),
+        Command::GetSnapshotChunk {
+            operations,
+            offset,
+            limit,
+        } => shipping
+            .chunk(bank, operations, offset, limit)
+            .map(ResponsePayload::SnapshotChunk),
+        Command::Handshake => Ok(ResponsePayload::ServerInfo(ServerInfo {
+            protocol_version: PROTOCOL_VERSION,
+            address: server.address.clone(),
+            client_address: server.client.map(|client| client.to_string()),
+            encodings: WireFormat::supported()
+                .iter()
+                .map(|format| format.name().to_string())
+                .collect(),
+        })),
+        Command::GetShardMap => Ok(ResponsePayload::ShardMap(vec![server.address.clone()])),
+        Command::SetAccountLimits {
+            token,
+            account,
+            limits,
+        } => {
+            check_admin(settings, &token)?;
+            bank.set_account_limits(account, limits)
+                .map(ResponsePayload::OperationId)
+        }
+        Command::GetAccountLimits(account) => bank
+            .get_account_limits(account)
+            .map(ResponsePayload::AccountLimits),
+        Command::SetAccountMetadata {
+            account,
+            key,
+            value,
+        } => bank
+            .set_account_metadata(account, key, value)
+            .map(|()| ResponsePayload::Done),
+        Command::SetAlertRule { account, below } => bank
+            .set_alert_rule(account, below)
+            .map(|()| ResponsePayload::Done),
+        Command::ListAlerts => Ok(ResponsePayload::Alerts(alerts.active())),
+        Command::GetAccountMetadata(account) => bank
+            .get_account_metadata(account)
+            .map(ResponsePayload::AccountMetadata),
+        Command::SetAccountOwners { account, owners } => bank
+            .set_account_owners(account, owners)
+            .map(|()| ResponsePayload::Done),
+        Command::GetAccountOwners(account) => bank
+            .get_account_owners(account)
+            .map(ResponsePayload::AccountOwners),
+        Command::GetSubtreeBalance(account) => bank
+            .get_subtree_balance(account)
+            .map(ResponsePayload::SubtreeBalance),
+        Command::SetAccountTags {
+            token,
+            account,
+            tags,
+        } => {
+            check_admin(settings, &token)?;
+            bank.set_account_tags(account, tags)
+                .map(|()| ResponsePayload::Done)
+        }
+        Command::GetAccountTags(account) => bank
+            .get_account_tags(account)
+            .map(ResponsePayload::AccountTags),
+        Command::FindAccountsByTag(tag) => Ok(ResponsePayload::AccountsByTag(
+            bank.find_accounts_by_tag(&tag),
+        )),
+        Command::FindAccounts {
+            min_balance,
+            max_balance,
+        } => Ok(ResponsePayload::AccountsByBalance(
+            bank.find_accounts(min_balance, max_balance),
+        )),
+        Command::GetTopAccounts(n) => Ok(ResponsePayload::TopAccounts(bank.top_accounts(n))),
+        Command::CloseAccount(account) => bank
+            .close_account(account)
+            .map(ResponsePayload::OperationId),
+        #[allow(deprecated)]
+        Command::ListAccounts(filter) => Ok(ResponsePayload::Accounts(bank.list_accounts(filter))),
+        Command::ListAccountsPaged { filter, page } => bank
+            .list_accounts_paged(filter, &page)
+            .map(ResponsePayload::AccountsPage),
+        Command::GetHistoryDigest { operations } => Ok(ResponsePayload::HistoryDigest(
+            bank.get_history_digest(operations),
+        )),
+        Command::CheckConsistency { token } => {
+            check_admin(settings, &token)?;
+            Ok(ResponsePayload::ConsistencyReport(bank.check_consistency()))
+        }
+        Command::SetMaintenance { token, enabled } => {
+            check_admin(settings, &token)?;
+            *maintenance = enabled;
+            Ok(ResponsePayload::Done)
+        }
+        Command::WriteSnapshot { token } => {
+            check_admin(settings, &token)?;
+            snapshots
+                .write_now(&settings.config.snapshots, bank)
+                .map(ResponsePayload::SnapshotQueued)
+                .ok_or_else(|| BankError::InvalidConfig("snapshots.dir is not set".to_string()))
+        }
+        Command::AddToken {
+            token,
+            new_token,
+            identity,
+            admin,
+        } => {
+            check_admin(settings, &token)?;
+            settings
+                .add_token(&new_token, identity.as_deref(), admin)
+                .map(|()| ResponsePayload::Done)
+        }
+        Command::RevokeToken { token, revoked } => {
+            check_admin(settings, &token)?;
+            // Иначе можно остаться без единого административного токена
+            if revoked == token {
+                return Err(BankError::Forbidden(
+                    "the token of the request can not be revoked".to_string(),
+                ));
+            }
+            settings
+                .revoke_token(&revoked)
+                .map(|()| ResponsePayload::Done)
+        }
+        Command::ListTokens { token } => {
+            check_admin(settings, &token)?;
+            Ok(ResponsePayload::Tokens(list_tokens(&settings.config)))
+        }
+        Command::SetRateLimit {
+            token,
+            account,
+            limit,
+        } => {
+            check_admin(settings, &token)?;
+            settings
+                .set_rate_limit(account.as_deref(), limit)
+                .map(|()| ResponsePayload::Done)
+        }
+        Command::GetRateLimits { token } => {
+            check_admin(settings, &token)?;
+            Ok(ResponsePayload::RateLimits(
+                settings.config.rate_limits.to_limits(),
+            ))
+        }
+        Command::ApproveTransfer { token, id } => {
+            check_admin(settings, &token)?;
+            bank.approve_transfer(id).map(|()| ResponsePayload::Done)
+        }
+        Command::RejectTransfer { token, id } => {
+            check_admin(settings, &token)?;
+            bank.reject_transfer(id).map(|()| ResponsePayload::Done)
+        }
+        Command::ListPendingTransfers { token } => {
+            check_admin(settings, &token)?;
+            Ok(ResponsePayload::PendingTransfers(bank.pending_transfers()))
+        }
+        Command::SetRequiredApprovals {
+            token,
+            account,
+            approvals,
+        } => {
+            check_admin(settings, &token)?;
+            bank.set_required_approvals(account, approvals)
+                .map(|()| ResponsePayload::Done)
+        }
+        Command::ProposeTransfer { from, to, amount } => bank
+            .propose_transfer(from, to, amount, caller.as_deref())
+            .map(ResponsePayload::Proposal),
+        Command::ApproveProposal(id) => bank
+            .approve_proposal(id, caller.as_deref())
+            .map(ResponsePayload::Proposal),
+        Command::OpenDispute { operation, reason } => bank
+            .open_dispute(operation, reason, caller.as_deref())
+            .map(ResponsePayload::Dispute),
+        Command::ListDisputes { include_resolved } => {
+            Ok(ResponsePayload::Disputes(bank.disputes(include_resolved)))
+        }
+        Command::ResolveDispute { token, id, reverse } => {
+            check_admin(settings, &token)?;
+            bank.resolve_dispute(id, reverse)
+                .map(|()| ResponsePayload::Done)
+        }
+        Command::AnonymizeAccount { token, account } => {
+            check_admin(settings, &token)?;
+            let (pseudonym, renames) = bank.anonymize_account(account)?;
+            // Блокировки, корзины и оповещения держатся по имени счета
+            locks.rename(&renames);
+            rate_limiter.rename(&renames);
+            alerts.rename(&renames);
+            subscriptions.rename(&renames);
+            // В прежних снимках осталось старое имя; копии у реплик и в
+            // чужих резервных копиях сервер заменить не может
+            snapshots.replace_all(&settings.config.snapshots, bank);
+            *shipping = Shipping::default();
+            Ok(ResponsePayload::Pseudonym(pseudonym))
+        }
+        Command::Subscribe { from, account } => {
+            if progress.is_none() || connection.is_none() {
+                return Err(BankError::ProtocolError(
+                    "subscriptions need a pipelined connection".to_string(),
+                ));
+            }
+            let account = match account {
+                Some(account) => {
+                    bank.resolve_account(&account)?;
+                    bank.account_name(&account).map(str::to_string)
+                }
+                None => None,
+            };
+            *subscribe = Some((from.unwrap_or(bank.history_len()), account));
+            Ok(ResponsePayload::Done)
+        }
+        Command::ListJobs { token } => {
+            check_admin(settings, &token)?;
+            Ok(ResponsePayload::Jobs(
+                scheduler.list(&settings.config.jobs, bank.now()),
+            ))
+        }
+        Command::AsIdentity { .. }
+        | Command::WithRequestId { .. }
+        | Command::WithDeadline { .. }
+        | Command::WithPosition { .. }
+        | Command::WithLock { .. } => {
+            unreachable!("unwrapped in dispatch")
+        }
+        Command::GetMetrics { token } => {
+            check_admin(settings, &token)?;
+            Ok(ResponsePayload::Metrics(metrics.snapshot()))
+        }
+        Command::GetStats { token } => {
+            check_admin(settings, &token)?;
+            Ok(ResponsePayload::Stats(ServerStats {
+                address: address.clone(),
+                durability: durability.map(|durability| durability.to_string()),
+                ..bank.stats()
+            }))
+        }
+    }
+}
+
+fn check_admin(settings: &Settings, token: &str) -> Result<(), BankError> {
+    if settings.config.is_admin(token) {
+        Ok(())
+    } else {
+        Err(BankError::Unauthorized)
+    }
+}
+
+/// Whether a transfer of `amount` has to wait for an admin to approve it.
+fn needs_approval(settings: &Settings, amount: u32) -> bool {
+    settings
+        .config
+        .approval_threshold
+        .is_some_and(|threshold| amount > threshold)
+}
+
+/// Refuses a batch with a transfer above the approval threshold: a batch is
+/// applied all or nothing, so one of its transfers can not wait for an admin.
+fn check_batch_approval(
+    settings: &Settings,
+    operations: &[BatchOperation],
+) -> Result<(), BankError> {
+    for (index, operation) in operations.iter().enumerate() {
+        if let BatchOperation::Transfer { amount, .. } = operation {
+            if needs_approval(settings, *amount) {
+                return Err(BankError::BatchFailed {
+                    index,
+                    error: Box::new(BankError::LimitExceeded(format!(
+                        "transfer of {} needs approval and can not be part of a batch",
+                        amount
+                    ))),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Pays the payees at or below the approval threshold with
+/// [`Bank::bulk_transfer`] and queues the others for an admin; those get
+/// `BankError::TransferPending`. Nothing is queued if the rest can not be
+/// paid.
+fn bulk_transfer(
+    bank: &mut Bank,
+    settings: &Settings,
+    from: AccountRef,
+    payees: Vec<(AccountRef, u32)>,
+) -> Result<Vec<Result<usize, BankError>>, BankError> {
+    let (queued, paid): (Vec<_>, Vec<_>) = payees
+        .into_iter()
+        .enumerate()
+        .partition(|(_, (_, amount))| needs_approval(settings, *amount));
+    let (indices, paid): (Vec<usize>, Vec<_>) = paid.into_iter().unzip();
+    let mut results: Vec<Option<Result<usize, BankError>>> =
+        (0..indices.len() + queued.len()).map(|_| None).collect();
+    for (index, result) in indices.into_iter().zip(bank.bulk_transfer(&from, &paid)?) {
+        results[index] = Some(result);
+    }
+    for (index, (payee, amount)) in queued {
+        let id = bank.queue_transfer(from.clone(), payee, amount);
+        results[index] = Some(id.and_then(|id| Err(BankError::TransferPending(id))));
+    }
+    Ok(results.into_iter().flatten().collect())
+}
+
+/// Admin tokens and identity tokens of `config`, sorted, with only their
+/// beginnings shown.
+fn list_tokens(config: &Config) -> Vec<TokenInfo> {
+    let mut tokens: Vec<&String> = config.admin_tokens.iter().collect();
+    tokens.extend(config.identities.keys().filter(|t| !config.is_admin(t)));
+    tokens.sort();
+    tokens
+        .into_iter()
+        .map(|token| TokenInfo {
+            // Токен целиком не уходит даже администратору
+            token: token
+                .chars()
+                .take(token.chars().count() / 2)
+                .take(4)
+                .collect::<String>()
+                + "…",
+            identity: config.identity(token).map(str::to_string),
+            admin: config.is_admin(token),
+        })
+        .collect()
+}
+
+/// Re-reads the config file and applies the settings that live in the bank.
+fn reload_config(settings: &mut Settings, bank: &mut Bank) -> Result<(), BankError> {
+    settings.reload()?;
+    bank.set_velocity_rules(settings.config.velocity_rules.clone());
+    set_capacity(&settings.config, bank);
+    Ok(())
+}
+
+fn set_capacity(config: &Config, bank: &mut Bank) {
+    bank.set_capacity(config.capacity.max_accounts, config.capacity.max_history);
+}
+
+/// Reads the connections of `listener` in background threads and executes
+/// their requests one at a time on this thread, the only one with the bank.
+fn serve(
+    server: &mut Server,
+    listener: &TcpListener,
+    socket: SocketOptions,
+    limits: Limits,
+    reload_requested: &AtomicBool,
+) {
+    let (jobs, requests) = mpsc::sync_channel::<Job>(MAX_QUEUED_JOBS);
+    let (remote_jobs, remote_requests) = mpsc::channel::<RemoteJob>();
+    let proxy_protocol = server.settings.config.proxy_protocol.clone();
+    let proxy_protocol = &proxy_protocol;
+    thread::scope(|scope| {
+        let federation_jobs = jobs.clone();
+        scope.spawn(move || federation_worker(remote_requests, federation_jobs));
+        scope.spawn(|| {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = socket.apply(&stream) {
+                            eprintln!("Failed to set socket options: {}", e);
+                        }
+                        let jobs = jobs.clone();
+                        scope.spawn(move || connection(stream, proxy_protocol, limits, jobs));
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to establish a connection: {}", e);
+                    }
+                }
+            }
+        });
+
+        // Отстающим подписчикам операции досылаются, не дожидаясь запросов
+        let mut behind = false;
+        loop {
+            // Без запросов очередь ждет не дольше такта расписания
+            let wait = if behind {
+                Duration::ZERO
+            } else {
+                SCHEDULER_TICK
+            };
+            let job = match requests.recv_timeout(wait) {
+                Ok(job) => Some(job),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if reload_requested.swap(false, Ordering::Relaxed) {
+                match reload_config(&mut server.settings, &mut server.bank) {
+                    Ok(()) => println!("Config reloaded"),
+                    Err(e) => eprintln!("Failed to reload config: {:?}", e),
+                }
+            }
+            if let Some(replica) = &mut server.replica {
+                replica.sync(&mut server.bank);
+            }
+            server.transactions.expire();
+            server.locks.expire();
+            server.coordinator.retry(&mut server.bank, Instant::now());
+            if let Some(job) = job {
+                execute_job(server, job, &remote_jobs);
+            }
+            run_jobs(server);
+            server
+                .snapshots
+                .maybe_write(&server.settings.config.snapshots, &server.bank);
+            behind = server.subscriptions.push(&server.bank);
+        }
+    });
+}
+
+/// Executes one request read by a connection thread and sends the answer
+/// back to its connection.
+fn execute_job(server: &mut Server, job: Job, remote_jobs: &Sender<RemoteJob>) {
+    server.cancelled = job.cancelled;
+    server.received = job.received;
+    server.client = Some(job.client);
+    server.connection = job.connection;
+    server.token = None;
+    server.progress = match (&job.request_id, &job.request) {
+        (Some(request_id), Request::Command(format, _)) => Some(Progress {
+            request_id: request_id.clone(),
+            format: *format,
+            reply: job.reply.clone(),
+        }),
+        _ => None,
+    };
+    let answer = handle_request(server, job.request, &job.data, job.request_id.clone());
+    // Писатель соединения ждет, пока не останется отправителей ответов
+    let progress = server.progress.take();
+    let connection = server.connection.take();
+    // На подписку отвечают новыми операциями, пока ее не отменят
+    if let (Some((from, account)), Some(progress), Some(connection)) =
+        (server.subscribe.take(), progress, connection.clone())
+    {
+        let cancelled = server.cancelled.clone();
+        server
+            .subscriptions
+            .add(progress, connection, cancelled, from, account);
+        return;
+    }
+    // На перевод в другой банк ответят, когда будет готова его удаленная часть
+    if let (Some(transfer), Answer::Response(format, _)) = (server.remote_transfer.take(), &answer)
+    {
+        let _ = remote_jobs.send(RemoteJob {
+            format: *format,
+            transfer,
+            request_id: job.request_id,
+            data: job.data,
+            reply: job.reply,
+            cancelled: server.cancelled.clone(),
+            received: job.received,
+            client: job.client,
+            connection,
+        });
+        return;
+    }
+    // Соединение могло уже закрыться, ответ тогда просто не нужен
+    let _ = job.reply.send(Reply {
+        request_id: job.request_id,
+        answer,
+        buffer: job.data,
+        progress: false,
+    });
+}
+
+/// Runs the jobs of the config that are due. A failed job is logged and
+/// tried again at its next run.
+fn run_jobs(server: &mut Server) {
+    let now = server.bank.now();
+    let jobs = server.settings.config.jobs.clone();
+    for job in server.scheduler.due(&jobs, now) {
+        let result = run_task(server, &job.task);
+        if job.task.mutates() {
+            let webhook = server.settings.config.alert_webhook.as_deref();
+            server.alerts.check(&server.bank, webhook);
+        }
+        match &result {
+            Ok(()) => {
+                if server.settings.config.enabled(LogLevel::Info) {
+                    println!("Job {} ({}) done", job.name, job.task);
+                }
+            }
+            Err(e) => eprintln!("Job {} ({}) failed: {:?}", job.name, job.task, e),
+        }
+        server
+            .scheduler
+            .finished(&job.name, result.err().map(|e| format!("{:?}", e)));
+    }
+}
+
+fn run_task(server: &mut Server, task: &Task) -> Result<(), BankError> {
+    if task.mutates() {
+        if server.maintenance || server.settings.config.maintenance {
+            return Err(BankError::Maintenance);
+        }
+        if let Some(replica) = &server.replica {
+            return Err(BankError::NotPrimary(replica.primary().to_string()));
+        }
+    }
+    match task {
+        Task::Snapshot => server
+            .snapshots
+            .write_now(&server.settings.config.snapshots, &server.bank)
+            .map(|_| ())
+            .ok_or_else(|| BankError::InvalidConfig("snapshots.dir is not set".to_string())),
+        // Крупный перевод по расписанию тоже ждет администратора
+        Task::Transfer { from, to, amount } if needs_approval(&server.settings, *amount) => {
+            Err(BankError::TransferPending(server.bank.queue_transfer(
+                from.as_str(),
+                to.as_str(),
+                *amount,
+            )?))
+        }
+        Task::Transfer { from, to, amount } => {
+            server.bank.transfer(from.as_str(), to.as_str(), *amount)?;
+            Ok(())
+        }
+        Task::Interest {
+            from,
+            prefix,
+            rate_bps,
+        } => {
+            let payments: Vec<(String, u32)> = server
+                .bank
+                .find_accounts(Some(1), None)
+                .into_iter()
+                .filter(|(account, _)| account.starts_with(prefix.as_str()) && account != from)
+                .map(|(account, balance)| {
+                    let interest = balance as u64 * *rate_bps as u64 / 10_000;
+                    (account, u32::try_from(interest).unwrap_or(u32::MAX))
+                })
+                .filter(|(_, interest)| *interest > 0)
+                .collect();
+            // Проценты платятся всем или никому, иначе повтор задания после
+            // ошибки заплатил бы части счетов дважды
+            let total: u64 = payments.iter().map(|(_, interest)| *interest as u64).sum();
+            if total > server.bank.get_available_balance(from.as_str())? as u64 {
+                return Err(BankError::InsufficientFunds(
+                    u32::try_from(total).unwrap_or(u32::MAX),
+                ));
+            }
+            let (queued, paid): (Vec<_>, Vec<_>) = payments
+                .into_iter()
+                .partition(|(_, interest)| needs_approval(&server.settings, *interest));
+            let batch: Vec<BatchOperation> = paid
+                .into_iter()
+                .map(|(account, interest)| BatchOperation::Transfer {
+                    from: from.as_str().into(),
+                    to: account.into(),
+                    amount: interest,
+                })
+                .collect();
+            // Крупные выплаты ждут администратора. Они ставятся в очередь до
+            // остальных и снимаются с нее, если что-то не прошло: так задание
+            // не оставляет половину выплат сделанной
+            let mut pending = Vec::new();
+            let result = queued
+                .into_iter()
+                .try_for_each(|(account, interest)| {
+                    let id =
+                        server
+                            .bank
+                            .queue_transfer(from.as_str(), account.as_str(), interest)?;
+                    pending.push(id);
+                    Ok(())
+                })
+                .and_then(|()| server.bank.batch(&batch).map(|_| ()));
+            if result.is_err() {
+                for id in pending {
+                    let _ = server.bank.reject_transfer(id);
+                }
+            }
+            result
+        }
+    }
+}
+
+/// Reads the requests of one connection: a single command, or frames of a
+/// pipelined connection until the client closes it. A connection from a
+/// load balancer first names the client in a PROXY protocol header.
+fn connection(
+    mut stream: TcpStream,
+    proxy_protocol: &ProxyProtocolConfig,
+    limits: Limits,
+    jobs: SyncSender<Job>,
+) {
+    let peer = match stream
+        .set_read_timeout(limits.handshake)
+        .and_then(|()| stream.peer_addr())
+    {
+        Ok(peer) => peer,
+        Err(e) => {
+            eprintln!("Failed to set up connection: {}", e);
+            return;
+        }
+    };
+    let client = match proxy_protocol.expected_from(peer.ip()) {
+        true => match proxy_protocol::read_header(&mut stream) {
+            // Заголовок без адреса шлет сам балансировщик, например для проверки
+            Ok(client) => client.unwrap_or(peer),
+            Err(e) => {
+                eprintln!("No valid PROXY header from {}, closing it: {}", peer, e);
+                return;
+            }
+        },
+        false => peer,
+    };
+
+    // На байт больше наибольшей команды, чтобы заметить превышение
+    let mut buffer = [0; MAX_COMMAND_SIZE + 1];
+    let received = stream.read(&mut buffer);
+    let received = match received {
+        Ok(n) => &buffer[..n],
+        Err(e) if is_timeout(&e) => {
+            eprintln!("Connection sent no command in time, closing it");
+            return;
+        }
+        Err(e) if is_disconnect(&e) => {
+            eprintln!("Client disconnected before sending a command");
+            return;
+        }
+        Err(e) => {
+            let error = BankError::ProtocolError(format!("failed to read command: {}", e));
+            write_reply(
+                &mut stream,
+                &Answer::Response(WireFormat::default(), Err(error)),
+                limits.max_message_size,
+            );
+            return;
+        }
+    };
+    if let Some((&PIPELINE_MARKER, received)) = received.split_first() {
+        return pipeline(stream, client, received, limits, jobs);
+    }
+    if received.len() > MAX_COMMAND_SIZE {
+        let format = WireFormat::detect(received).map_or(WireFormat::default(), |(f, _)| f);
+        let error = BankError::MessageTooLarge {
+            size: received.len(),
+            limit: MAX_COMMAND_SIZE,
+        };
+        let answer = Answer::Response(format, Err(error));
+        write_reply(&mut stream, &answer, limits.max_message_size);
+        return discard(&stream, limits);
+    }
+
+    let (reply, answer) = mpsc::channel();
+    let job = Job {
+        request_id: None,
+        request: Request::decode(received, false),
+        data: received.to_vec(),
+        reply,
+        cancelled: Arc::default(),
+        received: Instant::now(),
+        client,
+        connection: None,
+    };
+    if jobs.send(job).is_ok() {
+        if let Ok(reply) = answer.recv() {
+            write_reply(&mut stream, &reply.answer, limits.max_message_size);
+        }
+    }
+}
+
+/// Passes on the requests of a pipelined connection without waiting for the
+/// answers; a separate thread writes the answers as they are ready. A frame
+/// without a body cancels the request with its ID. A frame that does not
+/// arrive in time, or a request over the size limit, closes the connection.
+fn pipeline(
+    stream: TcpStream,
+    client: SocketAddr,
+    received: &[u8],
+    limits: Limits,
+    jobs: SyncSender<Job>,
+) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(e) => {
+            eprintln!("Failed to clone stream: {}", e);
+            return;
+        }
+    };
+    // Транзакции соединения откатываются, когда его запросы выполнены и оно закрыто
+    let connection = Connection::open();
+    // Флаги отмены запросов, на которые еще не ответили
+    let in_flight: Arc<Mutex<HashMap<String, Arc<AtomicBool>>>> = Arc::default();
+    // Буферы запросов, на которые уже ответили, для следующих запросов
+    let spare: Arc<Mutex<Vec<Vec<u8>>>> = Arc::default();
+    let (reply, answers) = mpsc::channel::<Reply>();
+    let answered = Arc::clone(&in_flight);
+    let returned = Arc::clone(&spare);
+    let writer = thread::spawn(move || {
+        // Ответы кодируются и собираются в кадры в одни и те же буферы
+        let mut body = Vec::new();
+        let mut frame = Vec::new();
+        for reply in answers {
+            let request_id = reply.request_id.unwrap_or_default();
+            if !reply.progress {
+                answered.lock().unwrap().remove(&request_id);
+            }
+            body.clear();
+            reply.answer.encode_into(&mut body, limits.max_message_size);
+            if let Err(e) = write_frame_with(&mut writer, &request_id, &body, &mut frame) {
+                if is_disconnect(&e) {
+                    eprintln!("Client disconnected before all answers were written");
+                } else {
+                    eprintln!("Failed to write to stream: {}", e);
+                }
+                // Ответы уже некуда писать: ждущие запросы отменяются, а
+                // чтение новых прерывается
+                for cancelled in answered.lock().unwrap().values() {
+                    cancelled.store(true, Ordering::Relaxed);
+                }
+                let _ = writer.shutdown(Shutdown::Both);
+                break;
+            }
+            body.clear();
+            body.shrink_to(MAX_KEPT_BUFFER);
+            frame.clear();
+            frame.shrink_to(MAX_KEPT_BUFFER);
+            keep_spare(&returned, reply.buffer);
+        }
+    });
+
+    // Начало первого кадра могло прийти вместе с маркером
+    let mut reader = FrameReader {
+        received,
+        stream: &stream,
+        timeout: limits.frame,
+        deadline: None,
+    };
+    let mut data = Vec::new();
+    // Формат последнего запроса: в нем же отвечаем на запрос, который не прочитан
+    let mut format = WireFormat::default();
+    let mut unread = false;
+    loop {
+        reader.deadline = None;
+        match read_frame_limited(&mut reader, &mut data, limits.max_message_size) {
+            Ok(Some(request_id)) if data.is_empty() => {
+                // Отмена уже отвеченного запроса ничего не меняет
+                if let Some(cancelled) = in_flight.lock().unwrap().get(&request_id) {
+                    cancelled.store(true, Ordering::Relaxed);
+                }
+            }
+            Ok(Some(request_id)) => {
+                let cancelled = Arc::new(AtomicBool::new(false));
+                in_flight
+                    .lock()
+                    .unwrap()
+                    .insert(request_id.clone(), Arc::clone(&cancelled));
+                if let Ok((detected, _)) = WireFormat::detect(&data) {
+                    format = detected;
+                }
+                let next = spare.lock().unwrap().pop().unwrap_or_default();
+                let job = Job {
+                    request_id: Some(request_id),
+                    request: Request::decode(&data, true),
+                    data: mem::replace(&mut data, next),
+                    reply: reply.clone(),
+                    cancelled,
+                    received: Instant::now(),
+                    client,
+                    connection: Some(Arc::clone(&connection)),
+                };
+                if jobs.send(job).is_err() {
+                    break;
+                }
+            }
+            Ok(None) => break,
+            Err(e) if is_timeout(&e) => {
+                eprintln!("Frame did not arrive in time, closing the connection");
+                break;
+            }
+            Err(e) if is_disconnect(&e) => {
+                eprintln!("Client disconnected in the middle of a frame");
+                break;
+            }
+            Err(e) => {
+                match TooLarge::find(&e) {
+                    // Тело не читается: соединение закрывается после ответа
+                    Some(too_large) => {
+                        eprintln!("{}, closing the connection", too_large);
+                        let error = BankError::MessageTooLarge {
+                            size: too_large.size,
+                            limit: too_large.limit,
+                        };
+                        let _ = reply.send(Reply {
+                            request_id: Some(too_large.request_id.clone()),
+                            answer: Answer::Response(
+                                format,
+                                Err(error.with_request_id(&too_large.request_id)),
+                            ),
+                            buffer: Vec::new(),
+                            progress: false,
+                        });
+                        unread = true;
+                    }
+                    None => eprintln!("Failed to read frame: {}", e),
+                }
+                break;
+            }
+        }
+    }
+    // Писатель закончит, когда будут отправлены ответы на все прочитанные
+    // запросы; подписки соединения на этом заканчиваются
+    connection.close();
+    drop(reply);
+    let _ = writer.join();
+    if unread {
+        discard(&stream, limits);
+    }
+}
+
+/// Reads what is left of a request the connection will not handle, so that
+/// closing the socket does not reset it before the client reads the answer.
+/// It takes no longer than the handshake timeout and reads at most the
+/// largest message.
+fn discard(mut stream: &TcpStream, limits: Limits) {
+    let _ = stream.shutdown(Shutdown::Write);
+    let timeout = limits
+        .handshake
+        .unwrap_or(Duration::from_millis(HANDSHAKE_TIMEOUT_MS));
+    let deadline = Instant::now() + timeout;
+    let mut buffer = [0; 8192];
+    let mut left = limits.max_message_size;
+    while left > 0 {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || stream.set_read_timeout(Some(remaining)).is_err() {
+            break;
+        }
+        match stream.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => left = left.saturating_sub(n),
+        }
+    }
+}
+
+/// Reader of pipelined frames. Waiting for the first byte of a frame is not
+/// limited; once it arrives, the rest of the frame must follow by `deadline`.
+struct FrameReader<'a> {
+    // Байты, прочитанные вместе с маркером конвейера
+    received: &'a [u8],
+    stream: &'a TcpStream,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+}
+
+impl Read for FrameReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if !self.received.is_empty() {
+            let n = self.received.read(buf)?;
+            self.start();
+            return Ok(n);
+        }
+        let remaining = match self.deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(std::io::ErrorKind::TimedOut.into());
+                }
+                Some(remaining)
+            }
+            None => None,
+        };
+        self.stream.set_read_timeout(remaining)?;
+        let n = self.stream.read(buf)?;
+        if n > 0 {
+            self.start();
+        }
+        Ok(n)
+    }
+}
+
+impl FrameReader<'_> {
+    // Отсчет начинается с первого байта кадра
+    fn start(&mut self) {
+        if self.deadline.is_none() {
+            self.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        }
+    }
+}
+
+// Истекший таймаут чтения сокета на Unix - WouldBlock, на Windows - TimedOut
+fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+    )
+}
+
+// Клиент закрыл соединение или оно оборвалось: это не ошибка сервера
+fn is_disconnect(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::UnexpectedEof
+            | std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+    )
+}
+
+/// Keeps a request buffer for the next request of the connection, unless
+/// there are enough of them or it is too large to hold on to.
+fn keep_spare(spare: &Mutex<Vec<Vec<u8>>>, mut buffer: Vec<u8>) {
+    if buffer.capacity() == 0 || buffer.capacity() > MAX_KEPT_BUFFER {
+        return;
+    }
+    let mut spare = spare.lock().unwrap();
+    if spare.len() < SPARE_BUFFERS {
+        buffer.clear();
+        spare.push(buffer);
+    }
+}
+
+fn write_reply(stream: &mut TcpStream, answer: &Answer, limit: usize) {
+    let mut data = Vec::new();
+    answer.encode_into(&mut data, limit);
+    // Клиент мог уже закрыть соединение, это не повод останавливать сервер
+    match stream.write_all(&data) {
+        Ok(()) => {}
+        Err(e) if is_disconnect(&e) => eprintln!("Client disconnected before the answer"),
+        Err(e) => eprintln!("Failed to write to stream: {}", e),
+    }
+}
+
+/// Server set up from [`Args`], not yet accepting connections.
+struct Prepared {
+    server: Server,
+    listener: TcpListener,
+    socket: SocketOptions,
+    limits: Limits,
+    reload_requested: Arc<AtomicBool>,
+}
+
+impl Prepared {
+    fn serve(mut self) {
+        serve(
+            &mut self.server,
+            &self.listener,
+            self.socket,
+            self.limits,
+            &self.reload_requested,
+        );
+    }
+}
+
+/// Runs the server with `args` on this thread. Returns only if the server
+/// could not start.
+pub fn run(args: Args) -> io::Result<()> {
+    let prepared = prepare(args, Arc::new(SystemClock))?;
+    systemd::notify_ready();
+    prepared.serve();
+    Ok(())
+}
+
+/// Starts the server with `args` in a background thread of this process and
+/// returns the address it listens on. With port 0 the system picks a free
+/// port, so tests can run many servers side by side.
+pub fn spawn(args: Args) -> io::Result<SocketAddr> {
+    spawn_with_clock(args, Arc::new(SystemClock))
+}
+
+/// Same as [`spawn`], but the bank takes the time from `clock`, e.g. a
+/// [`TestClock`] to check daily limits without waiting for a day.
+pub fn spawn_with_clock(args: Args, clock: Arc<dyn Clock>) -> io::Result<SocketAddr> {
+    let prepared = prepare(args, clock)?;
+    let address = prepared.listener.local_addr()?;
+    thread::spawn(move || prepared.serve());
+    Ok(address)
+}
+
+/// Opens the bank in the storage from the config, encrypted with `key` if
+/// there is one. A new storage is filled from the primary, from
+/// `migrate_from` or from the latest snapshot.
+fn open_bank(
+    settings: &Settings,
+    args: &Args,
+    replica: Option<&mut Replica>,
+    key: Option<&Key>,
+) -> io::Result<Bank> {
+    let config = &settings.config.storage;
+    let latest = || match &settings.config.snapshots.dir {
+        Some(dir) => snapshots::load_latest(dir, key).map_err(|e| {
+            io::Error::other(format!(
+                "Failed to load snapshot from {}: {}",
+                dir.display(),
+                e
+            ))
+        }),
+        None => Ok(None),
+    };
+
+    if config.backend != StorageBackend::Memory && args.history_dir.is_some() {
+        return Err(io::Error::other(
+            "--history-dir can be used only with the memory storage",
+        ));
+    }
+    if config.backend == StorageBackend::Memory && config.durability.is_some() {
+        return Err(io::Error::other(
+            "storage.durability can be used only with the sled and postgres storages",
+        ));
+    }
+    let durability = config.durability.unwrap_or_default();
+    if config.backend == StorageBackend::Postgres && key.is_some() {
+        return Err(io::Error::other(
+            "encryption.key_file does not cover the postgres storage, encrypt the database instead",
+        ));
+    }
+    if args.history_archive_after.is_some() && args.history_dir.is_none() {
+        return Err(io::Error::other(
+            "--history-archive-after requires --history-dir",
+        ));
+    }
+    // Без отдельного хранилища банк остается в памяти, где и загружен. Второе
+    // значение - хранилище еще пустое
+    let storage: Option<(Box<dyn BankStorage>, bool)> = match config.backend {
+        StorageBackend::Memory => match &args.history_dir {
+            Some(dir) => {
+                let history = History::segmented(
+                    dir,
+                    history::SEGMENT_SIZE,
+                    args.history_memory,
+                    args.history_archive_after,
+                    key.cloned(),
+                )?;
+                Some((Box::new(MemoryStorage::with_history(history)), true))
+            }
+            None => None,
+        },
+        StorageBackend::Sled => {
+            let path = config
+                .path
+                .as_deref()
+                .ok_or_else(|| io::Error::other("storage.path is required for the sled backend"))?;
+            let sled = SledStorage::open(path, durability, key.cloned())?;
+            let new = sled.is_new();
+            Some((Box::new(sled), new))
+        }
+        #[cfg(feature = "postgres")]
+        StorageBackend::Postgres => {
+            let url = config.url.as_deref().ok_or_else(|| {
+                io::Error::other("storage.url is required for the postgres backend")
+            })?;
+            let pool_size = config.pool_size.unwrap_or(DEFAULT_POOL_SIZE);
+            let postgres =
+                PostgresStorage::open(url, pool_size, durability).map_err(io::Error::other)?;
+            let new = postgres.is_new();
+            Some((Box::new(postgres), new))
+        }
+        #[cfg(not(feature = "postgres"))]
+        StorageBackend::Postgres => {
+            return Err(io::Error::other(
+                "the server is built without the postgres feature",
+            ));
+        }
+    };
+    let storage = match storage {
+        Some((storage, false)) => {
+            println!("Opened storage with {} operations", storage.history_len());
+            // Хранилище уже содержит банк. Данные счетов вне истории берутся
+            // из снимка, только если база записана еще без них
+            let kept = storage.details().is_some();
+            let mut bank = Bank::with_storage(storage);
+            if let Some(snapshot) = latest()?.filter(|_| !kept) {
+                bank.set_account_details(
+                    snapshot.metadata,
+                    snapshot.owners,
+                    snapshot.tags,
+                    snapshot.approvals,
+                    snapshot.alert_rules,
+                );
+                bank.set_disputes(snapshot.disputes);
+            }
+            return Ok(bank);
+        }
+        storage => storage.map(|(storage, _)| storage),
+    };
+
+    let snapshot = match (&replica, &config.migrate_from) {
+        (Some(_), _) => None,
+        (None, Some(path)) => Some(snapshots::load(path, key).map_err(|e| {
+            io::Error::other(format!("Failed to load snapshot {}: {}", path.display(), e))
+        })?),
+        (None, None) => latest()?,
+    };
+    let mut bank = match (replica, snapshot) {
+        (Some(replica), _) => replica.bootstrap().map_err(|e| {
+            io::Error::other(format!(
+                "Failed to bootstrap from {}: {:?}",
+                replica.primary(),
+                e
+            ))
+        })?,
+        (None, Some(snapshot)) => {
+            println!("Loaded snapshot at operation {}", snapshot.history.len());
+            Bank::from_snapshot(snapshot)
+        }
+        (None, None) => Bank::default(),
+    };
+    if let Some(storage) = storage {
+        bank.set_storage(storage);
+    }
+    Ok(bank)
+}
+
+fn prepare(args: Args, clock: Arc<dyn Clock>) -> io::Result<Prepared> {
+    if args.port.is_empty() {
+        return Err(io::Error::other("no params"));
+    }
+    let socket = SocketOptions {
+        nodelay: args.tcp_nodelay,
+        keepalive: args.tcp_keepalive_secs.map(Duration::from_secs),
+        reuse_address: args.reuse_address,
+        connect_timeout: None,
+    };
+    // При активации сокетом systemd уже открыл порт
+    let listener = match systemd::activated_listener()? {
+        Some(listener) => listener,
+        None => socket.bind(format!("127.0.0.1:{}", args.port))?,
+    };
+    // С портом 0 настоящий порт известен только после bind
+    let bound = listener.local_addr()?;
+    let server_address = bound.to_string();
+    println!("server_address: {}", &server_address);
+    if let Some(path) = &args.pid_file {
+        systemd::write_pid_file(path)?;
+    }
+
+    let settings =
+        Settings::load(args.config.clone()).map_err(|e| io::Error::other(format!("{:?}", e)))?;
+    // Сигнал только выставляет флаг, сам файл перечитывается перед следующим запросом
+    let reload_requested = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&reload_requested))?;
+
+    let mut replica = args
+        .replica_of
+        .as_deref()
+        .map(|primary| Replica::new(primary, Duration::from_millis(args.replica_sync_ms)));
+    let key = settings.config.encryption.key()?;
+    let mut bank = open_bank(&settings, &args, replica.as_mut(), key.as_ref())?;
+    // Раздел хранилища после перечитывания конфига уже не меняется
+    let storage = &settings.config.storage;
+    let durability =
+        (storage.backend != StorageBackend::Memory).then(|| storage.durability.unwrap_or_default());
+    bank.set_clock(Arc::clone(&clock));
+    bank.set_velocity_rules(settings.config.velocity_rules.clone());
+    set_capacity(&settings.config, &mut bank);
+    if args.check_consistency {
+        let report = bank.check_consistency();
+        if !report.differences.is_empty() {
+            for difference in &report.differences {
+                eprintln!("Inconsistent state: {}", difference);
+            }
+            return Err(io::Error::other("state is inconsistent with the history"));
+        }
+        println!("State is consistent with {} operations", report.operations);
+    }
+    // Журнал и номера транзакций не должны зависеть от порта, выбранного системой
+    let coordinator_config = &settings.config.coordinator;
+    let coordinator_id = coordinator_config.id.as_deref();
+    let coordinator_log = args.coordinator_log.unwrap_or_else(|| {
+        PathBuf::from(format!(
+            "coordinator-{}.log",
+            coordinator_id.unwrap_or(&args.port)
+        ))
+    });
+    let coordinator = Coordinator::open(
+        coordinator_id.unwrap_or(&server_address),
+        &server_address,
+        &coordinator_log,
+        Duration::from_secs(coordinator_config.retry_secs),
+        &mut bank,
+    )?;
+    let tracer = match &args.otlp_endpoint {
+        Some(endpoint) => Some(Tracer::start(endpoint, "bank-server").map_err(io::Error::other)?),
+        None => None,
+    };
+    let server = Server {
+        address: server_address,
+        durability,
+        snapshots: Snapshots::start(bank.history_len(), key),
+        bank,
+        coordinator,
+        settings,
+        metrics: Metrics::default(),
+        tracer,
+        maintenance: false,
+        replica,
+        rate_limiter: RateLimiter::new(Arc::clone(&clock)),
+        locks: Locks::new(clock),
+        alerts: Alerts::default(),
+        scheduler: Scheduler::default(),
+        shipping: Shipping::default(),
+        transactions: Transactions::default(),
+        subscriptions: Subscriptions::default(),
+        cancelled: Arc::default(),
+        received: Instant::now(),
+        client: None,
+        connection: None,
+        progress: None,
+        token: None,
+        remote_transfer: None,
+        subscribe: None,
+    };
+    let limits = Limits {
+        max_message_size: args.max_message_size,
+        ..Limits::from_millis(args.handshake_timeout_ms, args.frame_timeout_ms)
+    };
+    Ok(Prepared {
+        server,
+        listener,
+        socket,
+        limits,
+        reload_requested,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Shutdown;
+    use std::thread;
+
+    use protocol_crate::pipeline::{read_frame, write_frame};
+    use protocol_crate::{AccountRef, Operation, PageRequest};
+
+    /// Starts a server on a free port and returns its address.
+    fn start_server() -> String {
+        start_server_with(Bank::default(), None, Limits::default())
+    }
+
+    fn start_server_with(mut bank: Bank, replica: Option<Replica>, limits: Limits) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let log_path =
+            std::env::temp_dir().join(format!("coordinator-malformed-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&log_path);
+
+        let coordinator =
+            Coordinator::open(&address, &address, &log_path, Duration::ZERO, &mut bank).unwrap();
+        // Файл остается открытым у координатора, в каталоге он не нужен
+        let _ = std::fs::remove_file(&log_path);
+        let mut server = Server {
+            address: address.clone(),
+            durability: None,
+            bank,
+            coordinator,
+            settings: Settings::load(None).unwrap(),
+            metrics: Metrics::default(),
+            snapshots: Snapshots::start(0, None),
+            tracer: None,
+            maintenance: false,
+            replica,
+            rate_limiter: RateLimiter::default(),
+            locks: Locks::default(),
+            alerts: Alerts::default(),
+            scheduler: Scheduler::default(),
+            shipping: Shipping::default(),
+            transactions: Transactions::default(),
+            subscriptions: Subscriptions::default(),
+            cancelled: Arc::default(),
+            received: Instant::now(),
+            client: None,
+            connection: None,
+            progress: None,
+            token: None,
+            remote_transfer: None,
+            subscribe: None,
+        };
+        let socket = SocketOptions::default();
+        thread::spawn(move || {
+            serve(
+                &mut server,
+                &listener,
+                socket,
+                limits,
+                &AtomicBool::new(false),
+            )
+        });
+        address
+    }
+
+    fn send_as(address: &str, format: WireFormat, data: &[u8]) -> Response {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(data).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        format.decode(&received).unwrap()
+    }
+
+    /// Sends raw bytes; the response comes back in the format they look like.
+    fn send(address: &str, data: &[u8]) -> Response {
+        let (format, _) = WireFormat::detect(data).unwrap();
+        send_as(address, format, data)
+    }
+
+    #[test]
+    fn malformed_requests() {
+        let address = start_server();
+
+        // Псевдослучайные байты (xorshift), чтобы тест был воспроизводимым
+        let mut state: u32 = 2463534242;
+        for round in 0..50 {
+            let data: Vec<u8> = (0..1 + round * 7)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    state as u8
+                })
+                .collect();
+            let x = send(&address, &data);
+            assert!(matches!(x, Err(BankError::ProtocolError(_))), "{:?}", x);
+        }
+        let x = send(&address, b"");
+        assert!(matches!(x, Err(BankError::ProtocolError(_))));
+        let x = send(&address, br#"{"CreateAccount": 5}"#);
+        assert!(matches!(x, Err(BankError::ProtocolError(_))));
+
+        // Сервер продолжает обслуживать корректные команды
+        let command = serde_json::to_vec(&Command::CreateAccount("X".to_string())).unwrap();
+        let x = send(&address, &command);
+        assert!(matches!(x, Ok(ResponsePayload::Account(0))));
+    }
+
+    #[test]
+    fn deeply_nested_command() {
+        let address = start_server();
+        // 200 000 оберток WithDeadline вокруг команды, около 2,4 МБ
+        let format = WireFormat::Bincode;
+        let history = Command::GetHistoryPaged {
+            page: PageRequest::first(10),
+        };
+        let leaf = format.encode(&history);
+        let wrapped = format.encode_command(&Command::WithDeadline {
+            timeout_ms: 1,
+            command: Box::new(history.clone()),
+        });
+        let wrapper = &wrapped[..wrapped.len() - leaf.len()];
+        let mut nested = wrapper[..1].to_vec();
+        for _ in 0..200_000 {
+            nested.extend_from_slice(&wrapper[1..]);
+        }
+        nested.extend_from_slice(&leaf);
+
+        let mut stream = TcpStream::connect(&address).unwrap();
+        let mut data = vec![PIPELINE_MARKER];
+        write_frame(&mut data, "deep", &nested).unwrap();
+        write_frame(&mut data, "next", &format.encode_command(&history)).unwrap();
+        stream.write_all(&data).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+
+        let mut responses = Vec::new();
+        while let Some((request_id, body)) = read_frame(&mut stream).unwrap() {
+            let response: Response = format.decode(&body).unwrap();
+            responses.push((request_id, response));
+        }
+        assert_eq!(2, responses.len());
+        assert!(
+            matches!(&responses[0], (id, Err(BankError::ProtocolError(_))) if id == "deep"),
+            "{:?}",
+            responses[0]
+        );
+        assert!(matches!(
+            &responses[1],
+            (_, Ok(ResponsePayload::HistoryPage(_)))
+        ));
+    }
+
+    #[test]
+    fn binary_formats() {
+        let address = start_server();
+        let command = Command::CreateAccount("X".to_string());
+        for (id, format) in [WireFormat::Bincode, WireFormat::MsgPack]
+            .into_iter()
+            .enumerate()
+        {
+            let x = send_as(&address, format, &format.encode_command(&command));
+            if id == 0 {
+                assert!(matches!(x, Ok(ResponsePayload::Account(0))));
+            } else {
+                assert!(matches!(x, Err(BankError::AccountAlreadyExists(_))));
+            }
+        }
+        let command = Command::GetAccountBalance(AccountRef::Id(0));
+        let data = WireFormat::MsgPack.encode_command(&command);
+        let x = send_as(&address, WireFormat::MsgPack, &data);
+        assert!(matches!(x, Ok(ResponsePayload::AccountBalance(0))));
+    }
+
+    #[test]
+    fn handshake() {
+        let address = start_server();
+        let data = WireFormat::MsgPack.encode_command(&Command::Handshake);
+        let Ok(ResponsePayload::ServerInfo(info)) = send_as(&address, WireFormat::MsgPack, &data)
+        else {
+            panic!("no server info");
+        };
+        assert_eq!(PROTOCOL_VERSION, info.protocol_version);
+        assert_eq!(address, info.address);
+        assert_eq!(vec!["bincode", "msgpack", "json"], info.encodings);
+        assert_eq!(
+            Some(WireFormat::Bincode),
+            WireFormat::negotiate(&info.encodings)
+        );
+    }
+
+    #[test]
+    fn request_id() {
+        let address = start_server();
+        let tagged = |command: Command| Command::WithRequestId {
+            request_id: "r-1".to_string(),
+            command: Box::new(command),
+        };
+        let format = WireFormat::default();
+        let data = format.encode_command(&tagged(Command::CreateAccount("X".to_string())));
+        assert!(matches!(
+            send(&address, &data),
+            Ok(ResponsePayload::Account(0))
+        ));
+
+        let data = format.encode_command(&tagged(Command::DecreaseAccount("X".into(), 5)));
+        match send(&address, &data) {
+            Err(BankError::RequestFailed { request_id, error }) => {
+                assert_eq!("r-1", request_id);
+                assert!(matches!(*error, BankError::InsufficientFunds(_)));
+            }
+            x => panic!("unexpected response {:?}", x),
+        }
+    }
+
+    #[test]
+    fn restore_progress() {
+        let address = start_server();
+        let mut stream = TcpStream::connect(&address).unwrap();
+        let format = WireFormat::default();
+        let mut history = vec![Operation::CreateAccount("X".to_string())];
+        history.extend((1..2500).map(|_| Operation::IncreaseAccount("X".to_string(), 1)));
+        let mut data = vec![PIPELINE_MARKER];
+        let expected = protocol_crate::digest::history_digest(&history);
+        let restore = format.encode_command(&Command::Restore(history));
+        write_frame(&mut data, "r", &restore).unwrap();
+        stream.write_all(&data).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+
+        let mut responses = Vec::new();
+        while let Some((request_id, body)) = read_frame(&mut stream).unwrap() {
+            assert_eq!("r", request_id);
+            let response: Response = format.decode(&body).unwrap();
+            responses.push(response);
+        }
+        let applied: Vec<usize> = responses
+            .iter()
+            .filter_map(|response| match response {
+                Ok(ResponsePayload::RestoreProgress(progress)) => Some(progress.applied),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![1000, 2000, 2500], applied);
+        assert!(matches!(
+            responses.last(),
+            Some(Ok(ResponsePayload::Restored(digest))) if *digest == expected
+        ));
+    }
+
+    #[test]
+    fn deadline() {
+        let address = start_server();
+        let within = |timeout_ms, command: Command| {
+            let command = Command::WithDeadline {
+                timeout_ms,
+                command: Box::new(command),
+            };
+            send(&address, &WireFormat::default().encode_command(&command))
+        };
+        assert!(matches!(
+            within(0, Command::CreateAccount("X".to_string())),
+            Err(BankError::DeadlineExceeded)
+        ));
+        assert!(matches!(
+            within(60_000, Command::CreateAccount("X".to_string())),
+            Ok(ResponsePayload::Account(0))
+        ));
+    }
+
+    #[test]
+    fn read_replica() {
+        let primary = start_server();
+        let command = |command: Command| WireFormat::default().encode_command(&command);
+        let _ = send(&primary, &command(Command::CreateAccount("X".to_string())));
+        let _ = send(&primary, &command(Command::IncreaseAccount("X".into(), 5)));
+
+        let mut replica = Replica::new(&primary, Duration::ZERO);
+        let bank = replica.bootstrap().unwrap();
+        let address = start_server_with(bank, Some(replica), Limits::default());
+        let balance = command(Command::GetAccountBalance("X".into()));
+        assert!(matches!(
+            send(&address, &balance),
+            Ok(ResponsePayload::AccountBalance(5))
+        ));
+        assert!(matches!(
+            send(&address, &command(Command::IncreaseAccount("X".into(), 1))),
+            Err(BankError::NotPrimary(p)) if p == primary
+        ));
+
+        // Новые операции основного сервера реплика забирает перед запросом
+        let _ = send(&primary, &command(Command::IncreaseAccount("X".into(), 2)));
+        assert!(matches!(
+            send(&address, &balance),
+            Ok(ResponsePayload::AccountBalance(7))
+        ));
+    }
+
+    #[test]
+    fn pipelined_requests() {
+        let address = start_server();
+        let mut stream = TcpStream::connect(&address).unwrap();
+        let format = WireFormat::default();
+        let mut data = vec![PIPELINE_MARKER];
+        let commands = [
+            ("a", Command::CreateAccount("X".to_string())),
+            ("b", Command::IncreaseAccount("X".into(), 5)),
+            ("c", Command::DecreaseAccount("X".into(), 50)),
+        ];
+        for (request_id, command) in &commands {
+            write_frame(&mut data, request_id, &format.encode_command(command)).unwrap();
+        }
+        // Все запросы уходят сразу, не дожидаясь ответов
+        stream.write_all(&data).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+
+        let mut responses = std::collections::HashMap::new();
+        while let Some((request_id, body)) = read_frame(&mut stream).unwrap() {
+            let response: Response = format.decode(&body).unwrap();
+            responses.insert(request_id, response);
+        }
+        assert_eq!(3, responses.len());
+        assert!(matches!(responses["a"], Ok(ResponsePayload::Account(0))));
+        assert!(matches!(
+            responses["b"],
+            Ok(ResponsePayload::OperationId(1))
+        ));
+        assert!(matches!(
+            &responses["c"],
+            Err(BankError::RequestFailed { request_id, .. }) if request_id == "c"
+        ));
+    }
+
+    #[test]
+    fn slow_clients() {
+        let limits = Limits::from_millis(200, 200);
+        let address = start_server_with(Bank::default(), None, limits);
+        let closed = |stream: &mut TcpStream| {
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).unwrap();
+            received.is_empty()
+        };
+
+        // Соединение без команды закрывается
+        let mut silent = TcpStream::connect(&address).unwrap();
+        assert!(closed(&mut silent));
+
+        // Кадр, который не дошел целиком, закрывает соединение
+        let format = WireFormat::default();
+        let mut frame = Vec::new();
+        let command = format.encode_command(&Command::CreateAccount("X".to_string()));
+        write_frame(&mut frame, "a", &command).unwrap();
+        let mut slow = TcpStream::connect(&address).unwrap();
+        slow.write_all(&[PIPELINE_MARKER]).unwrap();
+        slow.write_all(&frame[..3]).unwrap();
+        assert!(closed(&mut slow));
+
+        // Простой между кадрами не ограничен
+        let mut idle = TcpStream::connect(&address).unwrap();
+        idle.write_all(&[PIPELINE_MARKER]).unwrap();
+        thread::sleep(Duration::from_millis(400));
+        idle.write_all(&frame).unwrap();
+        let (request_id, body) = read_frame(&mut idle).unwrap().unwrap();
+        assert_eq!("a", request_id);
+        let response: Response = format.decode(&body).unwrap();
+        assert!(matches!(response, Ok(ResponsePayload::Account(0))));
+    }
+
+    #[test]
+    fn message_too_large() {
+        let limits = Limits {
+            max_message_size: 128,
+            ..Limits::default()
+        };
+        let address = start_server_with(Bank::default(), None, limits);
+        let format = WireFormat::default();
+        let too_large = |response: &Response, expected: usize| match response.as_ref() {
+            Err(e) => {
+                matches!(e.cause(), BankError::MessageTooLarge { limit, .. } if *limit == expected)
+            }
+            Ok(_) => false,
+        };
+
+        // Одиночная команда не обрезается, а отклоняется
+        let long = Command::CreateAccount("X".repeat(MAX_COMMAND_SIZE));
+        let response = send(&address, &format.encode_command(&long));
+        assert!(too_large(&response, MAX_COMMAND_SIZE), "{:?}", response);
+
+        // Ответ больше предела заменяется ошибкой
+        for account in [
+            "account-1",
+            "account-2",
+            "account-3",
+            "account-4",
+            "account-5",
+        ] {
+            let _ = send(
+                &address,
+                &format.encode_command(&Command::CreateAccount(account.into())),
+            );
+        }
+        let history = Command::GetHistoryPaged {
+            page: PageRequest::first(1000),
+        };
+        let response = send(&address, &format.encode_command(&history));
+        assert!(too_large(&response, 128), "{:?}", response);
+
+        // Кадр больше предела: ответ с его ID, затем соединение закрывается
+        let mut stream = TcpStream::connect(&address).unwrap();
+        let mut data = vec![PIPELINE_MARKER];
+        let command = Command::CreateAccount("Y".repeat(200));
+        write_frame(&mut data, "a", &format.encode_command(&command)).unwrap();
+        stream.write_all(&data).unwrap();
+        let (request_id, body) = read_frame(&mut stream).unwrap().unwrap();
+        assert_eq!("a", request_id);
+        let response: Response = format.decode(&body).unwrap();
+        assert!(too_large(&response, 128), "{:?}", response);
+        assert!(read_frame(&mut stream).unwrap().is_none());
+    }
+
+    #[test]
+    fn client_disconnects() {
+        let address = start_server();
+        let format = WireFormat::default();
+        let command = format.encode_command(&Command::CreateAccount("X".to_string()));
+        let mut frame = vec![PIPELINE_MARKER];
+        write_frame(&mut frame, "a", &command).unwrap();
+
+        // Обрыв посреди кадра
+        let mut stream = TcpStream::connect(&address).unwrap();
+        stream.write_all(&frame[..4]).unwrap();
+        drop(stream);
+
+        // Обрыв до ответа: одиночная команда и очередь конвейерных запросов
+        let mut stream = TcpStream::connect(&address).unwrap();
+        stream.write_all(&command).unwrap();
+        drop(stream);
+        let mut stream = TcpStream::connect(&address).unwrap();
+        let mut data = vec![PIPELINE_MARKER];
+        let history = format.encode_command(&Command::GetHistoryPaged {
+            page: PageRequest::first(1000),
+        });
+        for request_id in 0..100 {
+            write_frame(&mut data, &request_id.to_string(), &history).unwrap();
+        }
+        stream.write_all(&data).unwrap();
+        drop(stream);
+
+        // Остальные клиенты обслуживаются как прежде
+        let balance = format.encode_command(&Command::GetAccountBalance("X".into()));
+        for _ in 0..100 {
+            match send(&address, &balance) {
+                Ok(ResponsePayload::AccountBalance(0)) => return,
+                Err(BankError::AccountDoesNotExist(_)) => thread::sleep(Duration::from_millis(10)),
+                x => panic!("unexpected response {:?}", x),
+            }
+        }
+        panic!("command of the disconnected client was not applied");
+    }
+
+    #[test]
+    fn ephemeral_port() {
+        let dir = std::env::temp_dir();
+        let log = |n: u32| {
+            let path = dir.join(format!(
+                "coordinator-ephemeral-{}-{}.log",
+                std::process::id(),
+                n
+            ));
+            path.to_str().unwrap().to_string()
+        };
+        let start = |n| {
+            let args = ["server", "0", "--coordinator-log", &log(n)];
+            spawn(Args::parse_from(args)).unwrap()
+        };
+        let (first, second) = (start(1), start(2));
+        assert_ne!(0, first.port());
+        assert_ne!(first, second);
+
+        // Сервер сообщает выбранный системой адрес и клиентам
+        let data = WireFormat::default().encode_command(&Command::Handshake);
+        let Ok(ResponsePayload::ServerInfo(info)) = send(&second.to_string(), &data) else {
+            panic!("no server info");
+        };
+        assert_eq!(second.to_string(), info.address);
+        for n in [1, 2] {
+            let _ = std::fs::remove_file(log(n));
+        }
+    }
+}
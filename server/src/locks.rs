@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use protocol_crate::{BankError, LockId};
+
+use crate::clock::{Clock, SystemClock};
+
+/// Locks clients take on accounts to keep other clients from changing them
+/// during a workflow of several requests. A lock runs out after its lease,
+/// so a client that died does not keep the account locked forever. Locks
+/// are kept in memory only and are gone after a restart. Leases run on
+/// the clock the locks are made with.
+#[derive(Debug)]
+pub struct Locks {
+    next: LockId,
+    // Блокировки по имени счета: номер и когда кончается аренда, в
+    // миллисекундах по часам clock
+    held: HashMap<String, (LockId, u64)>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for Locks {
+    fn default() -> Self {
+        Locks::new(Arc::new(SystemClock))
+    }
+}
+
+impl Locks {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Locks {
+            next: 0,
+            held: HashMap::new(),
+            clock,
+        }
+    }
+
+    /// Locks all of `accounts` with one lock for `lease`, or none of them if
+    /// another lock on one of them is still in force. Requests are executed
+    /// one at a time and never wait for a lock, so taking several locks at
+    /// once can not deadlock.
+    pub fn lock(&mut self, accounts: &[&str], lease: Duration) -> Result<LockId, BankError> {
+        self.check(accounts, None)?;
+        let id = self.next;
+        self.next += 1;
+        let expires = self
+            .clock
+            .now_millis()
+            .saturating_add(lease.as_millis() as u64);
+        for account in accounts {
+            self.held.insert(account.to_string(), (id, expires));
+        }
+        Ok(id)
+    }
+
+    pub fn unlock(&mut self, id: LockId) -> Result<(), BankError> {
+        let now = self.clock.now_millis();
+        let held = self
+            .held
+            .values()
+            .any(|(lock, expires)| *lock == id && *expires > now);
+        self.held.retain(|_, (lock, _)| *lock != id);
+        match held {
+            true => Ok(()),
+            false => Err(BankError::LockDoesNotExist(id)),
+        }
+    }
+
+    /// Accounts that lock `id` holds.
+    pub fn accounts(&self, id: LockId) -> Vec<&str> {
+        self.held
+            .iter()
+            .filter(|(_, (lock, _))| *lock == id)
+            .map(|(account, _)| account.as_str())
+            .collect()
+    }
+
+    /// Fails with `AccountLocked` if one of `accounts` is locked by a lock
+    /// other than `holding`.
+    pub fn check(&self, accounts: &[&str], holding: Option<LockId>) -> Result<(), BankError> {
+        let now = self.clock.now_millis();
+        for account in accounts {
+            match self.held.get(*account) {
+                Some((lock, expires)) if *expires > now && Some(*lock) != holding => {
+                    return Err(BankError::AccountLocked(account.to_string()));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the locks of renamed accounts to their new names; `renames`
+    /// maps old names to new ones.
+    pub fn rename(&mut self, renames: &HashMap<String, String>) {
+        for (old, new) in renames {
+            if let Some(lock) = self.held.remove(old) {
+                self.held.insert(new.clone(), lock);
+            }
+        }
+    }
+
+    /// Forgets the locks whose lease has run out.
+    pub fn expire(&mut self) {
+        let now = self.clock.now_millis();
+        self.held.retain(|_, (_, expires)| *expires > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::TestClock;
+
+    #[test]
+    fn leases() {
+        let clock = TestClock::at(1_000);
+        let mut locks = Locks::new(Arc::new(clock.clone()));
+        let lease = Duration::from_secs(10);
+        let lock = locks.lock(&["X"], lease).unwrap();
+        assert!(matches!(
+            locks.lock(&["X"], lease),
+            Err(BankError::AccountLocked(account)) if account == "X"
+        ));
+        assert!(locks.check(&["X", "Y"], Some(lock)).is_ok());
+        assert!(locks.check(&["Y", "X"], None).is_err());
+        clock.advance_millis(9_999);
+        assert!(locks.check(&["X"], None).is_err());
+
+        // Аренда кончилась: счет свободен, а снять блокировку уже нельзя
+        clock.advance_millis(1);
+        assert!(locks.check(&["X"], None).is_ok());
+        assert!(matches!(
+            locks.unlock(lock),
+            Err(BankError::LockDoesNotExist(_))
+        ));
+        let next = locks.lock(&["X"], lease).unwrap();
+        assert_ne!(lock, next);
+        locks.unlock(next).unwrap();
+        assert!(locks.check(&["X"], None).is_ok());
+    }
+
+    #[test]
+    fn expired_locks_are_forgotten() {
+        let clock = TestClock::at(1_000);
+        let mut locks = Locks::new(Arc::new(clock.clone()));
+        let short = locks.lock(&["X"], Duration::from_secs(1)).unwrap();
+        let long = locks.lock(&["Y"], Duration::from_secs(2)).unwrap();
+        clock.advance(1);
+        locks.expire();
+        assert!(locks.accounts(short).is_empty());
+        assert_eq!(vec!["Y"], locks.accounts(long));
+    }
+
+    #[test]
+    fn several_accounts() {
+        let mut locks = Locks::new(Arc::new(TestClock::at(1_000)));
+        let lease = Duration::from_secs(10);
+        let lock = locks.lock(&["X", "Y"], lease).unwrap();
+        // Счета берутся все сразу или ни одного
+        assert!(locks.lock(&["Z", "Y"], lease).is_err());
+        assert!(locks.check(&["Z"], None).is_ok());
+        assert!(locks.check(&["X", "Y"], Some(lock)).is_ok());
+
+        locks.unlock(lock).unwrap();
+        assert!(locks.check(&["X", "Y"], None).is_ok());
+        locks.lock(&["Z", "Y"], lease).unwrap();
+    }
+
+    #[test]
+    fn renamed_account() {
+        let mut locks = Locks::new(Arc::new(TestClock::at(1_000)));
+        let lock = locks.lock(&["X"], Duration::from_secs(10)).unwrap();
+        locks.rename(&[("X".to_string(), "anonymous-0".to_string())].into());
+        assert!(locks.check(&["X"], None).is_ok());
+        assert!(locks.check(&["anonymous-0"], None).is_err());
+        locks.unlock(lock).unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+use protocol_crate::CommandMetrics;
+
+use crate::bank::Bank;
+
+// Сколько последних замеров хранить на команду для перцентилей
+const LATENCY_SAMPLES: usize = 1024;
+
+// Границы корзин гистограммы, мкс; локальные операции укладываются в доли миллисекунды
+const BUCKETS_US: [u64; 11] = [
+    10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 100_000,
+];
+
+#[derive(Debug, Default)]
+struct CommandStats {
+    count: u64,
+    errors: u64,
+    // Исход -> число команд: "ok" или имя ошибки
+    outcomes: BTreeMap<&'static str, u64>,
+    // Число замеров не длиннее каждой из границ BUCKETS_US
+    buckets: [u64; BUCKETS_US.len()],
+    total_us: u64,
+    // Кольцевой буфер последних задержек, мкс
+    latencies: Vec<u64>,
+    next_sample: usize,
+}
+
+/// Per-command request counters and latency percentiles.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    commands: BTreeMap<&'static str, CommandStats>,
+}
+
+impl Metrics {
+    /// Records a handled command; `error` is the name of the error it failed with.
+    pub fn record(
+        &mut self,
+        command: &'static str,
+        latency: Duration,
+        error: Option<&'static str>,
+    ) {
+        let stats = self.commands.entry(command).or_default();
+        stats.count += 1;
+        if error.is_some() {
+            stats.errors += 1;
+        }
+        *stats.outcomes.entry(error.unwrap_or("ok")).or_default() += 1;
+
+        let micros = latency.as_micros() as u64;
+        stats.total_us += micros;
+        for (bucket, &bound) in stats.buckets.iter_mut().zip(&BUCKETS_US) {
+            if micros <= bound {
+                *bucket += 1;
+            }
+        }
+        if stats.latencies.len() < LATENCY_SAMPLES {
+            stats.latencies.push(micros);
+        } else {
+            stats.latencies[stats.next_sample] = micros;
+        }
+        stats.next_sample = (stats.next_sample + 1) % LATENCY_SAMPLES;
+    }
+
+    pub fn snapshot(&self) -> Vec<CommandMetrics> {
+        self.commands
+            .iter()
+            .map(|(command, stats)| {
+                let mut latencies = stats.latencies.clone();
+                latencies.sort_unstable();
+                CommandMetrics {
+                    command: command.to_string(),
+                    count: stats.count,
+                    errors: stats.errors,
+                    p50_us: percentile(&latencies, 50),
+                    p90_us: percentile(&latencies, 90),
+                    p99_us: percentile(&latencies, 99),
+                    max_us: latencies.last().copied().unwrap_or(0),
+                }
+            })
+            .collect()
+    }
+
+    /// Renders the counters, latency histograms and gauges of `bank` in the
+    /// Prometheus text exposition format.
+    pub fn prometheus(&self, bank: &Bank) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP bank_commands_total Commands handled, by command and outcome.\n");
+        out.push_str("# TYPE bank_commands_total counter\n");
+        for (command, stats) in &self.commands {
+            for (outcome, count) in &stats.outcomes {
+                let _ = writeln!(
+                    out,
+                    "bank_commands_total{{command=\"{}\",outcome=\"{}\"}} {}",
+                    command, outcome, count
+                );
+            }
+        }
+
+        out.push_str("# HELP bank_command_duration_seconds Time to handle a command.\n");
+        out.push_str("# TYPE bank_command_duration_seconds histogram\n");
+        for (command, stats) in &self.commands {
+            for (count, bound) in stats.buckets.iter().zip(BUCKETS_US) {
+                let _ = writeln!(
+                    out,
+                    "bank_command_duration_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}",
+                    command,
+                    bound as f64 / 1e6,
+                    count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "bank_command_duration_seconds_bucket{{command=\"{}\",le=\"+Inf\"}} {}",
+                command, stats.count
+            );
+            let _ = writeln!(
+                out,
+                "bank_command_duration_seconds_sum{{command=\"{}\"}} {}",
+                command,
+                stats.total_us as f64 / 1e6
+            );
+            let _ = writeln!(
+                out,
+                "bank_command_duration_seconds_count{{command=\"{}\"}} {}",
+                command, stats.count
+            );
+        }
+
+        let gauges = [
+            (
+                "bank_accounts",
+                "Number of accounts.",
+                bank.account_count() as u64,
+            ),
+            (
+                "bank_total_balance",
+                "Sum of all account balances.",
+                bank.total_balance(),
+            ),
+            (
+                "bank_history_operations",
+                "Operations in the history.",
+                bank.history_len() as u64,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(
+                out,
+                "# HELP {} {}\n# TYPE {} gauge\n{} {}",
+                name, help, name, name, value
+            );
+        }
+        out
+    }
+}
+
+/// Nearest-rank percentile of already sorted samples.
+fn percentile(sorted: &[u64], p: usize) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (p * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_and_percentiles() {
+        let mut metrics = Metrics::default();
+        for i in 1..=100 {
+            let error = (i % 10 == 0).then_some("InsufficientFunds");
+            metrics.record("Transfer", Duration::from_micros(i), error);
+        }
+        metrics.record("GetHistory", Duration::from_micros(7), None);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(2, snapshot.len());
+        let transfer = snapshot.iter().find(|m| m.command == "Transfer").unwrap();
+        assert_eq!(100, transfer.count);
+        assert_eq!(10, transfer.errors);
+        assert_eq!(50, transfer.p50_us);
+        assert_eq!(90, transfer.p90_us);
+        assert_eq!(99, transfer.p99_us);
+        assert_eq!(100, transfer.max_us);
+    }
+
+    #[test]
+    fn keeps_last_samples() {
+        let mut metrics = Metrics::default();
+        for _ in 0..LATENCY_SAMPLES {
+            metrics.record("Transfer", Duration::from_micros(1000), None);
+        }
+        for _ in 0..LATENCY_SAMPLES {
+            metrics.record("Transfer", Duration::from_micros(1), None);
+        }
+        let snapshot = metrics.snapshot();
+        assert_eq!(2 * LATENCY_SAMPLES as u64, snapshot[0].count);
+        assert_eq!(1, snapshot[0].max_us);
+    }
+
+    #[test]
+    fn prometheus() {
+        let mut metrics = Metrics::default();
+        metrics.record("Transfer", Duration::from_micros(40), None);
+        metrics.record(
+            "Transfer",
+            Duration::from_micros(700),
+            Some("InsufficientFunds"),
+        );
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.increase_account("X", 15);
+
+        let text = metrics.prometheus(&bank);
+        for line in [
+            "bank_commands_total{command=\"Transfer\",outcome=\"ok\"} 1",
+            "bank_commands_total{command=\"Transfer\",outcome=\"InsufficientFunds\"} 1",
+            "bank_command_duration_seconds_bucket{command=\"Transfer\",le=\"0.00005\"} 1",
+            "bank_command_duration_seconds_bucket{command=\"Transfer\",le=\"0.001\"} 2",
+            "bank_command_duration_seconds_bucket{command=\"Transfer\",le=\"+Inf\"} 2",
+            "bank_command_duration_seconds_sum{command=\"Transfer\"} 0.00074",
+            "bank_accounts 1",
+            "bank_total_balance 15",
+            "bank_history_operations 2",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{} missing in\n{}",
+                line,
+                text
+            );
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+
+use protocol_crate::digest::{chain, to_hex, GENESIS};
+
+use crate::config::{Durability, Settings};
+use crate::encryption::Key;
+#[cfg(feature = "postgres")]
+use crate::postgres_storage::PostgresStorage;
+use crate::sled_storage::SledStorage;
+use crate::snapshots;
+use crate::storage::{self, BankStorage, MemoryStorage};
+
+// Соединений с PostgreSQL хватает двух: перенос идет в одно
+#[cfg(feature = "postgres")]
+const POOL_SIZE: u32 = 2;
+
+/// Where a bank is stored, written as `snapshot:PATH`, `sled:PATH` or
+/// `postgres:URL`. A snapshot file can only be read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+    Snapshot(PathBuf),
+    Sled(PathBuf),
+    Postgres(String),
+}
+
+impl FromStr for Location {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("snapshot", path)) => Ok(Location::Snapshot(path.into())),
+            Some(("sled", path)) => Ok(Location::Sled(path.into())),
+            Some(("postgres", url)) => Ok(Location::Postgres(url.to_string())),
+            _ => Err(format!(
+                "{}: expected snapshot:PATH, sled:PATH or postgres:URL",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::Snapshot(path) => write!(f, "snapshot:{}", path.display()),
+            Location::Sled(path) => write!(f, "sled:{}", path.display()),
+            Location::Postgres(url) => write!(f, "postgres:{}", url),
+        }
+    }
+}
+
+/// What both storages hold after a migration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Migrated {
+    pub accounts: usize,
+    pub total_balance: u64,
+    pub operations: usize,
+    /// Hash chain of the history, as in `GetHistoryDigest`.
+    pub history_digest: String,
+}
+
+/// Copies the accounts, balances and history from `from` to `to`, which
+/// must hold no bank yet, then reopens `to` and checks that it holds the
+/// same balances and the same history. Account metadata, owners and tags
+/// stay in the snapshots, as they do for a running server. With `key`
+/// encrypted snapshots and sled databases are read, and a sled database is
+/// written encrypted.
+pub fn migrate(from: &Location, to: &Location, key: Option<&Key>) -> io::Result<Migrated> {
+    let source = open_existing(from, key)?;
+    let (mut target, new) = open(to, key)?;
+    if !new {
+        return Err(io::Error::other(format!("{} already holds a bank", to)));
+    }
+    storage::copy(&*source, &mut *target);
+    drop(target);
+
+    // Сверяется то, что действительно осталось в хранилище
+    let target = open_existing(to, key)?;
+    verify(&*source, &*target).map_err(|e| io::Error::other(format!("{}: {}", to, e)))
+}
+
+/// Restores a bank lost with its machine from the S3 bucket that the
+/// server config at `config` copies snapshots to: the snapshot `name`, or
+/// the newest one, is downloaded and migrated to `to` as by `migrate`, with
+/// the encryption key of that config.
+pub fn restore_from_s3(config: &Path, name: Option<&str>, to: &Location) -> io::Result<Migrated> {
+    let settings = Settings::load(Some(config.to_path_buf()))
+        .map_err(|e| io::Error::other(format!("{:?}", e)))?;
+    let key = settings.config.encryption.key()?;
+    let s3 = settings.config.snapshots.s3.ok_or_else(|| {
+        io::Error::other(format!("{}: no [snapshots.s3] section", config.display()))
+    })?;
+    let dir = std::env::temp_dir().join(format!("bank-restore-{}", process::id()));
+    let migrated = snapshots::download(&s3, name, &dir)
+        .and_then(|path| migrate(&Location::Snapshot(path), to, key.as_ref()));
+    let _ = fs::remove_dir_all(&dir);
+    migrated
+}
+
+/// Opens the storage at `location`; `true` if it holds no bank yet.
+fn open(location: &Location, key: Option<&Key>) -> io::Result<(Box<dyn BankStorage>, bool)> {
+    match location {
+        Location::Snapshot(path) => {
+            let snapshot = snapshots::load(path, key)?;
+            Ok((Box::new(MemoryStorage::from_snapshot(snapshot)), false))
+        }
+        Location::Sled(path) => {
+            let sled = SledStorage::open(path, Durability::EveryOperation, key.cloned())?;
+            let new = sled.is_new();
+            Ok((Box::new(sled), new))
+        }
+        #[cfg(feature = "postgres")]
+        Location::Postgres(url) => {
+            let postgres = PostgresStorage::open(url, POOL_SIZE, Durability::EveryOperation)
+                .map_err(io::Error::other)?;
+            let new = postgres.is_new();
+            Ok((Box::new(postgres), new))
+        }
+        #[cfg(not(feature = "postgres"))]
+        Location::Postgres(_) => Err(io::Error::other(
+            "the server is built without the postgres feature",
+        )),
+    }
+}
+
+fn open_existing(location: &Location, key: Option<&Key>) -> io::Result<Box<dyn BankStorage>> {
+    match open(location, key)? {
+        (_, true) => Err(io::Error::other(format!("{} holds no bank", location))),
+        (storage, false) => Ok(storage),
+    }
+}
+
+fn verify(source: &dyn BankStorage, target: &dyn BankStorage) -> Result<Migrated, String> {
+    if source.account_count() != target.account_count() {
+        return Err(format!(
+            "{} accounts instead of {}",
+            target.account_count(),
+            source.account_count()
+        ));
+    }
+    let mut total_balance = 0;
+    for id in 0..source.account_count() {
+        let expected = (source.account_name(id), source.balance(id));
+        let found = (target.account_name(id), target.balance(id));
+        if expected != found {
+            return Err(format!(
+                "account {} is {:?} instead of {:?}",
+                id, found, expected
+            ));
+        }
+        total_balance += expected.1 as u64;
+    }
+
+    if source.history_len() != target.history_len() {
+        return Err(format!(
+            "{} operations instead of {}",
+            target.history_len(),
+            source.history_len()
+        ));
+    }
+    // История читается из обоих хранилищ по странице; время сверяется
+    // напрямую, операции - по хеш-цепочке
+    let (mut expected, mut found) = (GENESIS, GENESIS);
+    for start in (0..source.history_len()).step_by(storage::PAGE_SIZE) {
+        let end = start + storage::PAGE_SIZE;
+        let page = source.entries(start, end).into_iter();
+        for (index, (original, copy)) in (start..).zip(page.zip(target.entries(start, end))) {
+            if original.0 != copy.0 {
+                return Err(format!("operation {} has another time", index));
+            }
+            expected = chain(&expected, &original.1);
+            found = chain(&found, &copy.1);
+        }
+    }
+    if expected != found {
+        return Err(format!(
+            "history digest {} instead of {}",
+            to_hex(&found),
+            to_hex(&expected)
+        ));
+    }
+    Ok(Migrated {
+        accounts: source.account_count(),
+        total_balance,
+        operations: source.history_len(),
+        history_digest: to_hex(&expected),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bank::Bank;
+
+    #[test]
+    fn snapshot_to_sled_to_sled() {
+        let dir = std::env::temp_dir().join(format!("migration-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.create_account("X/savings".to_string());
+        let _ = bank.increase_account("X", 10);
+        let _ = bank.transfer("X", "X/savings", 4);
+        let snapshot = bank.snapshot();
+        let file = dir.join("snapshot.json");
+        std::fs::write(&file, serde_json::to_vec(&snapshot).unwrap()).unwrap();
+
+        let first = Location::Sled(dir.join("first"));
+        let second = Location::Sled(dir.join("second"));
+        let migrated = migrate(&Location::Snapshot(file), &first, None).unwrap();
+        assert_eq!(2, migrated.accounts);
+        assert_eq!(10, migrated.total_balance);
+        assert_eq!(4, migrated.operations);
+        assert_eq!(
+            bank.get_history_digest(None).digest,
+            migrated.history_digest
+        );
+        assert_eq!(migrated, migrate(&first, &second, None).unwrap());
+
+        // В заполненное хранилище и из пустого не переносится
+        assert!(migrate(&first, &second, None).is_err());
+        assert!(migrate(&Location::Sled(dir.join("empty")), &second, None).is_err());
+        assert!(migrate(&first, &Location::Snapshot(dir.join("snapshot.json")), None).is_err());
+
+        let (storage, _) = open(&second, None).unwrap();
+        assert_eq!(snapshot, Bank::with_storage(storage).snapshot());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn parses_locations() {
+        assert_eq!(
+            Ok(Location::Postgres("host=db user=bank".to_string())),
+            "postgres:host=db user=bank".parse()
+        );
+        assert_eq!(
+            Ok(Location::Sled(PathBuf::from("/var/bank"))),
+            "sled:/var/bank".parse()
+        );
+        assert!("/var/bank".parse::<Location>().is_err());
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::ops::Index;
+use std::sync::Arc;
+
+use protocol_crate::AccountId;
+
+/// Account names of a bank, each allocated once. The index by name, the
+/// list by ID and the operations kept in memory share the same `Arc<str>`
+/// instead of holding a `String` each.
+#[derive(Debug, Default)]
+pub struct Names {
+    ids: HashMap<Arc<str>, AccountId>,
+    names: Vec<Arc<str>>,
+}
+
+impl Names {
+    /// Adds a name that is not in the table yet; its ID is the number of
+    /// names before it.
+    pub fn push(&mut self, name: &str) -> AccountId {
+        let id = self.names.len();
+        let name: Arc<str> = Arc::from(name);
+        self.ids.insert(Arc::clone(&name), id);
+        self.names.push(name);
+        id
+    }
+
+    /// Gives the account `id` the name `name`, which is not in the table.
+    pub fn rename(&mut self, id: AccountId, name: &str) {
+        let name: Arc<str> = Arc::from(name);
+        self.ids.remove(&self.names[id]);
+        self.ids.insert(Arc::clone(&name), id);
+        self.names[id] = name;
+    }
+
+    pub fn id(&self, name: &str) -> Option<AccountId> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// The shared copy of `name`; a name not in the table gets its own.
+    pub fn intern(&self, name: &str) -> Arc<str> {
+        match self.ids.get_key_value(name) {
+            Some((name, _)) => Arc::clone(name),
+            None => Arc::from(name),
+        }
+    }
+}
+
+impl Index<AccountId> for Names {
+    type Output = str;
+
+    fn index(&self, id: AccountId) -> &str {
+        &self.names[id]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared() {
+        let mut names = Names::default();
+        assert_eq!(0, names.push("X"));
+        assert_eq!(1, names.push("X/savings"));
+        assert_eq!(Some(1), names.id("X/savings"));
+        assert_eq!(None, names.id("Y"));
+        assert_eq!("X", &names[0]);
+        assert_eq!("X/savings", &names[1]);
+
+        // Известное имя не копируется: таблица, список и операции делят одну строку
+        let x = names.intern("X");
+        assert!(Arc::ptr_eq(&x, &names.names[0]));
+        assert_eq!(3, Arc::strong_count(&x));
+        assert_eq!(1, Arc::strong_count(&names.intern("Y")));
+    }
+}
@@ -0,0 +1,561 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+
+use postgres::{GenericClient, NoTls};
+use protocol_crate::digest::Hash;
+use protocol_crate::{AccountId, Operation};
+use r2d2::{Pool, PooledConnection};
+use r2d2_postgres::PostgresConnectionManager;
+use serde_json::Value;
+
+use crate::config::Durability;
+use crate::names::Names;
+use crate::storage::{self, AccountDetails, BankStorage};
+
+type Manager = PostgresConnectionManager<NoTls>;
+
+/// Schema changes in order; the database remembers how many it has applied.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE accounts (
+        id BIGINT PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        balance BIGINT NOT NULL
+    );
+    CREATE TABLE operations (
+        id BIGINT PRIMARY KEY,
+        created_at BIGINT NOT NULL,
+        kind TEXT NOT NULL,
+        operation JSONB NOT NULL
+    );
+    CREATE TABLE bank_state (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );",
+    "CREATE TABLE checkpoints (
+        block BIGINT PRIMARY KEY,
+        hash BYTEA NOT NULL
+    );",
+];
+
+/// Storage in PostgreSQL, so the bank data can be queried with SQL: the
+/// `accounts` table holds the balances and `operations` the history, with
+/// the time, the kind and the operation itself as JSON; `checkpoints` has
+/// the hash of the history after every block. Every operation is
+/// written in one transaction with the balances it changed. Names and
+/// balances are also kept in memory; operations are read back through a
+/// connection pool.
+pub struct PostgresStorage {
+    pool: Pool<Manager>,
+    // Соединение для записи; при переносе в нем открыта транзакция всего переноса
+    writer: PooledConnection<Manager>,
+    names: Names,
+    balances: Vec<u32>,
+    history_len: usize,
+    // Счета и балансы, измененные с последней операции
+    new_accounts: Vec<AccountId>,
+    changed: Vec<AccountId>,
+    // Хеши блоков, записываются вместе со следующей операцией
+    checkpoints: Vec<(i64, Vec<u8>)>,
+    complete: bool,
+}
+
+impl PostgresStorage {
+    /// Connects to the database at `url` with at most `pool_size`
+    /// connections and brings its schema up to date. With `durability`
+    /// `never` commits do not wait for the WAL of PostgreSQL to be on disk;
+    /// the server flushes it in the background on its own schedule, so an
+    /// interval of its own cannot be set here.
+    pub fn open(url: &str, pool_size: u32, durability: Durability) -> Result<Self, String> {
+        if let Durability::EveryMs(_) = durability {
+            return Err(format!(
+                "durability \"{}\" is not supported by the postgres backend, \
+                 set wal_writer_delay of the database and \"never\" instead",
+                durability
+            ));
+        }
+        let config = url.parse().map_err(|e| format!("{}: {}", url, e))?;
+        let pool = Pool::builder()
+            .max_size(pool_size.max(2))
+            .build(PostgresConnectionManager::new(config, NoTls))
+            .map_err(|e| format!("{}: {}", url, e))?;
+        let mut writer = pool.get().map_err(|e| e.to_string())?;
+        if durability == Durability::Never {
+            // Только для соединения записи: чтения ничего не коммитят
+            writer
+                .batch_execute("SET synchronous_commit = off")
+                .map_err(|e| e.to_string())?;
+        }
+        migrate(&mut writer).map_err(|e| format!("Failed to migrate the schema: {}", e))?;
+
+        let complete = writer
+            .query_opt("SELECT value FROM bank_state WHERE key = 'complete'", &[])
+            .map_err(|e| e.to_string())?
+            .is_some();
+        if !complete {
+            // Перенос идет одной транзакцией: прерванный не оставляет следов
+            writer
+                .batch_execute("BEGIN; TRUNCATE accounts, operations, checkpoints;")
+                .map_err(|e| e.to_string())?;
+        }
+
+        let mut names = Names::default();
+        let mut balances = Vec::new();
+        let rows = writer
+            .query("SELECT name, balance FROM accounts ORDER BY id", &[])
+            .map_err(|e| e.to_string())?;
+        for row in rows {
+            names.push(row.get(0));
+            balances.push(row.get::<_, i64>(1) as u32);
+        }
+        let history_len: i64 = writer
+            .query_one("SELECT count(*) FROM operations", &[])
+            .map_err(|e| e.to_string())?
+            .get(0);
+        Ok(PostgresStorage {
+            pool,
+            writer,
+            names,
+            balances,
+            history_len: history_len as usize,
+            new_accounts: Vec::new(),
+            changed: Vec::new(),
+            checkpoints: Vec::new(),
+            complete,
+        })
+    }
+
+    /// Whether the database holds no bank yet. Such a storage is filled by
+    /// [`crate::bank::Bank::set_storage`], e.g. from a snapshot.
+    pub fn is_new(&self) -> bool {
+        !self.complete
+    }
+
+    fn reader(&self) -> PooledConnection<Manager> {
+        self.pool
+            .get()
+            .unwrap_or_else(|e| panic!("Failed to connect to the bank database: {}", e))
+    }
+
+    fn entry(&self, index: usize) -> (u64, Operation) {
+        self.entries(index, index + 1)
+            .pop()
+            .unwrap_or_else(|| panic!("Operation {} is missing from the bank database", index))
+    }
+
+    fn pending(&mut self) -> Entry {
+        self.changed.sort_unstable();
+        self.changed.dedup();
+        Entry {
+            accounts: self
+                .new_accounts
+                .drain(..)
+                .map(|id| (id as i64, self.names[id].to_string()))
+                .collect(),
+            balances: self
+                .changed
+                .drain(..)
+                .map(|id| (id as i64, self.balances[id] as i64))
+                .collect(),
+            checkpoints: mem::take(&mut self.checkpoints),
+            operation: None,
+        }
+    }
+}
+
+/// Accounts and balances changed since the previous operation, written
+/// together with the next one.
+struct Entry {
+    accounts: Vec<(i64, String)>,
+    balances: Vec<(i64, i64)>,
+    checkpoints: Vec<(i64, Vec<u8>)>,
+    // Номер, время и сама операция
+    operation: Option<(i64, i64, Value)>,
+}
+
+impl Entry {
+    fn write(&self, client: &mut impl GenericClient) -> Result<(), postgres::Error> {
+        for (id, name) in &self.accounts {
+            client.execute(
+                "INSERT INTO accounts (id, name, balance) VALUES ($1, $2, 0)",
+                &[id, name],
+            )?;
+        }
+        for (id, balance) in &self.balances {
+            client.execute(
+                "UPDATE accounts SET balance = $2 WHERE id = $1",
+                &[id, balance],
+            )?;
+        }
+        for (block, hash) in &self.checkpoints {
+            client.execute(
+                "INSERT INTO checkpoints (block, hash) VALUES ($1, $2)
+                 ON CONFLICT (block) DO UPDATE SET hash = EXCLUDED.hash",
+                &[block, hash],
+            )?;
+        }
+        if let Some((id, created_at, operation)) = &self.operation {
+            client.execute(
+                "INSERT INTO operations (id, created_at, kind, operation) VALUES ($1, $2, $3, $4)",
+                &[id, created_at, &kind(operation), operation],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for PostgresStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PostgresStorage")
+            .field("accounts", &self.names.len())
+            .field("history_len", &self.history_len)
+            .field("complete", &self.complete)
+            .finish()
+    }
+}
+
+impl BankStorage for PostgresStorage {
+    fn add_account(&mut self, name: &str) -> AccountId {
+        let id = self.names.push(name);
+        self.balances.push(0);
+        self.new_accounts.push(id);
+        id
+    }
+
+    fn account_id(&self, name: &str) -> Option<AccountId> {
+        self.names.id(name)
+    }
+
+    fn account_name(&self, id: AccountId) -> &str {
+        &self.names[id]
+    }
+
+    fn account_count(&self) -> usize {
+        self.names.len()
+    }
+
+    fn balance(&self, id: AccountId) -> u32 {
+        self.balances[id]
+    }
+
+    fn set_balance(&mut self, id: AccountId, balance: u32) {
+        self.balances[id] = balance;
+        self.changed.push(id);
+    }
+
+    fn append(&mut self, operation: Operation, timestamp: u64) {
+        let mut entry = self.pending();
+        entry.operation = Some((
+            self.history_len as i64,
+            timestamp as i64,
+            serde_json::to_value(&operation).unwrap(),
+        ));
+        let written = match self.complete {
+            true => self.writer.transaction().and_then(|mut transaction| {
+                entry.write(&mut transaction)?;
+                transaction.commit()
+            }),
+            // При переносе все идет в уже открытую транзакцию
+            false => entry.write(&mut *self.writer),
+        };
+        written.unwrap_or_else(|e| failed(self.history_len, e));
+        self.history_len += 1;
+    }
+
+    fn history_len(&self) -> usize {
+        self.history_len
+    }
+
+    fn timestamp(&self, index: usize) -> u64 {
+        self.entry(index).0
+    }
+
+    fn range(&self, start: usize, end: usize) -> Vec<Operation> {
+        self.entries(start, end)
+            .into_iter()
+            .map(|(_, operation)| operation)
+            .collect()
+    }
+
+    fn entries(&self, start: usize, end: usize) -> Vec<(u64, Operation)> {
+        let end = end.min(self.history_len);
+        let start = start.min(end);
+        let rows = self
+            .reader()
+            .query(
+                "SELECT created_at, operation FROM operations
+                 WHERE id >= $1 AND id < $2 ORDER BY id",
+                &[&(start as i64), &(end as i64)],
+            )
+            .unwrap_or_else(|e| failed(start, e));
+        rows.iter()
+            .enumerate()
+            .map(|(offset, row)| {
+                let operation = serde_json::from_value(row.get(1))
+                    .unwrap_or_else(|e| panic!("Operation {} is corrupt: {}", start + offset, e));
+                (row.get::<_, i64>(0) as u64, operation)
+            })
+            .collect()
+    }
+
+    fn rename_accounts(&mut self, renames: &HashMap<String, String>) {
+        let mut accounts = Vec::new();
+        for (old, new) in renames {
+            if let Some(id) = self.names.id(old) {
+                // Еще не записанные счета попадут в базу уже с новым именем
+                self.names.rename(id, new);
+                accounts.push((id as i64, new.clone()));
+            }
+        }
+        let mut operations = Vec::new();
+        for (index, (_, mut operation)) in self.entries(0, self.history_len).into_iter().enumerate()
+        {
+            if storage::rename(&mut operation, renames) {
+                operations.push((index as i64, serde_json::to_value(&operation).unwrap()));
+            }
+        }
+        let written = match self.complete {
+            true => self.writer.transaction().and_then(|mut transaction| {
+                rename(&mut transaction, &accounts, &operations)?;
+                transaction.commit()
+            }),
+            false => rename(&mut *self.writer, &accounts, &operations),
+        };
+        written.unwrap_or_else(|e| panic!("Failed to write the bank database: {}", e));
+    }
+
+    fn checkpoints(&self) -> Vec<Hash> {
+        let rows = self
+            .reader()
+            .query("SELECT hash FROM checkpoints ORDER BY block", &[])
+            .unwrap_or_else(|e| panic!("Failed to read the bank database: {}", e));
+        rows.iter()
+            .map(|row| {
+                row.get::<_, &[u8]>(0)
+                    .try_into()
+                    .unwrap_or_else(|_| panic!("A hash in the bank database is corrupt"))
+            })
+            .collect()
+    }
+
+    fn set_checkpoint(&mut self, block: usize, hash: &Hash) {
+        self.checkpoints.push((block as i64, hash.to_vec()));
+    }
+
+    fn details(&self) -> Option<AccountDetails> {
+        let row = self
+            .reader()
+            .query_opt("SELECT value FROM bank_state WHERE key = 'details'", &[])
+            .unwrap_or_else(|e| panic!("Failed to read the bank database: {}", e))?;
+        let details = serde_json::from_str(row.get(0))
+            .unwrap_or_else(|e| panic!("Account details in the bank database are corrupt: {}", e));
+        Some(details)
+    }
+
+    fn set_details(&mut self, details: &AccountDetails) {
+        // При переносе запись идет в уже открытую транзакцию
+        self.writer
+            .execute(
+                "INSERT INTO bank_state (key, value) VALUES ('details', $1)
+                 ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value",
+                &[&serde_json::to_string(details).unwrap()],
+            )
+            .unwrap_or_else(|e| panic!("Failed to write the bank database: {}", e));
+    }
+
+    fn flush(&mut self) {
+        if self.complete {
+            return;
+        }
+        // Счета без операций тоже должны попасть в базу
+        let entry = self.pending();
+        entry
+            .write(&mut *self.writer)
+            .and_then(|()| {
+                self.writer.batch_execute(
+                    "INSERT INTO bank_state (key, value) VALUES ('complete', ''); COMMIT;",
+                )
+            })
+            .unwrap_or_else(|e| panic!("Failed to write the bank database: {}", e));
+        self.complete = true;
+    }
+}
+
+/// Gives the accounts `(id, name)` their new names and replaces the
+/// operations `(id, operation)` that mention them.
+fn rename(
+    client: &mut impl GenericClient,
+    accounts: &[(i64, String)],
+    operations: &[(i64, Value)],
+) -> Result<(), postgres::Error> {
+    for (id, name) in accounts {
+        client.execute("UPDATE accounts SET name = $2 WHERE id = $1", &[id, name])?;
+    }
+    for (id, operation) in operations {
+        client.execute(
+            "UPDATE operations SET operation = $2 WHERE id = $1",
+            &[id, operation],
+        )?;
+    }
+    Ok(())
+}
+
+/// Applies the migrations the database has not seen yet, each in its own
+/// transaction.
+fn migrate(client: &mut postgres::Client) -> Result<(), postgres::Error> {
+    client
+        .batch_execute("CREATE TABLE IF NOT EXISTS schema_migrations (version INT PRIMARY KEY)")?;
+    let applied: i64 = client
+        .query_one("SELECT count(*) FROM schema_migrations", &[])?
+        .get(0);
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied as usize) {
+        let mut transaction = client.transaction()?;
+        transaction.batch_execute(migration)?;
+        transaction.execute(
+            "INSERT INTO schema_migrations (version) VALUES ($1)",
+            &[&(version as i32 + 1)],
+        )?;
+        transaction.commit()?;
+    }
+    Ok(())
+}
+
+// Вид операции - имя ее варианта из поля "type" в JSON
+fn kind(operation: &Value) -> String {
+    operation["type"].as_str().unwrap_or_default().to_string()
+}
+
+// База - единственная копия состояния: без нее продолжать нельзя
+fn failed(index: usize, e: impl fmt::Display) -> ! {
+    panic!(
+        "Failed to access operation {} in the bank database: {}",
+        index, e
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bank::Bank;
+    use protocol_crate::{BankError, RemoteAccount, ReservationKind};
+
+    // Тесты идут только с базой из BANK_TEST_POSTGRES_URL (в виде
+    // "host=... user=..."); каждый работает в своей схеме
+    fn url(schema: &str) -> Option<String> {
+        let url = std::env::var("BANK_TEST_POSTGRES_URL").ok()?;
+        Some(format!("{} options=-csearch_path={}", url, schema))
+    }
+
+    fn open(schema: &str) -> Option<PostgresStorage> {
+        let url = url(schema)?;
+        let mut client = postgres::Client::connect(&url, NoTls).unwrap();
+        client
+            .batch_execute(&format!(
+                "DROP SCHEMA IF EXISTS {0} CASCADE; CREATE SCHEMA {0};",
+                schema
+            ))
+            .unwrap();
+        Some(PostgresStorage::open(&url, 2, Durability::EveryOperation).unwrap())
+    }
+
+    #[test]
+    fn survives_reopen() {
+        let Some(storage) = open("survives_reopen") else {
+            return;
+        };
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.create_account("X/savings".to_string());
+        let _ = bank.increase_account("X", 10);
+        bank.set_storage(Box::new(storage));
+        let _ = bank.transfer("X", "X/savings", 4);
+        let _ = bank.create_account("Y".to_string());
+        let snapshot = bank.snapshot();
+        drop(bank);
+
+        let url = url("survives_reopen").unwrap();
+        let storage = PostgresStorage::open(&url, 2, Durability::EveryOperation).unwrap();
+        assert!(!storage.is_new());
+        let mut bank = Bank::with_storage(Box::new(storage));
+        assert_eq!(snapshot, bank.snapshot());
+        assert!(bank.check_consistency().differences.is_empty());
+        assert!(bank.increase_account("Y", 1).is_ok());
+
+        // Данные доступны обычным SQL
+        let mut client = postgres::Client::connect(&url, NoTls).unwrap();
+        let transfers = client
+            .query_one(
+                "SELECT count(*) FROM operations WHERE kind = 'Transfer'",
+                &[],
+            )
+            .unwrap();
+        assert_eq!(1, transfers.get::<_, i64>(0));
+        let savings = client
+            .query_one("SELECT balance FROM accounts WHERE name = 'X/savings'", &[])
+            .unwrap();
+        assert_eq!(4, savings.get::<_, i64>(0));
+    }
+
+    #[test]
+    fn details_survive_reopen() {
+        let Some(storage) = open("details_survive_reopen") else {
+            return;
+        };
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.create_account("Y".to_string());
+        let _ = bank.increase_account("X", 3);
+        bank.set_account_owners("X", ["alice".to_string()].into())
+            .unwrap();
+        bank.set_storage(Box::new(storage));
+        bank.set_account_tags("X", ["customer".to_string()].into())
+            .unwrap();
+        bank.set_required_approvals("X", 2).unwrap();
+        bank.set_alert_rule("X", Some(5)).unwrap();
+        let remote = RemoteAccount {
+            address: "127.0.0.1:7879".to_string(),
+            account: "Z".into(),
+        };
+        let reservation = bank
+            .reserve("X", 4, ReservationKind::Credit, remote)
+            .unwrap();
+        let pending = bank.queue_transfer("X", "Y", 2).unwrap();
+        let proposal = bank.propose_transfer("X", "Y", 1, Some("alice")).unwrap();
+        let snapshot = bank.snapshot();
+        drop(bank);
+
+        // Снимка нет: все берется из базы
+        let url = url("details_survive_reopen").unwrap();
+        let mut bank = Bank::with_storage(Box::new(
+            PostgresStorage::open(&url, 2, Durability::EveryOperation).unwrap(),
+        ));
+        assert_eq!(snapshot, bank.snapshot());
+        assert_eq!(1, bank.get_account_owners("X").unwrap().len());
+        assert_eq!(vec![("X", 5, 0)], bank.alert_rules());
+        assert_eq!(1, bank.get_available_balance("X").unwrap());
+        assert!(matches!(
+            bank.approve_proposal(proposal.id, Some("alice")),
+            Err(BankError::AlreadyApproved(_))
+        ));
+        bank.reject_transfer(pending).unwrap();
+        bank.commit_reservation(reservation).unwrap();
+        assert_eq!(7, bank.get_account_balance("X").unwrap());
+    }
+
+    #[test]
+    fn interrupted_migration_leaves_nothing() {
+        let Some(mut storage) = open("interrupted_migration") else {
+            return;
+        };
+        assert!(storage.is_new());
+        storage.add_account("X");
+        storage.append(Operation::CreateAccount("X".to_string()), 0);
+        drop(storage);
+
+        let url = url("interrupted_migration").unwrap();
+        let storage = PostgresStorage::open(&url, 2, Durability::EveryOperation).unwrap();
+        assert!(storage.is_new());
+        assert_eq!(0, storage.account_count());
+        assert_eq!(0, storage.history_len());
+    }
+}
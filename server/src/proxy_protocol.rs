@@ -0,0 +1,146 @@
+use std::io::{self, ErrorKind, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+// Начало заголовков: текстового v1 и двоичного v2
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+// Наибольшая длина строки v1 вместе с \r\n
+const V1_MAX_LEN: usize = 107;
+const V2_LOCAL: u8 = 0x20;
+const V2_PROXY: u8 = 0x21;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+/// Reads the PROXY protocol header (version 1 or 2) that a load balancer
+/// sends before the client's data, and nothing past it. Returns the address
+/// of the client the balancer accepted, or `None` if the header does not
+/// carry one, e.g. for the balancer's own health checks.
+pub fn read_header(stream: &mut impl Read) -> io::Result<Option<SocketAddr>> {
+    let mut start = [0; 6];
+    stream.read_exact(&mut start)?;
+    if start == V1_PREFIX {
+        read_v1(stream)
+    } else if start == V2_SIGNATURE[..6] {
+        read_v2(stream)
+    } else {
+        Err(invalid("no PROXY protocol header"))
+    }
+}
+
+fn read_v1(stream: &mut impl Read) -> io::Result<Option<SocketAddr>> {
+    // Строка читается по байту, чтобы не захватить данные клиента
+    let mut line = Vec::new();
+    let mut byte = [0; 1];
+    while !line.ends_with(b"\r\n") {
+        if V1_PREFIX.len() + line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY header line too long"));
+        }
+        stream.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY header is not text"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["UNKNOWN", ..] => Ok(None),
+        [family @ ("TCP4" | "TCP6"), source, _, port, _] => {
+            let ip: IpAddr = source.parse().map_err(|_| invalid("bad source address"))?;
+            let port: u16 = port.parse().map_err(|_| invalid("bad source port"))?;
+            if ip.is_ipv4() != (family == "TCP4") {
+                return Err(invalid("source address of another family"));
+            }
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY header")),
+    }
+}
+
+fn read_v2(stream: &mut impl Read) -> io::Result<Option<SocketAddr>> {
+    let mut header = [0; 10];
+    stream.read_exact(&mut header)?;
+    if header[..6] != V2_SIGNATURE[6..] {
+        return Err(invalid("no PROXY protocol header"));
+    }
+    let (command, family) = (header[6], header[7]);
+    let len = u16::from_be_bytes([header[8], header[9]]) as usize;
+    // Адреса и дополнительные поля читаются целиком, даже если не нужны
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body)?;
+    match (command, family) {
+        (V2_LOCAL, _) => Ok(None),
+        (V2_PROXY, V2_TCP4) if len >= 12 => {
+            let ip: [u8; 4] = body[..4].try_into().unwrap();
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+        }
+        (V2_PROXY, V2_TCP6) if len >= 36 => {
+            let ip: [u8; 16] = body[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        // Другие семейства (UDP, UNIX) клиенту банка не встречаются
+        (V2_PROXY, _) => Ok(None),
+        _ => Err(invalid("unsupported PROXY protocol version or command")),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn read(data: &[u8]) -> (io::Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut cursor = Cursor::new(data);
+        let header = read_header(&mut cursor);
+        let rest = data[cursor.position() as usize..].to_vec();
+        (header, rest)
+    }
+
+    #[test]
+    fn version_1() {
+        let (header, rest) = read(b"PROXY TCP4 203.0.113.7 10.0.0.1 4321 7878\r\n{\"x\":1}");
+        assert_eq!("203.0.113.7:4321", header.unwrap().unwrap().to_string());
+        assert_eq!(b"{\"x\":1}".to_vec(), rest);
+        let (header, _) = read(b"PROXY TCP6 2001:db8::1 ::1 4321 7878\r\n");
+        assert_eq!("[2001:db8::1]:4321", header.unwrap().unwrap().to_string());
+        assert_eq!(None, read(b"PROXY UNKNOWN\r\n").0.unwrap());
+
+        assert!(read(b"PROXY TCP4 2001:db8::1 ::1 4321 7878\r\n").0.is_err());
+        assert!(read(b"PROXY TCP4 203.0.113.7\r\n").0.is_err());
+        assert!(read(&[b"PROXY ".as_slice(), &[b'x'; 200]].concat())
+            .0
+            .is_err());
+        assert!(read(b"{\"GetHistory\":null}").0.is_err());
+    }
+
+    #[test]
+    fn version_2() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[V2_PROXY, V2_TCP4, 0, 15]);
+        data.extend_from_slice(&[203, 0, 113, 7, 10, 0, 0, 1, 0x10, 0xe1, 0x1e, 0xc6]);
+        // Дополнительное поле TLV после адресов пропускается
+        data.extend_from_slice(&[0x04, 0, 0]);
+        data.extend_from_slice(b"rest");
+        let (header, rest) = read(&data);
+        assert_eq!("203.0.113.7:4321", header.unwrap().unwrap().to_string());
+        assert_eq!(b"rest".to_vec(), rest);
+
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[V2_PROXY, V2_TCP6, 0, 36]);
+        data.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        data.extend_from_slice(&[0; 16]);
+        data.extend_from_slice(&[0x10, 0xe1, 0x1e, 0xc6]);
+        let (header, _) = read(&data);
+        assert_eq!("[2001:db8::1]:4321", header.unwrap().unwrap().to_string());
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[V2_LOCAL, 0, 0, 0]);
+        assert_eq!(None, read(&local).0.unwrap());
+        local[12] = 0x31;
+        assert!(read(&local).0.is_err());
+    }
+}
@@ -0,0 +1,564 @@
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+use std::str::FromStr;
+
+use protocol_crate::{Operation, QueryRow, ReservationKind};
+
+use crate::scheduler::{civil_from_days, days_from_civil};
+
+const DAY: u64 = 24 * 60 * 60;
+// Виды операций, как их называет и экспорт истории в CSV
+const KINDS: [&str; 10] = [
+    "create",
+    "increase",
+    "decrease",
+    "transfer",
+    "remote_out",
+    "remote_in",
+    "transaction",
+    "set_limits",
+    "close",
+    "anonymize",
+];
+
+/// Query over the bank history, evaluated on the server:
+///
+/// ```text
+/// count | sum | avg | min | max
+///     [where CONDITION [and CONDITION ...]]
+///     [group by kind | from | to | account | day | month]
+/// ```
+///
+/// A condition is `FIELD OP VALUE`. The fields are `kind` (as in the CSV
+/// export: `transfer`, `increase`, ...), `from` and `to` (the money source
+/// and destination), `account` (either of them), `amount` and `time` (unix
+/// seconds or `YYYY-MM-DD`, UTC). Text fields take `=` and `!=`, numbers also
+/// `<`, `<=`, `>` and `>=`. A value with spaces is written in double quotes.
+/// `sum`, `avg`, `min` and `max` are over the amounts; an operation with no
+/// value of the group key, such as a deposit grouped by `from`, is left out.
+///
+/// "Sum of transfers from Alice per day" is
+/// `sum where kind = transfer and from = Alice group by day`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    aggregate: Aggregate,
+    conditions: Vec<Condition>,
+    group: Option<Key>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Aggregate {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Condition {
+    field: Field,
+    op: Op,
+    value: Value,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Kind,
+    From,
+    To,
+    Account,
+    Amount,
+    Time,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    Text(String),
+    Number(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Kind,
+    From,
+    To,
+    Account,
+    Day,
+    Month,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    // Значение в кавычках никогда не считается ключевым словом
+    Quoted(String),
+    Op(Op),
+}
+
+/// What a query sees of one operation.
+struct Fields {
+    kind: &'static str,
+    from: Option<String>,
+    to: Option<String>,
+    amount: u64,
+    time: u64,
+}
+
+impl Query {
+    /// Account that every matching operation touches, if a condition pins
+    /// one down; only its operations need to be looked at.
+    pub fn account(&self) -> Option<&str> {
+        self.conditions
+            .iter()
+            .find_map(|condition| match condition {
+                Condition {
+                    field: Field::From | Field::To | Field::Account,
+                    op: Op::Eq,
+                    value: Value::Text(name),
+                } => Some(name.as_str()),
+                _ => None,
+            })
+    }
+
+    /// Evaluates the query over operations with their timestamps. Rows come
+    /// ordered by group, which for days and months is the time order.
+    pub fn run(&self, operations: impl Iterator<Item = (u64, Operation)>) -> Vec<QueryRow> {
+        // Количество, сумма, минимум и максимум по каждой группе
+        let mut groups: BTreeMap<Option<String>, (u64, u64, u64, u64)> = BTreeMap::new();
+        if self.group.is_none() {
+            groups.insert(None, (0, 0, u64::MAX, 0));
+        }
+        for (time, operation) in operations {
+            let fields = Fields::of(&operation, time);
+            if !self
+                .conditions
+                .iter()
+                .all(|condition| condition.matches(&fields))
+            {
+                continue;
+            }
+            let keys = match self.group {
+                Some(key) => key.values(&fields).into_iter().map(Some).collect(),
+                None => vec![None],
+            };
+            for key in keys {
+                let group = groups.entry(key).or_insert((0, 0, u64::MAX, 0));
+                group.0 += 1;
+                group.1 += fields.amount;
+                group.2 = group.2.min(fields.amount);
+                group.3 = group.3.max(fields.amount);
+            }
+        }
+        groups
+            .into_iter()
+            .map(|(group, (count, sum, min, max))| QueryRow {
+                group,
+                count,
+                value: match self.aggregate {
+                    _ if count == 0 => 0,
+                    Aggregate::Count => count,
+                    Aggregate::Sum => sum,
+                    Aggregate::Avg => sum / count,
+                    Aggregate::Min => min,
+                    Aggregate::Max => max,
+                },
+            })
+            .collect()
+    }
+}
+
+impl FromStr for Query {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = tokenize(s)?.into_iter().peekable();
+        let aggregate = match word(tokens.next()).as_deref() {
+            Some("count") => Aggregate::Count,
+            Some("sum") => Aggregate::Sum,
+            Some("avg") => Aggregate::Avg,
+            Some("min") => Aggregate::Min,
+            Some("max") => Aggregate::Max,
+            _ => return Err("expected count, sum, avg, min or max".to_string()),
+        };
+        // "sum amount" читается так же, как "sum"
+        if aggregate != Aggregate::Count {
+            take(&mut tokens, "amount");
+        }
+
+        let mut conditions = Vec::new();
+        if take(&mut tokens, "where") {
+            loop {
+                conditions.push(Condition::parse(&mut tokens)?);
+                if !take(&mut tokens, "and") {
+                    break;
+                }
+            }
+        }
+
+        let mut group = None;
+        if take(&mut tokens, "group") {
+            if !take(&mut tokens, "by") {
+                return Err("expected by after group".to_string());
+            }
+            group = Some(match word(tokens.next()).as_deref() {
+                Some("kind") => Key::Kind,
+                Some("from") => Key::From,
+                Some("to") => Key::To,
+                Some("account") => Key::Account,
+                Some("day") => Key::Day,
+                Some("month") => Key::Month,
+                _ => return Err("expected kind, from, to, account, day or month".to_string()),
+            });
+        }
+        match tokens.next() {
+            None => Ok(Query {
+                aggregate,
+                conditions,
+                group,
+            }),
+            Some(token) => Err(format!("unexpected {}", describe(&token))),
+        }
+    }
+}
+
+impl Condition {
+    fn parse(tokens: &mut impl Iterator<Item = Token>) -> Result<Self, String> {
+        let field = match word(tokens.next()).as_deref() {
+            Some("kind") => Field::Kind,
+            Some("from") => Field::From,
+            Some("to") => Field::To,
+            Some("account") => Field::Account,
+            Some("amount") => Field::Amount,
+            Some("time") => Field::Time,
+            _ => return Err("expected kind, from, to, account, amount or time".to_string()),
+        };
+        let Some(Token::Op(op)) = tokens.next() else {
+            return Err("expected =, !=, <, <=, > or >=".to_string());
+        };
+        let text = match tokens.next() {
+            Some(Token::Word(text) | Token::Quoted(text)) => text,
+            _ => return Err("expected a value".to_string()),
+        };
+        let value = match field {
+            Field::Amount => Value::Number(
+                text.parse()
+                    .map_err(|_| format!("{}: expected an amount", text))?,
+            ),
+            Field::Time => Value::Number(parse_time(&text)?),
+            _ if !matches!(op, Op::Eq | Op::Ne) => {
+                return Err(format!("{}: only = and != compare text", text))
+            }
+            Field::Kind if !KINDS.contains(&text.as_str()) => {
+                return Err(format!("{}: expected one of {}", text, KINDS.join(", ")))
+            }
+            _ => Value::Text(text),
+        };
+        Ok(Condition { field, op, value })
+    }
+
+    fn matches(&self, fields: &Fields) -> bool {
+        match (&self.value, self.field) {
+            (Value::Number(value), Field::Amount) => self.op.compare(fields.amount, *value),
+            (Value::Number(value), _) => self.op.compare(fields.time, *value),
+            (Value::Text(value), field) => {
+                let found = match field {
+                    Field::Kind => fields.kind == value,
+                    Field::From => fields.from.as_ref() == Some(value),
+                    Field::To => fields.to.as_ref() == Some(value),
+                    _ => fields.from.as_ref() == Some(value) || fields.to.as_ref() == Some(value),
+                };
+                found == (self.op == Op::Eq)
+            }
+        }
+    }
+}
+
+impl Op {
+    fn compare(self, left: u64, right: u64) -> bool {
+        match self {
+            Op::Eq => left == right,
+            Op::Ne => left != right,
+            Op::Lt => left < right,
+            Op::Le => left <= right,
+            Op::Gt => left > right,
+            Op::Ge => left >= right,
+        }
+    }
+}
+
+impl Key {
+    fn values(self, fields: &Fields) -> Vec<String> {
+        match self {
+            Key::Kind => vec![fields.kind.to_string()],
+            Key::From => fields.from.iter().cloned().collect(),
+            Key::To => fields.to.iter().cloned().collect(),
+            // Перевод между счетами попадает в группы обоих
+            Key::Account => {
+                let mut accounts: Vec<String> =
+                    fields.from.iter().chain(&fields.to).cloned().collect();
+                accounts.dedup();
+                accounts
+            }
+            Key::Day => {
+                let (year, month, day) = civil_from_days(fields.time / DAY);
+                vec![format!("{:04}-{:02}-{:02}", year, month, day)]
+            }
+            Key::Month => {
+                let (year, month, _) = civil_from_days(fields.time / DAY);
+                vec![format!("{:04}-{:02}", year, month)]
+            }
+        }
+    }
+}
+
+impl Fields {
+    fn of(operation: &Operation, time: u64) -> Self {
+        let (kind, from, to) = sides(operation);
+        Fields {
+            kind,
+            from,
+            to,
+            amount: operation.amount() as u64,
+            time,
+        }
+    }
+}
+
+/// Kind of the operation, as the CSV export names it, with the accounts
+/// the money comes from and goes to.
+pub(crate) fn sides(operation: &Operation) -> (&'static str, Option<String>, Option<String>) {
+    match operation {
+        Operation::CreateAccount(account) => ("create", None, Some(account.clone())),
+        Operation::IncreaseAccount(account, _) => ("increase", None, Some(account.clone())),
+        Operation::DecreaseAccount(account, _) => ("decrease", Some(account.clone()), None),
+        Operation::Transfer(from, to, _) => ("transfer", Some(from.clone()), Some(to.clone())),
+        Operation::RemoteTransferOut { from, to, .. } => {
+            ("remote_out", Some(from.clone()), Some(to.to_string()))
+        }
+        Operation::RemoteTransferIn { from, to, .. } => {
+            ("remote_in", Some(from.to_string()), Some(to.clone()))
+        }
+        Operation::TransactionLeg {
+            account,
+            kind: ReservationKind::Debit,
+            ..
+        } => ("transaction", Some(account.clone()), None),
+        Operation::TransactionLeg {
+            account,
+            kind: ReservationKind::Credit,
+            ..
+        } => ("transaction", None, Some(account.clone())),
+        Operation::SetLimits { account, .. } => ("set_limits", None, Some(account.clone())),
+        Operation::CloseAccount(account) => ("close", None, Some(account.clone())),
+        Operation::AnonymizeAccount { pseudonym, .. } => {
+            ("anonymize", None, Some(pseudonym.clone()))
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => text.push(c),
+                    None => return Err("unterminated quote".to_string()),
+                }
+            }
+            tokens.push(Token::Quoted(text));
+        } else if "=!<>".contains(c) {
+            chars.next();
+            let equals = chars.next_if_eq(&'=').is_some();
+            tokens.push(Token::Op(match (c, equals) {
+                ('=', _) => Op::Eq,
+                ('!', true) => Op::Ne,
+                ('<', false) => Op::Lt,
+                ('<', true) => Op::Le,
+                ('>', false) => Op::Gt,
+                ('>', true) => Op::Ge,
+                _ => return Err("expected != after !".to_string()),
+            }));
+        } else {
+            let mut text = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !"\"=!<>".contains(*c)) {
+                text.push(c);
+            }
+            tokens.push(Token::Word(text));
+        }
+    }
+    Ok(tokens)
+}
+
+// Ключевые слова и имена полей не зависят от регистра, имена счетов - да
+fn word(token: Option<Token>) -> Option<String> {
+    match token {
+        Some(Token::Word(word)) => Some(word.to_lowercase()),
+        _ => None,
+    }
+}
+
+/// Takes the next token if it is `keyword`.
+fn take(tokens: &mut Peekable<impl Iterator<Item = Token>>, keyword: &str) -> bool {
+    let found = matches!(
+        tokens.peek(),
+        Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword)
+    );
+    if found {
+        tokens.next();
+    }
+    found
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Word(text) => text.clone(),
+        Token::Quoted(text) => format!("\"{}\"", text),
+        Token::Op(op) => format!("{:?}", op),
+    }
+}
+
+/// Unix seconds, or the start of a `YYYY-MM-DD` day in UTC.
+fn parse_time(text: &str) -> Result<u64, String> {
+    if let Ok(seconds) = text.parse() {
+        return Ok(seconds);
+    }
+    let bad = || format!("{}: expected unix seconds or YYYY-MM-DD", text);
+    let parts: Vec<u64> = text
+        .split('-')
+        .map(|part| part.parse().map_err(|_| bad()))
+        .collect::<Result<_, _>>()?;
+    match parts[..] {
+        [year, month, day]
+            if year >= 1970 && (1..=12).contains(&month) && (1..=31).contains(&day) =>
+        {
+            Ok(days_from_civil(year, month, day) * DAY)
+        }
+        _ => Err(bad()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(query: &str, operations: &[(u64, Operation)]) -> Vec<(Option<String>, u64, u64)> {
+        query
+            .parse::<Query>()
+            .unwrap()
+            .run(operations.iter().cloned())
+            .into_iter()
+            .map(|row| (row.group, row.count, row.value))
+            .collect()
+    }
+
+    #[test]
+    fn queries() {
+        let day = days_from_civil(2026, 3, 1) * DAY;
+        let operations = [
+            (day, Operation::CreateAccount("Alice".to_string())),
+            (day, Operation::IncreaseAccount("Alice".to_string(), 100)),
+            (
+                day + 10,
+                Operation::Transfer("Alice".to_string(), "Bob".to_string(), 10),
+            ),
+            (
+                day + 20,
+                Operation::Transfer("Alice".to_string(), "Bob".to_string(), 20),
+            ),
+            (
+                day + DAY,
+                Operation::Transfer("Alice".to_string(), "Carol".to_string(), 5),
+            ),
+            (
+                day + DAY,
+                Operation::Transfer("Bob".to_string(), "Alice".to_string(), 7),
+            ),
+        ];
+
+        assert_eq!(
+            vec![
+                (Some("2026-03-01".to_string()), 2, 30),
+                (Some("2026-03-02".to_string()), 1, 5),
+            ],
+            run(
+                "sum where kind = transfer and from = Alice group by day",
+                &operations
+            )
+        );
+        assert_eq!(vec![(None, 6, 6)], run("count", &operations));
+        assert_eq!(
+            vec![(None, 3, 12)],
+            run("AVG amount WHERE account = Bob", &operations)
+        );
+        assert_eq!(
+            vec![
+                (Some("Alice".to_string()), 3, 20),
+                (Some("Bob".to_string()), 1, 7),
+            ],
+            run(
+                "max where amount>=5 and amount < 100 group by from",
+                &operations
+            )
+        );
+        assert_eq!(
+            vec![
+                (Some("Alice".to_string()), 6, 142),
+                (Some("Bob".to_string()), 3, 37),
+                (Some("Carol".to_string()), 1, 5),
+            ],
+            run("sum group by account", &operations)
+        );
+        assert_eq!(
+            vec![(None, 1, 7)],
+            run("sum where time >= 2026-03-02 and to != Carol", &operations)
+        );
+        // Без совпадений строка одна, с нулями
+        assert_eq!(
+            vec![(None, 0, 0)],
+            run("min where from = Dave", &operations)
+        );
+        assert!(run("sum where from = Dave group by day", &operations).is_empty());
+
+        let query: Query = "count where kind = transfer and to = \"Bob Smith\""
+            .parse()
+            .unwrap();
+        assert_eq!(Some("Bob Smith"), query.account());
+    }
+
+    #[test]
+    fn invalid_queries() {
+        for query in [
+            "",
+            "total",
+            "sum where",
+            "sum where kind = payment",
+            "sum where from > Alice",
+            "sum where amount = lots",
+            "sum where time >= 2026-13-01",
+            "sum group day",
+            "sum group by week",
+            "count where from = \"Alice",
+            "count from = Alice",
+        ] {
+            assert!(query.parse::<Query>().is_err(), "{}", query);
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use protocol_crate::{AccountRef, BankError, BatchOperation, Command, RateLimit};
+
+use crate::clock::{Clock, SystemClock};
+use crate::config::RateLimitConfig;
+
+// Сколько корзин держать, прежде чем забыть уже наполнившиеся
+const MAX_BUCKETS: usize = 10_000;
+
+/// Token buckets of the accounts requests go to, so that a client sending
+/// requests to one account as fast as it can does not take the whole
+/// server. Buckets start full and refill on the clock the limiter is made
+/// with.
+#[derive(Debug)]
+pub struct RateLimiter {
+    buckets: HashMap<String, Bucket>,
+    clock: Arc<dyn Clock>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    // Миллисекунды по часам ограничителя
+    updated: u64,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: RateLimit, now: u64) {
+        let elapsed = now.saturating_sub(self.updated) as f64 / 1000.0;
+        let tokens = self.tokens + elapsed * limit.per_second as f64;
+        self.tokens = tokens.min(limit.burst as f64);
+        self.updated = now;
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new(Arc::new(SystemClock))
+    }
+}
+
+impl RateLimiter {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        RateLimiter {
+            buckets: HashMap::new(),
+            clock,
+        }
+    }
+
+    /// Takes a token from the bucket of each of `accounts` that has a limit
+    /// in `config`. If one of the buckets is empty, no token is taken and
+    /// the request is rejected.
+    pub fn check(&mut self, config: &RateLimitConfig, accounts: &[&str]) -> Result<(), BankError> {
+        let now = self.clock.now_millis();
+        let mut limited: Vec<(&str, RateLimit)> = accounts
+            .iter()
+            .filter_map(|account| config.limit(account).map(|limit| (*account, limit)))
+            .collect();
+        // Перевод со счета на него же - один запрос к счету
+        limited.sort_by_key(|(account, _)| *account);
+        limited.dedup_by_key(|(account, _)| *account);
+
+        for (account, limit) in &limited {
+            let bucket = self.buckets.entry(account.to_string()).or_insert(Bucket {
+                tokens: limit.burst as f64,
+                updated: now,
+            });
+            bucket.refill(*limit, now);
+            if bucket.tokens < 1.0 {
+                let missing = 1.0 - bucket.tokens;
+                return Err(BankError::RateLimited {
+                    account: account.to_string(),
+                    retry_after_ms: match limit.per_second {
+                        0 => u64::MAX,
+                        per_second => (missing * 1000.0 / per_second as f64).ceil() as u64,
+                    },
+                });
+            }
+        }
+        for (account, _) in &limited {
+            self.buckets.get_mut(*account).unwrap().tokens -= 1.0;
+        }
+
+        if self.buckets.len() > MAX_BUCKETS {
+            // Полная корзина ничем не отличается от новой
+            self.buckets
+                .retain(|account, bucket| match config.limit(account) {
+                    Some(limit) => {
+                        bucket.refill(limit, now);
+                        bucket.tokens < limit.burst as f64
+                    }
+                    None => false,
+                });
+        }
+        Ok(())
+    }
+
+    /// Moves the buckets of renamed accounts to their new names, so that a
+    /// rename does not refill them.
+    pub fn rename(&mut self, renames: &HashMap<String, String>) {
+        for (old, new) in renames {
+            if let Some(bucket) = self.buckets.remove(old) {
+                self.buckets.insert(new.clone(), bucket);
+            }
+        }
+    }
+}
+
+/// Local accounts a client `command` reads or changes. Commands of other
+/// servers finishing a transfer and admin commands are not limited.
+pub fn accounts<'a>(command: &'a Command, address: &str) -> Vec<&'a AccountRef> {
+    match command {
+        Command::IncreaseAccount(account, _)
+        | Command::DecreaseAccount(account, _)
+        | Command::DecreaseIfBalanceAtLeast { account, .. }
+        | Command::GetAccountBalance(account)
+        | Command::GetBalanceAt { account, .. }
+        | Command::GetVersionedBalance(account)
+        | Command::GetAccountHistoryPaged { account, .. }
+        | Command::GetProjectedHistory {
+            account: Some(account),
+            ..
+        }
+        | Command::RemoteTransfer { from: account, .. }
+        | Command::GetStatement { account, .. }
+        | Command::GetAccountLimits(account)
+        | Command::SetAccountMetadata { account, .. }
+        | Command::SetAlertRule { account, .. }
+        | Command::GetAccountMetadata(account)
+        | Command::SetAccountOwners { account, .. }
+        | Command::GetAccountOwners(account)
+        | Command::GetSubtreeBalance(account)
+        | Command::GetAccountTags(account)
+        | Command::LockAccount { account, .. }
+        | Command::CloseAccount(account) => vec![account],
+        Command::Transfer { from, to, .. }
+        | Command::TransferIf { from, to, .. }
+        | Command::ProposeTransfer { from, to, .. } => {
+            vec![from, to]
+        }
+        Command::Transaction(legs) => legs
+            .iter()
+            .filter(|leg| leg.account.address == address)
+            .map(|leg| &leg.account.account)
+            .collect(),
+        Command::Batch(operations) => operations
+            .iter()
+            .flat_map(|operation| match operation {
+                BatchOperation::Deposit { account, .. }
+                | BatchOperation::Withdraw { account, .. } => vec![account],
+                BatchOperation::Transfer { from, to, .. } => vec![from, to],
+            })
+            .collect(),
+        Command::BulkTransfer { from, payees } => std::iter::once(from)
+            .chain(payees.iter().map(|(payee, _)| payee))
+            .collect(),
+        Command::LockAccounts { accounts, .. } => accounts.iter().collect(),
+        #[allow(deprecated)]
+        Command::GetAccountHistory(account) => vec![account],
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::TestClock;
+
+    fn config() -> RateLimitConfig {
+        RateLimitConfig {
+            default: Some(RateLimit {
+                per_second: 10,
+                burst: 2,
+            }),
+            accounts: [(
+                "vip".to_string(),
+                RateLimit {
+                    per_second: 1000,
+                    burst: 100,
+                },
+            )]
+            .into(),
+        }
+    }
+
+    fn limiter() -> (RateLimiter, TestClock) {
+        let clock = TestClock::at(1_000);
+        (RateLimiter::new(Arc::new(clock.clone())), clock)
+    }
+
+    #[test]
+    fn token_bucket() {
+        let (config, (mut limiter, clock)) = (config(), limiter());
+        assert!(limiter.check(&config, &["X"]).is_ok());
+        assert!(limiter.check(&config, &["X", "X"]).is_ok());
+        assert!(matches!(
+            limiter.check(&config, &["X"]),
+            Err(BankError::RateLimited { account, retry_after_ms: 100 }) if account == "X"
+        ));
+        // Другие счета это не задевает, а корзина со временем наполняется
+        assert!(limiter.check(&config, &["Y"]).is_ok());
+        for _ in 0..100 {
+            assert!(limiter.check(&config, &["vip"]).is_ok());
+        }
+        clock.advance_millis(40);
+        assert!(matches!(
+            limiter.check(&config, &["X"]),
+            Err(BankError::RateLimited {
+                retry_after_ms: 60,
+                ..
+            })
+        ));
+        clock.advance_millis(60);
+        assert!(limiter.check(&config, &["X"]).is_ok());
+        assert!(limiter.check(&config, &["X"]).is_err());
+
+        // Больше burst корзина не наполняется
+        clock.advance(60);
+        assert!(limiter.check(&config, &["X"]).is_ok());
+        assert!(limiter.check(&config, &["X"]).is_ok());
+        assert!(limiter.check(&config, &["X"]).is_err());
+    }
+
+    #[test]
+    fn rejected_request_takes_nothing() {
+        let (config, (mut limiter, _clock)) = (config(), limiter());
+        limiter.check(&config, &["X", "Y"]).unwrap();
+        limiter.check(&config, &["X"]).unwrap();
+        assert!(limiter.check(&config, &["Y", "X"]).is_err());
+        assert!(limiter.check(&config, &["Y"]).is_ok());
+
+        let unlimited = RateLimitConfig::default();
+        for _ in 0..10 {
+            assert!(limiter.check(&unlimited, &["X"]).is_ok());
+        }
+    }
+
+    #[test]
+    fn renamed_account() {
+        let (config, (mut limiter, _clock)) = (config(), limiter());
+        limiter.check(&config, &["X"]).unwrap();
+        limiter.check(&config, &["X"]).unwrap();
+        limiter.rename(&[("X".to_string(), "Z".to_string())].into());
+        // Переименование не наполняет корзину
+        assert!(limiter.check(&config, &["Z"]).is_err());
+        assert!(limiter.check(&config, &["X"]).is_ok());
+    }
+}
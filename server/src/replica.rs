@@ -0,0 +1,110 @@
+use std::time::{Duration, Instant};
+
+use protocol_crate::{BankError, PageRequest};
+
+use crate::bank::Bank;
+use crate::federation::RemoteBank;
+
+// Сколько операций догоняющего хвоста запрашивать за раз; архив дешево
+// разбирать, так что страницы крупные
+const PAGE_SIZE: usize = 10_000;
+
+/// Read-only copy of the bank running at the primary. Before serving a
+/// request it pulls the new operations of the primary, at most once per
+/// `sync_interval`, so its answers are at most that much behind.
+pub struct Replica {
+    primary: String,
+    remote: RemoteBank,
+    sync_interval: Duration,
+    last_sync: Instant,
+}
+
+impl Replica {
+    pub fn new(primary: &str, sync_interval: Duration) -> Self {
+        Replica {
+            primary: primary.to_string(),
+            remote: RemoteBank::new(primary),
+            sync_interval,
+            last_sync: Instant::now(),
+        }
+    }
+
+    pub fn primary(&self) -> &str {
+        &self.primary
+    }
+
+    /// Builds a copy of the primary bank: downloads its snapshot in chunks,
+    /// then replays only the operations that happened after the snapshot was
+    /// taken.
+    pub fn bootstrap(&mut self) -> Result<Bank, BankError> {
+        let mut bank = Bank::from_snapshot(self.remote.download_snapshot()?);
+        println!(
+            "Loaded snapshot of {} at operation {}",
+            self.primary,
+            bank.history_len()
+        );
+        self.catch_up(&mut bank)?;
+        println!(
+            "Caught up with {} at operation {}",
+            self.primary,
+            bank.history_len()
+        );
+        Ok(bank)
+    }
+
+    /// Pulls the new operations of the primary unless the last sync is more
+    /// recent than the sync interval. A failed sync leaves the data as it was.
+    pub fn sync(&mut self, bank: &mut Bank) {
+        if self.last_sync.elapsed() < self.sync_interval {
+            return;
+        }
+        if let Err(e) = self.catch_up(bank) {
+            eprintln!("Failed to sync with {}: {:?}", self.primary, e);
+        }
+    }
+
+    /// Makes sure the bank holds at least `position` operations, pulling
+    /// the new operations of the primary right away if it does not. Fails
+    /// with `NotPrimary`, redirecting to the primary, if they still are not
+    /// here, e.g. because the primary is unreachable.
+    pub fn reach(&mut self, bank: &mut Bank, position: usize) -> Result<(), BankError> {
+        if bank.history_len() < position {
+            if let Err(e) = self.catch_up(bank) {
+                eprintln!("Failed to sync with {}: {:?}", self.primary, e);
+            }
+        }
+        if bank.history_len() < position {
+            return Err(BankError::NotPrimary(self.primary.clone()));
+        }
+        Ok(())
+    }
+
+    fn catch_up(&mut self, bank: &mut Bank) -> Result<(), BankError> {
+        let mut page = PageRequest::at(bank.history_len(), PAGE_SIZE);
+        loop {
+            let got = self.remote.history_archive(page)?;
+            bank.restore(&got.items)?;
+            match got.next_cursor {
+                Some(cursor) => {
+                    page = PageRequest {
+                        cursor: Some(cursor),
+                        limit: PAGE_SIZE,
+                    }
+                }
+                None => break,
+            }
+        }
+        let operations = bank.history_len();
+
+        // Совпадение хешей означает, что история скопирована без расхождений
+        let expected = self.remote.history_digest(operations)?;
+        if expected != bank.get_history_digest(Some(operations)) {
+            return Err(BankError::InvalidHistory {
+                index: operations,
+                reason: format!("history diverged from {}", self.primary),
+            });
+        }
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+}
@@ -0,0 +1,442 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use banklib::ShardMap;
+use protocol_crate::codec::{Serializer, WireFormat};
+use protocol_crate::pipeline::PIPELINE_MARKER;
+use protocol_crate::{
+    AccountRef, BankError, Command, CommandMetrics, Cursor, PageRequest, RemoteAccount,
+    ReservationKind, Response, ResponsePayload, ServerInfo, TransactionLeg, MAX_COMMAND_SIZE,
+    PROTOCOL_VERSION,
+};
+
+use crate::federation::RemoteBank;
+use crate::rate_limit;
+
+// Сколько ждать команду от клиента
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Fronts several bank servers, the shards, placing accounts on them by a
+/// [`ShardMap`], which clients can fetch to reach the shards directly.
+///
+/// Commands on accounts of one shard go to that shard; a transfer between
+/// shards runs as a two-phase commit transaction coordinated by the shard
+/// of the debited account. Listings and metrics are gathered from every
+/// shard; paged listings go through the shards one after another. Commands
+/// that would need the shards' own state, like account IDs or snapshots,
+/// fail with `NotRoutable`.
+pub struct Router {
+    // Адрес, на котором маршрутизатор принимает соединения
+    address: String,
+    map: ShardMap,
+}
+
+impl Router {
+    /// Router over the shards at `shards`, given as each shard sees its own
+    /// address: a transfer between shards names them by it.
+    pub fn new(shards: Vec<String>) -> Self {
+        Router {
+            address: String::new(),
+            map: ShardMap::new(shards),
+        }
+    }
+
+    /// Address of the shard that owns `account`.
+    pub fn shard(&self, account: &str) -> &str {
+        self.map.shard(account)
+    }
+
+    /// Accepts connections until the listener fails; each one is served by
+    /// a thread of its own.
+    pub fn serve(mut self, listener: TcpListener) {
+        if let Ok(address) = listener.local_addr() {
+            self.address = address.to_string();
+        }
+        let router = Arc::new(self);
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let router = Arc::clone(&router);
+                    thread::spawn(move || router.connection(stream));
+                }
+                Err(e) => eprintln!("Failed to establish a connection: {}", e),
+            }
+        }
+    }
+
+    /// Executes `command` on the shards it concerns and merges their answers.
+    pub fn execute(&self, command: Command, client: Option<SocketAddr>) -> Response {
+        match innermost(&command) {
+            // Рукопожатие относится к самому маршрутизатору
+            Command::Handshake => Ok(ResponsePayload::ServerInfo(ServerInfo {
+                protocol_version: PROTOCOL_VERSION,
+                address: self.address.clone(),
+                client_address: client.map(|client| client.to_string()),
+                encodings: WireFormat::supported()
+                    .iter()
+                    .map(|format| format.name().to_string())
+                    .collect(),
+            })),
+            Command::GetShardMap => Ok(ResponsePayload::ShardMap(self.map.shards().to_vec())),
+            Command::CreateAccount(name) => self.send(self.map.shard(name), command),
+            Command::Transfer { from, to, amount } => {
+                let (from, to, amount) = (from.clone(), to.clone(), *amount);
+                let (from_shard, to_shard) = (self.route(&from)?, self.route(&to)?);
+                if from_shard == to_shard {
+                    return self.send(from_shard, command);
+                }
+                let leg = |shard: &str, account: AccountRef, kind| TransactionLeg {
+                    account: RemoteAccount {
+                        address: shard.to_string(),
+                        account,
+                    },
+                    kind,
+                    amount,
+                };
+                let legs = vec![
+                    leg(from_shard, from, ReservationKind::Debit),
+                    leg(to_shard, to, ReservationKind::Credit),
+                ];
+                match peel(self.send(from_shard, rewrap(&command, Command::Transaction(legs)))?) {
+                    ResponsePayload::Transaction(_) => Ok(ResponsePayload::Done),
+                    payload => Err(BankError::UnexpectedResponse(format!("{:?}", payload))),
+                }
+            }
+            // Устаревшие списки без страниц остаются для прежних клиентов
+            #[allow(deprecated)]
+            Command::GetHistory => {
+                let histories = self.gather(&command, |payload| match payload {
+                    ResponsePayload::History(operations) => Some(operations),
+                    _ => None,
+                })?;
+                Ok(ResponsePayload::History(histories.concat()))
+            }
+            #[allow(deprecated)]
+            Command::ListAccounts(_) => {
+                let lists = self.gather(&command, |payload| match payload {
+                    ResponsePayload::Accounts(accounts) => Some(accounts),
+                    _ => None,
+                })?;
+                Ok(ResponsePayload::Accounts(lists.concat()))
+            }
+            Command::GetHistoryPaged { page } => {
+                self.paged(&command, page, |page| Command::GetHistoryPaged { page })
+            }
+            Command::ListAccountsPaged { filter, page } => {
+                let filter = *filter;
+                self.paged(&command, page, |page| Command::ListAccountsPaged {
+                    filter,
+                    page,
+                })
+            }
+            Command::FindAccounts { .. } => {
+                let lists = self.gather(&command, |payload| match payload {
+                    ResponsePayload::AccountsByBalance(accounts) => Some(accounts),
+                    _ => None,
+                })?;
+                Ok(ResponsePayload::AccountsByBalance(lists.concat()))
+            }
+            Command::FindAccountsByTag(_) => {
+                let lists = self.gather(&command, |payload| match payload {
+                    ResponsePayload::AccountsByTag(accounts) => Some(accounts),
+                    _ => None,
+                })?;
+                Ok(ResponsePayload::AccountsByTag(lists.concat()))
+            }
+            Command::GetTopAccounts(n) => {
+                let n = *n;
+                let lists = self.gather(&command, |payload| match payload {
+                    ResponsePayload::TopAccounts(accounts) => Some(accounts),
+                    _ => None,
+                })?;
+                let mut accounts = lists.concat();
+                accounts.sort_by_key(|(_, balance)| std::cmp::Reverse(*balance));
+                accounts.truncate(n);
+                Ok(ResponsePayload::TopAccounts(accounts))
+            }
+            Command::GetMetrics { .. } => {
+                let snapshots = self.gather(&command, |payload| match payload {
+                    ResponsePayload::Metrics(metrics) => Some(metrics),
+                    _ => None,
+                })?;
+                Ok(ResponsePayload::Metrics(merge_metrics(snapshots)))
+            }
+            inner => {
+                let accounts = rate_limit::accounts(inner, "");
+                let Some((first, rest)) = accounts.split_first() else {
+                    return Err(BankError::NotRoutable(format!(
+                        "{} is not routed to shards",
+                        inner.name()
+                    )));
+                };
+                let shard = self.route(first)?;
+                for account in rest {
+                    if self.route(account)? != shard {
+                        return Err(BankError::NotRoutable(format!(
+                            "{} touches accounts of several shards",
+                            inner.name()
+                        )));
+                    }
+                }
+                self.send(shard, command)
+            }
+        }
+    }
+
+    fn connection(&self, mut stream: TcpStream) {
+        let client = stream.peer_addr().ok();
+        if let Err(e) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+            eprintln!("Failed to set read timeout: {}", e);
+        }
+        // На байт больше наибольшей команды, чтобы заметить превышение
+        let mut buffer = vec![0; MAX_COMMAND_SIZE + 1];
+        let received = match stream.read(&mut buffer) {
+            Ok(n) => &buffer[..n],
+            Err(e) => {
+                eprintln!("Failed to read command: {}", e);
+                return;
+            }
+        };
+        let (format, response) = if received.first() == Some(&PIPELINE_MARKER) {
+            let error = BankError::NotRoutable("pipelined connections are not routed".to_string());
+            (WireFormat::default(), Err(error))
+        } else if received.len() > MAX_COMMAND_SIZE {
+            let error = BankError::MessageTooLarge {
+                size: received.len(),
+                limit: MAX_COMMAND_SIZE,
+            };
+            (WireFormat::default(), Err(error))
+        } else {
+            match WireFormat::detect(received) {
+                Ok((format, data)) => (
+                    format,
+                    format
+                        .decode(data)
+                        .and_then(|command| self.execute(command, client)),
+                ),
+                Err(e) => (WireFormat::default(), Err(e)),
+            }
+        };
+        // Клиент мог уже закрыть соединение
+        if let Err(e) = stream.write_all(&format.encode(&response)) {
+            eprintln!("Failed to write to stream: {}", e);
+        }
+    }
+
+    fn route(&self, account: &AccountRef) -> Result<&str, BankError> {
+        match account {
+            AccountRef::Name(name) => Ok(self.map.shard(name)),
+            AccountRef::Id(id) => Err(BankError::NotRoutable(format!(
+                "account {} is given by ID, which is only unique within a shard",
+                id
+            ))),
+        }
+    }
+
+    fn send(&self, shard: &str, command: Command) -> Response {
+        RemoteBank::new(shard).send_command(command)
+    }
+
+    /// Page of a listing over all shards, taken from one shard after another.
+    /// A cursor of the router is the index of the shard the listing goes on
+    /// at and the cursor within that shard, if any, e.g. `1/20`.
+    fn paged(
+        &self,
+        command: &Command,
+        page: &PageRequest,
+        with_page: impl Fn(PageRequest) -> Command,
+    ) -> Response {
+        let invalid = |cursor: &Cursor| BankError::InvalidCursor(String::from(cursor.clone()));
+        let (index, cursor) = match &page.cursor {
+            None => (0, None),
+            Some(cursor) => {
+                let text = String::from(cursor.clone());
+                let (index, inner) = match text.split_once('/') {
+                    Some((index, inner)) => (index, Some(Cursor::from(inner.to_string()))),
+                    None => (text.as_str(), None),
+                };
+                (index.parse().map_err(|_| invalid(cursor))?, inner)
+            }
+        };
+        let shards = self.map.shards();
+        let shard = match shards.get(index) {
+            Some(shard) => shard,
+            None => return Err(invalid(page.cursor.as_ref().unwrap())),
+        };
+        let request = PageRequest {
+            cursor,
+            limit: page.limit,
+        };
+        let mut payload = peel(self.send(shard, rewrap(command, with_page(request)))?);
+        let next_cursor = match &mut payload {
+            ResponsePayload::HistoryPage(page) => &mut page.next_cursor,
+            ResponsePayload::AccountsPage(page) => &mut page.next_cursor,
+            _ => {
+                return Err(BankError::UnexpectedResponse(format!(
+                    "{}: unexpected answer to {}",
+                    shard,
+                    command.name()
+                )))
+            }
+        };
+        // Страница кончается вместе со списком шарда, следующая начинает
+        // список следующего шарда
+        *next_cursor = match next_cursor.take() {
+            Some(cursor) => Some(Cursor::from(format!("{}/{}", index, String::from(cursor)))),
+            None if index + 1 < shards.len() => Some(Cursor::from((index + 1).to_string())),
+            None => None,
+        };
+        Ok(payload)
+    }
+
+    /// Sends `command` to every shard at once and picks the part of each
+    /// answer `pick` takes; any other answer fails the whole command.
+    fn gather<T: Send>(
+        &self,
+        command: &Command,
+        pick: impl Fn(ResponsePayload) -> Option<T> + Sync,
+    ) -> Result<Vec<T>, BankError> {
+        thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .map
+                .shards()
+                .iter()
+                .map(|shard| {
+                    let pick = &pick;
+                    scope.spawn(move || {
+                        let payload = peel(self.send(shard, command.clone())?);
+                        pick(payload).ok_or_else(|| {
+                            BankError::UnexpectedResponse(format!(
+                                "{}: unexpected answer to {}",
+                                shard,
+                                command.name()
+                            ))
+                        })
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("shard request panicked"))
+                .collect()
+        })
+    }
+}
+
+/// The command inside the wrappers; it decides where the command goes.
+fn innermost(command: &Command) -> &Command {
+    match command {
+        Command::AsIdentity { command, .. }
+        | Command::WithRequestId { command, .. }
+        | Command::WithDeadline { command, .. }
+        | Command::WithPosition { command, .. }
+        | Command::WithLock { command, .. } => innermost(command),
+        command => command,
+    }
+}
+
+/// `command` wrapped the same way as `wrapped`, so the shard checks the
+/// same identity and deadline.
+fn rewrap(wrapped: &Command, command: Command) -> Command {
+    match wrapped {
+        Command::AsIdentity {
+            token,
+            command: inner,
+        } => Command::AsIdentity {
+            token: token.clone(),
+            command: Box::new(rewrap(inner, command)),
+        },
+        Command::WithRequestId {
+            request_id,
+            command: inner,
+        } => Command::WithRequestId {
+            request_id: request_id.clone(),
+            command: Box::new(rewrap(inner, command)),
+        },
+        Command::WithDeadline {
+            timeout_ms,
+            command: inner,
+        } => Command::WithDeadline {
+            timeout_ms: *timeout_ms,
+            command: Box::new(rewrap(inner, command)),
+        },
+        Command::WithPosition {
+            min_position,
+            command: inner,
+        } => Command::WithPosition {
+            min_position: *min_position,
+            command: Box::new(rewrap(inner, command)),
+        },
+        Command::WithLock {
+            lock,
+            command: inner,
+        } => Command::WithLock {
+            lock: *lock,
+            command: Box::new(rewrap(inner, command)),
+        },
+        _ => command,
+    }
+}
+
+/// Payload without the history position of one shard, which means nothing
+/// once answers of several shards are merged.
+fn peel(payload: ResponsePayload) -> ResponsePayload {
+    match payload {
+        ResponsePayload::Positioned { payload, .. } => peel(*payload),
+        payload => payload,
+    }
+}
+
+/// Request counts of all shards added up; the latencies are those of the
+/// slowest shard.
+fn merge_metrics(snapshots: Vec<Vec<CommandMetrics>>) -> Vec<CommandMetrics> {
+    let mut merged: BTreeMap<String, CommandMetrics> = BTreeMap::new();
+    for metrics in snapshots.into_iter().flatten() {
+        match merged.get_mut(&metrics.command) {
+            Some(total) => {
+                total.count += metrics.count;
+                total.errors += metrics.errors;
+                total.p50_us = total.p50_us.max(metrics.p50_us);
+                total.p90_us = total.p90_us.max(metrics.p90_us);
+                total.p99_us = total.p99_us.max(metrics.p99_us);
+                total.max_us = total.max_us.max(metrics.max_us);
+            }
+            None => {
+                merged.insert(metrics.command.clone(), metrics);
+            }
+        }
+    }
+    merged.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consistent_hashing() {
+        let shards = |n: usize| (0..n).map(|i| format!("10.0.0.{}:7878", i)).collect();
+        let router = Router::new(shards(3));
+        let names: Vec<String> = (0..300).map(|i| format!("account-{}", i)).collect();
+        // Счета делятся между всеми шардами, подсчета живут с родителем
+        for shard in router.map.shards() {
+            let owned = names
+                .iter()
+                .filter(|name| router.shard(name) == shard)
+                .count();
+            assert!(owned > 50, "{} owns {} accounts", shard, owned);
+        }
+        assert_eq!(router.shard("account-7"), router.shard("account-7/savings"));
+
+        // С новым шардом счета переезжают только на него
+        let grown = Router::new(shards(4));
+        for name in &names {
+            let shard = grown.shard(name);
+            assert!(shard == router.shard(name) || shard == "10.0.0.3:7878");
+        }
+    }
+}
@@ -0,0 +1,379 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use protocol_crate::JobInfo;
+
+use crate::config::JobConfig;
+
+const MINUTE: u64 = 60;
+const HOUR: u64 = 60 * MINUTE;
+const DAY: u64 = 24 * HOUR;
+// Дальше этого ближайший запуск не ищется: выражение вроде "0 0 30 2 *" не сработает никогда
+const MAX_DAYS_AHEAD: u64 = 8 * 366;
+
+/// When a job runs: every so many seconds (`every 90s`, `every 15m`,
+/// `every 6h`, `every 1d`), or at the minutes a five-field cron expression
+/// matches, in UTC.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Schedule {
+    Every(u64),
+    Cron(Cron),
+}
+
+impl Schedule {
+    /// First time after `time` (unix seconds) the job is due, `None` if never.
+    pub fn next_after(&self, time: u64) -> Option<u64> {
+        match self {
+            Schedule::Every(secs) => Some(time + secs),
+            Schedule::Cron(cron) => cron.next_after(time),
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(every) = s.strip_prefix("every ") else {
+            return s.parse().map(Schedule::Cron);
+        };
+        let every = every.trim();
+        let split = every.len().saturating_sub(1);
+        let unit = match &every[split..] {
+            "s" => 1,
+            "m" => MINUTE,
+            "h" => HOUR,
+            "d" => DAY,
+            _ => return Err(format!("{}: expected a unit of s, m, h or d", s)),
+        };
+        match every[..split].parse::<u64>() {
+            Ok(count) if count > 0 => Ok(Schedule::Every(count * unit)),
+            _ => Err(format!("{}: expected a positive number", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Schedule::Every(secs) => {
+                let (count, unit) = [(DAY, "d"), (HOUR, "h"), (MINUTE, "m")]
+                    .into_iter()
+                    .find(|(unit, _)| secs % unit == 0)
+                    .map_or((*secs, "s"), |(unit, name)| (secs / unit, name));
+                write!(f, "every {}{}", count, unit)
+            }
+            Schedule::Cron(cron) => f.write_str(&cron.text),
+        }
+    }
+}
+
+/// Cron expression: minute, hour, day of month, month and day of week
+/// (0 or 7 is Sunday), each `*`, a number, a range `a-b` or a list of them,
+/// optionally with a step `/n`. As in cron, when both the day of month and
+/// the day of week are restricted, a day matching either is taken.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    text: String,
+    // Биты разрешенных значений каждого поля
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for Cron {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("{}: expected five cron fields", s));
+        };
+        let field =
+            |text: &str, min, max| parse_field(text, min, max).map_err(|e| format!("{}: {}", s, e));
+        let mut cron = Cron {
+            text: s.to_string(),
+            minutes: field(minutes, 0, 59)?,
+            hours: field(hours, 0, 23)?,
+            days: field(days, 1, 31)?,
+            months: field(months, 1, 12)?,
+            weekdays: field(weekdays, 0, 7)?,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        };
+        // Воскресенье можно записать и как 7
+        if cron.weekdays & 1 << 7 != 0 {
+            cron.weekdays |= 1;
+        }
+        if cron.next_after(0).is_none() {
+            return Err(format!("{}: never matches", s));
+        }
+        Ok(cron)
+    }
+}
+
+impl Cron {
+    fn next_after(&self, time: u64) -> Option<u64> {
+        // Ищется следующая минута; неподходящие дни и часы пропускаются целиком
+        let mut time = (time / MINUTE + 1) * MINUTE;
+        let last = time + MAX_DAYS_AHEAD * DAY;
+        while time < last {
+            let days = time / DAY;
+            let (_, month, day) = civil_from_days(days);
+            // 1 января 1970 года - четверг
+            let weekday = (days + 4) % 7;
+            if !self.matches_day(month, day, weekday) {
+                time = (days + 1) * DAY;
+            } else if self.hours & 1 << (time % DAY / HOUR) == 0 {
+                time = (time / HOUR + 1) * HOUR;
+            } else if self.minutes & 1 << (time % HOUR / MINUTE) == 0 {
+                time += MINUTE;
+            } else {
+                return Some(time);
+            }
+        }
+        None
+    }
+
+    fn matches_day(&self, month: u64, day: u64, weekday: u64) -> bool {
+        if self.months & 1 << month == 0 {
+            return false;
+        }
+        let by_day = self.days & 1 << day != 0;
+        let by_weekday = self.weekdays & 1 << weekday != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => by_day || by_weekday,
+            _ => by_day && by_weekday,
+        }
+    }
+}
+
+fn parse_field(text: &str, min: u64, max: u64) -> Result<u64, String> {
+    let mut bits = 0;
+    for item in text.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse().map_err(|_| bad(item))?),
+            None => (item, 1),
+        };
+        let (from, to) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((from, to)) => (number(from, min, max)?, number(to, min, max)?),
+                // Шаг от одного числа идет до конца поля, как "5/15"
+                None if item.contains('/') => (number(range, min, max)?, max),
+                None => (number(range, min, max)?, number(range, min, max)?),
+            },
+        };
+        if from > to || step == 0 {
+            return Err(bad(item));
+        }
+        for value in (from..=to).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+fn number(text: &str, min: u64, max: u64) -> Result<u64, String> {
+    match text.parse() {
+        Ok(value) if (min..=max).contains(&value) => Ok(value),
+        _ => Err(format!("{} is not within {}-{}", text, min, max)),
+    }
+}
+
+fn bad(item: &str) -> String {
+    format!("bad field item {}", item)
+}
+
+/// Year, month and day of the day `days` after 1970-01-01.
+pub(crate) fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // Алгоритм Говарда Хиннанта: годы считаются с марта, эры по 400 лет
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Days from 1970-01-01 to the date, the inverse of [`civil_from_days`]; the
+/// year is 1970 or later.
+pub(crate) fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = year - u64::from(month <= 2);
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// When each job of the config runs next and how its last run went. Jobs
+/// are known by name: a job whose schedule changed is planned anew, a job
+/// no longer in the config is forgotten. Runs missed while the server was
+/// busy or down are not made up.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    states: HashMap<String, JobState>,
+}
+
+#[derive(Debug)]
+struct JobState {
+    schedule: Schedule,
+    next_run: Option<u64>,
+    last_run: Option<u64>,
+    last_error: Option<String>,
+}
+
+impl Scheduler {
+    /// Jobs of `jobs` that are due at `now`; each is planned for its next run.
+    pub fn due<'a>(&mut self, jobs: &'a [JobConfig], now: u64) -> Vec<&'a JobConfig> {
+        self.sync(jobs, now);
+        jobs.iter()
+            .filter(|job| {
+                let state = self.states.get_mut(&job.name).unwrap();
+                if state.next_run.is_none_or(|next| next > now) {
+                    return false;
+                }
+                state.next_run = job.schedule.next_after(now);
+                state.last_run = Some(now);
+                true
+            })
+            .collect()
+    }
+
+    /// Records how the last run of the job `name` ended.
+    pub fn finished(&mut self, name: &str, error: Option<String>) {
+        if let Some(state) = self.states.get_mut(name) {
+            state.last_error = error;
+        }
+    }
+
+    /// The jobs of `jobs` with their next and last runs.
+    pub fn list(&mut self, jobs: &[JobConfig], now: u64) -> Vec<JobInfo> {
+        self.sync(jobs, now);
+        jobs.iter()
+            .map(|job| {
+                let state = &self.states[&job.name];
+                JobInfo {
+                    name: job.name.clone(),
+                    schedule: job.schedule.to_string(),
+                    task: job.task.to_string(),
+                    next_run: state.next_run,
+                    last_run: state.last_run,
+                    last_error: state.last_error.clone(),
+                }
+            })
+            .collect()
+    }
+
+    fn sync(&mut self, jobs: &[JobConfig], now: u64) {
+        self.states
+            .retain(|name, _| jobs.iter().any(|job| job.name == *name));
+        for job in jobs {
+            let planned = self.states.get(&job.name);
+            if planned.is_none_or(|state| state.schedule != job.schedule) {
+                let state = JobState {
+                    schedule: job.schedule.clone(),
+                    next_run: job.schedule.next_after(now),
+                    last_run: None,
+                    last_error: None,
+                };
+                self.states.insert(job.name.clone(), state);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Task;
+
+    // 2024-02-28 23:59:30 UTC, среда
+    const NOW: u64 = 1_709_164_770;
+
+    fn next(schedule: &str, time: u64) -> Option<u64> {
+        schedule.parse::<Schedule>().unwrap().next_after(time)
+    }
+
+    #[test]
+    fn schedules() {
+        assert_eq!((2024, 2, 28), civil_from_days(NOW / DAY));
+        assert_eq!(NOW / DAY, days_from_civil(2024, 2, 28));
+        assert_eq!(Some(NOW + 90), next("every 90s", NOW));
+        assert_eq!(Some(NOW + 6 * HOUR), next("every 6h", NOW));
+        assert_eq!(
+            "every 6h",
+            "every 360m".parse::<Schedule>().unwrap().to_string()
+        );
+        // Каждую минуту, полночь 29 февраля, ближайшее воскресенье в 3:00
+        assert_eq!(Some(NOW + 30), next("* * * * *", NOW));
+        assert_eq!(Some(NOW + 30), next("0 0 29 2 *", NOW));
+        assert_eq!(Some(NOW + 30 + 3 * DAY + 3 * HOUR), next("0 3 * * 7", NOW));
+        assert_eq!(Some(NOW + 30 + 15 * MINUTE), next("*/15 * * * *", NOW + 60));
+        // День месяца или день недели: 1-е число или ближайший понедельник
+        assert_eq!(Some(NOW + 30 + DAY + 9 * HOUR), next("0 9 1 * 1", NOW));
+        assert_eq!(Some(NOW + 30 + DAY * 4 + 9 * HOUR), next("0 9 * * 1", NOW));
+
+        for bad in [
+            "every 0s",
+            "every 5w",
+            "* * *",
+            "60 * * * *",
+            "0 0 30 2 *",
+            "5-1 * * * *",
+        ] {
+            assert!(bad.parse::<Schedule>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn plans_runs() {
+        let job = |name: &str, schedule: &str| JobConfig {
+            name: name.to_string(),
+            schedule: schedule.parse().unwrap(),
+            task: Task::Snapshot,
+        };
+        let jobs = vec![job("often", "every 10s"), job("daily", "0 0 * * *")];
+        let mut scheduler = Scheduler::default();
+        assert!(scheduler.due(&jobs, NOW).is_empty());
+        let due = scheduler.due(&jobs, NOW + 30);
+        assert_eq!(
+            vec!["often", "daily"],
+            due.iter().map(|j| &j.name).collect::<Vec<_>>()
+        );
+        scheduler.finished("daily", Some("failed".to_string()));
+
+        let listed = scheduler.list(&jobs, NOW + 30);
+        assert_eq!(Some(NOW + 40), listed[0].next_run);
+        assert_eq!(Some(NOW + 30 + DAY), listed[1].next_run);
+        assert_eq!(Some("failed".to_string()), listed[1].last_error);
+
+        // Новое расписание отсчитывается заново, удаленная задача забывается
+        let jobs = vec![job("often", "every 1m")];
+        assert!(scheduler.due(&jobs, NOW + 40).is_empty());
+        assert_eq!(1, scheduler.list(&jobs, NOW + 40).len());
+        assert_eq!(1, scheduler.due(&jobs, NOW + 100).len());
+    }
+}
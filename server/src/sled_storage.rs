@@ -0,0 +1,464 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use fs2::FileExt;
+
+use protocol_crate::digest::Hash;
+use protocol_crate::{AccountId, Operation};
+
+use crate::config::Durability;
+use crate::encryption::{self, Key};
+use crate::names::Names;
+use crate::storage::{self, AccountDetails, BankStorage};
+
+// Вид записи - первый байт ключа, за ним номер в big-endian, чтобы записи
+// одного вида шли в базе по порядку
+const ACCOUNT: u8 = b'a';
+const BALANCE: u8 = b'b';
+const OPERATION: u8 = b'o';
+// Хеш цепочки после блока истории
+const CHECKPOINT: u8 = b'h';
+// Ставится, когда в базу перенесено все состояние банка
+const COMPLETE: &[u8] = b"complete";
+// Данные счетов вне истории, одной записью
+const DETAILS: &[u8] = b"details";
+
+/// Storage in an embedded sled database. Every operation is written in one
+/// atomic batch with the accounts and balances it changed, so after a crash
+/// the database holds the state after some operation, never half of one.
+/// Names and balances are also kept in memory; operations are read back
+/// from the database on demand. With a key the values are encrypted; the
+/// keys hold only the kind and the number of a record.
+#[derive(Debug)]
+pub struct SledStorage {
+    db: sled::Db,
+    names: Names,
+    balances: Vec<u32>,
+    history_len: usize,
+    // Изменения с последней операции, записываются вместе со следующей
+    pending: sled::Batch,
+    // Сбрасывать базу на диск после каждой операции
+    sync: bool,
+    key: Option<Key>,
+    complete: bool,
+    // Объявлено после db, чтобы освобождаться уже после нее
+    _closed: Closed,
+}
+
+// Файл базы sled закрывает из своих фоновых потоков уже после того, как
+// отпущен sled::Db. Ждем этого, чтобы закрытую базу можно было сразу открыть
+// снова, в том числе из этого же процесса: блокировка та же, что берет sled
+#[derive(Debug)]
+struct Closed(PathBuf);
+
+impl Drop for Closed {
+    fn drop(&mut self) {
+        if let Ok(file) = File::open(&self.0) {
+            let _ = file.lock_exclusive().and_then(|()| file.unlock());
+        }
+    }
+}
+
+impl SledStorage {
+    /// Opens the database at `path`, creating it if needed, and writes it to
+    /// disk as often as `durability` says, encrypted with `key` if there is
+    /// one.
+    pub fn open(path: &Path, durability: Durability, key: Option<Key>) -> io::Result<Self> {
+        let flush_every_ms = match durability {
+            Durability::EveryMs(ms) => Some(ms),
+            Durability::EveryOperation | Durability::Never => None,
+        };
+        let config = sled::Config::new()
+            .path(path)
+            .flush_every_ms(flush_every_ms);
+        let db = config.open()?;
+        let complete = db.contains_key(COMPLETE)?;
+        if !complete {
+            // Перенос прервался: начинаем его заново с пустой базы
+            db.clear()?;
+        }
+
+        let mut names = Names::default();
+        for entry in db.scan_prefix([ACCOUNT]) {
+            let (_, name) = entry?;
+            let name = encryption::open(key.as_ref(), name.to_vec())?;
+            names.push(&String::from_utf8_lossy(&name));
+        }
+        let mut balances = vec![0; names.len()];
+        for entry in db.scan_prefix([BALANCE]) {
+            let (id, balance) = entry?;
+            let balance = encryption::open(key.as_ref(), balance.to_vec())?;
+            balances[index(&id)] = u32::from_be_bytes(balance.as_slice().try_into().unwrap());
+        }
+        let history_len = match db.scan_prefix([OPERATION]).next_back() {
+            Some(entry) => index(&entry?.0) + 1,
+            None => 0,
+        };
+        Ok(SledStorage {
+            db,
+            names,
+            balances,
+            history_len,
+            pending: sled::Batch::default(),
+            sync: durability == Durability::EveryOperation,
+            key,
+            complete,
+            _closed: Closed(path.join("db")),
+        })
+    }
+
+    /// Whether the database holds no bank yet. Such a storage is filled by
+    /// [`crate::bank::Bank::set_storage`], e.g. from a snapshot.
+    pub fn is_new(&self) -> bool {
+        !self.complete
+    }
+
+    fn entry(&self, index: usize) -> (u64, Operation) {
+        let read = || -> io::Result<(u64, Operation)> {
+            let value = self
+                .db
+                .get(key(OPERATION, index))?
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such operation"))?;
+            self.decode(&value)
+        };
+        read().unwrap_or_else(|e| failed(index, e))
+    }
+
+    // Значение для записи в базу, зашифрованное, если есть ключ
+    fn seal(&self, value: &[u8]) -> Vec<u8> {
+        encryption::seal(self.key.as_ref(), value.to_vec())
+    }
+
+    fn encode(&self, timestamp: u64, operation: Operation) -> Vec<u8> {
+        self.seal(&serde_json::to_vec(&(timestamp, operation)).unwrap())
+    }
+
+    fn decode(&self, value: &[u8]) -> io::Result<(u64, Operation)> {
+        let value = encryption::open(self.key.as_ref(), value.to_vec())?;
+        serde_json::from_slice(&value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl BankStorage for SledStorage {
+    fn add_account(&mut self, name: &str) -> AccountId {
+        let id = self.names.push(name);
+        self.balances.push(0);
+        self.pending
+            .insert(&key(ACCOUNT, id), self.seal(name.as_bytes()));
+        self.pending
+            .insert(&key(BALANCE, id), self.seal(&0u32.to_be_bytes()));
+        id
+    }
+
+    fn account_id(&self, name: &str) -> Option<AccountId> {
+        self.names.id(name)
+    }
+
+    fn account_name(&self, id: AccountId) -> &str {
+        &self.names[id]
+    }
+
+    fn account_count(&self) -> usize {
+        self.names.len()
+    }
+
+    fn balance(&self, id: AccountId) -> u32 {
+        self.balances[id]
+    }
+
+    fn set_balance(&mut self, id: AccountId, balance: u32) {
+        self.balances[id] = balance;
+        self.pending
+            .insert(&key(BALANCE, id), self.seal(&balance.to_be_bytes()));
+    }
+
+    fn append(&mut self, operation: Operation, timestamp: u64) {
+        let value = self.encode(timestamp, operation);
+        self.pending
+            .insert(&key(OPERATION, self.history_len), value);
+        let index = self.history_len;
+        let written = self.db.apply_batch(mem::take(&mut self.pending));
+        // При переносе база сбрасывается один раз в конце
+        let flushed = written.and_then(|()| match self.sync && self.complete {
+            true => self.db.flush().map(|_| ()),
+            false => Ok(()),
+        });
+        flushed.unwrap_or_else(|e| failed(index, e.into()));
+        self.history_len += 1;
+    }
+
+    fn history_len(&self) -> usize {
+        self.history_len
+    }
+
+    fn timestamp(&self, index: usize) -> u64 {
+        self.entry(index).0
+    }
+
+    fn range(&self, start: usize, end: usize) -> Vec<Operation> {
+        let end = end.min(self.history_len);
+        let start = start.min(end);
+        self.db
+            .range(key(OPERATION, start)..key(OPERATION, end))
+            .enumerate()
+            .map(|(offset, entry)| {
+                let value = entry.map(|(_, value)| value);
+                let operation = value.map_err(io::Error::from).and_then(|v| self.decode(&v));
+                operation.unwrap_or_else(|e| failed(start + offset, e)).1
+            })
+            .collect()
+    }
+
+    fn rename_accounts(&mut self, renames: &HashMap<String, String>) {
+        // Имена и операции меняются одним пакетом вместе с еще не записанным
+        for (old, new) in renames {
+            if let Some(id) = self.names.id(old) {
+                self.names.rename(id, new);
+                let name = self.seal(new.as_bytes());
+                self.pending.insert(&key(ACCOUNT, id), name);
+            }
+        }
+        for (index, entry) in self.db.scan_prefix([OPERATION]).enumerate() {
+            let (key, value) = entry.unwrap_or_else(|e| failed(index, e.into()));
+            let (timestamp, mut operation) =
+                self.decode(&value).unwrap_or_else(|e| failed(index, e));
+            if storage::rename(&mut operation, renames) {
+                let value = self.encode(timestamp, operation);
+                self.pending.insert(key, value);
+            }
+        }
+        self.db
+            .apply_batch(mem::take(&mut self.pending))
+            .and_then(|()| self.db.flush().map(|_| ()))
+            .unwrap_or_else(|e| panic!("Failed to write the bank database: {}", e));
+    }
+
+    fn checkpoints(&self) -> Vec<Hash> {
+        self.db
+            .scan_prefix([CHECKPOINT])
+            .map(|entry| {
+                let (_, hash) =
+                    entry.unwrap_or_else(|e| panic!("Failed to read the bank database: {}", e));
+                hash.as_ref()
+                    .try_into()
+                    .unwrap_or_else(|_| panic!("A hash in the bank database is corrupt"))
+            })
+            .collect()
+    }
+
+    fn set_checkpoint(&mut self, block: usize, hash: &Hash) {
+        // По хешу операции не восстановить, он пишется как есть
+        self.pending.insert(&key(CHECKPOINT, block), hash);
+    }
+
+    fn details(&self) -> Option<AccountDetails> {
+        let value = self
+            .db
+            .get(DETAILS)
+            .unwrap_or_else(|e| panic!("Failed to read the bank database: {}", e))?;
+        let value = encryption::open(self.key.as_ref(), value.to_vec())
+            .unwrap_or_else(|e| panic!("Failed to read the bank database: {}", e));
+        let details = serde_json::from_slice(&value)
+            .unwrap_or_else(|e| panic!("Account details in the bank database are corrupt: {}", e));
+        Some(details)
+    }
+
+    fn set_details(&mut self, details: &AccountDetails) {
+        let value = self.seal(&serde_json::to_vec(details).unwrap());
+        self.pending.insert(DETAILS, value);
+        // При переносе данные счетов пишутся вместе с отметкой о его конце
+        if !self.complete {
+            return;
+        }
+        let written = self.db.apply_batch(mem::take(&mut self.pending));
+        written
+            .and_then(|()| match self.sync {
+                true => self.db.flush().map(|_| ()),
+                false => Ok(()),
+            })
+            .unwrap_or_else(|e| panic!("Failed to write the bank database: {}", e));
+    }
+
+    fn flush(&mut self) {
+        self.pending.insert(COMPLETE, &[]);
+        let written = self.db.apply_batch(mem::take(&mut self.pending));
+        written
+            .and_then(|()| self.db.flush().map(|_| ()))
+            .unwrap_or_else(|e| panic!("Failed to write the bank database: {}", e));
+        self.complete = true;
+    }
+}
+
+fn key(kind: u8, index: usize) -> [u8; 9] {
+    let mut key = [kind; 9];
+    key[1..].copy_from_slice(&(index as u64).to_be_bytes());
+    key
+}
+
+fn index(key: &[u8]) -> usize {
+    u64::from_be_bytes(key[1..9].try_into().unwrap()) as usize
+}
+
+// База - единственная копия состояния: без нее продолжать нельзя
+fn failed(index: usize, e: io::Error) -> ! {
+    panic!(
+        "Failed to access operation {} in the bank database: {}",
+        index, e
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account_index::BLOCK_SIZE;
+    use crate::bank::Bank;
+    use protocol_crate::{RemoteAccount, ReservationKind};
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("sled-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn survives_reopen() {
+        let dir = temp_dir("reopen");
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.create_account("X/savings".to_string());
+        let _ = bank.increase_account("X", 10);
+        bank.set_storage(Box::new(
+            SledStorage::open(&dir, Durability::EveryOperation, None).unwrap(),
+        ));
+        let _ = bank.transfer("X", "X/savings", 4);
+        let _ = bank.create_account("Y".to_string());
+        let snapshot = bank.snapshot();
+        drop(bank);
+
+        let storage = SledStorage::open(&dir, Durability::EveryOperation, None).unwrap();
+        assert!(!storage.is_new());
+        let mut bank = Bank::with_storage(Box::new(storage));
+        assert_eq!(snapshot, bank.snapshot());
+        assert!(bank.check_consistency().differences.is_empty());
+        assert_eq!(10, bank.get_subtree_balance("X").unwrap());
+        assert!(bank.increase_account("Y", 1).is_ok());
+        drop(bank);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn anonymized_survives_reopen() {
+        let dir = temp_dir("anonymized");
+        let mut bank = Bank::new();
+        bank.set_storage(Box::new(
+            SledStorage::open(&dir, Durability::EveryOperation, None).unwrap(),
+        ));
+        let _ = bank.create_account("X".to_string());
+        for _ in 0..BLOCK_SIZE {
+            let _ = bank.increase_account("X", 1);
+        }
+        let digest = bank.get_history_digest(Some(BLOCK_SIZE));
+        let (pseudonym, _) = bank.anonymize_account("X").unwrap();
+        let snapshot = bank.snapshot();
+        drop(bank);
+
+        let bank = Bank::with_storage(Box::new(
+            SledStorage::open(&dir, Durability::EveryOperation, None).unwrap(),
+        ));
+        assert_eq!(snapshot, bank.snapshot());
+        // Хеш блока сохранен до переименования и после него не пересчитывается
+        assert_eq!(digest, bank.get_history_digest(Some(BLOCK_SIZE)));
+        assert!(bank.check_consistency().differences.is_empty());
+        assert_eq!(
+            BLOCK_SIZE as u32,
+            bank.get_account_balance(pseudonym.as_str()).unwrap()
+        );
+        assert!(bank.get_account_balance("X").is_err());
+        drop(bank);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn details_survive_reopen() {
+        let dir = temp_dir("details");
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.increase_account("X", 7);
+        bank.set_account_owners("X", ["alice".to_string()].into())
+            .unwrap();
+        bank.set_storage(Box::new(
+            SledStorage::open(&dir, Durability::EveryOperation, None).unwrap(),
+        ));
+        let _ = bank.create_account("Y".to_string());
+        let _ = bank.increase_account("Y", 10);
+        bank.set_account_metadata("Y", "kyc".to_string(), Some("done".to_string()))
+            .unwrap();
+        bank.set_account_tags("Y", ["customer".to_string()].into())
+            .unwrap();
+        bank.set_required_approvals("Y", 2).unwrap();
+        bank.set_alert_rule("Y", Some(5)).unwrap();
+        let dispute = bank.open_dispute(3, "unknown".to_string(), None).unwrap();
+        let remote = RemoteAccount {
+            address: "127.0.0.1:7879".to_string(),
+            account: "Z".into(),
+        };
+        let reservation = bank
+            .reserve("X", 4, ReservationKind::Debit, remote)
+            .unwrap();
+        let pending = bank.queue_transfer("X", "Y", 3).unwrap();
+        let proposal = bank.propose_transfer("Y", "X", 1, Some("alice")).unwrap();
+        let snapshot = bank.snapshot();
+        drop(bank);
+
+        // Снимка нет: все берется из базы
+        let mut bank = Bank::with_storage(Box::new(
+            SledStorage::open(&dir, Durability::EveryOperation, None).unwrap(),
+        ));
+        assert_eq!(snapshot, bank.snapshot());
+        assert_eq!(1, bank.get_account_owners("X").unwrap().len());
+        assert_eq!(
+            Some("done"),
+            bank.get_account_metadata("Y")
+                .unwrap()
+                .get("kyc")
+                .map(String::as_str)
+        );
+        assert_eq!(1, bank.find_accounts_by_tag("customer").len());
+        assert_eq!(vec![("Y", 5, 10)], bank.alert_rules());
+        assert_eq!(dispute, bank.disputes(false)[0].id);
+        // Резервирование держит средства и после перезапуска
+        assert_eq!(0, bank.get_available_balance("X").unwrap());
+        bank.commit_reservation(reservation).unwrap();
+        assert_eq!(3, bank.get_account_balance("X").unwrap());
+        // Перевод все еще ждет администратора и держит средства
+        assert_eq!(0, bank.get_available_balance("X").unwrap());
+        bank.reject_transfer(pending).unwrap();
+        assert_eq!(3, bank.get_available_balance("X").unwrap());
+        // Одобрение, данное до перезапуска, засчитано
+        let approved = bank.approve_proposal(proposal.id, Some("bob")).unwrap();
+        assert!(approved.operation.is_some());
+        assert_eq!(4, bank.get_account_balance("X").unwrap());
+        drop(bank);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn interrupted_migration_starts_over() {
+        let dir = temp_dir("interrupted");
+        let mut storage = SledStorage::open(&dir, Durability::EveryOperation, None).unwrap();
+        assert!(storage.is_new());
+        storage.add_account("X");
+        storage.append(Operation::CreateAccount("X".to_string()), 0);
+        drop(storage);
+
+        let storage = SledStorage::open(&dir, Durability::EveryOperation, None).unwrap();
+        assert!(storage.is_new());
+        assert_eq!(0, storage.account_count());
+        assert_eq!(0, storage.history_len());
+        drop(storage);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,544 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use protocol_crate::{archive, BankError, Snapshot, SnapshotChunk};
+
+use crate::bank::Bank;
+use crate::config::{S3Config, SnapshotConfig};
+use crate::encryption::{self, Key};
+use crate::history::FrozenHistory;
+use crate::s3::Bucket;
+
+const PREFIX: &str = "snapshot-";
+const SUFFIX: &str = ".json";
+const ARCHIVE_SUFFIX: &str = ".rkyv";
+// Дописывается к имени сжатого снимка
+const COMPRESSED_SUFFIX: &str = ".zst";
+// Сжатые данные узнаются по началу кадра zstd, а не по имени файла
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+// Больше этого за один запрос снимка не отдается
+const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+struct Job {
+    dir: PathBuf,
+    keep: usize,
+    archive: bool,
+    compression: Option<i32>,
+    s3: Option<S3Config>,
+    // Снимок без истории: она читается уже в фоне
+    snapshot: Snapshot,
+    history: FrozenHistory,
+}
+
+/// Writes snapshots of the bank in a background thread. The policy is
+/// checked between requests, so an idle server writes nothing: without new
+/// operations there is nothing new to save.
+pub struct Snapshots {
+    jobs: Sender<Job>,
+    last_operations: usize,
+    last_time: Instant,
+}
+
+impl Snapshots {
+    /// Starts the writer thread; `operations` is the history length already
+    /// on disk. With `key` the snapshots are encrypted.
+    pub fn start(operations: usize, key: Option<Key>) -> Self {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        thread::spawn(move || {
+            for job in receiver {
+                let mut snapshot = job.snapshot;
+                match job.history.read() {
+                    Ok(entries) => {
+                        (snapshot.timestamps, snapshot.history) = entries.into_iter().unzip();
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to read the history for a snapshot: {}", e);
+                        continue;
+                    }
+                }
+                let written = write(
+                    &job.dir,
+                    &snapshot,
+                    job.archive,
+                    job.compression,
+                    key.as_ref(),
+                );
+                let path = match written {
+                    Ok(path) => path,
+                    Err(e) => {
+                        eprintln!("Failed to write snapshot to {}: {}", job.dir.display(), e);
+                        continue;
+                    }
+                };
+                if let Err(e) = prune(&job.dir, job.keep) {
+                    eprintln!("Failed to prune snapshots in {}: {}", job.dir.display(), e);
+                }
+                // Копия вне машины: неудача не мешает следующим снимкам
+                if let Some(s3) = &job.s3 {
+                    if let Err(e) = upload(&path, s3) {
+                        eprintln!("Failed to upload snapshot to {}: {}", s3.endpoint, e);
+                    }
+                }
+            }
+        });
+        Snapshots {
+            jobs,
+            last_operations: operations,
+            last_time: Instant::now(),
+        }
+    }
+
+    /// Hands a snapshot to the writer if `config` says it is time for one.
+    pub fn maybe_write(&mut self, config: &SnapshotConfig, bank: &Bank) {
+        let Some(dir) = &config.dir else {
+            return;
+        };
+        let operations = bank.history_len();
+        let new_operations = operations.saturating_sub(self.last_operations);
+        if new_operations == 0 {
+            return;
+        }
+        let by_count = config.every_operations.is_some_and(|n| new_operations >= n);
+        let by_time = config
+            .every_minutes
+            .is_some_and(|m| self.last_time.elapsed() >= Duration::from_secs(m * 60));
+        if by_count || by_time {
+            self.write(dir, config, bank);
+        }
+    }
+
+    /// Hands a snapshot to the writer whatever the policy says and returns
+    /// the number of operations in it; `None` without a snapshot directory.
+    pub fn write_now(&mut self, config: &SnapshotConfig, bank: &Bank) -> Option<usize> {
+        let dir = config.dir.as_ref()?;
+        self.write(dir, config, bank);
+        Some(bank.history_len())
+    }
+
+    /// Hands a snapshot to the writer that replaces all the kept ones, on
+    /// disk and in S3: they hold names the bank no longer has once an
+    /// account is anonymized.
+    pub fn replace_all(&mut self, config: &SnapshotConfig, bank: &Bank) {
+        let Some(dir) = &config.dir else {
+            return;
+        };
+        let config = SnapshotConfig {
+            keep: 1,
+            s3: config.s3.clone().map(|s3| S3Config { keep: 1, ..s3 }),
+            ..config.clone()
+        };
+        self.write(dir, &config, bank);
+    }
+
+    fn write(&mut self, dir: &Path, config: &SnapshotConfig, bank: &Bank) {
+        // Снимок снимается здесь, а история из сегментов читается,
+        // сериализуется и пишется уже в фоне
+        let (snapshot, history) = bank.snapshot_frozen();
+        let job = Job {
+            dir: dir.to_path_buf(),
+            keep: config.keep,
+            archive: config.archive,
+            compression: config.compression,
+            s3: config.s3.clone(),
+            snapshot,
+            history,
+        };
+        if self.jobs.send(job).is_ok() {
+            self.last_operations = bank.history_len();
+            self.last_time = Instant::now();
+        }
+    }
+}
+
+/// Snapshot being shipped to joining replicas. It is encoded once, so every
+/// chunk of a download comes from the same state while the bank goes on.
+#[derive(Default)]
+pub struct Shipping {
+    // Число операций снимка и сам снимок в архивном формате
+    current: Option<(usize, Vec<u8>)>,
+}
+
+impl Shipping {
+    /// At most `limit` bytes from `offset` of the snapshot at `operations`
+    /// operations; without `operations`, of a new snapshot of `bank` unless
+    /// the kept one is still current. Only the newest snapshot is kept.
+    pub fn chunk(
+        &mut self,
+        bank: &Bank,
+        operations: Option<usize>,
+        offset: u64,
+        limit: usize,
+    ) -> Result<SnapshotChunk, BankError> {
+        let kept = self.current.as_ref().map(|(kept, _)| *kept);
+        match operations {
+            Some(operations) if kept != Some(operations) => {
+                return Err(BankError::SnapshotUnavailable(operations));
+            }
+            None if kept != Some(bank.history_len()) => {
+                let data = archive::encode_snapshot(&bank.snapshot());
+                self.current = Some((bank.history_len(), data));
+            }
+            _ => {}
+        }
+        let (operations, data) = self.current.as_ref().unwrap();
+        let start = (offset as usize).min(data.len());
+        let end = start + limit.clamp(1, MAX_CHUNK_SIZE).min(data.len() - start);
+        Ok(SnapshotChunk {
+            operations: *operations,
+            size: data.len() as u64,
+            offset: start as u64,
+            data: data[start..end].to_vec(),
+        })
+    }
+}
+
+fn file_name(operations: usize, archive: bool, compressed: bool) -> String {
+    // Ведущие нули, чтобы имена сортировались по числу операций
+    let suffix = if archive { ARCHIVE_SUFFIX } else { SUFFIX };
+    let compressed = if compressed { COMPRESSED_SUFFIX } else { "" };
+    format!("{}{:020}{}{}", PREFIX, operations, suffix, compressed)
+}
+
+fn is_snapshot(name: &str) -> bool {
+    let name = name.strip_suffix(COMPRESSED_SUFFIX).unwrap_or(name);
+    name.starts_with(PREFIX) && (name.ends_with(SUFFIX) || name.ends_with(ARCHIVE_SUFFIX))
+}
+
+/// Snapshot files in `dir`, oldest first.
+fn list(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(is_snapshot)
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Writes `snapshot` next to the previous ones, as JSON or as an archive,
+/// compressed with zstd at the level `compression` if there is one and
+/// then encrypted with `key` if there is one. The file appears under its final name only once it is complete and on disk,
+/// so older snapshots can be pruned after it.
+///
+/// Returns the path of the file.
+fn write(
+    dir: &Path,
+    snapshot: &Snapshot,
+    archive: bool,
+    compression: Option<i32>,
+    key: Option<&Key>,
+) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(file_name(
+        snapshot.history.len(),
+        archive,
+        compression.is_some(),
+    ));
+    let temporary = path.with_extension("tmp");
+    let mut data = match archive {
+        true => archive::encode_snapshot(snapshot),
+        false => serde_json::to_vec(snapshot).unwrap(),
+    };
+    if let Some(level) = compression {
+        data = zstd::encode_all(data.as_slice(), level)?;
+    }
+    let data = encryption::seal(key, data);
+    let mut file = File::create(&temporary)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    fs::rename(&temporary, &path)?;
+    // Переименование долговечно, только когда сброшен и сам каталог
+    File::open(dir)?.sync_all()?;
+    Ok(path)
+}
+
+/// Removes all but the `keep` newest snapshots.
+fn prune(dir: &Path, keep: usize) -> io::Result<()> {
+    let files = list(dir)?;
+    for path in &files[..files.len().saturating_sub(keep.max(1))] {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Copies the snapshot file at `path` to the bucket of `config` under its
+/// own name and removes all but the `keep` newest snapshots there.
+fn upload(path: &Path, config: &S3Config) -> io::Result<()> {
+    let bucket = Bucket::new(config);
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap();
+    bucket.put(name, &fs::read(path)?)?;
+    let keys: Vec<String> = bucket
+        .list()?
+        .into_iter()
+        .filter(|key| is_snapshot(key))
+        .collect();
+    for key in &keys[..keys.len().saturating_sub(config.keep.max(1))] {
+        bucket.delete(key)?;
+    }
+    Ok(())
+}
+
+/// Downloads the snapshot `name` from the bucket of `config`, or the newest
+/// one there, to `dir`.
+///
+/// Returns the path of the downloaded file.
+pub fn download(config: &S3Config, name: Option<&str>, dir: &Path) -> io::Result<PathBuf> {
+    let bucket = Bucket::new(config);
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => bucket
+            .list()?
+            .into_iter()
+            .rfind(|key| is_snapshot(key))
+            .ok_or_else(|| io::Error::other(format!("{} holds no snapshots", config.bucket)))?,
+    };
+    if !is_snapshot(&name) {
+        return Err(io::Error::other(format!("{}: not a snapshot name", name)));
+    }
+    fs::create_dir_all(dir)?;
+    let path = dir.join(&name);
+    fs::write(&path, bucket.get(&name)?)?;
+    Ok(path)
+}
+
+/// Reads the newest snapshot in `dir` that can be read, `None` if there is
+/// none yet. A damaged snapshot is skipped for the one before it; the error
+/// is returned only if none of them can be read. Both formats are read, so
+/// switching the format keeps the older snapshots.
+pub fn load_latest(dir: &Path, key: Option<&Key>) -> io::Result<Option<Snapshot>> {
+    let files = match list(dir) {
+        Ok(files) => files,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut error = None;
+    for path in files.iter().rev() {
+        match load(path, key) {
+            Ok(snapshot) => return Ok(Some(snapshot)),
+            Err(e) => {
+                eprintln!("Skipping snapshot {}: {}", path.display(), e);
+                error.get_or_insert(e);
+            }
+        }
+    }
+    error.map_or(Ok(None), Err)
+}
+
+/// Reads the snapshot in the file `path`, in either format, compressed or
+/// not, encrypted with `key` or not.
+pub fn load(path: &Path, key: Option<&Key>) -> io::Result<Snapshot> {
+    let mut data = encryption::open(key, fs::read(path)?)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    if data.starts_with(&ZSTD_MAGIC) {
+        data = zstd::decode_all(data.as_slice())?;
+    }
+    let name = path.to_string_lossy();
+    let name = name.strip_suffix(COMPRESSED_SUFFIX).unwrap_or(&name);
+    let snapshot = match name.ends_with(ARCHIVE_SUFFIX) {
+        true => archive::decode_snapshot(&data).map_err(|e| format!("{:?}", e)),
+        false => serde_json::from_slice(&data).map_err(|e| e.to_string()),
+    };
+    snapshot.map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), e),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::History;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn retention_and_latest() {
+        let dir = std::env::temp_dir().join(format!("snapshots-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        assert!(load_latest(&dir, None).unwrap().is_none());
+
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        for amount in 1..=4 {
+            let _ = bank.increase_account("X", amount);
+            // Форматы чередуются, последний снимок - архив
+            write(&dir, &bank.snapshot(), amount % 2 == 0, None, None).unwrap();
+            prune(&dir, 2).unwrap();
+        }
+        let files = list(&dir).unwrap();
+        assert_eq!(2, files.len());
+        assert!(files[0].ends_with(file_name(4, false, false)));
+        assert!(files[1].ends_with(file_name(5, true, false)));
+
+        let latest = Bank::from_snapshot(load_latest(&dir, None).unwrap().unwrap());
+        assert_eq!(10, latest.get_account_balance("X").unwrap());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn damaged_latest() {
+        let dir = std::env::temp_dir().join(format!("snapshots-damaged-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        write(&dir, &bank.snapshot(), false, None, None).unwrap();
+        let _ = bank.increase_account("X", 1);
+        let latest = write(&dir, &bank.snapshot(), true, Some(3), None).unwrap();
+
+        // Недописанный последний снимок пропускается ради предыдущего
+        let data = fs::read(&latest).unwrap();
+        fs::write(&latest, &data[..data.len() / 2]).unwrap();
+        assert_eq!(1, load_latest(&dir, None).unwrap().unwrap().history.len());
+        fs::write(list(&dir).unwrap()[0].as_path(), b"{").unwrap();
+        assert!(load_latest(&dir, None).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn segmented_history() {
+        let dir = std::env::temp_dir().join(format!("snapshots-segments-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = SnapshotConfig {
+            dir: Some(dir.join("snapshots")),
+            ..SnapshotConfig::default()
+        };
+        let mut bank = Bank::new();
+        let history = History::segmented(&dir.join("history"), 3, 0, Some(3), None).unwrap();
+        bank.set_storage(Box::new(MemoryStorage::with_history(history)));
+        let _ = bank.create_account("X".to_string());
+        for _ in 0..10 {
+            let _ = bank.increase_account("X", 1);
+        }
+
+        // История из сегментов дочитывается уже в потоке записи
+        let expected = bank.snapshot();
+        let mut snapshots = Snapshots::start(0, None);
+        assert_eq!(Some(11), snapshots.write_now(&config, &bank));
+        let _ = bank.increase_account("X", 1);
+        let snapshots_dir = config.dir.as_ref().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while list(snapshots_dir).map_or(true, |files| files.is_empty())
+            && Instant::now() < deadline
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(Some(expected), load_latest(snapshots_dir, None).unwrap());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn compressed() {
+        let dir = std::env::temp_dir().join(format!("snapshots-zstd-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        for _ in 0..100 {
+            let _ = bank.increase_account("X", 1);
+        }
+
+        let plain = write(&dir, &bank.snapshot(), false, None, None).unwrap();
+        let _ = bank.increase_account("X", 1);
+        let json = write(&dir, &bank.snapshot(), false, Some(3), None).unwrap();
+        let _ = bank.increase_account("X", 1);
+        let archive = write(&dir, &bank.snapshot(), true, Some(19), None).unwrap();
+        assert!(json.ends_with(file_name(102, false, true)));
+        assert!(archive.ends_with(file_name(103, true, true)));
+        assert!(fs::metadata(&json).unwrap().len() * 4 < fs::metadata(&plain).unwrap().len());
+        assert_eq!(3, list(&dir).unwrap().len());
+
+        let latest = Bank::from_snapshot(load_latest(&dir, None).unwrap().unwrap());
+        assert_eq!(102, latest.get_account_balance("X").unwrap());
+        // Сжатие узнается по содержимому, даже если имя файла его не выдает
+        fs::rename(&json, &plain).unwrap();
+        let renamed = Bank::from_snapshot(load(&plain, None).unwrap());
+        assert_eq!(101, renamed.get_account_balance("X").unwrap());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn shipping() {
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        let _ = bank.increase_account("X", 10);
+        let mut shipping = Shipping::default();
+
+        let first = shipping.chunk(&bank, None, 0, 16).unwrap();
+        assert_eq!(
+            (2, 0, 16),
+            (first.operations, first.offset, first.data.len())
+        );
+        // Части одного снимка не меняются, пока банк идет дальше
+        let _ = bank.increase_account("X", 1);
+        let mut data = first.data;
+        while (data.len() as u64) < first.size {
+            let chunk = shipping
+                .chunk(&bank, Some(2), data.len() as u64, 16)
+                .unwrap();
+            data.extend(chunk.data);
+        }
+        let shipped = Bank::from_snapshot(archive::decode_snapshot(&data).unwrap());
+        assert_eq!(10, shipped.get_account_balance("X").unwrap());
+
+        // Новый снимок вытесняет старый
+        assert_eq!(3, shipping.chunk(&bank, None, 0, 16).unwrap().operations);
+        assert!(matches!(
+            shipping.chunk(&bank, Some(2), 16, 16),
+            Err(BankError::SnapshotUnavailable(2))
+        ));
+    }
+
+    #[test]
+    fn replaced_after_anonymization() {
+        let dir = std::env::temp_dir().join(format!("snapshots-replace-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = SnapshotConfig {
+            dir: Some(dir.clone()),
+            keep: 3,
+            ..SnapshotConfig::default()
+        };
+        let mut bank = Bank::new();
+        let _ = bank.create_account("X".to_string());
+        write(&dir, &bank.snapshot(), false, None, None).unwrap();
+        let _ = bank.increase_account("X", 1);
+        write(&dir, &bank.snapshot(), true, Some(3), None).unwrap();
+
+        let _ = bank.anonymize_account("X");
+        let mut snapshots = Snapshots::start(bank.history_len(), None);
+        snapshots.replace_all(&config, &bank);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while list(&dir).unwrap().len() != 1 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let files = list(&dir).unwrap();
+        assert_eq!(1, files.len());
+        assert!(files[0].ends_with(file_name(3, false, false)));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn policy() {
+        let dir = std::env::temp_dir().join(format!("snapshots-policy-{}", std::process::id()));
+        let config = SnapshotConfig {
+            dir: Some(dir.clone()),
+            every_operations: Some(2),
+            ..SnapshotConfig::default()
+        };
+        let mut bank = Bank::new();
+        let mut snapshots = Snapshots::start(0, None);
+        let _ = bank.create_account("X".to_string());
+        snapshots.maybe_write(&config, &bank);
+        assert_eq!(0, snapshots.last_operations);
+        let _ = bank.increase_account("X", 1);
+        snapshots.maybe_write(&config, &bank);
+        assert_eq!(2, snapshots.last_operations);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
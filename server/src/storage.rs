@@ -0,0 +1,339 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Debug;
+
+use protocol_crate::digest::Hash;
+use protocol_crate::{
+    AccountId, Dispute, Operation, PendingTransferId, ProposalId, ReservationId, Snapshot,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::bank::{Pending, Proposal, Reservation};
+use crate::history::{FrozenHistory, History};
+use crate::names::Names;
+
+// Сколько операций читается за раз при обходе всей истории
+pub const PAGE_SIZE: usize = 1000;
+
+/// Where a bank keeps its accounts, their balances and the operation
+/// history. The bank checks every change before it reaches the storage and
+/// derives its indices from what is stored, so a backend only has to keep
+/// the data.
+pub trait BankStorage: Debug + Send {
+    /// Adds an account with a zero balance; its ID is the number of
+    /// accounts before it.
+    fn add_account(&mut self, name: &str) -> AccountId;
+
+    fn account_id(&self, name: &str) -> Option<AccountId>;
+
+    /// Name of the account `id`, which must exist.
+    fn account_name(&self, id: AccountId) -> &str;
+
+    fn account_count(&self) -> usize;
+
+    /// Balance of the account `id`, which must exist.
+    fn balance(&self, id: AccountId) -> u32;
+
+    fn set_balance(&mut self, id: AccountId, balance: u32);
+
+    /// Appends `operation` made at `timestamp` (unix seconds) to the history.
+    fn append(&mut self, operation: Operation, timestamp: u64);
+
+    fn history_len(&self) -> usize;
+
+    /// Time of operation `index` of the history, which must exist.
+    fn timestamp(&self, index: usize) -> u64;
+
+    /// Operations `start..end` of the history; the part past its end is cut.
+    fn range(&self, start: usize, end: usize) -> Vec<Operation>;
+
+    /// Same as [`BankStorage::range`], but with the time of every operation.
+    fn entries(&self, start: usize, end: usize) -> Vec<(u64, Operation)> {
+        let operations = self.range(start, end);
+        (start..)
+            .map(|i| self.timestamp(i))
+            .zip(operations)
+            .collect()
+    }
+
+    /// The whole history, oldest first.
+    fn operations(&self) -> Box<dyn Iterator<Item = Operation> + '_> {
+        Box::new(
+            (0..self.history_len())
+                .step_by(PAGE_SIZE)
+                .flat_map(move |start| self.range(start, start + PAGE_SIZE)),
+        )
+    }
+
+    /// Renames the accounts `old -> new` of `renames` in the account list
+    /// and in every operation of the history, keeping their IDs, balances
+    /// and the timestamps. The new names must not be taken.
+    fn rename_accounts(&mut self, renames: &HashMap<String, String>);
+
+    /// Details of the accounts as last saved with
+    /// [`BankStorage::set_details`]; `None` if the storage does not keep
+    /// them or none were saved yet.
+    fn details(&self) -> Option<AccountDetails> {
+        None
+    }
+
+    /// Saves the details of the accounts in place of the saved ones. A
+    /// storage in memory does not keep them: the bank has them anyway.
+    fn set_details(&mut self, _details: &AccountDetails) {}
+
+    /// Hashes of the history chain after every full block of operations,
+    /// as saved with [`BankStorage::set_checkpoint`]; empty if the storage
+    /// does not keep them.
+    fn checkpoints(&self) -> Vec<Hash> {
+        Vec::new()
+    }
+
+    /// Saves the hash of the chain after block `block`. It is written
+    /// together with the next operation, the last one of the block.
+    fn set_checkpoint(&mut self, _block: usize, _hash: &Hash) {}
+
+    /// The history with its timestamps as it is now, to be read on another
+    /// thread while the bank goes on. By default it is read right here.
+    fn freeze_history(&self) -> FrozenHistory {
+        FrozenHistory::in_memory(
+            (0..self.history_len())
+                .step_by(PAGE_SIZE)
+                .flat_map(|start| self.entries(start, start + PAGE_SIZE))
+                .collect(),
+        )
+    }
+
+    /// Makes everything written so far durable. [`crate::bank::Bank::set_storage`]
+    /// calls it once all the data is copied.
+    fn flush(&mut self) {}
+
+    /// Accounts with their balances in ID order, the history and its
+    /// timestamps. The rest of the snapshot is left empty for the bank.
+    fn snapshot(&self) -> Snapshot {
+        let (timestamps, history) = (0..self.history_len())
+            .step_by(PAGE_SIZE)
+            .flat_map(|start| self.entries(start, start + PAGE_SIZE))
+            .unzip();
+        Snapshot {
+            history,
+            timestamps,
+            ..self.snapshot_accounts()
+        }
+    }
+
+    /// Same as [`BankStorage::snapshot`], but with an empty history: it
+    /// comes from [`BankStorage::freeze_history`].
+    fn snapshot_accounts(&self) -> Snapshot {
+        Snapshot {
+            accounts: (0..self.account_count())
+                .map(|id| (self.account_name(id).to_string(), self.balance(id)))
+                .collect(),
+            history: Vec::new(),
+            timestamps: Vec::new(),
+            metadata: HashMap::new(),
+            owners: HashMap::new(),
+            tags: HashMap::new(),
+            disputes: Vec::new(),
+            approvals: HashMap::new(),
+            alert_rules: HashMap::new(),
+            reservations: Vec::new(),
+            next_reservation_id: 0,
+            pending_transfers: Vec::new(),
+            next_pending_id: 0,
+            proposals: Vec::new(),
+            next_proposal_id: 0,
+        }
+    }
+}
+
+/// What the bank knows of its accounts beside the balances and the history,
+/// by account ID, so that renaming an account does not touch it.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountDetails {
+    pub metadata: HashMap<AccountId, BTreeMap<String, String>>,
+    pub owners: HashMap<AccountId, BTreeSet<String>>,
+    pub tags: HashMap<AccountId, BTreeSet<String>>,
+    // Сколько одобрений нужно для переводов со счета
+    pub approvals: HashMap<AccountId, u32>,
+    pub alert_rules: HashMap<AccountId, u32>,
+    pub disputes: Vec<Dispute>,
+    // Незавершенные резервирования и номер следующего
+    #[serde(default)]
+    pub reservations: BTreeMap<ReservationId, Reservation>,
+    #[serde(default)]
+    pub next_reservation_id: ReservationId,
+    // Переводы, ждущие администратора, и номер следующего
+    #[serde(default)]
+    pub pending: BTreeMap<PendingTransferId, Pending>,
+    #[serde(default)]
+    pub next_pending_id: PendingTransferId,
+    // Переводы, ждущие одобрений, и номер следующего
+    #[serde(default)]
+    pub proposals: BTreeMap<ProposalId, Proposal>,
+    #[serde(default)]
+    pub next_proposal_id: ProposalId,
+}
+
+/// Copies the accounts with their balances and the history with its
+/// timestamps from `from` to `to`, which must be empty, and makes the copy
+/// durable. The history is read a page at a time.
+pub fn copy(from: &dyn BankStorage, to: &mut dyn BankStorage) {
+    for id in 0..from.account_count() {
+        to.add_account(from.account_name(id));
+        to.set_balance(id, from.balance(id));
+    }
+    for start in (0..from.history_len()).step_by(PAGE_SIZE) {
+        for (timestamp, operation) in from.entries(start, start + PAGE_SIZE) {
+            to.append(operation, timestamp);
+        }
+    }
+    to.flush();
+}
+
+/// Replaces the account names of `operation` found in `renames`; tells
+/// whether there were any.
+pub fn rename(operation: &mut Operation, renames: &HashMap<String, String>) -> bool {
+    let mut renamed = false;
+    for account in operation.accounts_mut() {
+        if let Some(name) = renames.get(account.as_str()) {
+            *account = name.clone();
+            renamed = true;
+        }
+    }
+    renamed
+}
+
+/// Storage in process memory; the history alone can be moved to segment
+/// files with [`MemoryStorage::with_history`].
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    // Имена счетов, каждое хранится один раз
+    names: Names,
+    // Балансы по id счета
+    balances: Vec<u32>,
+    history: History,
+}
+
+impl MemoryStorage {
+    /// Empty storage keeping its history in `history`.
+    pub fn with_history(history: History) -> Self {
+        MemoryStorage {
+            history,
+            ..MemoryStorage::default()
+        }
+    }
+
+    /// Storage holding the accounts and the history of `snapshot`.
+    pub fn from_snapshot(snapshot: Snapshot) -> Self {
+        let mut names = Names::default();
+        let mut balances = Vec::with_capacity(snapshot.accounts.len());
+        for (name, balance) in &snapshot.accounts {
+            names.push(name);
+            balances.push(*balance);
+        }
+        let history = History::from_operations(snapshot.history, snapshot.timestamps, &names);
+        MemoryStorage {
+            names,
+            balances,
+            history,
+        }
+    }
+}
+
+impl BankStorage for MemoryStorage {
+    fn add_account(&mut self, name: &str) -> AccountId {
+        self.balances.push(0);
+        self.names.push(name)
+    }
+
+    fn account_id(&self, name: &str) -> Option<AccountId> {
+        self.names.id(name)
+    }
+
+    fn account_name(&self, id: AccountId) -> &str {
+        &self.names[id]
+    }
+
+    fn account_count(&self) -> usize {
+        self.names.len()
+    }
+
+    fn balance(&self, id: AccountId) -> u32 {
+        self.balances[id]
+    }
+
+    fn set_balance(&mut self, id: AccountId, balance: u32) {
+        self.balances[id] = balance;
+    }
+
+    fn append(&mut self, operation: Operation, timestamp: u64) {
+        self.history.push(operation, timestamp, &self.names);
+    }
+
+    fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    fn timestamp(&self, index: usize) -> u64 {
+        self.history.timestamp(index)
+    }
+
+    fn range(&self, start: usize, end: usize) -> Vec<Operation> {
+        self.history.range(start, end)
+    }
+
+    fn entries(&self, start: usize, end: usize) -> Vec<(u64, Operation)> {
+        self.history.entries(start, end)
+    }
+
+    fn freeze_history(&self) -> FrozenHistory {
+        self.history.freeze()
+    }
+
+    fn operations(&self) -> Box<dyn Iterator<Item = Operation> + '_> {
+        Box::new(self.history.iter())
+    }
+
+    fn rename_accounts(&mut self, renames: &HashMap<String, String>) {
+        for (old, new) in renames {
+            if let Some(id) = self.names.id(old) {
+                self.names.rename(id, new);
+            }
+        }
+        self.history.rename(renames, &self.names);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bank::Bank;
+
+    #[test]
+    fn bank_over_existing_storage() {
+        let mut storage = MemoryStorage::default();
+        for (timestamp, operation) in [
+            Operation::CreateAccount("X".to_string()),
+            Operation::CreateAccount("X/savings".to_string()),
+            Operation::IncreaseAccount("X".to_string(), 10),
+            Operation::Transfer("X".to_string(), "X/savings".to_string(), 4),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            if let Operation::CreateAccount(name) = &operation {
+                storage.add_account(name);
+            }
+            storage.append(operation, timestamp as u64);
+        }
+        storage.set_balance(0, 6);
+        storage.set_balance(1, 4);
+
+        let mut bank = Bank::with_storage(Box::new(storage));
+        assert!(bank.check_consistency().differences.is_empty());
+        assert_eq!(10, bank.get_subtree_balance("X").unwrap());
+        assert_eq!(3, bank.get_account_history("X").unwrap().len());
+        bank.increase_account("X/savings", 1).unwrap();
+        assert_eq!(5, bank.history_len());
+        assert_eq!(5, bank.snapshot().timestamps.len());
+    }
+}
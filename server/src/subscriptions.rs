@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use protocol_crate::{BankError, ResponsePayload};
+
+use crate::bank::Bank;
+use crate::interactive::Connection;
+use crate::storage::PAGE_SIZE;
+use crate::{Answer, Progress, Reply};
+
+/// Clients streaming the history with `Subscribe`. Each is sent the
+/// operations added since the last push, a page at a time, until it
+/// cancels the request or closes its connection.
+#[derive(Default)]
+pub struct Subscriptions {
+    open: Vec<Subscription>,
+}
+
+struct Subscription {
+    progress: Progress,
+    connection: Arc<Connection>,
+    cancelled: Arc<AtomicBool>,
+    // Номер следующей операции, которую клиент еще не получил
+    next: usize,
+    // Имя счета, если клиенту нужны только его операции
+    account: Option<String>,
+}
+
+impl Subscriptions {
+    pub fn add(
+        &mut self,
+        progress: Progress,
+        connection: Arc<Connection>,
+        cancelled: Arc<AtomicBool>,
+        from: usize,
+        account: Option<String>,
+    ) {
+        self.open.push(Subscription {
+            progress,
+            connection,
+            cancelled,
+            next: from,
+            account,
+        });
+    }
+
+    /// Follows accounts renamed by `AnonymizeAccount`.
+    pub fn rename(&mut self, renames: &HashMap<String, String>) {
+        for subscription in &mut self.open {
+            if let Some(new) = subscription
+                .account
+                .as_ref()
+                .and_then(|account| renames.get(account))
+            {
+                subscription.account = Some(new.clone());
+            }
+        }
+    }
+
+    /// Sends every subscriber at most a page of the operations it has not
+    /// seen yet and ends the subscriptions that were cancelled or whose
+    /// connection closed. Returns whether some subscriber is still behind.
+    pub fn push(&mut self, bank: &Bank) -> bool {
+        let operations = bank.history_len();
+        // Ответ Cancelled отпускает писателя соединения: он ждет всех отправителей
+        self.open.retain(|subscription| {
+            let ended = subscription.cancelled.load(Ordering::Relaxed)
+                || subscription.connection.is_closed();
+            if ended {
+                subscription.end();
+            }
+            !ended
+        });
+        for subscription in &mut self.open {
+            if subscription.next >= operations {
+                continue;
+            }
+            let page = bank.get_history_page(subscription.next, PAGE_SIZE);
+            let events: Vec<_> = (subscription.next..)
+                .zip(page)
+                .filter(|(_, operation)| match &subscription.account {
+                    Some(account) => operation.accounts().contains(&account.as_str()),
+                    None => true,
+                })
+                .collect();
+            subscription.next = (subscription.next + PAGE_SIZE).min(operations);
+            if !events.is_empty() {
+                subscription.progress.send(ResponsePayload::Events(events));
+            }
+        }
+        self.open
+            .iter()
+            .any(|subscription| subscription.next < operations)
+    }
+}
+
+impl Subscription {
+    /// Answers the `Subscribe` request itself, as a cancelled one.
+    fn end(&self) {
+        let _ = self.progress.reply.send(Reply {
+            request_id: Some(self.progress.request_id.clone()),
+            answer: Answer::Response(self.progress.format, Err(BankError::Cancelled)),
+            buffer: Vec::new(),
+            progress: false,
+        });
+    }
+}
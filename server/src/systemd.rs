@@ -0,0 +1,79 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, RawFd};
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::Path;
+use std::process;
+
+// Первый дескриптор, переданный systemd (SD_LISTEN_FDS_START)
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Tells systemd the server is ready (`Type=notify`); does nothing when the
+/// server was not started by systemd.
+pub fn notify_ready() {
+    if let Ok(socket) = env::var("NOTIFY_SOCKET") {
+        if let Err(e) = notify(&socket, "READY=1") {
+            eprintln!("Failed to notify systemd at {}: {}", socket, e);
+        }
+    }
+}
+
+fn notify(socket: &str, state: &str) -> io::Result<()> {
+    // Имя с @ - абстрактный сокет, они есть только в Linux
+    let address = match socket.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return Err(io::Error::from(io::ErrorKind::Unsupported)),
+        None => SocketAddr::from_pathname(socket)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
+
+/// Listener passed by systemd socket activation, `None` when there is none.
+pub fn activated_listener() -> io::Result<Option<TcpListener>> {
+    // Дескрипторы предназначены только процессу с LISTEN_PID
+    if env::var("LISTEN_PID").ok() != Some(process::id().to_string()) {
+        return Ok(None);
+    }
+    let fds: usize = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse().ok())
+        .unwrap_or(0);
+    match fds {
+        0 => Ok(None),
+        // SAFETY: systemd передает открытый сокет начиная с дескриптора 3,
+        // больше его никто в процессе не использует
+        1 => Ok(Some(unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START) })),
+        n => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("expected one socket from systemd, got {}", n),
+        )),
+    }
+}
+
+pub fn write_pid_file(path: &Path) -> io::Result<()> {
+    fs::write(path, format!("{}\n", process::id()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notify_socket() {
+        let path = env::temp_dir().join(format!("notify-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        notify(path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buffer = [0; 16];
+        let n = socket.recv(&mut buffer).unwrap();
+        assert_eq!(b"READY=1", &buffer[..n]);
+        let _ = fs::remove_file(&path);
+    }
+}
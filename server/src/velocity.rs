@@ -0,0 +1,54 @@
+use std::collections::VecDeque;
+
+/// Outflows of one account inside a sliding time window.
+#[derive(Debug, Default)]
+pub struct VelocityTracker {
+    // (время, сумма) в порядке возрастания времени
+    outflows: VecDeque<(u64, u32)>,
+}
+
+impl VelocityTracker {
+    /// Adds an outflow and forgets the ones older than `retention` seconds.
+    pub fn record(&mut self, timestamp: u64, amount: u32, retention: u64) {
+        self.outflows.push_back((timestamp, amount));
+        let oldest = timestamp.saturating_sub(retention);
+        while self.outflows.front().is_some_and(|(t, _)| *t < oldest) {
+            self.outflows.pop_front();
+        }
+    }
+
+    /// Sum of the outflows made at or after `since`.
+    pub fn total_since(&self, since: u64) -> u64 {
+        self.outflows
+            .iter()
+            .rev()
+            .take_while(|(t, _)| *t >= since)
+            .map(|(_, amount)| *amount as u64)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sliding_window() {
+        let mut tracker = VelocityTracker::default();
+        tracker.record(100, 5, 1000);
+        tracker.record(200, 7, 1000);
+        tracker.record(300, 1, 1000);
+        assert_eq!(13, tracker.total_since(0));
+        assert_eq!(8, tracker.total_since(200));
+        assert_eq!(0, tracker.total_since(301));
+    }
+
+    #[test]
+    fn forgets_old_outflows() {
+        let mut tracker = VelocityTracker::default();
+        tracker.record(100, 5, 50);
+        tracker.record(200, 7, 50);
+        assert_eq!(1, tracker.outflows.len());
+        assert_eq!(7, tracker.total_since(0));
+    }
+}